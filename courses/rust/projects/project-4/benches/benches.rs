@@ -1,92 +1,271 @@
+use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 
 use criterion::criterion_group;
 use criterion::criterion_main;
 use criterion::BatchSize;
 use criterion::Criterion;
+use hdrhistogram::Histogram;
+use kvs::workload::Workload;
+use kvs::Codec;
+use kvs::Durability;
 use kvs::KvStore;
 use kvs::KvsEngine;
+#[cfg(feature = "rocksdb")]
+use kvs::RocksKvsEngine;
 use kvs::SledKvsEngine;
-use rand::rngs::SmallRng;
-use rand::Rng;
-use rand::SeedableRng;
 use tempfile::TempDir;
 
+// The latency of each operation a bench runs, warm-up included, printed as percentiles once
+// it's done: criterion reports the mean time of an iteration, which hides the tail.
+struct Latencies {
+    name: &'static str,
+    histogram: Histogram<u64>,
+}
+
+impl Latencies {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            histogram: Histogram::new(3).unwrap(),
+        }
+    }
+
+    fn time<T>(&mut self, op: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = op();
+        self.histogram
+            .record(start.elapsed().as_nanos() as u64)
+            .unwrap();
+        result
+    }
+
+    // Print nothing for a bench that was filtered out.
+    fn print(&self) {
+        if self.histogram.is_empty() {
+            return;
+        }
+        let at = |quantile| Duration::from_nanos(self.histogram.value_at_quantile(quantile));
+        println!(
+            "{} latency: p50 {:.1?}, p95 {:.1?}, p99 {:.1?}, max {:.1?} over {} ops",
+            self.name,
+            at(0.5),
+            at(0.95),
+            at(0.99),
+            Duration::from_nanos(self.histogram.max()),
+            self.histogram.len()
+        );
+    }
+}
+
 fn write_benchmark(c: &mut Criterion) {
+    let mut latencies = Latencies::new("kvs_write");
     c.bench_function("kvs_write", |b| {
         b.iter_batched(
             || {
                 let dir = TempDir::new().unwrap();
-                let store = KvStore::open(dir.into_path()).unwrap();
-                store
+                KvStore::open(dir.into_path()).unwrap()
             },
             |store| {
-                let mut rng = SmallRng::from_seed([0; 32]);
-                for _ in 0..100 {
-                    let i = rng.gen_range(0..100000);
-                    let key = format!("key{}", i);
-                    let value = format!("value{}", i);
-                    store.set(key, value).unwrap();
+                for op in Workload::writes(100000).ops().take(100) {
+                    latencies.time(|| op.apply(&store).unwrap());
                 }
             },
             BatchSize::SmallInput,
         );
     });
+    latencies.print();
+    // Concurrent writers sharing syncs; each set waits for its record to reach the disk.
+    c.bench_function("kvs_group_commit_write", |b| {
+        b.iter_batched(
+            || {
+                let dir = TempDir::new().unwrap();
+                KvStore::open(dir.into_path())
+                    .unwrap()
+                    .with_group_commit(Duration::from_micros(500), 64 * 1024)
+                    .unwrap()
+            },
+            |store| {
+                thread::scope(|scope| {
+                    for thread_id in 0..8 {
+                        let store = store.clone();
+                        let ops = Workload::writes(100000).with_seed(thread_id).ops();
+                        scope.spawn(move || {
+                            for op in ops.take(100) {
+                                op.apply(&store).unwrap();
+                            }
+                        });
+                    }
+                });
+            },
+            BatchSize::SmallInput,
+        );
+    });
+    let mut latencies = Latencies::new("sled_write");
     c.bench_function("sled_write", |b| {
         b.iter_batched(
             || {
                 let dir = TempDir::new().unwrap();
-                let engine = SledKvsEngine::new(sled::open(dir).unwrap());
-                engine
+                SledKvsEngine::new(sled::open(dir).unwrap())
+            },
+            |engine| {
+                for op in Workload::writes(100000).ops().take(100) {
+                    latencies.time(|| op.apply(&engine).unwrap());
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    latencies.print();
+    #[cfg(feature = "rocksdb")]
+    {
+        let mut latencies = Latencies::new("rocksdb_write");
+        c.bench_function("rocksdb_write", |b| {
+            b.iter_batched(
+                || {
+                    let dir = TempDir::new().unwrap();
+                    RocksKvsEngine::open(dir.into_path()).unwrap()
+                },
+                |engine| {
+                    for op in Workload::writes(100000).ops().take(100) {
+                        latencies.time(|| op.apply(&engine).unwrap());
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        latencies.print();
+    }
+    // Sets leave flushing to sled's background thread.
+    let mut latencies = Latencies::new("sled_background_write");
+    c.bench_function("sled_background_write", |b| {
+        b.iter_batched(
+            || {
+                let dir = TempDir::new().unwrap();
+                let config = sled::Config::new().path(dir.into_path());
+                SledKvsEngine::with_config(config, Durability::Background).unwrap()
             },
             |engine| {
-                let mut rng = SmallRng::from_seed([0; 32]);
-                for _ in 0..100 {
-                    let i = rng.gen_range(0..100000);
-                    let key = format!("key{}", i);
-                    let value = format!("value{}", i);
-                    engine.set(key, value).unwrap();
+                for op in Workload::writes(100000).ops().take(100) {
+                    latencies.time(|| op.apply(&engine).unwrap());
                 }
             },
             BatchSize::SmallInput,
         )
     });
+    latencies.print();
 }
 
 fn read_benchmark(c: &mut Criterion) {
+    let mut latencies = Latencies::new("kvs_read");
     c.bench_function("kvs_read", |b| {
         let dir = TempDir::new().unwrap();
         let store = KvStore::open(dir.into_path()).unwrap();
-        for i in 0..100 {
-            let key = format!("key{}", i);
-            let value = format!("value{}", i);
-            store.set(key, value).unwrap();
-        }
-        let mut rng = SmallRng::from_seed([0; 32]);
+        let workload = Workload::reads(100);
+        workload.populate(&store).unwrap();
+        let mut ops = workload.ops();
         b.iter(|| {
-            let key = format!("key{}", rng.gen_range(0..100));
-            store.get(key).unwrap();
+            let op = ops.next().unwrap();
+            latencies.time(|| op.apply(&store).unwrap())
         });
     });
+    latencies.print();
+    // Compacting seals the populated values into a mapped log.
+    let mut latencies = Latencies::new("kvs_mmap_read");
+    c.bench_function("kvs_mmap_read", |b| {
+        let dir = TempDir::new().unwrap();
+        let store = KvStore::open(dir.into_path())
+            .unwrap()
+            .with_mmap_reads()
+            .unwrap();
+        let workload = Workload::reads(100);
+        workload.populate(&store).unwrap();
+        store.compact().unwrap();
+        let mut ops = workload.ops();
+        b.iter(|| {
+            let op = ops.next().unwrap();
+            latencies.time(|| op.apply(&store).unwrap())
+        });
+    });
+    latencies.print();
+    let mut latencies = Latencies::new("sled_read");
     c.bench_function("sled_read", |b| {
         let dir = TempDir::new().unwrap();
         let engine = SledKvsEngine::new(sled::open(dir).unwrap());
-        for i in 0..100 {
-            let key = format!("key{}", i);
-            let value = format!("value{}", i);
-            engine.set(key, value).unwrap();
-        }
-        let mut rng = SmallRng::from_seed([0; 32]);
+        let workload = Workload::reads(100);
+        workload.populate(&engine).unwrap();
+        let mut ops = workload.ops();
         b.iter(|| {
-            let key = format!("key{}", rng.gen_range(0..100));
-            engine.get(key).unwrap();
+            let op = ops.next().unwrap();
+            latencies.time(|| op.apply(&engine).unwrap())
         });
     });
+    latencies.print();
+    #[cfg(feature = "rocksdb")]
+    {
+        let mut latencies = Latencies::new("rocksdb_read");
+        c.bench_function("rocksdb_read", |b| {
+            let dir = TempDir::new().unwrap();
+            let engine = RocksKvsEngine::open(dir.into_path()).unwrap();
+            let workload = Workload::reads(100);
+            workload.populate(&engine).unwrap();
+            let mut ops = workload.ops();
+            b.iter(|| {
+                let op = ops.next().unwrap();
+                latencies.time(|| op.apply(&engine).unwrap())
+            });
+        });
+        latencies.print();
+    }
+}
+
+// The cost of each log codec, on small values: writing them, and reading them back from a
+// compacted, mapped log, where decoding is most of the work.
+fn codec_benchmark(c: &mut Criterion) {
+    let codecs = [
+        ("msgpack", Codec::MessagePack),
+        ("bincode", Codec::Bincode),
+        ("json_lines", Codec::JsonLines),
+    ];
+    for (name, codec) in codecs {
+        c.bench_function(&format!("kvs_{}_write", name), |b| {
+            b.iter_batched(
+                || {
+                    let dir = TempDir::new().unwrap();
+                    KvStore::open(dir.into_path())
+                        .unwrap()
+                        .with_codec(codec)
+                        .unwrap()
+                },
+                |store| {
+                    for op in Workload::writes(100000).ops().take(100) {
+                        op.apply(&store).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            );
+        });
+        c.bench_function(&format!("kvs_{}_read", name), |b| {
+            let dir = TempDir::new().unwrap();
+            let store = KvStore::open(dir.into_path())
+                .unwrap()
+                .with_codec(codec)
+                .unwrap()
+                .with_mmap_reads()
+                .unwrap();
+            let workload = Workload::reads(100);
+            workload.populate(&store).unwrap();
+            store.compact().unwrap();
+            let mut ops = workload.ops();
+            b.iter(|| ops.next().unwrap().apply(&store).unwrap());
+        });
+    }
 }
 
 criterion_group! {
     name = benches;
     config = Criterion::default().measurement_time(Duration::from_secs(46));
-    targets = write_benchmark, read_benchmark
+    targets = write_benchmark, read_benchmark, codec_benchmark
 }
 criterion_main!(benches);
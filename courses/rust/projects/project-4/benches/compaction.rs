@@ -0,0 +1,220 @@
+//! Sustained overwrites of a small key space on `KvStore` under several compaction
+//! settings, so they can be tuned with numbers. Criterion times the writes; after each
+//! setting the bench also prints:
+//! - write amplification: bytes passed to `write` by the process (from `/proc/self/io`,
+//!   so only on Linux) over the bytes of keys and values written
+//! - pauses: the slowest writes, which are the ones that compacted
+//! - disk usage: the most the logs took, sampled, and what they take at the end
+//!
+//! Tune with environment variables:
+//! - `KVS_COMPACTION_BENCH_THRESHOLDS`: compaction thresholds to run, in bytes, separated
+//!   by commas (default 262144,1048576,4194304)
+//! - `KVS_COMPACTION_BENCH_TOMBSTONES`: tombstone counts to run as horizons, at the default
+//!   threshold, separated by commas (default 1000)
+//! - `KVS_COMPACTION_BENCH_KEYS`: number of keys overwritten (default 1,000)
+//! - `KVS_COMPACTION_BENCH_VALUE_SIZE`: bytes of each value (default 256)
+//! - `KVS_COMPACTION_BENCH_REMOVE_EVERY`: make every this many writes a remove, 0 for none
+//!   (default 10)
+
+use std::env;
+use std::fs;
+use std::process;
+use std::time::Duration;
+use std::time::Instant;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use kvs::bench::Report;
+use kvs::workload::Op;
+use kvs::workload::Ops;
+use kvs::workload::Workload;
+use kvs::KvStore;
+use kvs::KvsEngine;
+use kvs::KvsError;
+use kvs::TombstoneHorizon;
+use tempfile::TempDir;
+
+// Check the disk usage every this many writes. Checking lists the logs, so not every write.
+const DISK_SAMPLE_EVERY: u64 = 256;
+
+fn env_or(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("{} must be a non-negative integer, got {:?}", name, value);
+            process::exit(2);
+        }),
+        Err(_) => default,
+    }
+}
+
+fn env_list_or(name: &str, default: &str) -> Vec<u64> {
+    let value = env::var(name).unwrap_or_else(|_| default.to_owned());
+    value
+        .split(',')
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| {
+            item.trim().parse().unwrap_or_else(|_| {
+                eprintln!(
+                    "{} must be integers separated by commas, got {:?}",
+                    name, value
+                );
+                process::exit(2);
+            })
+        })
+        .collect()
+}
+
+// Bytes this process has passed to `write` and the like, or None off Linux.
+fn bytes_written() -> Option<u64> {
+    let io = fs::read_to_string("/proc/self/io").ok()?;
+    io.lines()
+        .find_map(|line| line.strip_prefix("wchar: "))?
+        .parse()
+        .ok()
+}
+
+// One store written to for every iteration of a setting, and what was measured of it.
+struct Run {
+    _dir: TempDir,
+    store: KvStore,
+    ops: Ops,
+    remove_every: u64,
+    writes: u64,
+    user_bytes: u64,
+    written_bytes: Option<u64>,
+    latencies: Vec<Duration>,
+    peak_disk_bytes: u64,
+}
+
+impl Run {
+    fn new(store: KvStore, dir: TempDir, workload: &Workload, remove_every: u64) -> Self {
+        workload.populate(&store).unwrap();
+        Self {
+            _dir: dir,
+            store,
+            ops: workload.ops(),
+            remove_every,
+            writes: 0,
+            user_bytes: 0,
+            written_bytes: Some(0),
+            latencies: Vec::new(),
+            peak_disk_bytes: 0,
+        }
+    }
+
+    // Make `n` writes, returning the time they took, not counting the sampling between.
+    fn write(&mut self, n: u64) -> Duration {
+        let mut total = Duration::ZERO;
+        for _ in 0..n {
+            let (key, value) = match self.ops.next().unwrap() {
+                Op::Set(key, value) => (key, value),
+                Op::Get(_) => unreachable!("the workload only writes"),
+            };
+            self.writes += 1;
+            let remove = self.remove_every > 0 && self.writes.is_multiple_of(self.remove_every);
+            let before = bytes_written();
+            let start = Instant::now();
+            if remove {
+                self.user_bytes += key.len() as u64;
+                match self.store.remove(key) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(err) => panic!("remove failed: {}", err),
+                }
+            } else {
+                self.user_bytes += (key.len() + value.len()) as u64;
+                self.store.set(key, value).unwrap();
+            }
+            let elapsed = start.elapsed();
+            self.written_bytes = match (self.written_bytes, before, bytes_written()) {
+                (Some(total), Some(before), Some(after)) => Some(total + after - before),
+                _ => None,
+            };
+            total += elapsed;
+            self.latencies.push(elapsed);
+            if self.writes.is_multiple_of(DISK_SAMPLE_EVERY) {
+                let size = self.store.size_on_disk().unwrap();
+                self.peak_disk_bytes = self.peak_disk_bytes.max(size);
+            }
+        }
+        total
+    }
+
+    fn print(self, name: &str) {
+        let compactions = self.store.compactions();
+        let disk_bytes = self.store.size_on_disk().unwrap();
+        let amplification = match self.written_bytes {
+            Some(written) => format!("{:.2}", written as f64 / self.user_bytes.max(1) as f64),
+            None => "unknown".to_owned(),
+        };
+        let report = Report::new(self.latencies, Duration::ZERO);
+        println!("{}:", name);
+        println!("  writes               {}", self.writes);
+        println!("  compactions          {}", compactions);
+        println!("  write amplification  {}", amplification);
+        println!(
+            "  pauses               p99 {:.1?}, p99.9 {:.1?}, max {:.1?}",
+            report.percentile(0.99),
+            report.percentile(0.999),
+            report.percentile(1.0)
+        );
+        println!(
+            "  disk usage           peak {} bytes, final {} bytes",
+            self.peak_disk_bytes.max(disk_bytes),
+            disk_bytes
+        );
+    }
+}
+
+fn bench_setting(
+    c: &mut Criterion,
+    name: &str,
+    workload: &Workload,
+    remove_every: u64,
+    configure: impl Fn(KvStore) -> KvStore,
+) {
+    let dir = TempDir::new().unwrap();
+    let store = configure(KvStore::open(dir.path()).unwrap());
+    let mut run = Run::new(store, dir, workload, remove_every);
+    c.bench_function(name, |b| b.iter_custom(|iters| run.write(iters)));
+    run.print(name);
+}
+
+fn compaction_benchmark(c: &mut Criterion) {
+    let keys = env_or("KVS_COMPACTION_BENCH_KEYS", 1_000);
+    let value_size = env_or("KVS_COMPACTION_BENCH_VALUE_SIZE", 256) as usize;
+    let remove_every = env_or("KVS_COMPACTION_BENCH_REMOVE_EVERY", 10);
+    let workload = Workload::writes(keys).with_value_size(value_size);
+
+    let thresholds = env_list_or("KVS_COMPACTION_BENCH_THRESHOLDS", "262144,1048576,4194304");
+    for threshold in thresholds {
+        bench_setting(
+            c,
+            &format!("kvs_compaction_threshold_{}", threshold),
+            &workload,
+            remove_every,
+            |store| store.with_compaction_threshold(threshold),
+        );
+    }
+    for count in env_list_or("KVS_COMPACTION_BENCH_TOMBSTONES", "1000") {
+        bench_setting(
+            c,
+            &format!("kvs_compaction_tombstones_{}", count),
+            &workload,
+            remove_every,
+            |store| {
+                store.with_tombstone_horizon(TombstoneHorizon {
+                    max_age: None,
+                    max_count: Some(count),
+                })
+            },
+        );
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20).measurement_time(Duration::from_secs(20));
+    targets = compaction_benchmark
+}
+criterion_main!(benches);
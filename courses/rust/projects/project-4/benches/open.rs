@@ -0,0 +1,113 @@
+//! Measures how long `KvStore::open` takes to recover a large store, and exits with a
+//! non-zero status when it's slower than a threshold so CI can catch startup regressions.
+//!
+//! Tune with environment variables:
+//! - `KVS_OPEN_BENCH_KEYS`: number of keys to write (default 2,000,000)
+//! - `KVS_OPEN_BENCH_RUNS`: number of timed opens; the median is reported (default 5)
+//! - `KVS_OPEN_BENCH_MAX_MS`: fail if the median open takes longer (default 10,000)
+//! - `KVS_OPEN_BENCH_THREADS`: index thread counts to also time opens with, separated by
+//!   commas, to compare with the default of one per CPU (default 1). Only the default is
+//!   held to the threshold.
+
+use std::env;
+use std::process;
+use std::time::Duration;
+use std::time::Instant;
+
+use kvs::workload::Workload;
+use kvs::KvStore;
+use kvs::KvsEngine;
+use kvs::OpenOptions;
+use tempfile::TempDir;
+
+const BATCH_SIZE: u64 = 10_000;
+
+fn env_or(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("{} must be a non-negative integer, got {:?}", name, value);
+            process::exit(2);
+        }),
+        Err(_) => default,
+    }
+}
+
+fn env_list_or(name: &str, default: &str) -> Vec<u32> {
+    let value = env::var(name).unwrap_or_else(|_| default.to_owned());
+    value
+        .split(',')
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| {
+            item.trim().parse().unwrap_or_else(|_| {
+                eprintln!(
+                    "{} must be integers separated by commas, got {:?}",
+                    name, value
+                );
+                process::exit(2);
+            })
+        })
+        .collect()
+}
+
+fn populate(store: &KvStore, keys: u64) {
+    let workload = Workload::writes(keys);
+    for start in (0..keys).step_by(BATCH_SIZE as usize) {
+        let pairs = (start..keys.min(start + BATCH_SIZE))
+            .map(|n| (workload.key(n), workload.value(n)))
+            .collect();
+        store.set_batch(pairs).unwrap();
+    }
+}
+
+fn median_open(dir: &TempDir, runs: u64, keys: u64, options: OpenOptions) -> Duration {
+    let mut times: Vec<Duration> = (0..runs.max(1))
+        .map(|_| {
+            let start = Instant::now();
+            let store = KvStore::open_with(dir.path(), options).unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(store.len().unwrap(), keys);
+            elapsed
+        })
+        .collect();
+    times.sort();
+    times[times.len() / 2]
+}
+
+fn main() {
+    // `cargo bench` passes harness flags such as `--bench`; there's nothing to configure with them.
+    let keys = env_or("KVS_OPEN_BENCH_KEYS", 2_000_000);
+    let runs = env_or("KVS_OPEN_BENCH_RUNS", 5);
+    let max = Duration::from_millis(env_or("KVS_OPEN_BENCH_MAX_MS", 10_000));
+
+    let dir = TempDir::new().unwrap();
+    let start = Instant::now();
+    populate(&KvStore::open(dir.path()).unwrap(), keys);
+    println!("wrote {} keys in {:?}", keys, start.elapsed());
+
+    for index_threads in env_list_or("KVS_OPEN_BENCH_THREADS", "1") {
+        let options = OpenOptions {
+            index_threads,
+            ..OpenOptions::default()
+        };
+        let median = median_open(&dir, runs, keys, options);
+        println!(
+            "kvs_open (log replay, {} index threads): median {:?} over {} runs",
+            index_threads, median, runs
+        );
+    }
+
+    let options = OpenOptions::default();
+    let median = median_open(&dir, runs, keys, options);
+    println!(
+        "kvs_open (log replay, {} index threads): median {:?} over {} runs",
+        options.index_threads, median, runs
+    );
+
+    if median > max {
+        eprintln!(
+            "kvs_open regressed: median {:?} exceeds the {:?} threshold",
+            median, max
+        );
+        process::exit(1);
+    }
+}
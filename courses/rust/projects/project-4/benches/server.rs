@@ -0,0 +1,156 @@
+//! Measures a `KvsServer` end to end: each thread pool serving each engine, with as many
+//! client threads as the pool has threads, each sending a mix of gets and sets over its own
+//! connection. Thread counts run from 1 to twice the number of cores, so the reports show
+//! how throughput scales with threads.
+//!
+//! A pool serves a connection on one of its threads until the client hangs up, so there
+//! are never more clients than pool threads; the rest would wait for a thread forever.
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use kvs::thread_pool::ThreadPoolKind;
+use kvs::workload::Op;
+use kvs::workload::Ops;
+use kvs::workload::Workload;
+use kvs::KvStore;
+use kvs::KvsClient;
+use kvs::KvsEngine;
+use kvs::KvsServer;
+use kvs::SledKvsEngine;
+use kvs::WireFormat;
+use slog::o;
+use slog::Discard;
+use slog::Logger;
+use tempfile::TempDir;
+
+const KEY_SPACE: u64 = 1000;
+const READ_RATIO: f64 = 0.5;
+// Operations each client sends per iteration.
+const OPS_PER_CLIENT: u64 = 100;
+
+const POOLS: [ThreadPoolKind; 3] = [
+    ThreadPoolKind::Naive,
+    ThreadPoolKind::SharedQueue,
+    ThreadPoolKind::Rayon,
+];
+
+// 1, 2, 4, ... up to twice the number of cores.
+fn thread_counts() -> Vec<u32> {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get()) as u32;
+    let mut counts: Vec<u32> = (0..)
+        .map(|power| 1 << power)
+        .take_while(|&n| n < 2 * cores)
+        .collect();
+    counts.push(2 * cores);
+    counts
+}
+
+fn send(client: &mut KvsClient, op: Op) {
+    match op {
+        Op::Get(key) => drop(client.get(key).unwrap()),
+        Op::Set(key, value) => client.set(key, value).unwrap(),
+    }
+}
+
+fn bench_server<E: KvsEngine>(c: &mut Criterion, engine_name: &str, open: impl Fn(&TempDir) -> E) {
+    let workload = Workload::mixed(KEY_SPACE, READ_RATIO);
+    for pool in POOLS {
+        let mut group = c.benchmark_group(format!("{}_{}", engine_name, pool));
+        for threads in thread_counts() {
+            let dir = TempDir::new().unwrap();
+            let engine = open(&dir);
+            workload.populate(&engine).unwrap();
+            let (handle, addr) = KvsServer::new(engine, Logger::root(Discard, o!()))
+                .with_thread_pool(pool, threads)
+                .spawn(&SocketAddr::from(([127, 0, 0, 1], 0)))
+                .unwrap();
+            let mut clients: Vec<(KvsClient, Ops)> = (0..threads)
+                .map(|client_id| {
+                    let client = KvsClient::connect(&addr).unwrap();
+                    let ops = workload.clone().with_seed(client_id.into()).ops();
+                    (client, ops)
+                })
+                .collect();
+
+            group.throughput(Throughput::Elements(u64::from(threads) * OPS_PER_CLIENT));
+            group.bench_function(BenchmarkId::from_parameter(threads), |b| {
+                b.iter(|| {
+                    thread::scope(|scope| {
+                        for (client, ops) in clients.iter_mut() {
+                            scope.spawn(move || {
+                                for op in ops.take(OPS_PER_CLIENT as usize) {
+                                    send(client, op);
+                                }
+                            });
+                        }
+                    });
+                });
+            });
+
+            // Hang up first, or the pool's threads would keep serving the connections.
+            drop(clients);
+            handle.shutdown().unwrap();
+        }
+        group.finish();
+    }
+}
+
+fn server_benchmark(c: &mut Criterion) {
+    bench_server(c, "kvs", |dir| KvStore::open(dir.path()).unwrap());
+    bench_server(c, "sled", |dir| {
+        SledKvsEngine::new(sled::open(dir.path()).unwrap())
+    });
+    #[cfg(feature = "rocksdb")]
+    bench_server(c, "rocksdb", |dir| {
+        kvs::RocksKvsEngine::open(dir.path()).unwrap()
+    });
+}
+
+// The cost of each wire format, for one client on the kvs engine.
+fn wire_format_benchmark(c: &mut Criterion) {
+    let workload = Workload::mixed(KEY_SPACE, READ_RATIO);
+    let dir = TempDir::new().unwrap();
+    let engine = KvStore::open(dir.path()).unwrap();
+    workload.populate(&engine).unwrap();
+    let (handle, addr) = KvsServer::new(engine, Logger::root(Discard, o!()))
+        .spawn(&SocketAddr::from(([127, 0, 0, 1], 0)))
+        .unwrap();
+    let mut group = c.benchmark_group("kvs_wire_format");
+    group.throughput(Throughput::Elements(OPS_PER_CLIENT));
+    for format in [
+        WireFormat::MessagePack,
+        WireFormat::Json,
+        WireFormat::Bincode,
+    ] {
+        let mut client = KvsClient::builder(addr)
+            .wire_format(format)
+            .connect()
+            .unwrap();
+        let mut ops = workload.clone().ops();
+        group.bench_function(BenchmarkId::from_parameter(format), |b| {
+            b.iter(|| {
+                for op in ops.by_ref().take(OPS_PER_CLIENT as usize) {
+                    send(&mut client, op);
+                }
+            });
+        });
+    }
+    group.finish();
+    handle.shutdown().unwrap();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(5));
+    targets = server_benchmark, wire_format_benchmark
+}
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+fn main() {
+    // The gRPC service of proto/kvs.proto, generated only when the grpc feature serves it,
+    // with a protoc of its own so none need be installed.
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        tonic_build::compile_protos("proto/kvs.proto").unwrap();
+    }
+}
@@ -0,0 +1,24 @@
+//! Index a log of arbitrary bytes, and check that it's either read or refused as corrupt.
+//!
+//! Unlike `log_recovery`, which damages logs the store wrote, this starts from nothing, so
+//! it also reaches headers and records the store never writes. Run with
+//! `cargo +nightly fuzz run load_index`.
+#![no_main]
+
+use kvs::KvsError;
+use libfuzzer_sys::fuzz_target;
+use std::fs;
+use tempfile::TempDir;
+
+fuzz_target!(|bytes: &[u8]| {
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("0.kvs.log");
+    fs::write(&log, bytes).unwrap();
+    match kvs::fuzz::load_index(&log) {
+        Ok(_)
+        | Err(KvsError::Corruption { .. })
+        | Err(KvsError::UnsupportedLogFormat { .. })
+        | Err(KvsError::UnexpectedCommand) => {}
+        Err(err) => panic!("unclassified log error: {}", err),
+    }
+});
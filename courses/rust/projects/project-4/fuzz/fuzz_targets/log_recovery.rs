@@ -0,0 +1,75 @@
+//! Build a valid log, damage it, and check that recovery classifies the damage.
+//!
+//! Run with `cargo +nightly fuzz run log_recovery -- -timeout=10`; the timeout
+//! turns a recovery loop that never terminates into a reported failure.
+#![no_main]
+
+use kvs::{KvStore, KvsEngine, KvsError};
+use libfuzzer_sys::arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use std::fs;
+use tempfile::TempDir;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Set(u8, String),
+    Remove(u8),
+}
+
+#[derive(Arbitrary, Debug)]
+enum Damage {
+    /// XOR the byte at the position (modulo the log length) with the mask.
+    Flip(u16, u8),
+    /// Cut the log short at the position (modulo the log length).
+    Truncate(u16),
+    /// Append arbitrary bytes, as a torn or foreign write would.
+    Append(Vec<u8>),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    ops: Vec<Op>,
+    damage: Vec<Damage>,
+}
+
+fuzz_target!(|input: Input| {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for op in input.ops {
+            match op {
+                Op::Set(key, value) => store.set(key.to_string(), value).unwrap(),
+                Op::Remove(key) => match store.remove(key.to_string()) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(err) => panic!("remove failed: {}", err),
+                },
+            }
+        }
+    }
+
+    let log = temp_dir.path().join("0.kvs.log");
+    let mut bytes = fs::read(&log).unwrap();
+    for damage in input.damage {
+        match damage {
+            Damage::Flip(position, mask) if !bytes.is_empty() => {
+                let position = position as usize % bytes.len();
+                bytes[position] ^= mask;
+            }
+            Damage::Truncate(position) if !bytes.is_empty() => {
+                bytes.truncate(position as usize % bytes.len());
+            }
+            Damage::Append(extra) => bytes.extend(extra),
+            _ => {}
+        }
+    }
+    fs::write(&log, &bytes).unwrap();
+
+    let verified = KvStore::verify(temp_dir.path());
+    match &verified {
+        Ok(()) | Err(KvsError::Corruption { .. }) | Err(KvsError::UnsupportedLogFormat { .. }) => {}
+        Err(err) => panic!("unclassified recovery error: {}", err),
+    }
+    // A strict open must agree with verification about whether the log is usable.
+    let opened = KvStore::open_strict(temp_dir.path());
+    assert_eq!(verified.is_ok(), opened.is_ok());
+});
@@ -0,0 +1,37 @@
+//! Feed arbitrary bytes to the server's request decoders, and check that they only ever
+//! fail with an error a client can be told about.
+//!
+//! Run with `cargo +nightly fuzz run request_decoder`. Starting the corpus with a hello and
+//! a few framed requests gets the fuzzer past the handshake sooner.
+#![no_main]
+
+use kvs::KvsError;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (bool, &[u8])| {
+    let (budgeted, bytes) = input;
+    let budget = budgeted.then_some(1024);
+    match kvs::fuzz::decode_requests(bytes, budget) {
+        Ok(requests) => {
+            for request in requests {
+                // The server logs every request it decodes, and names it in its metrics.
+                let _ = format!("{:?}", request);
+                let _ = request.name();
+            }
+        }
+        Err(KvsError::IO(_))
+        | Err(KvsError::Decode(_))
+        | Err(KvsError::TransportCorruption)
+        | Err(KvsError::StringError(_)) => {}
+        Err(err) => panic!("unclassified request error: {}", err),
+    }
+    for result in [
+        kvs::fuzz::decode_resp_commands(bytes).map(drop),
+        kvs::fuzz::decode_http_requests(bytes).map(drop),
+    ] {
+        match result {
+            Ok(()) | Err(KvsError::IO(_)) | Err(KvsError::StringError(_)) => {}
+            Err(err) => panic!("unclassified request error: {}", err),
+        }
+    }
+});
@@ -0,0 +1,295 @@
+//! Who may make which requests of a `KvsServer` started with `KvsServer::with_acl`.
+//!
+//! A client names itself by sending a token with `Request::Authenticate`, which
+//! `KvsClientBuilder::token` does on every connection it opens. The token's grant decides
+//! what the connection's requests may do from then on: a role caps what kind of requests
+//! they may be, and key prefixes and buckets, if given, the keys they may be about. Requests
+//! on connections that sent no token, and those served over HTTP, RESP and gRPC, are held
+//! to the anonymous grant, and refused if there's none. A refused request isn't run, and
+//! fails with `KvsError::PermissionDenied`.
+//!
+//! Replicas following a primary with an ACL name themselves with the token given to
+//! `KvsServer::with_replication_token`, or are held to the anonymous grant without one.
+//! They're sent every key, so only a grant without key prefixes or buckets lets them follow.
+//!
+//! An ACL file is TOML, with an optional `[anonymous]` grant and a `[[token]]` table for
+//! each token:
+//!
+//! ```toml
+//! [anonymous]
+//! role = "read-only"
+//! key-prefixes = ["public/"]
+//!
+//! [[token]]
+//! token = "app-secret"
+//! role = "read-write"
+//! buckets = ["app"]
+//! ```
+
+use crate::KvsError;
+use crate::Request;
+use crate::Result;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// What kind of requests a grant allows, each role allowing those of the roles before it.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Requests that don't write, such as gets, key listings, stats and subscriptions.
+    ReadOnly,
+    /// Writes, leases and commits too.
+    ReadWrite,
+    /// Compaction, gossip and pinned requests too.
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReadOnly => write!(f, "read-only"),
+            Self::ReadWrite => write!(f, "read-write"),
+            Self::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// What a token, or a client without one, may do.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Grant {
+    pub role: Role,
+    /// Prefixes of the keys requests may be about, or empty for any key. Requests about
+    /// every key, such as `Request::Len` and `Request::Keys`, are refused unless it's empty,
+    /// and subscriptions must be to a prefix starting with one of them.
+    #[serde(default)]
+    pub key_prefixes: Vec<String>,
+    /// Buckets requests about keys may be made in, or None for any bucket and the server's
+    /// own namespace. Key prefixes apply to keys as they're named in the bucket.
+    #[serde(default)]
+    pub buckets: Option<Vec<String>>,
+}
+
+impl Grant {
+    /// A grant of `role` for any key in any bucket.
+    pub fn new(role: Role) -> Self {
+        Self {
+            role,
+            key_prefixes: Vec::new(),
+            buckets: None,
+        }
+    }
+
+    /// Return `KvsError::PermissionDenied` if the grant doesn't allow `request`, made in
+    /// `bucket`.
+    fn check(&self, request: &Request, bucket: Option<&str>) -> Result<()> {
+        match request {
+            Request::Tagged(_, request) | Request::Traced(_, request) => {
+                return self.check(request, bucket)
+            }
+            Request::InBucket(name, request) => return self.check(request, Some(name)),
+            _ => {}
+        }
+        let needed = role_needed(request);
+        if self.role < needed {
+            return Err(denied(format!(
+                "{} requests need the {} role",
+                request.name(),
+                needed
+            )));
+        }
+        if let Request::Pinned(request) = request {
+            return self.check(request, bucket);
+        }
+        if !is_about_keys(request) {
+            return Ok(());
+        }
+
+        if let Some(buckets) = &self.buckets {
+            match bucket {
+                Some(bucket) if buckets.iter().any(|allowed| allowed == bucket) => {}
+                Some(bucket) => return Err(denied(format!("no access to bucket {:?}", bucket))),
+                None => return Err(denied("requests must be made in a bucket".to_owned())),
+            }
+        }
+        if self.key_prefixes.is_empty() {
+            return Ok(());
+        }
+        let keys = match request {
+            Request::AcquireLease(key, _)
+            | Request::RenewLease(key, _, _)
+            | Request::ReleaseLease(key, _)
+            | Request::Subscribe(key) => vec![key.as_str()],
+            Request::FindByValue(_)
+            | Request::Len
+            | Request::SlotKeys(_, _)
+            | Request::Keys { .. } => {
+                return Err(denied(format!(
+                    "{} requests are about every key, and access is to only some",
+                    request.name()
+                )))
+            }
+            request => request.routing_keys(),
+        };
+        match keys.into_iter().find(|key| {
+            !self
+                .key_prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+        }) {
+            Some(key) => Err(denied(format!("no access to key {:?}", key))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The grants of a server's tokens, and of clients without one.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    tokens: HashMap<String, Grant>,
+    anonymous: Option<Grant>,
+}
+
+impl Acl {
+    /// An ACL granting nothing to anyone.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grant `grant` to clients that authenticate with `token`.
+    pub fn with_token(mut self, token: impl Into<String>, grant: Grant) -> Self {
+        self.tokens.insert(token.into(), grant);
+        self
+    }
+
+    /// Grant `grant` to clients without a token.
+    pub fn with_anonymous(mut self, grant: Grant) -> Self {
+        self.anonymous = Some(grant);
+        self
+    }
+
+    /// Read the ACL file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let invalid = |err: String| {
+            KvsError::StringError(format!("invalid ACL file {}: {}", path.display(), err))
+        };
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            KvsError::StringError(format!(
+                "unable to read ACL file {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        let file: AclFile = toml::from_str(&contents).map_err(|err| invalid(err.to_string()))?;
+        let mut acl = Self {
+            tokens: HashMap::new(),
+            anonymous: file.anonymous,
+        };
+        for entry in file.token {
+            let grant = Grant {
+                role: entry.role,
+                key_prefixes: entry.key_prefixes,
+                buckets: entry.buckets,
+            };
+            if acl.tokens.insert(entry.token, grant).is_some() {
+                return Err(invalid("a token is listed more than once".to_owned()));
+            }
+        }
+        Ok(acl)
+    }
+
+    /// Return `KvsError::PermissionDenied` if `token` isn't known.
+    pub(crate) fn authenticate(&self, token: &str) -> Result<()> {
+        if !self.tokens.contains_key(token) {
+            return Err(denied("unknown token".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Return `KvsError::PermissionDenied` if the grant of `token`, or the anonymous grant
+    /// without one, doesn't let a replica follow: that's sent every key in every bucket.
+    pub(crate) fn check_replica(&self, token: Option<&str>) -> Result<()> {
+        let grant = match token {
+            Some(token) => self.tokens.get(token),
+            None => self.anonymous.as_ref(),
+        };
+        match grant {
+            Some(grant) if grant.key_prefixes.is_empty() && grant.buckets.is_none() => Ok(()),
+            Some(_) => Err(denied(
+                "replicas are sent every key, and access is to only some".to_owned(),
+            )),
+            None if token.is_some() => Err(denied("unknown token".to_owned())),
+            None => Err(denied("a token is needed".to_owned())),
+        }
+    }
+
+    /// Return `KvsError::PermissionDenied` if the grant of `token`, or the anonymous grant
+    /// without one, doesn't allow `request`.
+    pub(crate) fn check(&self, token: Option<&str>, request: &Request) -> Result<()> {
+        // Anyone may say who they are.
+        if let Request::Authenticate(_) = request {
+            return Ok(());
+        }
+        let grant = match token {
+            Some(token) => self.tokens.get(token),
+            None => self.anonymous.as_ref(),
+        };
+        match grant {
+            Some(grant) => grant.check(request, None),
+            None => Err(denied("a token is needed".to_owned())),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct AclFile {
+    anonymous: Option<Grant>,
+    #[serde(default)]
+    token: Vec<TokenEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct TokenEntry {
+    token: String,
+    role: Role,
+    #[serde(default)]
+    key_prefixes: Vec<String>,
+    #[serde(default)]
+    buckets: Option<Vec<String>>,
+}
+
+fn denied(msg: String) -> KvsError {
+    KvsError::PermissionDenied(msg)
+}
+
+// The least role allowed to make `request`, wrapping aside.
+fn role_needed(request: &Request) -> Role {
+    match request {
+        Request::Compact | Request::Gossip { .. } | Request::Pinned(_) => Role::Admin,
+        Request::AcquireLease(_, _)
+        | Request::RenewLease(_, _, _)
+        | Request::ReleaseLease(_, _) => Role::ReadWrite,
+        request if request.is_write() => Role::ReadWrite,
+        _ => Role::ReadOnly,
+    }
+}
+
+// Whether `request` is about keys, so that key prefixes and buckets apply to it.
+fn is_about_keys(request: &Request) -> bool {
+    !matches!(
+        request,
+        Request::Begin
+            | Request::Commit
+            | Request::Rollback
+            | Request::Stats
+            | Request::Compact
+            | Request::Ping
+            | Request::Gossip { .. }
+            | Request::ClusterMap
+            | Request::Authenticate(_)
+    )
+}
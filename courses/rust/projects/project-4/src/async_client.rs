@@ -0,0 +1,171 @@
+//! A client for tokio applications, speaking the same protocol as `kvs::KvsClient` without
+//! blocking a runtime thread. Enabled by the `async` feature.
+
+use crate::frame;
+use crate::frame::WireFormat;
+use crate::handshake::Capabilities;
+use crate::handshake::Hello;
+use crate::handshake::PROTOCOL_VERSION;
+use crate::protocol::Request;
+use crate::protocol::Response;
+use crate::KvsError;
+use crate::Result;
+use bytes::Buf;
+use bytes::BufMut;
+use bytes::BytesMut;
+use futures::SinkExt;
+use futures::StreamExt;
+use rmp_serde::decode;
+use rmp_serde::Deserializer;
+use rmp_serde::Serializer;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_util::codec::Decoder;
+use tokio_util::codec::Encoder;
+use tokio_util::codec::Framed;
+
+/// Encodes requests and decodes responses for a `Framed` connection, after the handshake:
+/// msgpack messages back to back, or each in a checksummed frame if the connection asked
+/// for `Capabilities::CHECKSUMS`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KvsCodec {
+    checksums: bool,
+}
+
+impl KvsCodec {
+    pub fn new(checksums: bool) -> Self {
+        Self { checksums }
+    }
+}
+
+impl Encoder<Request> for KvsCodec {
+    type Error = KvsError;
+
+    fn encode(&mut self, request: Request, dst: &mut BytesMut) -> Result<()> {
+        if self.checksums {
+            dst.extend_from_slice(&frame::encode(WireFormat::MessagePack, &request)?);
+        } else {
+            request.serialize(&mut Serializer::new(dst.writer()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for KvsCodec {
+    type Item = Response;
+    type Error = KvsError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Response>> {
+        if self.checksums {
+            if src.len() < frame::HEADER_LEN {
+                return Ok(None);
+            }
+            let (len, checksum) =
+                frame::parse_header(src[..frame::HEADER_LEN].try_into().unwrap())?;
+            if src.len() < frame::HEADER_LEN + len {
+                src.reserve(frame::HEADER_LEN + len - src.len());
+                return Ok(None);
+            }
+            let frame = src.split_to(frame::HEADER_LEN + len);
+            let payload = &frame[frame::HEADER_LEN..];
+            return frame::decode_payload(payload, checksum, WireFormat::MessagePack).map(Some);
+        }
+        // Messages aren't delimited, so try to decode one, and wait for more bytes if they
+        // run out first.
+        let mut cursor = Cursor::new(&src[..]);
+        match Response::deserialize(&mut Deserializer::new(&mut cursor)) {
+            Ok(response) => {
+                let len = cursor.position() as usize;
+                src.advance(len);
+                Ok(Some(response))
+            }
+            Err(decode::Error::InvalidMarkerRead(err))
+            | Err(decode::Error::InvalidDataRead(err))
+                if err.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// An async client for a kvs server. Requests are made one at a time, each awaiting its
+/// response; it doesn't follow redirects or retry.
+pub struct KvsClient {
+    connection: Framed<TcpStream, KvsCodec>,
+}
+
+impl KvsClient {
+    pub async fn connect(addr: &SocketAddr) -> Result<Self> {
+        Self::open(addr, false).await
+    }
+
+    /// Connect, sending and receiving every message in a frame with a CRC-32. A damaged
+    /// response fails its call with `KvsError::TransportCorruption`.
+    pub async fn connect_with_checksums(addr: &SocketAddr) -> Result<Self> {
+        Self::open(addr, true).await
+    }
+
+    async fn open(addr: &SocketAddr, checksums: bool) -> Result<Self> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let mut wanted = Capabilities::empty();
+        if checksums {
+            wanted = wanted | Capabilities::CHECKSUMS;
+        }
+        let mut hello = Vec::new();
+        Hello::new(wanted).write(&mut hello)?;
+        stream.write_all(&hello).await?;
+        let mut hello = [0; 14];
+        stream.read_exact(&mut hello).await?;
+        let server = Hello::read(&mut &hello[..])?;
+        if server.version != PROTOCOL_VERSION {
+            return Err(KvsError::ProtocolMismatch {
+                client: PROTOCOL_VERSION,
+                server: server.version,
+            });
+        }
+        Ok(Self {
+            connection: Framed::new(stream, KvsCodec::new(checksums)),
+        })
+    }
+
+    async fn call(&mut self, request: Request) -> Result<Response> {
+        self.connection.send(request).await?;
+        let response = match self.connection.next().await {
+            Some(response) => response?,
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        };
+        match response.into_result()? {
+            Response::FrameCorrupted => Err(KvsError::TransportCorruption),
+            response => Ok(response),
+        }
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.call(Request::Get(key)).await? {
+            Response::GetOk(value) => Ok(value),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.call(Request::Set(key, value)).await? {
+            Response::SetOk(()) => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        match self.call(Request::Remove(key)).await? {
+            Response::RemoveOk(()) => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+}
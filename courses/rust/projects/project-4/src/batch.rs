@@ -0,0 +1,88 @@
+use crate::engines::KvsEngine;
+use crate::engines::SizeLimits;
+use crate::error::KvsError;
+use crate::error::Result;
+use crate::protocol::ErrorCode;
+use crossbeam::channel;
+use crossbeam::channel::Receiver;
+use crossbeam::channel::Sender;
+use slog::debug;
+use slog::Logger;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+// A write's error, split into its code and message, since the same one goes to every
+// connection with a set in the batch and a `KvsError` can't be cloned.
+type SetResult = std::result::Result<(), (ErrorCode, String)>;
+
+struct PendingSet {
+    key: String,
+    value: String,
+    done: Sender<SetResult>,
+}
+
+/// Collects `set` calls from many connections and applies them to the engine
+/// in batches, at most one batch per window.
+#[derive(Clone)]
+pub struct Batcher {
+    tx: Sender<PendingSet>,
+    size_limits: SizeLimits,
+}
+
+impl Batcher {
+    /// Start the batching thread. It exits once every `Batcher` clone has been dropped.
+    /// Sets over `size_limits` fail on their own rather than join a batch.
+    pub fn spawn<E: KvsEngine>(
+        engine: E,
+        window: Duration,
+        size_limits: SizeLimits,
+        log: Logger,
+    ) -> Result<Self> {
+        let (tx, rx) = channel::unbounded();
+        thread::Builder::new().spawn(move || run_batches(engine, window, rx, log))?;
+        Ok(Self { tx, size_limits })
+    }
+
+    /// Queue a set and wait until the batch containing it has been written.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        // Checked here, so a pair the engine would refuse can't fail the batch for the
+        // sets of other connections.
+        self.size_limits.check(&key, Some(&value))?;
+        let (done, result) = channel::bounded(1);
+        self.tx
+            .send(PendingSet { key, value, done })
+            .map_err(|_| KvsError::StringError("write batcher has stopped".to_owned()))?;
+        match result.recv() {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err((code, msg))) => Err(code.into_error(msg)),
+            Err(_) => Err(KvsError::StringError(
+                "write batcher has stopped".to_owned(),
+            )),
+        }
+    }
+}
+
+fn run_batches<E: KvsEngine>(engine: E, window: Duration, rx: Receiver<PendingSet>, log: Logger) {
+    // Block for the first write of a batch, then gather whatever else arrives within the window.
+    while let Ok(first) = rx.recv() {
+        let deadline = Instant::now() + window;
+        let mut pending = vec![first];
+        while let Ok(set) = rx.recv_deadline(deadline) {
+            pending.push(set);
+        }
+
+        debug!(&log, "writing batch"; "size" => pending.len());
+        let (pairs, waiters): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .map(|set| ((set.key, set.value), set.done))
+            .unzip();
+        let result = engine
+            .set_batch(pairs)
+            .map_err(|err| ErrorCode::split(&err));
+        for done in waiters {
+            // The connection may have gone away; there's nobody left to tell.
+            let _ = done.send(result.clone());
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Measurements for benchmarking `KvsEngine` implementations, and servers, running the
+//! workloads of `crate::workload`.
+//!
+//! The criterion benches and `kvs-bench` use these, so an engine measured with them is
+//! measured the same way as the engines in this crate.
+//!
+//! Only built with the `bench` feature, which is off by default.
+
+use crate::workload::Op;
+use crate::workload::Workload;
+use crate::KvsClient;
+use crate::KvsEngine;
+use crate::Result;
+use std::fmt;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How long each thread of a run goes on for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunLength {
+    /// This many operations.
+    Ops(usize),
+    /// Until this long after the run started.
+    Time(Duration),
+}
+
+/// Run operations of `workload` on each of `threads` threads at once for `length`, timing
+/// every operation. Each thread draws its operations from its own seed.
+pub fn run<E: KvsEngine>(
+    engine: &E,
+    workload: &Workload,
+    threads: usize,
+    length: RunLength,
+) -> Result<Report> {
+    run_threads(workload, threads, length, || {
+        let engine = engine.clone();
+        Ok(move |op: Op| op.apply(&engine))
+    })
+}
+
+/// Run operations of `workload` against the server at `addr` over `connections`
+/// connections at once, each from a thread of its own, for `length`, timing every
+/// operation from the client's side. The connections are made before the run starts.
+pub fn run_server(
+    addr: &SocketAddr,
+    workload: &Workload,
+    connections: usize,
+    length: RunLength,
+) -> Result<Report> {
+    run_threads(workload, connections, length, || {
+        let mut client = KvsClient::connect(addr)?;
+        Ok(move |op: Op| match op {
+            Op::Get(key) => client.get(key).map(drop),
+            Op::Set(key, value) => client.set(key, value),
+        })
+    })
+}
+
+// Run `workload` on `threads` threads, each handing its operations to a runner of its own
+// made by `runner`.
+fn run_threads<R>(
+    workload: &Workload,
+    threads: usize,
+    length: RunLength,
+    mut runner: impl FnMut() -> Result<R>,
+) -> Result<Report>
+where
+    R: FnMut(Op) -> Result<()> + Send,
+{
+    let runners = (0..threads).map(|_| runner()).collect::<Result<Vec<R>>>()?;
+    let capacity = match length {
+        RunLength::Ops(ops) => ops,
+        RunLength::Time(_) => 0,
+    };
+    let start = Instant::now();
+    let latencies = thread::scope(|scope| {
+        let handles: Vec<_> = runners
+            .into_iter()
+            .enumerate()
+            .map(|(thread_id, mut runner)| {
+                let ops = workload
+                    .clone()
+                    .with_seed(workload.seed.wrapping_add(thread_id as u64))
+                    .ops();
+                scope.spawn(move || -> Result<Vec<Duration>> {
+                    let mut latencies = Vec::with_capacity(capacity);
+                    for (done, op) in ops.enumerate() {
+                        let finished = match length {
+                            RunLength::Ops(ops) => done >= ops,
+                            RunLength::Time(time) => start.elapsed() >= time,
+                        };
+                        if finished {
+                            break;
+                        }
+                        let op_start = Instant::now();
+                        runner(op)?;
+                        latencies.push(op_start.elapsed());
+                    }
+                    Ok(latencies)
+                })
+            })
+            .collect();
+        let mut latencies = Vec::with_capacity(threads * capacity);
+        for handle in handles {
+            latencies.extend(handle.join().expect("benchmark thread panicked")?);
+        }
+        Ok::<_, crate::KvsError>(latencies)
+    })?;
+    Ok(Report::new(latencies, start.elapsed()))
+}
+
+/// The outcome of a benchmark run: how long it took and how long each operation took.
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// Wall-clock time of the whole run.
+    pub elapsed: Duration,
+    // Sorted, shortest first.
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// Make a report from the latencies of every operation of a run taking `elapsed`.
+    pub fn new(mut latencies: Vec<Duration>, elapsed: Duration) -> Self {
+        latencies.sort_unstable();
+        Self { elapsed, latencies }
+    }
+
+    pub fn ops(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// Operations completed per second of the run.
+    pub fn throughput(&self) -> f64 {
+        self.ops() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Return the latency that `fraction` of operations took at most, e.g. 0.99 for the
+    /// 99th percentile. Return zero if the run had no operations.
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (fraction.clamp(0.0, 1.0) * self.ops() as f64).ceil() as usize;
+        self.latencies[rank.saturating_sub(1)]
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let total: u128 = self.latencies.iter().map(Duration::as_nanos).sum();
+        Duration::from_nanos((total / self.ops() as u128) as u64)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "ops         {}", self.ops())?;
+        writeln!(f, "elapsed     {:.3?}", self.elapsed)?;
+        writeln!(f, "throughput  {:.0} ops/s", self.throughput())?;
+        writeln!(f, "mean        {:.1?}", self.mean())?;
+        writeln!(f, "p50         {:.1?}", self.percentile(0.5))?;
+        writeln!(f, "p95         {:.1?}", self.percentile(0.95))?;
+        writeln!(f, "p99         {:.1?}", self.percentile(0.99))?;
+        write!(f, "max         {:.1?}", self.percentile(1.0))
+    }
+}
@@ -0,0 +1,472 @@
+use clap::Parser;
+use clap::Subcommand;
+use clap::ValueEnum;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use std::error::Error;
+use std::result::Result;
+
+use kvs::data_dir;
+use kvs::ops;
+use kvs::ops::ApplySummary;
+use kvs::ops::Op;
+use kvs::ring;
+use kvs::ring::HashRing;
+use kvs::KvStore;
+use kvs::KvsClient;
+use kvs::KvsEngine;
+use kvs::OpenOptions;
+use kvs::SledKvsEngine;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Administer a group of kvs servers", long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum EngineName {
+    Kvs,
+    Sled,
+}
+
+impl fmt::Display for EngineName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kvs => write!(f, "kvs"),
+            Self::Sled => write!(f, "sled"),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Move keys between servers after adding or removing a server, so that every key lives
+    /// on the server its slot hashes to. Writes to the keys being moved should be paused while
+    /// this runs, since a key written to its old server after being moved is left behind.
+    /// Members of a cluster serve the moves themselves rather than redirect them to owners.
+    Rebalance {
+        /// Servers the keys are spread over now, separated by commas.
+        #[arg(long, name = "FROM", value_delimiter = ',', required = true)]
+        from: Vec<SocketAddr>,
+
+        /// Servers the keys should be spread over, separated by commas.
+        #[arg(long, name = "TO", value_delimiter = ',', required = true)]
+        to: Vec<SocketAddr>,
+
+        /// Number of slots keys hash into. Must match what clients of the group use.
+        #[arg(long, name = "SLOTS", default_value_t = ring::DEFAULT_SLOT_COUNT)]
+        slots: u32,
+
+        /// Move at most this many keys per second.
+        #[arg(long, name = "KEYS-PER-SECOND")]
+        rate: Option<u32>,
+
+        /// Print the slots that would move without moving any keys.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Move a server's data directory, verifying every file copied. Stop the server first.
+    /// An interrupted move carries on from where it stopped when run again, and the server
+    /// refuses to start on the old directory once the move is done.
+    MoveData {
+        /// Directory the data is in now.
+        #[arg(long, name = "FROM")]
+        from: PathBuf,
+
+        /// Directory to move it to. Must be empty or hold an interrupted move.
+        #[arg(long, name = "TO")]
+        to: PathBuf,
+    },
+    /// Write every key and value of a server's data directory to standard output, in a
+    /// format `restore` reads back into either engine. A kvs directory is read without
+    /// being modified, so its server can keep running; stop a sled server first.
+    Dump {
+        /// The server's data directory.
+        #[arg(long, name = "DATA-DIR", default_value = ".")]
+        data_dir: PathBuf,
+
+        /// Engine the data is in. Defaults to the one the directory records, or kvs.
+        #[arg(long, value_enum, name = "ENGINE-NAME")]
+        engine: Option<EngineName>,
+    },
+    /// Set every key and value of a dump read from standard input in a server's data
+    /// directory, alongside any keys it already holds. Stop the server first.
+    Restore {
+        /// The server's data directory, which is created if it doesn't exist.
+        #[arg(long, name = "DATA-DIR", default_value = ".")]
+        data_dir: PathBuf,
+
+        /// Engine to restore into. Defaults to the one the directory records, or kvs.
+        #[arg(long, value_enum, name = "ENGINE-NAME")]
+        engine: Option<EngineName>,
+    },
+    /// Move a server's data from one engine to the other, so it can be started with the
+    /// other engine. Every pair is copied and counted before the directory is switched over;
+    /// if anything fails, it still holds the old engine's data. Stop the server first.
+    Migrate {
+        /// The server's data directory.
+        #[arg(long, name = "DATA-DIR", default_value = ".")]
+        data_dir: PathBuf,
+
+        /// Engine the data is in now. Defaults to the one the directory records.
+        #[arg(long, value_enum, name = "FROM")]
+        from: Option<EngineName>,
+
+        /// Engine to move the data to.
+        #[arg(long, value_enum, name = "TO")]
+        to: EngineName,
+    },
+    /// Print how many keys a kvs data directory holds, how big its logs and index are and
+    /// how much of the logs compaction would reclaim. The directory is read without being
+    /// modified, so its server can keep running.
+    Stats {
+        /// The server's data directory.
+        #[arg(long, name = "DATA-DIR", default_value = ".")]
+        data_dir: PathBuf,
+    },
+    /// Read every log of a kvs data directory and report damaged logs, missing bloom
+    /// filters and leftover files. Fails if a log is damaged, unless repairing. Stop the
+    /// server before repairing.
+    Fsck {
+        /// The server's data directory.
+        #[arg(long, name = "DATA-DIR", default_value = ".")]
+        data_dir: PathBuf,
+
+        /// Rewrite every value that can still be read into a clean compacted log, and
+        /// remove the old logs. Writes in the damaged part of a log are lost.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Apply a file of `set <key> <value>` and `rm <key>` lines to a server's data
+    /// directory, in order, through one open store. Stop the server first.
+    Apply {
+        /// File of writes, one per line.
+        #[arg(long, name = "FILE")]
+        file: PathBuf,
+
+        /// The server's data directory, which is created if it doesn't exist.
+        #[arg(long, name = "DATA-DIR", default_value = ".")]
+        data_dir: PathBuf,
+
+        /// Engine the data is in. Defaults to the one the directory records, or kvs.
+        #[arg(long, value_enum, name = "ENGINE-NAME")]
+        engine: Option<EngineName>,
+    },
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    match cli.command {
+        Commands::Rebalance {
+            from,
+            to,
+            slots,
+            rate,
+            dry_run,
+        } => {
+            if slots == 0 {
+                return Err("--slots must be positive".into());
+            }
+            if rate == Some(0) {
+                return Err("--rate must be positive".into());
+            }
+            rebalance(&from, &to, slots, rate, dry_run)?;
+        }
+        Commands::MoveData { from, to } => move_data(&from, &to)?,
+        Commands::Dump { data_dir, engine } => {
+            let engine = data_dir_engine(&data_dir, engine)?;
+            let pairs = match engine {
+                EngineName::Kvs => dump(open_existing(&data_dir, true)?)?,
+                EngineName::Sled => dump(SledKvsEngine::new(sled::open(&data_dir)?))?,
+            };
+            eprintln!("dumped {} pairs", pairs);
+        }
+        Commands::Restore { data_dir, engine } => {
+            let engine = data_dir_engine(&data_dir, engine)?;
+            fs::create_dir_all(&data_dir)?;
+            // Pin the engine first, so a server won't open a half-restored directory with
+            // the other one.
+            data_dir::set_engine(&data_dir, &engine.to_string())?;
+            let pairs = match engine {
+                EngineName::Kvs => restore(KvStore::open(&data_dir)?)?,
+                EngineName::Sled => restore(SledKvsEngine::new(sled::open(&data_dir)?))?,
+            };
+            eprintln!("restored {} pairs", pairs);
+        }
+        Commands::Migrate { data_dir, from, to } => {
+            if data_dir::engine(&data_dir)?.is_none() && from.is_none() {
+                return Err(format!(
+                    "{} doesn't record an engine; give it with --from",
+                    data_dir.display()
+                )
+                .into());
+            }
+            let from = data_dir_engine(&data_dir, from)?;
+            if from == to {
+                return Err(format!("the data is already in {}", to).into());
+            }
+            let pairs = match from {
+                EngineName::Kvs => migrate(
+                    &data_dir,
+                    open_existing(&data_dir, false)?,
+                    SledKvsEngine::new(sled::open(&data_dir)?),
+                )?,
+                EngineName::Sled => migrate(
+                    &data_dir,
+                    SledKvsEngine::new(sled::open(&data_dir)?),
+                    KvStore::open(&data_dir)?,
+                )?,
+            };
+            data_dir::set_engine(&data_dir, &to.to_string())?;
+            eprintln!(
+                "done; migrated {} pairs from {} to {}; the {} files can be deleted",
+                pairs, from, to, from
+            );
+        }
+        Commands::Stats { data_dir } => {
+            if data_dir_engine(&data_dir, None)? != EngineName::Kvs {
+                return Err("stats are only kept for kvs data".into());
+            }
+            let stats = open_existing(&data_dir, true)?.stats()?;
+            println!("keys = {}", stats.keys);
+            println!("logs = {}", stats.logs);
+            println!("log-bytes = {}", stats.log_bytes);
+            println!("dead-bytes = {}", stats.dead_bytes);
+            println!("index-bytes = {}", stats.index_bytes);
+            match stats.last_compaction {
+                Some(time) => {
+                    let ago = SystemTime::now().duration_since(time).unwrap_or_default();
+                    println!("last-compaction = {}s ago", ago.as_secs());
+                }
+                None => println!("last-compaction = never"),
+            }
+        }
+        Commands::Fsck { data_dir, repair } => {
+            if data_dir_engine(&data_dir, None)? != EngineName::Kvs {
+                return Err("only kvs data can be checked".into());
+            }
+            let report = KvStore::fsck(&data_dir, repair)?;
+            for log in &report.logs {
+                match &log.damage {
+                    Some(damage) => println!(
+                        "log {}: {} bytes, damaged: {}",
+                        log.log_number, log.bytes, damage
+                    ),
+                    None => println!("log {}: {} bytes, ok", log.log_number, log.bytes),
+                }
+            }
+            for log_number in &report.missing_filters {
+                println!("log {}: no bloom filter", log_number);
+            }
+            for file in &report.orphaned_files {
+                println!("orphaned file {}", file.display());
+            }
+            if report.repaired {
+                println!("repaired; rewrote {} keys", report.keys);
+            } else if report.is_damaged() {
+                return Err("damaged logs found; run again with --repair to fix them".into());
+            } else {
+                println!("ok; {} keys", report.keys);
+            }
+        }
+        Commands::Apply {
+            file,
+            data_dir,
+            engine,
+        } => {
+            let ops = ops::parse(BufReader::new(fs::File::open(&file)?))?;
+            let engine = data_dir_engine(&data_dir, engine)?;
+            fs::create_dir_all(&data_dir)?;
+            data_dir::set_engine(&data_dir, &engine.to_string())?;
+            let summary = match engine {
+                EngineName::Kvs => apply(KvStore::open(&data_dir)?, ops)?,
+                EngineName::Sled => apply(SledKvsEngine::new(sled::open(&data_dir)?), ops)?,
+            };
+            println!(
+                "applied {} sets and {} removes",
+                summary.sets, summary.removes
+            );
+        }
+    }
+    Ok(())
+}
+
+fn rebalance(
+    from: &[SocketAddr],
+    to: &[SocketAddr],
+    slot_count: u32,
+    rate: Option<u32>,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let moves = ring::plan_moves(
+        &HashRing::new(from, slot_count),
+        &HashRing::new(to, slot_count),
+    );
+    let mut slots_by_route: BTreeMap<(SocketAddr, SocketAddr), Vec<u32>> = BTreeMap::new();
+    for slot_move in &moves {
+        slots_by_route
+            .entry((slot_move.from, slot_move.to))
+            .or_default()
+            .push(slot_move.slot);
+    }
+    eprintln!(
+        "{} of {} slots move between {} pairs of servers",
+        moves.len(),
+        slot_count,
+        slots_by_route.len()
+    );
+
+    let interval = rate.map(|rate| Duration::from_secs(1) / rate);
+    let mut next_move = Instant::now();
+    let mut moved = 0;
+    for ((source_addr, target_addr), slots) in slots_by_route {
+        if dry_run {
+            println!("{} -> {}: {} slots", source_addr, target_addr, slots.len());
+            continue;
+        }
+        // Members of a cluster would redirect keys to the member owning them, which the
+        // source may no longer be, or the target not be yet.
+        let mut source = KvsClient::builder(source_addr).pinned(true).connect()?;
+        let mut target = KvsClient::builder(target_addr).pinned(true).connect()?;
+        let keys = source.slot_keys(slot_count, slots)?;
+        eprintln!(
+            "moving {} keys from {} to {}",
+            keys.len(),
+            source_addr,
+            target_addr
+        );
+        for (done, key) in keys.into_iter().enumerate() {
+            if let Some(interval) = interval {
+                let now = Instant::now();
+                if next_move > now {
+                    thread::sleep(next_move - now);
+                }
+                next_move = next_move.max(now) + interval;
+            }
+            // The key is copied before it's removed, so it's never missing from both servers.
+            if let Some(value) = source.get(key.clone())? {
+                target.set(key.clone(), value)?;
+                source.remove(key)?;
+                moved += 1;
+            }
+            if (done + 1) % 1000 == 0 {
+                eprintln!("moved {} keys", moved);
+            }
+        }
+    }
+    if !dry_run {
+        eprintln!("done; moved {} keys", moved);
+    }
+    Ok(())
+}
+
+fn move_data(from: &Path, to: &Path) -> Result<(), Box<dyn Error>> {
+    let summary = data_dir::move_data(from, to, |file, len| {
+        eprintln!("copied {} ({} bytes)", file.display(), len);
+        Ok(())
+    })?;
+    if summary.resumed_files > 0 {
+        eprintln!(
+            "{} files were already copied by an earlier run",
+            summary.resumed_files
+        );
+    }
+    eprintln!(
+        "done; copied {} files ({} bytes) to {}",
+        summary.copied_files,
+        summary.copied_bytes,
+        to.display()
+    );
+    Ok(())
+}
+
+// Open the kvs store in `data_dir`, failing rather than creating one if there's none, so a
+// mistyped path isn't taken for an empty store.
+fn open_existing(data_dir: &Path, read_only: bool) -> kvs::Result<KvStore> {
+    let options = OpenOptions {
+        create_if_missing: false,
+        read_only,
+        ..OpenOptions::default()
+    };
+    KvStore::open_with(data_dir, options)
+}
+
+// Return the engine of the data directory, checking it against the one asked for, if any.
+fn data_dir_engine(
+    data_dir: &Path,
+    engine: Option<EngineName>,
+) -> Result<EngineName, Box<dyn Error>> {
+    let recorded = match data_dir::engine(data_dir)?.as_deref() {
+        Some("kvs") => Some(EngineName::Kvs),
+        Some("sled") => Some(EngineName::Sled),
+        Some(other) => return Err(format!("unrecognized engine {:?}", other).into()),
+        None => None,
+    };
+    match (recorded, engine) {
+        (Some(recorded), Some(engine)) if recorded != engine => Err(format!(
+            "{} holds {} data, not {}",
+            data_dir.display(),
+            recorded,
+            engine
+        )
+        .into()),
+        (recorded, engine) => Ok(engine.or(recorded).unwrap_or(EngineName::Kvs)),
+    }
+}
+
+fn dump<E: KvsEngine>(engine: E) -> Result<u64, Box<dyn Error>> {
+    Ok(engine.export(BufWriter::new(io::stdout().lock()))?)
+}
+
+fn restore<E: KvsEngine>(engine: E) -> Result<u64, Box<dyn Error>> {
+    Ok(engine.import(BufReader::new(io::stdin().lock()))?)
+}
+
+fn apply<E: KvsEngine>(engine: E, ops: Vec<(usize, Op)>) -> Result<ApplySummary, Box<dyn Error>> {
+    Ok(ops::apply(ops, |op| match op {
+        Op::Set(key, value) => engine.set(key, value),
+        Op::Remove(key) => engine.remove(key),
+    })?)
+}
+
+// Copy every pair of `from` into `to` through a dump file, and check that they all arrived.
+// Whatever `to` held before, perhaps from an earlier migration, is removed first.
+fn migrate<F: KvsEngine, T: KvsEngine>(
+    data_dir: &Path,
+    from: F,
+    to: T,
+) -> Result<u64, Box<dyn Error>> {
+    for key in to.keys()? {
+        to.remove(key)?;
+    }
+    to.compact()?;
+
+    let dump_path = data_dir.join("kvs.migrate.dump");
+    let exported = from.export(BufWriter::new(fs::File::create(&dump_path)?))?;
+    let imported = to.import(BufReader::new(fs::File::open(&dump_path)?))?;
+    fs::remove_file(&dump_path)?;
+    let (from_len, to_len) = (from.len()?, to.len()?);
+    if exported != from_len || imported != exported || to_len != imported {
+        return Err(format!(
+            "counts don't match: {} pairs, {} exported, {} imported, {} after import",
+            from_len, exported, imported, to_len
+        )
+        .into());
+    }
+    Ok(imported)
+}
@@ -0,0 +1,213 @@
+use clap::Parser;
+use clap::ValueEnum;
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::result::Result;
+use std::time::Duration;
+
+use kvs::bench;
+use kvs::bench::RunLength;
+use kvs::workload::KeyDistribution;
+use kvs::workload::Workload;
+use kvs::Durability;
+use kvs::KvStore;
+use kvs::KvsClient;
+use kvs::KvsEngine;
+#[cfg(feature = "rocksdb")]
+use kvs::RocksKvsEngine;
+use kvs::SledKvsEngine;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Benchmark a kvs engine or a running kvs-server", long_about = None)]
+struct Cli {
+    /// Benchmark the kvs-server at this address over TCP instead of an engine in this
+    /// process.
+    #[arg(long, name = "IP-PORT", conflicts_with_all = ["ENGINE-NAME", "PATH", "DURABILITY"])]
+    addr: Option<SocketAddr>,
+
+    #[arg(long, value_enum, name = "ENGINE-NAME", default_value_t = EngineName::Kvs)]
+    engine: EngineName,
+
+    /// Fraction of operations that are gets; the rest are sets.
+    #[arg(long, name = "RATIO", default_value_t = 0.5)]
+    read_ratio: f64,
+
+    /// Number of distinct keys. They are all set before the run starts, except on a server
+    /// without --populate.
+    #[arg(long, name = "KEYS", default_value_t = 10_000)]
+    key_space: u64,
+
+    /// How keys are picked: uniform, zipfian, with key0 the most popular, or sequential.
+    #[arg(long, value_enum, name = "DISTRIBUTION", default_value_t = Distribution::Uniform)]
+    keys: Distribution,
+
+    /// Exponent of the zipfian distribution, between 0 and 1; the higher, the fewer keys
+    /// get most of the operations.
+    #[arg(long, name = "EXPONENT", default_value_t = 0.99)]
+    zipf_exponent: f64,
+
+    /// Length of every value written, in bytes, or of the shortest with --max-value-size.
+    #[arg(long, name = "BYTES", default_value_t = 16)]
+    value_size: usize,
+
+    /// Write values of lengths spread evenly from --value-size up to this many bytes.
+    #[arg(long, name = "MAX-BYTES")]
+    max_value_size: Option<usize>,
+
+    /// Number of threads running operations at once, each with a connection of its own to a
+    /// server.
+    #[arg(
+        long,
+        visible_alias = "connections",
+        name = "THREADS",
+        default_value_t = 1
+    )]
+    threads: usize,
+
+    /// Number of operations each thread runs.
+    #[arg(long, name = "OPS", default_value_t = 10_000)]
+    ops: usize,
+
+    /// Run for this many seconds instead of a number of operations.
+    #[arg(long, name = "SECONDS", conflicts_with = "OPS")]
+    duration: Option<u64>,
+
+    /// Set every key of the key space on the server before the run.
+    #[arg(long, requires = "IP-PORT")]
+    populate: bool,
+
+    /// Seed for the choice of keys.
+    #[arg(long, name = "SEED", default_value_t = 0)]
+    seed: u64,
+
+    /// When the sled or rocksdb engine's writes reach the disk: "every-write" or
+    /// "background".
+    #[arg(long, name = "DURABILITY", default_value_t = Durability::EveryWrite)]
+    sled_durability: Durability,
+
+    /// Keep the engine's files in this directory instead of a temporary one, which is
+    /// removed afterwards.
+    #[arg(long, name = "PATH")]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Distribution {
+    Uniform,
+    Zipfian,
+    Sequential,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum EngineName {
+    Kvs,
+    Sled,
+    #[cfg(feature = "rocksdb")]
+    Rocksdb,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    if !(0.0..=1.0).contains(&cli.read_ratio) {
+        return Err("--read-ratio must be between 0 and 1".into());
+    }
+    if cli.threads == 0 {
+        return Err("--threads must be at least 1".into());
+    }
+    let (dir, temporary) = match &cli.dir {
+        Some(dir) => (dir.clone(), false),
+        None => (
+            env::temp_dir().join(format!("kvs-bench-{}", process::id())),
+            true,
+        ),
+    };
+    let max_value_size = cli.max_value_size.unwrap_or(cli.value_size);
+    if max_value_size < cli.value_size {
+        return Err("--max-value-size must be at least --value-size".into());
+    }
+    let keys = match cli.keys {
+        Distribution::Uniform => KeyDistribution::Uniform,
+        Distribution::Zipfian if cli.zipf_exponent > 0.0 && cli.zipf_exponent < 1.0 => {
+            KeyDistribution::Zipfian(cli.zipf_exponent)
+        }
+        Distribution::Zipfian => return Err("--zipf-exponent must be between 0 and 1".into()),
+        Distribution::Sequential => KeyDistribution::Sequential,
+    };
+    let workload = Workload::mixed(cli.key_space, cli.read_ratio)
+        .with_keys(keys)
+        .with_value_sizes(cli.value_size..=max_value_size)
+        .with_seed(cli.seed);
+    if let Some(addr) = cli.addr {
+        return run_server(addr, &workload, &cli);
+    }
+    let result = match cli.engine {
+        EngineName::Kvs => KvStore::open(&dir)
+            .map_err(Into::into)
+            .and_then(|engine| run(engine, &workload, &cli)),
+        EngineName::Sled => {
+            SledKvsEngine::with_config(sled::Config::new().path(&dir), cli.sled_durability)
+                .map_err(Into::into)
+                .and_then(|engine| run(engine, &workload, &cli))
+        }
+        #[cfg(feature = "rocksdb")]
+        EngineName::Rocksdb => RocksKvsEngine::open(&dir)
+            .map(|engine| engine.with_durability(cli.sled_durability))
+            .map_err(Into::into)
+            .and_then(|engine| run(engine, &workload, &cli)),
+    };
+    if temporary {
+        fs::remove_dir_all(&dir)?;
+    }
+    result
+}
+
+fn run<E: KvsEngine>(engine: E, workload: &Workload, cli: &Cli) -> Result<(), Box<dyn Error>> {
+    eprintln!("populating {} keys", workload.key_space);
+    workload.populate(&engine)?;
+    eprintln!(
+        "running {} on each of {} threads",
+        describe(cli),
+        cli.threads
+    );
+    let report = bench::run(&engine, workload, cli.threads, run_length(cli))?;
+    println!("{}", report);
+    Ok(())
+}
+
+fn run_server(addr: SocketAddr, workload: &Workload, cli: &Cli) -> Result<(), Box<dyn Error>> {
+    if cli.populate {
+        eprintln!("populating {} keys", workload.key_space);
+        let mut client = KvsClient::connect(&addr)?;
+        for n in 0..workload.key_space {
+            client.set(workload.key(n), workload.value(n))?;
+        }
+    }
+    eprintln!(
+        "running {} on each of {} connections to {}",
+        describe(cli),
+        cli.threads,
+        addr
+    );
+    let report = bench::run_server(&addr, workload, cli.threads, run_length(cli))?;
+    println!("{}", report);
+    Ok(())
+}
+
+fn run_length(cli: &Cli) -> RunLength {
+    match cli.duration {
+        Some(secs) => RunLength::Time(Duration::from_secs(secs)),
+        None => RunLength::Ops(cli.ops),
+    }
+}
+
+fn describe(cli: &Cli) -> String {
+    match cli.duration {
+        Some(secs) => format!("ops for {}s", secs),
+        None => format!("{} ops", cli.ops),
+    }
+}
@@ -1,69 +1,673 @@
 use clap::Parser;
 use clap::Subcommand;
 
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
 
 use std::error::Error;
 use std::result::Result;
 
+use kvs::ops;
+use kvs::ops::Op;
+use kvs::EntryMeta;
 use kvs::KvsClient;
+use kvs::KvsError;
+use kvs::RetryPolicy;
+use kvs::WireFormat;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde::Deserialize;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Without --addr, the server is taken from $KVS_ADDR, then from the addr of \
+                  ~/.config/kvs/client.toml, which may also set socket, timeout-ms, checksums and token."
+)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Give up on connecting, sending or receiving after this many milliseconds.
+    #[arg(long, global = true, name = "MILLISECONDS")]
+    timeout_ms: Option<u64>,
+
+    /// Retry requests failing with a transient network error up to this many times.
+    #[arg(long, global = true, name = "RETRIES", default_value_t = 0)]
+    retries: u32,
+
+    /// Protect every message with a checksum, sending read-only requests again when one is damaged.
+    #[arg(long, global = true)]
+    checksums: bool,
+
+    /// Encode messages as msgpack, json or bincode.
+    #[arg(long, global = true, name = "FORMAT", default_value_t = WireFormat::MessagePack)]
+    wire_format: WireFormat,
+
+    /// Work on the keys of this bucket rather than the server's own namespace.
+    #[arg(long, global = true, name = "BUCKET")]
+    bucket: Option<String>,
+
+    /// Make requests with this token's grant, on a server with an ACL.
+    #[arg(
+        long,
+        global = true,
+        name = "TOKEN",
+        env = "KVS_TOKEN",
+        hide_env_values = true
+    )]
+    token: Option<String>,
+
+    /// Connect over the Unix domain socket at this path rather than to --addr.
+    #[cfg(unix)]
+    #[arg(long, global = true, name = "SOCKET-PATH")]
+    socket: Option<PathBuf>,
 }
 
 const DEFAULT_ADDR: &str = "127.0.0.1:4000";
 const ADDR_NAME: &str = "IP-PORT";
+// The server to connect to when --addr isn't given.
+const ADDR_ENV: &str = "KVS_ADDR";
+
+/// Defaults for options not given on the command line or in the environment, read from
+/// `client.toml` in the `kvs` directory of the user's config directory, if it exists.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigFile {
+    addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    socket: Option<PathBuf>,
+    timeout_ms: Option<u64>,
+    checksums: Option<bool>,
+    token: Option<String>,
+}
+
+impl ConfigFile {
+    // Read the config file, or return the defaults if there's none.
+    fn load() -> Result<Self, Box<dyn Error>> {
+        let path = match config_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Self::default()),
+        };
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| format!("unable to read config file {}: {}", path.display(), err))?;
+        Ok(toml::from_str(&contents)
+            .map_err(|err| format!("invalid config file {}: {}", path.display(), err))?)
+    }
+}
+
+// `$XDG_CONFIG_HOME/kvs/client.toml`, or `~/.config/kvs/client.toml` without it.
+fn config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("kvs").join("client.toml"))
+}
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Set the value of a string key to a string. Print an error and return a non-zero exit code on failure.
     Set {
         key: String,
-        value: String,
-        #[arg(long, name = ADDR_NAME, default_value = DEFAULT_ADDR)]
-        addr: SocketAddr,
+        #[arg(required_unless_present = "stdin")]
+        value: Option<String>,
+        /// Read the value from standard input, as it is, rather than from the command line.
+        /// It may span lines, but must be UTF-8.
+        #[arg(long, conflicts_with = "value")]
+        stdin: bool,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
     },
 
     /// Get the string value of a given string key. Print an error and return a non-zero exit code on failure.
     Get {
         key: String,
-        #[arg(long, name = ADDR_NAME, default_value = DEFAULT_ADDR)]
-        addr: SocketAddr,
+        /// Write the value as it is, without a newline, and fail if the key isn't set
+        /// rather than saying so on standard output.
+        #[arg(long)]
+        raw: bool,
+        /// Read from one of the --replica servers, picked at random, falling back on the
+        /// server at --addr only if none can be reached. Replicas may be a little behind.
+        #[arg(long, requires = "REPLICA-IP-PORT")]
+        prefer_replica: bool,
+        /// A read-only replica of the server, for --prefer-replica. Repeat for each replica.
+        #[arg(long = "replica", name = "REPLICA-IP-PORT")]
+        replicas: Vec<SocketAddr>,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
     },
 
     /// Remove a given key. Print an error and return a non-zero exit code on failure.
     #[command(name = "rm")]
     Remove {
         key: String,
-        #[arg(long, name = ADDR_NAME, default_value = DEFAULT_ADDR)]
-        addr: SocketAddr,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Append to the value of a key, treating a missing key as empty, and print the length
+    /// of the new value in bytes.
+    Append {
+        key: String,
+        suffix: String,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Print part of the value of a key: LEN bytes from byte OFFSET, fewer if the value
+    /// ends sooner.
+    GetRange {
+        key: String,
+        offset: u64,
+        len: u64,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Print the value of a key once it's set, waiting for a write to set it if it isn't
+    /// yet. Print an error and return a non-zero exit code if the wait times out. The
+    /// answer is read for up to --timeout-ms longer than the wait.
+    WaitGet {
+        key: String,
+        /// How long to wait for the key to be set.
+        #[arg(long, value_name = "MILLISECONDS", default_value_t = 30_000)]
+        wait_ms: u64,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Take the lock KEY for OWNER, if no other owner holds it, and print whether it was
+    /// taken. Taking a lock the owner holds extends it.
+    Lock {
+        key: String,
+        owner: String,
+        /// How long the lock is held for, unless it's released sooner.
+        #[arg(long, value_name = "MILLISECONDS", default_value_t = 30_000)]
+        ttl_ms: u64,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Release the lock KEY if OWNER holds it, and print whether it did.
+    Unlock {
+        key: String,
+        owner: String,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Print the keys set to exactly VALUE, one to a line, in key order.
+    FindByValue {
+        value: String,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Move the value of a key to another key, replacing any value it had, in one write.
+    /// Print an error and return a non-zero exit code if the key isn't set.
+    #[command(name = "mv", visible_alias = "rename")]
+    Rename {
+        old_key: String,
+        new_key: String,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Add to the integer value of a key, treating a missing key as 0, and print the new value.
+    Incr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Subtract from the integer value of a key, treating a missing key as 0, and print the new value.
+    Decr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Print the size, version and last write time of a key's value, without fetching it.
+    /// Times are in milliseconds, and the stamp in nanoseconds, since the Unix epoch; those
+    /// the server doesn't know are left out.
+    Stat {
+        key: String,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Print whether a given key is set, without fetching its value.
+    Exists {
+        key: String,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Print the number of keys that are set.
+    Count {
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Print every key that is set, in order, fetching them a page at a time.
+    Keys {
+        /// Keys to fetch per request.
+        #[arg(long, name = "SIZE", default_value_t = 1000)]
+        page_size: u32,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Apply a file of `set <key> <value>` and `rm <key>` lines, in order, over one
+    /// connection, and print how many of each were applied.
+    Apply {
+        /// File of writes, one per line.
+        #[arg(long, name = "FILE")]
+        file: PathBuf,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Open one connection and run commands typed interactively.
+    Repl {
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Print the server's metrics in Prometheus text format.
+    Stats {
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Check the server is up, and print its version, engine, uptime and how many pings it
+    /// has answered.
+    Ping {
+        /// Print a JSON object rather than a line per field.
+        #[arg(long)]
+        json: bool,
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
     },
+
+    /// Have the server reclaim the space held by overwritten and removed values now.
+    Compact {
+        #[arg(long, name = ADDR_NAME, env = ADDR_ENV)]
+        addr: Option<SocketAddr>,
+    },
+}
+
+fn main() {
+    // Print errors as the REPL does, rather than as their `Debug` form.
+    if let Err(err) = run() {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut cli = Cli::parse();
+    let config = ConfigFile::load()?;
+    #[cfg(unix)]
+    {
+        cli.socket = cli.socket.take().or(config.socket);
+    }
+    cli.timeout_ms = cli.timeout_ms.or(config.timeout_ms);
+    cli.checksums |= config.checksums.unwrap_or(false);
+    cli.token = cli.token.take().or(config.token);
+    let default_addr = config.addr.unwrap_or_else(|| DEFAULT_ADDR.parse().unwrap());
+
+    let options = |addr: Option<SocketAddr>| {
+        let mut builder = KvsClient::builder(addr.unwrap_or(default_addr))
+            .retry_policy(RetryPolicy::with_max_retries(cli.retries))
+            .checksums(cli.checksums)
+            .wire_format(cli.wire_format);
+        if let Some(timeout_ms) = cli.timeout_ms {
+            builder = builder.timeout(Duration::from_millis(timeout_ms));
+        }
+        if let Some(bucket) = &cli.bucket {
+            builder = builder.bucket(bucket.clone());
+        }
+        if let Some(token) = &cli.token {
+            builder = builder.token(token.clone());
+        }
+        #[cfg(unix)]
+        if let Some(path) = &cli.socket {
+            builder = builder.socket(path.clone());
+        }
+        builder
+    };
+    let connect = |addr: Option<SocketAddr>| options(addr).connect();
 
     match cli.command {
-        Commands::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(&addr)?;
+        Commands::Set {
+            key,
+            value,
+            stdin: _,
+            addr,
+        } => {
+            let value = match value {
+                Some(value) => value,
+                None => read_value()?,
+            };
+            let mut client = connect(addr)?;
             client.set(key, value)?;
         }
-        Commands::Get { key, addr } => {
-            let mut client = KvsClient::connect(&addr)?;
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
+        Commands::Get {
+            key,
+            raw,
+            prefer_replica,
+            replicas,
+            addr,
+        } => {
+            let mut replica = None;
+            if prefer_replica {
+                // Start from a replica picked at random, so gets are spread across them.
+                let start = RandomState::new().build_hasher().finish() as usize;
+                replica = (0..replicas.len())
+                    .map(|i| replicas[(start + i) % replicas.len()])
+                    .find_map(|replica| connect(Some(replica)).ok());
+            }
+            let mut client = match replica {
+                Some(client) => client,
+                None => connect(addr)?,
+            };
+            match (client.get(key)?, raw) {
+                (Some(value), true) => {
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(value.as_bytes())?;
+                    stdout.flush()?;
+                }
+                (Some(value), false) => println!("{}", value),
+                (None, true) => return Err(KvsError::KeyNotFound.into()),
+                (None, false) => println!("Key not found"),
             }
         }
         Commands::Remove { key, addr } => {
-            let mut client = KvsClient::connect(&addr)?;
+            let mut client = connect(addr)?;
             client.remove(key)?;
         }
+        Commands::Append { key, suffix, addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.append(key, suffix)?);
+        }
+        Commands::GetRange {
+            key,
+            offset,
+            len,
+            addr,
+        } => {
+            let mut client = connect(addr)?;
+            match client.get_range(key, offset, len)? {
+                Some(range) => println!("{}", range),
+                None => println!("Key not found"),
+            }
+        }
+        Commands::WaitGet { key, wait_ms, addr } => {
+            let wait = Duration::from_millis(wait_ms);
+            let mut options = options(addr);
+            // The server answers once the wait is over, so the answer is read for longer.
+            if let Some(timeout_ms) = cli.timeout_ms {
+                options = options.read_timeout(wait + Duration::from_millis(timeout_ms));
+            }
+            let mut client = options.connect()?;
+            match client.wait_get(key, wait)? {
+                Some(value) => println!("{}", value),
+                None => {
+                    return Err(
+                        KvsError::StringError("timed out waiting for the key".to_owned()).into(),
+                    )
+                }
+            }
+        }
+        Commands::Lock {
+            key,
+            owner,
+            ttl_ms,
+            addr,
+        } => {
+            let mut client = connect(addr)?;
+            let ttl = Duration::from_millis(ttl_ms);
+            println!("{}", client.acquire_lock(key, owner, ttl)?);
+        }
+        Commands::Unlock { key, owner, addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.release_lock(key, owner)?);
+        }
+        Commands::FindByValue { value, addr } => {
+            let mut client = connect(addr)?;
+            for key in client.find_by_value(value)? {
+                println!("{}", key);
+            }
+        }
+        Commands::Rename {
+            old_key,
+            new_key,
+            addr,
+        } => {
+            let mut client = connect(addr)?;
+            client.rename(old_key, new_key)?;
+        }
+        Commands::Incr { key, delta, addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.incr(key, delta)?);
+        }
+        Commands::Decr { key, delta, addr } => {
+            let mut client = connect(addr)?;
+            let delta = delta.checked_neg().ok_or("the amount is out of range")?;
+            println!("{}", client.incr(key, delta)?);
+        }
+        Commands::Stat { key, addr } => {
+            let mut client = connect(addr)?;
+            match client.metadata(key)? {
+                Some(meta) => print_meta(&meta),
+                None => println!("Key not found"),
+            }
+        }
+        Commands::Exists { key, addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.contains(key)?);
+        }
+        Commands::Count { addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.len()?);
+        }
+        Commands::Keys { page_size, addr } => {
+            let mut client = connect(addr)?;
+            let mut cursor = None;
+            loop {
+                let (keys, next) = client.keys(cursor, page_size)?;
+                for key in keys {
+                    println!("{}", key);
+                }
+                cursor = next;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+        }
+        Commands::Apply { file, addr } => {
+            let ops = ops::parse(BufReader::new(File::open(file)?))?;
+            let mut client = connect(addr)?;
+            let summary = ops::apply(ops, |op| match op {
+                Op::Set(key, value) => client.set(key, value),
+                Op::Remove(key) => client.remove(key),
+            })?;
+            println!(
+                "applied {} sets and {} removes",
+                summary.sets, summary.removes
+            );
+        }
+        Commands::Repl { addr } => {
+            let client = connect(addr)?;
+            repl(client)?;
+        }
+        Commands::Stats { addr } => {
+            let mut client = connect(addr)?;
+            print!("{}", client.stats()?.to_prometheus());
+        }
+        Commands::Ping { json, addr } => {
+            let mut client = connect(addr)?;
+            let pong = client.ping()?;
+            let uptime_ms = pong.uptime.as_millis() as u64;
+            if json {
+                let pong = serde_json::json!({
+                    "version": pong.version,
+                    "engine": pong.engine,
+                    "uptime_ms": uptime_ms,
+                    "pings": pong.pings,
+                });
+                println!("{}", pong);
+            } else {
+                println!("version = {}", pong.version);
+                println!("engine = {}", pong.engine);
+                println!("uptime-ms = {}", uptime_ms);
+                println!("pings = {}", pong.pings);
+            }
+        }
+        Commands::Compact { addr } => {
+            let mut client = connect(addr)?;
+            client.compact()?;
+        }
+    }
+    Ok(())
+}
+
+fn print_meta(meta: &EntryMeta) {
+    println!("size = {}", meta.size);
+    println!("version = {}", meta.version);
+    if let Some(written) = meta.written {
+        let written = written.duration_since(UNIX_EPOCH).unwrap_or_default();
+        println!("modified-ms = {}", written.as_millis());
+    }
+    if let Some(stamp) = meta.stamp {
+        println!("stamp-ns = {}", stamp);
+    }
+}
+
+// Read a value from standard input, keeping every byte, newlines and all.
+fn read_value() -> Result<String, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    io::stdin().lock().read_to_end(&mut bytes)?;
+    Ok(String::from_utf8(bytes).map_err(|_| "values must be UTF-8")?)
+}
+
+const REPL_HELP: &str = "\
+get <key>          print the value of a key
+set <key> <value>  set a key; the value is the rest of the line
+rm <key>           remove a key
+mv <key> <new>     move the value of a key to another key
+append <key> <s>   append to the value of a key; the suffix is the rest of the line
+find <value>       print the keys set to a value; the value is the rest of the line
+incr <key> [n]     add n, or 1, to the integer value of a key
+decr <key> [n]     subtract n, or 1, from the integer value of a key
+stat <key>         print the size, version and last write time of a key
+exists <key>       print whether a key is set
+count              print the number of keys
+stats              print the server's metrics
+compact            reclaim the space of overwritten and removed values
+help               print this message
+quit               leave the repl";
+
+fn repl(mut client: KvsClient) -> Result<(), Box<dyn Error>> {
+    let mut editor = DefaultEditor::new()?;
+    loop {
+        let line = match editor.readline("kvs> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+
+        let mut words = line.splitn(3, char::is_whitespace);
+        let result = match (words.next(), words.next(), words.next()) {
+            (Some("get"), Some(key), None) => client.get(key.to_owned()).map(|value| {
+                println!("{}", value.as_deref().unwrap_or("Key not found"));
+            }),
+            (Some("set"), Some(key), Some(value)) => {
+                client.set(key.to_owned(), value.trim_start().to_owned())
+            }
+            (Some("rm"), Some(key), None) => client.remove(key.to_owned()),
+            (Some("append"), Some(key), Some(suffix)) => client
+                .append(key.to_owned(), suffix.trim_start().to_owned())
+                .map(|len| println!("{}", len)),
+            (Some("find"), Some(_), _) => client
+                .find_by_value(line["find".len()..].trim_start().to_owned())
+                .map(|keys| {
+                    for key in keys {
+                        println!("{}", key);
+                    }
+                }),
+            (Some("mv"), Some(key), Some(new_key)) => {
+                client.rename(key.to_owned(), new_key.trim().to_owned())
+            }
+            (Some(op @ ("incr" | "decr")), Some(key), delta) => {
+                let delta = match delta.map_or(Ok(1), |delta| delta.trim().parse::<i64>()) {
+                    Ok(delta) if op == "decr" => delta.checked_neg(),
+                    Ok(delta) => Some(delta),
+                    Err(_) => None,
+                };
+                match delta {
+                    Some(delta) => client
+                        .incr(key.to_owned(), delta)
+                        .map(|value| println!("{}", value)),
+                    None => {
+                        eprintln!("the amount must be an integer in range");
+                        Ok(())
+                    }
+                }
+            }
+            (Some("stat"), Some(key), None) => {
+                client.metadata(key.to_owned()).map(|meta| match meta {
+                    Some(meta) => print_meta(&meta),
+                    None => println!("Key not found"),
+                })
+            }
+            (Some("exists"), Some(key), None) => client
+                .contains(key.to_owned())
+                .map(|found| println!("{}", found)),
+            (Some("count"), None, None) => client.len().map(|len| println!("{}", len)),
+            (Some("stats"), None, None) => client
+                .stats()
+                .map(|stats| print!("{}", stats.to_prometheus())),
+            (Some("compact"), None, None) => client.compact(),
+            (Some("help"), None, None) => {
+                println!("{}", REPL_HELP);
+                Ok(())
+            }
+            (Some("quit"), None, None) | (Some("exit"), None, None) => break,
+            _ => {
+                eprintln!("unrecognized command; type `help` for a list of commands");
+                Ok(())
+            }
+        };
+        if let Err(err) = result {
+            eprintln!("error: {}", err);
+        }
     }
     Ok(())
 }
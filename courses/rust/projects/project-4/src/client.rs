@@ -1,62 +1,1013 @@
+use crate::client_cache::CacheHandle;
+use crate::cluster::ClusterMap;
+use crate::cluster::Member;
 use crate::error::KvsError;
 use crate::error::Result;
+use crate::frame;
+use crate::frame::WireFormat;
+use crate::handshake::Capabilities;
+use crate::handshake::Hello;
+use crate::handshake::PROTOCOL_VERSION;
+use crate::metrics::ServerStats;
+use crate::metrics::StatsFrame;
+use crate::protocol::ErrorCode;
+use crate::protocol::Pong;
 use crate::protocol::Request;
 use crate::protocol::Response;
+use crate::trace::Span;
+use crate::transport::Endpoint;
+use crate::transport::Transport;
+use crate::watch::KeyChange;
+use crate::EntryMeta;
+use crate::TraceId;
 use rmp_serde::decode::Deserializer;
 use rmp_serde::decode::ReadReader;
 use rmp_serde::encode::Serializer;
 use serde::Deserialize;
 use serde::Serialize;
+use std::error;
+use std::io;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Write;
+use std::mem;
 use std::net::SocketAddr;
-use std::net::TcpStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// How a client retries requests that fail with a transient I/O error.
+///
+/// A retry reconnects and sends the request again, so a write whose response was
+/// lost may be applied twice.
+///
+/// A write the server refused while compacting is retried on the same connection, and no
+/// sooner than the server asked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay before the first retry; it doubles with every further retry.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Never retry.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    /// Retry up to `max_retries` times, starting at a 50ms delay and backing off to at most 2s.
+    pub fn with_max_retries(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+
+    fn run<T>(&self, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match op() {
+                Err(err) if retries < self.max_retries && is_transient(&err) => {
+                    // A server pushing back says how long to wait at least.
+                    match err {
+                        KvsError::Throttled(retry_after) => thread::sleep(backoff.max(retry_after)),
+                        _ => thread::sleep(backoff),
+                    }
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+fn is_transient(err: &KvsError) -> bool {
+    if let KvsError::ServerBusy | KvsError::Throttled(_) | KvsError::RateLimited = err {
+        return true;
+    }
+    // The I/O error may be wrapped by an encode or decode error.
+    let mut source: Option<&(dyn error::Error + 'static)> = Some(err);
+    while let Some(err) = source {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            return matches!(
+                io_err.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::UnexpectedEof
+            );
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Configures and connects a `KvsClient`. Timeouts default to none, so operations
+/// block until the server answers.
+#[derive(Clone, Debug)]
+pub struct KvsClientBuilder {
+    endpoint: Endpoint,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    checksums: bool,
+    wire_format: WireFormat,
+    bucket: Option<String>,
+    pinned: bool,
+    token: Option<String>,
+}
+
+impl KvsClientBuilder {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            endpoint: Endpoint::Tcp(addr),
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            retry_policy: RetryPolicy::none(),
+            checksums: false,
+            wire_format: WireFormat::MessagePack,
+            bucket: None,
+            pinned: false,
+            token: None,
+        }
+    }
+
+    /// Connect over the Unix domain socket at `path` rather than to the builder's address.
+    /// Redirects to the leader of a replicated group still go over TCP.
+    #[cfg(unix)]
+    pub fn socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.endpoint = Endpoint::Unix(path.into());
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the connect, read and write timeouts at once.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.connect_timeout(timeout)
+            .read_timeout(timeout)
+            .write_timeout(timeout)
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Send and receive every message in a frame with a CRC-32, so damage on the way is
+    /// caught. A damaged request is sent again, as is a read-only request whose response
+    /// was damaged; otherwise the call fails with `KvsError::TransportCorruption`.
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Encode messages in `format` rather than msgpack. JSON and bincode are always sent in
+    /// checksummed frames, as `checksums` would send them. Calls fail with
+    /// `KvsError::Unsupported` on servers that don't speak the format, which the client
+    /// waits for the server's hello to check before its first request.
+    pub fn wire_format(mut self, format: WireFormat) -> Self {
+        self.wire_format = format;
+        self
+    }
+
+    /// Work on the keys of the named bucket rather than the server's own namespace; see
+    /// `Bucket`. Fails with `KvsError::Unsupported` on servers without buckets.
+    pub fn bucket(mut self, name: impl Into<String>) -> Self {
+        self.bucket = Some(name.into());
+        self
+    }
+
+    /// Have the server serve every request itself, even one about keys another member of
+    /// its cluster owns, rather than redirect it; see `kvs::cluster`. Meant for moving keys
+    /// between members, as `kvs rebalance` does.
+    pub fn pinned(mut self, pinned: bool) -> Self {
+        self.pinned = pinned;
+        self
+    }
+
+    /// Authenticate with `token` on every connection, so requests are made with its grant
+    /// on servers with an ACL; see `kvs::acl`. Connecting fails with
+    /// `KvsError::PermissionDenied` if the server doesn't know the token, and with
+    /// `KvsError::Unsupported` if it doesn't take tokens. Costs a round trip per connection.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    // The same options, for the server at `addr`.
+    pub(crate) fn for_addr(&self, addr: SocketAddr) -> Self {
+        Self {
+            endpoint: Endpoint::Tcp(addr),
+            ..self.clone()
+        }
+    }
+
+    pub fn connect(self) -> Result<KvsClient> {
+        let connection = self
+            .retry_policy
+            .run(|| Connection::open(&self, &self.endpoint))?;
+        Ok(KvsClient {
+            options: self,
+            connection: Some(connection),
+            redirect: None,
+            in_transaction: false,
+            next_id: 0,
+            in_flight: 0,
+            trace_id: None,
+            cache: None,
+        })
+    }
+}
+
+// Redirects to follow before giving up on finding a leader or a key's owner, and how long
+// to wait for a leader to be elected between them.
+const MAX_REDIRECTS: u32 = 20;
+const REDIRECT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Times to send a request again after frames are damaged, before giving up.
+const MAX_RESENDS: u32 = 3;
+
+struct Connection {
+    reader: Deserializer<ReadReader<BufReader<Box<dyn Transport>>>>,
+    writer: Serializer<BufWriter<Box<dyn Transport>>>,
+    // The format of the connection's frames, or None if its messages aren't framed.
+    framing: Option<WireFormat>,
+    // The server's hello, once read.
+    server: Option<Hello>,
+}
+
+impl Connection {
+    fn open(options: &KvsClientBuilder, endpoint: &Endpoint) -> Result<Self> {
+        let reader_stream = endpoint.connect(options.connect_timeout)?;
+        reader_stream.set_read_timeout(options.read_timeout)?;
+        reader_stream.set_write_timeout(options.write_timeout)?;
+        let writer_stream = reader_stream.try_clone()?;
+
+        let reader = Deserializer::new(BufReader::new(reader_stream));
+        let mut writer = Serializer::new(BufWriter::new(writer_stream));
+        let mut wanted = Capabilities::INCR | Capabilities::COMPACT | Capabilities::BACKPRESSURE;
+        if options.checksums {
+            wanted = wanted | Capabilities::CHECKSUMS;
+        }
+        if let Some(capability) = options.wire_format.capability() {
+            wanted = wanted | capability;
+        }
+        if options.bucket.is_some() {
+            wanted = wanted | Capabilities::BUCKETS;
+        }
+        // Flushed along with the first request, so the handshake costs no round trip.
+        Hello::new(wanted).write(writer.get_mut())?;
+        let mut connection = Self {
+            reader,
+            writer,
+            framing: frame::framing(wanted),
+            server: None,
+        };
+        if let Some(token) = &options.token {
+            match connection
+                .call(&Request::Authenticate(token.clone()))?
+                .into_result()?
+            {
+                Response::AuthenticateOk(()) => {}
+                _ => return Err(KvsError::UnexpectedResponse),
+            }
+        }
+        Ok(connection)
+    }
+
+    // Return what the server offers, reading its hello if that hasn't been done yet.
+    fn capabilities(&mut self) -> Result<Capabilities> {
+        if let Some(server) = self.server {
+            return Ok(server.capabilities);
+        }
+        self.writer.get_mut().flush()?;
+        let server = Hello::read(self.reader.get_mut())?;
+        if server.version != PROTOCOL_VERSION {
+            return Err(KvsError::ProtocolMismatch {
+                client: PROTOCOL_VERSION,
+                server: server.version,
+            });
+        }
+        self.server = Some(server);
+        Ok(server.capabilities)
+    }
+
+    fn call(&mut self, request: &Request) -> Result<Response> {
+        self.send(request)?;
+        self.receive()
+    }
+
+    fn send(&mut self, request: &Request) -> Result<()> {
+        // A server can't read a request in a format it doesn't speak.
+        let format = self.framing.and_then(WireFormat::capability);
+        for capability in [format, request.capability()].into_iter().flatten() {
+            if !self.capabilities()?.contains(capability) {
+                return Err(KvsError::Unsupported(capability));
+            }
+        }
+        match self.framing {
+            Some(format) => frame::write(self.writer.get_mut(), format, request)?,
+            None => request.serialize(&mut self.writer)?,
+        }
+        self.writer.get_mut().flush()?;
+        Ok(())
+    }
+
+    // Read a further response to a request answered with a stream of them.
+    fn receive(&mut self) -> Result<Response> {
+        self.capabilities()?;
+        match self.framing {
+            Some(format) => frame::read(self.reader.get_mut(), format),
+            None => Ok(Response::deserialize(&mut self.reader)?),
+        }
+    }
+}
+
+// `request` made in the trace `trace_id`, if there is one and the server takes trace ids.
+fn traced(
+    connection: &mut Connection,
+    request: &Request,
+    trace_id: Option<TraceId>,
+) -> Result<Option<Request>> {
+    Ok(match trace_id {
+        Some(trace_id) if connection.capabilities()?.contains(Capabilities::TRACING) => {
+            Some(Request::Traced(trace_id, Box::new(request.clone())))
+        }
+        _ => None,
+    })
+}
 
 pub struct KvsClient {
-    reader: Deserializer<ReadReader<BufReader<TcpStream>>>,
-    writer: Serializer<BufWriter<TcpStream>>,
+    options: KvsClientBuilder,
+    // None after a failed call, since the stream may be left mid-message.
+    connection: Option<Connection>,
+    // Where a server last redirected us: the leader of a replicated group, or the member of
+    // a cluster owning a key. Requests go to the builder's address when this is None.
+    redirect: Option<SocketAddr>,
+    // Transactions belong to a connection, so one can't survive a reconnect.
+    in_transaction: bool,
+    // The id to tag the next pipelined request with.
+    next_id: u64,
+    // Pipelined requests sent on the connection and not yet answered.
+    in_flight: u64,
+    // The trace the client's requests are made in, if set.
+    trace_id: Option<TraceId>,
+    // The values read, if the client caches them.
+    cache: Option<CacheHandle>,
 }
 
 impl KvsClient {
     pub fn connect(addr: &SocketAddr) -> Result<Self> {
-        let reader_stream = TcpStream::connect(addr)?;
-        let writer_stream = reader_stream.try_clone()?;
+        KvsClientBuilder::new(*addr).connect()
+    }
 
-        let reader = Deserializer::new(BufReader::new(reader_stream));
-        let writer = Serializer::new(BufWriter::new(writer_stream));
-        Ok(Self { reader, writer })
+    pub fn builder(addr: SocketAddr) -> KvsClientBuilder {
+        KvsClientBuilder::new(addr)
+    }
+
+    // Connect to where we were last redirected, or else where the builder says.
+    fn open_connection(&self) -> Result<Connection> {
+        match self.redirect {
+            Some(addr) => Connection::open(&self.options, &Endpoint::Tcp(addr)),
+            None => Connection::open(&self.options, &self.options.endpoint),
+        }
+    }
+
+    /// Make the client's requests in the trace `trace_id`, until it's set again, so the
+    /// spans the server makes serving them carry it; see `Request::Traced`. With none set,
+    /// a client built with the `tracing` feature makes each request in a trace of its own,
+    /// and one built without it sends no trace id. Servers that don't take trace ids are
+    /// sent none.
+    pub fn set_trace_id(&mut self, trace_id: Option<TraceId>) {
+        self.trace_id = trace_id;
+    }
+
+    // The trace to make the next request in, if any.
+    fn next_trace_id(&self) -> Option<TraceId> {
+        self.trace_id
+            .or_else(|| cfg!(feature = "tracing").then(TraceId::random))
+    }
+
+    // `request` made in the builder's bucket, if any, and pinned to the server if asked.
+    // Stats and subscriptions are served where they're sent anyway, so they aren't pinned.
+    fn scoped(&self, request: Request) -> Request {
+        let request = match &self.options.bucket {
+            Some(bucket) => request.in_bucket(bucket),
+            None => request,
+        };
+        if self.options.pinned && !matches!(request, Request::Stats | Request::Subscribe(_)) {
+            Request::Pinned(Box::new(request))
+        } else {
+            request
+        }
+    }
+
+    // Send a request, following redirects to the leader of a replicated group, or to the
+    // member of a cluster owning its keys.
+    fn call(&mut self, request: Request) -> Result<Response> {
+        if self.in_flight > 0 {
+            // The next response is one of theirs.
+            return Err(KvsError::StringError(
+                "pipelined requests are awaiting responses; recv them first".to_owned(),
+            ));
+        }
+        if let Some(cache) = &self.cache {
+            cache.invalidate_request(&request);
+        }
+        let request = self.scoped(request);
+        let trace_id = self.next_trace_id();
+        let span = Span::call(request.name(), trace_id);
+        let _entered = span.enter();
+        let result = self.call_redirected(request, trace_id);
+        span.record_failed(matches!(result, Err(_) | Ok(Response::Err(..))));
+        result
+    }
+
+    fn call_redirected(&mut self, request: Request, trace_id: Option<TraceId>) -> Result<Response> {
+        let mut redirects = 0;
+        loop {
+            let response = match self.call_once(&request, trace_id) {
+                // The server we were sent to may have failed since; start over from the
+                // builder's address, which will know of any new leader or owner.
+                Err(_) if self.redirect.is_some() && redirects < MAX_REDIRECTS => {
+                    self.redirect = None;
+                    redirects += 1;
+                    thread::sleep(REDIRECT_BACKOFF);
+                    continue;
+                }
+                result => result?,
+            };
+            match response {
+                Response::NotLeader(leader) if redirects < MAX_REDIRECTS => {
+                    self.redirect = leader;
+                    self.connection = None;
+                    redirects += 1;
+                    if leader.is_none() {
+                        // An election is under way.
+                        thread::sleep(REDIRECT_BACKOFF);
+                    }
+                }
+                Response::NotLeader(leader) => return Err(KvsError::NotLeader(leader)),
+                // A transaction belongs to its connection, so it can't follow the key.
+                Response::Moved(owner) if redirects < MAX_REDIRECTS && !self.in_transaction => {
+                    self.redirect = Some(owner);
+                    self.connection = None;
+                    redirects += 1;
+                }
+                Response::Moved(owner) => return Err(KvsError::Moved(owner)),
+                response => return Ok(response),
+            }
+        }
+    }
+
+    fn call_once(&mut self, request: &Request, trace_id: Option<TraceId>) -> Result<Response> {
+        let retry_policy = self.options.retry_policy;
+        retry_policy.run(|| {
+            let mut resends = 0;
+            loop {
+                let mut connection = match self.connection.take() {
+                    Some(connection) => connection,
+                    None if self.in_transaction => {
+                        self.in_transaction = false;
+                        return Err(KvsError::StringError(
+                            "connection lost during a transaction".to_owned(),
+                        ));
+                    }
+                    None => self.open_connection()?,
+                };
+                let traced = traced(&mut connection, request, trace_id)?;
+                // After a damaged frame the connection is dropped, since the frames that
+                // follow may not line up.
+                let resend = match connection.call(traced.as_ref().unwrap_or(request)) {
+                    // The server never ran the request.
+                    Ok(Response::FrameCorrupted) => true,
+                    // The server may have run the request.
+                    Err(KvsError::TransportCorruption) => request.is_idempotent(),
+                    // Nothing was sent, so the connection is still usable.
+                    Err(KvsError::Unsupported(capability)) => {
+                        self.connection = Some(connection);
+                        return Err(KvsError::Unsupported(capability));
+                    }
+                    result => {
+                        let response = result?;
+                        // The server hung up after saying so.
+                        if let Response::Err(ErrorCode::Busy, _) = response {
+                            return Err(KvsError::ServerBusy);
+                        }
+                        self.connection = Some(connection);
+                        // The request wasn't run, and the connection is still usable.
+                        match response {
+                            Response::Busy(retry_after) => {
+                                return Err(KvsError::Throttled(retry_after))
+                            }
+                            Response::Err(ErrorCode::RateLimited, _) => {
+                                return Err(KvsError::RateLimited)
+                            }
+                            _ => {}
+                        }
+                        return Ok(response);
+                    }
+                };
+                if !resend || resends == MAX_RESENDS {
+                    return Err(KvsError::TransportCorruption);
+                }
+                resends += 1;
+            }
+        })
+    }
+
+    /// Send `request` without waiting for its response, so that several can be in flight
+    /// on the connection at once. Return the id `recv` returns its response with.
+    ///
+    /// The server may serve requests in flight together in any order, and answers each as
+    /// it finishes, so wait for the response to a request before sending one that depends
+    /// on it. Pipelined requests aren't retried or redirected, and transactions and stats
+    /// can't be pipelined. Other calls fail until every response has been received.
+    pub fn send(&mut self, request: Request) -> Result<u64> {
+        if let Some(cache) = &self.cache {
+            cache.invalidate_request(&request);
+        }
+        let request = self.scoped(request);
+        let id = self.next_id;
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.open_connection()?,
+        };
+        let request = Request::Tagged(id, Box::new(request));
+        let traced = match traced(&mut connection, &request, self.next_trace_id()) {
+            Ok(traced) => traced,
+            Err(err) => {
+                self.in_flight = 0;
+                return Err(err);
+            }
+        };
+        match connection.send(traced.as_ref().unwrap_or(&request)) {
+            Ok(()) => {}
+            // Nothing was sent, so the connection is still usable.
+            Err(KvsError::Unsupported(capability)) => {
+                self.connection = Some(connection);
+                return Err(KvsError::Unsupported(capability));
+            }
+            // The responses to requests in flight are lost with the connection.
+            Err(err) => {
+                self.in_flight = 0;
+                return Err(err);
+            }
+        }
+        self.connection = Some(connection);
+        self.next_id += 1;
+        self.in_flight += 1;
+        Ok(id)
+    }
+
+    /// Wait for the next response to a request sent with `send`, in the order the server
+    /// finished them. Return the request's id with its response; `Response::into_result`
+    /// turns an error response into an error.
+    pub fn recv(&mut self) -> Result<(u64, Response)> {
+        if self.in_flight == 0 {
+            return Err(KvsError::StringError(
+                "no pipelined requests are awaiting responses".to_owned(),
+            ));
+        }
+        // A failure leaves the connection unusable, and the other responses lost with it.
+        let in_flight = mem::take(&mut self.in_flight);
+        let mut connection = self.connection.take().ok_or(KvsError::UnexpectedResponse)?;
+        match connection.receive()? {
+            Response::Tagged(id, response) => {
+                self.connection = Some(connection);
+                self.in_flight = in_flight - 1;
+                Ok((id, *response))
+            }
+            Response::FrameCorrupted => Err(KvsError::TransportCorruption),
+            Response::Err(ErrorCode::Busy, _) => Err(KvsError::ServerBusy),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let cmd = Request::Get(key);
-        cmd.serialize(&mut self.writer)?;
-        self.writer.get_mut().flush()?;
-        match Response::deserialize(&mut self.reader)? {
-            Response::GetOk(value) => Ok(value),
-            Response::Err(msg) => Err(KvsError::StringError(msg)),
+        // Reads in a transaction are the transaction's, so they go to the server.
+        let cache = self.cache.as_ref().filter(|_| !self.in_transaction);
+        if let Some(value) = cache.and_then(|cache| cache.get(&key)) {
+            return Ok(value);
+        }
+        let cached = cache
+            .and_then(CacheHandle::epoch)
+            .map(|epoch| (key.clone(), epoch));
+        match self.call(Request::Get(key))? {
+            Response::GetOk(value) => {
+                if let (Some(cache), Some((key, epoch))) = (&self.cache, cached) {
+                    cache.insert(key, value.clone(), epoch);
+                }
+                Ok(value)
+            }
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Get the values of several keys in one round trip, in the order of `keys`.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.call(Request::MultiGet(keys))? {
+            Response::MultiGetOk(values) => Ok(values),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Get a key's value with its version and when it was written. Read the key again
+    /// later and compare versions to tell whether it was written meanwhile.
+    pub fn get_with_meta(&mut self, key: String) -> Result<Option<(String, EntryMeta)>> {
+        match self.call(Request::GetWithMeta(key))? {
+            Response::GetWithMetaOk(found) => Ok(found),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Get a key's size, version and when it was written, without its value, or None if
+    /// it isn't set.
+    pub fn metadata(&mut self, key: String) -> Result<Option<EntryMeta>> {
+        match self.call(Request::Stat(key))? {
+            Response::StatOk(found) => Ok(found),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
             _ => Err(KvsError::UnexpectedResponse),
         }
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Request::Set(key, value);
-        cmd.serialize(&mut self.writer)?;
-        self.writer.get_mut().flush()?;
-        match Response::deserialize(&mut self.reader)? {
+        match self.call(Request::Set(key, value))? {
             Response::SetOk(()) => Ok(()),
-            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
             _ => Err(KvsError::UnexpectedResponse),
         }
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        let cmd = Request::Remove(key);
-        cmd.serialize(&mut self.writer)?;
-        self.writer.get_mut().flush()?;
-        match Response::deserialize(&mut self.reader)? {
+        match self.call(Request::Remove(key))? {
             Response::RemoveOk(()) => Ok(()),
-            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Add `delta` to the integer value of a key, treating a missing key as 0, and return the
+    /// new value.
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        match self.call(Request::Incr(key, delta))? {
+            Response::IncrOk(value) => Ok(value),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Move the value of `old_key` to `new_key`, replacing any value `new_key` had, in one
+    /// write on the server. Return `KvsError::KeyNotFound` if `old_key` isn't set.
+    pub fn rename(&mut self, old_key: String, new_key: String) -> Result<()> {
+        match self.call(Request::Rename(old_key, new_key))? {
+            Response::RenameOk(()) => Ok(()),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Append `suffix` to the value of a key, treating a missing key as empty, and return
+    /// the length of the new value in bytes.
+    pub fn append(&mut self, key: String, suffix: String) -> Result<u64> {
+        match self.call(Request::Append(key, suffix))? {
+            Response::AppendOk(len) => Ok(len),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Get `len` bytes of the value of a key from byte `offset`, fewer if the value ends
+    /// sooner, or None if the key doesn't exist. Only those bytes are sent.
+    pub fn get_range(&mut self, key: String, offset: u64, len: u64) -> Result<Option<String>> {
+        match self.call(Request::GetRange(key, offset, len))? {
+            Response::GetRangeOk(range) => Ok(range),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Get the keys set to exactly `value`, in key order.
+    pub fn find_by_value(&mut self, value: String) -> Result<Vec<String>> {
+        match self.call(Request::FindByValue(value))? {
+            Response::FindByValueOk(keys) => Ok(keys),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Get the value of a key once it's set, waiting up to `timeout` for a write to set it
+    /// if it isn't yet. Return None if the wait timed out. The client's read timeout, if
+    /// any, must be longer than `timeout`.
+    pub fn wait_get(&mut self, key: String, timeout: Duration) -> Result<Option<String>> {
+        let request = Request::WaitGet {
+            key,
+            timeout_ms: timeout.as_millis() as u64,
+        };
+        match self.call(request)? {
+            Response::WaitGetOk(value) => Ok(value),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Take the lock `key` for `owner` until `ttl` from now, if no other owner holds it,
+    /// and return whether it was taken. Taking a lock the owner holds extends it.
+    pub fn acquire_lock(&mut self, key: String, owner: String, ttl: Duration) -> Result<bool> {
+        match self.call(Request::AcquireLock(key, owner, ttl))? {
+            Response::AcquireLockOk(taken) => Ok(taken),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Release the lock `key` if `owner` holds it, and return whether it did.
+    pub fn release_lock(&mut self, key: String, owner: String) -> Result<bool> {
+        match self.call(Request::ReleaseLock(key, owner))? {
+            Response::ReleaseLockOk(released) => Ok(released),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    pub fn contains(&mut self, key: String) -> Result<bool> {
+        match self.call(Request::Contains(key))? {
+            Response::ContainsOk(found) => Ok(found),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    pub fn len(&mut self) -> Result<u64> {
+        match self.call(Request::Len)? {
+            Response::LenOk(len) => Ok(len),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Return a page of up to `limit` keys, in order, and the cursor to pass for the next
+    /// page, or None if this is the last. Pass None for the first page. The server caps
+    /// how many keys a page holds. Keys set or removed between pages may or may not be
+    /// listed, but every key set throughout is listed once.
+    pub fn keys(
+        &mut self,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        match self.call(Request::Keys { cursor, limit })? {
+            Response::KeysOk { keys, cursor } => Ok((keys, cursor)),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Have the server compact its engine now rather than waiting for it to on its own.
+    pub fn compact(&mut self) -> Result<()> {
+        match self.call(Request::Compact)? {
+            Response::CompactOk(()) => Ok(()),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Check the server is up, and return what it's running.
+    pub fn ping(&mut self) -> Result<Pong> {
+        match self.call(Request::Ping)? {
+            Response::PingOk(pong) => Ok(pong),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Return the optional features the server offers.
+    pub fn server_capabilities(&mut self) -> Result<Capabilities> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.open_connection()?,
+        };
+        let capabilities = connection.capabilities()?;
+        self.connection = Some(connection);
+        Ok(capabilities)
+    }
+
+    /// Return the keys the server holds in any of `slots`, out of `slot_count` slots.
+    pub fn slot_keys(&mut self, slot_count: u32, slots: Vec<u32>) -> Result<Vec<String>> {
+        match self.call(Request::SlotKeys(slot_count, slots))? {
+            Response::SlotKeysOk(keys) => Ok(keys),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Return the map of the server's cluster, which places keys on its members; see
+    /// `kvs::cluster`.
+    pub fn cluster_map(&mut self) -> Result<ClusterMap> {
+        match self.call(Request::ClusterMap)? {
+            Response::ClusterMapOk(map) => Ok(map),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    // Tell the server what a member of its cluster knows of it, and return what it knows.
+    pub(crate) fn gossip(&mut self, slot_count: u32, members: Vec<Member>) -> Result<Vec<Member>> {
+        match self.call(Request::Gossip {
+            slot_count,
+            members,
+        })? {
+            Response::GossipOk(members) => Ok(members),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Try to take an exclusive lease on `key` for `ttl`. Return the lease id, or None
+    /// if another client holds an unexpired lease on the key.
+    pub fn acquire_lease(&mut self, key: String, ttl: Duration) -> Result<Option<u64>> {
+        match self.call(Request::AcquireLease(key, ttl))? {
+            Response::AcquireLeaseOk(id) => Ok(id),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Extend a lease to expire `ttl` from now. Return false if the lease has already
+    /// expired or been released, in which case the caller no longer holds the key.
+    pub fn renew_lease(&mut self, key: String, lease_id: u64, ttl: Duration) -> Result<bool> {
+        match self.call(Request::RenewLease(key, lease_id, ttl))? {
+            Response::RenewLeaseOk(held) => Ok(held),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Give up a lease. Return false if it had already expired.
+    pub fn release_lease(&mut self, key: String, lease_id: u64) -> Result<bool> {
+        match self.call(Request::ReleaseLease(key, lease_id))? {
+            Response::ReleaseLeaseOk(held) => Ok(held),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Start a transaction on this connection. Until `commit` or `rollback`, gets, sets and
+    /// removes are buffered by the server and applied atomically on commit.
+    pub fn begin(&mut self) -> Result<()> {
+        match self.call(Request::Begin)? {
+            Response::BeginOk(()) => {
+                self.in_transaction = true;
+                Ok(())
+            }
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Commit the current transaction. Return an error, having applied nothing, if another
+    /// client changed a key the transaction used since it began.
+    pub fn commit(&mut self) -> Result<()> {
+        let response = self.call(Request::Commit);
+        self.in_transaction = false;
+        match response? {
+            Response::CommitOk(()) => Ok(()),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    pub fn rollback(&mut self) -> Result<()> {
+        let response = self.call(Request::Rollback);
+        self.in_transaction = false;
+        match response? {
+            Response::RollbackOk(()) => Ok(()),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        let mut stats = ServerStats::default();
+        self.stats_frames(|frame| stats.apply(frame))?;
+        Ok(stats)
+    }
+
+    /// Pass each frame of the server's stats to `each` as it arrives, without collecting them.
+    pub fn stats_frames(&mut self, mut each: impl FnMut(StatsFrame)) -> Result<()> {
+        let mut response = self.call(Request::Stats)?;
+        loop {
+            match response {
+                Response::StatsFrame(frame) => each(frame),
+                Response::StatsOk(()) => return Ok(()),
+                Response::Err(code, msg) => return Err(code.into_error(msg)),
+                _ => {
+                    // The rest of the stream would be taken for later responses.
+                    self.connection = None;
+                    return Err(KvsError::UnexpectedResponse);
+                }
+            }
+            // A failure mid-stream leaves the connection unusable, so take it out first.
+            let mut connection = self.connection.take().ok_or(KvsError::UnexpectedResponse)?;
+            response = connection.receive()?;
+            self.connection = Some(connection);
+        }
+    }
+
+    /// Cache up to `capacity` of the values `get` reads, dropping the least recently read
+    /// to make room, and serve gets of them without asking the server. The client
+    /// subscribes to every change on a connection of its own, and drops each changed key
+    /// from the cache, as it does the keys of its own writes, so a value it serves is stale
+    /// only until the change reaches it. While the subscription is down, as when the
+    /// server hangs up on it for falling behind, nothing is cached until it's made again.
+    /// Gets in a transaction aren't cached. Fails on a client with a bucket.
+    pub fn with_cache(mut self, capacity: usize) -> Result<Self> {
+        let mut options = self.options.clone();
+        // An idle subscription mustn't time out, or the cache would be emptied each time.
+        options.read_timeout = None;
+        let subscribe = move || options.clone().connect()?.subscribe("");
+        self.cache = Some(CacheHandle::spawn(capacity, subscribe()?, subscribe)?);
+        Ok(self)
+    }
+
+    /// Turn the connection into a stream of the changes to keys starting with `prefix`, as
+    /// the server makes them. Fails on a client with a bucket.
+    pub fn subscribe(mut self, prefix: impl Into<String>) -> Result<Subscription> {
+        if self.options.bucket.is_some() {
+            return Err(KvsError::StringError(
+                "subscriptions can't be made in a bucket".to_owned(),
+            ));
+        }
+        match self.call(Request::Subscribe(prefix.into()))? {
+            Response::SubscribeOk(()) => Ok(Subscription {
+                connection: self.connection.take().ok_or(KvsError::UnexpectedResponse)?,
+            }),
+            Response::Err(code, msg) => Err(code.into_error(msg)),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+}
+
+/// The changes to keys a `KvsClient` subscribed to.
+pub struct Subscription {
+    connection: Connection,
+}
+
+impl Subscription {
+    /// A handle on the subscription's stream, to shut it down from another thread.
+    pub(crate) fn stream(&self) -> io::Result<Box<dyn Transport>> {
+        self.connection.writer.get_ref().get_ref().try_clone()
+    }
+
+    /// Wait for the next change, for no longer than the client's read timeout. The server
+    /// hangs up on a subscriber that falls too far behind, which then has to subscribe again
+    /// and reread the keys it watches.
+    pub fn recv(&mut self) -> Result<KeyChange> {
+        match self.connection.receive()? {
+            Response::KeyChanged { key, op } => Ok(KeyChange { key, op }),
+            Response::FrameCorrupted => Err(KvsError::TransportCorruption),
             _ => Err(KvsError::UnexpectedResponse),
         }
     }
@@ -0,0 +1,238 @@
+//! A `KvsClient`'s cache of the values it has read, kept fresh by a subscription to the
+//! changes the server makes; see `KvsClient::with_cache`.
+
+use crate::client::Subscription;
+use crate::protocol::Request;
+use crate::sync::Mutex;
+use crate::transport::Transport;
+use crate::Result;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How long to wait between tries to subscribe again once the subscription is lost.
+const RESUBSCRIBE_BACKOFF: Duration = Duration::from_millis(100);
+
+struct Entry {
+    value: Option<String>,
+    // When the key was last read, by the cache's clock.
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<String, Entry>,
+    // The keys by when they were last read, least recently first.
+    by_use: BTreeMap<u64, String>,
+    clock: u64,
+    // Bumped by every invalidation, so a value read from the server isn't cached if its key
+    // may have changed while it was on the way.
+    epoch: u64,
+    // Whether the subscription is up. Changes made while it's down go unseen, so nothing is
+    // served or cached meanwhile.
+    subscribed: bool,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.by_use.remove(&entry.used);
+        }
+    }
+}
+
+// The subscriber thread's hold on its connection, for the client to shut it down with.
+#[derive(Default)]
+struct Subscriber {
+    stream: Option<Box<dyn Transport>>,
+    closed: bool,
+}
+
+/// The values a client has read, by key, with None for keys it found unset, which are
+/// dropped as changes to them arrive.
+pub(crate) struct ClientCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    subscriber: Mutex<Subscriber>,
+}
+
+impl ClientCache {
+    /// Return the cached value of `key`, if there is one.
+    pub fn get(&self, key: &str) -> Option<Option<String>> {
+        let mut entries = self.entries.lock();
+        if !entries.subscribed {
+            return None;
+        }
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries.entries.get_mut(key)?;
+        let used = mem::replace(&mut entry.used, clock);
+        let value = entry.value.clone();
+        entries.by_use.remove(&used);
+        entries.by_use.insert(clock, key.to_owned());
+        Some(value)
+    }
+
+    /// Return the epoch to pass `insert` the value read next with, or None if it mustn't
+    /// be cached.
+    pub fn epoch(&self) -> Option<u64> {
+        let entries = self.entries.lock();
+        entries.subscribed.then_some(entries.epoch)
+    }
+
+    /// Cache the value of `key` read from the server, unless anything was invalidated
+    /// since `epoch`, making room for it if the cache is full.
+    pub fn insert(&self, key: String, value: Option<String>, epoch: u64) {
+        let mut entries = self.entries.lock();
+        if !entries.subscribed || entries.epoch != epoch || self.capacity == 0 {
+            return;
+        }
+        entries.remove(&key);
+        while entries.entries.len() >= self.capacity {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            entries.entries.remove(&oldest);
+        }
+        entries.clock += 1;
+        let used = entries.clock;
+        entries.by_use.insert(used, key.clone());
+        entries.entries.insert(key, Entry { value, used });
+    }
+
+    /// Drop the keys `request` writes, before it's sent. Writes whose keys aren't told by
+    /// the request drop every key.
+    pub fn invalidate_request(&self, request: &Request) {
+        if !request.is_write() {
+            return;
+        }
+        match request {
+            Request::Set(key, _)
+            | Request::Remove(key)
+            | Request::Incr(key, _)
+            | Request::Append(key, _)
+            | Request::AcquireLock(key, _, _)
+            | Request::ReleaseLock(key, _) => self.invalidate(key),
+            Request::Rename(old_key, new_key) => {
+                self.invalidate(old_key);
+                self.invalidate(new_key);
+            }
+            Request::Tagged(_, request) | Request::Traced(_, request) => {
+                self.invalidate_request(request)
+            }
+            // The keys a transaction wrote were dropped as their writes were sent.
+            Request::Commit => {}
+            _ => self.clear(),
+        }
+    }
+
+    fn invalidate(&self, key: &str) {
+        let mut entries = self.entries.lock();
+        entries.epoch += 1;
+        entries.remove(key);
+    }
+
+    fn clear(&self) {
+        let subscribed = self.entries.lock().subscribed;
+        self.reset(subscribed);
+    }
+
+    // Drop every key, and serve and cache keys from now on only if `subscribed`.
+    fn reset(&self, subscribed: bool) {
+        let mut entries = self.entries.lock();
+        entries.epoch += 1;
+        entries.entries.clear();
+        entries.by_use.clear();
+        entries.subscribed = subscribed;
+    }
+
+    // Keep hold of the subscription's stream to shut it down with, and return whether the
+    // client is still there to use it.
+    fn attach(&self, subscription: &Subscription) -> Result<bool> {
+        let stream = subscription.stream()?;
+        let mut subscriber = self.subscriber.lock();
+        if subscriber.closed {
+            return Ok(false);
+        }
+        subscriber.stream = Some(stream);
+        Ok(true)
+    }
+
+    // Drop the keys of the changes `subscription` tells of, subscribing again with
+    // `subscribe` whenever it's lost, until the client is dropped.
+    fn run(&self, mut subscription: Subscription, subscribe: impl Fn() -> Result<Subscription>) {
+        loop {
+            while let Ok(change) = subscription.recv() {
+                self.invalidate(&change.key);
+            }
+            self.reset(false);
+            subscription = loop {
+                if self.subscriber.lock().closed {
+                    return;
+                }
+                let attached = subscribe()
+                    .and_then(|subscription| Ok((self.attach(&subscription)?, subscription)));
+                match attached {
+                    Ok((true, subscription)) => break subscription,
+                    Ok((false, _)) => return,
+                    Err(_) => thread::sleep(RESUBSCRIBE_BACKOFF),
+                }
+            };
+            self.reset(true);
+        }
+    }
+}
+
+/// A `KvsClient`'s hold on its cache and the thread keeping it, which stops once the handle
+/// is dropped.
+pub(crate) struct CacheHandle(Arc<ClientCache>);
+
+impl CacheHandle {
+    /// Cache up to `capacity` values, kept fresh by `subscription` to every key, and by
+    /// the subscriptions `subscribe` makes once it's lost.
+    pub fn spawn(
+        capacity: usize,
+        subscription: Subscription,
+        subscribe: impl Fn() -> Result<Subscription> + Send + 'static,
+    ) -> Result<Self> {
+        let cache = Arc::new(ClientCache {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+            subscriber: Mutex::new(Subscriber::default()),
+        });
+        cache.attach(&subscription)?;
+        cache.reset(true);
+        let thread_cache = cache.clone();
+        thread::Builder::new().spawn(move || thread_cache.run(subscription, subscribe))?;
+        Ok(Self(cache))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Option<String>> {
+        self.0.get(key)
+    }
+
+    pub fn epoch(&self) -> Option<u64> {
+        self.0.epoch()
+    }
+
+    pub fn insert(&self, key: String, value: Option<String>, epoch: u64) {
+        self.0.insert(key, value, epoch)
+    }
+
+    pub fn invalidate_request(&self, request: &Request) {
+        self.0.invalidate_request(request)
+    }
+}
+
+impl Drop for CacheHandle {
+    fn drop(&mut self) {
+        let mut subscriber = self.0.subscriber.lock();
+        subscriber.closed = true;
+        if let Some(stream) = &subscriber.stream {
+            let _ = stream.shutdown();
+        }
+    }
+}
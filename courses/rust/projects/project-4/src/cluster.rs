@@ -0,0 +1,363 @@
+//! Sharding of keys over a cluster of `KvsServer`s that know of each other.
+//!
+//! Each member, started with `KvsServer::with_cluster`, gossips with the others over the
+//! protocol of `KvsClient`: a few times a second it sends one of them, picked at random,
+//! everything it knows of the cluster, and takes in what it's sent back. A member that
+//! starts knowing of a single peer is soon known to every other. Keys are placed on the
+//! members as `ShardedKvsClient` places them, on a `HashRing` of the members' addresses, so
+//! members that know of the same members agree on who owns each slot.
+//!
+//! A member asked about a key another owns answers with `Response::Moved`, which
+//! `KvsClient` follows, so clients can send any request to any member. A
+//! `ShardedKvsClient::from_cluster` asks a member for the cluster's map instead, and sends
+//! each request to the key's owner straight away.
+//!
+//! Gossip needs the admin role under an ACL; see `kvs::acl`. Members of a cluster with ACLs
+//! gossip with the `ClusterConfig::token` they share, which each member's ACL must grant
+//! it, or without a token if their anonymous grants are admin.
+//!
+//! This is a minimal implementation: members don't move keys when the ring changes, which
+//! `kvs rebalance` does with requests every member serves itself; a member that fails keeps
+//! its slots, unreachable until it's back, and only a member that shuts down hands them to
+//! the others; and members agree on the ring only once gossip has reached them all, so for
+//! a moment a key may be sent back and forth.
+
+use crate::client::KvsClient;
+use crate::ring;
+use crate::ring::HashRing;
+use crate::sync::Mutex;
+use crate::KvsError;
+use crate::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use slog::debug;
+use slog::info;
+use slog::Logger;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// How often a member gossips with another.
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(200);
+// How long a member may take to answer gossip.
+const GOSSIP_TIMEOUT: Duration = Duration::from_millis(500);
+// How long a member may go unheard of before it's reported down. It gossips far more often.
+const DOWN_AFTER: Duration = Duration::from_secs(3);
+
+/// How a server takes part in a cluster.
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    /// Address this server serves clients on, where other members and clients reach it.
+    pub addr: SocketAddr,
+    /// Members to gossip with until others are known. Any one already in the cluster will
+    /// do.
+    pub peers: Vec<SocketAddr>,
+    /// Number of slots keys hash into, the same for every member.
+    pub slot_count: u32,
+    /// Token to gossip with, which the ACLs of the other members must grant the admin role.
+    pub token: Option<String>,
+}
+
+impl ClusterConfig {
+    pub fn new(addr: SocketAddr, peers: Vec<SocketAddr>) -> Self {
+        Self {
+            addr,
+            peers,
+            slot_count: ring::DEFAULT_SLOT_COUNT,
+            token: None,
+        }
+    }
+}
+
+/// What's known of a member of a cluster, as the members gossip it.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub addr: SocketAddr,
+    /// When the member last started, in milliseconds since the Unix epoch, so what it says
+    /// of itself since outranks what was said of it before.
+    pub incarnation: u64,
+    /// The number of rounds of gossip the member has started since.
+    pub heartbeat: u64,
+    /// Whether the member has left the cluster, and owns no slots.
+    pub left: bool,
+}
+
+impl Member {
+    // Whether this is later news of the member than `other`.
+    fn is_newer(&self, other: &Self) -> bool {
+        (self.incarnation, self.heartbeat) > (other.incarnation, other.heartbeat)
+    }
+}
+
+/// The members owning the slots of a cluster, which place keys on a `HashRing`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClusterMap {
+    pub slot_count: u32,
+    /// The members' addresses, in order.
+    pub members: Vec<SocketAddr>,
+}
+
+impl ClusterMap {
+    pub fn ring(&self) -> HashRing {
+        HashRing::new(&self.members, self.slot_count)
+    }
+}
+
+/// A member of a cluster, as another reports it in `ServerStats`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MemberStatus {
+    pub addr: SocketAddr,
+    /// Whether the member has been heard of lately. The member reporting always is.
+    pub up: bool,
+    /// The number of slots the member owns.
+    pub slots: u32,
+}
+
+/// A member's view of its cluster, as reported in `ServerStats`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClusterStatus {
+    /// The member reporting.
+    pub addr: SocketAddr,
+    pub slot_count: u32,
+    /// The members owning slots, the one reporting included, in order.
+    pub members: Vec<MemberStatus>,
+}
+
+struct Membership {
+    // Every member known of, with when news of it last came. Members that left are kept,
+    // so older news of them doesn't bring them back.
+    members: BTreeMap<SocketAddr, (Member, Instant)>,
+    // The ring of the members that haven't left.
+    ring: HashRing,
+}
+
+impl Membership {
+    fn place(&mut self, slot_count: u32) {
+        self.ring = HashRing::new(&owners(self), slot_count);
+    }
+}
+
+/// A server's membership of a cluster, kept up to date by gossip on a thread of its own.
+pub(crate) struct Cluster {
+    config: ClusterConfig,
+    state: Mutex<Membership>,
+    stopped: AtomicBool,
+    log: Logger,
+}
+
+impl Cluster {
+    /// Join the cluster `config` names, and start gossiping with its members.
+    pub fn start(config: ClusterConfig, log: Logger) -> Result<Arc<Self>> {
+        let incarnation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let me = Member {
+            addr: config.addr,
+            incarnation,
+            heartbeat: 0,
+            left: false,
+        };
+        let mut membership = Membership {
+            members: BTreeMap::from([(config.addr, (me, Instant::now()))]),
+            ring: HashRing::new(&[], config.slot_count),
+        };
+        membership.place(config.slot_count);
+        info!(&log, "joining cluster"; "ip-port" => config.addr.to_string(), "peers" => config.peers.len());
+        let cluster = Arc::new(Self {
+            config,
+            state: Mutex::new(membership),
+            stopped: AtomicBool::new(false),
+            log,
+        });
+        let gossiping = cluster.clone();
+        thread::Builder::new().spawn(move || gossiping.gossip_forever())?;
+        Ok(cluster)
+    }
+
+    /// Leave the cluster, telling the members up, so they take over this one's slots.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let (members, up) = {
+            let mut state = self.state.lock();
+            if let Some((me, _)) = state.members.get_mut(&self.config.addr) {
+                me.heartbeat += 1;
+                me.left = true;
+            }
+            state.place(self.config.slot_count);
+            let up: Vec<SocketAddr> = state
+                .members
+                .values()
+                .filter(|(member, seen)| {
+                    member.addr != self.config.addr && !member.left && seen.elapsed() < DOWN_AFTER
+                })
+                .map(|(member, _)| member.addr)
+                .collect();
+            (known(&state), up)
+        };
+        info!(&self.log, "leaving cluster"; "members" => up.len());
+        for addr in up {
+            if let Err(err) = self.exchange(addr, members.clone()) {
+                debug!(&self.log, "unable to say goodbye"; "member" => addr.to_string(), "error" => err.to_string());
+            }
+        }
+    }
+
+    /// The member owning `key`, or None if no member does, as when this one has left.
+    pub fn owner(&self, key: &str) -> Option<SocketAddr> {
+        self.state.lock().ring.node_for_key(key)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.config.addr
+    }
+
+    pub fn map(&self) -> ClusterMap {
+        let state = self.state.lock();
+        ClusterMap {
+            slot_count: self.config.slot_count,
+            members: owners(&state),
+        }
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let state = self.state.lock();
+        let mut slots: BTreeMap<SocketAddr, u32> = BTreeMap::new();
+        for slot in 0..self.config.slot_count {
+            if let Some(owner) = state.ring.node_for_slot(slot) {
+                *slots.entry(owner).or_default() += 1;
+            }
+        }
+        let members = state
+            .members
+            .values()
+            .filter(|(member, _)| !member.left)
+            .map(|(member, seen)| MemberStatus {
+                addr: member.addr,
+                up: member.addr == self.config.addr || seen.elapsed() < DOWN_AFTER,
+                slots: slots.get(&member.addr).copied().unwrap_or(0),
+            })
+            .collect();
+        ClusterStatus {
+            addr: self.config.addr,
+            slot_count: self.config.slot_count,
+            members,
+        }
+    }
+
+    /// Take in what a member hashing keys into `slot_count` slots knows of the cluster, and
+    /// return what this one knows.
+    pub fn gossip(&self, slot_count: u32, members: Vec<Member>) -> Result<Vec<Member>> {
+        if slot_count != self.config.slot_count {
+            return Err(KvsError::StringError(format!(
+                "the member hashes keys into {} slots, not {}",
+                self.config.slot_count, slot_count
+            )));
+        }
+        self.merge(members);
+        Ok(known(&self.state.lock()))
+    }
+
+    // Keep the news of `members` newer than what's known, placing slots anew if a member
+    // joined or left.
+    fn merge(&self, members: Vec<Member>) {
+        let mut state = self.state.lock();
+        let mut placed = false;
+        for member in members {
+            // Only a member itself says whether it's in the cluster.
+            if member.addr == self.config.addr {
+                continue;
+            }
+            let owned = match state.members.get(&member.addr) {
+                Some((known, _)) if !member.is_newer(known) => continue,
+                Some((known, _)) => !known.left,
+                None => false,
+            };
+            if owned == member.left {
+                placed = true;
+                if member.left {
+                    info!(&self.log, "member left"; "member" => member.addr.to_string());
+                } else {
+                    info!(&self.log, "member joined"; "member" => member.addr.to_string());
+                }
+            }
+            state.members.insert(member.addr, (member, Instant::now()));
+        }
+        if placed {
+            state.place(self.config.slot_count);
+        }
+    }
+
+    fn gossip_forever(&self) {
+        while !self.stopped.load(Ordering::SeqCst) {
+            thread::sleep(GOSSIP_INTERVAL);
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let (target, members) = {
+                let mut state = self.state.lock();
+                if let Some((me, seen)) = state.members.get_mut(&self.config.addr) {
+                    me.heartbeat += 1;
+                    *seen = Instant::now();
+                }
+                // The peers configured are tried too, in case the cluster was split before
+                // any of them was heard of.
+                let mut candidates: Vec<SocketAddr> = owners(&state);
+                for peer in &self.config.peers {
+                    if !candidates.contains(peer) {
+                        candidates.push(*peer);
+                    }
+                }
+                candidates.retain(|addr| *addr != self.config.addr);
+                if candidates.is_empty() {
+                    continue;
+                }
+                let pick = RandomState::new().build_hasher().finish() as usize % candidates.len();
+                (candidates[pick], known(&state))
+            };
+            match self.exchange(target, members) {
+                Ok(members) => self.merge(members),
+                Err(err) => {
+                    debug!(&self.log, "unable to gossip"; "member" => target.to_string(), "error" => err.to_string())
+                }
+            }
+        }
+    }
+
+    // Send `members` to the member at `addr`, and return what it knows.
+    fn exchange(&self, addr: SocketAddr, members: Vec<Member>) -> Result<Vec<Member>> {
+        let mut builder = KvsClient::builder(addr).timeout(GOSSIP_TIMEOUT);
+        if let Some(token) = &self.config.token {
+            builder = builder.token(token.clone());
+        }
+        builder.connect()?.gossip(self.config.slot_count, members)
+    }
+}
+
+// Everything known of the members.
+fn known(state: &Membership) -> Vec<Member> {
+    state
+        .members
+        .values()
+        .map(|(member, _)| member.clone())
+        .collect()
+}
+
+// The members that haven't left, in order.
+fn owners(state: &Membership) -> Vec<SocketAddr> {
+    state
+        .members
+        .values()
+        .filter(|(member, _)| !member.left)
+        .map(|(member, _)| member.addr)
+        .collect()
+}
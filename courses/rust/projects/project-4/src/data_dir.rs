@@ -0,0 +1,281 @@
+//! Moving a server's data directory to another place, possibly on another filesystem.
+//!
+//! Every file is copied under a temporary name, synced, renamed into place and read back
+//! to check its CRC-32 against the original's. Each verified file is recorded in a progress
+//! file in the destination, so a move that's interrupted picks up where it stopped when run
+//! again. Once everything is copied the source gets a `MOVED_FILE` naming the destination,
+//! and `kvs-server` refuses to start on a directory that has one.
+//!
+//! The server must be stopped while its directory is moved.
+
+use crate::KvsError;
+use crate::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Name of the file recording which engine the directory's data is in.
+pub const ENGINE_FILE: &str = "kvs.engine";
+
+/// Name of the file a `KvStore` locks while it's open, holding the PID of the process that
+/// opened it.
+pub const LOCK_FILE: &str = "kvs.lock";
+
+/// Name of the file left in a moved directory, holding the path it was moved to.
+pub const MOVED_FILE: &str = "kvs.moved";
+
+// Name of the file in the destination recording the files verified so far.
+const PROGRESS_FILE: &str = "kvs.move-progress";
+
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// What a move did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MoveSummary {
+    /// Files copied by this run.
+    pub copied_files: u64,
+    /// Bytes copied by this run.
+    pub copied_bytes: u64,
+    /// Files an interrupted run had already copied and verified.
+    pub resumed_files: u64,
+}
+
+/// Return where the directory was moved to, if it was.
+pub fn moved_to(dir: &Path) -> Result<Option<PathBuf>> {
+    match fs::read_to_string(dir.join(MOVED_FILE)) {
+        Ok(to) => Ok(Some(PathBuf::from(to.trim_end_matches('\n')))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Return the name of the engine the directory's data is in, if it records one.
+pub fn engine(dir: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(dir.join(ENGINE_FILE)) {
+        Ok(engine) => Ok(Some(engine.trim_end().to_owned())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Record that the directory's data is in `engine`, replacing any earlier record in one
+/// step, so the directory never records neither engine.
+pub fn set_engine(dir: &Path, engine: &str) -> Result<()> {
+    let partial = dir.join(format!("{}{}", ENGINE_FILE, PARTIAL_SUFFIX));
+    {
+        let mut file = File::create(&partial)?;
+        file.write_all(engine.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&partial, dir.join(ENGINE_FILE))?;
+    sync_dir(dir)
+}
+
+/// Copy every file under `from` to `to`, verify the copies and mark `from` as moved.
+///
+/// `on_file` is called with the path of each file, relative to `from`, once it's copied and
+/// verified; an error from it stops the move, which can be resumed later.
+pub fn move_data(
+    from: &Path,
+    to: &Path,
+    mut on_file: impl FnMut(&Path, u64) -> Result<()>,
+) -> Result<MoveSummary> {
+    if let Some(moved) = moved_to(from)? {
+        return Err(KvsError::StringError(format!(
+            "{} was already moved to {}",
+            from.display(),
+            moved.display()
+        )));
+    }
+    let to = absolute(to)?;
+    if to.starts_with(absolute(from)?) {
+        return Err(KvsError::StringError(
+            "the destination can't be inside the source".to_owned(),
+        ));
+    }
+
+    let mut done = resume(from, &to)?;
+    let mut progress = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(to.join(PROGRESS_FILE))?;
+    sync_dir(&to)?;
+
+    let mut summary = MoveSummary::default();
+    for file in source_files(from)? {
+        let source = from.join(&file);
+        let len = source.metadata()?.len();
+        if let Some(&(done_len, _)) = done.get(&file) {
+            if done_len != len {
+                return Err(KvsError::StringError(format!(
+                    "{} changed since the move started; is the server still running?",
+                    source.display()
+                )));
+            }
+            summary.resumed_files += 1;
+            continue;
+        }
+
+        let target = to.join(&file);
+        let checksum = copy_file(&source, &target)?;
+        let copy_checksum = checksum_of(&target)?;
+        if copy_checksum != checksum {
+            fs::remove_file(&target)?;
+            return Err(KvsError::StringError(format!(
+                "copy of {} doesn't match it (CRC-32 {:08x}, expected {:08x})",
+                source.display(),
+                copy_checksum,
+                checksum
+            )));
+        }
+        writeln!(progress, "{:08x} {} {}", checksum, len, file.display())?;
+        progress.sync_data()?;
+        done.insert(file.clone(), (len, checksum));
+        summary.copied_files += 1;
+        summary.copied_bytes += len;
+        on_file(&file, len)?;
+    }
+
+    // Only now is the destination complete, so only now may the source point at it.
+    fs::remove_file(to.join(PROGRESS_FILE))?;
+    sync_dir(&to)?;
+    let pointer = from.join(format!("{}{}", MOVED_FILE, PARTIAL_SUFFIX));
+    {
+        let mut file = File::create(&pointer)?;
+        writeln!(file, "{}", to.display())?;
+        file.sync_all()?;
+    }
+    fs::rename(&pointer, from.join(MOVED_FILE))?;
+    sync_dir(from)?;
+    Ok(summary)
+}
+
+// Length and CRC-32 of each file verified so far, by path relative to the source.
+type Done = BTreeMap<PathBuf, (u64, u32)>;
+
+// Read what an interrupted move already verified, or check that the destination is free.
+fn resume(from: &Path, to: &Path) -> Result<Done> {
+    let mut done = Done::new();
+    let progress = match File::open(to.join(PROGRESS_FILE)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            if to.exists() && fs::read_dir(to)?.next().is_some() {
+                return Err(KvsError::StringError(format!(
+                    "{} isn't empty and has no move to resume",
+                    to.display()
+                )));
+            }
+            fs::create_dir_all(to)?;
+            if let Some(parent) = to.parent() {
+                sync_dir(parent)?;
+            }
+            return Ok(done);
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    for line in BufReader::new(progress).lines() {
+        let line = line?;
+        let mut fields = line.splitn(3, ' ');
+        let entry = (|| {
+            let checksum = u32::from_str_radix(fields.next()?, 16).ok()?;
+            let len = fields.next()?.parse().ok()?;
+            let file = PathBuf::from(fields.next()?);
+            Some((file, len, checksum))
+        })();
+        // A line cut short by the interruption is the last one; that file is copied again.
+        let Some((file, len, checksum)) = entry else {
+            break;
+        };
+        if checksum_of(&to.join(&file))? != checksum {
+            return Err(KvsError::StringError(format!(
+                "copy of {} was damaged since it was verified",
+                from.join(&file).display()
+            )));
+        }
+        done.insert(file, (len, checksum));
+    }
+    Ok(done)
+}
+
+// Return the paths of the files under `dir`, relative to it, leaving out half-written copies.
+fn source_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative) = dirs.pop() {
+        for entry in fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if !path.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Copy `source` to `target` through a temporary file, syncing it before it's renamed into
+// place. Return the CRC-32 of what was read.
+fn copy_file(source: &Path, target: &Path) -> Result<u32> {
+    let dir = target
+        .parent()
+        .expect("copied files are under the destination");
+    fs::create_dir_all(dir)?;
+    let mut partial = target.as_os_str().to_owned();
+    partial.push(PARTIAL_SUFFIX);
+
+    let mut reader = File::open(source)?;
+    let mut writer = File::create(&partial)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+    }
+    writer.sync_all()?;
+    fs::rename(&partial, target)?;
+    sync_dir(dir)?;
+    Ok(hasher.finalize())
+}
+
+fn checksum_of(path: &Path) -> Result<u32> {
+    let mut reader = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+// Make a rename or new file in `dir` durable.
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn absolute(path: &Path) -> Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_owned())
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
@@ -0,0 +1,110 @@
+//! The format engines export their pairs in, and import them from.
+//!
+//! A dump starts with `MAGIC` and the format version as a big-endian `u32`. Then come
+//! records, each framed like checksummed protocol messages: a pair per key, in key order,
+//! and lastly the number of pairs, so a dump cut short is noticed when it's imported.
+
+use crate::frame;
+use crate::frame::WireFormat;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Read;
+use std::io::Write;
+
+const MAGIC: &[u8; 8] = b"KVSDUMP\0";
+const VERSION: u32 = 1;
+
+// Pairs imported per `set_batch`.
+const IMPORT_BATCH: usize = 1000;
+
+#[derive(Deserialize, Serialize, Debug)]
+enum Record {
+    Pair(String, String),
+    End(u64),
+}
+
+/// Writes a dump record by record.
+pub(crate) struct Exporter<W: Write> {
+    writer: W,
+    pairs: u64,
+}
+
+impl<W: Write> Exporter<W> {
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        Ok(Self { writer, pairs: 0 })
+    }
+
+    pub fn pair(&mut self, key: String, value: String) -> Result<()> {
+        frame::write(
+            &mut self.writer,
+            WireFormat::MessagePack,
+            &Record::Pair(key, value),
+        )?;
+        self.pairs += 1;
+        Ok(())
+    }
+
+    /// End the dump. Return the number of pairs in it.
+    pub fn finish(mut self) -> Result<u64> {
+        frame::write(
+            &mut self.writer,
+            WireFormat::MessagePack,
+            &Record::End(self.pairs),
+        )?;
+        self.writer.flush()?;
+        Ok(self.pairs)
+    }
+}
+
+/// Set every pair of the dump read from `reader` in `engine`. Return the number of pairs.
+pub(crate) fn import<E: KvsEngine>(engine: &E, mut reader: impl Read) -> Result<u64> {
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(KvsError::StringError("not a kvs dump".to_owned()));
+    }
+    let version = u32::from_be_bytes(header[8..].try_into().unwrap());
+    if version != VERSION {
+        return Err(KvsError::StringError(format!(
+            "unsupported dump version {}; expected {}",
+            version, VERSION
+        )));
+    }
+
+    let mut batch = Vec::with_capacity(IMPORT_BATCH);
+    let mut pairs = 0;
+    loop {
+        let record =
+            frame::read(&mut reader, WireFormat::MessagePack).map_err(|err| match err {
+                KvsError::TransportCorruption => {
+                    KvsError::StringError(format!("dump record {} failed its checksum", pairs + 1))
+                }
+                err => err,
+            })?;
+        match record {
+            Record::Pair(key, value) => {
+                batch.push((key, value));
+                pairs += 1;
+                if batch.len() == IMPORT_BATCH {
+                    engine.set_batch(std::mem::take(&mut batch))?;
+                }
+            }
+            Record::End(count) if count == pairs => break,
+            Record::End(count) => {
+                return Err(KvsError::StringError(format!(
+                    "dump holds {} pairs but says it holds {}",
+                    pairs, count
+                )))
+            }
+        }
+    }
+    if !batch.is_empty() {
+        engine.set_batch(batch)?;
+    }
+    Ok(pairs)
+}
@@ -0,0 +1,114 @@
+use super::group_commit::SyncLog;
+use crate::sync::Condvar;
+use crate::sync::Mutex;
+use crate::KvsError;
+use crate::Result;
+use std::io;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+struct State {
+    /// Set when a sync fails; every later write fails with it, since the log may have lost
+    /// records.
+    failed: Option<(io::ErrorKind, String)>,
+    closed: bool,
+}
+
+/// Syncs a `KvStore`'s log every interval, so writes that return before they're synced
+/// reach the disk within one interval of being made, rather than whenever the OS gets to
+/// them.
+///
+/// A sync is skipped when nothing has been written since the last, as told by the count
+/// of unsynced bytes the store's writer keeps.
+pub(super) struct BackgroundSync {
+    interval: Duration,
+    unsynced: Arc<AtomicU64>,
+    state: Mutex<State>,
+    closing: Condvar,
+}
+
+impl BackgroundSync {
+    pub fn new(interval: Duration, unsynced: Arc<AtomicU64>) -> Self {
+        Self {
+            interval,
+            unsynced,
+            state: Mutex::new(State {
+                failed: None,
+                closed: false,
+            }),
+            closing: Condvar::new(),
+        }
+    }
+
+    /// Return the error of the sync that failed, if one has.
+    pub fn check(&self) -> Result<()> {
+        match &self.state.lock().failed {
+            Some((kind, msg)) => Err(KvsError::IO(io::Error::new(*kind, msg.clone()))),
+            None => Ok(()),
+        }
+    }
+
+    /// Stop the sync thread once it has synced what's left.
+    pub fn close(&self) {
+        self.state.lock().closed = true;
+        self.closing.notify_one();
+    }
+
+    /// Run the sync thread until `close` is called.
+    pub fn run(&self, writer: &Mutex<impl SyncLog>) {
+        loop {
+            let closed = {
+                let mut state = self.state.lock();
+                let deadline = Instant::now() + self.interval;
+                while !state.closed {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    state = self.closing.wait_timeout(state, deadline - now).0;
+                }
+                state.closed
+            };
+
+            if self.unsynced.load(Ordering::SeqCst) > 0 {
+                if let Err(err) = writer.lock().sync_log() {
+                    self.state.lock().failed = Some((err.kind(), err.to_string()));
+                    return;
+                }
+            }
+            if closed {
+                return;
+            }
+        }
+    }
+}
+
+/// A `KvStore`'s hold on a `BackgroundSync` and its thread, which stops once the last
+/// handle is dropped.
+pub(super) struct SyncHandle(Arc<BackgroundSync>);
+
+impl SyncHandle {
+    pub fn spawn(
+        sync: BackgroundSync,
+        writer: Arc<Mutex<impl SyncLog + Send + 'static>>,
+    ) -> Result<Self> {
+        let sync = Arc::new(sync);
+        let thread_sync = sync.clone();
+        thread::Builder::new().spawn(move || thread_sync.run(&writer))?;
+        Ok(Self(sync))
+    }
+
+    pub fn check(&self) -> Result<()> {
+        self.0.check()
+    }
+}
+
+impl Drop for SyncHandle {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
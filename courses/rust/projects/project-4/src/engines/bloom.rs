@@ -0,0 +1,67 @@
+use crate::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+// Bits per key and probes per lookup, for about 1% false positives.
+const BITS_PER_KEY: usize = 10;
+const PROBES: u32 = 7;
+
+/// A bloom filter of the keys in a log: `may_contain` is true for every key added, and for
+/// few others.
+///
+/// Keys are hashed with CRC-32 under two seeds, so a filter written to disk reads back the
+/// same under any build.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Bloom {
+    bits: Vec<u64>,
+    probes: u32,
+}
+
+impl Bloom {
+    /// Build a filter of `keys`.
+    pub(crate) fn of<'a>(keys: impl ExactSizeIterator<Item = &'a str>) -> Self {
+        let words = (keys.len() * BITS_PER_KEY).div_ceil(64).max(1);
+        let mut bloom = Self {
+            bits: vec![0; words],
+            probes: PROBES,
+        };
+        for key in keys {
+            for bit in bloom.bits_of(key) {
+                bloom.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    /// Return whether `key` may have been added. False means it certainly wasn't.
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.bits_of(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // The bits probed for `key`, by double hashing.
+    fn bits_of(&self, key: &str) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let first = u64::from(crc32fast::hash(key.as_bytes()));
+        let mut hasher = crc32fast::Hasher::new_with_initial(0x9e37_79b9);
+        hasher.update(key.as_bytes());
+        let second = u64::from(hasher.finalize()) | 1;
+        (0..u64::from(self.probes)).map(move |i| (first.wrapping_add(i * second) % len) as usize)
+    }
+
+    /// Read the filter written to `path`. Return `None` if there's none, or it doesn't decode.
+    pub(crate) fn read(path: &Path) -> Option<Self> {
+        let bytes = fs::read(path).ok()?;
+        rmp_serde::from_slice::<Self>(&bytes)
+            .ok()
+            .filter(|bloom| !bloom.bits.is_empty())
+    }
+
+    /// Write the filter to `path`, replacing what's there.
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, rmp_serde::to_vec(self)?)?;
+        Ok(())
+    }
+}
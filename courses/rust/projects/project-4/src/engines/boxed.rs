@@ -0,0 +1,327 @@
+use super::CompactionState;
+use super::EngineGauges;
+use super::EngineMetrics;
+use super::EntryMeta;
+use super::KvsEngine;
+use super::Txn;
+use crate::Result;
+use std::io::Read;
+use std::io::Write;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+/// An engine of a type chosen at run time, such as by a command line option, so the types
+/// built over it needn't be generic over the engine.
+///
+/// It forwards every operation to the engine it was made from, through a virtual call. The
+/// `Bucket`s it hands out are of the `BoxedEngine`, not of the engine inside.
+pub struct BoxedEngine(Box<dyn DynEngine>);
+
+impl BoxedEngine {
+    pub fn new<E: KvsEngine>(engine: E) -> Self {
+        BoxedEngine(Box::new(engine))
+    }
+}
+
+impl Clone for BoxedEngine {
+    fn clone(&self) -> Self {
+        BoxedEngine(self.0.clone_box())
+    }
+}
+
+// `KvsEngine` with the generic methods and `Clone` made object safe, implemented by every
+// engine.
+trait DynEngine: Send + 'static {
+    fn clone_box(&self) -> Box<dyn DynEngine>;
+    fn set(&self, key: String, value: String) -> Result<()>;
+    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>>;
+    fn get_with_meta(&self, key: String) -> Result<Option<(String, EntryMeta)>>;
+    fn metadata(&self, key: String) -> Result<Option<EntryMeta>>;
+    fn remove(&self, key: String) -> Result<()>;
+    fn contains(&self, key: String) -> Result<bool>;
+    fn len(&self) -> Result<u64>;
+    fn keys(&self) -> Result<Vec<String>>;
+    fn keys_after(&self, after: Option<String>, limit: usize) -> Result<Vec<String>>;
+    fn range(&self, range: (Bound<String>, Bound<String>)) -> Result<Vec<(String, String)>>;
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>>;
+    fn find_by_value(&self, value: &str) -> Result<Vec<String>>;
+    fn is_empty(&self) -> Result<bool>;
+    fn incr(&self, key: String, delta: i64) -> Result<i64>;
+    fn rename(&self, old_key: String, new_key: String) -> Result<()>;
+    fn append(&self, key: String, suffix: String) -> Result<u64>;
+    fn acquire_lock(&self, key: String, owner: String, ttl: Duration) -> Result<bool>;
+    fn release_lock(&self, key: String, owner: String) -> Result<bool>;
+    fn get_range(&self, key: String, offset: u64, len: u64) -> Result<Option<String>>;
+    fn transaction(&self) -> Result<Txn>;
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()>;
+    fn name(&self) -> &'static str;
+    fn size_on_disk(&self) -> Result<u64>;
+    fn compact(&self) -> Result<()>;
+    fn compactions(&self) -> u64;
+    fn compaction_state(&self) -> CompactionState;
+    fn unsynced_bytes(&self) -> u64;
+    fn engine_metrics(&self) -> Result<EngineGauges>;
+    fn export(&self, writer: &mut dyn Write) -> Result<u64>;
+    fn import(&self, reader: &mut dyn Read) -> Result<u64>;
+}
+
+impl<E: KvsEngine> DynEngine for E {
+    fn clone_box(&self) -> Box<dyn DynEngine> {
+        Box::new(self.clone())
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsEngine::set(self, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvsEngine::get(self, key)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        KvsEngine::get_many(self, keys)
+    }
+
+    fn get_with_meta(&self, key: String) -> Result<Option<(String, EntryMeta)>> {
+        KvsEngine::get_with_meta(self, key)
+    }
+
+    fn metadata(&self, key: String) -> Result<Option<EntryMeta>> {
+        KvsEngine::metadata(self, key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvsEngine::remove(self, key)
+    }
+
+    fn contains(&self, key: String) -> Result<bool> {
+        KvsEngine::contains(self, key)
+    }
+
+    fn len(&self) -> Result<u64> {
+        KvsEngine::len(self)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        KvsEngine::keys(self)
+    }
+
+    fn keys_after(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        KvsEngine::keys_after(self, after, limit)
+    }
+
+    fn range(&self, range: (Bound<String>, Bound<String>)) -> Result<Vec<(String, String)>> {
+        KvsEngine::range(self, range)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        KvsEngine::scan_prefix(self, prefix)
+    }
+
+    fn find_by_value(&self, value: &str) -> Result<Vec<String>> {
+        KvsEngine::find_by_value(self, value)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        KvsEngine::is_empty(self)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        KvsEngine::incr(self, key, delta)
+    }
+
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        KvsEngine::rename(self, old_key, new_key)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<u64> {
+        KvsEngine::append(self, key, suffix)
+    }
+
+    fn acquire_lock(&self, key: String, owner: String, ttl: Duration) -> Result<bool> {
+        KvsEngine::acquire_lock(self, key, owner, ttl)
+    }
+
+    fn release_lock(&self, key: String, owner: String) -> Result<bool> {
+        KvsEngine::release_lock(self, key, owner)
+    }
+
+    fn get_range(&self, key: String, offset: u64, len: u64) -> Result<Option<String>> {
+        KvsEngine::get_range(self, key, offset, len)
+    }
+
+    fn transaction(&self) -> Result<Txn> {
+        KvsEngine::transaction(self)
+    }
+
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        KvsEngine::set_batch(self, pairs)
+    }
+
+    fn name(&self) -> &'static str {
+        KvsEngine::name(self)
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        KvsEngine::size_on_disk(self)
+    }
+
+    fn compact(&self) -> Result<()> {
+        KvsEngine::compact(self)
+    }
+
+    fn compactions(&self) -> u64 {
+        KvsEngine::compactions(self)
+    }
+
+    fn compaction_state(&self) -> CompactionState {
+        KvsEngine::compaction_state(self)
+    }
+
+    fn unsynced_bytes(&self) -> u64 {
+        KvsEngine::unsynced_bytes(self)
+    }
+
+    fn engine_metrics(&self) -> Result<EngineGauges> {
+        EngineMetrics::engine_metrics(self)
+    }
+
+    fn export(&self, writer: &mut dyn Write) -> Result<u64> {
+        KvsEngine::export(self, writer)
+    }
+
+    fn import(&self, reader: &mut dyn Read) -> Result<u64> {
+        KvsEngine::import(self, reader)
+    }
+}
+
+impl EngineMetrics for BoxedEngine {
+    fn engine_metrics(&self) -> Result<EngineGauges> {
+        self.0.engine_metrics()
+    }
+}
+
+impl KvsEngine for BoxedEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.0.get_many(keys)
+    }
+
+    fn get_with_meta(&self, key: String) -> Result<Option<(String, EntryMeta)>> {
+        self.0.get_with_meta(key)
+    }
+
+    fn metadata(&self, key: String) -> Result<Option<EntryMeta>> {
+        self.0.metadata(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn contains(&self, key: String) -> Result<bool> {
+        self.0.contains(key)
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.0.len()
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.0.keys()
+    }
+
+    fn keys_after(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.0.keys_after(after, limit)
+    }
+
+    fn range(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        self.0
+            .range((range.start_bound().cloned(), range.end_bound().cloned()))
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.0.scan_prefix(prefix)
+    }
+
+    fn find_by_value(&self, value: &str) -> Result<Vec<String>> {
+        self.0.find_by_value(value)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.0.is_empty()
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.0.incr(key, delta)
+    }
+
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        self.0.rename(old_key, new_key)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<u64> {
+        self.0.append(key, suffix)
+    }
+
+    fn acquire_lock(&self, key: String, owner: String, ttl: Duration) -> Result<bool> {
+        self.0.acquire_lock(key, owner, ttl)
+    }
+
+    fn release_lock(&self, key: String, owner: String) -> Result<bool> {
+        self.0.release_lock(key, owner)
+    }
+
+    fn get_range(&self, key: String, offset: u64, len: u64) -> Result<Option<String>> {
+        self.0.get_range(key, offset, len)
+    }
+
+    fn transaction(&self) -> Result<Txn> {
+        self.0.transaction()
+    }
+
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.0.set_batch(pairs)
+    }
+
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        self.0.size_on_disk()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.0.compact()
+    }
+
+    fn compactions(&self) -> u64 {
+        self.0.compactions()
+    }
+
+    fn compaction_state(&self) -> CompactionState {
+        self.0.compaction_state()
+    }
+
+    fn unsynced_bytes(&self) -> u64 {
+        self.0.unsynced_bytes()
+    }
+
+    fn export(&self, mut writer: impl Write) -> Result<u64> {
+        self.0.export(&mut writer)
+    }
+
+    fn import(&self, mut reader: impl Read) -> Result<u64> {
+        self.0.import(&mut reader)
+    }
+}
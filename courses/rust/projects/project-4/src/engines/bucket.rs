@@ -0,0 +1,217 @@
+use super::CompactionState;
+use super::EngineGauges;
+use super::EngineMetrics;
+use super::EntryMeta;
+use super::KvsEngine;
+use crate::KvsError;
+use crate::Result;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+/// A named keyspace within an engine, returned by `KvsEngine::bucket`. Keys set in a bucket
+/// are seen only through it, so applications sharing an engine can't collide.
+///
+/// A bucket stores its keys in the engine with a prefix: a NUL byte, the bucket's name and
+/// another NUL. The engine itself sees every bucket's keys so prefixed, and they count
+/// toward its key size limit. Transactions aren't supported in a bucket.
+#[derive(Clone)]
+pub struct Bucket<E: KvsEngine> {
+    engine: E,
+    prefix: String,
+}
+
+impl<E: KvsEngine> Bucket<E> {
+    pub(crate) fn new(engine: E, name: &str) -> Result<Self> {
+        Ok(Self {
+            engine,
+            prefix: prefix(name)?,
+        })
+    }
+
+    fn key(&self, key: String) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    // Take the bucket's prefix off the keys of `pairs`, all of which are in the bucket.
+    fn strip(&self, pairs: Vec<(String, String)>) -> Vec<(String, String)> {
+        pairs
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(&self.prefix)?.to_owned(), value)))
+            .collect()
+    }
+}
+
+/// Return the prefix of the keys of the bucket `name`, or an error if the name is invalid:
+/// empty, or containing a NUL.
+pub(crate) fn prefix(name: &str) -> Result<String> {
+    if name.is_empty() || name.contains('\0') {
+        return Err(KvsError::StringError(format!(
+            "invalid bucket name {:?}; names must be non-empty and without NULs",
+            name
+        )));
+    }
+    Ok(format!("\0{}\0", name))
+}
+
+/// The gauges are the whole engine's, not the bucket's alone.
+impl<E: KvsEngine> EngineMetrics for Bucket<E> {
+    fn engine_metrics(&self) -> Result<EngineGauges> {
+        self.engine.engine_metrics()
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Bucket<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.engine.set(self.key(key), value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(self.key(key))
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.engine
+            .get_many(keys.into_iter().map(|key| self.key(key)).collect())
+    }
+
+    fn get_with_meta(&self, key: String) -> Result<Option<(String, EntryMeta)>> {
+        self.engine.get_with_meta(self.key(key))
+    }
+
+    fn metadata(&self, key: String) -> Result<Option<EntryMeta>> {
+        self.engine.metadata(self.key(key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(self.key(key))
+    }
+
+    fn contains(&self, key: String) -> Result<bool> {
+        self.engine.contains(self.key(key))
+    }
+
+    /// Counts the bucket's keys, listing the engine's to find them.
+    fn len(&self) -> Result<u64> {
+        Ok(self.keys()?.len() as u64)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(self
+            .engine
+            .keys()?
+            .into_iter()
+            .filter_map(|key| key.strip_prefix(&self.prefix).map(str::to_owned))
+            .collect())
+    }
+
+    /// Pages through the engine's keys from the bucket's prefix, stopping at the first key
+    /// of another.
+    fn keys_after(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        // The prefix less its closing NUL sorts just before every key of the bucket.
+        let after = after.map_or_else(
+            || self.prefix[..self.prefix.len() - 1].to_owned(),
+            |key| self.key(key),
+        );
+        Ok(self
+            .engine
+            .keys_after(Some(after), limit)?
+            .into_iter()
+            .map_while(|key| key.strip_prefix(&self.prefix).map(str::to_owned))
+            .collect())
+    }
+
+    /// Scans the engine's keys in the bucket's part of `range`.
+    fn range(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(self.key(key.clone())),
+            Bound::Excluded(key) => Bound::Excluded(self.key(key.clone())),
+            Bound::Unbounded => Bound::Included(self.prefix.clone()),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included(self.key(key.clone())),
+            Bound::Excluded(key) => Bound::Excluded(self.key(key.clone())),
+            // The prefix with its closing NUL made a 1 sorts just after every key of the
+            // bucket.
+            Bound::Unbounded => {
+                Bound::Excluded(format!("{}\u{1}", &self.prefix[..self.prefix.len() - 1]))
+            }
+        };
+        Ok(self.strip(self.engine.range((start, end))?))
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        Ok(self.strip(self.engine.scan_prefix(&self.key(prefix.to_owned()))?))
+    }
+
+    /// Finds the keys of the whole engine set to `value`, and keeps the bucket's.
+    fn find_by_value(&self, value: &str) -> Result<Vec<String>> {
+        Ok(self
+            .engine
+            .find_by_value(value)?
+            .into_iter()
+            .filter_map(|key| key.strip_prefix(&self.prefix).map(str::to_owned))
+            .collect())
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.engine.incr(self.key(key), delta)
+    }
+
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        self.engine.rename(self.key(old_key), self.key(new_key))
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<u64> {
+        self.engine.append(self.key(key), suffix)
+    }
+
+    fn acquire_lock(&self, key: String, owner: String, ttl: Duration) -> Result<bool> {
+        self.engine.acquire_lock(self.key(key), owner, ttl)
+    }
+
+    fn release_lock(&self, key: String, owner: String) -> Result<bool> {
+        self.engine.release_lock(self.key(key), owner)
+    }
+
+    fn get_range(&self, key: String, offset: u64, len: u64) -> Result<Option<String>> {
+        self.engine.get_range(self.key(key), offset, len)
+    }
+
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.engine.set_batch(
+            pairs
+                .into_iter()
+                .map(|(key, value)| (self.key(key), value))
+                .collect(),
+        )
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    /// The size of the whole engine, since buckets share its files.
+    fn size_on_disk(&self) -> Result<u64> {
+        self.engine.size_on_disk()
+    }
+
+    /// Compacts the whole engine.
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    fn compactions(&self) -> u64 {
+        self.engine.compactions()
+    }
+
+    /// The state of the whole engine's compaction.
+    fn compaction_state(&self) -> CompactionState {
+        self.engine.compaction_state()
+    }
+
+    /// The unsynced bytes of the whole engine.
+    fn unsynced_bytes(&self) -> u64 {
+        self.engine.unsynced_bytes()
+    }
+}
@@ -0,0 +1,150 @@
+//! How the records of a `KvStore` log are encoded. Each log's header names the codec its
+//! records are in, so logs in different codecs can share a directory.
+
+use super::Command;
+use crate::KvsError;
+use crate::Result;
+use bincode::Options;
+use rmp_serde::decode;
+use rmp_serde::Deserializer;
+use rmp_serde::Serializer;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::result;
+
+/// The encoding of log records; see `KvStore::with_codec`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// MessagePack, which logs were always written in before the codec could be chosen.
+    #[default]
+    MessagePack,
+    /// Bincode, with integers as varints.
+    Bincode,
+    /// A line of JSON per record, for logs that can be read and searched as text.
+    JsonLines,
+}
+
+impl Codec {
+    // The number of the codec in log headers.
+    pub(crate) fn id(self) -> u16 {
+        match self {
+            Self::MessagePack => 0,
+            Self::Bincode => 1,
+            Self::JsonLines => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u16) -> Option<Self> {
+        match id {
+            0 => Some(Self::MessagePack),
+            1 => Some(Self::Bincode),
+            2 => Some(Self::JsonLines),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn log_codec(self) -> &'static dyn LogCodec {
+        match self {
+            Self::MessagePack => &MessagePackCodec,
+            Self::Bincode => &BincodeCodec,
+            Self::JsonLines => &JsonLinesCodec,
+        }
+    }
+}
+
+/// Why a record couldn't be decoded.
+#[derive(Debug)]
+pub(crate) enum RecordError {
+    /// The bytes end partway through the record.
+    Truncated,
+    /// The bytes don't decode as a command.
+    Malformed,
+    IO(io::Error),
+}
+
+impl From<io::Error> for RecordError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::IO(err),
+        }
+    }
+}
+
+/// Encodes commands as log records, and decodes them again.
+pub(crate) trait LogCodec {
+    fn encode(&self, command: &Command, writer: &mut dyn Write) -> Result<()>;
+
+    /// Decode the record `reader` starts with, reading no further than its end, so the
+    /// next record can be decoded from where it stops.
+    fn decode(&self, reader: &mut dyn BufRead) -> result::Result<Command, RecordError>;
+}
+
+struct MessagePackCodec;
+
+impl LogCodec for MessagePackCodec {
+    fn encode(&self, command: &Command, writer: &mut dyn Write) -> Result<()> {
+        command.serialize(&mut Serializer::new(writer))?;
+        Ok(())
+    }
+
+    fn decode(&self, reader: &mut dyn BufRead) -> result::Result<Command, RecordError> {
+        match Command::deserialize(&mut Deserializer::new(reader)) {
+            Ok(command) => Ok(command),
+            Err(decode::Error::InvalidMarkerRead(err))
+            | Err(decode::Error::InvalidDataRead(err)) => Err(err.into()),
+            Err(_) => Err(RecordError::Malformed),
+        }
+    }
+}
+
+struct BincodeCodec;
+
+// Lengths are capped at what MessagePack can encode, so a damaged one can't ask for more.
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(u32::MAX as u64)
+}
+
+impl LogCodec for BincodeCodec {
+    fn encode(&self, command: &Command, writer: &mut dyn Write) -> Result<()> {
+        bincode_options()
+            .serialize_into(writer, command)
+            .map_err(|err| match *err {
+                bincode::ErrorKind::Io(err) => KvsError::IO(err),
+                err => KvsError::StringError(format!("Bincode: {}", err)),
+            })
+    }
+
+    fn decode(&self, reader: &mut dyn BufRead) -> result::Result<Command, RecordError> {
+        bincode_options()
+            .deserialize_from(reader)
+            .map_err(|err| match *err {
+                bincode::ErrorKind::Io(err) => err.into(),
+                _ => RecordError::Malformed,
+            })
+    }
+}
+
+struct JsonLinesCodec;
+
+impl LogCodec for JsonLinesCodec {
+    fn encode(&self, command: &Command, writer: &mut dyn Write) -> Result<()> {
+        let mut line = serde_json::to_vec(command)
+            .map_err(|err| KvsError::StringError(format!("JSON: {}", err)))?;
+        line.push(b'\n');
+        writer.write_all(&line)?;
+        Ok(())
+    }
+
+    fn decode(&self, reader: &mut dyn BufRead) -> result::Result<Command, RecordError> {
+        let mut line = Vec::new();
+        reader.read_until(b'\n', &mut line)?;
+        if line.last() != Some(&b'\n') {
+            return Err(RecordError::Truncated);
+        }
+        serde_json::from_slice(&line).map_err(|_| RecordError::Malformed)
+    }
+}
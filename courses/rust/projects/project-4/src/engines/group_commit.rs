@@ -0,0 +1,163 @@
+use crate::sync::Condvar;
+use crate::sync::Mutex;
+use crate::KvsError;
+use crate::Result;
+use std::io;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+struct State {
+    /// Number of records appended so far. A record's ticket is the count including it.
+    written: u64,
+    /// Tickets up to this one are synced to disk.
+    durable: u64,
+    /// Bytes appended since the last sync.
+    pending_bytes: u64,
+    /// Set when a sync fails; every later wait fails with it, since the log may have lost
+    /// records.
+    failed: Option<(io::ErrorKind, String)>,
+    closed: bool,
+}
+
+/// What the commit thread flushes and syncs: a store's writer.
+pub(super) trait SyncLog {
+    /// Flush what has been appended to the log and sync it to disk.
+    fn sync_log(&mut self) -> io::Result<()>;
+}
+
+/// Lets a `KvStore`'s writers share syncs of the log.
+///
+/// Writers append records without flushing, take a ticket, and wait for a commit thread,
+/// which flushes and syncs whatever has been appended once the window passes or enough
+/// bytes pile up. Records reach the file as soon as their positions are taken, since
+/// that seeks the writer, so readers never wait on the commit thread. `appended` and
+/// `synced` must be called with the writer lock held.
+pub(super) struct GroupCommit {
+    window: Duration,
+    max_bytes: u64,
+    state: Mutex<State>,
+    appended: Condvar,
+    committed: Condvar,
+}
+
+impl GroupCommit {
+    pub fn new(window: Duration, max_bytes: u64) -> Self {
+        Self {
+            window,
+            max_bytes,
+            state: Mutex::new(State {
+                written: 0,
+                durable: 0,
+                pending_bytes: 0,
+                failed: None,
+                closed: false,
+            }),
+            appended: Condvar::new(),
+            committed: Condvar::new(),
+        }
+    }
+
+    /// Note a record of `bytes` appended to the writer's buffer. Return its ticket.
+    pub fn appended(&self, bytes: u64) -> u64 {
+        let mut state = self.state.lock();
+        state.written += 1;
+        state.pending_bytes += bytes;
+        self.appended.notify_one();
+        state.written
+    }
+
+    /// Block until the record with `ticket` is synced.
+    pub fn wait(&self, ticket: u64) -> Result<()> {
+        let mut state = self.state.lock();
+        loop {
+            if let Some((kind, msg)) = &state.failed {
+                return Err(KvsError::IO(io::Error::new(*kind, msg.clone())));
+            }
+            if state.durable >= ticket {
+                return Ok(());
+            }
+            state = self.committed.wait(state);
+        }
+    }
+
+    /// Note that every record appended so far is synced.
+    pub fn synced(&self) {
+        let mut state = self.state.lock();
+        state.durable = state.written;
+        state.pending_bytes = 0;
+        self.committed.notify_all();
+    }
+
+    /// Stop the commit thread once it has synced what's left.
+    pub fn close(&self) {
+        self.state.lock().closed = true;
+        self.appended.notify_one();
+    }
+
+    /// Run the commit thread until `close` is called.
+    pub fn run(&self, writer: &Mutex<impl SyncLog>) {
+        loop {
+            {
+                let mut state = self.state.lock();
+                while state.written == state.durable && !state.closed {
+                    state = self.appended.wait(state);
+                }
+                if state.written == state.durable {
+                    return;
+                }
+                // Gather more records until the window ends or enough bytes pile up.
+                let deadline = Instant::now() + self.window;
+                while state.pending_bytes < self.max_bytes && !state.closed {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    state = self.appended.wait_timeout(state, deadline - now).0;
+                }
+            }
+
+            let mut writer = writer.lock();
+            match writer.sync_log() {
+                Ok(()) => self.synced(),
+                Err(err) => {
+                    let mut state = self.state.lock();
+                    state.failed = Some((err.kind(), err.to_string()));
+                    self.committed.notify_all();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// A `KvStore`'s hold on a `GroupCommit` and its thread, which stops once the last
+/// handle is dropped.
+pub(super) struct CommitHandle(Arc<GroupCommit>);
+
+impl CommitHandle {
+    pub fn spawn(
+        commit: Arc<GroupCommit>,
+        writer: Arc<Mutex<impl SyncLog + Send + 'static>>,
+    ) -> Result<Self> {
+        let thread_commit = commit.clone();
+        thread::Builder::new().spawn(move || thread_commit.run(&writer))?;
+        Ok(Self(commit))
+    }
+}
+
+impl Deref for CommitHandle {
+    type Target = GroupCommit;
+
+    fn deref(&self) -> &GroupCommit {
+        &self.0
+    }
+}
+
+impl Drop for CommitHandle {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
@@ -0,0 +1,61 @@
+use crate::sync::Condvar;
+use crate::sync::Mutex;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// The set of keys currently locked by callers, shared by every clone of a store.
+#[derive(Default)]
+pub(crate) struct KeyLocks {
+    locked: Mutex<HashSet<String>>,
+    released: Condvar,
+}
+
+impl KeyLocks {
+    /// Block until no other guard holds `key`, then hold it until the returned guard is dropped.
+    pub(crate) fn lock(self: &Arc<Self>, key: String) -> KeyGuard {
+        let mut locked = self.locked.lock();
+        while locked.contains(&key) {
+            locked = self.released.wait(locked);
+        }
+        locked.insert(key.clone());
+        KeyGuard {
+            locks: self.clone(),
+            key,
+        }
+    }
+
+    /// Hold `key` if no other guard does, without blocking.
+    pub(crate) fn try_lock(self: &Arc<Self>, key: String) -> Option<KeyGuard> {
+        let mut locked = self.locked.lock();
+        if !locked.insert(key.clone()) {
+            return None;
+        }
+        Some(KeyGuard {
+            locks: self.clone(),
+            key,
+        })
+    }
+}
+
+/// Exclusive hold on one key, released when dropped.
+///
+/// Only other `lock_key` callers are excluded: plain `set`, `get` and `remove`
+/// calls go ahead regardless, so every read-modify-write of a key must take the lock.
+pub struct KeyGuard {
+    locks: Arc<KeyLocks>,
+    key: String,
+}
+
+impl KeyGuard {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        let mut locked = self.locks.locked.lock();
+        locked.remove(&self.key);
+        self.locks.released.notify_all();
+    }
+}
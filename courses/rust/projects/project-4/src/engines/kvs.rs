@@ -1,47 +1,288 @@
+use super::background_sync::BackgroundSync;
+use super::background_sync::SyncHandle;
+use super::bloom::Bloom;
+use super::codec::Codec;
+use super::codec::RecordError;
+use super::group_commit::CommitHandle;
+use super::group_commit::GroupCommit;
+use super::key_lock::KeyLocks;
+use super::log_header;
+use super::log_header::LogHeader;
+use super::log_header::HEADER_LEN;
+use super::log_header::LOG_FORMAT_VERSION;
+use super::txn::Txn;
+use super::txn::Versions;
+use super::CompactionState;
+use super::EngineGauges;
+use super::EngineMetrics;
+use super::EntryMeta;
+use super::KeyGuard;
 use super::KvsEngine;
+use super::SizeLimits;
+use crate::data_dir::LOCK_FILE;
+use crate::dump::Exporter;
+use crate::error::ResultExt;
+use crate::nesting::nested;
+use crate::sync::Mutex;
+use crate::thread_pool::SharedQueueThreadPool;
+use crate::thread_pool::ThreadPool;
+use crate::trace::Span;
+use crate::CorruptionKind;
+use crate::ErrorContext;
 use crate::KvsError;
 use crate::Result;
-use rmp_serde::decode;
-use rmp_serde::Deserializer;
-use rmp_serde::Serializer;
+use memmap2::Mmap;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::convert::Into;
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
+use std::fs::TryLockError;
 use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::mem;
+use std::ops::Bound;
+use std::ops::RangeBounds;
 use std::path::Path;
 use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::MutexGuard;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
+mod fsck;
+mod history;
+mod index;
+mod load;
+mod reader;
+mod readers;
+mod values;
+mod writer;
+pub use self::fsck::FsckReport;
+pub use self::fsck::LogReport;
+pub use self::history::Revision;
+use self::index::Index;
+use self::reader::KvStoreReader;
+use self::values::ValueIndex;
+use self::writer::CompactionProgress;
+use self::writer::KvStoreWriter;
+
+#[derive(Clone, Copy)]
 struct CommandPosition {
     log_number: u64,
     offset: u64,
     bytes: u64,
+    // The version of the value the record sets.
+    version: u64,
 }
 
 #[derive(Clone)]
 pub struct KvStore {
-    readers: Arc<RwLock<HashMap<u64, BufReader<File>>>>,
-    writer: Arc<RwLock<BufWriter<File>>>,
-    index: Arc<RwLock<HashMap<String, CommandPosition>>>,
-    log_number: Arc<RwLock<u64>>,
+    reader: KvStoreReader,
+    // None if the store was opened read-only.
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    // The locked lock file of a writable store, released once every clone is dropped.
+    _dir_lock: Option<Arc<File>>,
     path: PathBuf,
-    uncompacted_bytes: Arc<RwLock<u64>>,
+    compactions: Arc<AtomicU64>,
+    progress: Arc<CompactionProgress>,
+    key_locks: Arc<KeyLocks>,
+    versions: Arc<Versions>,
+    group_commit: Option<Arc<CommitHandle>>,
+    background_sync: Option<Arc<SyncHandle>>,
+    // Bytes written to the logs since they were last synced.
+    unsynced: Arc<AtomicU64>,
+    compaction_threshold: u64,
+    tombstone_horizon: TombstoneHorizon,
+    // The dead bytes past which writes are stalled, and how.
+    write_stall: Option<(u64, WriteStall)>,
+    size_limits: SizeLimits,
+    torn_tail: Option<TornTail>,
+}
+
+/// A record left partly written at the end of the last log, by a process that died while
+/// appending it, and dropped when the store was opened; see `KvStore::open`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TornTail {
+    pub log_number: u64,
+    /// Where the record started, which the log was cut back to.
+    pub offset: u64,
+    pub dropped_bytes: u64,
+}
+
+/// How `KvStore::open_with` opens a store, after the RocksDB options of the same names.
+/// The default opens it for writing as `KvStore::open` does, creating it if there's none.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpenOptions {
+    /// Create the directory and a new store if there's no store in it, rather than fail with
+    /// `KvsError::StoreNotFound`. A read-only store is never created: a directory without
+    /// one opens as an empty store.
+    pub create_if_missing: bool,
+    /// Fail with `KvsError::StoreExists` if there's a store in the directory already.
+    pub error_if_exists: bool,
+    /// Open the store for reading only; see `KvStore::open_read_only`.
+    pub read_only: bool,
+    /// Fail rather than cut off a partly written record; see `KvStore::open_strict`. A
+    /// read-only store leaves such a record out either way.
+    pub strict: bool,
+    /// Threads to index the logs on as the store opens, reading as many logs at once, and
+    /// applying each, oldest first, to the shards of the index side by side. The default is
+    /// one for each CPU.
+    pub index_threads: u32,
+    /// Shards to split the index into by key hash, each with a lock of its own, so a write
+    /// holds up only reads of the keys in its shard. The default is one for each CPU.
+    pub index_shards: u32,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            create_if_missing: true,
+            error_if_exists: false,
+            read_only: false,
+            strict: false,
+            index_threads: thread::available_parallelism().map_or(1, |n| n.get() as u32),
+            index_shards: thread::available_parallelism().map_or(1, |n| n.get() as u32),
+        }
+    }
+}
+
+/// When removes call for a compaction however few bytes they hold, so a store that mostly
+/// removes doesn't keep their records, and the values they removed, until the compaction
+/// threshold is crossed; see `KvStore::with_tombstone_horizon`. Unset limits don't apply.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TombstoneHorizon {
+    /// Compact once the oldest remove made since the last compaction is this old.
+    pub max_age: Option<Duration>,
+    /// Compact once this many removes have been made since the last compaction.
+    pub max_count: Option<u64>,
+}
+
+/// What writes do once the logs hold more dead bytes than a store allows, as when
+/// compaction keeps failing; see `KvStore::with_write_stall`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteStall {
+    /// Sleep this long before each write, slowing writers down while compaction catches up.
+    Delay(Duration),
+    /// Refuse writes with `KvsError::WriteStalled`.
+    Refuse,
+}
+
+/// The size and state of a `KvStore`'s logs and index; see `KvStore::stats`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Keys that are set.
+    pub keys: u64,
+    /// Log files, counting the one being written.
+    pub logs: u64,
+    /// Bytes of every log.
+    pub log_bytes: u64,
+    /// Bytes of the logs held by overwritten and removed values, which compaction
+    /// reclaims. An estimate: the records of a batch are counted as if written alone.
+    pub dead_bytes: u64,
+    /// When the logs were last compacted, if ever.
+    pub last_compaction: Option<SystemTime>,
+    /// Bytes the index holds, counting its keys and entries but not the skip list's own
+    /// nodes.
+    pub index_bytes: u64,
+    /// Bytes written to the logs since they were last synced to disk, which a power loss
+    /// could lose; see `KvsEngine::unsynced_bytes`.
+    pub unsynced_bytes: u64,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-enum Command {
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) enum Command {
+    /// A set without a version, as logs were written before format 3.
     Set(String, String),
+    /// A remove without a stamp, as logs were written before format 4.
     Remove(String),
+    /// Sets and removes applied together, or not at all.
+    Batch(#[serde(deserialize_with = "nested")] Vec<Command>),
+    /// A set of the value's version and when it was written, as logs were written in
+    /// format 3.
+    Put(String, String, PutMeta),
+    /// A set with its meta.
+    SetStamped(String, String, RecordMeta),
+    /// A remove with the meta of when it was made.
+    RemoveStamped(String, RecordMeta),
+}
+
+/// What a record says of the write it makes. A value set before versions were kept has the
+/// default: version 0, written at no known time.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct RecordMeta {
+    /// The store's count of writes when the record was written, so a key's version grows
+    /// with every write to it.
+    pub(crate) version: u64,
+    /// When the record was written, in milliseconds since the Unix epoch.
+    pub(crate) written: u64,
+    /// When the record was written by the store's own clock, in nanoseconds since the Unix
+    /// epoch. It follows the wall clock, but never goes back nor repeats, so it orders the
+    /// writes even when the wall clock is set back. 0 for records from before format 4.
+    pub(crate) stamp: u64,
+}
+
+/// The meta of a format 3 set, which has no stamp.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PutMeta {
+    version: u64,
+    written: u64,
+}
+
+impl From<PutMeta> for RecordMeta {
+    fn from(meta: PutMeta) -> Self {
+        Self {
+            version: meta.version,
+            written: meta.written,
+            stamp: 0,
+        }
+    }
+}
+
+impl RecordMeta {
+    // What an engine tells of a value of `size` bytes set by a record with this meta.
+    fn entry_meta(self, size: usize) -> EntryMeta {
+        EntryMeta {
+            version: self.version,
+            written: (self.written > 0).then(|| UNIX_EPOCH + Duration::from_millis(self.written)),
+            stamp: (self.stamp > 0).then_some(self.stamp),
+            size: size as u64,
+        }
+    }
+}
+
+/// What a set or remove record does, whichever format it's in.
+pub(crate) enum Change {
+    Set(String, String, RecordMeta),
+    Remove(String, RecordMeta),
+}
+
+impl Command {
+    /// The change a set or remove record makes; None for a batch.
+    pub(crate) fn change(self) -> Option<Change> {
+        match self {
+            Command::Set(key, value) => Some(Change::Set(key, value, RecordMeta::default())),
+            Command::Put(key, value, meta) => Some(Change::Set(key, value, meta.into())),
+            Command::SetStamped(key, value, meta) => Some(Change::Set(key, value, meta)),
+            Command::Remove(key) => Some(Change::Remove(key, RecordMeta::default())),
+            Command::RemoveStamped(key, meta) => Some(Change::Remove(key, meta)),
+            Command::Batch(_) => None,
+        }
+    }
 }
 
 fn log_path(path: &Path, log_number: u64) -> PathBuf {
@@ -49,6 +290,11 @@ fn log_path(path: &Path, log_number: u64) -> PathBuf {
     path.join(file_name)
 }
 
+// The bloom filter of a sealed log is kept beside it as <number>.kvs.bloom.
+fn bloom_path(path: &Path, log_number: u64) -> PathBuf {
+    path.join(format!("{}.kvs.bloom", log_number))
+}
+
 fn get_log_numbers(dir: &Path) -> io::Result<Vec<u64>> {
     // Format of a log file name is <number>.kvs.log
     let mut log_numbers: Vec<u64> = fs::read_dir(dir)?
@@ -66,202 +312,1070 @@ fn get_log_numbers(dir: &Path) -> io::Result<Vec<u64>> {
     Ok(log_numbers)
 }
 
+// The contexts of errors in the commonest operations on a store's files.
+fn listing(dir: &Path) -> ErrorContext {
+    ErrorContext::new("listing the logs in").file(dir)
+}
+
+fn opening(log_path: &Path) -> ErrorContext {
+    ErrorContext::new("opening").file(log_path)
+}
+
+fn indexing(log_path: &Path) -> ErrorContext {
+    ErrorContext::new("indexing").file(log_path)
+}
+
+/// Where `load_index` puts the keys a log sets and removes.
+trait IndexSink {
+    fn set(&mut self, key: String, pos: CommandPosition);
+    fn remove(&mut self, key: String);
+}
+
+impl IndexSink for BTreeMap<String, CommandPosition> {
+    fn set(&mut self, key: String, pos: CommandPosition) {
+        self.insert(key, pos);
+    }
+
+    fn remove(&mut self, key: String) {
+        BTreeMap::remove(self, &key);
+    }
+}
+
+// Index the records of a log, adding the key of each to `keys` if given, and record its
+// codec in `codecs` once its header is read.
 fn load_index(
     log_number: u64,
-    index: &mut HashMap<String, CommandPosition>,
+    index: &mut impl IndexSink,
     reader: &mut BufReader<File>,
+    codecs: &mut HashMap<u64, Codec>,
+    mut keys: Option<&mut HashSet<String>>,
 ) -> Result<()> {
-    let mut des = Deserializer::new(reader);
-    let mut offset = 0;
-    loop {
-        match Command::deserialize(&mut des) {
-            Ok(Command::Set(key, _)) => {
-                let bytes = des.get_mut().stream_position()? - offset;
-                index.insert(
-                    key,
-                    CommandPosition {
-                        log_number,
-                        offset,
-                        bytes,
-                    },
-                );
-            }
-            Ok(Command::Remove(key)) => {
-                index.remove(&key);
+    let (mut offset, codec) = log_header::records_start(reader, log_number)?;
+    codecs.insert(log_number, codec);
+    // Running out of bytes between records is the normal end of the log.
+    while !reader.fill_buf()?.is_empty() {
+        let corruption = |kind| KvsError::Corruption {
+            log_number,
+            offset,
+            kind,
+        };
+        let command = codec.log_codec().decode(reader);
+        if let (Some(keys), Ok(command)) = (keys.as_deref_mut(), &command) {
+            command.add_keys(keys);
+        }
+        match command {
+            Ok(Command::Batch(commands)) => {
+                for command in commands {
+                    let bytes = encoded_len(codec, &command)?;
+                    match command.change() {
+                        Some(Change::Set(key, _, meta)) => {
+                            index.set(
+                                key,
+                                CommandPosition {
+                                    log_number,
+                                    offset,
+                                    bytes,
+                                    version: meta.version,
+                                },
+                            );
+                        }
+                        Some(Change::Remove(key, _)) => index.remove(key),
+                        None => return Err(corruption(CorruptionKind::Malformed)),
+                    }
+                }
             }
-            Err(decode::Error::InvalidMarkerRead(err)) => match err.kind() {
-                std::io::ErrorKind::UnexpectedEof => {
-                    break;
+            Ok(command) => match command.change() {
+                Some(Change::Set(key, _, meta)) => {
+                    let bytes = reader.stream_position()? - offset;
+                    index.set(
+                        key,
+                        CommandPosition {
+                            log_number,
+                            offset,
+                            bytes,
+                            version: meta.version,
+                        },
+                    );
                 }
-                _ => return Err(KvsError::IO(err)),
+                Some(Change::Remove(key, _)) => index.remove(key),
+                None => unreachable!("batches are matched above"),
             },
-            Err(err) => return Err(KvsError::Decode(err)),
+            Err(RecordError::Truncated) => return Err(corruption(CorruptionKind::Truncated)),
+            Err(RecordError::Malformed) => return Err(corruption(CorruptionKind::Malformed)),
+            Err(RecordError::IO(err)) => return Err(KvsError::IO(err)),
         }
-        offset = des.get_mut().stream_position()?;
+        offset = reader.stream_position()?;
     }
     Ok(())
 }
 
-const COMPACTION_THRESHOLD_BYTES: u64 = 1048576;
+// Index the log at `path` on its own, as log 0, and read back every value the index points
+// at. Return how many keys the log leaves set.
+#[cfg(feature = "fuzz")]
+pub(crate) fn index_log(path: &Path) -> Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut index: BTreeMap<String, CommandPosition> = BTreeMap::new();
+    let mut codecs = HashMap::new();
+    load_index(0, &mut index, &mut reader, &mut codecs, None)?;
+    for (key, pos) in &index {
+        read_value(&mut reader, codecs[&0], pos, key)?;
+    }
+    Ok(index.len())
+}
+
+impl Command {
+    // Add the keys the command sets or removes to `keys`.
+    fn add_keys(&self, keys: &mut HashSet<String>) {
+        match self {
+            Command::Set(key, _)
+            | Command::Put(key, _, _)
+            | Command::SetStamped(key, _, _)
+            | Command::Remove(key)
+            | Command::RemoveStamped(key, _) => {
+                keys.insert(key.clone());
+            }
+            Command::Batch(commands) => {
+                for command in commands {
+                    command.add_keys(keys);
+                }
+            }
+        }
+    }
+}
+
+// Size of a command on its own in `codec`, used to account for the members of a batch
+// record.
+fn encoded_len(codec: Codec, command: &Command) -> Result<u64> {
+    let mut bytes = Vec::new();
+    codec.log_codec().encode(command, &mut bytes)?;
+    Ok(bytes.len() as u64)
+}
+
+// The error for the record at `pos` when the index points into a log that isn't open.
+fn missing_log(pos: &CommandPosition) -> KvsError {
+    KvsError::Corruption {
+        log_number: pos.log_number,
+        offset: pos.offset,
+        kind: CorruptionKind::MissingLog,
+    }
+}
+
+// Read the value `key` was set to by the `codec` record at `pos`, with its meta.
+fn read_value(
+    reader: &mut BufReader<File>,
+    codec: Codec,
+    pos: &CommandPosition,
+    key: &str,
+) -> Result<(String, RecordMeta)> {
+    reader.seek(SeekFrom::Start(pos.offset))?;
+    decode_value(reader, codec, pos, key)
+}
+
+// Decode the `codec` record at the start of `reader`, which is at `pos`, and return the
+// value it sets `key` to, with its meta.
+fn decode_value(
+    reader: &mut dyn BufRead,
+    codec: Codec,
+    pos: &CommandPosition,
+    key: &str,
+) -> Result<(String, RecordMeta)> {
+    let corruption = |kind| KvsError::Corruption {
+        log_number: pos.log_number,
+        offset: pos.offset,
+        kind,
+    };
+    match codec.log_codec().decode(reader) {
+        Ok(Command::Batch(commands)) => commands
+            .into_iter()
+            .rev()
+            .find_map(|command| match command.change() {
+                Some(Change::Set(set_key, value, meta)) if set_key == key => Some((value, meta)),
+                _ => None,
+            })
+            .ok_or(KvsError::UnexpectedCommand),
+        Ok(command) => match command.change() {
+            Some(Change::Set(_, value, meta)) => Ok((value, meta)),
+            _ => Err(KvsError::UnexpectedCommand),
+        },
+        Err(RecordError::Truncated) => Err(corruption(CorruptionKind::Truncated)),
+        Err(RecordError::Malformed) => Err(corruption(CorruptionKind::Malformed)),
+        Err(RecordError::IO(err)) => Err(KvsError::IO(err)),
+    }
+}
+
+const DEFAULT_COMPACTION_THRESHOLD_BYTES: u64 = 1048576;
+
+// Holds when the logs were last compacted, in seconds since the Unix epoch.
+const COMPACTED_FILE: &str = "kvs.compacted";
+
+// Pairs `export` reads per page.
+const EXPORT_PAGE: usize = 1000;
 
 impl KvStore {
     /// Open the KvStore at a given path. Return the KvStore.
+    ///
+    /// The directory is locked while the store is open, so that no other process, nor this
+    /// one, can open it for writing as well. Return `KvsError::AlreadyLocked` if it's taken.
+    /// Clones share the lock, so reopening a directory fails until every clone of the store
+    /// that has it, in whatever thread, has been dropped.
+    ///
+    /// A record left partly written at the end of the last log, by a process that died
+    /// while appending it, is cut off so the log can be appended to again; `torn_tail` says
+    /// what was dropped. Any other damage fails the open with `KvsError::Corruption`.
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(path, OpenOptions::default())
+    }
+
+    /// Open the KvStore at a given path as `open` does, but fail with
+    /// `KvsError::Corruption` rather than cut off a partly written record.
+    pub fn open_strict(path: impl Into<PathBuf>) -> Result<Self> {
+        let options = OpenOptions {
+            strict: true,
+            ..OpenOptions::default()
+        };
+        Self::open_with(path, options)
+    }
+
+    /// Open the KvStore at a given path for reading only: no log is created or written and
+    /// the directory isn't locked, so it can be read while a server writes to it. Writes
+    /// fail with `KvsError::ReadOnly`.
+    ///
+    /// The store holds what the logs held when it was opened, less a last record a writer
+    /// was still appending. The logs it read stay open, so compaction by the writer doesn't
+    /// pull them from under it.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        let options = OpenOptions {
+            read_only: true,
+            ..OpenOptions::default()
+        };
+        Self::open_with(path, options)
+    }
+
+    /// Open the KvStore at a given path as `options` say. Whether there's a store already
+    /// is told by whether the directory holds any logs.
+    pub fn open_with(path: impl Into<PathBuf>, options: OpenOptions) -> Result<Self> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        let exists = match get_log_numbers(&path) {
+            Ok(log_numbers) => !log_numbers.is_empty(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(KvsError::IO(err).with_context(|| listing(&path))),
+        };
+        if exists && options.error_if_exists {
+            return Err(KvsError::StoreExists(path));
+        }
+        if !exists && !options.create_if_missing {
+            return Err(KvsError::StoreNotFound(path));
+        }
+        if options.read_only {
+            return Self::load(path, false, false, options);
+        }
+        fs::create_dir_all(&path).context(|| ErrorContext::new("creating").file(&path))?;
+        let dir_lock = lock_dir(&path)?;
+        let mut store = Self::load(path, true, options.strict, options)?;
+        store._dir_lock = Some(Arc::new(dir_lock));
+        Ok(store)
+    }
 
-        let log_numbers = get_log_numbers(&path)?;
-        let mut index = HashMap::new();
+    fn load(path: PathBuf, writable: bool, strict: bool, options: OpenOptions) -> Result<Self> {
+        let log_numbers = get_log_numbers(&path).context(|| listing(&path))?;
+        // A last log in an older format is sealed rather than appended to, so the build that
+        // wrote it can still tell the records that follow are newer than it.
+        let upgrade = match log_numbers.last() {
+            Some(&last) if writable => older_format(&path, last)?,
+            _ => false,
+        };
         let mut readers = HashMap::new();
+        let mut blooms = HashMap::new();
+        let mut codecs = HashMap::new();
+        let mut torn_tail = None;
 
+        let mut logs = Vec::new();
         for &log_number in &log_numbers {
-            let rfile = File::open(log_path(&path, log_number))?;
-            let mut reader = BufReader::new(rfile);
-            load_index(log_number, &mut index, &mut reader)?;
-            readers.insert(log_number, reader);
+            // Every log but the last is sealed. Its filter is rebuilt from the log if it's
+            // missing or unreadable, and saved again unless the store is read-only.
+            let sealed = upgrade || Some(&log_number) != log_numbers.last();
+            let mut gather_keys = false;
+            if sealed {
+                match Bloom::read(&bloom_path(&path, log_number)) {
+                    Some(bloom) => {
+                        blooms.insert(log_number, bloom);
+                    }
+                    None => gather_keys = true,
+                }
+            }
+            logs.push((log_number, log_path(&path, log_number), gather_keys));
+        }
+        let pool = SharedQueueThreadPool::new(options.index_threads.max(1))?;
+        let index = Arc::new(Index::new(options.index_shards as usize));
+        let reading = options.index_threads as usize;
+        for log in load::index_logs(&pool, logs, index.shards().len(), reading) {
+            let log = log?;
+            let log_number = log.log_number;
+            let log_path = log_path(&path, log_number);
+            let bloom_path = bloom_path(&path, log_number);
+            if let Some(codec) = log.codec {
+                codecs.insert(log_number, codec);
+            }
+            match log.result.context(|| indexing(&log_path)) {
+                // A writer may be partway through appending to the last log; leave out the
+                // record it hasn't finished.
+                Err(KvsError::Corruption {
+                    kind: CorruptionKind::Truncated,
+                    ..
+                }) if !writable && Some(&log_number) == log_numbers.last() => {}
+                // A writer died partway through it; cut it off so appends follow the last
+                // whole record.
+                Err(KvsError::Corruption {
+                    offset,
+                    kind: CorruptionKind::Truncated,
+                    ..
+                }) if !strict && Some(&log_number) == log_numbers.last() => {
+                    let truncating = || {
+                        ErrorContext::new("truncating")
+                            .file(&log_path)
+                            .offset(offset)
+                    };
+                    let file = File::options()
+                        .write(true)
+                        .open(&log_path)
+                        .context(truncating)?;
+                    let len = file.metadata().context(truncating)?.len();
+                    file.set_len(offset).context(truncating)?;
+                    file.sync_all().context(truncating)?;
+                    torn_tail = Some(TornTail {
+                        log_number,
+                        offset,
+                        dropped_bytes: len - offset,
+                    });
+                }
+                result => result?,
+            }
+            if let Some(keys) = log.keys {
+                let bloom = Bloom::of(keys.iter().map(String::as_str));
+                if writable {
+                    bloom
+                        .write(&bloom_path)
+                        .context(|| ErrorContext::new("writing").file(&bloom_path))?;
+                }
+                blooms.insert(log_number, bloom);
+            }
+            readers.insert(log_number, log.reader);
+            load::apply(&pool, &index, log.shards)?;
         }
 
-        let &log_number = log_numbers.last().unwrap_or(&0);
-        let writer = new_log_file(&path, log_number, &mut readers)?;
+        let &last_log_number = log_numbers.last().unwrap_or(&0);
+        // New logs are written in the codec of the last one until `with_codec` says
+        // otherwise.
+        let codec = codecs.get(&last_log_number).copied().unwrap_or_default();
+        let log_number = if upgrade {
+            last_log_number + 1
+        } else {
+            last_log_number
+        };
+        let log_writer = if writable {
+            // The last log is written to again, so a filter left from when it was sealed,
+            // by a compaction cut short, would go stale.
+            remove_if_exists(&bloom_path(&path, log_number))?;
+            Some(new_log_file(
+                &path,
+                log_number,
+                codec,
+                &mut readers,
+                &mut codecs,
+            )?)
+        } else {
+            None
+        };
 
+        let reader = KvStoreReader::new(&path, readers, blooms, codecs, index);
+        *reader.values.write() = values::load(&path, &reader)?;
+        let versions = Arc::new(Versions::default());
+        let progress = Arc::new(CompactionProgress::default());
+        let writer = match log_writer {
+            Some(log_writer) => Some(Arc::new(Mutex::new(KvStoreWriter::new(
+                log_writer,
+                log_number,
+                codec,
+                path.clone(),
+                reader.clone(),
+                versions.clone(),
+                progress.clone(),
+            )?))),
+            None => None,
+        };
+        let unsynced = match &writer {
+            Some(writer) => writer.lock().unsynced(),
+            None => Arc::new(AtomicU64::new(0)),
+        };
         Ok(Self {
-            readers: Arc::new(RwLock::new(readers)),
-            writer: Arc::new(RwLock::new(writer)),
-            index: Arc::new(RwLock::new(index)),
-            log_number: Arc::new(RwLock::new(log_number)),
+            reader,
+            writer,
+            _dir_lock: None,
             path,
-            uncompacted_bytes: Arc::new(RwLock::new(0)),
+            compactions: Arc::new(AtomicU64::new(0)),
+            progress,
+            key_locks: Arc::new(KeyLocks::default()),
+            versions,
+            group_commit: None,
+            background_sync: None,
+            unsynced,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD_BYTES,
+            tombstone_horizon: TombstoneHorizon::default(),
+            write_stall: None,
+            size_limits: SizeLimits::default(),
+            torn_tail,
         })
     }
 
-    fn compact(&self) -> Result<()> {
-        let mut log_number = self.log_number.write().unwrap();
-        *log_number += 1;
-        let mut readers = self.readers.write().unwrap();
-        let mut writer = self.writer.write().unwrap();
-
-        *writer = new_log_file(&self.path, *log_number, &mut readers)?;
-        let mut index = self.index.write().unwrap();
-
-        for command_pos in &mut index.values_mut() {
-            let reader = readers.get_mut(&command_pos.log_number).unwrap();
-            reader.seek(SeekFrom::Start(command_pos.offset))?;
-            let mut source = reader.take(command_pos.bytes);
-            command_pos.log_number = *log_number;
-            command_pos.offset = writer.stream_position()?;
-            let mut inner = writer.get_mut();
-            io::copy(&mut source, &mut inner)?;
+    /// Commit writes in groups: rather than flushing the log for every write, writers wait
+    /// for a thread that flushes and syncs everything written in the last `window`, or
+    /// sooner once `max_bytes` are waiting. A write returns once it is synced to disk, so
+    /// this also makes writes durable against power loss, which plain flushes are not.
+    pub fn with_group_commit(mut self, window: Duration, max_bytes: u64) -> Result<Self> {
+        let writer = self.writer.clone().ok_or(KvsError::ReadOnly)?;
+        let commit = Arc::new(GroupCommit::new(window, max_bytes));
+        writer.lock().set_group_commit(commit.clone());
+        self.group_commit = Some(Arc::new(CommitHandle::spawn(commit, writer)?));
+        Ok(self)
+    }
+
+    /// Sync the log to disk every `interval` on a thread of its own, so writes, which return
+    /// once flushed to the OS, are on disk within about `interval` of being made. Compaction
+    /// syncs the log it writes before removing the old ones. Once a sync fails, every write
+    /// fails with its error, since the log may have lost records.
+    ///
+    /// How much a power loss could still lose is told by `unsynced_bytes`.
+    pub fn with_background_sync(mut self, interval: Duration) -> Result<Self> {
+        let writer = self.writer.clone().ok_or(KvsError::ReadOnly)?;
+        writer.lock().set_background_sync();
+        let sync = BackgroundSync::new(interval, self.unsynced.clone());
+        self.background_sync = Some(Arc::new(SyncHandle::spawn(sync, writer)?));
+        Ok(self)
+    }
+
+    /// Compact once more than `bytes` of the logs hold overwritten or removed values,
+    /// rather than the default of 1 MiB.
+    pub fn with_compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Compact once the removes made since the last compaction pass `horizon`, as well as
+    /// once the compaction threshold is crossed. Like the threshold, it's checked as writes
+    /// finish, so removes left by the last write before the store goes quiet stay until the
+    /// next one; and only removes made since the store was opened count.
+    pub fn with_tombstone_horizon(mut self, horizon: TombstoneHorizon) -> Self {
+        self.tombstone_horizon = horizon;
+        self
+    }
+
+    /// Stall writes as `stall` says while the logs hold more than `dead_bytes` of
+    /// overwritten and removed values, so disk use can't run away when compaction can't keep
+    /// up, as when it keeps failing. Set it well above the compaction threshold, which
+    /// compaction normally keeps the dead bytes under. A write about to be refused tries
+    /// compacting first, and goes ahead if that brings the dead bytes under the limit.
+    ///
+    /// Whether writes are stalled, and how many have been, is in `compaction_state`.
+    pub fn with_write_stall(mut self, dead_bytes: u64, stall: WriteStall) -> Self {
+        self.write_stall = Some((dead_bytes, stall));
+        self
+    }
+
+    /// Keep an index of the keys set to each value, so `find_by_value` looks them up rather
+    /// than reading every pair. It's built now from the values in the logs, and from then on
+    /// kept up to date by every write, in memory, where it holds each key and value once more.
+    ///
+    /// The index is checkpointed to `kvs.values` beside the logs now and after every
+    /// compaction, and a store opened with the file there keeps the index again, reading
+    /// only the values written since the checkpoint. Remove the file while the store is
+    /// closed to stop keeping it. Does nothing if the store keeps one already.
+    pub fn create_value_index(&self) -> Result<()> {
+        // Writes wait meanwhile, so none is left out of the index.
+        let _writer = self.writer()?;
+        if self.reader.values.read().is_some() {
+            return Ok(());
+        }
+        let mut values = ValueIndex::default();
+        for (key, value) in self.reader.read_pairs(self.reader.index.iter())? {
+            values.set(key, value);
+        }
+        values::write_checkpoint(&self.path, &self.reader.index, &values)?;
+        *self.reader.values.write() = Some(values);
+        Ok(())
+    }
+
+    /// Return whether the store keeps an index of values; see `create_value_index`.
+    pub fn has_value_index(&self) -> bool {
+        self.reader.values.read().is_some()
+    }
+
+    /// Refuse writes of keys and values over `limits`.
+    pub fn with_size_limits(mut self, limits: SizeLimits) -> Self {
+        self.size_limits = limits;
+        self
+    }
+
+    /// Read from sealed logs, those no longer written to, through memory maps rather than
+    /// seeking and reading their files, so reads of them share no lock and make no system
+    /// call. Compaction seals the log it writes, so compacted values are read through a map.
+    /// A read-only store maps all its logs but the last, which a writer may still be
+    /// appending to.
+    pub fn with_mmap_reads(self) -> Result<Self> {
+        {
+            // The writer's lock keeps compaction from starting a log in between.
+            let writer = self.writer.as_ref().map(|writer| writer.lock());
+            self.reader.map_logs(&self.path)?;
+            if let Some(mut writer) = writer {
+                writer.set_mmap_reads();
+            }
+        }
+        Ok(self)
+    }
+
+    /// Write the records of new logs in `codec`, rather than that of the last log, or
+    /// MessagePack in a new directory. Logs in other codecs are compacted into one in
+    /// `codec` now, unless the log being written is the only one and holds no records, in
+    /// which case its header is just rewritten. Each log's codec is in its header, so the
+    /// store can be opened again without this.
+    pub fn with_codec(self, codec: Codec) -> Result<Self> {
+        let compact = self.writer()?.set_codec(codec)?;
+        if compact {
+            self.compact()?;
+        }
+        Ok(self)
+    }
+
+    /// Check that every log in the directory at `path` decodes cleanly, without modifying it.
+    /// Return a `KvsError::Corruption` locating the first bad record.
+    pub fn verify(path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        let mut index: BTreeMap<String, CommandPosition> = BTreeMap::new();
+        let mut codecs = HashMap::new();
+        for log_number in get_log_numbers(&path).context(|| listing(&path))? {
+            let log_path = log_path(&path, log_number);
+            let file = File::open(&log_path).context(|| opening(&log_path))?;
+            let mut reader = BufReader::new(file);
+            load_index(log_number, &mut index, &mut reader, &mut codecs, None)
+                .context(|| indexing(&log_path))?;
         }
+        Ok(())
+    }
 
-        let stale_log_numbers: Vec<u64> = readers
-            .keys()
-            .filter(|&&number| number < *log_number)
-            .cloned()
-            .collect();
+    /// Lock `key` against other `lock_key` callers in this process, blocking until it is free.
+    /// Hold the guard across a read-modify-write of the key to keep it from interleaving
+    /// with another.
+    pub fn lock_key(&self, key: String) -> KeyGuard {
+        self.key_locks.lock(key)
+    }
+
+    /// Lock `key` if no other caller holds it. Return `None` instead of blocking.
+    pub fn try_lock_key(&self, key: String) -> Option<KeyGuard> {
+        self.key_locks.try_lock(key)
+    }
+
+    /// Return whether a sealed log may hold a record of `key`, by their bloom filters. False
+    /// means none does, so a search of the sealed logs for the key can skip them all. The
+    /// log being written isn't covered.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.reader
+            .blooms
+            .read()
+            .values()
+            .any(|bloom| bloom.may_contain(key))
+    }
 
-        for log_number in stale_log_numbers {
-            readers.remove(&log_number);
-            let log_path = log_path(&self.path, log_number);
-            fs::remove_file(log_path)?;
+    /// Return how many keys are set, and how big the logs and index are.
+    pub fn stats(&self) -> Result<StoreStats> {
+        let entry_bytes = mem::size_of::<(String, CommandPosition)>();
+        let (mut keys, mut live_bytes, mut index_bytes) = (0, 0, 0);
+        for (key, pos) in self.reader.index.iter() {
+            keys += 1;
+            live_bytes += pos.bytes;
+            index_bytes += (key.capacity() + entry_bytes) as u64;
         }
 
-        let mut uncompacted_bytes = self.uncompacted_bytes.write().unwrap();
-        *uncompacted_bytes = 0;
+        let readers = self.reader.readers.read();
+        let mut log_bytes = 0;
+        for reader in readers.values() {
+            log_bytes += reader.get_ref().metadata()?.len();
+        }
+        let last_compaction = fs::read_to_string(self.path.join(COMPACTED_FILE))
+            .ok()
+            .and_then(|secs| secs.trim().parse().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        Ok(StoreStats {
+            keys,
+            logs: readers.len() as u64,
+            log_bytes,
+            dead_bytes: log_bytes.saturating_sub(live_bytes + readers.len() as u64 * HEADER_LEN),
+            last_compaction,
+            index_bytes,
+            unsynced_bytes: self.unsynced_bytes(),
+        })
+    }
 
+    /// Return the partly written record `open` cut off the end of the last log, if any.
+    pub fn torn_tail(&self) -> Option<TornTail> {
+        self.torn_tail
+    }
+
+    /// Return the smallest key that is set, if any.
+    pub fn first_key(&self) -> Option<String> {
+        self.reader.index.first_key()
+    }
+
+    /// Return the largest key that is set, if any.
+    pub fn last_key(&self) -> Option<String> {
+        self.reader.index.last_key()
+    }
+
+    // The store's writer, locked, or `KvsError::ReadOnly` if the store was opened
+    // read-only.
+    fn writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        let writer = self.writer.as_deref().ok_or(KvsError::ReadOnly)?;
+        Ok(writer.lock())
+    }
+
+    // Compact if the logs hold more dead bytes than the write stall allows, and stall the
+    // write as it says if they still do. Call without the writer locked.
+    fn admit_write(&self) -> Result<()> {
+        let Some((limit, stall)) = self.write_stall else {
+            return Ok(());
+        };
+        let dead_bytes = {
+            let mut writer = self.writer()?;
+            // A delayed write compacts once it's made, as every write does over the
+            // threshold; a refused one never is, so it tries here instead. The error of a
+            // failed compaction went to the write after which it ran.
+            let refused = stall == WriteStall::Refuse;
+            if refused && writer.uncompacted_bytes() > limit && writer.compact().is_ok() {
+                self.compactions.fetch_add(1, Ordering::SeqCst);
+            }
+            writer.uncompacted_bytes()
+        };
+        let stalled = dead_bytes > limit;
+        self.progress.set_stalled(stalled);
+        match stall {
+            _ if !stalled => Ok(()),
+            WriteStall::Delay(delay) => {
+                Span::write_stall(dead_bytes, limit).in_scope(|| thread::sleep(delay));
+                Ok(())
+            }
+            WriteStall::Refuse => Err(KvsError::WriteStalled { dead_bytes, limit }),
+        }
+    }
+
+    // Wait until group commit has synced the record with `ticket`, then compact if enough
+    // of the logs is dead, or the removes have passed the tombstone horizon. Call without
+    // the writer locked.
+    fn finish_write(&self, ticket: Option<u64>) -> Result<()> {
+        if let (Some(group_commit), Some(ticket)) = (&self.group_commit, ticket) {
+            Span::group_commit().in_scope(|| group_commit.wait(ticket))?;
+        }
+        if let Some(background_sync) = &self.background_sync {
+            background_sync.check()?;
+        }
+        let due = {
+            let writer = self.writer()?;
+            writer.uncompacted_bytes() > self.compaction_threshold
+                || writer.tombstones_due(&self.tombstone_horizon)
+        };
+        if due {
+            self.compact()?;
+        }
         Ok(())
     }
+
+    /// Validate and apply a transaction's writes as one batch record. See `Txn`.
+    pub(super) fn commit(
+        &self,
+        start: u64,
+        reads: &BTreeSet<String>,
+        writes: BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        for (key, value) in &writes {
+            self.size_limits.check(key, value.as_deref())?;
+        }
+        self.admit_write()?;
+        let ticket = {
+            let mut writer = self.writer()?;
+            if self
+                .versions
+                .changed_since(start, reads.iter().chain(writes.keys()))
+            {
+                return Err(KvsError::TransactionConflict);
+            }
+            if writes.is_empty() {
+                return Ok(());
+            }
+            writer.write_batch(writes)?
+        };
+        self.finish_write(ticket)
+    }
+}
+
+/// Reports the logs as segments, with the dead bytes compaction would reclaim. It keeps no
+/// cache of values, so it has no cache hits.
+impl EngineMetrics for KvStore {
+    fn engine_metrics(&self) -> Result<EngineGauges> {
+        let log_numbers = get_log_numbers(&self.path)?;
+        let mut disk_bytes = 0;
+        for &log_number in &log_numbers {
+            disk_bytes += fs::metadata(log_path(&self.path, log_number))?.len();
+        }
+        Ok(EngineGauges {
+            disk_bytes,
+            segments: Some(log_numbers.len() as u64),
+            dead_bytes: Some(self.progress.state().dead_bytes),
+            cache_hits: None,
+            cache_misses: None,
+        })
+    }
 }
 
 impl KvsEngine for KvStore {
     /// Set the value of a string key to a string. Return an error if the value is not written successfully.
     fn set(&self, key: String, value: String) -> Result<()> {
-        {
-            let cmd = Command::Set(key.clone(), value);
-            let mut writer = self.writer.write().unwrap();
-            let offset = writer.stream_position()?;
-            let mut inner = writer.get_mut();
-            cmd.serialize(&mut Serializer::new(&mut inner))?;
-            let bytes = writer.stream_position()? - offset;
-            let mut index = self.index.write().unwrap();
-            if let Some(cmd) = index.insert(
-                key,
-                CommandPosition {
-                    log_number: *self.log_number.read().unwrap(),
-                    offset,
-                    bytes,
-                },
-            ) {
-                let mut uncompacted_bytes = self.uncompacted_bytes.write().unwrap();
-                *uncompacted_bytes += cmd.bytes;
+        self.size_limits.check(&key, Some(&value))?;
+        self.admit_write()?;
+        let ticket = self.writer()?.set(key, value)?;
+        self.finish_write(ticket)
+    }
+
+    /// Read the key's value and write the sum under the writer lock, so no other write can
+    /// come between the two.
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.admit_write()?;
+        let (value, ticket) = {
+            let mut writer = self.writer()?;
+            let current = self.reader.get(&key)?;
+            let value = super::add_delta(current, delta)?;
+            let digits = value.to_string();
+            self.size_limits.check(&key, Some(&digits))?;
+            (value, writer.set(key, digits)?)
+        };
+        self.finish_write(ticket)?;
+        Ok(value)
+    }
+
+    /// Read the value and write it back as one batch record, a set of the new key and a
+    /// remove of the old, under the writer lock.
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        self.size_limits.check(&new_key, None)?;
+        self.admit_write()?;
+        let ticket = {
+            let mut writer = self.writer()?;
+            let value = self.reader.get(&old_key)?.ok_or(KvsError::KeyNotFound)?;
+            if old_key == new_key {
+                return Ok(());
             }
-            writer.flush()?;
-        }
+            let writes = BTreeMap::from([(new_key, Some(value)), (old_key, None)]);
+            writer.write_batch(writes)?
+        };
+        self.finish_write(ticket)
+    }
 
-        if *self.uncompacted_bytes.read().unwrap() > COMPACTION_THRESHOLD_BYTES {
-            self.compact()?;
-        }
+    /// Read the value and write it back with `suffix` on the end under the writer lock, as
+    /// `incr` does, so the whole value is written to the log again.
+    fn append(&self, key: String, suffix: String) -> Result<u64> {
+        self.admit_write()?;
+        let (len, ticket) = {
+            let mut writer = self.writer()?;
+            let mut value = self.reader.get(&key)?.unwrap_or_default();
+            value.push_str(&suffix);
+            self.size_limits.check(&key, Some(&value))?;
+            (value.len() as u64, writer.set(key, value)?)
+        };
+        self.finish_write(ticket)?;
+        Ok(len)
+    }
 
-        Ok(())
+    /// Read the lock and write it back under the writer lock, as `incr` does, so two owners
+    /// can't both find it free.
+    fn acquire_lock(&self, key: String, owner: String, ttl: Duration) -> Result<bool> {
+        self.admit_write()?;
+        let ticket = {
+            let mut writer = self.writer()?;
+            let current = self.reader.get(&key)?;
+            let lock = match super::lock_for(current.as_deref(), &owner, ttl)? {
+                Some(lock) => lock,
+                None => return Ok(false),
+            };
+            self.size_limits.check(&key, Some(&lock))?;
+            writer.set(key, lock)?
+        };
+        self.finish_write(ticket)?;
+        Ok(true)
+    }
+
+    fn release_lock(&self, key: String, owner: String) -> Result<bool> {
+        self.admit_write()?;
+        let ticket = {
+            let mut writer = self.writer()?;
+            let current = self.reader.get(&key)?;
+            if !super::holds_lock(current.as_deref(), &owner)? {
+                return Ok(false);
+            }
+            writer.remove(key)?
+        };
+        self.finish_write(ticket)?;
+        Ok(true)
     }
 
     /// Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
+    ///
+    /// Reads take no lock on the index, and only shared locks otherwise, so they don't wait
+    /// on each other, nor on writes but for the moment a compaction removes old logs.
     fn get(&self, key: String) -> Result<Option<String>> {
-        let index = self.index.read().unwrap();
-        if let Some(pos) = index.get(&key) {
-            let mut readers = self.readers.write().unwrap();
-            let mut reader = readers.get_mut(&pos.log_number).unwrap();
-            reader.seek(SeekFrom::Start(pos.offset))?;
-
-            let mut des = Deserializer::new(&mut reader);
-            match Command::deserialize(&mut des) {
-                Ok(Command::Set(_, value)) => Ok(Some(value)),
-                Ok(Command::Remove(_)) => Err(KvsError::UnexpectedCommand),
-                Err(decode::Error::InvalidMarkerRead(err)) => Err(KvsError::IO(err)),
-                Err(err) => Err(KvsError::Decode(err)),
-            }
-        } else {
-            Ok(None)
-        }
+        self.reader.get(&key)
+    }
+
+    /// Values are read in the order they lie in the logs rather than that of `keys`, so
+    /// each log is read front to back.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.reader.get_many(&keys)
+    }
+
+    fn get_with_meta(&self, key: String) -> Result<Option<(String, EntryMeta)>> {
+        Ok(self.reader.get_with_meta(&key)?.map(|(value, meta)| {
+            let meta = meta.entry_meta(value.len());
+            (value, meta)
+        }))
     }
 
     /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
     fn remove(&self, key: String) -> Result<()> {
-        let mut index = self.index.write().unwrap();
-        if let Some(old_cmd) = index.remove(&key) {
-            let cmd = Command::Remove(key.clone());
-            let mut writer = self.writer.write().unwrap();
-            let mut inner = writer.get_mut();
-            cmd.serialize(&mut Serializer::new(&mut inner))?;
-            writer.flush()?;
-            {
-                let mut uncompacted_bytes = self.uncompacted_bytes.write().unwrap();
-                *uncompacted_bytes += old_cmd.bytes;
+        self.admit_write()?;
+        let ticket = self.writer()?.remove(key)?;
+        self.finish_write(ticket)
+    }
+
+    /// Answered from the in-memory index; the log isn't read.
+    fn contains(&self, key: String) -> Result<bool> {
+        Ok(self.reader.index.contains_key(&key))
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.reader.index.len() as u64)
+    }
+
+    /// Keys come back in order.
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.reader.index.iter().map(|(key, _)| key).collect())
+    }
+
+    fn keys_after(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let from = match after {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        Ok(self
+            .reader
+            .index
+            .range((from, Bound::Unbounded))
+            .take(limit)
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    fn range(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        self.reader.read_pairs(self.reader.index.range(range))
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.reader.read_pairs(
+            self.reader
+                .index
+                .range(prefix.to_owned()..)
+                .take_while(|(key, _)| key.starts_with(prefix)),
+        )
+    }
+
+    /// Looked up in the value index if the store keeps one; see `create_value_index`.
+    fn find_by_value(&self, value: &str) -> Result<Vec<String>> {
+        if let Some(values) = self.reader.values.read().as_ref() {
+            return Ok(values.find(value));
+        }
+        Ok(self
+            .reader
+            .read_pairs(self.reader.index.iter())?
+            .into_iter()
+            .filter(|(_, pair_value)| pair_value == value)
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    /// Set several keys, flushing the log once for the whole batch.
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in &pairs {
+            self.size_limits.check(key, Some(value))?;
+        }
+        self.admit_write()?;
+        let ticket = self.writer()?.set_batch(pairs)?;
+        self.finish_write(ticket)
+    }
+
+    fn transaction(&self) -> Result<Txn> {
+        Ok(Txn::new(self.clone(), self.versions.clone()))
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        let mut size = 0;
+        for log_number in get_log_numbers(&self.path)? {
+            size += fs::metadata(log_path(&self.path, log_number))?.len();
+        }
+        Ok(size)
+    }
+
+    /// Rewrite the live values into a new log and remove the old ones. The new log is
+    /// sealed, with a bloom filter of its keys and, with mmap reads on, mapped; later writes
+    /// go to another.
+    ///
+    /// Writes wait for the whole compaction, but reads go on, from the old logs until the
+    /// index points at the new one, and wait only while the old logs are removed.
+    fn compact(&self) -> Result<()> {
+        self.writer()?.compact()?;
+        self.compactions.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn compactions(&self) -> u64 {
+        self.compactions.load(Ordering::SeqCst)
+    }
+
+    fn compaction_state(&self) -> CompactionState {
+        self.progress.state()
+    }
+
+    /// Counts the records appended and the logs compaction writes, until a sync by group
+    /// commit or the background sync. With neither, only the OS syncs the logs, so the
+    /// count only grows.
+    fn unsynced_bytes(&self) -> u64 {
+        self.unsynced.load(Ordering::SeqCst)
+    }
+
+    /// Reads the values a page of keys at a time, so the pairs held at once are bounded.
+    fn export(&self, writer: impl Write) -> Result<u64> {
+        let mut exporter = Exporter::new(writer)?;
+        let mut after: Option<String> = None;
+        loop {
+            let from = match &after {
+                Some(key) => Bound::Excluded(key.clone()),
+                None => Bound::Unbounded,
+            };
+            let entries = self.reader.index.range((from, Bound::Unbounded));
+            let page = self.reader.read_pairs(entries.take(EXPORT_PAGE))?;
+            let last_page = page.len() < EXPORT_PAGE;
+            for (key, value) in page {
+                after = Some(key.clone());
+                exporter.pair(key, value)?;
             }
-            if *self.uncompacted_bytes.read().unwrap() > COMPACTION_THRESHOLD_BYTES {
-                self.compact()?;
+            if last_page {
+                return exporter.finish();
             }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
         }
     }
 }
 
+// Lock the directory at `path` for a writer, recording this process's PID in the lock file.
+// The lock is advisory, and released when the returned file is closed.
+fn lock_dir(path: &Path) -> Result<File> {
+    let lock_path = path.join(LOCK_FILE);
+    let locking = || ErrorContext::new("locking").file(&lock_path);
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .context(locking)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid).context(locking)?;
+            return Err(KvsError::AlreadyLocked {
+                pid: pid.trim().parse().ok(),
+            });
+        }
+        Err(TryLockError::Error(err)) => return Err(err).context(locking),
+    }
+    file.set_len(0).context(locking)?;
+    write!(file, "{}", process::id()).context(locking)?;
+    file.flush().context(locking)?;
+    Ok(file)
+}
+
+// Whether log `log_number` holds records in a format older than this build writes.
+fn older_format(path: &Path, log_number: u64) -> Result<bool> {
+    let log_path = log_path(path, log_number);
+    let file = File::open(&log_path).context(|| opening(&log_path))?;
+    let mut reader = BufReader::new(file);
+    match LogHeader::read(&mut reader, log_number) {
+        Ok(Some(header)) => Ok(header.format_version < LOG_FORMAT_VERSION),
+        // Format 0, unless it's empty.
+        Ok(None) => Ok(!reader
+            .fill_buf()
+            .context(|| ErrorContext::new("reading").file(&log_path))?
+            .is_empty()),
+        // A bad header is dealt with as the log is loaded.
+        Err(_) => Ok(false),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).context(|| ErrorContext::new("removing").file(path))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Map the sealed log `log_number` into memory.
+fn map_log(path: &Path, log_number: u64) -> Result<Mmap> {
+    let log_path = log_path(path, log_number);
+    let mapping = || ErrorContext::new("mapping").file(&log_path);
+    let file = File::open(&log_path).context(mapping)?;
+    // SAFETY: only sealed logs are mapped, never the newest, and nothing changes a sealed
+    // log: writes append to the newest log, `load` cuts a torn tail off only the newest, and
+    // compaction and `KvStore::fsck` repairs write new logs and remove the old ones, which
+    // leaves the maps of removed logs as they were. So the mapped bytes stay as they are
+    // while the map lives. Another program editing the logs would break this, as it would
+    // break the store anyway.
+    unsafe { Mmap::map(&file) }.context(mapping)
+}
+
+// Open log `new_log_number` for appending, creating it with a header for `codec` records if
+// it's new or empty.
 fn new_log_file(
     path: &Path,
     new_log_number: u64,
+    codec: Codec,
     readers: &mut HashMap<u64, BufReader<File>>,
+    codecs: &mut HashMap<u64, Codec>,
 ) -> Result<BufWriter<File>> {
     let log_path = log_path(path, new_log_number);
+    let creating = || ErrorContext::new("creating").file(&log_path);
 
     let mut wfile = File::options()
         .create(true)
-        .write(true)
         .append(true)
-        .open(&log_path)?;
-    wfile.seek(SeekFrom::End(0))?;
-    let writer = BufWriter::new(wfile);
-    let rfile = File::open(&log_path)?;
+        .open(&log_path)
+        .context(creating)?;
+    wfile.seek(SeekFrom::End(0)).context(creating)?;
+    let new = wfile.metadata().context(creating)?.len() == 0;
+    let mut writer = BufWriter::new(wfile);
+    if new {
+        LogHeader::new(codec).write(&mut writer).context(creating)?;
+        writer.flush().context(creating)?;
+        codecs.insert(new_log_number, codec);
+    }
+    let rfile = File::open(&log_path).context(|| opening(&log_path))?;
     let reader = BufReader::new(rfile);
     readers.insert(new_log_number, reader);
     Ok(writer)
@@ -0,0 +1,186 @@
+//! Checking a kvs data directory offline, and repairing it by rewriting what can still be
+//! read into a clean compacted log.
+
+use super::bloom_path;
+use super::get_log_numbers;
+use super::indexing;
+use super::listing;
+use super::load_index;
+use super::lock_dir;
+use super::log_path;
+use super::missing_log;
+use super::new_log_file;
+use super::opening;
+use super::read_value;
+use super::remove_if_exists;
+use super::Bloom;
+use super::Codec;
+use super::Command;
+use super::CommandPosition;
+use super::KvStore;
+use crate::error::ResultExt;
+use crate::ErrorContext;
+use crate::KvsError;
+use crate::Result;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// What `KvStore::fsck` found in a data directory.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Each log, in order.
+    pub logs: Vec<LogReport>,
+    /// Keys set by the records that could be read.
+    pub keys: u64,
+    /// Sealed logs whose bloom filter is missing or unreadable.
+    pub missing_filters: Vec<u64>,
+    /// Bloom filters of logs that are gone, or of the log being written.
+    pub orphaned_files: Vec<PathBuf>,
+    /// Whether the directory was rewritten.
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    /// Return whether any log is damaged.
+    pub fn is_damaged(&self) -> bool {
+        self.logs.iter().any(|log| log.damage.is_some())
+    }
+}
+
+/// What `KvStore::fsck` found in a log.
+#[derive(Debug)]
+pub struct LogReport {
+    pub log_number: u64,
+    pub bytes: u64,
+    /// Why the log couldn't be read to its end, if it couldn't. Records before the damage
+    /// were read; those after it can't be found.
+    pub damage: Option<KvsError>,
+}
+
+impl KvStore {
+    /// Read every log in the directory at `path` to its end, and report the damage, the
+    /// missing bloom filters and the files left behind, without changing anything.
+    ///
+    /// With `repair`, the directory is locked and every value that could be read is
+    /// rewritten into a new compacted log with a fresh filter, and the old logs and filters
+    /// are removed. Writes in the damaged part of a log are lost, so keys they overwrote or
+    /// removed may come back. Logs in a format this build doesn't read are never repaired
+    /// away: repair fails with `KvsError::UnsupportedLogFormat` instead.
+    pub fn fsck(path: impl Into<PathBuf>, repair: bool) -> Result<FsckReport> {
+        let path = path.into();
+        let _dir_lock = if repair { Some(lock_dir(&path)?) } else { None };
+        let log_numbers = get_log_numbers(&path).context(|| listing(&path))?;
+        let mut report = FsckReport::default();
+        let mut index = BTreeMap::new();
+        let mut readers = HashMap::new();
+        let mut codecs = HashMap::new();
+        for &log_number in &log_numbers {
+            let log_path = log_path(&path, log_number);
+            let file = File::open(&log_path).context(|| opening(&log_path))?;
+            let bytes = file.metadata().context(|| opening(&log_path))?.len();
+            let mut reader = BufReader::new(file);
+            let damage = load_index(log_number, &mut index, &mut reader, &mut codecs, None)
+                .context(|| indexing(&log_path))
+                .err();
+            let sealed = Some(&log_number) != log_numbers.last();
+            if sealed && Bloom::read(&bloom_path(&path, log_number)).is_none() {
+                report.missing_filters.push(log_number);
+            }
+            report.logs.push(LogReport {
+                log_number,
+                bytes,
+                damage,
+            });
+            readers.insert(log_number, reader);
+        }
+        report.keys = index.len() as u64;
+
+        for entry in fs::read_dir(&path)? {
+            let file = entry?.path();
+            let log_number = file
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".kvs.bloom"))
+                .and_then(|number| number.parse::<u64>().ok());
+            if let Some(log_number) = log_number {
+                if !log_numbers.contains(&log_number) || Some(&log_number) == log_numbers.last() {
+                    report.orphaned_files.push(file);
+                }
+            }
+        }
+        report.orphaned_files.sort();
+
+        if repair {
+            for log in &mut report.logs {
+                if let Some(KvsError::UnsupportedLogFormat { .. }) = log.damage {
+                    return Err(log.damage.take().unwrap());
+                }
+            }
+            // First, since an orphan may have the number the new log is about to get.
+            for file in &report.orphaned_files {
+                remove_if_exists(file)?;
+            }
+            rewrite(&path, &log_numbers, &index, &mut readers, &mut codecs)?;
+            report.repaired = true;
+        }
+        Ok(report)
+    }
+}
+
+// Write the values `index` points at into a sealed log after the others, with its filter,
+// start an empty log after it, then remove the old ones. Until they're gone, the new log
+// overrides them, so a repair cut short can be run again. The new logs are in the codec
+// of the last one, as `KvStore::open` would write them.
+fn rewrite(
+    path: &Path,
+    log_numbers: &[u64],
+    index: &BTreeMap<String, CommandPosition>,
+    readers: &mut HashMap<u64, BufReader<File>>,
+    codecs: &mut HashMap<u64, Codec>,
+) -> Result<()> {
+    let codec = log_numbers
+        .last()
+        .and_then(|last| codecs.get(last))
+        .copied()
+        .unwrap_or_default();
+    let compacted = log_numbers.last().map_or(0, |last| last + 1);
+    let compacted_path = log_path(path, compacted);
+    let compacting = || ErrorContext::new("compacting into").file(&compacted_path);
+    let mut writer = new_log_file(path, compacted, codec, readers, codecs)?;
+    for (key, pos) in index {
+        let (Some(reader), Some(&codec)) = (
+            readers.get_mut(&pos.log_number),
+            codecs.get(&pos.log_number),
+        ) else {
+            return Err(missing_log(pos));
+        };
+        let (value, meta) = read_value(reader, codec, pos, key).context(|| {
+            ErrorContext::new("reading")
+                .file(log_path(path, pos.log_number))
+                .offset(pos.offset)
+                .key(key)
+        })?;
+        codec
+            .log_codec()
+            .encode(&Command::SetStamped(key.clone(), value, meta), &mut writer)
+            .context(compacting)?;
+    }
+    writer.flush().context(compacting)?;
+    writer.get_ref().sync_all().context(compacting)?;
+    Bloom::of(index.keys().map(String::as_str)).write(&bloom_path(path, compacted))?;
+    new_log_file(path, compacted + 1, codec, readers, codecs)?;
+
+    for &log_number in log_numbers {
+        readers.remove(&log_number);
+        let log_path = log_path(path, log_number);
+        fs::remove_file(&log_path).context(|| ErrorContext::new("removing").file(&log_path))?;
+        remove_if_exists(&bloom_path(path, log_number))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! Reading back the earlier writes to a key, which the logs keep until compaction drops
+//! them.
+
+use super::log_header;
+use super::log_path;
+use super::Change;
+use super::Command;
+use super::KvStore;
+use crate::EntryMeta;
+use crate::Result;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+
+/// A write to a key found in a store's logs; see `KvStore::history`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Revision {
+    /// The value written, or None for a remove.
+    pub value: Option<String>,
+    /// The version and time of the write, and the size of the value; 0 for a remove. A
+    /// remove from before format 4 has version 0 and no time.
+    pub meta: EntryMeta,
+}
+
+impl KvStore {
+    /// Return the writes to `key` still in the logs, oldest first.
+    ///
+    /// This is best effort: compaction keeps only the live value of each key, so how far
+    /// back it goes depends on when the store last compacted, and after a compaction only
+    /// the live value is left. Logs are read through rather than looked up in the index,
+    /// skipping sealed logs whose filter rules the key out, so it's slow next to `get`.
+    pub fn history(&self, key: &str) -> Result<Vec<Revision>> {
+        let mut log_numbers: Vec<u64> = self.reader.codecs.read().keys().copied().collect();
+        log_numbers.sort_unstable();
+        let mut revisions = Vec::new();
+        // The latest version read, so the copies a compaction running meanwhile writes of
+        // values already read are left out.
+        let mut latest = 0;
+        for log_number in log_numbers {
+            let ruled_out = self
+                .reader
+                .blooms
+                .read()
+                .get(&log_number)
+                .is_some_and(|bloom| !bloom.may_contain(key));
+            if ruled_out {
+                continue;
+            }
+            let mut reader = match File::open(log_path(&self.path, log_number)) {
+                Ok(file) => BufReader::new(file),
+                // Compacted away since the logs were listed.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let (_, codec) = log_header::records_start(&mut reader, log_number)?;
+            while !reader.fill_buf()?.is_empty() {
+                // A record being appended may be read only in part; it and what follows
+                // are left out, as they would be by a read before the append.
+                let commands = match codec.log_codec().decode(&mut reader) {
+                    Ok(Command::Batch(commands)) => commands,
+                    Ok(command) => vec![command],
+                    Err(_) => break,
+                };
+                for change in commands.into_iter().filter_map(Command::change) {
+                    let (value, meta) = match change {
+                        Change::Set(set_key, value, meta) if set_key == key => (Some(value), meta),
+                        Change::Remove(removed_key, meta) if removed_key == key => (None, meta),
+                        _ => continue,
+                    };
+                    if meta.version > 0 && meta.version <= latest {
+                        continue;
+                    }
+                    latest = latest.max(meta.version);
+                    let size = value.as_ref().map_or(0, String::len);
+                    revisions.push(Revision {
+                        value,
+                        meta: meta.entry_meta(size),
+                    });
+                }
+            }
+        }
+        Ok(revisions)
+    }
+
+    /// Return the value `key` had as of `version`: that of the last write to it still in
+    /// the logs with a version no later, or None if that write was a remove or there's no
+    /// such write left. Best effort, as `history` is: once compacted, a value overwritten
+    /// since can't be read back.
+    pub fn get_at(&self, key: &str, version: u64) -> Result<Option<String>> {
+        Ok(self
+            .history(key)?
+            .into_iter()
+            .rev()
+            .find(|revision| revision.meta.version <= version)
+            .and_then(|revision| revision.value))
+    }
+}
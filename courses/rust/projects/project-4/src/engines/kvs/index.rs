@@ -0,0 +1,177 @@
+//! The index of a `KvStore`: where the latest record of each key is, split into shards by
+//! key hash. Each shard has a lock of its own, so a write holds up only reads of the keys
+//! in its shard, and the shards fill side by side as the store opens.
+//!
+//! Keys are in order within a shard. A walk of the whole index merges the shards, copying
+//! a page of each out at a time, so no lock is held while the caller works through it.
+
+use super::CommandPosition;
+use crate::sync::RwLock;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+
+// Keys a walk copies out of a shard at a time.
+const PAGE: usize = 256;
+
+pub(super) type Shard = BTreeMap<String, CommandPosition>;
+
+pub(super) struct Index {
+    shards: Vec<RwLock<Shard>>,
+}
+
+impl Index {
+    /// An empty index of `shards` shards, or of one if that's 0.
+    pub(super) fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(Shard::new()))
+                .collect(),
+        }
+    }
+
+    pub(super) fn shards(&self) -> &[RwLock<Shard>] {
+        &self.shards
+    }
+
+    /// The shard of `shards` that `key` is in. The default hasher has fixed keys, so a key
+    /// is always in the same one.
+    pub(super) fn shard_of(key: &str, shards: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % shards as u64) as usize
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<Shard> {
+        &self.shards[Self::shard_of(key, self.shards.len())]
+    }
+
+    pub(super) fn get(&self, key: &str) -> Option<CommandPosition> {
+        self.shard(key).read().get(key).copied()
+    }
+
+    pub(super) fn contains_key(&self, key: &str) -> bool {
+        self.shard(key).read().contains_key(key)
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().len()).sum()
+    }
+
+    /// Point `key` at `pos`, returning where it pointed before. The key is never missing
+    /// meanwhile, as it would be to a read between a remove and an insert.
+    pub(super) fn insert(&self, key: String, pos: CommandPosition) -> Option<CommandPosition> {
+        self.shard(&key).write().insert(key, pos)
+    }
+
+    pub(super) fn remove(&self, key: &str) -> Option<CommandPosition> {
+        self.shard(key).write().remove(key)
+    }
+
+    pub(super) fn first_key(&self) -> Option<String> {
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.read().keys().next().cloned())
+            .min()
+    }
+
+    pub(super) fn last_key(&self) -> Option<String> {
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.read().keys().next_back().cloned())
+            .max()
+    }
+
+    /// Walk every key in order.
+    pub(super) fn iter(&self) -> Entries<'_> {
+        self.range(..)
+    }
+
+    /// Walk the keys in `range` in order. Keys written meanwhile may or may not be found.
+    pub(super) fn range<R: RangeBounds<String>>(&self, range: R) -> Entries<'_> {
+        let start = range.start_bound().cloned();
+        Entries {
+            index: self,
+            end: range.end_bound().cloned(),
+            shards: (0..self.shards.len())
+                .map(|_| ShardWalk {
+                    page: VecDeque::new(),
+                    from: Some(start.clone()),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A walk of the index in key order, which reads each shard a page at a time.
+pub(super) struct Entries<'a> {
+    index: &'a Index,
+    end: Bound<String>,
+    shards: Vec<ShardWalk>,
+}
+
+// Where a walk is in one shard.
+struct ShardWalk {
+    // Entries read from the shard and not yet returned.
+    page: VecDeque<(String, CommandPosition)>,
+    // Where to read the shard on from, or None once it's been read to the end of the walk.
+    from: Option<Bound<String>>,
+}
+
+impl Entries<'_> {
+    // Read the next page of shard `i`, if every entry read from it has been returned.
+    fn fill(&mut self, i: usize) {
+        let ShardWalk { page, from } = &mut self.shards[i];
+        if !page.is_empty() {
+            return;
+        }
+        let start = match from.take() {
+            Some(start) if runs_forward(&start, &self.end) => start,
+            _ => return,
+        };
+        let shard = self.index.shards[i].read();
+        page.extend(
+            shard
+                .range((start, self.end.clone()))
+                .take(PAGE)
+                .map(|(key, &pos)| (key.clone(), pos)),
+        );
+        if page.len() == PAGE {
+            *from = page.back().map(|(key, _)| Bound::Excluded(key.clone()));
+        }
+    }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = (String, CommandPosition);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<usize> = None;
+        for i in 0..self.shards.len() {
+            self.fill(i);
+            let key = match self.shards[i].page.front() {
+                Some((key, _)) => key,
+                None => continue,
+            };
+            if next.is_none_or(|n| key < &self.shards[n].page[0].0) {
+                next = Some(i);
+            }
+        }
+        self.shards[next?].page.pop_front()
+    }
+}
+
+// Whether keys can lie between `start` and `end`. `BTreeMap::range` panics on bounds that
+// run backwards.
+fn runs_forward(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start <= end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start < end,
+        _ => true,
+    }
+}
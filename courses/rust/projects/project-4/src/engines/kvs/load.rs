@@ -0,0 +1,218 @@
+//! Indexing the logs of a `KvStore` as it opens. Several logs are read at once, each on a
+//! thread of its own, into what it sets and removes, split into the index's shards. Each is
+//! then applied to the index a shard to a task, so the shards fill side by side without
+//! waiting on one another's locks.
+//!
+//! Logs are applied oldest first, one at a time, so a later log's writes to a key win over
+//! an earlier one's. No more logs are read at once than there are threads, and the next is
+//! only started once the oldest has been handed on, so besides the index only the changes
+//! of the logs being read, and of the one being applied, are held.
+
+use super::index::Index;
+use super::load_index;
+use super::opening;
+use super::Codec;
+use super::CommandPosition;
+use super::IndexSink;
+use crate::error::ResultExt;
+use crate::thread_pool::SharedQueueThreadPool;
+use crate::thread_pool::ThreadPool;
+use crate::KvsError;
+use crate::Result;
+use crossbeam::channel;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::iter::Enumerate;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::vec;
+
+/// What one log sets and removes, split into the index's shards. A key the log removes
+/// maps to None, so it can be removed from what earlier logs set.
+pub(super) struct LogShards(Vec<BTreeMap<String, Option<CommandPosition>>>);
+
+impl LogShards {
+    fn new(shards: usize) -> Self {
+        Self((0..shards).map(|_| BTreeMap::new()).collect())
+    }
+
+    fn shard(&mut self, key: &str) -> &mut BTreeMap<String, Option<CommandPosition>> {
+        let shard = Index::shard_of(key, self.0.len());
+        &mut self.0[shard]
+    }
+}
+
+impl IndexSink for LogShards {
+    fn set(&mut self, key: String, pos: CommandPosition) {
+        self.shard(&key).insert(key, Some(pos));
+    }
+
+    fn remove(&mut self, key: String) {
+        self.shard(&key).insert(key, None);
+    }
+}
+
+/// A log read to its end, or to the damage that stopped it.
+pub(super) struct IndexedLog {
+    pub(super) log_number: u64,
+    pub(super) reader: BufReader<File>,
+    pub(super) shards: LogShards,
+    // The log's codec, once its header is read.
+    pub(super) codec: Option<Codec>,
+    // The keys the log writes, if they were asked for.
+    pub(super) keys: Option<HashSet<String>>,
+    // Why the log couldn't be read to its end, if it couldn't.
+    pub(super) result: Result<()>,
+}
+
+/// Read the logs `(log number, path, whether to gather its keys)` on `pool`, up to `reading`
+/// at once, each split into `shards` shards. They come back in the order given, failing on
+/// any that won't open.
+pub(super) fn index_logs(
+    pool: &SharedQueueThreadPool,
+    logs: Vec<(u64, PathBuf, bool)>,
+    shards: usize,
+    reading: usize,
+) -> IndexedLogs<'_> {
+    let (tx, rx) = channel::unbounded();
+    let mut logs = IndexedLogs {
+        pool,
+        count: logs.len(),
+        unread: logs.into_iter().enumerate(),
+        shards,
+        tx,
+        rx,
+        read: HashMap::new(),
+        next: 0,
+    };
+    for _ in 0..reading.max(1) {
+        logs.read_next();
+    }
+    logs
+}
+
+/// The logs being read, in the order they were given.
+pub(super) struct IndexedLogs<'a> {
+    pool: &'a SharedQueueThreadPool,
+    count: usize,
+    unread: Enumerate<vec::IntoIter<(u64, PathBuf, bool)>>,
+    shards: usize,
+    tx: channel::Sender<(usize, Result<IndexedLog>)>,
+    rx: channel::Receiver<(usize, Result<IndexedLog>)>,
+    // Logs read ahead of those before them, by their place in the order.
+    read: HashMap<usize, Result<IndexedLog>>,
+    // The place of the log to return next.
+    next: usize,
+}
+
+impl IndexedLogs<'_> {
+    // Start reading the next log, if there's one left.
+    fn read_next(&mut self) {
+        let (i, (log_number, log_path, gather_keys)) = match self.unread.next() {
+            Some(log) => log,
+            None => return,
+        };
+        let (tx, shards) = (self.tx.clone(), self.shards);
+        self.pool.spawn(move || {
+            // Sent even if reading panics, so the log isn't waited on forever.
+            let indexed = panic::catch_unwind(AssertUnwindSafe(|| {
+                index_one(log_number, log_path, gather_keys, shards)
+            }))
+            .unwrap_or_else(|_| Err(panicked()));
+            let _ = tx.send((i, indexed));
+        });
+    }
+}
+
+impl Iterator for IndexedLogs<'_> {
+    type Item = Result<IndexedLog>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.count {
+            return None;
+        }
+        let log = loop {
+            if let Some(log) = self.read.remove(&self.next) {
+                break log;
+            }
+            // Each log sends once, and `self` holds a sender, so this only waits.
+            let (i, log) = self.rx.recv().expect("a sender is held");
+            self.read.insert(i, log);
+        };
+        self.next += 1;
+        self.read_next();
+        Some(log)
+    }
+}
+
+fn index_one(
+    log_number: u64,
+    log_path: PathBuf,
+    gather_keys: bool,
+    shards: usize,
+) -> Result<IndexedLog> {
+    let file = File::open(&log_path).context(|| opening(&log_path))?;
+    let mut reader = BufReader::new(file);
+    let mut shards = LogShards::new(shards);
+    let mut codecs = HashMap::new();
+    let mut keys = gather_keys.then(HashSet::new);
+    let result = load_index(
+        log_number,
+        &mut shards,
+        &mut reader,
+        &mut codecs,
+        keys.as_mut(),
+    );
+    Ok(IndexedLog {
+        log_number,
+        reader,
+        shards,
+        codec: codecs.remove(&log_number),
+        keys,
+        result,
+    })
+}
+
+/// Apply what a log sets and removes to `index`, which it was split for, a shard to a task
+/// on `pool`. Return once every shard is done.
+pub(super) fn apply(
+    pool: &SharedQueueThreadPool,
+    index: &Arc<Index>,
+    log: LogShards,
+) -> Result<()> {
+    let (tx, rx) = channel::unbounded();
+    let mut tasks = 0;
+    for (i, changes) in log.0.into_iter().enumerate() {
+        if changes.is_empty() {
+            continue;
+        }
+        let index = index.clone();
+        let tx = tx.clone();
+        pool.spawn(move || {
+            let mut shard = index.shards()[i].write();
+            for (key, pos) in changes {
+                match pos {
+                    Some(pos) => shard.insert(key, pos),
+                    None => shard.remove(&key),
+                };
+            }
+            let _ = tx.send(());
+        });
+        tasks += 1;
+    }
+    drop(tx);
+    // A task that panicked drops its sender without sending.
+    if rx.iter().count() < tasks {
+        return Err(panicked());
+    }
+    Ok(())
+}
+
+fn panicked() -> KvsError {
+    KvsError::StringError("a thread indexing the logs panicked".to_owned())
+}
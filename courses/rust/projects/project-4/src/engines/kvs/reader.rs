@@ -0,0 +1,180 @@
+//! The read side of a `KvStore`: the index and the open logs, shared by every clone of the
+//! store and by its writer.
+
+use super::decode_value;
+use super::index::Index;
+use super::log_path;
+use super::map_log;
+use super::missing_log;
+use super::readers::ThreadReaders;
+use super::values::ValueIndex;
+use super::Bloom;
+use super::Codec;
+use super::CommandPosition;
+use super::RecordMeta;
+use crate::error::ResultExt;
+use crate::sync::RwLock;
+use crate::ErrorContext;
+use crate::Result;
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Reads of a store, which take no lock the writer holds for long, so they go on while it
+/// writes and compacts. Clones share everything.
+#[derive(Clone)]
+pub(super) struct KvStoreReader {
+    // The store's directory, to name the log a read failed in.
+    path: Arc<Path>,
+    // The open logs, which each thread's readers are opened from.
+    pub(super) readers: Arc<RwLock<HashMap<u64, BufReader<File>>>>,
+    pub(super) thread_readers: Arc<ThreadReaders>,
+    // Maps of the sealed logs, read from in place of their readers when mmap reads are on.
+    pub(super) maps: Arc<RwLock<HashMap<u64, Mmap>>>,
+    // Filters of the keys in each sealed log.
+    pub(super) blooms: Arc<RwLock<HashMap<u64, Bloom>>>,
+    // The codec of each log's records, from its header.
+    pub(super) codecs: Arc<RwLock<HashMap<u64, Codec>>>,
+    // Only the writer adds, moves or removes keys.
+    pub(super) index: Arc<Index>,
+    // The keys set to each value, if the store keeps a value index. Only the writer
+    // changes it.
+    pub(super) values: Arc<RwLock<Option<ValueIndex>>>,
+}
+
+impl KvStoreReader {
+    pub(super) fn new(
+        path: &Path,
+        readers: HashMap<u64, BufReader<File>>,
+        blooms: HashMap<u64, Bloom>,
+        codecs: HashMap<u64, Codec>,
+        index: Arc<Index>,
+    ) -> Self {
+        KvStoreReader {
+            path: path.into(),
+            readers: Arc::new(RwLock::new(readers)),
+            thread_readers: Arc::new(ThreadReaders::default()),
+            maps: Arc::new(RwLock::new(HashMap::new())),
+            blooms: Arc::new(RwLock::new(blooms)),
+            codecs: Arc::new(RwLock::new(codecs)),
+            index,
+            values: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Map every open log in `path` but the newest, which is the one being written, or, in
+    /// a read-only store, the one a writer may still append to or cut a torn tail off.
+    pub(super) fn map_logs(&self, path: &Path) -> Result<()> {
+        let mut maps = self.maps.write();
+        let readers = self.readers.read();
+        let newest = readers.keys().max().copied();
+        for &sealed in readers.keys() {
+            if Some(sealed) != newest {
+                maps.insert(sealed, map_log(path, sealed)?);
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.get_with_meta(key)?.map(|(value, _)| value))
+    }
+
+    pub(super) fn get_with_meta(&self, key: &str) -> Result<Option<(String, RecordMeta)>> {
+        match self.index.get(key) {
+            Some(pos) => self.read_current(key, pos),
+            None => Ok(None),
+        }
+    }
+
+    /// Values are read in the order they lie in the logs rather than that of `keys`, so
+    /// each log is read front to back.
+    pub(super) fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut found: Vec<(usize, CommandPosition)> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| self.index.get(key).map(|pos| (i, pos)))
+            .collect();
+        found.sort_unstable_by_key(|(_, pos)| (pos.log_number, pos.offset));
+        let mut values = vec![None; keys.len()];
+        for (i, pos) in found {
+            values[i] = self.read_current(&keys[i], pos)?.map(|(value, _)| value);
+        }
+        Ok(values)
+    }
+
+    /// Read the value of each indexed key, leaving out those removed before their values
+    /// are read.
+    pub(super) fn read_pairs(
+        &self,
+        entries: impl Iterator<Item = (String, CommandPosition)>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for (key, pos) in entries {
+            if let Some((value, _)) = self.read_current(&key, pos)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    // Read the value of `key`, which the index had at `pos`, with its meta. Compaction may
+    // have moved the record since, and removed its log, in which case the key is looked up
+    // again. Return None if it has been removed.
+    fn read_current(
+        &self,
+        key: &str,
+        mut pos: CommandPosition,
+    ) -> Result<Option<(String, RecordMeta)>> {
+        loop {
+            if let Some(read) = self.read_at(&pos, key)? {
+                return Ok(Some(read));
+            }
+            match self.index.get(key) {
+                Some(moved) => {
+                    // Compaction points the index at a key's new record before it retires
+                    // the old log, so a log gone while the index still points into it was
+                    // lost rather than compacted.
+                    if (moved.log_number, moved.offset) == (pos.log_number, pos.offset) {
+                        return Err(missing_log(&pos));
+                    }
+                    pos = moved;
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Read the value `key` was set to by the record at `pos`, with its meta, or None if
+    /// compaction has retired its log. Reads only share locks: a mapped log is read in
+    /// place, and others through this thread's own reader of the log.
+    pub(super) fn read_at(
+        &self,
+        pos: &CommandPosition,
+        key: &str,
+    ) -> Result<Option<(String, RecordMeta)>> {
+        let codec = match self.codecs.read().get(&pos.log_number) {
+            Some(&codec) => codec,
+            None => return Ok(None),
+        };
+        let reading = || {
+            ErrorContext::new("reading")
+                .file(log_path(&self.path, pos.log_number))
+                .offset(pos.offset)
+                .key(key)
+        };
+        if let Some(map) = self.maps.read().get(&pos.log_number) {
+            return decode_value(&mut &map[pos.offset as usize..], codec, pos, key)
+                .context(reading)
+                .map(Some);
+        }
+        self.thread_readers
+            .with_reader(&self.readers, pos.log_number, pos.offset, |reader| {
+                decode_value(reader, codec, pos, key)
+            })
+            .context(reading)
+    }
+}
@@ -0,0 +1,116 @@
+//! Each thread's own handles on the logs, so reads take no lock on the open logs and keep
+//! their buffers from one read to the next.
+
+use crate::sync::RwLock;
+use crate::Result;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use thread_local::ThreadLocal;
+
+/// A log read at an offset of its own rather than at the cursor of its file, so handles
+/// sharing the file don't move each other's place.
+pub(super) struct LogFile {
+    file: File,
+    offset: u64,
+}
+
+impl Read for LogFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let read = self.file.read_at(buf, self.offset)?;
+        #[cfg(windows)]
+        let read = self.file.seek_read(buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for LogFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.file.metadata()?.len().checked_add_signed(delta),
+        };
+        self.offset = offset.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative offset")
+        })?;
+        Ok(self.offset)
+    }
+}
+
+// The readers one thread has opened, by log number.
+#[derive(Default)]
+struct Handles {
+    readers: HashMap<u64, BufReader<LogFile>>,
+    // The first live log when the readers were last pruned.
+    first_log: u64,
+}
+
+/// Readers of the logs, opened lazily by each thread from the store's open logs.
+///
+/// Compaction retires the logs it rewrote by moving the first live log past them. Reads of
+/// a retired log are turned away, to be looked up again in the index, and each thread drops
+/// its handles on retired logs the next time it reads.
+#[derive(Default)]
+pub(super) struct ThreadReaders {
+    handles: ThreadLocal<RefCell<Handles>>,
+    first_log: AtomicU64,
+}
+
+impl ThreadReaders {
+    /// Mark every log numbered below `log_number` as retired. Call once the index no
+    /// longer points into them, and before they are removed.
+    pub(super) fn retire_below(&self, log_number: u64) {
+        self.first_log.fetch_max(log_number, Ordering::SeqCst);
+    }
+
+    /// Call `read` with this thread's reader of log `log_number`, placed at `offset`. The
+    /// reader is opened from `logs` the first time. Return None if the log is retired or no
+    /// longer open.
+    pub(super) fn with_reader<T>(
+        &self,
+        logs: &RwLock<HashMap<u64, BufReader<File>>>,
+        log_number: u64,
+        offset: u64,
+        read: impl FnOnce(&mut BufReader<LogFile>) -> Result<T>,
+    ) -> Result<Option<T>> {
+        let first_log = self.first_log.load(Ordering::SeqCst);
+        if log_number < first_log {
+            return Ok(None);
+        }
+        let mut handles = self.handles.get_or_default().borrow_mut();
+        if handles.first_log < first_log {
+            handles.readers.retain(|&number, _| number >= first_log);
+            handles.first_log = first_log;
+        }
+        let reader = match handles.readers.entry(log_number) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = match logs.read().get(&log_number) {
+                    Some(log) => log.get_ref().try_clone()?,
+                    None => return Ok(None),
+                };
+                entry.insert(BufReader::new(LogFile { file, offset: 0 }))
+            }
+        };
+        // Moving within what's buffered keeps it, so reads of nearby records, as in log
+        // order, needn't go back to the file.
+        let delta = offset as i64 - reader.stream_position()? as i64;
+        reader.seek_relative(delta)?;
+        read(reader).map(Some)
+    }
+}
@@ -0,0 +1,134 @@
+//! The value index of a `KvStore`: the keys set to each value, so `find_by_value` needn't
+//! read every pair. The writer keeps it as it writes, and it's checkpointed beside the
+//! logs, when it's created and after each compaction, so opening the store again only
+//! reads the values written since.
+
+use super::index::Index;
+use super::missing_log;
+use super::reader::KvStoreReader;
+use super::Command;
+use crate::error::ResultExt;
+use crate::ErrorContext;
+use crate::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// The checkpoint of the value index, whose presence says the store keeps one.
+pub(super) const VALUES_FILE: &str = "kvs.values";
+
+/// The keys set to each value, and the value of each key, to find the keys a write moves
+/// from one value to another.
+#[derive(Default)]
+pub(super) struct ValueIndex {
+    keys: HashMap<String, BTreeSet<String>>,
+    values: HashMap<String, String>,
+}
+
+impl ValueIndex {
+    pub(super) fn set(&mut self, key: String, value: String) {
+        self.remove(&key);
+        self.keys
+            .entry(value.clone())
+            .or_default()
+            .insert(key.clone());
+        self.values.insert(key, value);
+    }
+
+    pub(super) fn remove(&mut self, key: &str) {
+        if let Some(value) = self.values.remove(key) {
+            if let Some(keys) = self.keys.get_mut(&value) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&value);
+                }
+            }
+        }
+    }
+
+    /// Apply the sets and removes of a record just written.
+    pub(super) fn apply(&mut self, record: &Command) {
+        match record {
+            Command::Set(key, value)
+            | Command::Put(key, value, _)
+            | Command::SetStamped(key, value, _) => self.set(key.clone(), value.clone()),
+            Command::Remove(key) | Command::RemoveStamped(key, _) => self.remove(key),
+            Command::Batch(commands) => {
+                for command in commands {
+                    self.apply(command);
+                }
+            }
+        }
+    }
+
+    /// The keys set to `value`, in order.
+    pub(super) fn find(&self, value: &str) -> Vec<String> {
+        self.keys
+            .get(value)
+            .map_or_else(Vec::new, |keys| keys.iter().cloned().collect())
+    }
+}
+
+/// A value index as written to disk: the value of each key, with where in the logs the
+/// record that set it was. A key whose record has moved since was written again, and its
+/// value is read anew.
+#[derive(Serialize, Deserialize, Default)]
+struct Checkpoint {
+    values: HashMap<String, (u64, u64, String)>,
+}
+
+/// Write a checkpoint of `values`, which `index` holds the positions of, to `path`. Call
+/// with the writer locked, so the two agree.
+pub(super) fn write_checkpoint(path: &Path, index: &Index, values: &ValueIndex) -> Result<()> {
+    let mut checkpoint = Checkpoint::default();
+    for (key, pos) in index.iter() {
+        if let Some(value) = values.values.get(&key) {
+            let value = value.clone();
+            checkpoint
+                .values
+                .insert(key, (pos.log_number, pos.offset, value));
+        }
+    }
+    // Written aside and renamed over the last, so a crash partway leaves one or the other.
+    let values_path = path.join(VALUES_FILE);
+    let temp_path = path.join(format!("{}.tmp", VALUES_FILE));
+    let writing = || ErrorContext::new("writing").file(&values_path);
+    fs::write(&temp_path, rmp_serde::to_vec(&checkpoint)?).context(writing)?;
+    fs::rename(&temp_path, &values_path).context(writing)?;
+    Ok(())
+}
+
+/// Load the value index checkpointed in `path`, if the store keeps one, reading the values
+/// of the keys written since the checkpoint from the logs. A checkpoint that doesn't
+/// decode is rebuilt from the logs whole.
+pub(super) fn load(path: &Path, reader: &KvStoreReader) -> Result<Option<ValueIndex>> {
+    let values_path = path.join(VALUES_FILE);
+    let bytes = match fs::read(&values_path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).context(|| ErrorContext::new("reading").file(&values_path)),
+    };
+    let mut checkpoint: Checkpoint = rmp_serde::from_slice(&bytes).unwrap_or_default();
+    let mut values = ValueIndex::default();
+    for (key, pos) in reader.index.iter() {
+        let value = match checkpoint.values.remove(&key) {
+            Some((log_number, offset, value))
+                if (log_number, offset) == (pos.log_number, pos.offset) =>
+            {
+                value
+            }
+            _ => {
+                reader
+                    .read_at(&pos, &key)?
+                    .ok_or_else(|| missing_log(&pos))?
+                    .0
+            }
+        };
+        values.set(key, value);
+    }
+    Ok(Some(values))
+}
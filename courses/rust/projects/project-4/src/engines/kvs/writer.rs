@@ -0,0 +1,595 @@
+//! The write side of a `KvStore`: the log being written, and compaction of the others.
+
+use super::bloom_path;
+use super::encoded_len;
+use super::log_path;
+use super::map_log;
+use super::missing_log;
+use super::new_log_file;
+use super::reader::KvStoreReader;
+use super::remove_if_exists;
+use super::values;
+use super::Bloom;
+use super::Change;
+use super::Codec;
+use super::Command;
+use super::CommandPosition;
+use super::RecordMeta;
+use super::TombstoneHorizon;
+use super::COMPACTED_FILE;
+use crate::engines::group_commit::GroupCommit;
+use crate::engines::group_commit::SyncLog;
+use crate::engines::log_header::LogHeader;
+use crate::engines::log_header::HEADER_LEN;
+use crate::engines::txn::Versions;
+use crate::engines::CompactionState;
+use crate::error::ResultExt;
+use crate::trace::Span;
+use crate::CorruptionKind;
+use crate::ErrorContext;
+use crate::KvsError;
+use crate::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// How far the writer's compaction has got, shared with the store so it can be told
+/// without waiting for the writer.
+#[derive(Default)]
+pub(super) struct CompactionProgress {
+    running: AtomicBool,
+    processed: AtomicU64,
+    remaining: AtomicU64,
+    // Bytes of the logs held by overwritten and removed values since the last compaction.
+    dead: AtomicU64,
+    // Removes since the last compaction, and the bytes of their records.
+    tombstones: AtomicU64,
+    tombstone_bytes: AtomicU64,
+    // Whether writes are stalled on the dead bytes, and how many have been.
+    stalled: AtomicBool,
+    stalled_writes: AtomicU64,
+}
+
+impl CompactionProgress {
+    pub(super) fn state(&self) -> CompactionState {
+        CompactionState {
+            running: self.running.load(Ordering::SeqCst),
+            bytes_processed: self.processed.load(Ordering::SeqCst),
+            bytes_remaining: self.remaining.load(Ordering::SeqCst),
+            dead_bytes: self.dead.load(Ordering::SeqCst),
+            tombstones: self.tombstones.load(Ordering::SeqCst),
+            tombstone_bytes: self.tombstone_bytes.load(Ordering::SeqCst),
+            stalled: self.stalled.load(Ordering::SeqCst),
+            stalled_writes: self.stalled_writes.load(Ordering::SeqCst),
+        }
+    }
+
+    // Note whether a write is being stalled on the dead bytes.
+    pub(super) fn set_stalled(&self, stalled: bool) {
+        self.stalled.store(stalled, Ordering::SeqCst);
+        if stalled {
+            self.stalled_writes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Start a compaction expected to rewrite `live_bytes`.
+    fn start(&self, live_bytes: u64) {
+        self.processed.store(0, Ordering::SeqCst);
+        self.remaining.store(live_bytes, Ordering::SeqCst);
+        self.running.store(true, Ordering::SeqCst);
+    }
+
+    // Count `bytes` more rewritten. What's left is only estimated, so it may run out
+    // before the compaction does.
+    fn advance(&self, bytes: u64) {
+        self.processed.fetch_add(bytes, Ordering::SeqCst);
+        let _ = self
+            .remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                Some(left.saturating_sub(bytes))
+            });
+    }
+
+    // End the compaction, whether or not it got through.
+    fn finish(&self) {
+        self.remaining.store(0, Ordering::SeqCst);
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Appends to a store's log and points its index at what was appended. A store has one,
+/// behind a mutex, so writes go one at a time.
+///
+/// Each write returns a ticket to wait on before the write counts as done, as
+/// `flush_record` does; wait without the writer locked.
+pub(super) struct KvStoreWriter {
+    writer: BufWriter<File>,
+    log_number: u64,
+    // The codec new logs are written in.
+    codec: Codec,
+    path: PathBuf,
+    reader: KvStoreReader,
+    versions: Arc<Versions>,
+    group_commit: Option<Arc<GroupCommit>>,
+    // Whether a thread syncs the log in the background.
+    background_sync: bool,
+    // Bytes written to the logs since they were last synced, shared with the store.
+    unsynced: Arc<AtomicU64>,
+    progress: Arc<CompactionProgress>,
+    // Whether compacted logs are mapped for reading.
+    mmap_reads: bool,
+    // The version of the last write.
+    last_version: u64,
+    // The stamp of the last write, which the next one's is later than.
+    last_stamp: u64,
+    // When the first remove since the last compaction was made.
+    oldest_tombstone: Option<Instant>,
+}
+
+impl KvStoreWriter {
+    pub(super) fn new(
+        writer: BufWriter<File>,
+        log_number: u64,
+        codec: Codec,
+        path: PathBuf,
+        reader: KvStoreReader,
+        versions: Arc<Versions>,
+        progress: Arc<CompactionProgress>,
+    ) -> Result<Self> {
+        // The meta of removes isn't kept, so a key removed with the highest version may see
+        // it again, and a stamp taken after it with the wall clock set back may be earlier.
+        let latest = match reader.index.iter().max_by_key(|(_, pos)| pos.version) {
+            Some((key, pos)) => reader.read_at(&pos, &key)?,
+            None => None,
+        };
+        let (last_version, last_stamp) =
+            latest.map_or((0, 0), |(_, meta)| (meta.version, meta.stamp));
+        Ok(KvStoreWriter {
+            writer,
+            log_number,
+            codec,
+            path,
+            reader,
+            versions,
+            group_commit: None,
+            background_sync: false,
+            unsynced: Arc::new(AtomicU64::new(0)),
+            progress,
+            mmap_reads: false,
+            last_version,
+            last_stamp,
+            oldest_tombstone: None,
+        })
+    }
+
+    /// Bytes of the logs held by overwritten and removed values since the last compaction.
+    pub(super) fn uncompacted_bytes(&self) -> u64 {
+        self.progress.dead.load(Ordering::SeqCst)
+    }
+
+    /// Whether the removes since the last compaction have passed `horizon`.
+    pub(super) fn tombstones_due(&self, horizon: &TombstoneHorizon) -> bool {
+        let too_many = horizon
+            .max_count
+            .is_some_and(|max| self.progress.tombstones.load(Ordering::SeqCst) >= max);
+        let too_old = match (horizon.max_age, self.oldest_tombstone) {
+            (Some(max), Some(oldest)) => oldest.elapsed() >= max,
+            _ => false,
+        };
+        too_many || too_old
+    }
+
+    pub(super) fn set_group_commit(&mut self, group_commit: Arc<GroupCommit>) {
+        self.group_commit = Some(group_commit);
+    }
+
+    /// The count of bytes written since the logs were last synced.
+    pub(super) fn unsynced(&self) -> Arc<AtomicU64> {
+        self.unsynced.clone()
+    }
+
+    pub(super) fn set_background_sync(&mut self) {
+        self.background_sync = true;
+    }
+
+    /// Map the logs compaction writes from now on.
+    pub(super) fn set_mmap_reads(&mut self) {
+        self.mmap_reads = true;
+    }
+
+    /// Write new logs in `codec`. Return whether logs in other codecs are left to compact:
+    /// if the log being written is the only one and holds no records, its header is just
+    /// rewritten.
+    pub(super) fn set_codec(&mut self, codec: Codec) -> Result<bool> {
+        self.codec = codec;
+        let mut codecs = self.reader.codecs.write();
+        if codecs.values().all(|&log_codec| log_codec == codec) {
+            Ok(false)
+        } else if codecs.len() == 1 && self.writer.stream_position()? <= HEADER_LEN {
+            self.writer.flush()?;
+            self.writer.get_ref().set_len(0)?;
+            LogHeader::new(codec).write(&mut self.writer)?;
+            self.writer.flush()?;
+            codecs.insert(self.log_number, codec);
+            Ok(false)
+        } else {
+            Ok(true)
+        }
+    }
+
+    // The codec of the log being written.
+    fn writer_codec(&self) -> Result<Codec> {
+        match self.reader.codecs.read().get(&self.log_number) {
+            Some(&codec) => Ok(codec),
+            None => Err(KvsError::Corruption {
+                log_number: self.log_number,
+                offset: 0,
+                kind: CorruptionKind::MissingLog,
+            }),
+        }
+    }
+
+    // The context of an error appending to the log being written, for `key` if the record
+    // is for only one.
+    fn appending(&self, key: Option<&str>) -> ErrorContext {
+        let context = ErrorContext::new("appending to").file(log_path(&self.path, self.log_number));
+        match key {
+            Some(key) => context.key(key),
+            None => context,
+        }
+    }
+
+    // Append `record` to the log being written, in `codec`. Return its offset and length.
+    fn append(&mut self, codec: Codec, record: &Command) -> Result<(u64, u64)> {
+        let offset = self.writer.stream_position()?;
+        codec.log_codec().encode(record, self.writer.get_mut())?;
+        Ok((offset, self.writer.stream_position()? - offset))
+    }
+
+    // Start the log `log_number`, in the store's codec.
+    fn start_log(&mut self, log_number: u64) -> Result<()> {
+        let mut readers = self.reader.readers.write();
+        let mut codecs = self.reader.codecs.write();
+        self.writer = new_log_file(
+            &self.path,
+            log_number,
+            self.codec,
+            &mut readers,
+            &mut codecs,
+        )?;
+        self.log_number = log_number;
+        Ok(())
+    }
+
+    // The meta of a write about to be made.
+    fn next_meta(&mut self) -> RecordMeta {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_version += 1;
+        self.last_stamp = (now.as_nanos() as u64).max(self.last_stamp + 1);
+        RecordMeta {
+            version: self.last_version,
+            written: now.as_millis() as u64,
+            stamp: self.last_stamp,
+        }
+    }
+
+    // Flush an appended record, or leave it to group commit. Return the ticket to wait on,
+    // if any.
+    fn flush_record(&mut self, bytes: u64) -> Result<Option<u64>> {
+        self.unsynced.fetch_add(bytes, Ordering::SeqCst);
+        match &self.group_commit {
+            Some(group_commit) => Ok(Some(group_commit.appended(bytes))),
+            None => {
+                self.writer.flush()?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Point the index at a record written to the log, counting the record it replaces as
+    // uncompacted. The record is flushed first, so reads find it whole.
+    fn index_set(&mut self, key: String, pos: CommandPosition) {
+        if let Some(replaced) = self.reader.index.insert(key, pos) {
+            self.progress
+                .dead
+                .fetch_add(replaced.bytes, Ordering::SeqCst);
+        }
+    }
+
+    // Apply what `record` writes to the value index, if the store keeps one.
+    fn index_values(&self, record: &Command) {
+        if let Some(values) = self.reader.values.write().as_mut() {
+            values.apply(record);
+        }
+    }
+
+    // Count a remove record of `bytes` as uncompacted, and as a tombstone.
+    fn add_tombstone(&mut self, bytes: u64) {
+        self.oldest_tombstone.get_or_insert_with(Instant::now);
+        self.progress.tombstones.fetch_add(1, Ordering::SeqCst);
+        self.progress
+            .tombstone_bytes
+            .fetch_add(bytes, Ordering::SeqCst);
+        self.progress.dead.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    // Drop `key` from the index, counting its record as uncompacted.
+    fn index_remove(&mut self, key: &str) {
+        if let Some(removed) = self.reader.index.remove(key) {
+            self.progress
+                .dead
+                .fetch_add(removed.bytes, Ordering::SeqCst);
+        }
+    }
+
+    /// Write a set record and point the index at it.
+    pub(super) fn set(&mut self, key: String, value: String) -> Result<Option<u64>> {
+        let meta = self.next_meta();
+        let cmd = Command::SetStamped(key.clone(), value, meta);
+        let (offset, bytes) = self
+            .append(self.writer_codec()?, &cmd)
+            .context(|| self.appending(Some(&key)))?;
+        self.versions.record([&key]);
+        let ticket = self
+            .flush_record(bytes)
+            .context(|| self.appending(Some(&key)))?;
+        let pos = CommandPosition {
+            log_number: self.log_number,
+            offset,
+            bytes,
+            version: meta.version,
+        };
+        self.index_values(&cmd);
+        self.index_set(key, pos);
+        Ok(ticket)
+    }
+
+    /// Write a set record for each pair, flushing once for them all.
+    pub(super) fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<Option<u64>> {
+        let codec = self.writer_codec()?;
+        let start = self
+            .writer
+            .stream_position()
+            .context(|| self.appending(None))?;
+        let mut written = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let meta = self.next_meta();
+            let cmd = Command::SetStamped(key.clone(), value, meta);
+            let (offset, bytes) = self
+                .append(codec, &cmd)
+                .context(|| self.appending(Some(&key)))?;
+            self.versions.record([&key]);
+            let pos = CommandPosition {
+                log_number: self.log_number,
+                offset,
+                bytes,
+                version: meta.version,
+            };
+            written.push((key, pos, cmd));
+        }
+        let bytes = self
+            .writer
+            .stream_position()
+            .context(|| self.appending(None))?
+            - start;
+        let ticket = self.flush_record(bytes).context(|| self.appending(None))?;
+        for (key, pos, cmd) in written {
+            self.index_values(&cmd);
+            self.index_set(key, pos);
+        }
+        Ok(ticket)
+    }
+
+    /// Write a remove record and drop the key from the index. Return
+    /// `KvsError::KeyNotFound` if it isn't set.
+    pub(super) fn remove(&mut self, key: String) -> Result<Option<u64>> {
+        if !self.reader.index.contains_key(&key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.versions.record([&key]);
+        let cmd = Command::RemoveStamped(key.clone(), self.next_meta());
+        let (_, bytes) = self
+            .append(self.writer_codec()?, &cmd)
+            .context(|| self.appending(Some(&key)))?;
+        let ticket = self
+            .flush_record(bytes)
+            .context(|| self.appending(Some(&key)))?;
+        self.index_values(&cmd);
+        self.index_remove(&key);
+        self.add_tombstone(bytes);
+        Ok(ticket)
+    }
+
+    /// Write a transaction's sets and removes, None being a remove, as one batch record.
+    pub(super) fn write_batch(
+        &mut self,
+        writes: BTreeMap<String, Option<String>>,
+    ) -> Result<Option<u64>> {
+        self.versions.record(writes.keys());
+        let record = Command::Batch(
+            writes
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Some(value) => Command::SetStamped(key, value, self.next_meta()),
+                    None => Command::RemoveStamped(key, self.next_meta()),
+                })
+                .collect(),
+        );
+        let codec = self.writer_codec()?;
+        let (offset, bytes) = self
+            .append(codec, &record)
+            .context(|| self.appending(None))?;
+        let ticket = self.flush_record(bytes).context(|| self.appending(None))?;
+
+        self.index_values(&record);
+        if let Command::Batch(commands) = record {
+            for command in commands {
+                let bytes = encoded_len(codec, &command)?;
+                match command.change() {
+                    Some(Change::Set(key, _, meta)) => {
+                        let pos = CommandPosition {
+                            log_number: self.log_number,
+                            offset,
+                            bytes,
+                            version: meta.version,
+                        };
+                        self.index_set(key, pos);
+                    }
+                    Some(Change::Remove(key, _)) => {
+                        self.index_remove(&key);
+                        self.add_tombstone(bytes);
+                    }
+                    None => {}
+                }
+            }
+        }
+        Ok(ticket)
+    }
+
+    /// Rewrite the live values into a new log and remove the old ones. The new log is
+    /// sealed, with a bloom filter of its keys and, with mmap reads on, mapped; later writes
+    /// go to another.
+    ///
+    /// Reads go on meanwhile, from the old logs until the index points at the new one, and
+    /// wait only while the old logs are removed.
+    pub(super) fn compact(&mut self) -> Result<()> {
+        // The live values are about what's left of the logs once the dead ones are taken out.
+        let mut log_bytes = 0;
+        for reader in self.reader.readers.read().values() {
+            log_bytes += reader.get_ref().metadata()?.len();
+        }
+        self.progress
+            .start(log_bytes.saturating_sub(self.uncompacted_bytes()));
+        let span = Span::compaction();
+        let result = span.in_scope(|| self.rewrite_logs());
+        span.record_failed(result.is_err());
+        self.progress.finish();
+        result
+    }
+
+    fn rewrite_logs(&mut self) -> Result<()> {
+        let compacted_log_number = self.log_number + 1;
+        self.start_log(compacted_log_number)?;
+        let compacted_path = log_path(&self.path, compacted_log_number);
+        let compacting = || ErrorContext::new("compacting into").file(&compacted_path);
+
+        // Each live value is rewritten as a set of its own, with its meta, since it may share
+        // a batch record with values that are no longer live. The index is pointed at the new
+        // records once they're flushed, so reads find them whole.
+        let index = self.reader.index.clone();
+        let mut moved = Vec::with_capacity(index.len());
+        for (key, pos) in index.iter() {
+            // Only compaction removes logs, and the writer can't start another meanwhile, so a
+            // log missing now was never there.
+            let (value, meta) = self
+                .reader
+                .read_at(&pos, &key)?
+                .ok_or_else(|| missing_log(&pos))?;
+            let (offset, bytes) = self
+                .append(self.codec, &Command::SetStamped(key.clone(), value, meta))
+                .context(compacting)?;
+            let pos = CommandPosition {
+                log_number: compacted_log_number,
+                offset,
+                bytes,
+                version: meta.version,
+            };
+            self.progress.advance(pos.bytes);
+            moved.push((key, pos));
+        }
+        self.writer.flush().context(compacting)?;
+        if self.group_commit.is_some() || self.background_sync {
+            // Writes waiting on the stale logs are in this one now, and must be on disk
+            // before those are removed.
+            self.writer.get_ref().sync_data().context(compacting)?;
+            self.unsynced.store(0, Ordering::SeqCst);
+            if let Some(group_commit) = &self.group_commit {
+                group_commit.synced();
+            }
+        } else {
+            let compacted_bytes = self.writer.stream_position().context(compacting)?;
+            self.unsynced.fetch_add(compacted_bytes, Ordering::SeqCst);
+        }
+
+        let bloom = Bloom::of(moved.iter().map(|(key, _)| key.as_str()));
+        let compacted_bloom = bloom_path(&self.path, compacted_log_number);
+        bloom
+            .write(&compacted_bloom)
+            .context(|| ErrorContext::new("writing").file(&compacted_bloom))?;
+        self.reader
+            .blooms
+            .write()
+            .insert(compacted_log_number, bloom);
+        if self.mmap_reads {
+            let map = map_log(&self.path, compacted_log_number)?;
+            self.reader.maps.write().insert(compacted_log_number, map);
+        }
+        for (key, pos) in moved {
+            self.reader.index.insert(key, pos);
+        }
+        self.reader
+            .thread_readers
+            .retire_below(compacted_log_number);
+        self.start_log(compacted_log_number + 1)?;
+
+        // Locks are taken in the order reads take them: readers, maps, blooms, codecs.
+        let mut readers = self.reader.readers.write();
+        let mut maps = self.reader.maps.write();
+        let mut blooms = self.reader.blooms.write();
+        let mut codecs = self.reader.codecs.write();
+        let stale_log_numbers: Vec<u64> = readers
+            .keys()
+            .filter(|&&number| number < compacted_log_number)
+            .cloned()
+            .collect();
+
+        for log_number in stale_log_numbers {
+            readers.remove(&log_number);
+            maps.remove(&log_number);
+            blooms.remove(&log_number);
+            codecs.remove(&log_number);
+            let log_path = log_path(&self.path, log_number);
+            fs::remove_file(&log_path).context(|| ErrorContext::new("removing").file(&log_path))?;
+            remove_if_exists(&bloom_path(&self.path, log_number))?;
+        }
+
+        self.progress.dead.store(0, Ordering::SeqCst);
+        self.progress.tombstones.store(0, Ordering::SeqCst);
+        self.progress.tombstone_bytes.store(0, Ordering::SeqCst);
+        self.oldest_tombstone = None;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let compacted_file = self.path.join(COMPACTED_FILE);
+        fs::write(&compacted_file, now.as_secs().to_string())
+            .context(|| ErrorContext::new("writing").file(&compacted_file))?;
+        if let Some(values) = self.reader.values.read().as_ref() {
+            values::write_checkpoint(&self.path, &self.reader.index, values)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl SyncLog for KvStoreWriter {
+    fn sync_log(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+}
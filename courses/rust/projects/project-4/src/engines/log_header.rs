@@ -0,0 +1,166 @@
+//! The header each log starts with, so that a file that isn't a kvs log, or is one in a
+//! format this build doesn't read, is refused rather than misparsed.
+//!
+//! A header is `MAGIC`, the log format version as a big-endian `u16`, the major, minor and
+//! patch version of the build that created the log as big-endian `u16`s, when it was
+//! created in seconds since the Unix epoch as a big-endian `u64`, the codec of its records
+//! as a big-endian `u16`, and a CRC-32 of all that as a big-endian `u32`. Format 1 headers
+//! have no codec; their records are MessagePack, as are those of logs written before
+//! headers (format 0), which start straight with a record. Format 3 has the header of
+//! format 2, and sets carry their value's version and write time. Format 4 adds the
+//! store's stamp to sets, and removes carry the same meta as sets.
+
+use super::codec::Codec;
+use crate::CorruptionKind;
+use crate::KvsError;
+use crate::Result;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Version of the log format this build writes.
+pub(crate) const LOG_FORMAT_VERSION: u16 = 4;
+
+// Starts with a byte msgpack never starts a value with, so a header can't be taken for a
+// record of a format 0 log, which always starts with a map of one entry.
+const MAGIC: [u8; 4] = [0xc1, b'K', b'L', b'G'];
+const RECORD_START: u8 = 0x81;
+
+// The magic, format version and engine version, laid out alike in every format.
+const PREFIX_LEN: usize = 12;
+
+/// Length of the header this build writes.
+pub(crate) const HEADER_LEN: u64 = 26;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct LogHeader {
+    pub(crate) format_version: u16,
+    /// The major, minor and patch version of the build that created the log.
+    pub(crate) engine_version: [u16; 3],
+    pub(crate) created: SystemTime,
+    pub(crate) codec: Codec,
+}
+
+impl LogHeader {
+    /// The header of a log of `codec` records created now by this build.
+    pub(crate) fn new(codec: Codec) -> Self {
+        let version = |part: &str| part.parse().unwrap_or(u16::MAX);
+        Self {
+            format_version: LOG_FORMAT_VERSION,
+            engine_version: [
+                version(env!("CARGO_PKG_VERSION_MAJOR")),
+                version(env!("CARGO_PKG_VERSION_MINOR")),
+                version(env!("CARGO_PKG_VERSION_PATCH")),
+            ],
+            created: SystemTime::now(),
+            codec,
+        }
+    }
+
+    pub(crate) fn write(&self, writer: &mut impl Write) -> Result<()> {
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&self.format_version.to_be_bytes());
+        for part in self.engine_version {
+            header.extend_from_slice(&part.to_be_bytes());
+        }
+        let created = self.created.duration_since(UNIX_EPOCH).unwrap_or_default();
+        header.extend_from_slice(&created.as_secs().to_be_bytes());
+        header.extend_from_slice(&self.codec.id().to_be_bytes());
+        header.extend_from_slice(&crc32fast::hash(&header).to_be_bytes());
+        writer.write_all(&header)?;
+        Ok(())
+    }
+
+    /// Read the header of log `log_number`, leaving `reader` at its first record. Return
+    /// None for a format 0 log, or an empty one. A log in a newer format is refused on its
+    /// version alone, since the rest of its header may be laid out differently.
+    pub(crate) fn read(reader: &mut BufReader<File>, log_number: u64) -> Result<Option<Self>> {
+        let corruption = |kind| KvsError::Corruption {
+            log_number,
+            offset: 0,
+            kind,
+        };
+        match reader.fill_buf()?.first() {
+            None | Some(&RECORD_START) => return Ok(None),
+            Some(&first) if first == MAGIC[0] => {}
+            Some(_) => return Err(corruption(CorruptionKind::Header)),
+        }
+        let mut header = vec![0; PREFIX_LEN];
+        read_exact(reader, &mut header, log_number)?;
+        if header[..4] != MAGIC {
+            return Err(corruption(CorruptionKind::Header));
+        }
+        let format_version = u16_at(&header, 4);
+        let engine_version = [u16_at(&header, 6), u16_at(&header, 8), u16_at(&header, 10)];
+        if format_version > LOG_FORMAT_VERSION {
+            let [major, minor, patch] = engine_version;
+            return Err(KvsError::UnsupportedLogFormat {
+                log_number,
+                version: format_version,
+                written_by: format!("{}.{}.{}", major, minor, patch),
+            });
+        }
+        let len = header_len(format_version).ok_or(corruption(CorruptionKind::Header))?;
+        header.resize(len as usize, 0);
+        read_exact(reader, &mut header[PREFIX_LEN..], log_number)?;
+
+        let (fields, checksum) = header.split_at(len as usize - 4);
+        if crc32fast::hash(fields).to_be_bytes() != checksum {
+            return Err(corruption(CorruptionKind::Header));
+        }
+        let codec = match format_version {
+            1 => Some(Codec::MessagePack),
+            _ => Codec::from_id(u16_at(fields, 20)),
+        };
+        Ok(Some(Self {
+            format_version,
+            engine_version,
+            created: UNIX_EPOCH
+                + Duration::from_secs(u64::from_be_bytes(fields[12..20].try_into().unwrap())),
+            codec: codec.ok_or(corruption(CorruptionKind::Header))?,
+        }))
+    }
+}
+
+// Length of a header of format `version`, if it has one.
+fn header_len(version: u16) -> Option<u64> {
+    match version {
+        1 => Some(24),
+        2..=4 => Some(HEADER_LEN),
+        _ => None,
+    }
+}
+
+// Fill `buf` from `reader`, as a truncated header if the log ends first.
+fn read_exact(reader: &mut BufReader<File>, buf: &mut [u8], log_number: u64) -> Result<()> {
+    match reader.read_exact(buf) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Err(KvsError::Corruption {
+            log_number,
+            offset: 0,
+            kind: CorruptionKind::Truncated,
+        }),
+        result => Ok(result?),
+    }
+}
+
+/// Check the header of log `log_number` and return the offset of its first record, leaving
+/// `reader` there, and the codec of its records. Each format this build still reads has an
+/// arm in `header_len`; a log in an older one is read as it is, and rewritten in the
+/// current one when it's next compacted.
+pub(crate) fn records_start(reader: &mut BufReader<File>, log_number: u64) -> Result<(u64, Codec)> {
+    match LogHeader::read(reader, log_number)? {
+        None => Ok((0, Codec::MessagePack)),
+        Some(header) => Ok((header_len(header.format_version).unwrap(), header.codec)),
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes(bytes[at..at + 2].try_into().unwrap())
+}
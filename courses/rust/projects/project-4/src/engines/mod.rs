@@ -1,16 +1,372 @@
+use crate::dump;
+use crate::dump::Exporter;
+use crate::KvsError;
 use crate::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::Read;
+use std::io::Write;
+use std::ops::RangeBounds;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
-pub trait KvsEngine: Clone + Send + 'static {
+/// The gauges an engine reports about itself for dashboards, under the same names whichever
+/// engine it is, so one dashboard serves a server on any of them. Every `KvsEngine` has
+/// them; see `ServerStats::engine`.
+pub trait EngineMetrics {
+    /// Return the engine's gauges, leaving out, as None, those it has no notion of.
+    fn engine_metrics(&self) -> Result<EngineGauges>;
+}
+
+pub trait KvsEngine: EngineMetrics + Clone + Send + 'static {
     /// Set the value of a string key to a string. Return an error if the value is not written successfully.
     fn set(&self, key: String, value: String) -> Result<()>;
     // Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
     fn get(&self, key: String) -> Result<Option<String>>;
+    /// Get the values of several keys, in the order of `keys`, with None for those that
+    /// don't exist.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>>;
+    /// Get the value of a key with its version and when it was written, or None if it
+    /// doesn't exist. Return an error if the engine doesn't keep versions.
+    fn get_with_meta(&self, _key: String) -> Result<Option<(String, EntryMeta)>> {
+        Err(KvsError::StringError(
+            "versions are not supported by this engine".to_owned(),
+        ))
+    }
+    /// Get what the engine keeps of a key's value, without the value, or None if it doesn't
+    /// exist. Return an error if the engine doesn't keep versions.
+    fn metadata(&self, key: String) -> Result<Option<EntryMeta>> {
+        Ok(self.get_with_meta(key)?.map(|(_, meta)| meta))
+    }
     /// Remove a given string key. Return an error if the key does not exit or value is not read successfully.
     fn remove(&self, key: String) -> Result<()>;
+    /// Return whether a given string key is set, without reading its value.
+    fn contains(&self, key: String) -> Result<bool>;
+    /// Return the number of keys that are set.
+    fn len(&self) -> Result<u64>;
+    /// Return every key that is set, in no particular order.
+    fn keys(&self) -> Result<Vec<String>>;
+    /// Return up to `limit` of the keys that are set, in order, starting with the first
+    /// after `after`, or the first of all if it's None.
+    fn keys_after(&self, after: Option<String>, limit: usize) -> Result<Vec<String>>;
+    /// Return the keys in `range` with their values, in key order. Writes made meanwhile
+    /// may or may not be included.
+    fn range(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>>;
+    /// Return the keys starting with `prefix` with their values, in key order. Writes made
+    /// meanwhile may or may not be included.
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>>;
+    /// Return the keys set to exactly `value`, in key order. Engines without an index of
+    /// values read every pair to find them.
+    fn find_by_value(&self, value: &str) -> Result<Vec<String>> {
+        Ok(self
+            .range(..)?
+            .into_iter()
+            .filter(|(_, pair_value)| pair_value == value)
+            .map(|(key, _)| key)
+            .collect())
+    }
+    /// Return whether no keys are set.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Add `delta` to the integer value of a key, treating a missing key as 0, and return the
+    /// new value. Return an error if the value isn't an integer or the sum overflows.
+    fn incr(&self, key: String, delta: i64) -> Result<i64>;
+    /// Move the value of `old_key` to `new_key`, replacing any value `new_key` had, as a
+    /// single write, so a crash partway leaves both keys as they were or both changed.
+    /// Return `KvsError::KeyNotFound` if `old_key` isn't set. A key renamed to itself is
+    /// left as it is.
+    fn rename(&self, old_key: String, new_key: String) -> Result<()>;
+    /// Append `suffix` to the value of a key, treating a missing key as empty, and return
+    /// the length of the new value in bytes.
+    fn append(&self, key: String, suffix: String) -> Result<u64>;
+    /// Take the lock `key` for `owner` until `ttl` from now, if no other owner holds it,
+    /// and return whether it was taken. An owner taking a lock it holds extends it. The
+    /// lock is kept as the key's value, so a lock whose owner died expires by itself; a
+    /// key set to something else returns `KvsError::NotALock`.
+    fn acquire_lock(&self, key: String, owner: String, ttl: Duration) -> Result<bool>;
+    /// Release the lock `key` if `owner` holds it, removing the key, and return whether it
+    /// did hold it. A lock that has expired is left for the next owner to take over.
+    fn release_lock(&self, key: String, owner: String) -> Result<bool>;
+    /// Get `len` bytes of the value of a key from byte `offset`, fewer if the value ends
+    /// sooner, or None if the key doesn't exist. Return an error if either end of the range
+    /// falls inside a character.
+    fn get_range(&self, key: String, offset: u64, len: u64) -> Result<Option<String>> {
+        self.get(key)?
+            .map(|value| value_range(&value, offset, len))
+            .transpose()
+    }
+    /// Start a transaction. Return an error if the engine doesn't support transactions.
+    fn transaction(&self) -> Result<Txn> {
+        Err(KvsError::StringError(
+            "transactions are not supported by this engine".to_owned(),
+        ))
+    }
+    /// Return the bucket `name`: a namespace of keys kept apart from those of other buckets.
+    /// See `Bucket`.
+    fn bucket(&self, name: &str) -> Result<Bucket<Self>> {
+        Bucket::new(self.clone(), name)
+    }
+    /// Set several keys with a single write to the underlying storage. Return an error if any value is not written successfully.
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()>;
+    /// Return the engine's name, as `kvs-server --engine` takes it.
+    fn name(&self) -> &'static str;
+    /// Return the number of bytes the engine's files occupy on disk.
+    fn size_on_disk(&self) -> Result<u64>;
+    /// Reclaim the space held by overwritten and removed values now, rather than when the
+    /// engine next gets around to it.
+    fn compact(&self) -> Result<()>;
+    /// Return the number of compactions performed since the engine was opened.
+    fn compactions(&self) -> u64;
+    /// Return how far along a compaction is, if one is running, and how much is left for
+    /// the next. Engines that compact out of sight report themselves idle.
+    fn compaction_state(&self) -> CompactionState {
+        CompactionState::default()
+    }
+    /// Return how many bytes of writes that have returned aren't known to be synced to
+    /// disk, and so could be lost to a power loss. Engines that don't count them return 0.
+    fn unsynced_bytes(&self) -> u64 {
+        0
+    }
+    /// Write every pair to `writer` as a dump: a versioned header, then the pairs in key
+    /// order, each checksummed. Return the number of pairs. Writes made meanwhile may or
+    /// may not be included.
+    fn export(&self, writer: impl Write) -> Result<u64> {
+        let mut exporter = Exporter::new(writer)?;
+        let mut keys = self.keys()?;
+        keys.sort_unstable();
+        for key in keys {
+            // Skip keys removed since they were listed.
+            if let Some(value) = self.get(key.clone())? {
+                exporter.pair(key, value)?;
+            }
+        }
+        exporter.finish()
+    }
+    /// Set every pair of a dump written by `export`, by this engine or another. Return the
+    /// number of pairs, or an error if the dump is damaged or cut short, in which case the
+    /// pairs before the damage may have been set.
+    fn import(&self, reader: impl Read) -> Result<u64> {
+        dump::import(self, reader)
+    }
+}
+
+/// Add `delta` to a stored counter value, as `KvsEngine::incr` does.
+pub(crate) fn add_delta(current: Option<String>, delta: i64) -> Result<i64> {
+    let current = match current {
+        Some(value) => value.parse::<i64>().map_err(|_| KvsError::NotAnInteger)?,
+        None => 0,
+    };
+    current.checked_add(delta).ok_or(KvsError::IntegerOverflow)
+}
+
+// What a lock is kept as: `kvs-lock:<expiry in ms since the Unix epoch>:<owner>`.
+const LOCK_PREFIX: &str = "kvs-lock:";
+
+/// Split a lock's value into when it expires and its owner.
+fn parse_lock(value: &str) -> Result<(u64, &str)> {
+    value
+        .strip_prefix(LOCK_PREFIX)
+        .and_then(|lock| lock.split_once(':'))
+        .and_then(|(expires, owner)| Some((expires.parse().ok()?, owner)))
+        .ok_or(KvsError::NotALock)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The value to set to take a lock held as `current` for `owner`, as
+/// `KvsEngine::acquire_lock` does, or None if another owner holds it.
+pub(crate) fn lock_for(
+    current: Option<&str>,
+    owner: &str,
+    ttl: Duration,
+) -> Result<Option<String>> {
+    let now = now_ms();
+    if let Some(current) = current {
+        let (expires, holder) = parse_lock(current)?;
+        if holder != owner && expires > now {
+            return Ok(None);
+        }
+    }
+    let expires = now.saturating_add(ttl.as_millis() as u64);
+    Ok(Some(format!("{}{}:{}", LOCK_PREFIX, expires, owner)))
+}
+
+/// Whether `owner` holds a lock held as `current`, and so may release it, as
+/// `KvsEngine::release_lock` does.
+pub(crate) fn holds_lock(current: Option<&str>, owner: &str) -> Result<bool> {
+    match current {
+        Some(current) => {
+            let (expires, holder) = parse_lock(current)?;
+            Ok(holder == owner && expires > now_ms())
+        }
+        None => Ok(false),
+    }
+}
+
+/// The `len` bytes of `value` from `offset`, as `KvsEngine::get_range` returns them.
+pub(crate) fn value_range(value: &str, offset: u64, len: u64) -> Result<String> {
+    let start = offset.min(value.len() as u64) as usize;
+    let end = offset.saturating_add(len).min(value.len() as u64) as usize;
+    match value.get(start..end) {
+        Some(range) => Ok(range.to_owned()),
+        None => Err(KvsError::StringError(format!(
+            "bytes {} to {} of the value split a character",
+            start, end
+        ))),
+    }
 }
 
+/// The largest keys and values an engine or server accepts. Writes of larger ones fail with
+/// `KvsError::TooLarge`. Unset limits don't apply.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeLimits {
+    pub max_key_bytes: Option<usize>,
+    pub max_value_bytes: Option<usize>,
+}
+
+impl SizeLimits {
+    /// Return `KvsError::TooLarge` if `key`, or `value` if given, is over its limit.
+    pub fn check(&self, key: &str, value: Option<&str>) -> Result<()> {
+        check_len("key", key.len(), self.max_key_bytes)?;
+        match value {
+            Some(value) => check_len("value", value.len(), self.max_value_bytes),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What an engine's compaction is up to; see `KvsEngine::compaction_state`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionState {
+    /// Whether a compaction is running. Writes wait for it to finish.
+    pub running: bool,
+    /// Bytes of live values rewritten so far by the running compaction, or by the last one
+    /// when idle.
+    pub bytes_processed: u64,
+    /// Bytes of live values the running compaction is estimated to have left to rewrite.
+    pub bytes_remaining: u64,
+    /// Bytes held by overwritten and removed values, which the next compaction reclaims.
+    pub dead_bytes: u64,
+    /// Removes made since the last compaction, whose records the next one drops.
+    #[serde(default)]
+    pub tombstones: u64,
+    /// Bytes of the logs held by those removes' own records, counted in `dead_bytes` too.
+    #[serde(default)]
+    pub tombstone_bytes: u64,
+    /// Whether writes are being stalled because `dead_bytes` is over the engine's limit.
+    #[serde(default)]
+    pub stalled: bool,
+    /// Writes stalled or refused so far for that.
+    #[serde(default)]
+    pub stalled_writes: u64,
+}
+
+/// An engine's gauges; see `EngineMetrics`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EngineGauges {
+    /// Size of the engine's files on disk, in bytes.
+    pub disk_bytes: u64,
+    /// Number of files the engine's data is spread over, such as a `KvStore`'s logs.
+    pub segments: Option<u64>,
+    /// Bytes on disk held by overwritten and removed values, not yet reclaimed.
+    pub dead_bytes: Option<u64>,
+    /// Reads answered from the engine's cache since it was opened.
+    pub cache_hits: Option<u64>,
+    /// Reads that had to go to disk since it was opened.
+    pub cache_misses: Option<u64>,
+}
+
+/// What an engine keeps of a value besides the value itself; see
+/// `KvsEngine::get_with_meta` and `KvsEngine::metadata`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryMeta {
+    /// Grows with every write to the key, so a value read again with the same version
+    /// hasn't been written meanwhile. 0 for values set before versions were kept.
+    pub version: u64,
+    /// When the value was written, if known. Taken from the writer's clock, so only
+    /// roughly comparable between servers.
+    pub written: Option<SystemTime>,
+    /// When the value was written by the store's own clock, in nanoseconds since the Unix
+    /// epoch, if known. Unlike `written` it never goes back, so of two values of a store
+    /// the one with the later stamp was written last.
+    #[serde(default)]
+    pub stamp: Option<u64>,
+    /// Length of the value in bytes.
+    #[serde(default)]
+    pub size: u64,
+}
+
+fn check_len(what: &str, len: usize, max: Option<usize>) -> Result<()> {
+    match max {
+        Some(max) if len > max => Err(KvsError::TooLarge(format!(
+            "{} of {} bytes is over the limit of {} bytes",
+            what, len, max
+        ))),
+        _ => Ok(()),
+    }
+}
+
+mod background_sync;
+
+mod bloom;
+
+mod boxed;
+pub use self::boxed::BoxedEngine;
+
+mod bucket;
+pub(crate) use self::bucket::prefix as bucket_prefix;
+pub use self::bucket::Bucket;
+
+mod codec;
+pub use self::codec::Codec;
+
+mod group_commit;
+
+mod key_lock;
+pub use self::key_lock::KeyGuard;
+
 mod kvs;
+#[cfg(feature = "fuzz")]
+pub(crate) use self::kvs::index_log;
+pub(crate) use self::kvs::Change;
+pub(crate) use self::kvs::Command;
+pub use self::kvs::FsckReport;
 pub use self::kvs::KvStore;
+pub use self::kvs::LogReport;
+pub use self::kvs::OpenOptions;
+pub use self::kvs::Revision;
+pub use self::kvs::StoreStats;
+pub use self::kvs::TombstoneHorizon;
+pub use self::kvs::TornTail;
+pub use self::kvs::WriteStall;
+
+mod log_header;
+pub(crate) use self::log_header::LOG_FORMAT_VERSION;
+
+mod registry;
+pub use self::registry::open_registered;
+pub use self::registry::register_engine;
+pub use self::registry::registered_engines;
+pub use self::registry::BUILTIN_ENGINES;
+
+#[cfg(feature = "rocksdb")]
+mod rocks;
+#[cfg(feature = "rocksdb")]
+pub use self::rocks::RocksKvsEngine;
 
 mod sled;
+pub use self::sled::Durability;
 pub use self::sled::SledKvsEngine;
+
+mod traced;
+pub use self::traced::Traced;
+
+mod txn;
+pub use self::txn::Txn;
@@ -0,0 +1,88 @@
+//! Engines from outside this crate, known by name, which `kvs-server --engine` opens as it
+//! does the built-in `kvs` and `sled`.
+//!
+//! A crate with an engine of its own registers it before running the server, so a binary
+//! of its own is all it takes:
+//!
+//! ```no_run
+//! use kvs::server_cli::Outcome;
+//! use kvs::BoxedEngine;
+//! use kvs::KvStore;
+//!
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     // Stands in for an engine from another crate.
+//!     kvs::register_engine("strict-kvs", |dir| {
+//!         Ok(BoxedEngine::new(KvStore::open_strict(dir)?))
+//!     })?;
+//!     if let Outcome::ConfigChecked { config, problems } = kvs::server_cli::run()? {
+//!         println!("{}", config.join("\n"));
+//!         if !problems.is_empty() {
+//!             return Err(problems.join("\n").into());
+//!         }
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use super::BoxedEngine;
+use crate::sync::RwLock;
+use crate::KvsError;
+use crate::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The names of the engines built into this crate, which can't be registered.
+#[cfg(not(feature = "rocksdb"))]
+pub const BUILTIN_ENGINES: &[&str] = &["kvs", "sled"];
+/// The names of the engines built into this crate, which can't be registered.
+#[cfg(feature = "rocksdb")]
+pub const BUILTIN_ENGINES: &[&str] = &["kvs", "sled", "rocksdb"];
+
+// Opens an engine keeping its files in the given directory.
+type Constructor = Arc<dyn Fn(&Path) -> Result<BoxedEngine> + Send + Sync>;
+
+static ENGINES: RwLock<BTreeMap<String, Constructor>> = RwLock::new(BTreeMap::new());
+
+/// Register the engine `name`, opened by `constructor` in the directory it's to keep its
+/// files in. Return an error if `name` is taken, by a built-in engine or one registered
+/// before.
+pub fn register_engine<F>(name: &str, constructor: F) -> Result<()>
+where
+    F: Fn(&Path) -> Result<BoxedEngine> + Send + Sync + 'static,
+{
+    if BUILTIN_ENGINES.contains(&name) {
+        return Err(KvsError::StringError(format!(
+            "{} is a built-in engine",
+            name
+        )));
+    }
+    let mut engines = ENGINES.write();
+    if engines.contains_key(name) {
+        return Err(KvsError::StringError(format!(
+            "an engine named {} is already registered",
+            name
+        )));
+    }
+    engines.insert(name.to_owned(), Arc::new(constructor));
+    Ok(())
+}
+
+/// Return the names of the registered engines, in order, without the built-in ones.
+pub fn registered_engines() -> Vec<String> {
+    ENGINES.read().keys().cloned().collect()
+}
+
+/// Open the registered engine `name` in `dir`. Return an error if there's no such engine
+/// or its constructor fails.
+pub fn open_registered(name: &str, dir: &Path) -> Result<BoxedEngine> {
+    // Not held while the engine opens, so a constructor may register engines of its own.
+    let constructor = ENGINES.read().get(name).cloned();
+    match constructor {
+        Some(constructor) => constructor(dir),
+        None => Err(KvsError::StringError(format!(
+            "no engine named {} is registered",
+            name
+        ))),
+    }
+}
@@ -0,0 +1,328 @@
+use super::Durability;
+use crate::dump::Exporter;
+use crate::error::KvsError;
+use crate::error::Result;
+use crate::sync::Mutex;
+use crate::EngineGauges;
+use crate::EngineMetrics;
+use crate::KvsEngine;
+use crate::SizeLimits;
+use rocksdb::Direction;
+use rocksdb::IteratorMode;
+use rocksdb::Options;
+use rocksdb::WriteBatch;
+use rocksdb::WriteOptions;
+use rocksdb::DB;
+use std::fs;
+use std::io::Write;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::MutexGuard;
+use std::time::Duration;
+
+/// An engine over RocksDB, an LSM tree, for comparing against `KvStore` on the same
+/// workloads.
+///
+/// Writes are made one at a time, as `KvStore`'s are, so that `remove`, `incr`, `rename` and
+/// `append` can read the value they replace; reads go straight to RocksDB.
+#[derive(Clone)]
+pub struct RocksKvsEngine {
+    db: Arc<DB>,
+    writes: Arc<Mutex<()>>,
+    durability: Durability,
+    size_limits: SizeLimits,
+}
+
+impl RocksKvsEngine {
+    /// Open the database in `path`, creating it if there's none, syncing every write.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        Ok(Self {
+            db: Arc::new(DB::open(&options, path)?),
+            writes: Arc::new(Mutex::new(())),
+            durability: Durability::EveryWrite,
+            size_limits: SizeLimits::default(),
+        })
+    }
+
+    /// Sync writes as `durability` says. With `Background`, a write is in RocksDB's log
+    /// once it returns but reaches the disk when the operating system gets to it.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Refuse writes of keys and values over `limits`.
+    pub fn with_size_limits(mut self, limits: SizeLimits) -> Self {
+        self.size_limits = limits;
+        self
+    }
+
+    fn write_options(&self) -> WriteOptions {
+        let mut options = WriteOptions::default();
+        options.set_sync(self.durability == Durability::EveryWrite);
+        options
+    }
+
+    fn lock_writes(&self) -> MutexGuard<'_, ()> {
+        self.writes.lock()
+    }
+
+    // Collect the pairs from `start` on for as long as `within` holds, in key order.
+    fn pairs_from(
+        &self,
+        start: IteratorMode<'_>,
+        within: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for pair in self.db.iterator(start) {
+            let (key, value) = pair?;
+            if !within(&key) {
+                break;
+            }
+            pairs.push(utf8_pair((key, value))?);
+        }
+        Ok(pairs)
+    }
+}
+
+/// Reports the SST files as segments, and as dead the bytes of them RocksDB estimates
+/// compaction would drop. Cache hits are only counted with statistics on, which they
+/// aren't.
+impl EngineMetrics for RocksKvsEngine {
+    fn engine_metrics(&self) -> Result<EngineGauges> {
+        let sst_bytes = self
+            .db
+            .property_int_value("rocksdb.live-sst-files-size")?
+            .unwrap_or(0);
+        let live_bytes = self
+            .db
+            .property_int_value("rocksdb.estimate-live-data-size")?
+            .unwrap_or(0);
+        Ok(EngineGauges {
+            disk_bytes: self.size_on_disk()?,
+            segments: Some(self.db.live_files()?.len() as u64),
+            dead_bytes: Some(sst_bytes.saturating_sub(live_bytes)),
+            cache_hits: None,
+            cache_misses: None,
+        })
+    }
+}
+
+impl KvsEngine for RocksKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.size_limits.check(&key, Some(&value))?;
+        let _writes = self.lock_writes();
+        self.db.put_opt(key, value, &self.write_options())?;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.db.get(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.db
+            .multi_get(keys)
+            .into_iter()
+            .map(|value| -> Result<Option<String>> {
+                Ok(value?.map(String::from_utf8).transpose()?)
+            })
+            .collect()
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let _writes = self.lock_writes();
+        self.db.get_pinned(&key)?.ok_or(KvsError::KeyNotFound)?;
+        self.db.delete_opt(key, &self.write_options())?;
+        Ok(())
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let _writes = self.lock_writes();
+        let current = self.db.get(&key)?.map(String::from_utf8).transpose()?;
+        let value = super::add_delta(current, delta)?;
+        let digits = value.to_string();
+        self.size_limits.check(&key, Some(&digits))?;
+        self.db.put_opt(key, digits, &self.write_options())?;
+        Ok(value)
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<u64> {
+        let _writes = self.lock_writes();
+        let mut value = self
+            .db
+            .get(&key)?
+            .map(String::from_utf8)
+            .transpose()?
+            .unwrap_or_default();
+        value.push_str(&suffix);
+        self.size_limits.check(&key, Some(&value))?;
+        let len = value.len() as u64;
+        self.db.put_opt(key, value, &self.write_options())?;
+        Ok(len)
+    }
+
+    fn acquire_lock(&self, key: String, owner: String, ttl: Duration) -> Result<bool> {
+        let _writes = self.lock_writes();
+        let current = self.db.get(&key)?.map(String::from_utf8).transpose()?;
+        let lock = match super::lock_for(current.as_deref(), &owner, ttl)? {
+            Some(lock) => lock,
+            None => return Ok(false),
+        };
+        self.size_limits.check(&key, Some(&lock))?;
+        self.db.put_opt(key, lock, &self.write_options())?;
+        Ok(true)
+    }
+
+    fn release_lock(&self, key: String, owner: String) -> Result<bool> {
+        let _writes = self.lock_writes();
+        let current = self.db.get(&key)?.map(String::from_utf8).transpose()?;
+        if !super::holds_lock(current.as_deref(), &owner)? {
+            return Ok(false);
+        }
+        self.db.delete_opt(key, &self.write_options())?;
+        Ok(true)
+    }
+
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        self.size_limits.check(&new_key, None)?;
+        let _writes = self.lock_writes();
+        let value = self.db.get(&old_key)?.ok_or(KvsError::KeyNotFound)?;
+        if old_key == new_key {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::default();
+        batch.put(new_key, value);
+        batch.delete(old_key);
+        self.db.write_opt(batch, &self.write_options())?;
+        Ok(())
+    }
+
+    fn contains(&self, key: String) -> Result<bool> {
+        Ok(self.db.get_pinned(key)?.is_some())
+    }
+
+    /// Counts the keys one by one, as RocksDB only keeps an estimate.
+    fn len(&self) -> Result<u64> {
+        let mut len = 0;
+        for pair in self.db.iterator(IteratorMode::Start) {
+            pair?;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for pair in self.db.iterator(IteratorMode::Start) {
+            let (key, _) = pair?;
+            keys.push(String::from_utf8(key.into_vec())?);
+        }
+        Ok(keys)
+    }
+
+    fn keys_after(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let start = match &after {
+            Some(key) => IteratorMode::From(key.as_bytes(), Direction::Forward),
+            None => IteratorMode::Start,
+        };
+        let mut keys = Vec::new();
+        for pair in self.db.iterator(start) {
+            if keys.len() == limit {
+                break;
+            }
+            let (key, _) = pair?;
+            if after
+                .as_ref()
+                .is_some_and(|after| *key == *after.as_bytes())
+            {
+                continue;
+            }
+            keys.push(String::from_utf8(key.into_vec())?);
+        }
+        Ok(keys)
+    }
+
+    fn range(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                IteratorMode::From(key.as_bytes(), Direction::Forward)
+            }
+            Bound::Unbounded => IteratorMode::Start,
+        };
+        let mut pairs = self.pairs_from(start, |key| match range.end_bound() {
+            Bound::Included(end) => key <= end.as_bytes(),
+            Bound::Excluded(end) => key < end.as_bytes(),
+            Bound::Unbounded => true,
+        })?;
+        if let Bound::Excluded(start) = range.start_bound() {
+            pairs.retain(|(key, _)| key != start);
+        }
+        Ok(pairs)
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let start = IteratorMode::From(prefix.as_bytes(), Direction::Forward);
+        self.pairs_from(start, |key| key.starts_with(prefix.as_bytes()))
+    }
+
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in &pairs {
+            self.size_limits.check(key, Some(value))?;
+        }
+        let mut batch = WriteBatch::default();
+        for (key, value) in &pairs {
+            batch.put(key, value);
+        }
+        let _writes = self.lock_writes();
+        self.db.write_opt(batch, &self.write_options())?;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "rocksdb"
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        let mut size = 0;
+        for entry in fs::read_dir(self.db.path())? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                size += metadata.len();
+            }
+        }
+        Ok(size)
+    }
+
+    /// Iterates over a consistent view of the database rather than looking up each key.
+    fn export(&self, writer: impl Write) -> Result<u64> {
+        let mut exporter = Exporter::new(writer)?;
+        for pair in self.db.iterator(IteratorMode::Start) {
+            let (key, value) = utf8_pair(pair?)?;
+            exporter.pair(key, value)?;
+        }
+        exporter.finish()
+    }
+
+    /// Compacts every level down to the last, on the calling thread.
+    fn compact(&self) -> Result<()> {
+        self.db.compact_range(None::<&[u8]>, None::<&[u8]>);
+        Ok(())
+    }
+
+    fn compactions(&self) -> u64 {
+        // RocksDB compacts on background threads of its own and doesn't count them.
+        0
+    }
+}
+
+fn utf8_pair((key, value): (Box<[u8]>, Box<[u8]>)) -> Result<(String, String)> {
+    Ok((
+        String::from_utf8(key.into_vec())?,
+        String::from_utf8(value.into_vec())?,
+    ))
+}
@@ -1,23 +1,117 @@
+use crate::dump::Exporter;
 use crate::error::KvsError;
 use crate::error::Result;
+use crate::EngineGauges;
+use crate::EngineMetrics;
 use crate::KvsEngine;
+use crate::SizeLimits;
+use serde::Deserialize;
+use sled::transaction::abort;
+use sled::transaction::TransactionError;
+use sled::Batch;
+use sled::Config;
 use sled::Db;
+use sled::IVec;
+use sled::Iter;
+use std::fmt;
+use std::io::Write;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// When a `SledKvsEngine`'s writes reach the disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Durability {
+    /// Flush after every write, so a write is on disk once it returns.
+    EveryWrite,
+    /// Leave flushing to sled's background thread, which runs every `flush_every_ms` of
+    /// the engine's `Config`. Writes since the last flush are lost in a crash.
+    Background,
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EveryWrite => write!(f, "every-write"),
+            Self::Background => write!(f, "background"),
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "every-write" => Ok(Self::EveryWrite),
+            "background" => Ok(Self::Background),
+            _ => Err(KvsError::StringError(format!(
+                "invalid durability {:?}; expected every-write or background",
+                s
+            ))),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    durability: Durability,
+    size_limits: SizeLimits,
 }
 
 impl SledKvsEngine {
+    /// Wrap an open database, flushing after every write.
     pub fn new(db: Db) -> Self {
-        Self { db }
+        Self {
+            db,
+            durability: Durability::EveryWrite,
+            size_limits: SizeLimits::default(),
+        }
+    }
+
+    /// Open a database tuned by `config`, such as its cache capacity and how often it
+    /// flushes in the background, with the given durability.
+    pub fn with_config(config: Config, durability: Durability) -> Result<Self> {
+        Ok(Self {
+            db: config.open()?,
+            durability,
+            size_limits: SizeLimits::default(),
+        })
+    }
+
+    /// Refuse writes of keys and values over `limits`.
+    pub fn with_size_limits(mut self, limits: SizeLimits) -> Self {
+        self.size_limits = limits;
+        self
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.durability == Durability::EveryWrite {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reports only the size of the tree on disk: sled 0.34 keeps its cache hits, and what
+/// it could say of dead space, to itself.
+impl EngineMetrics for SledKvsEngine {
+    fn engine_metrics(&self) -> Result<EngineGauges> {
+        Ok(EngineGauges {
+            disk_bytes: self.db.size_on_disk()?,
+            ..EngineGauges::default()
+        })
     }
 }
 
 impl KvsEngine for SledKvsEngine {
     fn set(&self, key: String, value: String) -> Result<()> {
+        self.size_limits.check(&key, Some(&value))?;
         self.db.insert(key, value.as_str())?;
-        self.db.flush()?;
+        self.flush()?;
         Ok(())
     }
 
@@ -31,9 +125,211 @@ impl KvsEngine for SledKvsEngine {
         Ok(value)
     }
 
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
     fn remove(&self, key: String) -> Result<()> {
         self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Retries until the value it read is still there to swap out.
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        loop {
+            let current = self.db.get(&key)?;
+            let value = super::add_delta(
+                current
+                    .as_ref()
+                    .map(|i_vec| String::from_utf8(i_vec.to_vec()))
+                    .transpose()?,
+                delta,
+            )?;
+            let digits = value.to_string();
+            self.size_limits.check(&key, Some(&digits))?;
+            let swapped = self
+                .db
+                .compare_and_swap(&key, current, Some(digits.as_bytes()))?;
+            if swapped.is_ok() {
+                self.flush()?;
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Retries until the value it read is still there to swap out, as `incr` does.
+    fn append(&self, key: String, suffix: String) -> Result<u64> {
+        loop {
+            let current = self.db.get(&key)?;
+            let mut value = current
+                .as_ref()
+                .map_or_else(Vec::new, |i_vec| i_vec.to_vec());
+            value.extend_from_slice(suffix.as_bytes());
+            let value = String::from_utf8(value)?;
+            self.size_limits.check(&key, Some(&value))?;
+            let swapped = self
+                .db
+                .compare_and_swap(&key, current, Some(value.as_bytes()))?;
+            if swapped.is_ok() {
+                self.flush()?;
+                return Ok(value.len() as u64);
+            }
+        }
+    }
+
+    /// Retries until the lock it read is still there to swap out, as `incr` does.
+    fn acquire_lock(&self, key: String, owner: String, ttl: Duration) -> Result<bool> {
+        loop {
+            let current = self.db.get(&key)?;
+            let value = current
+                .as_ref()
+                .map(|i_vec| String::from_utf8(i_vec.to_vec()))
+                .transpose()?;
+            let lock = match super::lock_for(value.as_deref(), &owner, ttl)? {
+                Some(lock) => lock,
+                None => return Ok(false),
+            };
+            self.size_limits.check(&key, Some(&lock))?;
+            let swapped = self
+                .db
+                .compare_and_swap(&key, current, Some(lock.as_bytes()))?;
+            if swapped.is_ok() {
+                self.flush()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Retries until the lock it read is still there to remove.
+    fn release_lock(&self, key: String, owner: String) -> Result<bool> {
+        loop {
+            let current = self.db.get(&key)?;
+            let value = current
+                .as_ref()
+                .map(|i_vec| String::from_utf8(i_vec.to_vec()))
+                .transpose()?;
+            if !super::holds_lock(value.as_deref(), &owner)? {
+                return Ok(false);
+            }
+            let swapped = self.db.compare_and_swap(&key, current, None::<&[u8]>)?;
+            if swapped.is_ok() {
+                self.flush()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Moves the value in a sled transaction, so no write comes between reading it and
+    /// moving it.
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        self.size_limits.check(&new_key, None)?;
+        let moved = self.db.transaction(|tx| {
+            let value = match tx.get(&old_key)? {
+                Some(value) => value,
+                None => return abort(KvsError::KeyNotFound),
+            };
+            if old_key != new_key {
+                tx.remove(old_key.as_str())?;
+                tx.insert(new_key.as_str(), value)?;
+            }
+            Ok(())
+        });
+        match moved {
+            Ok(()) => self.flush(),
+            Err(TransactionError::Abort(err)) => Err(err),
+            Err(TransactionError::Storage(err)) => Err(err.into()),
+        }
+    }
+
+    fn contains(&self, key: String) -> Result<bool> {
+        Ok(self.db.contains_key(key)?)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.db.len() as u64)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for key in self.db.iter().keys() {
+            keys.push(String::from_utf8(key?.to_vec())?);
+        }
+        Ok(keys)
+    }
+
+    fn keys_after(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let from = match after {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        let mut keys = Vec::new();
+        for key in self.db.range((from, Bound::Unbounded)).keys().take(limit) {
+            keys.push(String::from_utf8(key?.to_vec())?);
+        }
+        Ok(keys)
+    }
+
+    fn range(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        pairs(self.db.range(range))
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        pairs(self.db.scan_prefix(prefix))
+    }
+
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in &pairs {
+            self.size_limits.check(key, Some(value))?;
+        }
+        let mut batch = Batch::default();
+        for (key, value) in &pairs {
+            batch.insert(key.as_str(), value.as_str());
+        }
+        self.db.apply_batch(batch)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    /// Iterates over a consistent view of the tree rather than looking up each key.
+    fn export(&self, writer: impl Write) -> Result<u64> {
+        let mut exporter = Exporter::new(writer)?;
+        for pair in self.db.iter() {
+            let (key, value) = utf8_pair(pair?)?;
+            exporter.pair(key, value)?;
+        }
+        exporter.finish()
+    }
+
+    /// sled reclaims space on its own as it goes, so this only flushes what it has buffered.
+    fn compact(&self) -> Result<()> {
         self.db.flush()?;
         Ok(())
     }
+
+    fn compactions(&self) -> u64 {
+        // sled reclaims space internally and doesn't report it.
+        0
+    }
+}
+
+// Collect the pairs `iter` yields as strings, converting each as it's read, so an error
+// stops the scan there.
+fn pairs(iter: Iter) -> Result<Vec<(String, String)>> {
+    iter.map(|pair| utf8_pair(pair?)).collect()
+}
+
+fn utf8_pair((key, value): (IVec, IVec)) -> Result<(String, String)> {
+    Ok((
+        String::from_utf8(key.to_vec())?,
+        String::from_utf8(value.to_vec())?,
+    ))
 }
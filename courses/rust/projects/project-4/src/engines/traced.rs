@@ -0,0 +1,227 @@
+use super::CompactionState;
+use super::EngineGauges;
+use super::EngineMetrics;
+use super::EntryMeta;
+use super::KvsEngine;
+use super::Txn;
+use crate::trace::Span;
+use crate::Result;
+use slog::warn;
+use slog::Logger;
+use std::io::Write;
+use std::ops::RangeBounds;
+use std::time::Duration;
+use std::time::Instant;
+
+/// An engine that logs a warning for every operation taking `threshold` or longer, with the
+/// key it was on and whether the engine compacted meanwhile, which is likely why.
+///
+/// Compactions are told by `KvsEngine::compactions`, so one started by another operation
+/// running alongside is reported too, and sled's are never. Operations of a transaction
+/// aren't timed.
+///
+/// Built with the `tracing` feature, every operation also gets a span, slow or not; see
+/// `TraceId`.
+#[derive(Clone)]
+pub struct Traced<E: KvsEngine> {
+    engine: E,
+    log: Logger,
+    threshold: Duration,
+}
+
+impl<E: KvsEngine> Traced<E> {
+    pub fn new(engine: E, log: Logger, threshold: Duration) -> Self {
+        Self {
+            engine,
+            log,
+            threshold,
+        }
+    }
+
+    // Run `op`, the operation `name` on `keys` keys, the first of which is `key`.
+    fn time<T>(
+        &self,
+        name: &'static str,
+        key: Option<&str>,
+        keys: usize,
+        op: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let compactions = self.engine.compactions();
+        let span = Span::engine_op(name, key, keys);
+        let start = Instant::now();
+        let result = span.in_scope(op);
+        let elapsed = start.elapsed();
+        span.record_compacted(self.engine.compactions() > compactions);
+        span.record_failed(result.is_err());
+        if elapsed >= self.threshold {
+            warn!(
+                self.log,
+                "slow engine operation";
+                "op" => name,
+                "key" => key,
+                "keys" => keys,
+                "micros" => elapsed.as_micros() as u64,
+                "compacted" => self.engine.compactions() > compactions,
+                "failed" => result.is_err()
+            );
+        }
+        result
+    }
+}
+
+impl<E: KvsEngine> EngineMetrics for Traced<E> {
+    fn engine_metrics(&self) -> Result<EngineGauges> {
+        self.engine.engine_metrics()
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Traced<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let logged = key.clone();
+        self.time("set", Some(&logged), 1, || self.engine.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let logged = key.clone();
+        self.time("get", Some(&logged), 1, || self.engine.get(key))
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let logged = keys.first().cloned();
+        self.time("get_many", logged.as_deref(), keys.len(), || {
+            self.engine.get_many(keys)
+        })
+    }
+
+    fn get_with_meta(&self, key: String) -> Result<Option<(String, EntryMeta)>> {
+        let logged = key.clone();
+        self.time("get_with_meta", Some(&logged), 1, || {
+            self.engine.get_with_meta(key)
+        })
+    }
+
+    fn metadata(&self, key: String) -> Result<Option<EntryMeta>> {
+        let logged = key.clone();
+        self.time("metadata", Some(&logged), 1, || self.engine.metadata(key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let logged = key.clone();
+        self.time("remove", Some(&logged), 1, || self.engine.remove(key))
+    }
+
+    fn contains(&self, key: String) -> Result<bool> {
+        let logged = key.clone();
+        self.time("contains", Some(&logged), 1, || self.engine.contains(key))
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.time("len", None, 0, || self.engine.len())
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.time("keys", None, 0, || self.engine.keys())
+    }
+
+    fn keys_after(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let logged = after.clone();
+        self.time("keys_after", logged.as_deref(), limit, || {
+            self.engine.keys_after(after, limit)
+        })
+    }
+
+    fn range(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        self.time("range", None, 0, || self.engine.range(range))
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.time("scan_prefix", Some(prefix), 0, || {
+            self.engine.scan_prefix(prefix)
+        })
+    }
+
+    fn find_by_value(&self, value: &str) -> Result<Vec<String>> {
+        self.time("find_by_value", None, 0, || {
+            self.engine.find_by_value(value)
+        })
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let logged = key.clone();
+        self.time("incr", Some(&logged), 1, || self.engine.incr(key, delta))
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<u64> {
+        let logged = key.clone();
+        self.time("append", Some(&logged), 1, || {
+            self.engine.append(key, suffix)
+        })
+    }
+
+    fn acquire_lock(&self, key: String, owner: String, ttl: Duration) -> Result<bool> {
+        let logged = key.clone();
+        self.time("acquire_lock", Some(&logged), 1, || {
+            self.engine.acquire_lock(key, owner, ttl)
+        })
+    }
+
+    fn release_lock(&self, key: String, owner: String) -> Result<bool> {
+        let logged = key.clone();
+        self.time("release_lock", Some(&logged), 1, || {
+            self.engine.release_lock(key, owner)
+        })
+    }
+
+    fn get_range(&self, key: String, offset: u64, len: u64) -> Result<Option<String>> {
+        let logged = key.clone();
+        self.time("get_range", Some(&logged), 1, || {
+            self.engine.get_range(key, offset, len)
+        })
+    }
+
+    fn rename(&self, old_key: String, new_key: String) -> Result<()> {
+        let logged = old_key.clone();
+        self.time("rename", Some(&logged), 2, || {
+            self.engine.rename(old_key, new_key)
+        })
+    }
+
+    fn transaction(&self) -> Result<Txn> {
+        self.engine.transaction()
+    }
+
+    fn set_batch(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let logged = pairs.first().map(|(key, _)| key.clone());
+        self.time("set_batch", logged.as_deref(), pairs.len(), || {
+            self.engine.set_batch(pairs)
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        self.engine.size_on_disk()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.time("compact", None, 0, || self.engine.compact())
+    }
+
+    fn compactions(&self) -> u64 {
+        self.engine.compactions()
+    }
+
+    fn compaction_state(&self) -> CompactionState {
+        self.engine.compaction_state()
+    }
+
+    fn unsynced_bytes(&self) -> u64 {
+        self.engine.unsynced_bytes()
+    }
+
+    fn export(&self, writer: impl Write) -> Result<u64> {
+        self.engine.export(writer)
+    }
+}
@@ -0,0 +1,141 @@
+use super::KvStore;
+use super::KvsEngine;
+use crate::sync::Mutex;
+use crate::KvsError;
+use crate::Result;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Tracks which keys changed while transactions are open, shared by every clone of a store.
+///
+/// Every write takes the next version number. While at least one transaction is open, the
+/// version of each written key is remembered so commits can detect conflicting writes.
+#[derive(Default)]
+pub(crate) struct Versions {
+    inner: Mutex<VersionsInner>,
+}
+
+#[derive(Default)]
+struct VersionsInner {
+    current: u64,
+    open_transactions: u64,
+    changed: HashMap<String, u64>,
+}
+
+impl Versions {
+    /// Record a write of `keys`. Callers must hold the store's writer lock, so that a
+    /// commit validating under the same lock sees every completed write.
+    pub(crate) fn record<'a>(&self, keys: impl IntoIterator<Item = &'a String>) {
+        let mut inner = self.inner.lock();
+        inner.current += 1;
+        if inner.open_transactions > 0 {
+            let version = inner.current;
+            for key in keys {
+                inner.changed.insert(key.clone(), version);
+            }
+        }
+    }
+
+    /// Return whether any of `keys` was written after version `start`.
+    pub(crate) fn changed_since<'a>(
+        &self,
+        start: u64,
+        mut keys: impl Iterator<Item = &'a String>,
+    ) -> bool {
+        let inner = self.inner.lock();
+        keys.any(|key| {
+            inner
+                .changed
+                .get(key)
+                .is_some_and(|&version| version > start)
+        })
+    }
+
+    fn begin(&self) -> u64 {
+        let mut inner = self.inner.lock();
+        inner.open_transactions += 1;
+        inner.current
+    }
+
+    fn end(&self) {
+        let mut inner = self.inner.lock();
+        inner.open_transactions -= 1;
+        if inner.open_transactions == 0 {
+            inner.changed.clear();
+        }
+    }
+}
+
+/// A transaction on a `KvStore`, started with `KvsEngine::transaction`.
+///
+/// Writes are buffered until `commit`, which applies them as a single atomic log record.
+/// Reads see the transaction's own writes. Commit fails with `KvsError::TransactionConflict`
+/// if any key the transaction read or wrote was changed by someone else after it began, so
+/// a committed transaction behaves as if it ran alone against a snapshot taken at its start.
+/// Dropping a transaction without committing rolls it back.
+pub struct Txn {
+    store: KvStore,
+    versions: Arc<Versions>,
+    start: u64,
+    reads: BTreeSet<String>,
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Txn {
+    pub(crate) fn new(store: KvStore, versions: Arc<Versions>) -> Self {
+        let start = versions.begin();
+        Self {
+            store,
+            versions,
+            start,
+            reads: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        self.reads.insert(key.clone());
+        self.store.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Remove a given key. Return an error if the key does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// The keys the transaction writes, with whether each is set rather than removed.
+    pub(crate) fn writes(&self) -> impl Iterator<Item = (&String, bool)> {
+        self.writes
+            .iter()
+            .map(|(key, value)| (key, value.is_some()))
+    }
+
+    /// Apply the transaction's writes, or return `KvsError::TransactionConflict` and apply
+    /// nothing if another writer got there first.
+    pub fn commit(mut self) -> Result<()> {
+        let writes = std::mem::take(&mut self.writes);
+        self.store.commit(self.start, &self.reads, writes)
+    }
+
+    /// Discard the transaction's writes.
+    pub fn rollback(self) {}
+}
+
+impl Drop for Txn {
+    fn drop(&mut self) {
+        self.versions.end();
+    }
+}
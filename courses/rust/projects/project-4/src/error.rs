@@ -1,36 +1,301 @@
+use crate::Capabilities;
 use rmp_serde::decode;
 use rmp_serde::encode;
+use std::backtrace::Backtrace;
 use std::error;
 use std::fmt;
 use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::result;
 use std::string::FromUtf8Error;
+use std::time::Duration;
+
+/// How a log record failed to decode, or couldn't be found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// The log ends partway through a record.
+    Truncated,
+    /// The record's bytes don't decode as a command.
+    Malformed,
+    /// The log's header is damaged, or the file isn't a kvs log.
+    Header,
+    /// The index points into a log the store doesn't have open.
+    MissingLog,
+}
+
+/// What a store was doing when an I/O error came up: the operation, and the file, offset
+/// and key it was working on, where they're known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// What was being done to the file, as a verb: "opening", "reading", "appending to".
+    pub operation: &'static str,
+    pub file: Option<PathBuf>,
+    pub offset: Option<u64>,
+    pub key: Option<String>,
+}
+
+impl ErrorContext {
+    pub(crate) fn new(operation: &'static str) -> Self {
+        Self {
+            operation,
+            file: None,
+            offset: None,
+            key: None,
+        }
+    }
+
+    pub(crate) fn file(mut self, file: impl Into<PathBuf>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub(crate) fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub(crate) fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.operation)?;
+        if let Some(file) = &self.file {
+            write!(f, " {}", file.display())?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, " for key {:?}", key)?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub enum KvsError {
+    Corruption {
+        log_number: u64,
+        offset: u64,
+        kind: CorruptionKind,
+    },
+    /// A log is in a format newer than this build reads. Holds the format version and the
+    /// version of the build that wrote the log.
+    UnsupportedLogFormat {
+        log_number: u64,
+        version: u16,
+        written_by: String,
+    },
     Decode(decode::Error),
     Encode(encode::Error),
     IO(io::Error),
     KeyNotFound,
+    /// The value of a key being incremented isn't an integer.
+    NotAnInteger,
+    /// The value of a key being locked or unlocked isn't a lock.
+    NotALock,
+    /// Incrementing a key would take its value out of the range of an `i64`.
+    IntegerOverflow,
+    /// A replicated server that isn't the leader can't serve the request. Holds the leader's
+    /// address, if one is known.
+    NotLeader(Option<SocketAddr>),
+    /// A member of a cluster can't serve a request about keys another member owns. Holds
+    /// the owner's address.
+    Moved(SocketAddr),
     UnexpectedCommand,
     UnexpectedResponse,
     StringError(String),
+    /// Another writer changed a key the transaction used before it committed.
+    TransactionConflict,
+    /// A checksummed frame arrived damaged, and the request couldn't safely be sent again.
+    TransportCorruption,
+    /// The client and server speak different versions of the protocol.
+    ProtocolMismatch {
+        client: u16,
+        server: u16,
+    },
+    /// The server doesn't offer a capability the request needs.
+    Unsupported(Capabilities),
+    /// The server turned the connection away because all its threads are busy and its
+    /// queue is full. Worth retrying later.
+    ServerBusy,
+    /// The server refused a write while its engine compacts, and asked for it to be retried
+    /// after the given delay. Worth retrying then.
+    Throttled(Duration),
+    /// The server refused a request because the client is over its rate limit. Worth
+    /// retrying later.
+    RateLimited,
+    /// A store refused a write because its logs hold more dead bytes than its write stall
+    /// allows, as when compaction keeps failing; see `KvStore::with_write_stall`.
+    WriteStalled {
+        dead_bytes: u64,
+        limit: u64,
+    },
+    /// A key or value is over the size limit of the engine or server. Says which, and by
+    /// how much.
+    TooLarge(String),
+    /// The store was opened read-only, so it can't be written.
+    ReadOnly,
+    /// The server's ACL doesn't allow the request, or doesn't know the token; see
+    /// `kvs::acl`. Says why.
+    PermissionDenied(String),
+    /// Another `KvStore` has the directory open for writing. Holds the PID of its process,
+    /// if it could be read.
+    AlreadyLocked {
+        pid: Option<u32>,
+    },
+    /// There's no store in the directory, and it wasn't to be created.
+    StoreNotFound(PathBuf),
+    /// There's a store in the directory already, and a new one was to be created.
+    StoreExists(PathBuf),
     Sled(sled::Error),
+    #[cfg(feature = "rocksdb")]
+    Rocks(rocksdb::Error),
     Utf8(FromUtf8Error),
+    /// An `IO` or `Encode` error with what the store was doing when it came up.
+    Context(Box<ContextError>),
+}
+
+/// An error with what the store was doing when it came up, and a backtrace of where,
+/// captured if `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE` asks.
+#[derive(Debug)]
+pub struct ContextError {
+    pub context: ErrorContext,
+    pub source: KvsError,
+    pub backtrace: Backtrace,
+}
+
+impl KvsError {
+    /// The error under any context added to it, to match on.
+    pub fn root(&self) -> &KvsError {
+        match self {
+            Self::Context(err) => err.source.root(),
+            err => err,
+        }
+    }
+
+    /// What the store was doing when the error came up, if it's known.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Self::Context(err) => Some(&err.context),
+            _ => None,
+        }
+    }
+
+    /// Where the context was added to the error, if a backtrace was captured.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        match self {
+            Self::Context(err) => Some(&err.backtrace),
+            _ => None,
+        }
+    }
+
+    // Add `context` to an I/O error, or an encoding one, which is how MessagePack reports
+    // a failed write. Other errors are left as they are, since callers match on them and
+    // they say what went wrong already.
+    pub(crate) fn with_context(self, context: impl FnOnce() -> ErrorContext) -> Self {
+        match self {
+            Self::IO(_) | Self::Encode(_) => Self::Context(Box::new(ContextError {
+                context: context(),
+                source: self,
+                backtrace: Backtrace::capture(),
+            })),
+            err => err,
+        }
+    }
+}
+
+/// Adds context to the I/O error of a result, as `KvsError::with_context` does.
+pub(crate) trait ResultExt<T> {
+    fn context(self, context: impl FnOnce() -> ErrorContext) -> Result<T>;
+}
+
+impl<T, E: Into<KvsError>> ResultExt<T> for result::Result<T, E> {
+    fn context(self, context: impl FnOnce() -> ErrorContext) -> Result<T> {
+        self.map_err(|err| err.into().with_context(context))
+    }
 }
 
 impl fmt::Display for KvsError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::Corruption {
+                log_number,
+                offset,
+                kind,
+            } => write!(
+                f,
+                "Corruption: {:?} record in log {} at offset {}",
+                kind, log_number, offset
+            ),
+            Self::UnsupportedLogFormat {
+                log_number,
+                version,
+                written_by,
+            } => write!(
+                f,
+                "Log {} is in format {}, written by kvs {}; this build reads up to format {}",
+                log_number,
+                version,
+                written_by,
+                crate::engines::LOG_FORMAT_VERSION
+            ),
             Self::Encode(err) => write!(f, "Encode: {}", err),
             Self::Decode(err) => write!(f, "Decode: {}", err),
             Self::IO(err) => write!(f, "IO: {}", err),
             Self::KeyNotFound => write!(f, "Key not found"),
+            Self::NotAnInteger => write!(f, "Value is not an integer"),
+            Self::NotALock => write!(f, "Value is not a lock"),
+            Self::IntegerOverflow => write!(f, "Increment would overflow"),
+            Self::NotLeader(Some(leader)) => write!(f, "Not leader; the leader is {}", leader),
+            Self::NotLeader(None) => write!(f, "Not leader; no leader is elected"),
+            Self::Moved(owner) => write!(f, "Moved; the key is owned by {}", owner),
             Self::UnexpectedCommand => write!(f, "UnexpectedCommand"),
             Self::UnexpectedResponse => write!(f, "UnexpectedResponse"),
             Self::StringError(msg) => write!(f, "{}", msg),
+            Self::TransactionConflict => write!(f, "Transaction conflict"),
+            Self::TransportCorruption => write!(f, "Transport corruption"),
+            Self::ProtocolMismatch { client, server } => write!(
+                f,
+                "Protocol mismatch: the client speaks version {}, the server version {}",
+                client, server
+            ),
+            Self::Unsupported(capabilities) => {
+                write!(f, "Not supported by the server: {}", capabilities)
+            }
+            Self::ServerBusy => write!(f, "Server busy; try again later"),
+            Self::Throttled(retry_after) => write!(
+                f,
+                "Server is compacting; try again in {}ms",
+                retry_after.as_millis()
+            ),
+            Self::RateLimited => write!(f, "Rate limit exceeded; try again later"),
+            Self::WriteStalled { dead_bytes, limit } => write!(
+                f,
+                "Writes stalled: {} bytes await compaction, over the limit of {}",
+                dead_bytes, limit
+            ),
+            Self::TooLarge(msg) => write!(f, "{}", msg),
+            Self::ReadOnly => write!(f, "Store is open read-only"),
+            Self::PermissionDenied(msg) => write!(f, "Permission denied: {}", msg),
+            Self::AlreadyLocked { pid: Some(pid) } => {
+                write!(f, "Data directory is locked by process {}", pid)
+            }
+            Self::AlreadyLocked { pid: None } => {
+                write!(f, "Data directory is locked by another process")
+            }
+            Self::StoreNotFound(path) => write!(f, "No store in {}", path.display()),
+            Self::StoreExists(path) => write!(f, "A store already exists in {}", path.display()),
             Self::Sled(err) => write!(f, "Sled: {}", err),
+            #[cfg(feature = "rocksdb")]
+            Self::Rocks(err) => write!(f, "RocksDB: {}", err),
             Self::Utf8(err) => write!(f, "Utf8: {}", err),
+            Self::Context(err) => write!(f, "{}: {}", err.context, err.source),
         }
     }
 }
@@ -38,15 +303,39 @@ impl fmt::Display for KvsError {
 impl error::Error for KvsError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
+            Self::Corruption { .. } => None,
+            Self::UnsupportedLogFormat { .. } => None,
             Self::Decode(source) => Some(source),
             Self::Encode(source) => Some(source),
             Self::IO(source) => Some(source),
             Self::KeyNotFound => None,
+            Self::NotAnInteger => None,
+            Self::NotALock => None,
+            Self::IntegerOverflow => None,
+            Self::NotLeader(_) => None,
+            Self::Moved(_) => None,
             Self::UnexpectedCommand => None,
             Self::UnexpectedResponse => None,
             Self::StringError(_) => None,
+            Self::TransactionConflict => None,
+            Self::TransportCorruption => None,
+            Self::ProtocolMismatch { .. } => None,
+            Self::Unsupported(_) => None,
+            Self::ServerBusy => None,
+            Self::Throttled(_) => None,
+            Self::RateLimited => None,
+            Self::WriteStalled { .. } => None,
+            Self::TooLarge(_) => None,
+            Self::ReadOnly => None,
+            Self::PermissionDenied(_) => None,
+            Self::AlreadyLocked { .. } => None,
+            Self::StoreNotFound(_) => None,
+            Self::StoreExists(_) => None,
             Self::Sled(source) => Some(source),
+            #[cfg(feature = "rocksdb")]
+            Self::Rocks(source) => Some(source),
             Self::Utf8(source) => Some(source),
+            Self::Context(err) => Some(&err.source),
         }
     }
 }
@@ -75,6 +364,13 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+#[cfg(feature = "rocksdb")]
+impl From<rocksdb::Error> for KvsError {
+    fn from(e: rocksdb::Error) -> Self {
+        Self::Rocks(e)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(e: FromUtf8Error) -> Self {
         Self::Utf8(e)
@@ -0,0 +1,55 @@
+//! Fault injection for testing how the store copes with writes cut short.
+//!
+//! Not part of the crate's API: it's public only so integration tests can reach it, and may
+//! change or go away in any release.
+
+use std::io;
+use std::io::Write;
+
+/// A writer that passes writes through until a budget of bytes runs out, then fails
+/// every write as a crashed process or a full disk would.
+///
+/// The write that crosses the budget is torn: the bytes that fit are written, and the
+/// write fails.
+pub struct FaultyWriter<W> {
+    inner: W,
+    budget: u64,
+}
+
+impl<W: Write> FaultyWriter<W> {
+    /// Wrap `inner`, letting through at most `budget` bytes.
+    pub fn new(inner: W, budget: u64) -> Self {
+        Self { inner, budget }
+    }
+
+    /// Bytes left before writes start failing.
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for FaultyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.budget == 0 {
+            return Err(io::Error::other("injected write fault"));
+        }
+        let fits = buf.len().min(self.budget as usize);
+        self.inner.write_all(&buf[..fits])?;
+        self.budget -= fits as u64;
+        if fits < buf.len() {
+            return Err(io::Error::other("injected write fault"));
+        }
+        Ok(fits)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,166 @@
+//! Checksummed framing of protocol messages.
+//!
+//! A client that wants its connection checksummed asks for `Capabilities::CHECKSUMS` in its
+//! handshake. From then on every message in either direction is sent as a frame: its
+//! length and CRC-32 as big-endian `u32`s, then the encoding of the message in the
+//! connection's `WireFormat`.
+//!
+//! That is msgpack unless the client asked for `Capabilities::JSON` or
+//! `Capabilities::BINCODE`, and a connection in either of those is framed whether it asked
+//! for checksums or not, since neither delimits its messages as msgpack does. In JSON a
+//! request or response is an object naming its variant, such as `{"Get":"key"}` or
+//! `{"Set":["key","value"]}`, or just the name of one without fields, such as `"Len"`.
+
+use crate::handshake::Capabilities;
+use crate::KvsError;
+use crate::Result;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::io::Read;
+use std::io::Write;
+use std::str::FromStr;
+
+// A corrupt length could otherwise make the reader allocate without bound.
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+// A frame's length and checksum come before its payload.
+pub(crate) const HEADER_LEN: usize = 8;
+
+/// The encoding of protocol messages; see `KvsClientBuilder::wire_format`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    MessagePack,
+    /// JSON, for clients without a good msgpack library.
+    Json,
+    /// Bincode, with integers as varints.
+    Bincode,
+}
+
+impl WireFormat {
+    /// The capability a client asks for to speak the format, if it isn't msgpack.
+    pub fn capability(self) -> Option<Capabilities> {
+        match self {
+            Self::MessagePack => None,
+            Self::Json => Some(Capabilities::JSON),
+            Self::Bincode => Some(Capabilities::BINCODE),
+        }
+    }
+
+    fn encode<T: Serialize>(self, message: &T) -> Result<Vec<u8>> {
+        match self {
+            Self::MessagePack => Ok(rmp_serde::to_vec(message)?),
+            Self::Json => serde_json::to_vec(message)
+                .map_err(|err| KvsError::StringError(format!("JSON: {}", err))),
+            Self::Bincode => bincode::DefaultOptions::new()
+                .serialize(message)
+                .map_err(|err| KvsError::StringError(format!("Bincode: {}", err))),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T> {
+        match self {
+            Self::MessagePack => Ok(rmp_serde::from_slice(payload)?),
+            Self::Json => serde_json::from_slice(payload)
+                .map_err(|err| KvsError::StringError(format!("JSON: {}", err))),
+            Self::Bincode => bincode::DefaultOptions::new()
+                .deserialize(payload)
+                .map_err(|err| KvsError::StringError(format!("Bincode: {}", err))),
+        }
+    }
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MessagePack => write!(f, "msgpack"),
+            Self::Json => write!(f, "json"),
+            Self::Bincode => write!(f, "bincode"),
+        }
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "msgpack" => Ok(Self::MessagePack),
+            "json" => Ok(Self::Json),
+            "bincode" => Ok(Self::Bincode),
+            _ => Err(KvsError::StringError(format!(
+                "invalid wire format {:?}; expected msgpack, json or bincode",
+                s
+            ))),
+        }
+    }
+}
+
+/// How messages are sent on a connection whose client asked for `capabilities`: None for
+/// msgpack back to back, or the format of its frames. A client asking for both JSON and
+/// bincode gets JSON.
+pub(crate) fn framing(capabilities: Capabilities) -> Option<WireFormat> {
+    if capabilities.contains(Capabilities::JSON) {
+        Some(WireFormat::Json)
+    } else if capabilities.contains(Capabilities::BINCODE) {
+        Some(WireFormat::Bincode)
+    } else if capabilities.contains(Capabilities::CHECKSUMS) {
+        Some(WireFormat::MessagePack)
+    } else {
+        None
+    }
+}
+
+/// Encode `message` in `format` as a whole frame, header and all.
+pub(crate) fn encode<T: Serialize>(format: WireFormat, message: &T) -> Result<Vec<u8>> {
+    let payload = format.encode(message)?;
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+pub(crate) fn write<T: Serialize>(
+    writer: &mut impl Write,
+    format: WireFormat,
+    message: &T,
+) -> Result<()> {
+    writer.write_all(&encode(format, message)?)?;
+    Ok(())
+}
+
+/// Read a frame. Return `KvsError::TransportCorruption` if its checksum doesn't match.
+pub(crate) fn read<T: DeserializeOwned>(reader: &mut impl Read, format: WireFormat) -> Result<T> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let (len, checksum) = parse_header(header)?;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    decode_payload(&payload, checksum, format)
+}
+
+/// Return the length of the payload a frame header announces, and its checksum.
+pub(crate) fn parse_header(header: [u8; HEADER_LEN]) -> Result<(usize, u32)> {
+    let len = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let checksum = u32::from_be_bytes(header[4..].try_into().unwrap());
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::TransportCorruption);
+    }
+    Ok((len as usize, checksum))
+}
+
+/// Decode the `format` payload of a frame, checking it against the checksum from its
+/// header.
+pub(crate) fn decode_payload<T: DeserializeOwned>(
+    payload: &[u8],
+    checksum: u32,
+    format: WireFormat,
+) -> Result<T> {
+    if crc32fast::hash(payload) != checksum {
+        return Err(KvsError::TransportCorruption);
+    }
+    format.decode(payload)
+}
@@ -0,0 +1,76 @@
+//! Entry points for the fuzz targets in `fuzz/`, which feed them arbitrary bytes.
+//!
+//! Each decodes the bytes as the server or a store would and returns what it made of
+//! them, or why it couldn't. None may panic, whatever the bytes: a client or a damaged log
+//! must only ever get an error back.
+
+use crate::engines;
+use crate::frame;
+use crate::handshake::Capabilities;
+use crate::handshake::Hello;
+use crate::handshake::MAGIC;
+use crate::http;
+use crate::resp;
+use crate::server;
+use crate::KvsError;
+use crate::Request;
+use crate::Result;
+use crate::SizeLimits;
+use rmp_serde::decode;
+use std::io;
+use std::path::Path;
+
+/// Decode `bytes` as what a client sends a kvs-protocol server: a hello, or none from a
+/// client older than the handshake, and then requests, each within `budget` bytes if given.
+/// Return the requests, up to the end of the bytes.
+pub fn decode_requests(bytes: &[u8], budget: Option<u64>) -> Result<Vec<Request>> {
+    let mut reader = server::request_reader(bytes, budget);
+    let wanted = match bytes.first() {
+        Some(&byte) if byte == MAGIC[0] => Hello::read(reader.get_mut())?.capabilities,
+        Some(_) => Capabilities::empty(),
+        None => return Ok(Vec::new()),
+    };
+    let framing = frame::framing(wanted);
+    let mut requests = Vec::new();
+    loop {
+        match server::read_request(&mut reader, framing) {
+            Ok(request) => requests.push(request),
+            Err(KvsError::IO(err))
+            | Err(KvsError::Decode(decode::Error::InvalidMarkerRead(err)))
+                if err.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                return Ok(requests);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Decode `bytes` as the commands a client sends a RESP server, up to the end of the bytes.
+pub fn decode_resp_commands(bytes: &[u8]) -> Result<Vec<Vec<Vec<u8>>>> {
+    let mut reader = bytes;
+    let mut commands = Vec::new();
+    let max_bulk_len = resp::max_bulk_len(SizeLimits::default());
+    while let Some(command) = resp::read_command(&mut reader, max_bulk_len)? {
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+/// Decode `bytes` as the requests a client sends the HTTP gateway, up to the end of the
+/// bytes, and return their methods and paths.
+pub fn decode_http_requests(bytes: &[u8]) -> Result<Vec<(String, String)>> {
+    let mut reader = bytes;
+    let mut requests = Vec::new();
+    let max_body_len = http::max_body_len(SizeLimits::default());
+    while let Some(request) = http::read_request(&mut reader, &mut io::sink(), max_body_len)? {
+        requests.push((request.method, request.path));
+    }
+    Ok(requests)
+}
+
+/// Index the log at `path` as a store does on opening it, and read back the value of
+/// every key it sets. Return how many keys that is.
+pub fn load_index(path: &Path) -> Result<usize> {
+    engines::index_log(path)
+}
@@ -0,0 +1,247 @@
+//! The gRPC service of proto/kvs.proto, served by `KvsServer::with_grpc_addr` for clients
+//! that would rather not speak the kvs protocol. Enabled by the `grpc` feature.
+//!
+//! Each call is made into the request of the kvs protocol it amounts to, and served as the
+//! other protocols' are, so raft, batching, size limits and metrics apply to it alike.
+//! Calls are served on tokio's blocking threads, since engines block.
+
+use crate::protocol::ErrorCode;
+use crate::protocol::Request;
+use crate::protocol::Response;
+use crate::sync::Mutex;
+use crate::KvsError;
+use crate::Result;
+use slog::error;
+use slog::info;
+use slog::Logger;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::thread;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
+/// The messages and the generated client and server of proto/kvs.proto.
+pub mod proto {
+    tonic::include_proto!("kvs");
+}
+
+use proto::kvs_server::Kvs;
+use proto::kvs_server::KvsServer;
+use proto::GetRequest;
+use proto::GetResponse;
+use proto::Pair;
+use proto::RemoveRequest;
+use proto::RemoveResponse;
+use proto::ScanRequest;
+use proto::SetRequest;
+use proto::SetResponse;
+
+// Keys read at a time by a scan, and pairs it may have sent that the client hasn't read.
+const SCAN_PAGE: u32 = 100;
+const SCAN_BUFFER: usize = 4;
+
+/// A gRPC listener, serving on a thread of its own until stopped.
+pub(crate) struct GrpcListener {
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl GrpcListener {
+    /// Start serving the service on `addr`, making each call into the requests `call`
+    /// serves.
+    pub fn start<C>(addr: SocketAddr, call: C, log: Logger) -> Result<Self>
+    where
+        C: Fn(Request) -> Response + Clone + Send + 'static,
+    {
+        // Bound here, so a taken address fails the server's start rather than the thread.
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let (stop, stopped) = oneshot::channel();
+        info!(&log, "serving grpc"; "ip-port" => addr.to_string());
+        thread::Builder::new().spawn(move || {
+            let served = runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let service = KvsServer::new(Service {
+                    call: Mutex::new(call),
+                });
+                tonic::transport::Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming_shutdown(
+                        tokio_stream::wrappers::TcpListenerStream::new(listener),
+                        async {
+                            let _ = stopped.await;
+                        },
+                    )
+                    .await
+                    .map_err(|err| KvsError::StringError(err.to_string()))
+            });
+            if let Err(err) = served {
+                error!(&log, "grpc listener failed with error {}", err);
+            }
+        })?;
+        Ok(Self { stop: Some(stop) })
+    }
+
+    /// Stop serving, letting calls under way finish.
+    pub fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+struct Service<C> {
+    // Held only to clone, since what a call is served with needn't be Sync.
+    call: Mutex<C>,
+}
+
+impl<C> Service<C>
+where
+    C: Fn(Request) -> Response + Clone + Send + 'static,
+{
+    // Serve `request` on a blocking thread.
+    async fn serve(&self, request: Request) -> std::result::Result<Response, Status> {
+        let call = self.call.lock().clone();
+        tokio::task::spawn_blocking(move || call(request))
+            .await
+            .map_err(|err| Status::internal(err.to_string()))
+    }
+}
+
+#[tonic::async_trait]
+impl<C> Kvs for Service<C>
+where
+    C: Fn(Request) -> Response + Clone + Send + 'static,
+{
+    async fn get(
+        &self,
+        request: tonic::Request<GetRequest>,
+    ) -> std::result::Result<tonic::Response<GetResponse>, Status> {
+        match self.serve(Request::Get(request.into_inner().key)).await? {
+            Response::GetOk(value) => Ok(tonic::Response::new(GetResponse { value })),
+            response => Err(status(response)),
+        }
+    }
+
+    async fn set(
+        &self,
+        request: tonic::Request<SetRequest>,
+    ) -> std::result::Result<tonic::Response<SetResponse>, Status> {
+        let SetRequest { key, value } = request.into_inner();
+        match self.serve(Request::Set(key, value)).await? {
+            Response::SetOk(()) => Ok(tonic::Response::new(SetResponse {})),
+            response => Err(status(response)),
+        }
+    }
+
+    async fn remove(
+        &self,
+        request: tonic::Request<RemoveRequest>,
+    ) -> std::result::Result<tonic::Response<RemoveResponse>, Status> {
+        match self
+            .serve(Request::Remove(request.into_inner().key))
+            .await?
+        {
+            Response::RemoveOk(()) => Ok(tonic::Response::new(RemoveResponse {})),
+            response => Err(status(response)),
+        }
+    }
+
+    type ScanStream = ReceiverStream<std::result::Result<Pair, Status>>;
+
+    async fn scan(
+        &self,
+        request: tonic::Request<ScanRequest>,
+    ) -> std::result::Result<tonic::Response<Self::ScanStream>, Status> {
+        let prefix = request.into_inner().prefix;
+        let call = self.call.lock().clone();
+        let (sender, receiver) = mpsc::channel(SCAN_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            if let Err(response) = scan(&call, &prefix, &sender) {
+                let _ = sender.blocking_send(Err(status(response)));
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+// Send the pairs whose keys start with `prefix`, in key order, a page at a time, until
+// they run out or the client goes away. A key removed between reading its page and its
+// value is skipped. Fail with the response to a request that failed.
+fn scan<C>(
+    call: &C,
+    prefix: &str,
+    sender: &mpsc::Sender<std::result::Result<Pair, Status>>,
+) -> std::result::Result<(), Response>
+where
+    C: Fn(Request) -> Response,
+{
+    // The prefix itself comes first of the keys starting with it, then those after it.
+    let mut keys = if prefix.is_empty() {
+        Vec::new()
+    } else {
+        vec![prefix.to_owned()]
+    };
+    let mut cursor = keys.first().cloned();
+    let mut more = true;
+    loop {
+        if keys.is_empty() && more {
+            (keys, cursor) = match call(Request::Keys {
+                cursor: cursor.clone(),
+                limit: SCAN_PAGE,
+            }) {
+                Response::KeysOk { keys, cursor } => (keys, cursor),
+                response => return Err(response),
+            };
+            more = cursor.is_some();
+        }
+        let end = keys
+            .iter()
+            .position(|key| !key.starts_with(prefix))
+            .unwrap_or(keys.len());
+        let done = end < keys.len() || !more;
+        keys.truncate(end);
+        if !keys.is_empty() {
+            let values = match call(Request::MultiGet(keys.clone())) {
+                Response::MultiGetOk(values) => values,
+                response => return Err(response),
+            };
+            for (key, value) in keys.drain(..).zip(values) {
+                if let Some(value) = value {
+                    if sender.blocking_send(Ok(Pair { key, value })).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+// The status of a call the server answered with `response` rather than success.
+fn status(response: Response) -> Status {
+    match response {
+        Response::Err(ErrorCode::KeyNotFound, msg) => Status::not_found(msg),
+        Response::Err(ErrorCode::NotAnInteger | ErrorCode::NotALock, msg) => {
+            Status::failed_precondition(msg)
+        }
+        Response::Err(ErrorCode::TooLarge, msg) => Status::invalid_argument(msg),
+        Response::Err(ErrorCode::Busy | ErrorCode::RateLimited, msg) => {
+            Status::resource_exhausted(msg)
+        }
+        Response::Err(ErrorCode::ReadOnly | ErrorCode::PermissionDenied, msg) => {
+            Status::permission_denied(msg)
+        }
+        Response::Err(ErrorCode::TransactionConflict, msg) => Status::aborted(msg),
+        Response::Err(_, msg) => Status::internal(msg),
+        Response::NotLeader(leader) => Status::unavailable(KvsError::NotLeader(leader).to_string()),
+        Response::Moved(owner) => Status::failed_precondition(KvsError::Moved(owner).to_string()),
+        _ => Status::internal("unexpected response"),
+    }
+}
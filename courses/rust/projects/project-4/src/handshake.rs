@@ -0,0 +1,177 @@
+//! The hellos a client and server exchange when a connection opens.
+//!
+//! Each side sends `MAGIC`, its protocol version as a big-endian `u16` and its
+//! capabilities as a big-endian `u64`. The client's capabilities are those it wants, the
+//! server's those it offers. Versions must match: a server sends its hello either way, and
+//! hangs up if they don't, so the client can say which versions disagreed. Within a
+//! version, requests added later are offered as capabilities, so a client can tell that a
+//! server lacks one before sending it.
+//!
+//! `MAGIC` starts with a byte msgpack never starts a message with, so a server can still
+//! serve clients that predate the handshake and send requests straight away.
+
+use crate::KvsError;
+use crate::Result;
+use std::fmt;
+use std::io::Read;
+use std::io::Write;
+use std::ops::BitOr;
+
+/// Version of the protocol this build speaks.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+pub(crate) const MAGIC: [u8; 4] = [0xc1, b'K', b'V', b'S'];
+
+/// A set of optional protocol features.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Messages after the handshake are sent in checksummed frames.
+    pub const CHECKSUMS: Self = Self(1);
+    /// `incr` requests are served. Replicated servers don't serve them.
+    pub const INCR: Self = Self(1 << 1);
+    /// `compact` requests are served.
+    pub const COMPACT: Self = Self(1 << 2);
+    /// Requests can be made in a bucket.
+    pub const BUCKETS: Self = Self(1 << 3);
+    /// Requests can be pipelined, and are answered as they're served.
+    pub const PIPELINING: Self = Self(1 << 4);
+    /// Connections can subscribe to changes of keys.
+    pub const WATCH: Self = Self(1 << 5);
+    /// `multi_get` requests are served.
+    pub const MULTI_GET: Self = Self(1 << 6);
+    /// Messages after the handshake are JSON, in frames; see `WireFormat`.
+    pub const JSON: Self = Self(1 << 7);
+    /// Messages after the handshake are bincode, in frames; see `WireFormat`.
+    pub const BINCODE: Self = Self(1 << 8);
+    /// `keys` requests are served.
+    pub const KEYS: Self = Self(1 << 9);
+    /// Writes may be answered with `Response::Busy` while the engine compacts.
+    pub const BACKPRESSURE: Self = Self(1 << 10);
+    /// `ping` requests are served.
+    pub const PING: Self = Self(1 << 11);
+    /// `get_with_meta` and `stat` requests are served.
+    pub const VERSIONS: Self = Self(1 << 12);
+    /// `rename` requests are served. Replicated servers don't serve them.
+    pub const RENAME: Self = Self(1 << 13);
+    /// `append` requests are served. Replicated servers don't serve them.
+    pub const APPEND: Self = Self(1 << 14);
+    /// `get_range` requests are served.
+    pub const GET_RANGE: Self = Self(1 << 15);
+    /// `find_by_value` requests are served.
+    pub const FIND_BY_VALUE: Self = Self(1 << 16);
+    /// `wait_get` requests are served.
+    pub const WAIT_GET: Self = Self(1 << 17);
+    /// `acquire_lock` and `release_lock` requests are served. Replicated servers don't
+    /// serve them.
+    pub const LOCKS: Self = Self(1 << 18);
+    /// Requests may carry a trace id; see `Request::Traced`.
+    pub const TRACING: Self = Self(1 << 19);
+    /// `gossip` and `cluster_map` requests are served, and requests may be pinned to the
+    /// server; see `kvs::cluster`.
+    pub const CLUSTER: Self = Self(1 << 20);
+    /// `authenticate` requests are served; see `kvs::acl`.
+    pub const AUTH: Self = Self(1 << 21);
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Return whether every capability in `other` is in `self`.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Self::CHECKSUMS, "checksums"),
+            (Self::INCR, "incr"),
+            (Self::COMPACT, "compact"),
+            (Self::BUCKETS, "buckets"),
+            (Self::PIPELINING, "pipelining"),
+            (Self::WATCH, "watch"),
+            (Self::MULTI_GET, "multi_get"),
+            (Self::JSON, "json"),
+            (Self::BINCODE, "bincode"),
+            (Self::KEYS, "keys"),
+            (Self::BACKPRESSURE, "backpressure"),
+            (Self::PING, "ping"),
+            (Self::VERSIONS, "versions"),
+            (Self::RENAME, "rename"),
+            (Self::APPEND, "append"),
+            (Self::GET_RANGE, "get_range"),
+            (Self::FIND_BY_VALUE, "find_by_value"),
+            (Self::WAIT_GET, "wait_get"),
+            (Self::LOCKS, "locks"),
+            (Self::TRACING, "tracing"),
+            (Self::CLUSTER, "cluster"),
+            (Self::AUTH, "auth"),
+        ];
+        let mut first = true;
+        for (capability, name) in names {
+            if self.contains(capability) {
+                write!(f, "{}{}", if first { "" } else { "," }, name)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Hello {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&self.version.to_be_bytes())?;
+        writer.write_all(&self.capabilities.bits().to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Read a hello. Return an error if the peer sent something else.
+    pub fn read(reader: &mut impl Read) -> Result<Self> {
+        let mut hello = [0; 14];
+        reader.read_exact(&mut hello)?;
+        if hello[..4] != MAGIC {
+            return Err(KvsError::StringError(
+                "peer didn't answer the kvs handshake; it may predate protocol versions".to_owned(),
+            ));
+        }
+        Ok(Self {
+            version: u16::from_be_bytes(hello[4..6].try_into().unwrap()),
+            capabilities: Capabilities::from_bits(u64::from_be_bytes(
+                hello[6..].try_into().unwrap(),
+            )),
+        })
+    }
+}
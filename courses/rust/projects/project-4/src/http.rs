@@ -0,0 +1,261 @@
+//! The bits of HTTP/1.1 the HTTP gateway of `KvsServer::with_http_addr` needs.
+//!
+//! Connections are kept alive unless the client asks otherwise. Bodies must come with a
+//! `Content-Length`; chunked ones aren't supported.
+
+use crate::KvsError;
+use crate::Result;
+use crate::SizeLimits;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+
+// Bounds on what a client can make the reader allocate. Bodies grow as bytes arrive, so
+// a Content-Length alone doesn't make it allocate anything.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+
+/// The longest body worth reading under `limits`: bodies are values, and can't be longer.
+pub(crate) fn max_body_len(limits: SizeLimits) -> usize {
+    limits
+        .max_value_bytes
+        .unwrap_or(MAX_BODY_LEN)
+        .min(MAX_BODY_LEN)
+}
+
+pub(crate) struct HttpRequest {
+    pub method: String,
+    /// The path, percent-decoded.
+    pub path: String,
+    /// The query's parameters, percent-decoded.
+    pub query: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub keep_alive: bool,
+}
+
+impl HttpRequest {
+    /// Return the value of the query parameter `name`, if given.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub(crate) struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    /// A plain text response, ending the text with a line break as curl users expect.
+    pub fn text(status: u16, text: impl Into<String>) -> Self {
+        let mut text = text.into();
+        text.push('\n');
+        Self::new(status, "text/plain; charset=utf-8", text)
+    }
+
+    pub fn no_content() -> Self {
+        Self::new(204, "text/plain; charset=utf-8", Vec::new())
+    }
+
+    pub fn write(&self, writer: &mut impl Write, keep_alive: bool) -> Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len(),
+            if keep_alive { "keep-alive" } else { "close" }
+        )?;
+        writer.write_all(&self.body)?;
+        Ok(())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        421 => "Misdirected Request",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Read a request. Return `None` if the client hung up between requests,
+/// `KvsError::StringError` if the request is malformed, and `KvsError::TooLarge` if its
+/// body is over `max_body_len`, after which nothing the client sends can be trusted to
+/// line up.
+///
+/// `writer` is used to tell a client waiting on `Expect: 100-continue` to send its body.
+pub(crate) fn read_request(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    max_body_len: usize,
+) -> Result<Option<HttpRequest>> {
+    let line = match read_line(reader)? {
+        Some(line) if line.is_empty() => {
+            // A stray line break between requests is allowed before the request line.
+            match read_line(reader)? {
+                Some(line) => line,
+                None => return Ok(None),
+            }
+        }
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request("malformed request line"));
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(bad_request("unsupported HTTP version")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = percent_decode(path, false)?;
+    let mut params = Vec::new();
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        params.push((percent_decode(name, true)?, percent_decode(value, true)?));
+    }
+
+    let mut content_len = 0;
+    let mut expect_continue = false;
+    for headers in 0.. {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers == MAX_HEADERS {
+            return Err(bad_request("too many headers"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request("malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_len = value
+                    .parse()
+                    .ok()
+                    .filter(|&len| len <= MAX_BODY_LEN)
+                    .ok_or_else(|| bad_request("invalid Content-Length"))?;
+            }
+            "transfer-encoding" => {
+                return Err(bad_request(
+                    "chunked bodies aren't supported; send a Content-Length",
+                ))
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            "expect" if value.eq_ignore_ascii_case("100-continue") => expect_continue = true,
+            _ => {}
+        }
+    }
+
+    if content_len > max_body_len {
+        return Err(KvsError::TooLarge(format!(
+            "value of {} bytes is over the limit of {} bytes",
+            content_len, max_body_len
+        )));
+    }
+    if expect_continue && content_len > 0 {
+        write!(writer, "HTTP/1.1 100 {}\r\n\r\n", reason(100))?;
+        writer.flush()?;
+    }
+    let mut body = Vec::new();
+    reader
+        .by_ref()
+        .take(content_len as u64)
+        .read_to_end(&mut body)?;
+    if body.len() < content_len {
+        return Err(unexpected_eof());
+    }
+    Ok(Some(HttpRequest {
+        method: method.to_owned(),
+        path,
+        query: params,
+        body,
+        keep_alive,
+    }))
+}
+
+// Read a line, without its line break. Return `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(if line.len() as u64 + 1 >= MAX_LINE_LEN {
+            bad_request("line too long")
+        } else {
+            unexpected_eof()
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| bad_request("request line and headers must be UTF-8"))
+}
+
+// Decode `%XX` escapes, and in a query, `+` for a space.
+fn percent_decode(s: &str, query: bool) -> Result<String> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next(), bytes.next()];
+                let escaped = match hex {
+                    [Some(high), Some(low)] => std::str::from_utf8(&[high, low])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                    _ => None,
+                };
+                decoded.push(escaped.ok_or_else(|| bad_request("malformed percent-encoding"))?);
+            }
+            b'+' if query => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| bad_request("decoded URLs must be UTF-8"))
+}
+
+fn bad_request(msg: &str) -> KvsError {
+    KvsError::StringError(msg.to_owned())
+}
+
+fn unexpected_eof() -> KvsError {
+    std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()
+}
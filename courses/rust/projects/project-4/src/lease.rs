@@ -0,0 +1,75 @@
+use crate::sync::Mutex;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+struct Lease {
+    id: u64,
+    expires: Instant,
+}
+
+/// Time-limited, exclusive claims on keys, kept by the server on behalf of its clients.
+///
+/// Lease ids increase with every grant, so they can be used as fencing tokens: a
+/// holder whose lease expired always carries a smaller id than the next holder.
+/// Leases live in memory only, so a server restart releases all of them.
+#[derive(Default)]
+pub(crate) struct Leases {
+    inner: Mutex<LeasesInner>,
+}
+
+#[derive(Default)]
+struct LeasesInner {
+    leases: HashMap<String, Lease>,
+    last_id: u64,
+}
+
+impl Leases {
+    /// Grant a lease on `key` for `ttl`, unless another unexpired lease holds it.
+    pub fn acquire(&self, key: String, ttl: Duration) -> Option<u64> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+        // Expired leases are only dropped here, which keeps every other call O(1).
+        inner.leases.retain(|_, lease| lease.expires > now);
+        if inner.leases.contains_key(&key) {
+            return None;
+        }
+        inner.last_id += 1;
+        let id = inner.last_id;
+        inner.leases.insert(
+            key,
+            Lease {
+                id,
+                expires: now + ttl,
+            },
+        );
+        Some(id)
+    }
+
+    /// Extend the lease `id` on `key` to expire `ttl` from now. Return false if it is no longer held.
+    pub fn renew(&self, key: &str, id: u64, ttl: Duration) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+        match inner.leases.get_mut(key) {
+            Some(lease) if lease.id == id && lease.expires > now => {
+                lease.expires = now + ttl;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Give up the lease `id` on `key`. Return false if it was no longer held.
+    pub fn release(&self, key: &str, id: u64) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock();
+        match inner.leases.get(key) {
+            Some(lease) if lease.id == id => {
+                let held = lease.expires > now;
+                inner.leases.remove(key);
+                held
+            }
+            _ => false,
+        }
+    }
+}
@@ -1,18 +1,140 @@
 mod engines;
+pub use engines::open_registered;
+pub use engines::register_engine;
+pub use engines::registered_engines;
+pub use engines::BoxedEngine;
+pub use engines::Bucket;
+pub use engines::Codec;
+pub use engines::CompactionState;
+pub use engines::Durability;
+pub use engines::EngineGauges;
+pub use engines::EngineMetrics;
+pub use engines::EntryMeta;
+pub use engines::FsckReport;
+pub use engines::KeyGuard;
 pub use engines::KvStore;
 pub use engines::KvsEngine;
+pub use engines::LogReport;
+pub use engines::OpenOptions;
+pub use engines::Revision;
+#[cfg(feature = "rocksdb")]
+pub use engines::RocksKvsEngine;
+pub use engines::SizeLimits;
 pub use engines::SledKvsEngine;
+pub use engines::StoreStats;
+pub use engines::TombstoneHorizon;
+pub use engines::TornTail;
+pub use engines::Traced;
+pub use engines::Txn;
+pub use engines::WriteStall;
+pub use engines::BUILTIN_ENGINES;
 
 mod error;
+pub use error::ContextError;
+pub use error::CorruptionKind;
+pub use error::ErrorContext;
 pub use error::KvsError;
 pub use error::Result;
 
+pub mod acl;
+
+mod batch;
+
+#[cfg(feature = "async")]
+pub mod async_client;
+
+pub mod data_dir;
+
+mod dump;
+
+// Public only for tests/recovery.rs.
+#[doc(hidden)]
+pub mod fault;
+
+#[cfg(feature = "bench")]
+pub mod bench;
+
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub mod fuzz;
+
+mod frame;
+pub use frame::WireFormat;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+mod handshake;
+
+mod http;
+pub use handshake::Capabilities;
+pub use handshake::PROTOCOL_VERSION;
+
+pub mod cluster;
+
 mod client;
 pub use client::KvsClient;
+pub use client::KvsClientBuilder;
+pub use client::RetryPolicy;
+pub use client::Subscription;
+
+mod client_cache;
+
+mod lease;
+
+pub mod log_file;
+
+mod nesting;
+
+mod metrics;
+pub use metrics::ServerStats;
+pub use metrics::StatsFrame;
+
+pub mod ops;
 
 mod protocol;
+pub use protocol::ErrorCode;
+pub use protocol::Pong;
+pub use protocol::Request;
+pub use protocol::Response;
+
+pub mod raft;
+
+mod rate_limit;
+
+pub mod replication;
+
+mod resp;
+
+pub mod ring;
 
 mod server;
 pub use server::KvsServer;
+pub use server::Protocol;
+pub use server::ServerHandle;
+
+pub mod server_cli;
+
+mod sharded_client;
+pub use sharded_client::ShardedKvsClient;
+
+#[cfg(feature = "tracing")]
+mod span_log;
+#[cfg(feature = "tracing")]
+pub use span_log::SpanLog;
+
+mod sync;
 
 pub mod thread_pool;
+
+mod trace;
+pub use trace::TraceId;
+
+mod transport;
+
+mod watch;
+pub use watch::ChangeOp;
+pub use watch::KeyChange;
+
+#[cfg(feature = "bench")]
+pub mod workload;
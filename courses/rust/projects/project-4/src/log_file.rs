@@ -0,0 +1,129 @@
+//! A log file that rotates itself once it grows too large or too old.
+//!
+//! Rotating renames `<path>` to `<path>.1`, `<path>.1` to `<path>.2` and so on, dropping the
+//! oldest beyond the number kept, and starts a new `<path>`. Only whole lines are written
+//! to a file, so no line is split across two.
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+/// When a `LogFile` rotates, and how many rotated files it keeps. With neither limit set it
+/// never rotates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_bytes: None,
+            max_age: None,
+            keep: 5,
+        }
+    }
+}
+
+/// A file appended to, and rotated as its `Rotation` says.
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    bytes: u64,
+    opened: Instant,
+    // Whether the last byte written ended a line, so a rotation now splits none.
+    at_line_start: bool,
+}
+
+impl LogFile {
+    /// Open the log file at `path`, appending to what it already holds.
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let file = append(path)?;
+        let bytes = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            rotation,
+            file,
+            bytes,
+            opened: Instant::now(),
+            at_line_start: true,
+        })
+    }
+
+    /// The path of the `n`th most recently rotated file.
+    pub fn rotated_path(&self, n: usize) -> PathBuf {
+        rotated_path(&self.path, n)
+    }
+
+    // Whether to rotate before writing `incoming` bytes. A file with
+    // nothing in it yet takes them however many there are.
+    fn due(&self, incoming: usize) -> bool {
+        let too_large = self
+            .rotation
+            .max_bytes
+            .is_some_and(|max| self.bytes > 0 && self.bytes + incoming as u64 > max);
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max| self.opened.elapsed() >= max);
+        too_large || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.rotation.keep));
+            for n in (1..self.rotation.keep).rev() {
+                match fs::rename(self.rotated_path(n), self.rotated_path(n + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = append(&self.path)?;
+        self.bytes = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.at_line_start && self.due(buf.len()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.bytes += written as u64;
+        self.at_line_start = buf[..written].ends_with(b"\n");
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
@@ -0,0 +1,439 @@
+use crate::cluster::ClusterStatus;
+use crate::engines::CompactionState;
+use crate::engines::EngineGauges;
+use crate::engines::KvsEngine;
+use crate::error::Result;
+use crate::raft::RaftStatus;
+use crate::replication::FollowStatus;
+use crate::replication::ReplicaStatus;
+use crate::sync::Mutex;
+use serde::Deserialize;
+use serde::Serialize;
+use slog::error;
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+// Upper bounds (in microseconds) of the request latency histogram buckets.
+const LATENCY_BUCKETS_MICROS: [u64; 12] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 500_000, 1_000_000,
+];
+
+/// Counters shared by every connection handled by a `KvsServer`.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<String, u64>>,
+    errors: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_MICROS.len()],
+    latency_count: AtomicU64,
+    latency_sum_micros: AtomicU64,
+    active_connections: AtomicU64,
+    rejected_connections: AtomicU64,
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Record a connection turned away because the server was too busy.
+    pub fn connection_rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Record a completed request of the given type.
+    pub fn record_request(&self, name: &str, latency: Duration, failed: bool) {
+        {
+            let mut requests = self.requests.lock();
+            *requests.entry(name.to_owned()).or_insert(0) += 1;
+        }
+        if failed {
+            self.errors.fetch_add(1, Ordering::SeqCst);
+        }
+        let micros = latency.as_micros() as u64;
+        if let Some(bucket) = LATENCY_BUCKETS_MICROS
+            .iter()
+            .position(|&upper| micros <= upper)
+        {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::SeqCst);
+        }
+        self.latency_count.fetch_add(1, Ordering::SeqCst);
+        self.latency_sum_micros.fetch_add(micros, Ordering::SeqCst);
+    }
+
+    /// Take a consistent-enough copy of the counters, including engine figures.
+    pub fn snapshot<E: KvsEngine>(&self, engine: &E) -> Result<ServerStats> {
+        let gauges = engine.engine_metrics()?;
+        Ok(ServerStats {
+            requests: self.requests.lock().clone(),
+            errors: self.errors.load(Ordering::SeqCst),
+            latency_buckets: LATENCY_BUCKETS_MICROS
+                .iter()
+                .zip(self.latency_buckets.iter())
+                .map(|(&upper, count)| (upper, count.load(Ordering::SeqCst)))
+                .collect(),
+            latency_count: self.latency_count.load(Ordering::SeqCst),
+            latency_sum_micros: self.latency_sum_micros.load(Ordering::SeqCst),
+            active_connections: self.active_connections.load(Ordering::SeqCst),
+            rejected_connections: self.rejected_connections.load(Ordering::SeqCst),
+            compactions: engine.compactions(),
+            compaction: engine.compaction_state(),
+            engine_size: gauges.disk_bytes,
+            unsynced_bytes: engine.unsynced_bytes(),
+            engine: gauges,
+            raft: None,
+            replicas: None,
+            following: None,
+            cluster: None,
+        })
+    }
+}
+
+/// A point-in-time view of a server's metrics.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ServerStats {
+    /// Number of requests served, by request type.
+    pub requests: BTreeMap<String, u64>,
+    /// Number of requests that produced an error response.
+    pub errors: u64,
+    /// Request latency histogram as (upper bound in microseconds, count) pairs.
+    /// Requests slower than the last bound are only reflected in `latency_count`.
+    pub latency_buckets: Vec<(u64, u64)>,
+    pub latency_count: u64,
+    pub latency_sum_micros: u64,
+    pub active_connections: u64,
+    /// Number of connections turned away because every thread was busy and the queue full.
+    pub rejected_connections: u64,
+    pub compactions: u64,
+    /// What the engine's compaction is up to.
+    pub compaction: CompactionState,
+    /// Size of the engine's files on disk, in bytes.
+    pub engine_size: u64,
+    /// Bytes of writes the engine hasn't synced to disk yet; see
+    /// `KvsEngine::unsynced_bytes`.
+    pub unsynced_bytes: u64,
+    /// The engine's own gauges; see `EngineMetrics`.
+    pub engine: EngineGauges,
+    /// The server's view of its Raft group, if it is replicated.
+    pub raft: Option<RaftStatus>,
+    /// The replicas following the server, if it ships its changes to any that connect.
+    pub replicas: Option<Vec<ReplicaStatus>>,
+    /// The server's view of its primary, if it's a replica.
+    pub following: Option<FollowStatus>,
+    /// The server's view of its cluster, if it's a member of one.
+    pub cluster: Option<ClusterStatus>,
+}
+
+/// One piece of a `ServerStats`. A server sends its stats as a stream of these, so neither
+/// side has to encode a whole report as one message.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum StatsFrame {
+    /// Number of requests of the named type served.
+    Requests(String, u64),
+    Errors(u64),
+    /// One latency histogram bucket, as (upper bound in microseconds, count).
+    LatencyBucket(u64, u64),
+    Latency {
+        count: u64,
+        sum_micros: u64,
+    },
+    ActiveConnections(u64),
+    RejectedConnections(u64),
+    Compactions(u64),
+    Compaction(CompactionState),
+    EngineSize(u64),
+    UnsyncedBytes(u64),
+    Engine(EngineGauges),
+    Raft(RaftStatus),
+    Replicas(Vec<ReplicaStatus>),
+    Following(FollowStatus),
+    Cluster(ClusterStatus),
+}
+
+impl ServerStats {
+    /// Split the stats into frames, which `apply` puts back together.
+    pub fn into_frames(self) -> impl Iterator<Item = StatsFrame> {
+        let requests = self
+            .requests
+            .into_iter()
+            .map(|(name, count)| StatsFrame::Requests(name, count));
+        let latency_buckets = self
+            .latency_buckets
+            .into_iter()
+            .map(|(upper, count)| StatsFrame::LatencyBucket(upper, count));
+        let scalars = [
+            StatsFrame::Errors(self.errors),
+            StatsFrame::Latency {
+                count: self.latency_count,
+                sum_micros: self.latency_sum_micros,
+            },
+            StatsFrame::ActiveConnections(self.active_connections),
+            StatsFrame::RejectedConnections(self.rejected_connections),
+            StatsFrame::Compactions(self.compactions),
+            StatsFrame::Compaction(self.compaction),
+            StatsFrame::EngineSize(self.engine_size),
+            StatsFrame::UnsyncedBytes(self.unsynced_bytes),
+            StatsFrame::Engine(self.engine),
+        ];
+        requests
+            .chain(latency_buckets)
+            .chain(scalars)
+            .chain(self.raft.map(StatsFrame::Raft))
+            .chain(self.replicas.map(StatsFrame::Replicas))
+            .chain(self.following.map(StatsFrame::Following))
+            .chain(self.cluster.map(StatsFrame::Cluster))
+    }
+
+    /// Add a frame received from a server.
+    pub fn apply(&mut self, frame: StatsFrame) {
+        match frame {
+            StatsFrame::Requests(name, count) => {
+                self.requests.insert(name, count);
+            }
+            StatsFrame::Errors(errors) => self.errors = errors,
+            StatsFrame::LatencyBucket(upper, count) => self.latency_buckets.push((upper, count)),
+            StatsFrame::Latency { count, sum_micros } => {
+                self.latency_count = count;
+                self.latency_sum_micros = sum_micros;
+            }
+            StatsFrame::ActiveConnections(connections) => self.active_connections = connections,
+            StatsFrame::RejectedConnections(connections) => self.rejected_connections = connections,
+            StatsFrame::Compactions(compactions) => self.compactions = compactions,
+            StatsFrame::Compaction(compaction) => self.compaction = compaction,
+            StatsFrame::EngineSize(size) => self.engine_size = size,
+            StatsFrame::UnsyncedBytes(bytes) => self.unsynced_bytes = bytes,
+            StatsFrame::Engine(engine) => self.engine = engine,
+            StatsFrame::Raft(raft) => self.raft = Some(raft),
+            StatsFrame::Replicas(replicas) => self.replicas = Some(replicas),
+            StatsFrame::Following(following) => self.following = Some(following),
+            StatsFrame::Cluster(cluster) => self.cluster = Some(cluster),
+        }
+    }
+
+    /// Render the stats in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# TYPE kvs_requests_total counter\n");
+        for (name, count) in &self.requests {
+            let _ = writeln!(out, "kvs_requests_total{{type=\"{}\"}} {}", name, count);
+        }
+        out.push_str("# TYPE kvs_errors_total counter\n");
+        let _ = writeln!(out, "kvs_errors_total {}", self.errors);
+
+        out.push_str("# TYPE kvs_request_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (upper, count) in &self.latency_buckets {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                *upper as f64 / 1e6,
+                cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "kvs_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            self.latency_count
+        );
+        let _ = writeln!(
+            out,
+            "kvs_request_duration_seconds_sum {}",
+            self.latency_sum_micros as f64 / 1e6
+        );
+        let _ = writeln!(
+            out,
+            "kvs_request_duration_seconds_count {}",
+            self.latency_count
+        );
+
+        out.push_str("# TYPE kvs_active_connections gauge\n");
+        let _ = writeln!(out, "kvs_active_connections {}", self.active_connections);
+        out.push_str("# TYPE kvs_rejected_connections_total counter\n");
+        let _ = writeln!(
+            out,
+            "kvs_rejected_connections_total {}",
+            self.rejected_connections
+        );
+        out.push_str("# TYPE kvs_compactions_total counter\n");
+        let _ = writeln!(out, "kvs_compactions_total {}", self.compactions);
+        out.push_str("# TYPE kvs_compaction_running gauge\n");
+        let _ = writeln!(
+            out,
+            "kvs_compaction_running {}",
+            u8::from(self.compaction.running)
+        );
+        out.push_str("# TYPE kvs_compaction_processed_bytes gauge\n");
+        let _ = writeln!(
+            out,
+            "kvs_compaction_processed_bytes {}",
+            self.compaction.bytes_processed
+        );
+        out.push_str("# TYPE kvs_compaction_remaining_bytes gauge\n");
+        let _ = writeln!(
+            out,
+            "kvs_compaction_remaining_bytes {}",
+            self.compaction.bytes_remaining
+        );
+        out.push_str("# TYPE kvs_dead_bytes gauge\n");
+        let _ = writeln!(out, "kvs_dead_bytes {}", self.compaction.dead_bytes);
+        out.push_str("# TYPE kvs_tombstones gauge\n");
+        let _ = writeln!(out, "kvs_tombstones {}", self.compaction.tombstones);
+        out.push_str("# TYPE kvs_tombstone_bytes gauge\n");
+        let _ = writeln!(
+            out,
+            "kvs_tombstone_bytes {}",
+            self.compaction.tombstone_bytes
+        );
+        out.push_str("# TYPE kvs_writes_stalled gauge\n");
+        let _ = writeln!(
+            out,
+            "kvs_writes_stalled {}",
+            u8::from(self.compaction.stalled)
+        );
+        out.push_str("# TYPE kvs_stalled_writes_total counter\n");
+        let _ = writeln!(
+            out,
+            "kvs_stalled_writes_total {}",
+            self.compaction.stalled_writes
+        );
+        out.push_str("# TYPE kvs_engine_size_bytes gauge\n");
+        let _ = writeln!(out, "kvs_engine_size_bytes {}", self.engine_size);
+        out.push_str("# TYPE kvs_unsynced_bytes gauge\n");
+        let _ = writeln!(out, "kvs_unsynced_bytes {}", self.unsynced_bytes);
+        // Whichever the engine, its gauges go by the same names; those it has no notion of
+        // are left out.
+        let engine_gauges = [
+            ("kvs_engine_segments", "gauge", self.engine.segments),
+            ("kvs_engine_dead_bytes", "gauge", self.engine.dead_bytes),
+            (
+                "kvs_engine_cache_hits_total",
+                "counter",
+                self.engine.cache_hits,
+            ),
+            (
+                "kvs_engine_cache_misses_total",
+                "counter",
+                self.engine.cache_misses,
+            ),
+        ];
+        for (name, kind, value) in engine_gauges {
+            if let Some(value) = value {
+                let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                let _ = writeln!(out, "{} {}", name, value);
+            }
+        }
+        if let Some(raft) = &self.raft {
+            out.push_str("# TYPE kvs_raft_term gauge\n");
+            let _ = writeln!(out, "kvs_raft_term {}", raft.term);
+            out.push_str("# TYPE kvs_raft_is_leader gauge\n");
+            let _ = writeln!(
+                out,
+                "kvs_raft_is_leader {}",
+                u8::from(raft.leader == Some(raft.id))
+            );
+            out.push_str("# TYPE kvs_raft_commit_index gauge\n");
+            let _ = writeln!(out, "kvs_raft_commit_index {}", raft.commit_index);
+        }
+        if let Some(replicas) = &self.replicas {
+            out.push_str("# TYPE kvs_replicas gauge\n");
+            let _ = writeln!(out, "kvs_replicas {}", replicas.len());
+            out.push_str("# TYPE kvs_replica_lag gauge\n");
+            for replica in replicas {
+                let _ = writeln!(
+                    out,
+                    "kvs_replica_lag{{replica=\"{}\"}} {}",
+                    replica.peer, replica.lag
+                );
+            }
+        }
+        if let Some(following) = &self.following {
+            out.push_str("# TYPE kvs_replication_connected gauge\n");
+            let _ = writeln!(
+                out,
+                "kvs_replication_connected {}",
+                u8::from(following.connected)
+            );
+            out.push_str("# TYPE kvs_replication_lag gauge\n");
+            let _ = writeln!(out, "kvs_replication_lag {}", following.lag);
+            out.push_str("# TYPE kvs_replication_resyncs_total counter\n");
+            let _ = writeln!(out, "kvs_replication_resyncs_total {}", following.resyncs);
+        }
+        if let Some(cluster) = &self.cluster {
+            let up = cluster.members.iter().filter(|member| member.up).count();
+            out.push_str("# TYPE kvs_cluster_members gauge\n");
+            let _ = writeln!(out, "kvs_cluster_members {}", cluster.members.len());
+            out.push_str("# TYPE kvs_cluster_members_up gauge\n");
+            let _ = writeln!(out, "kvs_cluster_members_up {}", up);
+            out.push_str("# TYPE kvs_cluster_slots gauge\n");
+            for member in &cluster.members {
+                let _ = writeln!(
+                    out,
+                    "kvs_cluster_slots{{member=\"{}\"}} {}",
+                    member.addr, member.slots
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Answer every HTTP request on `listener` with the current metrics in Prometheus format.
+pub(crate) fn serve_prometheus<E: KvsEngine>(
+    listener: TcpListener,
+    metrics: &Metrics,
+    engine: E,
+    log: Logger,
+) {
+    for result in listener.incoming() {
+        let outcome = result
+            .map_err(Into::into)
+            .and_then(|stream| respond_prometheus(stream, metrics, &engine));
+        if let Err(err) = outcome {
+            error!(
+                &log,
+                "metrics request failed with error {}",
+                err.to_string()
+            );
+        }
+    }
+}
+
+fn respond_prometheus<E: KvsEngine>(
+    stream: TcpStream,
+    metrics: &Metrics,
+    engine: &E,
+) -> Result<()> {
+    // The path is ignored: every request gets the metrics page.
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let body = metrics.snapshot(engine)?.to_prometheus();
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    writer.flush()?;
+    Ok(())
+}
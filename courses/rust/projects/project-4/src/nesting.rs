@@ -0,0 +1,46 @@
+//! A bound on how deep requests, responses and log records may nest when decoded.
+//!
+//! Serde decodes a message nested in another by recursing into it, so a few megabytes of a
+//! request tagged over and over, or of a batch holding a batch holding a batch, would
+//! overflow the stack of the thread decoding them. Bincode has no bound of its own, and
+//! MessagePack's is deeper than a server thread's stack.
+
+use serde::de::Error;
+use serde::Deserialize;
+use serde::Deserializer;
+use std::cell::Cell;
+
+// Far deeper than anything is nested on purpose: a tagged request in a bucket is two.
+const MAX_DEPTH: usize = 8;
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// Puts the depth back as it was once the nested value is decoded, or fails to be.
+struct Guard(usize);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(self.0));
+    }
+}
+
+/// Decode a value nested in another, for `#[serde(deserialize_with = "nested")]`,
+/// failing if it's more than `MAX_DEPTH` deep.
+pub(crate) fn nested<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let depth = DEPTH.with(Cell::get);
+    if depth >= MAX_DEPTH {
+        return Err(D::Error::custom(format!(
+            "nested more than {} deep",
+            MAX_DEPTH
+        )));
+    }
+    let _guard = Guard(depth);
+    DEPTH.with(|cell| cell.set(depth + 1));
+    T::deserialize(deserializer)
+}
@@ -0,0 +1,69 @@
+//! Files of writes to apply in one go, as `kvs apply` and `kvs-client apply` read them.
+//!
+//! Each line is `set <key> <value>`, the value being the rest of the line, or `rm <key>`.
+//! Blank lines and lines starting with `#` are skipped. The whole file is parsed before any
+//! of it is applied, so a mistyped line doesn't leave it half applied.
+
+use crate::KvsError;
+use crate::Result;
+use std::io::BufRead;
+
+/// A write read from a line of a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Set(String, String),
+    Remove(String),
+}
+
+/// What applying a file did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApplySummary {
+    pub sets: u64,
+    pub removes: u64,
+}
+
+/// Read the writes of a file, each with the number of the line it's on.
+pub fn parse(reader: impl BufRead) -> Result<Vec<(usize, Op)>> {
+    let mut ops = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.splitn(3, char::is_whitespace);
+        let op = match (words.next(), words.next(), words.next()) {
+            (Some("set"), Some(key), Some(value)) => {
+                Op::Set(key.to_owned(), value.trim_start().to_owned())
+            }
+            (Some("rm"), Some(key), None) => Op::Remove(key.to_owned()),
+            _ => {
+                return Err(KvsError::StringError(format!(
+                    "line {}: expected `set <key> <value>` or `rm <key>`",
+                    i + 1
+                )))
+            }
+        };
+        ops.push((i + 1, op));
+    }
+    Ok(ops)
+}
+
+/// Pass each write to `each` in order, stopping at the first that fails. The error names
+/// the line the write is on; the writes before it stay applied.
+pub fn apply(
+    ops: Vec<(usize, Op)>,
+    mut each: impl FnMut(Op) -> Result<()>,
+) -> Result<ApplySummary> {
+    let mut summary = ApplySummary::default();
+    for (line, op) in ops {
+        let removal = matches!(op, Op::Remove(_));
+        each(op).map_err(|err| KvsError::StringError(format!("line {}: {}", line, err)))?;
+        if removal {
+            summary.removes += 1;
+        } else {
+            summary.sets += 1;
+        }
+    }
+    Ok(summary)
+}
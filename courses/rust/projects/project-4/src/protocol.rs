@@ -1,17 +1,499 @@
+use crate::cluster::ClusterMap;
+use crate::cluster::Member;
+use crate::handshake::Capabilities;
+use crate::metrics::StatsFrame;
+use crate::nesting::nested;
+use crate::watch::ChangeOp;
+use crate::EntryMeta;
+use crate::KvsError;
+use crate::Result;
+use crate::SizeLimits;
+use crate::TraceId;
 use serde::Deserialize;
 use serde::Serialize;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub enum Request {
     Get(String),
+    /// Get several keys in one round trip, answered with their values in the same order.
+    MultiGet(Vec<String>),
+    /// Get a key's value with its version and when it was written; see
+    /// `KvsEngine::get_with_meta`.
+    GetWithMeta(String),
+    /// Get what's kept of a key's value, without the value; see `KvsEngine::metadata`.
+    Stat(String),
     Set(String, String),
     Remove(String),
+    Incr(String, i64),
+    /// Move a key's value to another key; see `KvsEngine::rename`.
+    Rename(String, String),
+    /// Append to a key's value; see `KvsEngine::append`.
+    Append(String, String),
+    /// Get part of a key's value: the key, the offset and the length; see
+    /// `KvsEngine::get_range`.
+    GetRange(String, u64, u64),
+    /// The keys set to a value; see `KvsEngine::find_by_value`.
+    FindByValue(String),
+    /// Get a key's value once it's set, waiting up to `timeout_ms` milliseconds for a write
+    /// to set it if it isn't yet. Answered with `Response::WaitGetOk`, with None if the
+    /// wait timed out.
+    WaitGet {
+        key: String,
+        timeout_ms: u64,
+    },
+    /// Take a lock for an owner, for a time; see `KvsEngine::acquire_lock`.
+    AcquireLock(String, String, Duration),
+    /// Release an owner's lock; see `KvsEngine::release_lock`.
+    ReleaseLock(String, String),
+    Contains(String),
+    Len,
+    /// Keys held in any of the given slots, out of the given number of slots.
+    SlotKeys(u32, Vec<u32>),
+    /// Up to `limit` keys, in order, after `cursor`: none for the first page, or the cursor
+    /// the last page was answered with. Answered with `Response::KeysOk`.
+    Keys {
+        cursor: Option<String>,
+        limit: u32,
+    },
+    AcquireLease(String, Duration),
+    RenewLease(String, u64, Duration),
+    ReleaseLease(String, u64),
+    Begin,
+    Commit,
+    Rollback,
+    Stats,
+    /// Compact the engine now; see `KvsEngine::compact`.
+    Compact,
+    /// Check the server is up, and learn what it's running. Answered with
+    /// `Response::PingOk`, by every member of a replicated group.
+    Ping,
+    /// A request about keys, made in the named bucket rather than the engine's own
+    /// namespace; see `Bucket`.
+    InBucket(String, #[serde(deserialize_with = "nested")] Box<Request>),
+    /// A request pipelined with others on the connection: the server may serve it
+    /// alongside them, and answers with a `Response::Tagged` carrying the same id.
+    Tagged(u64, #[serde(deserialize_with = "nested")] Box<Request>),
+    /// A request made in the trace with the given id, which the server's spans of it carry.
+    /// Goes around any other wrapping, and is answered as the request is.
+    Traced(TraceId, #[serde(deserialize_with = "nested")] Box<Request>),
+    /// Turn the connection into a stream of `Response::KeyChanged`, one for each change to
+    /// a key starting with the prefix, after a `SubscribeOk`.
+    Subscribe(String),
+    /// What a member of a cluster hashing keys into `slot_count` slots knows of it, answered
+    /// with what the server knows; see `kvs::cluster`.
+    Gossip {
+        slot_count: u32,
+        members: Vec<Member>,
+    },
+    /// The map of the server's cluster; see `kvs::cluster`.
+    ClusterMap,
+    /// A request the server serves itself, even if another member of its cluster owns its
+    /// keys, as `kvs rebalance` makes them to move keys between members.
+    Pinned(#[serde(deserialize_with = "nested")] Box<Request>),
+    /// Make the connection's further requests with the token's grant; see `kvs::acl`.
+    /// Servers without an ACL take any token.
+    Authenticate(String),
+}
+
+impl Request {
+    /// Name of the request type, as reported in metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Get(_) => "get",
+            Self::MultiGet(_) => "multi_get",
+            Self::GetWithMeta(_) => "get_with_meta",
+            Self::Stat(_) => "stat",
+            Self::Set(_, _) => "set",
+            Self::Remove(_) => "remove",
+            Self::Incr(_, _) => "incr",
+            Self::Rename(_, _) => "rename",
+            Self::Append(_, _) => "append",
+            Self::GetRange(_, _, _) => "get_range",
+            Self::FindByValue(_) => "find_by_value",
+            Self::WaitGet { .. } => "wait_get",
+            Self::AcquireLock(_, _, _) => "acquire_lock",
+            Self::ReleaseLock(_, _) => "release_lock",
+            Self::Contains(_) => "contains",
+            Self::Len => "len",
+            Self::SlotKeys(_, _) => "slot_keys",
+            Self::Keys { .. } => "keys",
+            Self::AcquireLease(_, _) => "acquire_lease",
+            Self::RenewLease(_, _, _) => "renew_lease",
+            Self::ReleaseLease(_, _) => "release_lease",
+            Self::Begin => "begin",
+            Self::Commit => "commit",
+            Self::Rollback => "rollback",
+            Self::Stats => "stats",
+            Self::Compact => "compact",
+            Self::Ping => "ping",
+            Self::Subscribe(_) => "subscribe",
+            Self::Gossip { .. } => "gossip",
+            Self::ClusterMap => "cluster_map",
+            Self::Authenticate(_) => "authenticate",
+            Self::InBucket(_, request)
+            | Self::Tagged(_, request)
+            | Self::Traced(_, request)
+            | Self::Pinned(request) => request.name(),
+        }
+    }
+
+    /// The capability a server must offer to serve the request, if it isn't part of the base
+    /// protocol.
+    pub fn capability(&self) -> Option<Capabilities> {
+        match self {
+            Self::Incr(_, _) => Some(Capabilities::INCR),
+            Self::Rename(_, _) => Some(Capabilities::RENAME),
+            Self::Append(_, _) => Some(Capabilities::APPEND),
+            Self::GetRange(_, _, _) => Some(Capabilities::GET_RANGE),
+            Self::FindByValue(_) => Some(Capabilities::FIND_BY_VALUE),
+            Self::WaitGet { .. } => Some(Capabilities::WAIT_GET),
+            Self::AcquireLock(_, _, _) | Self::ReleaseLock(_, _) => Some(Capabilities::LOCKS),
+            Self::Compact => Some(Capabilities::COMPACT),
+            Self::MultiGet(_) => Some(Capabilities::MULTI_GET),
+            Self::GetWithMeta(_) | Self::Stat(_) => Some(Capabilities::VERSIONS),
+            Self::Keys { .. } => Some(Capabilities::KEYS),
+            Self::Ping => Some(Capabilities::PING),
+            Self::Subscribe(_) => Some(Capabilities::WATCH),
+            Self::Gossip { .. } | Self::ClusterMap => Some(Capabilities::CLUSTER),
+            Self::Authenticate(_) => Some(Capabilities::AUTH),
+            Self::InBucket(_, request) => Some(
+                request
+                    .capability()
+                    .map_or(Capabilities::BUCKETS, |needed| {
+                        needed | Capabilities::BUCKETS
+                    }),
+            ),
+            Self::Tagged(_, request) => Some(
+                request
+                    .capability()
+                    .map_or(Capabilities::PIPELINING, |needed| {
+                        needed | Capabilities::PIPELINING
+                    }),
+            ),
+            Self::Traced(_, request) => Some(
+                request
+                    .capability()
+                    .map_or(Capabilities::TRACING, |needed| {
+                        needed | Capabilities::TRACING
+                    }),
+            ),
+            Self::Pinned(request) => Some(
+                request
+                    .capability()
+                    .map_or(Capabilities::CLUSTER, |needed| {
+                        needed | Capabilities::CLUSTER
+                    }),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Return `KvsError::TooLarge` if a key or value of the request is over `limits`.
+    pub fn check_size(&self, limits: &SizeLimits) -> Result<()> {
+        match self {
+            Self::Set(key, value) | Self::Append(key, value) => limits.check(key, Some(value)),
+            Self::Get(key)
+            | Self::GetWithMeta(key)
+            | Self::Stat(key)
+            | Self::Remove(key)
+            | Self::Incr(key, _)
+            | Self::GetRange(key, _, _)
+            | Self::WaitGet { key, .. }
+            | Self::AcquireLock(key, _, _)
+            | Self::ReleaseLock(key, _)
+            | Self::Contains(key) => limits.check(key, None),
+            Self::Rename(old_key, new_key) => {
+                limits.check(old_key, None)?;
+                limits.check(new_key, None)
+            }
+            Self::MultiGet(keys) => keys.iter().try_for_each(|key| limits.check(key, None)),
+            Self::Keys {
+                cursor: Some(key), ..
+            } => limits.check(key, None),
+            Self::InBucket(_, request)
+            | Self::Tagged(_, request)
+            | Self::Traced(_, request)
+            | Self::Pinned(request) => request.check_size(limits),
+            _ => Ok(()),
+        }
+    }
+
+    /// The keys the request is about, which decide the member of a cluster to serve it; see
+    /// `kvs::cluster`. Those of a request made in a bucket are as the client named them,
+    /// without the bucket's prefix, and a pinned request is about none.
+    pub fn routing_keys(&self) -> Vec<&str> {
+        match self {
+            Self::Get(key)
+            | Self::GetWithMeta(key)
+            | Self::Stat(key)
+            | Self::Set(key, _)
+            | Self::Remove(key)
+            | Self::Incr(key, _)
+            | Self::Append(key, _)
+            | Self::GetRange(key, _, _)
+            | Self::WaitGet { key, .. }
+            | Self::AcquireLock(key, _, _)
+            | Self::ReleaseLock(key, _)
+            | Self::Contains(key) => vec![key],
+            Self::Rename(old_key, new_key) => vec![old_key, new_key],
+            Self::MultiGet(keys) => keys.iter().map(String::as_str).collect(),
+            Self::InBucket(_, request) | Self::Tagged(_, request) | Self::Traced(_, request) => {
+                request.routing_keys()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Make the request in `bucket`, if it's about keys. Other requests are left as they are.
+    pub fn in_bucket(self, bucket: &str) -> Self {
+        match self {
+            Self::Get(_)
+            | Self::MultiGet(_)
+            | Self::GetWithMeta(_)
+            | Self::Stat(_)
+            | Self::Set(_, _)
+            | Self::Remove(_)
+            | Self::Incr(_, _)
+            | Self::Rename(_, _)
+            | Self::Append(_, _)
+            | Self::GetRange(_, _, _)
+            | Self::FindByValue(_)
+            | Self::WaitGet { .. }
+            | Self::AcquireLock(_, _, _)
+            | Self::ReleaseLock(_, _)
+            | Self::Contains(_)
+            | Self::Len
+            | Self::SlotKeys(_, _)
+            | Self::Keys { .. } => Self::InBucket(bucket.to_owned(), Box::new(self)),
+            _ => self,
+        }
+    }
+
+    /// Whether the request writes to the engine, or would outside of a transaction.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Self::InBucket(_, request)
+            | Self::Tagged(_, request)
+            | Self::Traced(_, request)
+            | Self::Pinned(request) => request.is_write(),
+            request => matches!(
+                request,
+                Self::Set(_, _)
+                    | Self::Remove(_)
+                    | Self::Incr(_, _)
+                    | Self::Rename(_, _)
+                    | Self::Append(_, _)
+                    | Self::AcquireLock(_, _, _)
+                    | Self::ReleaseLock(_, _)
+                    | Self::Commit
+            ),
+        }
+    }
+
+    /// Whether sending the request twice has the same effect as sending it once.
+    pub fn is_idempotent(&self) -> bool {
+        if let Self::InBucket(_, request)
+        | Self::Tagged(_, request)
+        | Self::Traced(_, request)
+        | Self::Pinned(request) = self
+        {
+            return request.is_idempotent();
+        }
+        matches!(
+            self,
+            Self::Get(_)
+                | Self::MultiGet(_)
+                | Self::GetWithMeta(_)
+                | Self::Stat(_)
+                | Self::GetRange(_, _, _)
+                | Self::FindByValue(_)
+                | Self::WaitGet { .. }
+                | Self::AcquireLock(_, _, _)
+                | Self::ReleaseLock(_, _)
+                | Self::Contains(_)
+                | Self::Len
+                | Self::SlotKeys(_, _)
+                | Self::Keys { .. }
+                | Self::Stats
+                | Self::Compact
+                | Self::Ping
+                | Self::Gossip { .. }
+                | Self::ClusterMap
+                | Self::Authenticate(_)
+        )
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub enum Response {
     GetOk(Option<String>),
+    MultiGetOk(Vec<Option<String>>),
+    GetWithMetaOk(Option<(String, EntryMeta)>),
+    StatOk(Option<EntryMeta>),
     SetOk(()),
     RemoveOk(()),
-    Err(String),
+    IncrOk(i64),
+    RenameOk(()),
+    /// The length of the value appended to, in bytes.
+    AppendOk(u64),
+    GetRangeOk(Option<String>),
+    FindByValueOk(Vec<String>),
+    WaitGetOk(Option<String>),
+    /// Whether the lock was taken.
+    AcquireLockOk(bool),
+    /// Whether the owner held the lock it released.
+    ReleaseLockOk(bool),
+    ContainsOk(bool),
+    LenOk(u64),
+    SlotKeysOk(Vec<String>),
+    /// A page of keys, with the cursor to ask for the next page with, or None if it's the
+    /// last.
+    KeysOk {
+        keys: Vec<String>,
+        cursor: Option<String>,
+    },
+    AcquireLeaseOk(Option<u64>),
+    RenewLeaseOk(bool),
+    ReleaseLeaseOk(bool),
+    BeginOk(()),
+    CommitOk(()),
+    RollbackOk(()),
+    /// Part of the answer to `Request::Stats`. The frames are followed by `StatsOk`.
+    StatsFrame(StatsFrame),
+    StatsOk(()),
+    CompactOk(()),
+    PingOk(Pong),
+    /// The server is a replica that isn't the leader; retry at the leader's address, if known.
+    NotLeader(Option<SocketAddr>),
+    /// The server is a member of a cluster, and the member at the address owns the request's
+    /// keys; retry there.
+    Moved(SocketAddr),
+    /// The write wasn't run because the engine is compacting; retry after the given delay.
+    /// Sent only to clients that want `Capabilities::BACKPRESSURE`, which keep the
+    /// connection.
+    Busy(Duration),
+    /// The request's frame failed its checksum, so it wasn't run. The server hangs up after
+    /// sending this.
+    FrameCorrupted,
+    Err(ErrorCode, String),
+    /// The response to the `Request::Tagged` with the same id.
+    Tagged(u64, #[serde(deserialize_with = "nested")] Box<Response>),
+    SubscribeOk(()),
+    /// A change to a key a subscription covers. Sent only to subscribed connections.
+    KeyChanged {
+        key: String,
+        op: ChangeOp,
+    },
+    GossipOk(Vec<Member>),
+    ClusterMapOk(ClusterMap),
+    AuthenticateOk(()),
+}
+
+impl Response {
+    /// Return the error the response carries, if it's `Err`, `NotLeader`, `Moved` or `Busy`,
+    /// or else the response.
+    pub fn into_result(self) -> Result<Self> {
+        match self {
+            Self::Err(code, msg) => Err(code.into_error(msg)),
+            Self::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
+            Self::Moved(owner) => Err(KvsError::Moved(owner)),
+            Self::Busy(retry_after) => Err(KvsError::Throttled(retry_after)),
+            response => Ok(response),
+        }
+    }
+}
+
+/// A server's answer to `Request::Ping`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Pong {
+    /// The version of kvs the server runs.
+    pub version: String,
+    /// The name of the server's engine; see `KvsEngine::name`.
+    pub engine: String,
+    /// How long the server has been serving.
+    pub uptime: Duration,
+    /// The number of pings the server has answered, this one included.
+    pub pings: u64,
+}
+
+/// The kind of error a request failed with, so the client can rebuild the `KvsError`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    KeyNotFound,
+    NotAnInteger,
+    IntegerOverflow,
+    TransactionConflict,
+    /// The server's engine failed to read or write its files.
+    Io,
+    /// The server is too busy to serve the connection, and hangs up after saying so.
+    Busy,
+    /// A key, value or whole request is over the server's size limits.
+    TooLarge,
+    /// The client is over the server's rate limit.
+    RateLimited,
+    /// A key being locked or unlocked holds something other than a lock.
+    NotALock,
+    /// The server is a replica, which refuses writes.
+    ReadOnly,
+    /// The server's ACL doesn't allow the request; see `kvs::acl`.
+    PermissionDenied,
+    /// Any other error; only its message is sent.
+    Other,
+}
+
+impl ErrorCode {
+    pub fn of(err: &KvsError) -> Self {
+        match err.root() {
+            KvsError::KeyNotFound => Self::KeyNotFound,
+            KvsError::NotAnInteger => Self::NotAnInteger,
+            KvsError::IntegerOverflow => Self::IntegerOverflow,
+            KvsError::TransactionConflict => Self::TransactionConflict,
+            KvsError::IO(_) => Self::Io,
+            KvsError::ServerBusy => Self::Busy,
+            KvsError::TooLarge(_) => Self::TooLarge,
+            KvsError::RateLimited => Self::RateLimited,
+            KvsError::NotALock => Self::NotALock,
+            KvsError::ReadOnly => Self::ReadOnly,
+            KvsError::PermissionDenied(_) => Self::PermissionDenied,
+            _ => Self::Other,
+        }
+    }
+
+    /// Split `err` into its code and the message `into_error` rebuilds it from.
+    pub(crate) fn split(err: &KvsError) -> (Self, String) {
+        match err {
+            // The client adds its own prefix when it rebuilds the error.
+            KvsError::IO(err) => (Self::Io, err.to_string()),
+            KvsError::Context(err) => match &err.source {
+                KvsError::IO(source) => (Self::Io, format!("{}: {}", err.context, source)),
+                source => (Self::of(source), format!("{}: {}", err.context, source)),
+            },
+            // As with IO errors, the client adds the prefix.
+            KvsError::PermissionDenied(msg) => (Self::PermissionDenied, msg.clone()),
+            err => (Self::of(err), err.to_string()),
+        }
+    }
+
+    /// Rebuild the error the server sent with this code and `msg`.
+    pub fn into_error(self, msg: String) -> KvsError {
+        match self {
+            Self::KeyNotFound => KvsError::KeyNotFound,
+            Self::NotAnInteger => KvsError::NotAnInteger,
+            Self::IntegerOverflow => KvsError::IntegerOverflow,
+            Self::TransactionConflict => KvsError::TransactionConflict,
+            // The kind isn't sent: a failure on the server's disk says nothing about whether
+            // the connection is worth retrying.
+            Self::Io => KvsError::IO(io::Error::other(msg)),
+            Self::Busy => KvsError::ServerBusy,
+            Self::TooLarge => KvsError::TooLarge(msg),
+            Self::RateLimited => KvsError::RateLimited,
+            Self::NotALock => KvsError::NotALock,
+            Self::ReadOnly => KvsError::ReadOnly,
+            Self::PermissionDenied => KvsError::PermissionDenied(msg),
+            Self::Other => KvsError::StringError(msg),
+        }
+    }
 }
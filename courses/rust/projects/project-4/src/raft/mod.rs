@@ -0,0 +1,93 @@
+//! Replication of a `KvsServer` across a group of servers with the Raft consensus algorithm.
+//!
+//! Every server in the group runs a `RaftNode` next to its engine. Writes are appended to a
+//! replicated log of engine commands and applied to each server's engine once a majority of
+//! the group has stored them. Only the elected leader serves reads and writes; other servers
+//! answer with `Response::NotLeader` so clients can redirect, which `KvsClient` does.
+//!
+//! This is a minimal implementation: the log is never compacted or snapshotted, group
+//! membership is fixed by configuration, and the leader serves reads without checking that
+//! it is still the leader, so a deposed leader may briefly answer with stale values.
+
+use crate::KvsError;
+use crate::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+mod node;
+pub(crate) use self::node::RaftNode;
+
+mod rpc;
+
+mod storage;
+
+/// Another server in the Raft group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Peer {
+    pub id: u64,
+    /// Address the peer's Raft node listens on.
+    pub raft_addr: SocketAddr,
+    /// Address the peer serves clients on, where clients are redirected when it leads.
+    pub client_addr: SocketAddr,
+}
+
+impl FromStr for Peer {
+    type Err = KvsError;
+
+    /// Parse a peer written as `ID,RAFT-IP-PORT,CLIENT-IP-PORT`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            KvsError::StringError(format!(
+                "invalid peer {:?}; expected ID,RAFT-IP-PORT,CLIENT-IP-PORT",
+                s
+            ))
+        };
+        let parts: Vec<&str> = s.split(',').collect();
+        match parts[..] {
+            [id, raft_addr, client_addr] => Ok(Self {
+                id: id.parse().map_err(|_| invalid())?,
+                raft_addr: raft_addr.parse().map_err(|_| invalid())?,
+                client_addr: client_addr.parse().map_err(|_| invalid())?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How a server takes part in a Raft group.
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// This server's id, unique within the group.
+    pub id: u64,
+    /// Address this server's Raft node listens on for its peers.
+    pub addr: SocketAddr,
+    /// Every other server in the group.
+    pub peers: Vec<Peer>,
+    /// Directory the node keeps its term, vote and log in.
+    pub data_dir: PathBuf,
+}
+
+impl RaftConfig {
+    pub fn new(id: u64, addr: SocketAddr, peers: Vec<Peer>, data_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            id,
+            addr,
+            peers,
+            data_dir: data_dir.into(),
+        }
+    }
+}
+
+/// A server's view of its Raft group, as reported in `ServerStats`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RaftStatus {
+    pub id: u64,
+    pub term: u64,
+    /// Id of the server this one believes is the leader, if any.
+    pub leader: Option<u64>,
+    /// Index of the last log entry known to be stored by a majority.
+    pub commit_index: u64,
+}
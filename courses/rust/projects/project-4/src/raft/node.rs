@@ -0,0 +1,670 @@
+use super::rpc::AppendReply;
+use super::rpc::AppendRequest;
+use super::rpc::Message;
+use super::rpc::PeerClient;
+use super::rpc::Reply;
+use super::rpc::VoteReply;
+use super::rpc::VoteRequest;
+use super::storage::AppliedIndex;
+use super::storage::Entry;
+use super::storage::HardState;
+use super::storage::Storage;
+use super::Peer;
+use super::RaftConfig;
+use super::RaftStatus;
+use crate::engines::Change;
+use crate::engines::Command;
+use crate::engines::KvsEngine;
+use crate::sync::Mutex;
+use crate::KvsError;
+use crate::Result;
+use crossbeam::channel;
+use crossbeam::channel::Receiver;
+use crossbeam::channel::RecvTimeoutError;
+use crossbeam::channel::Sender;
+use rmp_serde::decode;
+use rmp_serde::Deserializer;
+use rmp_serde::Serializer;
+use serde::Deserialize;
+use serde::Serialize;
+use slog::error;
+use slog::info;
+use slog::Logger;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::MutexGuard;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+const TICK: Duration = Duration::from_millis(10);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+// Election timeouts are picked at random between this and twice this.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_ENTRIES_PER_APPEND: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+type Waiter = Sender<std::result::Result<(), String>>;
+
+struct State {
+    role: Role,
+    hard: HardState,
+    // The entry at index i (counting from 1) is log[i - 1].
+    log: Vec<Entry>,
+    storage: Storage,
+    commit_index: u64,
+    leader: Option<u64>,
+    election_deadline: Instant,
+    last_heartbeat: Instant,
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    // Proposals waiting for the entry at an index, with the term it was appended in.
+    waiters: HashMap<u64, (u64, Waiter)>,
+}
+
+impl State {
+    fn last_log_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            index => self.log[index as usize - 1].term,
+        }
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.term_at(self.last_log_index())
+    }
+}
+
+/// One server's member of a Raft group, applying committed commands to its engine.
+pub(crate) struct RaftNode<E: KvsEngine> {
+    id: u64,
+    addr: SocketAddr,
+    peers: Vec<(Peer, PeerClient)>,
+    state: Mutex<State>,
+    // Only the applier thread takes this lock; it's there because engines needn't be Sync.
+    applied: Mutex<Applied<E>>,
+    // Wakes the ticker to replicate new entries without waiting for the next heartbeat.
+    kick: Sender<()>,
+    // Wakes the applier once entries are committed.
+    committed: Sender<()>,
+    stopped: AtomicBool,
+    log: Logger,
+}
+
+// The engine, and how much of the log has been applied to it.
+struct Applied<E> {
+    engine: E,
+    index: u64,
+    saved: AppliedIndex,
+}
+
+fn random_election_timeout() -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    ELECTION_TIMEOUT + Duration::from_millis(random % ELECTION_TIMEOUT.as_millis() as u64)
+}
+
+impl<E: KvsEngine> RaftNode<E> {
+    /// Load the node's storage, start listening for peers and start taking part in elections.
+    pub fn start(config: RaftConfig, engine: E, log: Logger) -> Result<Arc<Self>> {
+        let (storage, hard, entries) = Storage::open(config.data_dir.clone())?;
+        let (saved, applied) = AppliedIndex::open(config.data_dir)?;
+        if applied > entries.len() as u64 {
+            return Err(KvsError::StringError(format!(
+                "the raft log holds {} entries, but {} were applied",
+                entries.len(),
+                applied
+            )));
+        }
+        let listener = TcpListener::bind(config.addr)?;
+        let now = Instant::now();
+        let state = State {
+            role: Role::Follower,
+            hard,
+            log: entries,
+            storage,
+            // Entries up to the last one applied were committed; those after it wait for a
+            // leader to say so. The applied index is saved after the engine writes, so a crash
+            // in between applies those entries again, which their sets and removes survive.
+            // An engine that doesn't sync every write can lose some of them to a machine
+            // crash all the same.
+            commit_index: applied,
+            leader: None,
+            election_deadline: now + random_election_timeout(),
+            last_heartbeat: now,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            waiters: HashMap::new(),
+        };
+        let (kick, kicked) = channel::unbounded();
+        let (committed, commits) = channel::unbounded();
+        let node = Arc::new(Self {
+            id: config.id,
+            addr: listener.local_addr()?,
+            peers: config
+                .peers
+                .into_iter()
+                .map(|peer| {
+                    let client = PeerClient::new(peer.raft_addr);
+                    (peer, client)
+                })
+                .collect(),
+            state: Mutex::new(state),
+            applied: Mutex::new(Applied {
+                engine,
+                index: applied,
+                saved,
+            }),
+            kick,
+            committed,
+            stopped: AtomicBool::new(false),
+            log,
+        });
+        info!(&node.log, "raft node started"; "id" => node.id, "ip-port" => node.addr.to_string());
+
+        let listening = node.clone();
+        thread::Builder::new().spawn(move || listening.listen(listener))?;
+        let ticking = node.clone();
+        thread::Builder::new().spawn(move || ticking.tick(kicked))?;
+        let applying = node.clone();
+        thread::Builder::new().spawn(move || applying.apply_committed(commits))?;
+        Ok(node)
+    }
+
+    /// Stop taking part in the group.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        {
+            let mut state = self.state.lock();
+            state.role = Role::Follower;
+            state.leader = None;
+            state.waiters.clear();
+        }
+        let _ = self.kick.send(());
+        let _ = self.committed.send(());
+        // Wake the accept loop so it sees the flag.
+        let _ = TcpStream::connect(self.addr);
+    }
+
+    /// Return an error redirecting to the leader unless this node is the leader.
+    pub fn check_leader(&self) -> Result<()> {
+        let state = self.state.lock();
+        match state.role {
+            Role::Leader => Ok(()),
+            _ => Err(self.not_leader(&state)),
+        }
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        let state = self.state.lock();
+        RaftStatus {
+            id: self.id,
+            term: state.hard.term,
+            leader: state.leader,
+            commit_index: state.commit_index,
+        }
+    }
+
+    /// Replicate `command` and apply it once a majority has stored it. Return the result
+    /// of applying it to this node's engine.
+    pub fn propose(&self, command: Command) -> Result<()> {
+        let (index, done) = {
+            let mut state = self.state.lock();
+            if state.role != Role::Leader {
+                return Err(self.not_leader(&state));
+            }
+            let (waiter, done) = channel::bounded(1);
+            let index = state.last_log_index() + 1;
+            let term = state.hard.term;
+            state.waiters.insert(index, (term, waiter));
+            self.append(&mut state, command)?;
+            (index, done)
+        };
+        let _ = self.kick.send(());
+
+        match done.recv_timeout(PROPOSAL_TIMEOUT) {
+            Ok(result) => result.map_err(KvsError::StringError),
+            Err(RecvTimeoutError::Timeout) => {
+                self.state.lock().waiters.remove(&index);
+                Err(KvsError::StringError(
+                    "timed out waiting for a majority to store the write".to_owned(),
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(KvsError::StringError(
+                "lost leadership; the write may or may not be applied".to_owned(),
+            )),
+        }
+    }
+
+    fn majority(&self) -> usize {
+        let group_size = self.peers.len() + 1;
+        group_size / 2 + 1
+    }
+
+    fn not_leader(&self, state: &State) -> KvsError {
+        let leader_addr = state.leader.and_then(|leader| {
+            self.peers
+                .iter()
+                .find(|(peer, _)| peer.id == leader)
+                .map(|(peer, _)| peer.client_addr)
+        });
+        KvsError::NotLeader(leader_addr)
+    }
+
+    fn tick(self: Arc<Self>, kicked: Receiver<()>) {
+        while !self.stopped.load(Ordering::SeqCst) {
+            let kicked = kicked.recv_timeout(TICK).is_ok();
+            let (role, due) = {
+                let state = self.state.lock();
+                let due = match state.role {
+                    Role::Leader => kicked || state.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL,
+                    _ => Instant::now() >= state.election_deadline,
+                };
+                (state.role, due)
+            };
+            let result = match role {
+                _ if !due => Ok(()),
+                Role::Leader => self.replicate(),
+                Role::Follower | Role::Candidate => self.campaign(),
+            };
+            if let Err(err) = result {
+                error!(&self.log, "raft tick failed with error {}", err);
+            }
+        }
+    }
+
+    // Send a message to every peer at once, returning their replies in the order of `peers`.
+    fn call_peers(&self, messages: &[Message]) -> Vec<Result<Reply>> {
+        thread::scope(|scope| {
+            let calls: Vec<_> = self
+                .peers
+                .iter()
+                .zip(messages)
+                .map(|((_, client), message)| scope.spawn(move || client.call(message)))
+                .collect();
+            calls
+                .into_iter()
+                .map(|call| call.join().expect("raft peer call panicked"))
+                .collect()
+        })
+    }
+
+    fn campaign(&self) -> Result<()> {
+        let request = {
+            let mut state = self.state.lock();
+            state.role = Role::Candidate;
+            state.hard.term += 1;
+            state.hard.voted_for = Some(self.id);
+            state.storage.save_state(&state.hard)?;
+            state.leader = None;
+            state.election_deadline = Instant::now() + random_election_timeout();
+            info!(&self.log, "starting election"; "term" => state.hard.term);
+            VoteRequest {
+                term: state.hard.term,
+                candidate_id: self.id,
+                last_log_index: state.last_log_index(),
+                last_log_term: state.last_log_term(),
+            }
+        };
+        let messages: Vec<Message> = self
+            .peers
+            .iter()
+            .map(|_| Message::RequestVote(request.clone()))
+            .collect();
+        let replies = self.call_peers(&messages);
+
+        let mut state = self.state.lock();
+        if state.hard.term != request.term || state.role != Role::Candidate {
+            return Ok(());
+        }
+        let mut votes = 1;
+        for reply in replies {
+            if let Ok(Reply::RequestVote(reply)) = reply {
+                if reply.term > state.hard.term {
+                    return self.step_down(&mut state, reply.term);
+                }
+                if reply.vote_granted {
+                    votes += 1;
+                }
+            }
+        }
+        if votes >= self.majority() {
+            self.become_leader(&mut state)?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&self, state: &mut MutexGuard<State>) -> Result<()> {
+        info!(&self.log, "elected leader"; "term" => state.hard.term);
+        state.role = Role::Leader;
+        state.leader = Some(self.id);
+        let next_index = state.last_log_index() + 1;
+        for (peer, _) in &self.peers {
+            state.next_index.insert(peer.id, next_index);
+            state.match_index.insert(peer.id, 0);
+        }
+        // Entries from earlier terms only commit along with one from the current term,
+        // so start the term with an entry that changes nothing.
+        self.append(state, Command::Batch(Vec::new()))?;
+        let _ = self.kick.send(());
+        Ok(())
+    }
+
+    fn append(&self, state: &mut MutexGuard<State>, command: Command) -> Result<()> {
+        let entry = Entry {
+            term: state.hard.term,
+            command,
+        };
+        state.storage.append(std::slice::from_ref(&entry))?;
+        state.log.push(entry);
+        // Without peers, the entry is already stored by a majority.
+        self.advance_commit(state);
+        Ok(())
+    }
+
+    fn replicate(&self) -> Result<()> {
+        let (term, messages) = {
+            let mut state = self.state.lock();
+            state.last_heartbeat = Instant::now();
+            let messages: Vec<Message> = self
+                .peers
+                .iter()
+                .map(|(peer, _)| {
+                    let prev_log_index = state.next_index[&peer.id] - 1;
+                    let start = prev_log_index as usize;
+                    let end = state.log.len().min(start + MAX_ENTRIES_PER_APPEND);
+                    Message::AppendEntries(AppendRequest {
+                        term: state.hard.term,
+                        leader_id: self.id,
+                        prev_log_index,
+                        prev_log_term: state.term_at(prev_log_index),
+                        entries: state.log[start..end].to_vec(),
+                        leader_commit: state.commit_index,
+                    })
+                })
+                .collect();
+            (state.hard.term, messages)
+        };
+        let replies = self.call_peers(&messages);
+
+        let mut state = self.state.lock();
+        if state.hard.term != term || state.role != Role::Leader {
+            return Ok(());
+        }
+        for (((peer, _), message), reply) in self.peers.iter().zip(&messages).zip(replies) {
+            let (sent, reply) = match (message, reply) {
+                (Message::AppendEntries(sent), Ok(Reply::AppendEntries(reply))) => (sent, reply),
+                // The peer is unreachable; it gets the same entries with the next heartbeat.
+                _ => continue,
+            };
+            if reply.term > state.hard.term {
+                return self.step_down(&mut state, reply.term);
+            }
+            if reply.success {
+                let matched = sent.prev_log_index + sent.entries.len() as u64;
+                state.match_index.insert(peer.id, matched);
+                state.next_index.insert(peer.id, matched + 1);
+            } else {
+                let next_index = (reply.last_log_index + 1).clamp(1, sent.prev_log_index.max(1));
+                state.next_index.insert(peer.id, next_index);
+            }
+        }
+        self.advance_commit(&mut state);
+
+        // Keep going without waiting for a heartbeat while a peer is behind.
+        let last_log_index = state.last_log_index();
+        if state
+            .next_index
+            .values()
+            .any(|&next| next <= last_log_index)
+        {
+            let _ = self.kick.send(());
+        }
+        Ok(())
+    }
+
+    // Commit the latest entry of the current term stored by a majority, and everything before it.
+    fn advance_commit(&self, state: &mut MutexGuard<State>) {
+        let majority = self.majority();
+        let mut index = state.last_log_index();
+        while index > state.commit_index && state.term_at(index) == state.hard.term {
+            let replicas = 1 + state
+                .match_index
+                .values()
+                .filter(|&&matched| matched >= index)
+                .count();
+            if replicas >= majority {
+                state.commit_index = index;
+                let _ = self.committed.send(());
+                break;
+            }
+            index -= 1;
+        }
+    }
+
+    // Apply entries as they're committed, until the node stops.
+    fn apply_committed(self: Arc<Self>, commits: Receiver<()>) {
+        while !self.stopped.load(Ordering::SeqCst) {
+            let _ = commits.recv_timeout(TICK);
+            if let Err(err) = self.apply_batch() {
+                error!(&self.log, "raft apply failed with error {}", err);
+            }
+        }
+    }
+
+    // Apply the entries committed since the last batch and tell their proposers how it went.
+    // The node's lock is held only to take the entries, so elections and replication carry
+    // on while the engine writes.
+    fn apply_batch(&self) -> Result<()> {
+        let mut applied = self.applied.lock();
+        let batch: Vec<(Entry, Option<Waiter>)> = {
+            let mut state = self.state.lock();
+            (applied.index + 1..=state.commit_index)
+                .map(|index| {
+                    let entry = state.log[index as usize - 1].clone();
+                    let waiter = match state.waiters.remove(&index) {
+                        Some((term, waiter)) if term == entry.term => Some(waiter),
+                        _ => None,
+                    };
+                    (entry, waiter)
+                })
+                .collect()
+        };
+        if batch.is_empty() {
+            return Ok(());
+        }
+        for (entry, waiter) in batch {
+            let result = apply(&applied.engine, entry.command).map_err(|err| err.to_string());
+            // Only the proposer hears about a failed command; for others it's expected, such
+            // as removing a key that was already removed.
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(result);
+            }
+            applied.index += 1;
+        }
+        applied.saved.save(applied.index)
+    }
+
+    fn step_down(&self, state: &mut MutexGuard<State>, term: u64) -> Result<()> {
+        if term > state.hard.term {
+            state.hard.term = term;
+            state.hard.voted_for = None;
+            state.storage.save_state(&state.hard)?;
+        }
+        if state.role == Role::Leader {
+            info!(&self.log, "stepping down"; "term" => term);
+            // Dropping the waiters tells their proposers the outcome is unknown.
+            state.waiters.clear();
+        }
+        if state.role != Role::Follower {
+            state.role = Role::Follower;
+            state.election_deadline = Instant::now() + random_election_timeout();
+        }
+        Ok(())
+    }
+
+    fn listen(self: Arc<Self>, listener: TcpListener) {
+        for result in listener.incoming() {
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let node = self.clone();
+            let spawned = result.and_then(|stream| {
+                thread::Builder::new().spawn(move || {
+                    if let Err(err) = node.serve_peer(stream) {
+                        error!(&node.log, "raft connection failed with error {}", err);
+                    }
+                })
+            });
+            if let Err(err) = spawned {
+                error!(&self.log, "raft accept failed with error {}", err);
+            }
+        }
+    }
+
+    fn serve_peer(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = Deserializer::new(BufReader::new(stream.try_clone()?));
+        let mut writer = BufWriter::new(stream);
+        loop {
+            let message = match Message::deserialize(&mut reader) {
+                Ok(message) => message,
+                Err(decode::Error::InvalidMarkerRead(err))
+                    if err.kind() == io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if self.stopped.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let reply = match message {
+                Message::RequestVote(request) => Reply::RequestVote(self.handle_vote(request)?),
+                Message::AppendEntries(request) => {
+                    Reply::AppendEntries(self.handle_append(request)?)
+                }
+            };
+            reply.serialize(&mut Serializer::new(&mut writer))?;
+            writer.flush()?;
+        }
+    }
+
+    fn handle_vote(&self, request: VoteRequest) -> Result<VoteReply> {
+        let mut state = self.state.lock();
+        if request.term > state.hard.term {
+            self.step_down(&mut state, request.term)?;
+        }
+        let up_to_date = (request.last_log_term, request.last_log_index)
+            >= (state.last_log_term(), state.last_log_index());
+        let vote_granted = request.term == state.hard.term
+            && state
+                .hard
+                .voted_for
+                .is_none_or(|id| id == request.candidate_id)
+            && up_to_date;
+        if vote_granted {
+            state.hard.voted_for = Some(request.candidate_id);
+            state.storage.save_state(&state.hard)?;
+            state.election_deadline = Instant::now() + random_election_timeout();
+        }
+        Ok(VoteReply {
+            term: state.hard.term,
+            vote_granted,
+        })
+    }
+
+    fn handle_append(&self, request: AppendRequest) -> Result<AppendReply> {
+        let mut state = self.state.lock();
+        if request.term < state.hard.term {
+            return Ok(AppendReply {
+                term: state.hard.term,
+                success: false,
+                last_log_index: state.last_log_index(),
+            });
+        }
+        if request.term > state.hard.term || state.role != Role::Follower {
+            self.step_down(&mut state, request.term)?;
+        }
+        state.leader = Some(request.leader_id);
+        state.election_deadline = Instant::now() + random_election_timeout();
+
+        if request.prev_log_index > state.last_log_index()
+            || state.term_at(request.prev_log_index) != request.prev_log_term
+        {
+            return Ok(AppendReply {
+                term: state.hard.term,
+                success: false,
+                last_log_index: state
+                    .last_log_index()
+                    .min(request.prev_log_index.saturating_sub(1)),
+            });
+        }
+
+        // Skip entries this node already has, and drop its own from the first that disagrees.
+        let last_new_index = request.prev_log_index + request.entries.len() as u64;
+        let mut index = request.prev_log_index;
+        let mut new_entries = Vec::new();
+        for entry in request.entries {
+            index += 1;
+            if new_entries.is_empty() && index <= state.last_log_index() {
+                if state.term_at(index) == entry.term {
+                    continue;
+                }
+                let state = &mut *state;
+                state.log.truncate(index as usize - 1);
+                state.storage.rewrite(&state.log)?;
+            }
+            new_entries.push(entry);
+        }
+        state.storage.append(&new_entries)?;
+        state.log.extend(new_entries);
+
+        if request.leader_commit > state.commit_index {
+            state.commit_index = request
+                .leader_commit
+                .min(last_new_index)
+                .max(state.commit_index);
+            let _ = self.committed.send(());
+        }
+        Ok(AppendReply {
+            term: state.hard.term,
+            success: true,
+            last_log_index: state.last_log_index(),
+        })
+    }
+}
+
+fn apply<E: KvsEngine>(engine: &E, command: Command) -> Result<()> {
+    match command {
+        Command::Batch(commands) => commands
+            .into_iter()
+            .try_for_each(|command| apply(engine, command)),
+        command => match command.change() {
+            Some(Change::Set(key, value, _)) => engine.set(key, value),
+            Some(Change::Remove(key, _)) => engine.remove(key),
+            None => Ok(()),
+        },
+    }
+}
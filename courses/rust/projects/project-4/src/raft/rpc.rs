@@ -0,0 +1,103 @@
+use super::storage::Entry;
+use crate::sync::Mutex;
+use crate::Result;
+use rmp_serde::decode::Deserializer;
+use rmp_serde::decode::ReadReader;
+use rmp_serde::encode::Serializer;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(100);
+const IO_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct VoteRequest {
+    pub term: u64,
+    pub candidate_id: u64,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct VoteReply {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct AppendRequest {
+    pub term: u64,
+    pub leader_id: u64,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<Entry>,
+    pub leader_commit: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct AppendReply {
+    pub term: u64,
+    pub success: bool,
+    /// Index of the follower's last entry, so a leader can skip back past a mismatch at once.
+    pub last_log_index: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) enum Message {
+    RequestVote(VoteRequest),
+    AppendEntries(AppendRequest),
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) enum Reply {
+    RequestVote(VoteReply),
+    AppendEntries(AppendReply),
+}
+
+struct Connection {
+    reader: Deserializer<ReadReader<BufReader<TcpStream>>>,
+    writer: Serializer<BufWriter<TcpStream>>,
+}
+
+/// A connection to a peer's Raft node, opened on first use and reopened after a failure.
+pub(crate) struct PeerClient {
+    addr: SocketAddr,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl PeerClient {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            connection: Mutex::new(None),
+        }
+    }
+
+    pub fn call(&self, message: &Message) -> Result<Reply> {
+        let mut slot = self.connection.lock();
+        let mut connection = match slot.take() {
+            Some(connection) => connection,
+            None => {
+                let stream = TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT)?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                Connection {
+                    reader: Deserializer::new(BufReader::new(stream.try_clone()?)),
+                    writer: Serializer::new(BufWriter::new(stream)),
+                }
+            }
+        };
+        message.serialize(&mut connection.writer)?;
+        connection.writer.get_mut().flush()?;
+        let reply = Reply::deserialize(&mut connection.reader)?;
+        *slot = Some(connection);
+        Ok(reply)
+    }
+}
@@ -0,0 +1,178 @@
+use crate::data_dir;
+use crate::engines::Command;
+use crate::KvsError;
+use crate::Result;
+use rmp_serde::decode;
+use rmp_serde::Deserializer;
+use rmp_serde::Serializer;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+const STATE_FILE: &str = "state";
+const LOG_FILE: &str = "log";
+const APPLIED_FILE: &str = "applied";
+
+/// An entry of the replicated log.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct Entry {
+    /// Term of the leader that appended the entry.
+    pub term: u64,
+    pub command: Command,
+}
+
+/// The part of a node's state that must survive a restart for elections to stay safe.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub(crate) struct HardState {
+    pub term: u64,
+    pub voted_for: Option<u64>,
+}
+
+/// Keeps a node's hard state and log in a directory of their own.
+pub(crate) struct Storage {
+    dir: PathBuf,
+    log: BufWriter<File>,
+}
+
+impl Storage {
+    /// Open the storage in `dir`, returning what it holds.
+    pub fn open(dir: PathBuf) -> Result<(Self, HardState, Vec<Entry>)> {
+        fs::create_dir_all(&dir)?;
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(bytes) => rmp_serde::from_slice(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let log_path = dir.join(LOG_FILE);
+        let (entries, complete) = read_entries(&log_path)?;
+        let log = open_log(&log_path)?;
+        // The log may have just been created.
+        data_dir::sync_dir(&dir)?;
+        let mut storage = Self { dir, log };
+        if !complete {
+            // A crash tore the last entry. It was never acknowledged, so drop it.
+            storage.rewrite(&entries)?;
+        }
+        Ok((storage, state, entries))
+    }
+
+    /// Replace the hard state, durably, so a vote or term is never forgotten once it's
+    /// been acted on.
+    pub fn save_state(&self, state: &HardState) -> Result<()> {
+        write_durably(&self.dir, STATE_FILE, &rmp_serde::to_vec(state)?)
+    }
+
+    /// Add entries to the end of the log, durably, since they're acknowledged once this
+    /// returns.
+    pub fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        for entry in entries {
+            entry.serialize(&mut Serializer::new(&mut self.log))?;
+        }
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Replace the whole log, durably, after a leader overwrote some of its entries.
+    pub fn rewrite(&mut self, entries: &[Entry]) -> Result<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", LOG_FILE));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for entry in entries {
+            entry.serialize(&mut Serializer::new(&mut writer))?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        let log_path = self.dir.join(LOG_FILE);
+        fs::rename(tmp_path, &log_path)?;
+        data_dir::sync_dir(&self.dir)?;
+        self.log = open_log(&log_path)?;
+        Ok(())
+    }
+}
+
+/// Keeps the index of the last entry applied to the engine, apart from the rest of the
+/// storage so it's saved by whoever applies entries rather than under the node's lock.
+pub(crate) struct AppliedIndex {
+    dir: PathBuf,
+}
+
+impl AppliedIndex {
+    /// Open the applied index in `dir`, returning it: 0 if nothing was applied yet.
+    pub fn open(dir: PathBuf) -> Result<(Self, u64)> {
+        let index = match fs::read(dir.join(APPLIED_FILE)) {
+            Ok(bytes) => rmp_serde::from_slice(&bytes)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        Ok((Self { dir }, index))
+    }
+
+    /// Replace the applied index, durably.
+    pub fn save(&self, index: u64) -> Result<()> {
+        write_durably(&self.dir, APPLIED_FILE, &rmp_serde::to_vec(&index)?)
+    }
+}
+
+// Replace the file `name` in `dir` with `bytes`, so that after a crash it holds either them
+// or what it held before.
+fn write_durably(dir: &Path, name: &str, bytes: &[u8]) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", name));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(tmp_path, dir.join(name))?;
+    data_dir::sync_dir(dir)
+}
+
+fn open_log(path: &Path) -> Result<BufWriter<File>> {
+    let file = File::options().create(true).append(true).open(path)?;
+    Ok(BufWriter::new(file))
+}
+
+// Read every whole entry of the log at `path`, and whether the log held nothing else.
+fn read_entries(path: &Path) -> Result<(Vec<Entry>, bool)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), true)),
+        Err(err) => return Err(err.into()),
+    };
+    let len = file.metadata()?.len();
+    let mut des = Deserializer::new(BufReader::new(file));
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        match Entry::deserialize(&mut des) {
+            Ok(entry) => entries.push(entry),
+            // Only the last entry can be torn, and it runs into the end of the log. Anything
+            // else is damage that dropping entries would hide.
+            Err(decode::Error::InvalidMarkerRead(err))
+            | Err(decode::Error::InvalidDataRead(err))
+                if err.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                return Ok((entries, false))
+            }
+            Err(err) => {
+                return Err(KvsError::StringError(format!(
+                    "raft log {} is corrupt at offset {}: {}",
+                    path.display(),
+                    offset,
+                    err
+                )))
+            }
+        }
+        offset = des.get_mut().stream_position()?;
+    }
+    Ok((entries, true))
+}
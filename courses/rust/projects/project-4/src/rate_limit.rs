@@ -0,0 +1,55 @@
+use crate::sync::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+// Buckets are only dropped once there are this many, so a few clients never pay for it.
+const PRUNE_AT: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// A token bucket for each peer IP address. A bucket holds up to `burst` tokens, refills
+/// at `rate` tokens a second, and each request takes one.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            burst: f64::from(burst),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from `ip`'s bucket. Return false if it has none left.
+    pub fn admit(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= PRUNE_AT {
+            // A bucket that has refilled is no different from a new one.
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            refilled: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.refilled = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
@@ -0,0 +1,81 @@
+use crate::sync::Condvar;
+use crate::sync::Mutex;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::time::Duration;
+
+// Changes kept for replicas that are behind. A replica further behind is sent the whole
+// store again.
+const BACKLOG: usize = 100_000;
+
+struct Backlog {
+    keys: VecDeque<String>,
+    // The position of the first change kept.
+    first: u64,
+}
+
+/// The keys a primary's writes have changed, in order, for its replicas to be sent. A
+/// change is known by its position, counting from 0 for the first made since the log was
+/// created, and the log by an id of its own, so positions in another are told apart.
+pub(crate) struct ChangeLog {
+    id: u64,
+    backlog: Mutex<Backlog>,
+    // Notified when a change is appended, to wake the shippers waiting for one.
+    appended: Condvar,
+}
+
+impl ChangeLog {
+    pub fn new() -> Self {
+        Self {
+            id: RandomState::new().build_hasher().finish(),
+            backlog: Mutex::new(Backlog {
+                keys: VecDeque::new(),
+                first: 0,
+            }),
+            appended: Condvar::new(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Record a change to `key`, dropping the oldest change kept if the backlog is full.
+    pub fn append(&self, key: &str) {
+        let mut backlog = self.backlog.lock();
+        backlog.keys.push_back(key.to_owned());
+        if backlog.keys.len() > BACKLOG {
+            backlog.keys.pop_front();
+            backlog.first += 1;
+        }
+        self.appended.notify_all();
+    }
+
+    /// The position the next change will have.
+    pub fn head(&self) -> u64 {
+        let backlog = self.backlog.lock();
+        backlog.first + backlog.keys.len() as u64
+    }
+
+    /// Whether the changes from `position` on are all kept.
+    pub fn holds(&self, position: u64) -> bool {
+        let backlog = self.backlog.lock();
+        (backlog.first..=backlog.first + backlog.keys.len() as u64).contains(&position)
+    }
+
+    /// The keys of up to `max` changes from `position` on, waiting up to `timeout` for one
+    /// if there are none yet, or None if they aren't all kept.
+    pub fn read(&self, position: u64, max: usize, timeout: Duration) -> Option<Vec<String>> {
+        let mut backlog = self.backlog.lock();
+        if backlog.first + backlog.keys.len() as u64 == position {
+            backlog = self.appended.wait_timeout(backlog, timeout).0;
+        }
+        let skip = position.checked_sub(backlog.first)? as usize;
+        if skip > backlog.keys.len() {
+            return None;
+        }
+        Some(backlog.keys.range(skip..).take(max).cloned().collect())
+    }
+}
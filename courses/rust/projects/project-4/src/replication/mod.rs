@@ -0,0 +1,96 @@
+//! Asynchronous replication of a `KvsServer` to read-only replicas by log shipping.
+//!
+//! A primary, started with `KvsServer::with_replication_addr`, keeps a backlog of the keys
+//! its writes change, and listens for replicas on its replication address. A replica,
+//! started with `KvsServer::with_primary`, connects to it and is streamed each change in
+//! turn, with the value the key holds on the primary as it's sent, which it applies to its
+//! own engine. A replica that starts anew, or falls further behind than the backlog goes,
+//! is sent the primary's whole store first. Replicas serve reads, subscriptions and stats,
+//! and refuse writes with `KvsError::ReadOnly`.
+//!
+//! A primary with an ACL ships its changes only to replicas whose token, given with
+//! `KvsServer::with_replication_token`, it grants every key; see `kvs::acl`. Others are
+//! turned away, and keep trying to follow.
+//!
+//! Shipping is asynchronous: a write is answered once the primary has made it, so a replica
+//! may serve a value a little older than the primary's, and a write the primary answered
+//! may be lost with it. How far each replica is behind is reported in `ServerStats`. A
+//! replica keeps its place in the primary's changes only in memory, so once restarted it
+//! is sent the whole store again, as it is once the primary restarts. Versions and
+//! revisions are the replica's own.
+
+use serde::Deserialize;
+use serde::Serialize;
+use std::net::SocketAddr;
+
+mod changes;
+pub(crate) use self::changes::ChangeLog;
+
+mod primary;
+pub(crate) use self::primary::Primary;
+
+mod replica;
+pub(crate) use self::replica::Replica;
+
+/// A replica following a primary, as the primary reports it in `ServerStats`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaStatus {
+    /// The replica's address, as the primary sees it.
+    pub peer: String,
+    /// How many of the primary's changes the replica has caught up with.
+    pub position: u64,
+    /// Changes the primary has made that the replica hasn't applied yet.
+    pub lag: u64,
+}
+
+/// A replica's view of the primary it follows, as reported in `ServerStats`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FollowStatus {
+    /// The primary's replication address.
+    pub primary: SocketAddr,
+    /// Whether the replica is connected to the primary and has its whole store.
+    pub connected: bool,
+    /// How many of the primary's changes the replica has caught up with.
+    pub position: u64,
+    /// Changes the primary had made when last heard from that the replica hasn't applied.
+    pub lag: u64,
+    /// The number of times the replica has been sent the primary's whole store.
+    pub resyncs: u64,
+}
+
+// What a replica sends its primary.
+#[derive(Deserialize, Serialize, Debug)]
+enum FromReplica {
+    /// Start streaming the changes of the primary's log `log_id` from `position`, or the
+    /// whole store if those aren't in the backlog any more, or `log_id` isn't the primary's.
+    /// `token` names the replica to a primary with an ACL.
+    Follow {
+        log_id: Option<u64>,
+        position: u64,
+        token: Option<String>,
+    },
+    /// Every change before this position has been applied.
+    Ack(u64),
+}
+
+// What a primary sends its replica.
+#[derive(Deserialize, Serialize, Debug)]
+enum ToReplica {
+    /// The whole store follows, as of the change at `position` of the log `log_id`; keys
+    /// it doesn't hold are to be removed.
+    Resync { log_id: u64, position: u64 },
+    /// A key of the whole store, with its value.
+    Pair(String, String),
+    /// The whole store has been sent.
+    Resynced,
+    /// The change at `position`, which left `key` set to `value`, or unset if None.
+    Change {
+        position: u64,
+        key: String,
+        value: Option<String>,
+    },
+    /// The changes so far have been sent, of `head` made; answered with `Ack`.
+    Heartbeat { head: u64 },
+    /// The primary's ACL doesn't let the replica follow, for this reason. It hangs up.
+    Denied(String),
+}
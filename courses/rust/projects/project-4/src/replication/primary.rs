@@ -0,0 +1,265 @@
+use super::ChangeLog;
+use super::FromReplica;
+use super::ReplicaStatus;
+use super::ToReplica;
+use crate::acl::Acl;
+use crate::engines::KvsEngine;
+use crate::sync::Mutex;
+use crate::KvsError;
+use crate::Result;
+use rmp_serde::decode::ReadReader;
+use rmp_serde::Deserializer;
+use rmp_serde::Serializer;
+use serde::Deserialize;
+use serde::Serialize;
+use slog::error;
+use slog::info;
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How long a shipper waits for a change before sending a heartbeat anyway.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+// How long a replica may take to answer a heartbeat, or to take what it's sent.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_CHANGES_PER_HEARTBEAT: usize = 256;
+// Keys read from the engine at a time when sending the whole store.
+const RESYNC_PAGE: usize = 1000;
+
+type Reader = Deserializer<ReadReader<BufReader<TcpStream>>>;
+
+/// A primary's shipping of its changes to the replicas that connect to it, each on a thread
+/// of its own.
+pub(crate) struct Primary {
+    changes: Arc<ChangeLog>,
+    addr: SocketAddr,
+    // The replicas following, by the id of their connection, with how many changes they
+    // have applied.
+    replicas: Mutex<BTreeMap<u64, (String, u64)>>,
+    last_id: AtomicU64,
+    stopped: AtomicBool,
+    // Who may follow, if not anyone.
+    acl: Option<Arc<Acl>>,
+    log: Logger,
+}
+
+impl Primary {
+    /// Start listening for replicas on `addr`, to ship them the changes of `engine` that
+    /// `changes` records, if `acl` lets them follow.
+    pub fn start<E: KvsEngine>(
+        addr: SocketAddr,
+        engine: E,
+        changes: Arc<ChangeLog>,
+        acl: Option<Arc<Acl>>,
+        log: Logger,
+    ) -> Result<Arc<Self>> {
+        let listener = TcpListener::bind(addr)?;
+        let primary = Arc::new(Self {
+            changes,
+            addr: listener.local_addr()?,
+            replicas: Mutex::new(BTreeMap::new()),
+            last_id: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+            acl,
+            log,
+        });
+        info!(&primary.log, "serving replicas"; "ip-port" => primary.addr.to_string());
+        let listening = primary.clone();
+        // Engines needn't be Sync, so each replica's shipper has a clone of its own.
+        thread::Builder::new().spawn(move || listening.listen(listener, engine))?;
+        Ok(primary)
+    }
+
+    /// Stop shipping changes, hanging up on every replica.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it sees the flag.
+        let _ = TcpStream::connect(self.addr);
+    }
+
+    /// The replicas following, in the order they connected.
+    pub fn replicas(&self) -> Vec<ReplicaStatus> {
+        let head = self.changes.head();
+        self.replicas
+            .lock()
+            .values()
+            .map(|(peer, position)| ReplicaStatus {
+                peer: peer.clone(),
+                position: *position,
+                lag: head.saturating_sub(*position),
+            })
+            .collect()
+    }
+
+    fn listen<E: KvsEngine>(self: Arc<Self>, listener: TcpListener, engine: E) {
+        for result in listener.incoming() {
+            if self.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let primary = self.clone();
+            let engine = engine.clone();
+            let spawned = result.and_then(|stream| {
+                thread::Builder::new().spawn(move || {
+                    if let Err(err) = primary.ship(&engine, stream) {
+                        error!(
+                            &primary.log,
+                            "replication connection failed with error {}", err
+                        );
+                    }
+                })
+            });
+            if let Err(err) = spawned {
+                error!(&self.log, "replication accept failed with error {}", err);
+            }
+        }
+    }
+
+    // Ship changes to the replica on `stream` until it or the primary goes away.
+    fn ship<E: KvsEngine>(&self, engine: &E, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let peer = stream.peer_addr()?.to_string();
+        let mut reader = Deserializer::new(BufReader::new(stream.try_clone()?));
+        let mut writer = BufWriter::new(stream);
+        let (log_id, position, token) = match FromReplica::deserialize(&mut reader)? {
+            FromReplica::Follow {
+                log_id,
+                position,
+                token,
+            } => (log_id, position, token),
+            FromReplica::Ack(_) => {
+                return Err(KvsError::StringError(
+                    "a replica acknowledged changes before following".to_owned(),
+                ))
+            }
+        };
+        if self.stopped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        if let Some(acl) = &self.acl {
+            if let Err(err) = acl.check_replica(token.as_deref()) {
+                let reason = match &err {
+                    KvsError::PermissionDenied(reason) => reason.clone(),
+                    err => err.to_string(),
+                };
+                send(&mut writer, &ToReplica::Denied(reason))?;
+                writer.flush()?;
+                return Err(err);
+            }
+        }
+        let position = if log_id == Some(self.changes.id()) && self.changes.holds(position) {
+            position
+        } else {
+            self.resync(engine, &mut writer)?
+        };
+        info!(&self.log, "replica following"; "peer" => &peer, "position" => position);
+
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        self.replicas.lock().insert(id, (peer, position));
+        let result = self.stream(engine, id, position, &mut reader, &mut writer);
+        self.replicas.lock().remove(&id);
+        result
+    }
+
+    // Send the changes from `position` on as they're made, each followed by a heartbeat
+    // the replica acknowledges.
+    fn stream<E: KvsEngine>(
+        &self,
+        engine: &E,
+        id: u64,
+        mut position: u64,
+        reader: &mut Reader,
+        writer: &mut BufWriter<TcpStream>,
+    ) -> Result<()> {
+        while !self.stopped.load(Ordering::SeqCst) {
+            let keys =
+                match self
+                    .changes
+                    .read(position, MAX_CHANGES_PER_HEARTBEAT, HEARTBEAT_INTERVAL)
+                {
+                    Some(keys) => keys,
+                    None => {
+                        position = self.resync(engine, writer)?;
+                        continue;
+                    }
+                };
+            for key in keys {
+                // The value is read as it's sent, so however the key was changed, the
+                // replica ends up with what the primary holds.
+                let value = engine.get(key.clone())?;
+                send(
+                    writer,
+                    &ToReplica::Change {
+                        position,
+                        key,
+                        value,
+                    },
+                )?;
+                position += 1;
+            }
+            let head = self.changes.head();
+            send(writer, &ToReplica::Heartbeat { head })?;
+            writer.flush()?;
+            let acked = match FromReplica::deserialize(&mut *reader)? {
+                FromReplica::Ack(acked) => acked,
+                FromReplica::Follow { .. } => {
+                    return Err(KvsError::StringError(
+                        "a replica asked to follow twice".to_owned(),
+                    ))
+                }
+            };
+            if let Some((_, position)) = self.replicas.lock().get_mut(&id) {
+                *position = acked;
+            }
+        }
+        Ok(())
+    }
+
+    // Send the whole store, and return the position of the first change made since it was
+    // read, which the replica follows on from.
+    fn resync<E: KvsEngine>(&self, engine: &E, writer: &mut BufWriter<TcpStream>) -> Result<u64> {
+        let position = self.changes.head();
+        send(
+            writer,
+            &ToReplica::Resync {
+                log_id: self.changes.id(),
+                position,
+            },
+        )?;
+        let mut after = None;
+        loop {
+            let keys = engine.keys_after(after, RESYNC_PAGE)?;
+            after = match keys.last() {
+                Some(last) => Some(last.clone()),
+                None => break,
+            };
+            let values = engine.get_many(keys.clone())?;
+            for (key, value) in keys.into_iter().zip(values) {
+                // A key removed since the page was listed is sent as a change later.
+                if let Some(value) = value {
+                    send(writer, &ToReplica::Pair(key, value))?;
+                }
+            }
+        }
+        send(writer, &ToReplica::Resynced)?;
+        writer.flush()?;
+        Ok(position)
+    }
+}
+
+fn send(writer: &mut BufWriter<TcpStream>, message: &ToReplica) -> Result<()> {
+    message.serialize(&mut Serializer::new(writer))?;
+    Ok(())
+}
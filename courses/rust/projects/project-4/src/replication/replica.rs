@@ -0,0 +1,239 @@
+use super::FollowStatus;
+use super::FromReplica;
+use super::ToReplica;
+use crate::engines::KvsEngine;
+use crate::sync::Mutex;
+use crate::watch::ChangeOp;
+use crate::watch::Watchers;
+use crate::KvsError;
+use crate::Result;
+use rmp_serde::Deserializer;
+use rmp_serde::Serializer;
+use serde::Deserialize;
+use serde::Serialize;
+use slog::debug;
+use slog::info;
+use slog::warn;
+use slog::Logger;
+use std::collections::HashSet;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::mem;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+// How long the primary may go quiet before the replica gives up on the connection. It
+// sends heartbeats far more often.
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(5);
+// How long to wait between tries to reach the primary once it's lost.
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+struct Following {
+    // The log the replica has followed, once it has the primary's whole store.
+    log_id: Option<u64>,
+    position: u64,
+    // The primary's head as of its last heartbeat.
+    head: u64,
+    connected: bool,
+    resyncs: u64,
+    // The connection to the primary, to shut down once the replica stops.
+    stream: Option<TcpStream>,
+}
+
+/// A replica's following of its primary, on a thread of its own, applying the changes it's
+/// sent to the replica's engine.
+pub(crate) struct Replica {
+    primary: SocketAddr,
+    // Names the replica to a primary with an ACL.
+    token: Option<String>,
+    // The server's watchers, told of the changes applied like those of its own writes.
+    watchers: Arc<Watchers>,
+    state: Mutex<Following>,
+    stopped: AtomicBool,
+    log: Logger,
+}
+
+impl Replica {
+    /// Start following the primary whose replication address is `primary`, naming the
+    /// replica with `token` if given.
+    pub fn start<E: KvsEngine>(
+        primary: SocketAddr,
+        token: Option<String>,
+        engine: E,
+        watchers: Arc<Watchers>,
+        log: Logger,
+    ) -> Result<Arc<Self>> {
+        let replica = Arc::new(Self {
+            primary,
+            token,
+            watchers,
+            state: Mutex::new(Following::default()),
+            stopped: AtomicBool::new(false),
+            log,
+        });
+        let following = replica.clone();
+        // Engines needn't be Sync, so the engine is the following thread's alone.
+        thread::Builder::new().spawn(move || following.follow(&engine))?;
+        Ok(replica)
+    }
+
+    /// Stop following the primary.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(stream) = &self.state.lock().stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn status(&self) -> FollowStatus {
+        let state = self.state.lock();
+        FollowStatus {
+            primary: self.primary,
+            connected: state.connected,
+            position: state.position,
+            lag: state.head.saturating_sub(state.position),
+            resyncs: state.resyncs,
+        }
+    }
+
+    fn follow<E: KvsEngine>(&self, engine: &E) {
+        info!(&self.log, "following primary"; "ip-port" => self.primary.to_string());
+        while !self.stopped.load(Ordering::SeqCst) {
+            let result = self.follow_once(engine);
+            let was_connected = {
+                let mut state = self.state.lock();
+                state.stream = None;
+                mem::replace(&mut state.connected, false)
+            };
+            match result {
+                _ if self.stopped.load(Ordering::SeqCst) => break,
+                Err(err) if was_connected => {
+                    warn!(&self.log, "lost the primary"; "error" => err.to_string())
+                }
+                Err(KvsError::PermissionDenied(reason)) => {
+                    warn!(&self.log, "the primary won't be followed"; "reason" => reason)
+                }
+                Err(err) => {
+                    debug!(&self.log, "unable to follow the primary"; "error" => err.to_string())
+                }
+                Ok(()) => {}
+            }
+            thread::sleep(RECONNECT_BACKOFF);
+        }
+    }
+
+    // Connect to the primary and apply what it sends until the connection is lost.
+    fn follow_once<E: KvsEngine>(&self, engine: &E) -> Result<()> {
+        let stream = TcpStream::connect_timeout(&self.primary, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let (log_id, position) = {
+            let mut state = self.state.lock();
+            if self.stopped.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            state.stream = Some(stream.try_clone()?);
+            (state.log_id, state.position)
+        };
+        let mut reader = Deserializer::new(BufReader::new(stream.try_clone()?));
+        let mut writer = BufWriter::new(stream);
+        let token = self.token.clone();
+        send(
+            &mut writer,
+            &FromReplica::Follow {
+                log_id,
+                position,
+                token,
+            },
+        )?;
+        writer.flush()?;
+
+        // The log being resynced from, with the keys held before the resync that it hasn't
+        // sent yet, which are removed once it's done.
+        let mut resync: Option<(u64, HashSet<String>)> = None;
+        loop {
+            match ToReplica::deserialize(&mut reader)? {
+                ToReplica::Resync { log_id, position } => {
+                    info!(&self.log, "copying the primary's store"; "position" => position);
+                    {
+                        let mut state = self.state.lock();
+                        // Until it's done, the changes from the old position no longer
+                        // bring the engine up to date.
+                        state.log_id = None;
+                        state.position = position;
+                        state.head = position;
+                        state.connected = false;
+                    }
+                    resync = Some((log_id, engine.keys()?.into_iter().collect()));
+                }
+                ToReplica::Pair(key, value) => {
+                    if let Some((_, stale)) = &mut resync {
+                        stale.remove(&key);
+                    }
+                    self.apply(engine, key, Some(value))?;
+                }
+                ToReplica::Resynced => {
+                    if let Some((log_id, stale)) = resync.take() {
+                        for key in stale {
+                            self.apply(engine, key, None)?;
+                        }
+                        let mut state = self.state.lock();
+                        state.log_id = Some(log_id);
+                        state.resyncs += 1;
+                    }
+                }
+                ToReplica::Change {
+                    position,
+                    key,
+                    value,
+                } => {
+                    self.apply(engine, key, value)?;
+                    self.state.lock().position = position + 1;
+                }
+                ToReplica::Heartbeat { head } => {
+                    let position = {
+                        let mut state = self.state.lock();
+                        state.head = head;
+                        state.connected = true;
+                        state.position
+                    };
+                    send(&mut writer, &FromReplica::Ack(position))?;
+                    writer.flush()?;
+                }
+                ToReplica::Denied(reason) => return Err(KvsError::PermissionDenied(reason)),
+            }
+        }
+    }
+
+    // Leave `key` set to `value`, or unset if None, telling watchers if that changes it.
+    fn apply<E: KvsEngine>(&self, engine: &E, key: String, value: Option<String>) -> Result<()> {
+        if engine.get(key.clone())? == value {
+            return Ok(());
+        }
+        match value {
+            Some(value) => {
+                engine.set(key.clone(), value)?;
+                self.watchers.publish(&key, ChangeOp::Set);
+            }
+            None => {
+                engine.remove(key.clone())?;
+                self.watchers.publish(&key, ChangeOp::Remove);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn send(writer: &mut BufWriter<TcpStream>, message: &FromReplica) -> Result<()> {
+    message.serialize(&mut Serializer::new(writer))?;
+    Ok(())
+}
@@ -0,0 +1,156 @@
+//! The subset of the Redis serialization protocol (RESP) a server speaks with
+//! `Protocol::Resp`.
+//!
+//! Commands arrive as arrays of bulk strings, as Redis clients send them, or as inline
+//! commands, a line of words, as typed into a terminal. Replies are simple strings, errors,
+//! integers and bulk strings.
+
+use crate::KvsError;
+use crate::Result;
+use crate::SizeLimits;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+
+// Bounds on what a client can make the reader allocate. Buffers grow as bytes arrive, so
+// a length alone doesn't make it allocate anything.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+const MAX_BULK_LEN: usize = 64 * 1024 * 1024;
+// Arguments set aside for a command before any have arrived.
+const ARGS_CAPACITY: usize = 16;
+
+/// The longest bulk string worth reading under `limits`: keys and values can't be longer,
+/// and the other arguments of commands are short.
+pub(crate) fn max_bulk_len(limits: SizeLimits) -> usize {
+    let key = limits.max_key_bytes.unwrap_or(MAX_BULK_LEN);
+    let value = limits.max_value_bytes.unwrap_or(MAX_BULK_LEN);
+    key.max(value).max(MAX_LINE_LEN as usize).min(MAX_BULK_LEN)
+}
+
+/// A reply to a command.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+}
+
+impl Reply {
+    pub fn write(&self, writer: &mut impl Write) -> Result<()> {
+        match self {
+            Self::Status(status) => write!(writer, "+{}\r\n", status)?,
+            // A line break would end the error early.
+            Self::Error(msg) => write!(writer, "-{}\r\n", msg.replace(['\r', '\n'], " "))?,
+            Self::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Self::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Self::Bulk(Some(value)) => {
+                write!(writer, "${}\r\n", value.len())?;
+                writer.write_all(value.as_bytes())?;
+                writer.write_all(b"\r\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read a command: its name and arguments. Return `None` if the client hung up between
+/// commands, and `KvsError::StringError` if it broke the protocol, or sent a bulk string
+/// over `max_bulk_len`, after which nothing it sends can be trusted to line up.
+pub(crate) fn read_command(
+    reader: &mut impl BufRead,
+    max_bulk_len: usize,
+) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let Some(count) = line.strip_prefix(b"*") else {
+            let words: Vec<Vec<u8>> = line
+                .split(|byte| byte.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(|word| word.to_vec())
+                .collect();
+            // Blank lines are skipped, as Redis does.
+            if words.is_empty() {
+                continue;
+            }
+            return Ok(Some(words));
+        };
+        let count = parse_len(count, MAX_ARGS, "multibulk length")?;
+        let mut args = Vec::with_capacity(count.min(ARGS_CAPACITY));
+        for _ in 0..count {
+            args.push(read_bulk(reader, max_bulk_len)?);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+fn read_bulk(reader: &mut impl BufRead, max_len: usize) -> Result<Vec<u8>> {
+    let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+    let Some(len) = line.strip_prefix(b"$") else {
+        return Err(protocol_error(format!(
+            "expected '$', got '{}'",
+            String::from_utf8_lossy(&line[..line.len().min(1)])
+        )));
+    };
+    let len = parse_len(len, max_len, "bulk length")?;
+    let mut bulk = Vec::new();
+    reader
+        .by_ref()
+        .take(len as u64 + 2)
+        .read_to_end(&mut bulk)?;
+    if bulk.len() < len + 2 {
+        return Err(unexpected_eof());
+    }
+    if !bulk.ends_with(b"\r\n") {
+        return Err(protocol_error(
+            "bulk string not followed by CRLF".to_owned(),
+        ));
+    }
+    bulk.truncate(len);
+    Ok(bulk)
+}
+
+// Read a line, without its line break. Return `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(if line.len() as u64 + 1 >= MAX_LINE_LEN {
+            protocol_error("too big inline request".to_owned())
+        } else {
+            unexpected_eof()
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize, what: &str) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error(format!("invalid {}", what)))
+}
+
+fn protocol_error(msg: String) -> KvsError {
+    KvsError::StringError(format!("Protocol error: {}", msg))
+}
+
+fn unexpected_eof() -> KvsError {
+    std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()
+}
@@ -0,0 +1,98 @@
+//! Consistent hashing of keys onto a set of servers.
+//!
+//! Keys hash into a fixed number of slots, and slots are placed on a ring of servers, each
+//! of which owns many points on the ring. Adding or removing a server only moves the slots
+//! next to its points, about `1 / servers` of them, which `plan_moves` lists.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+/// Number of points each server gets on the ring. More points spread slots more evenly.
+const POINTS_PER_NODE: u32 = 64;
+
+/// Number of slots used when none is given.
+pub const DEFAULT_SLOT_COUNT: u32 = 1024;
+
+// FNV-1a, finished with the splitmix64 mixer so that similar inputs land far apart.
+// Slots must stay put across processes and Rust versions, so std's hashers won't do.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Return the slot `key` belongs to, out of `slot_count` slots.
+pub fn slot_for_key(key: &str, slot_count: u32) -> u32 {
+    (hash(key.as_bytes()) % u64::from(slot_count)) as u32
+}
+
+/// An assignment of slots to servers.
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    slot_count: u32,
+    points: BTreeMap<u64, SocketAddr>,
+}
+
+impl HashRing {
+    pub fn new(nodes: &[SocketAddr], slot_count: u32) -> Self {
+        let mut points = BTreeMap::new();
+        for &node in nodes {
+            for point in 0..POINTS_PER_NODE {
+                points.insert(hash(format!("{}#{}", node, point).as_bytes()), node);
+            }
+        }
+        Self { slot_count, points }
+    }
+
+    pub fn slot_count(&self) -> u32 {
+        self.slot_count
+    }
+
+    /// Return the server owning `slot`, or None if the ring has no servers.
+    pub fn node_for_slot(&self, slot: u32) -> Option<SocketAddr> {
+        let position = hash(&slot.to_le_bytes());
+        self.points
+            .range(position..)
+            .chain(self.points.iter())
+            .next()
+            .map(|(_, &node)| node)
+    }
+
+    /// Return the server owning `key`, or None if the ring has no servers.
+    pub fn node_for_key(&self, key: &str) -> Option<SocketAddr> {
+        self.node_for_slot(slot_for_key(key, self.slot_count))
+    }
+}
+
+/// A slot whose keys must move from one server to another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlotMove {
+    pub slot: u32,
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+}
+
+/// List the slots that change servers going from ring `old` to ring `new`.
+///
+/// # Panics
+///
+/// Panics if the rings have different slot counts, since every key would change slot.
+pub fn plan_moves(old: &HashRing, new: &HashRing) -> Vec<SlotMove> {
+    assert_eq!(
+        old.slot_count, new.slot_count,
+        "rings must have the same number of slots"
+    );
+    (0..old.slot_count)
+        .filter_map(
+            |slot| match (old.node_for_slot(slot), new.node_for_slot(slot)) {
+                (Some(from), Some(to)) if from != to => Some(SlotMove { slot, from, to }),
+                _ => None,
+            },
+        )
+        .collect()
+}
@@ -1,84 +1,2239 @@
+use crate::acl::Acl;
+use crate::batch::Batcher;
+use crate::cluster::Cluster;
+use crate::cluster::ClusterConfig;
+use crate::engines;
+use crate::engines::Command;
 use crate::engines::KvsEngine;
+use crate::engines::SizeLimits;
+use crate::engines::Txn;
+use crate::error::KvsError;
 use crate::error::Result;
+use crate::frame;
+use crate::frame::WireFormat;
+#[cfg(feature = "grpc")]
+use crate::grpc::GrpcListener;
+use crate::handshake::Capabilities;
+use crate::handshake::Hello;
+use crate::handshake::MAGIC;
+use crate::handshake::PROTOCOL_VERSION;
+use crate::http;
+use crate::http::HttpRequest;
+use crate::http::HttpResponse;
+use crate::lease::Leases;
+use crate::metrics;
+use crate::metrics::Metrics;
+use crate::protocol::ErrorCode;
+use crate::protocol::Pong;
 use crate::protocol::Request;
 use crate::protocol::Response;
+use crate::raft::RaftConfig;
+use crate::raft::RaftNode;
+use crate::rate_limit::RateLimiter;
+use crate::replication::ChangeLog;
+use crate::replication::Primary;
+use crate::replication::Replica;
+use crate::resp;
+use crate::resp::Reply;
+use crate::ring;
+use crate::sync::Mutex;
 use crate::thread_pool::NaiveThreadPool;
+use crate::thread_pool::RayonThreadPool;
+use crate::thread_pool::SharedQueueThreadPool;
 use crate::thread_pool::ThreadPool;
+use crate::thread_pool::ThreadPoolKind;
+use crate::trace::Span;
+use crate::transport;
+use crate::transport::Endpoint;
+use crate::transport::Listener;
+use crate::transport::Transport;
+use crate::watch::ChangeOp;
+use crate::watch::Wake;
+use crate::watch::Watchers;
+use crossbeam::channel;
+use crossbeam::channel::RecvTimeoutError;
+use rmp_serde::decode;
+use rmp_serde::decode::ReadReader;
 use rmp_serde::Deserializer;
 use rmp_serde::Serializer;
 use serde::Deserialize;
 use serde::Serialize;
 use slog::debug;
 use slog::error;
+use slog::info;
+use slog::o;
+use slog::warn;
+use slog::Discard;
 use slog::Logger;
+use std::collections::HashSet;
+use std::fmt;
+#[cfg(unix)]
+use std::fs;
+use std::io;
+use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
+use std::io::Read;
 use std::io::Write;
+use std::mem;
+use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::net::TcpListener;
 use std::net::TcpStream;
+#[cfg(unix)]
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+/// The protocol a server speaks with its clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    /// The protocol of `KvsClient`.
+    Kvs,
+    /// A subset of the Redis protocol, for Redis clients: the GET, SET, DEL, EXISTS and
+    /// PING commands.
+    Resp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kvs => write!(f, "kvs"),
+            Self::Resp => write!(f, "resp"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(Self::Kvs),
+            "resp" => Ok(Self::Resp),
+            _ => Err(KvsError::StringError(format!(
+                "invalid protocol {:?}; expected kvs or resp",
+                s
+            ))),
+        }
+    }
+}
 
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
     log: Logger,
+    metrics: Arc<Metrics>,
+    leases: Arc<Leases>,
+    metrics_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    #[cfg(feature = "grpc")]
+    grpc_addr: Option<SocketAddr>,
+    batch_window: Option<Duration>,
+    raft: Option<RaftConfig>,
+    replication_addr: Option<SocketAddr>,
+    primary: Option<SocketAddr>,
+    replication_token: Option<String>,
+    cluster: Option<ClusterConfig>,
+    thread_pool: (ThreadPoolKind, u32),
+    protocol: Protocol,
+    slow_request: Option<Duration>,
+    queue_limit: Option<usize>,
+    max_connections: Option<usize>,
+    idle_timeout: Option<Duration>,
+    size_limits: SizeLimits,
+    backpressure: Option<Duration>,
+    rate_limit: Option<(u32, u32)>,
+    acl: Option<Acl>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     pub fn new(engine: E, log: Logger) -> Self {
-        Self { engine, log }
+        Self {
+            engine,
+            log,
+            metrics: Arc::new(Metrics::default()),
+            leases: Arc::new(Leases::default()),
+            metrics_addr: None,
+            http_addr: None,
+            #[cfg(feature = "grpc")]
+            grpc_addr: None,
+            batch_window: None,
+            raft: None,
+            replication_addr: None,
+            primary: None,
+            replication_token: None,
+            cluster: None,
+            thread_pool: (ThreadPoolKind::Naive, 32),
+            protocol: Protocol::Kvs,
+            slow_request: None,
+            queue_limit: None,
+            max_connections: None,
+            idle_timeout: None,
+            size_limits: SizeLimits::default(),
+            backpressure: None,
+            rate_limit: None,
+            acl: None,
+        }
+    }
+
+    /// Also serve Prometheus-format metrics over HTTP on the given address.
+    pub fn with_metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Also serve the store over HTTP on the given address: `GET`, `PUT` and `DELETE` on
+    /// `/keys/{key}`, with values as request and response bodies, and `GET /keys`, which
+    /// lists the keys as a JSON array, optionally only those starting with `?prefix=`.
+    /// HTTP connections are served on the same pool as others, and count against the same
+    /// limits.
+    pub fn with_http_addr(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// Also serve the gRPC service of proto/kvs.proto on the given address; see
+    /// `kvs::grpc`.
+    #[cfg(feature = "grpc")]
+    pub fn with_grpc_addr(mut self, addr: SocketAddr) -> Self {
+        self.grpc_addr = Some(addr);
+        self
+    }
+
+    /// Group `set` requests arriving within `window` of each other into a single engine write.
+    /// Each set waits at most `window` longer, in exchange for far fewer flushes under load.
+    pub fn with_batch_window(mut self, window: Duration) -> Self {
+        self.batch_window = Some(window);
+        self
+    }
+
+    /// Replicate the engine across a Raft group; see `kvs::raft`. Only the group's leader
+    /// serves requests, other than stats. Sets are proposed one by one, so a batch window
+    /// has no effect, and transactions aren't supported.
+    pub fn with_raft(mut self, config: RaftConfig) -> Self {
+        self.raft = Some(config);
+        self
+    }
+
+    /// Ship every change to the replicas that connect on `addr`; see `kvs::replication`.
+    /// With an ACL, only replicas whose token it grants every key are shipped changes.
+    pub fn with_replication_addr(mut self, addr: SocketAddr) -> Self {
+        self.replication_addr = Some(addr);
+        self
+    }
+
+    /// Follow the primary whose replication address is `addr`, as a replica serving reads
+    /// only; see `kvs::replication`. Writes are refused with `KvsError::ReadOnly`.
+    pub fn with_primary(mut self, addr: SocketAddr) -> Self {
+        self.primary = Some(addr);
+        self
+    }
+
+    /// Name the replica to a primary with an ACL with `token`, which the primary's ACL must
+    /// grant every key in every bucket. Without one, the replica is held to the anonymous
+    /// grant of the primary's ACL.
+    pub fn with_replication_token(mut self, token: String) -> Self {
+        self.replication_token = Some(token);
+        self
+    }
+
+    /// Share out keys with the other members of a cluster, redirecting requests about keys
+    /// another member owns to it with `Response::Moved`; see `kvs::cluster`.
+    pub fn with_cluster(mut self, config: ClusterConfig) -> Self {
+        self.cluster = Some(config);
+        self
+    }
+
+    /// Serve connections on a pool of the given kind and size, rather than on a thread of
+    /// their own. Each connection holds a thread until its client hangs up, so a pool of
+    /// `threads` serves at most that many clients at once.
+    pub fn with_thread_pool(mut self, kind: ThreadPoolKind, threads: u32) -> Self {
+        self.thread_pool = (kind, threads);
+        self
+    }
+
+    /// Let at most `limit` connections wait for a thread of a shared-queue pool. Beyond
+    /// that, connections are turned away with `KvsError::ServerBusy` rather than queued.
+    /// Other pools don't queue connections, so this has no effect on them.
+    pub fn with_queue_limit(mut self, limit: usize) -> Self {
+        self.queue_limit = Some(limit);
+        self
+    }
+
+    /// Keep at most `limit` client connections open at once. Beyond that, connections are
+    /// turned away with `KvsError::ServerBusy`, whichever the pool.
+    pub fn with_max_connections(mut self, limit: usize) -> Self {
+        self.max_connections = Some(limit);
+        self
+    }
+
+    /// Close client connections that send nothing for `timeout`, so clients that went away
+    /// without hanging up don't hold threads of the pool forever. The client of a connection
+    /// closed this way sees it hung up, and reconnects if it retries.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Speak `protocol` with clients rather than the protocol of `KvsClient`.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Log a warning for every request taking `threshold` or longer to serve.
+    pub fn with_slow_request_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_request = Some(threshold);
+        self
+    }
+
+    /// Refuse requests with keys or values over `limits`, with `KvsError::TooLarge`. With
+    /// both limits set, a kvs-protocol request too large to hold the largest key and value
+    /// is refused as soon as it's read that far, and its connection closed, so a client
+    /// can't make the server buffer it. So is a RESP bulk string longer than both limits.
+    pub fn with_size_limits(mut self, limits: SizeLimits) -> Self {
+        self.size_limits = limits;
+        self
+    }
+
+    /// Answer writes made while the engine compacts with `Response::Busy`, asking the
+    /// client to retry after `retry_after`, rather than have them wait for the compaction
+    /// to finish. Only clients that want `Capabilities::BACKPRESSURE` are answered so;
+    /// others, and the HTTP and RESP protocols, wait as before.
+    pub fn with_compaction_backpressure(mut self, retry_after: Duration) -> Self {
+        self.backpressure = Some(retry_after);
+        self
+    }
+
+    /// Serve each client IP address at most `max_rps` kvs-protocol requests a second, after
+    /// a burst of up to `burst`. Requests over the limit aren't run, and are answered with
+    /// `KvsError::RateLimited`. Clients on a Unix socket aren't limited.
+    pub fn with_rate_limit(mut self, max_rps: u32, burst: u32) -> Self {
+        self.rate_limit = Some((max_rps, burst));
+        self
+    }
+
+    /// Serve only the requests `acl` allows, refusing others with
+    /// `KvsError::PermissionDenied`; see `kvs::acl`. Members of a cluster gossip with the
+    /// token of their `ClusterConfig`, which `acl` must grant the admin role, and replicas
+    /// follow only if it grants their token every key; see `with_replication_token`.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
     }
 
     pub fn serve(&mut self, addr: &SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
-        let thread_pool = NaiveThreadPool::new(32)?;
-        for result in listener.incoming() {
-            let stream = result?;
+        self.run(listener, &AtomicBool::new(false))
+    }
+
+    /// Serve on the Unix domain socket at `path` rather than over TCP. Who may connect is
+    /// up to the socket's permissions.
+    #[cfg(unix)]
+    pub fn serve_unix(&mut self, path: &Path) -> Result<()> {
+        let listener = transport::bind_unix(path)?;
+        self.run(listener, &AtomicBool::new(false))
+    }
+
+    /// Serve on a background thread. Return a handle that stops the server, and the
+    /// address it is listening on, which is ready to accept connections.
+    pub fn spawn(self, addr: &SocketAddr) -> Result<(ServerHandle, SocketAddr)> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::Builder::new().spawn(move || self.run(listener, &shutdown))?
+        };
+        let handle = ServerHandle {
+            endpoint: Endpoint::Tcp(addr),
+            shutdown,
+            thread: Some(thread),
+        };
+        Ok((handle, addr))
+    }
+
+    /// Serve on the Unix domain socket at `path`, on a background thread. Return a handle
+    /// that stops the server, and removes the socket once it has.
+    #[cfg(unix)]
+    pub fn spawn_unix(self, path: &Path) -> Result<ServerHandle> {
+        let listener = transport::bind_unix(path)?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::Builder::new().spawn(move || self.run(listener, &shutdown))?
+        };
+        Ok(ServerHandle {
+            endpoint: Endpoint::Unix(path.to_owned()),
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// Serve `engine` on a background thread, on a free port of the loopback interface and
+    /// without logging. Meant for tests and examples.
+    pub fn spawn_local(engine: E) -> Result<(ServerHandle, SocketAddr)> {
+        Self::new(engine, Logger::root(Discard, o!())).spawn(&([127, 0, 0, 1], 0).into())
+    }
+
+    fn run(&self, listener: impl Listener, shutdown: &AtomicBool) -> Result<()> {
+        if let Some(metrics_addr) = self.metrics_addr {
+            let metrics_listener = TcpListener::bind(metrics_addr)?;
+            info!(&self.log, "serving metrics"; "ip-port" => metrics_addr.to_string());
+            let metrics = self.metrics.clone();
             let engine = self.engine.clone();
             let log = self.log.clone();
-            thread_pool.spawn(move || {
-                if let Err(err) = serve(&log, engine, stream) {
-                    error!(&log, "failed with error {}", err.to_string())
+            thread::Builder::new().spawn(move || {
+                metrics::serve_prometheus(metrics_listener, &metrics, engine, log)
+            })?;
+        }
+
+        let raft = match &self.raft {
+            Some(config) => Some(RaftNode::start(
+                config.clone(),
+                self.engine.clone(),
+                self.log.clone(),
+            )?),
+            None => None,
+        };
+        let batcher = match self.batch_window {
+            Some(window) if raft.is_none() => Some(Batcher::spawn(
+                self.engine.clone(),
+                window,
+                self.size_limits,
+                self.log.clone(),
+            )?),
+            _ => None,
+        };
+        let acl = self.acl.clone().map(Arc::new);
+        let (watchers, primary) = match self.replication_addr {
+            Some(addr) => {
+                let changes = Arc::new(ChangeLog::new());
+                let primary = Primary::start(
+                    addr,
+                    self.engine.clone(),
+                    changes.clone(),
+                    acl.clone(),
+                    self.log.clone(),
+                )?;
+                (Watchers::with_change_log(changes), Some(primary))
+            }
+            None => (Watchers::default(), None),
+        };
+        let watchers = Arc::new(watchers);
+        watchers.spawn_timer()?;
+        let replica = match self.primary {
+            Some(addr) => Some(Replica::start(
+                addr,
+                self.replication_token.clone(),
+                self.engine.clone(),
+                watchers.clone(),
+                self.log.clone(),
+            )?),
+            None => None,
+        };
+        let cluster = match &self.cluster {
+            Some(config) => Some(Cluster::start(config.clone(), self.log.clone())?),
+            None => None,
+        };
+        let services = Services {
+            engine: self.engine.clone(),
+            metrics: self.metrics.clone(),
+            leases: self.leases.clone(),
+            batcher,
+            raft,
+            primary,
+            replica,
+            cluster,
+            connections: Arc::new(AtomicU64::new(0)),
+            started: Instant::now(),
+            pings: Arc::new(AtomicU64::new(0)),
+            stalled: Arc::new(AtomicBool::new(false)),
+            watchers,
+            slow_request: self.slow_request,
+            idle_timeout: self.idle_timeout,
+            size_limits: self.size_limits,
+            backpressure: self.backpressure,
+            rate_limiter: self
+                .rate_limit
+                .map(|(max_rps, burst)| Arc::new(RateLimiter::new(max_rps, burst))),
+            acl,
+            requests: Arc::new(SharedQueueThreadPool::new(self.thread_pool.1)?),
+        };
+        let http_listener = match self.http_addr {
+            Some(http_addr) => {
+                let http_listener = TcpListener::bind(http_addr)?;
+                info!(&self.log, "serving http"; "ip-port" => http_addr.to_string());
+                Some(http_listener)
+            }
+            None => None,
+        };
+        #[cfg(feature = "grpc")]
+        let mut grpc = match self.grpc_addr {
+            Some(grpc_addr) => {
+                let services = services.clone();
+                let call = move |request| call(&services, request);
+                Some(GrpcListener::start(grpc_addr, call, self.log.clone())?)
+            }
+            None => None,
+        };
+
+        let (kind, threads) = self.thread_pool;
+        match kind {
+            ThreadPoolKind::Naive => self.accept(
+                NaiveThreadPool::new(threads)?,
+                listener,
+                http_listener,
+                shutdown,
+                &services,
+            )?,
+            ThreadPoolKind::SharedQueue => self.accept(
+                match self.queue_limit {
+                    Some(limit) => SharedQueueThreadPool::with_queue_limit(threads, limit)?,
+                    None => SharedQueueThreadPool::new(threads)?,
+                },
+                listener,
+                http_listener,
+                shutdown,
+                &services,
+            )?,
+            ThreadPoolKind::Rayon => self.accept(
+                RayonThreadPool::new(threads)?,
+                listener,
+                http_listener,
+                shutdown,
+                &services,
+            )?,
+        }
+        if let Some(raft) = &services.raft {
+            raft.stop();
+        }
+        if let Some(primary) = &services.primary {
+            primary.stop();
+        }
+        if let Some(replica) = &services.replica {
+            replica.stop();
+        }
+        if let Some(cluster) = &services.cluster {
+            cluster.stop();
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(grpc) = &mut grpc {
+            grpc.stop();
+        }
+        Ok(())
+    }
+
+    fn accept<P: ThreadPool + Send + Sync + 'static>(
+        &self,
+        thread_pool: P,
+        listener: impl Listener,
+        http_listener: Option<TcpListener>,
+        shutdown: &AtomicBool,
+        services: &Services<E>,
+    ) -> Result<()> {
+        let thread_pool = Arc::new(thread_pool);
+        let open = Arc::new(AtomicUsize::new(0));
+        if let Some(http_listener) = http_listener {
+            let thread_pool = thread_pool.clone();
+            let open = open.clone();
+            let limits = (self.max_connections, self.queue_limit);
+            let services = services.clone();
+            let log = self.log.clone();
+            thread::Builder::new().spawn(move || {
+                accept_http(http_listener, thread_pool, open, limits, services, log)
+            })?;
+        }
+        loop {
+            let result = listener.accept();
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = result?;
+            let log = services.connection_log(&self.log, &*stream);
+            let protocol = self.protocol;
+            let slot = match self.max_connections {
+                Some(limit) => match ConnectionSlot::take(&open, limit) {
+                    Some(slot) => Some(slot),
+                    None => {
+                        warn!(&log, "too many connections; turning the connection away"; "limit" => limit);
+                        turn_away(&log, services, protocol, stream);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            // Kept to turn the client away with, should the pool refuse the connection.
+            let spare = match self.queue_limit {
+                Some(_) => Some(stream.try_clone()?),
+                None => None,
+            };
+            let task = {
+                let services = services.clone();
+                let log = log.clone();
+                move || {
+                    // Given back when the connection closes, or if the pool refuses it.
+                    let _slot = slot;
+                    services.metrics.connection_opened();
+                    let result = match protocol {
+                        Protocol::Kvs => serve(&log, &services, stream),
+                        Protocol::Resp => serve_resp(&log, &services, stream),
+                    };
+                    if let Err(err) = result {
+                        error!(&log, "failed with error {}", err.to_string())
+                    }
+                    services.metrics.connection_closed();
                 }
-            })
+            };
+            if thread_pool.try_spawn(task).is_err() {
+                warn!(
+                    &log,
+                    "pool is full; turning the connection away";
+                    "queued" => thread_pool.queued_tasks(),
+                    "active" => thread_pool.active_workers()
+                );
+                let stream = spare.expect("only a bounded pool refuses tasks");
+                turn_away(&log, services, protocol, stream);
+            }
         }
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(log: &Logger, engine: E, mut stream: TcpStream) -> Result<()> {
-    let request = read_request(&mut stream)?;
-    debug!(&log, "request = {:?}", request);
-    let mut response = process_request(&engine, request);
-    debug!(&log, "response = {:?}", response);
-    respond(stream, &mut response)?;
+// A connection counted against the server's limit until it's dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    // Count a connection against `open`, unless `limit` are already open.
+    fn take(open: &Arc<AtomicUsize>, limit: usize) -> Option<Self> {
+        open.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+            (count < limit).then_some(count + 1)
+        })
+        .ok()?;
+        Some(Self(open.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Stops a server started with `KvsServer::spawn` when shut down or dropped.
+///
+/// The server stops accepting connections; connections it already accepted are served
+/// until their clients hang up.
+pub struct ServerHandle {
+    endpoint: Endpoint,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl ServerHandle {
+    /// Stop the server and wait for it to stop accepting connections. Return the error the
+    /// server failed with, if any.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it sees the flag. If the server has already failed,
+        // there's nobody listening, which is fine.
+        let _ = self.endpoint.connect(None);
+        let result = thread.join().expect("server thread panicked");
+        #[cfg(unix)]
+        if let Endpoint::Unix(path) = &self.endpoint {
+            let _ = fs::remove_file(path);
+        }
+        result
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+// What every connection of a server shares.
+#[derive(Clone)]
+struct Services<E: KvsEngine> {
+    engine: E,
+    metrics: Arc<Metrics>,
+    leases: Arc<Leases>,
+    batcher: Option<Batcher>,
+    raft: Option<Arc<RaftNode<E>>>,
+    // Ships changes to replicas, if the server is a primary.
+    primary: Option<Arc<Primary>>,
+    // Follows the primary, if the server is a replica.
+    replica: Option<Arc<Replica>>,
+    // The server's membership of a cluster, if it shares out keys with others.
+    cluster: Option<Arc<Cluster>>,
+    // The number of connections accepted so far, over every protocol.
+    connections: Arc<AtomicU64>,
+    started: Instant,
+    // The number of pings answered so far.
+    pings: Arc<AtomicU64>,
+    // Whether the engine was stalling writes when last looked at, to log when that changes.
+    stalled: Arc<AtomicBool>,
+    slow_request: Option<Duration>,
+    // How long a connection may go without sending anything before it's closed.
+    idle_timeout: Option<Duration>,
+    size_limits: SizeLimits,
+    // How long clients are asked to wait before retrying writes refused during compaction,
+    // if they're refused.
+    backpressure: Option<Duration>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    // Who may make which requests, if not everyone may make any.
+    acl: Option<Arc<Acl>>,
+    // Serves pipelined requests. It's apart from the pool serving connections, whose
+    // threads may all be held by connections waiting on it.
+    requests: Arc<SharedQueueThreadPool>,
+    // The connections subscribed to changes of keys.
+    watchers: Arc<Watchers>,
+}
+
+impl<E: KvsEngine> Services<E> {
+    // A child of `log` for a new connection, so its entries can be told from those of
+    // others.
+    fn connection_log(&self, log: &Logger, stream: &dyn Transport) -> Logger {
+        let id = self.connections.fetch_add(1, Ordering::SeqCst) + 1;
+        log.new(o!("conn" => id, "peer" => stream.peer()))
+    }
+
+    // Log how long the connection's `seq`th request took, as a warning if it was slow.
+    fn log_timing(&self, log: &Logger, seq: u64, name: &str, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        match self.slow_request {
+            Some(threshold) if elapsed >= threshold => {
+                warn!(log, "slow request"; "seq" => seq, "request" => name, "micros" => micros)
+            }
+            _ => debug!(log, "request served"; "seq" => seq, "request" => name, "micros" => micros),
+        }
+        self.log_stall(log);
+    }
+
+    // Log when the engine starts or stops stalling writes on its compaction debt.
+    fn log_stall(&self, log: &Logger) {
+        let state = self.engine.compaction_state();
+        if self.stalled.swap(state.stalled, Ordering::SeqCst) == state.stalled {
+            return;
+        }
+        if state.stalled {
+            warn!(log, "stalling writes until compaction catches up"; "dead-bytes" => state.dead_bytes);
+        } else {
+            info!(log, "no longer stalling writes"; "dead-bytes" => state.dead_bytes);
+        }
+    }
+
+    // The most bytes a kvs-protocol request may take, if both size limits are set.
+    fn request_budget(&self) -> Option<u64> {
+        match (
+            self.size_limits.max_key_bytes,
+            self.size_limits.max_value_bytes,
+        ) {
+            (Some(key), Some(value)) => Some((key + value) as u64 + REQUEST_OVERHEAD),
+            _ => None,
+        }
+    }
+
+    // The capabilities offered in the server's hello.
+    fn capabilities(&self) -> Capabilities {
+        let capabilities = Capabilities::CHECKSUMS
+            | Capabilities::COMPACT
+            | Capabilities::BUCKETS
+            | Capabilities::PIPELINING
+            | Capabilities::WATCH
+            | Capabilities::MULTI_GET
+            | Capabilities::JSON
+            | Capabilities::BINCODE
+            | Capabilities::KEYS
+            | Capabilities::PING
+            | Capabilities::VERSIONS
+            | Capabilities::GET_RANGE
+            | Capabilities::FIND_BY_VALUE
+            | Capabilities::WAIT_GET
+            | Capabilities::TRACING
+            | Capabilities::CLUSTER
+            | Capabilities::AUTH;
+        let capabilities = match self.backpressure {
+            Some(_) => capabilities | Capabilities::BACKPRESSURE,
+            None => capabilities,
+        };
+        if self.raft.is_some() {
+            capabilities
+        } else {
+            capabilities
+                | Capabilities::INCR
+                | Capabilities::RENAME
+                | Capabilities::APPEND
+                | Capabilities::LOCKS
+        }
+    }
+
+    // Return the refusal of a request from `peer` if it's over the rate limit.
+    fn rate_limit(&self, peer: Option<IpAddr>) -> Option<Response> {
+        match (&self.rate_limiter, peer) {
+            (Some(limiter), Some(ip)) if !limiter.admit(ip) => {
+                Some(error_response(KvsError::RateLimited))
+            }
+            _ => None,
+        }
+    }
+
+    // Take `token` as the one a connection's requests are made with, if the ACL knows it.
+    fn authenticate(&self, token: &str) -> Result<()> {
+        match &self.acl {
+            Some(acl) => acl.authenticate(token),
+            None => Ok(()),
+        }
+    }
+
+    // Return the refusal of a request made with `token`, or without one, if the ACL doesn't
+    // allow it.
+    fn check_access(&self, token: Option<&str>, request: &Request) -> Option<Response> {
+        let acl = self.acl.as_ref()?;
+        acl.check(token, request).err().map(error_response)
+    }
+
+    // Return `Response::Busy` for a write the engine shouldn't take while it compacts, if
+    // the client understands it. A write in a transaction is only made by `Commit`.
+    fn throttle(&self, wanted: Capabilities, request: &Request, in_txn: bool) -> Option<Response> {
+        let retry_after = self.backpressure?;
+        let writes = match request {
+            Request::Commit => true,
+            request => !in_txn && request.is_write(),
+        };
+        let throttled = writes
+            && wanted.contains(Capabilities::BACKPRESSURE)
+            && self.engine.compaction_state().running;
+        throttled.then_some(Response::Busy(retry_after))
+    }
+}
+
+fn serve<E: KvsEngine>(
+    log: &Logger,
+    services: &Services<E>,
+    stream: Box<dyn Transport>,
+) -> Result<()> {
+    // Connections are persistent: keep serving requests until the client hangs up.
+    // A transaction left open when the client hangs up is rolled back.
+    let mut txn = None;
+    let mut seq = 0;
+    // The token the connection's requests are made with, once the client sends one.
+    let mut token = None;
+    let budget = services.request_budget();
+    stream.set_read_timeout(services.idle_timeout)?;
+    let mut reader = request_reader(BufReader::new(stream.try_clone()?), budget);
+    let mut writer = BufWriter::new(stream);
+    let first = match reader.get_mut().inner.fill_buf() {
+        Ok(buf) => buf.first().copied(),
+        Err(err) if idled_out(&err) => {
+            debug!(&log, "closing idle connection");
+            return Ok(());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    // What the client wants of the server's capabilities.
+    let wanted = match first {
+        Some(byte) if byte == MAGIC[0] => {
+            let hello = Hello::read(reader.get_mut())?;
+            Hello::new(services.capabilities()).write(&mut writer)?;
+            writer.flush()?;
+            if hello.version != PROTOCOL_VERSION {
+                warn!(&log, "client speaks another protocol version"; "version" => hello.version);
+                return Ok(());
+            }
+            hello.capabilities
+        }
+        // A client from before the handshake, which sends requests straight away.
+        Some(_) => Capabilities::empty(),
+        None => return Ok(()),
+    };
+    let framing = frame::framing(wanted);
+    let peer = writer.get_ref().peer_ip();
+    // Shared with the pipelined requests being served, which answer as they finish.
+    let writer = Arc::new(Mutex::new(writer));
+    loop {
+        let request = match read_request(&mut reader, framing) {
+            Ok(request) => request,
+            Err(KvsError::IO(err))
+            | Err(KvsError::Decode(decode::Error::InvalidMarkerRead(err)))
+                if err.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                return Ok(());
+            }
+            Err(KvsError::IO(err))
+            | Err(KvsError::Decode(decode::Error::InvalidMarkerRead(err)))
+            | Err(KvsError::Decode(decode::Error::InvalidDataRead(err)))
+                if idled_out(&err) =>
+            {
+                debug!(&log, "closing idle connection");
+                return Ok(());
+            }
+            Err(KvsError::TransportCorruption) => {
+                // Nothing after a bad frame can be trusted to line up, so have the client
+                // send the request again on a new connection.
+                warn!(&log, "request frame failed its checksum");
+                let mut writer = writer.lock();
+                send(&mut *writer, framing, &Response::FrameCorrupted)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(_) if reader.get_ref().exhausted() => {
+                // The rest of the request is left unread, so nothing after it lines up.
+                let budget = budget.unwrap_or_default();
+                warn!(&log, "request too large"; "limit" => budget);
+                let err =
+                    KvsError::TooLarge(format!("request is over the limit of {} bytes", budget));
+                let mut writer = writer.lock();
+                send(&mut *writer, framing, &error_response(err))?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        seq += 1;
+        // Trace ids and pins go around whatever else wraps the request, nested however the
+        // client liked, and all come off here, so what they wrap is recognized below. It's
+        // served as it would be without them, in the span of the outermost trace id.
+        let (mut trace_id, mut pinned, mut request) = (None, false, request);
+        let request = loop {
+            request = match request {
+                Request::Traced(id, request) => {
+                    trace_id = trace_id.or(Some(id));
+                    *request
+                }
+                Request::Pinned(request) => {
+                    pinned = true;
+                    *request
+                }
+                request => break request,
+            };
+        };
+        // A pin keeps a request from being sent to the member owning its keys. Requests
+        // answered here wherever they're sent don't need it back, and a pipelined one keeps
+        // it inside its tag.
+        let request = match request {
+            request if !pinned => request,
+            request @ (Request::Stats | Request::Subscribe(_) | Request::Authenticate(_)) => {
+                request
+            }
+            Request::Tagged(id, request) => Request::Tagged(id, Box::new(Request::Pinned(request))),
+            request => Request::Pinned(Box::new(request)),
+        };
+        let span = Span::request(request.name(), seq, trace_id);
+        let _entered = span.enter();
+        // Tokens are secrets, so they're kept out of the log.
+        match &request {
+            Request::Authenticate(_) => debug!(&log, "request = Authenticate(..)"; "seq" => seq),
+            request => debug!(&log, "request = {:?}", request; "seq" => seq),
+        }
+        let refusal = services
+            .rate_limit(peer)
+            .or_else(|| services.check_access(token.as_deref(), &request));
+        if let Some(refusal) = refusal {
+            debug!(&log, "request refused: {:?}", refusal; "seq" => seq);
+            let response = match request {
+                Request::Tagged(id, _) => Response::Tagged(id, Box::new(refusal)),
+                _ => refusal,
+            };
+            let mut writer = writer.lock();
+            send(&mut *writer, framing, &response)?;
+            writer.flush()?;
+            continue;
+        }
+        if let Request::Authenticate(given) = request {
+            let response = match services.authenticate(&given) {
+                Ok(()) => {
+                    token = Some(given);
+                    Response::AuthenticateOk(())
+                }
+                Err(err) => error_response(err),
+            };
+            let mut writer = writer.lock();
+            send(&mut *writer, framing, &response)?;
+            writer.flush()?;
+            continue;
+        }
+        if let Request::Tagged(id, request) = request {
+            let refusal = match pipelining_refusal(&request, txn.is_some()) {
+                Some(msg) => Some(Response::Err(ErrorCode::Other, msg)),
+                None => services.throttle(wanted, &request, false),
+            };
+            match refusal {
+                Some(response) => {
+                    let response = Response::Tagged(id, Box::new(response));
+                    let mut writer = writer.lock();
+                    send(&mut *writer, framing, &response)?;
+                    writer.flush()?;
+                }
+                None => serve_tagged(log, services, &writer, framing, seq, id, *request),
+            }
+            continue;
+        }
+        if let Request::Subscribe(prefix) = request {
+            let refusal = match &services.raft {
+                // Changes are published where they're proposed, on the leader.
+                Some(raft) => raft.check_leader().err().map(error_response),
+                None => None,
+            };
+            let refusal = refusal.or_else(|| {
+                txn.is_some().then(|| {
+                    Response::Err(ErrorCode::Other, "a transaction is in progress".to_owned())
+                })
+            });
+            match refusal {
+                Some(response) => {
+                    let mut writer = writer.lock();
+                    send(&mut *writer, framing, &response)?;
+                    writer.flush()?;
+                    continue;
+                }
+                None => {
+                    let stream = reader.get_mut().inner.get_mut();
+                    return watch(log, services, &mut **stream, &writer, framing, prefix);
+                }
+            }
+        }
+        let name = request.name();
+        let start = Instant::now();
+        let mut writer = writer.lock();
+        let failed = match request {
+            Request::Stats => write_stats(services, &mut *writer, framing)?,
+            request => {
+                let response = match services.throttle(wanted, &request, txn.is_some()) {
+                    Some(busy) => busy,
+                    None => process_request(services, &mut txn, request),
+                };
+                debug!(&log, "response = {:?}", response; "seq" => seq);
+                send(&mut *writer, framing, &response)?;
+                matches!(response, Response::Err(..))
+            }
+        };
+        span.record_failed(failed);
+        writer.flush()?;
+        drop(writer);
+        let elapsed = start.elapsed();
+        services.metrics.record_request(name, elapsed, failed);
+        services.log_timing(log, seq, name, elapsed);
+    }
+}
+
+// How often a subscribed connection with no changes to send checks whether its client has
+// hung up.
+const WATCH_POLL: Duration = Duration::from_secs(1);
+
+// Send the changes to keys starting with `prefix` until the client hangs up, or falls so
+// far behind that it has missed some.
+fn watch<E: KvsEngine>(
+    log: &Logger,
+    services: &Services<E>,
+    stream: &mut dyn Transport,
+    writer: &Mutex<BufWriter<Box<dyn Transport>>>,
+    framing: Option<WireFormat>,
+    prefix: String,
+) -> Result<()> {
+    debug!(log, "subscribed"; "prefix" => &prefix);
+    let changes = services.watchers.subscribe(prefix);
+    let reply = |response: &Response| -> Result<()> {
+        // Pipelined requests may still be answering.
+        let mut writer = writer.lock();
+        send(&mut *writer, framing, response)?;
+        Ok(writer.flush()?)
+    };
+    reply(&Response::SubscribeOk(()))?;
+    // The client sends nothing more, so reads only tell whether it has hung up.
+    stream.set_read_timeout(Some(Duration::from_millis(1)))?;
+    loop {
+        match changes.recv_timeout(WATCH_POLL) {
+            Ok(change) => reply(&Response::KeyChanged {
+                key: change.key,
+                op: change.op,
+            })?,
+            // Whatever the client sends ends the subscription, so it may as well be read.
+            Err(RecvTimeoutError::Timeout) => match stream.read(&mut [0]) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {
+                    warn!(log, "subscribed client sent a request; hanging up");
+                    return Ok(());
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(err) => return Err(err.into()),
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                warn!(log, "subscriber fell behind; hanging up");
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Why `request` can't be pipelined, if it can't: it depends on the connection's state, or
+// streams its response.
+fn pipelining_refusal(request: &Request, in_transaction: bool) -> Option<String> {
+    match request {
+        _ if in_transaction => Some("requests can't be pipelined in a transaction".to_owned()),
+        // Wrapped, a request is refused as it would be on its own, and wrapped twice the same
+        // way, it's refused outright.
+        Request::Traced(_, inner) | Request::Pinned(inner) | Request::InBucket(_, inner) => {
+            if mem::discriminant(request) == mem::discriminant(&**inner) {
+                Some(format!(
+                    "{} requests can't be wrapped twice alike",
+                    request.name()
+                ))
+            } else {
+                pipelining_refusal(inner, false)
+            }
+        }
+        Request::Begin
+        | Request::Commit
+        | Request::Rollback
+        | Request::Stats
+        | Request::Subscribe(_)
+        | Request::Authenticate(_) => {
+            Some(format!("{} requests can't be pipelined", request.name()))
+        }
+        Request::Tagged(_, _) => Some("pipelined requests can't be nested".to_owned()),
+        _ => None,
+    }
+}
+
+// Serve the connection's `seq`th request, pipelined with `id`, on the server's request
+// pool, so the connection goes on reading requests meanwhile. The response is sent once
+// it's ready, which may be before those of requests read earlier. It's served in the span
+// entered when it was read, which records how long it waited for a thread.
+fn serve_tagged<E: KvsEngine>(
+    log: &Logger,
+    services: &Services<E>,
+    writer: &Arc<Mutex<BufWriter<Box<dyn Transport>>>>,
+    framing: Option<WireFormat>,
+    seq: u64,
+    id: u64,
+    request: Request,
+) {
+    let name = request.name();
+    let start = Instant::now();
+    let span = Span::current();
+    let respond = {
+        let services = services.clone();
+        let log = log.clone();
+        let writer = writer.clone();
+        let span = span.clone();
+        move |response: Response| {
+            debug!(&log, "response = {:?}", response; "seq" => seq, "id" => id);
+            let failed = matches!(response, Response::Err(..));
+            span.record_failed(failed);
+            let response = Response::Tagged(id, Box::new(response));
+            let mut writer = writer.lock();
+            let result = send(&mut *writer, framing, &response).and_then(|()| Ok(writer.flush()?));
+            drop(writer);
+            if let Err(err) = result {
+                // The client hung up, and the connection's own loop will see that.
+                debug!(&log, "unable to send a pipelined response: {}", err; "seq" => seq);
+            }
+            let elapsed = start.elapsed();
+            services.metrics.record_request(name, elapsed, failed);
+            services.log_timing(&log, seq, name, elapsed);
+        }
+    };
+    let services = services.clone();
+    let pool = services.requests.clone();
+    match request {
+        // Parked until it's answered, rather than holding a thread of the pool for the
+        // whole wait. One made in a bucket waits on a thread of the pool like any request.
+        Request::WaitGet { key, timeout_ms }
+            if refusal(&services, &request).is_none() && moved(&services, &request).is_none() =>
+        {
+            let woken = services.clone();
+            let read = key.clone();
+            let span = span.clone();
+            let wake = Box::new(move || {
+                let pool = woken.requests.clone();
+                pool.spawn(move || {
+                    let response = span.in_scope(|| wait_get_response(&woken, read));
+                    respond(response)
+                });
+            });
+            park_wait_get(&services, key, timeout_ms, wake);
+        }
+        request => {
+            let span = span.clone();
+            pool.spawn(move || {
+                span.record_queued(start.elapsed());
+                let response = span.in_scope(|| process_request(&services, &mut None, request));
+                respond(response)
+            })
+        }
+    }
+}
+
+// Park a `wait_get` of `key` until the key is set or `timeout_ms` milliseconds pass, or wake
+// it now if the key is set already. Set before it was parked, the key would never be
+// published to it.
+fn park_wait_get<E: KvsEngine>(services: &Services<E>, key: String, timeout_ms: u64, wake: Wake) {
+    let timeout = Duration::from_millis(timeout_ms);
+    let id = services.watchers.park(key.clone(), timeout, wake);
+    // An error is the answer too, once the request reads the key again.
+    if !matches!(services.engine.contains(key), Ok(false)) {
+        if let Some(wake) = services.watchers.unpark(id) {
+            wake();
+        }
+    }
+}
+
+// Answer a woken `wait_get` of `key`: its value, or None if the wait timed out.
+fn wait_get_response<E: KvsEngine>(services: &Services<E>, key: String) -> Response {
+    match services.engine.get(key) {
+        Ok(value) => Response::WaitGetOk(value),
+        Err(err) => error_response(err),
+    }
+}
+
+// Room in a request's budget for everything but its key and value, such as its encoding and
+// the slots of a `SlotKeys` request.
+const REQUEST_OVERHEAD: u64 = 64 * 1024;
+
+// Reads the requests of a kvs-protocol connection, each within the connection's budget.
+pub(crate) type RequestReader<R> = Deserializer<ReadReader<BudgetReader<R>>>;
+
+pub(crate) fn request_reader<R: BufRead>(inner: R, budget: Option<u64>) -> RequestReader<R> {
+    Deserializer::new(BudgetReader::new(inner, budget))
+}
+
+// Read the next request of a connection framed as `framing`, starting its budget afresh.
+pub(crate) fn read_request<R: BufRead>(
+    reader: &mut RequestReader<R>,
+    framing: Option<WireFormat>,
+) -> Result<Request> {
+    reader.get_mut().reset();
+    match framing {
+        Some(format) => frame::read(reader.get_mut(), format),
+        None => Request::deserialize(reader).map_err(KvsError::from),
+    }
+}
+
+// Fails reads once a request has taken its budget of bytes, so that a client can't make the
+// server buffer a request too large to serve.
+pub(crate) struct BudgetReader<R> {
+    inner: R,
+    budget: Option<u64>,
+    left: u64,
+}
+
+impl<R> BudgetReader<R> {
+    fn new(inner: R, budget: Option<u64>) -> Self {
+        Self {
+            inner,
+            budget,
+            left: budget.unwrap_or(u64::MAX),
+        }
+    }
+
+    // Start the budget of the next request.
+    fn reset(&mut self) {
+        self.left = self.budget.unwrap_or(u64::MAX);
+    }
+
+    fn exhausted(&self) -> bool {
+        self.left == 0
+    }
+}
+
+impl<R: Read> Read for BudgetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.left == 0 {
+            return Err(io::Error::other("request over its size budget"));
+        }
+        let len = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..len])?;
+        self.left -= read as u64;
+        Ok(read)
+    }
+}
+
+// Whether a read failed for the connection's idle timeout running out.
+fn idled_out(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+// Tell a client the server can't take its connection, and hang up.
+fn turn_away<E: KvsEngine>(
+    log: &Logger,
+    services: &Services<E>,
+    protocol: Protocol,
+    stream: Box<dyn Transport>,
+) {
+    services.metrics.connection_rejected();
+    if let Err(err) = reject_busy(services, protocol, stream) {
+        debug!(log, "unable to tell the client the server is busy: {}", err);
+    }
+}
+
+// How long to wait for the hello of a client being turned away, which says how to encode the
+// reply. The wait holds up accepting other connections, which are likely turned away too.
+const BUSY_HELLO_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Tell a client the server is too busy to serve it, on the accepting thread, since the
+// pool has no room.
+fn reject_busy<E: KvsEngine>(
+    services: &Services<E>,
+    protocol: Protocol,
+    mut stream: Box<dyn Transport>,
+) -> Result<()> {
+    let msg = KvsError::ServerBusy.to_string();
+    if protocol == Protocol::Resp {
+        return Reply::Error(format!("ERR {}", msg)).write(&mut stream);
+    }
+    stream.set_read_timeout(Some(BUSY_HELLO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let framing = match reader.fill_buf()?.first() {
+        Some(&byte) if byte == MAGIC[0] => {
+            let hello = Hello::read(&mut reader)?;
+            Hello::new(services.capabilities()).write(&mut writer)?;
+            frame::framing(hello.capabilities)
+        }
+        Some(_) => None,
+        None => return Ok(()),
+    };
+    send(&mut writer, framing, &Response::Err(ErrorCode::Busy, msg))?;
+    writer.flush()?;
     Ok(())
 }
 
-fn read_request(stream: &mut TcpStream) -> Result<Request> {
-    let mut reader = Deserializer::new(BufReader::new(stream));
-    Ok(Request::deserialize(&mut reader)?)
+fn serve_resp<E: KvsEngine>(
+    log: &Logger,
+    services: &Services<E>,
+    stream: Box<dyn Transport>,
+) -> Result<()> {
+    let mut seq = 0;
+    stream.set_read_timeout(services.idle_timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let max_bulk_len = resp::max_bulk_len(services.size_limits);
+    loop {
+        let command = match resp::read_command(&mut reader, max_bulk_len) {
+            Ok(Some(command)) => command,
+            Ok(None) => return Ok(()),
+            Err(KvsError::IO(err)) if idled_out(&err) => {
+                debug!(&log, "closing idle connection");
+                return Ok(());
+            }
+            // The rest of the stream can't be parsed, so tell the client why and hang up.
+            Err(KvsError::StringError(msg)) => {
+                warn!(&log, "client broke the RESP protocol: {}", msg);
+                Reply::Error(format!("ERR {}", msg)).write(&mut writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        seq += 1;
+        let name = String::from_utf8_lossy(&command[0]).to_ascii_lowercase();
+        let start = Instant::now();
+        let reply = resp_reply(services, command);
+        debug!(&log, "reply = {:?}", reply; "seq" => seq);
+        reply.write(&mut writer)?;
+        writer.flush()?;
+        services.log_timing(log, seq, &name, start.elapsed());
+    }
+}
+
+// Run a RESP command as the requests it amounts to.
+fn resp_reply<E: KvsEngine>(services: &Services<E>, command: Vec<Vec<u8>>) -> Reply {
+    let mut args = Vec::with_capacity(command.len());
+    for arg in command {
+        match String::from_utf8(arg) {
+            Ok(arg) => args.push(arg),
+            Err(_) => return Reply::Error("ERR keys and values must be UTF-8".to_owned()),
+        }
+    }
+    let name = args.remove(0).to_ascii_lowercase();
+    let wrong_arity = || {
+        Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ))
+    };
+    match (name.as_str(), args.len()) {
+        ("ping", 0) => Reply::Status("PONG"),
+        ("ping", 1) => Reply::Bulk(args.pop()),
+        ("get", 1) => match call(services, Request::Get(args.pop().unwrap())) {
+            Response::GetOk(value) => Reply::Bulk(value),
+            response => resp_error(response),
+        },
+        ("set", 2) => {
+            let value = args.pop().unwrap();
+            match call(services, Request::Set(args.pop().unwrap(), value)) {
+                Response::SetOk(()) => Reply::Status("OK"),
+                response => resp_error(response),
+            }
+        }
+        ("set", n) if n > 2 => Reply::Error("ERR syntax error".to_owned()),
+        ("del", n) if n > 0 => {
+            let mut removed = 0;
+            for key in args {
+                match call(services, Request::Remove(key)) {
+                    Response::RemoveOk(()) => removed += 1,
+                    Response::Err(ErrorCode::KeyNotFound, _) => {}
+                    response => return resp_error(response),
+                }
+            }
+            Reply::Integer(removed)
+        }
+        ("exists", n) if n > 0 => {
+            let mut found = 0;
+            for key in args {
+                match call(services, Request::Contains(key)) {
+                    Response::ContainsOk(true) => found += 1,
+                    Response::ContainsOk(false) => {}
+                    response => return resp_error(response),
+                }
+            }
+            Reply::Integer(found)
+        }
+        ("rename", 2) => {
+            let new_key = args.pop().unwrap();
+            match call(services, Request::Rename(args.pop().unwrap(), new_key)) {
+                Response::RenameOk(()) => Reply::Status("OK"),
+                Response::Err(ErrorCode::KeyNotFound, _) => {
+                    Reply::Error("ERR no such key".to_owned())
+                }
+                response => resp_error(response),
+            }
+        }
+        ("append", 2) => {
+            let suffix = args.pop().unwrap();
+            match call(services, Request::Append(args.pop().unwrap(), suffix)) {
+                Response::AppendOk(len) => Reply::Integer(len as i64),
+                response => resp_error(response),
+            }
+        }
+        ("ping" | "get" | "set" | "del" | "exists" | "rename" | "append", _) => wrong_arity(),
+        _ => Reply::Error(format!("ERR unknown command '{}'", name)),
+    }
+}
+
+// Process a request outside of any transaction, recording it in the server's metrics.
+fn call<E: KvsEngine>(services: &Services<E>, request: Request) -> Response {
+    let name = request.name();
+    let start = Instant::now();
+    let response = match services.check_access(None, &request) {
+        Some(refusal) => refusal,
+        None => process_request(services, &mut None, request),
+    };
+    services.metrics.record_request(
+        name,
+        start.elapsed(),
+        matches!(
+            response,
+            Response::Err(..) | Response::NotLeader(_) | Response::Moved(_)
+        ),
+    );
+    response
+}
+
+fn resp_error(response: Response) -> Reply {
+    match response {
+        Response::Err(_, msg) => Reply::Error(format!("ERR {}", msg)),
+        Response::NotLeader(leader) => Reply::Error(format!("ERR {}", KvsError::NotLeader(leader))),
+        Response::Moved(owner) => Reply::Error(format!("ERR {}", KvsError::Moved(owner))),
+        _ => Reply::Error("ERR unexpected response".to_owned()),
+    }
+}
+
+// Serve HTTP connections on `thread_pool`, counted with the other listener's in `open`,
+// under the server's `(max connections, queue limit)`.
+fn accept_http<E: KvsEngine, P: ThreadPool>(
+    listener: TcpListener,
+    thread_pool: Arc<P>,
+    open: Arc<AtomicUsize>,
+    (max_connections, queue_limit): (Option<usize>, Option<usize>),
+    services: Services<E>,
+    log: Logger,
+) {
+    for result in listener.incoming() {
+        let stream = match result {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!(&log, "http accept failed with error {}", err.to_string());
+                continue;
+            }
+        };
+        let log = services.connection_log(&log, &stream);
+        let slot = match max_connections {
+            Some(limit) => match ConnectionSlot::take(&open, limit) {
+                Some(slot) => Some(slot),
+                None => {
+                    warn!(&log, "too many connections; turning the http connection away"; "limit" => limit);
+                    turn_away_http(&log, &services, stream);
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let spare = match queue_limit.map(|_| stream.try_clone()) {
+            Some(Ok(spare)) => Some(spare),
+            Some(Err(err)) => {
+                error!(&log, "unable to serve http connection: {}", err.to_string());
+                continue;
+            }
+            None => None,
+        };
+        let task = {
+            let services = services.clone();
+            let log = log.clone();
+            move || {
+                let _slot = slot;
+                if let Err(err) = serve_http(&log, &services, stream) {
+                    error!(
+                        &log,
+                        "http connection failed with error {}",
+                        err.to_string()
+                    )
+                }
+            }
+        };
+        if thread_pool.try_spawn(task).is_err() {
+            warn!(
+                &log,
+                "pool is full; turning the http connection away";
+                "queued" => thread_pool.queued_tasks(),
+                "active" => thread_pool.active_workers()
+            );
+            let stream = spare.expect("only a bounded pool refuses tasks");
+            turn_away_http(&log, &services, stream);
+        }
+    }
+}
+
+// Tell an HTTP client the server can't take its connection, and hang up.
+fn turn_away_http<E: KvsEngine>(log: &Logger, services: &Services<E>, mut stream: TcpStream) {
+    services.metrics.connection_rejected();
+    let response = HttpResponse::text(503, KvsError::ServerBusy.to_string());
+    if let Err(err) = response.write(&mut stream, false) {
+        debug!(log, "unable to tell the client the server is busy: {}", err);
+    }
+}
+
+fn serve_http<E: KvsEngine>(log: &Logger, services: &Services<E>, stream: TcpStream) -> Result<()> {
+    let mut seq = 0;
+    stream.set_read_timeout(services.idle_timeout)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let max_body_len = http::max_body_len(services.size_limits);
+    loop {
+        let request = match http::read_request(&mut reader, &mut writer, max_body_len) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(KvsError::IO(err)) if idled_out(&err) => {
+                debug!(&log, "closing idle http connection");
+                return Ok(());
+            }
+            Err(KvsError::StringError(msg)) => {
+                warn!(&log, "malformed http request: {}", msg);
+                HttpResponse::text(400, msg).write(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            // The body isn't read, so the rest of the stream can't be parsed either.
+            Err(KvsError::TooLarge(msg)) => {
+                warn!(&log, "http request too large: {}", msg);
+                HttpResponse::text(413, msg).write(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        seq += 1;
+        debug!(&log, "http request"; "seq" => seq, "method" => &request.method, "path" => &request.path);
+        let start = Instant::now();
+        let response = http_reply(services, &request);
+        response.write(&mut writer, request.keep_alive)?;
+        writer.flush()?;
+        let name = format!("{} {}", request.method, request.path);
+        services.log_timing(log, seq, &name, start.elapsed());
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+// Route an HTTP request to the requests it amounts to.
+fn http_reply<E: KvsEngine>(services: &Services<E>, request: &HttpRequest) -> HttpResponse {
+    if request.path == "/keys" {
+        if request.method != "GET" {
+            return HttpResponse::text(405, "only GET lists keys");
+        }
+        // A single slot holds every key.
+        let mut keys = match call(services, Request::SlotKeys(1, vec![0])) {
+            Response::SlotKeysOk(keys) => keys,
+            response => return http_error(response),
+        };
+        let prefix = request.param("prefix").unwrap_or("");
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort();
+        return match serde_json::to_vec(&keys) {
+            Ok(body) => HttpResponse::new(200, "application/json", body),
+            Err(err) => HttpResponse::text(500, err.to_string()),
+        };
+    }
+    let key = match request.path.strip_prefix("/keys/") {
+        Some(key) if !key.is_empty() => key.to_owned(),
+        _ => return HttpResponse::text(404, "no such resource; try /keys/{key}"),
+    };
+    match request.method.as_str() {
+        "GET" => match call(services, Request::Get(key)) {
+            Response::GetOk(Some(value)) => {
+                HttpResponse::new(200, "application/octet-stream", value)
+            }
+            Response::GetOk(None) => HttpResponse::text(404, KvsError::KeyNotFound.to_string()),
+            response => http_error(response),
+        },
+        "PUT" => match String::from_utf8(request.body.clone()) {
+            Ok(value) => match call(services, Request::Set(key, value)) {
+                Response::SetOk(()) => HttpResponse::no_content(),
+                response => http_error(response),
+            },
+            Err(_) => HttpResponse::text(400, "values must be UTF-8"),
+        },
+        "DELETE" => match call(services, Request::Remove(key)) {
+            Response::RemoveOk(()) => HttpResponse::no_content(),
+            Response::Err(ErrorCode::KeyNotFound, msg) => HttpResponse::text(404, msg),
+            response => http_error(response),
+        },
+        _ => HttpResponse::text(405, "keys support GET, PUT and DELETE"),
+    }
+}
+
+fn http_error(response: Response) -> HttpResponse {
+    match response {
+        Response::Err(ErrorCode::TooLarge, msg) => HttpResponse::text(413, msg),
+        Response::Err(ErrorCode::ReadOnly | ErrorCode::PermissionDenied, msg) => {
+            HttpResponse::text(403, msg)
+        }
+        Response::Err(_, msg) => HttpResponse::text(500, msg),
+        Response::NotLeader(leader) => {
+            HttpResponse::text(503, KvsError::NotLeader(leader).to_string())
+        }
+        Response::Moved(owner) => HttpResponse::text(421, KvsError::Moved(owner).to_string()),
+        _ => HttpResponse::text(500, "unexpected response"),
+    }
+}
+
+fn send(writer: &mut impl Write, framing: Option<WireFormat>, response: &Response) -> Result<()> {
+    match framing {
+        Some(format) => frame::write(writer, format, response),
+        None => Ok(response.serialize(&mut Serializer::new(writer))?),
+    }
+}
+
+// Send the server's stats as a stream of frames. Return whether it failed.
+fn write_stats<E: KvsEngine>(
+    services: &Services<E>,
+    writer: &mut impl Write,
+    framing: Option<WireFormat>,
+) -> Result<bool> {
+    let mut stats = match services.metrics.snapshot(&services.engine) {
+        Ok(stats) => stats,
+        Err(err) => {
+            send(writer, framing, &error_response(err))?;
+            return Ok(true);
+        }
+    };
+    stats.raft = services.raft.as_ref().map(|raft| raft.status());
+    stats.replicas = services.primary.as_ref().map(|primary| primary.replicas());
+    stats.following = services.replica.as_ref().map(|replica| replica.status());
+    stats.cluster = services.cluster.as_ref().map(|cluster| cluster.status());
+    for frame in stats.into_frames() {
+        send(writer, framing, &Response::StatsFrame(frame))?;
+    }
+    send(writer, framing, &Response::StatsOk(()))?;
+    Ok(false)
 }
 
-fn process_request<E: KvsEngine>(engine: &E, request: Request) -> Response {
+// Why `request` can't be served, if it can't: a key or value is too large, the server is a
+// member of a Raft group that isn't the leader, or it's a read-only replica.
+fn refusal<E: KvsEngine>(services: &Services<E>, request: &Request) -> Option<Response> {
+    if let Err(err) = request.check_size(&services.size_limits) {
+        return Some(error_response(err));
+    }
+    if services.replica.is_some() && request.is_write() {
+        return Some(error_response(KvsError::ReadOnly));
+    }
+
+    // Replicated servers send clients to the leader. Stats, compaction and pings concern
+    // only the server itself, so every member serves them.
+    if let Some(raft) = &services.raft {
+        if !matches!(request, Request::Compact | Request::Ping) {
+            if let Err(err) = raft.check_leader() {
+                return Some(error_response(err));
+            }
+        }
+    }
+    None
+}
+
+// Where to send `request` instead, if the server is a member of a cluster and another
+// member owns its keys. Keys owned by several members can't be served together by any.
+fn moved<E: KvsEngine>(services: &Services<E>, request: &Request) -> Option<Response> {
+    let cluster = services.cluster.as_ref()?;
+    let mut owners: Vec<SocketAddr> = request
+        .routing_keys()
+        .into_iter()
+        .filter_map(|key| cluster.owner(key))
+        .collect();
+    owners.sort();
+    owners.dedup();
+    match owners[..] {
+        [] => None,
+        [owner] if owner == cluster.addr() => None,
+        [owner] => Some(Response::Moved(owner)),
+        _ => Some(Response::Err(
+            ErrorCode::Other,
+            "the keys are owned by different members of the cluster".to_owned(),
+        )),
+    }
+}
+
+fn process_request<E: KvsEngine>(
+    services: &Services<E>,
+    txn: &mut Option<Txn>,
+    request: Request,
+) -> Response {
+    match request {
+        Request::Pinned(request) => serve_request(services, txn, *request),
+        request => match moved(services, &request) {
+            Some(response) => response,
+            None => serve_request(services, txn, request),
+        },
+    }
+}
+
+// Serve a request here, whichever member of a cluster owns its keys.
+fn serve_request<E: KvsEngine>(
+    services: &Services<E>,
+    txn: &mut Option<Txn>,
+    request: Request,
+) -> Response {
+    if let Some(response) = refusal(services, &request) {
+        return response;
+    }
+
+    if let Request::InBucket(bucket, request) = request {
+        return bucket_response(services, txn, &bucket, *request);
+    }
+    // Clients put the trace id outermost, where `serve` takes it off, but one made inside
+    // other wrapping is served as if it weren't there.
+    if let Request::Traced(_, request) | Request::Pinned(request) = request {
+        return serve_request(services, txn, *request);
+    }
+
+    // While a transaction is open, reads and writes of keys go through it.
+    if let Some(open) = txn {
+        match request {
+            Request::Get(key) => {
+                return match open.get(key) {
+                    Ok(value) => Response::GetOk(value),
+                    Err(err) => error_response(err),
+                }
+            }
+            Request::MultiGet(keys) => {
+                let values: Result<Vec<Option<String>>> =
+                    keys.into_iter().map(|key| open.get(key)).collect();
+                return match values {
+                    Ok(values) => Response::MultiGetOk(values),
+                    Err(err) => error_response(err),
+                };
+            }
+            // Nothing else can write while the connection waits, so the key would never be set.
+            Request::WaitGet { .. } => {
+                return Response::Err(
+                    ErrorCode::Other,
+                    "wait_get can't be made in a transaction".to_owned(),
+                )
+            }
+            // A lock taken in a transaction would only be taken once it commits, by when
+            // another owner may have it.
+            Request::AcquireLock(_, _, _) | Request::ReleaseLock(_, _) => {
+                return Response::Err(
+                    ErrorCode::Other,
+                    "locks can't be taken or released in a transaction".to_owned(),
+                )
+            }
+            // A value the transaction wrote has no version until it's committed.
+            Request::GetWithMeta(_) | Request::Stat(_) => {
+                return Response::Err(
+                    ErrorCode::Other,
+                    "versions can't be read in a transaction".to_owned(),
+                )
+            }
+            Request::Set(key, value) => {
+                open.set(key, value);
+                return Response::SetOk(());
+            }
+            Request::Remove(key) => {
+                return match open.remove(key) {
+                    Ok(()) => Response::RemoveOk(()),
+                    Err(err) => error_response(err),
+                }
+            }
+            Request::Incr(key, delta) => {
+                let result = open
+                    .get(key.clone())
+                    .and_then(|current| engines::add_delta(current, delta));
+                return match result {
+                    Ok(value) => {
+                        open.set(key, value.to_string());
+                        Response::IncrOk(value)
+                    }
+                    Err(err) => error_response(err),
+                };
+            }
+            Request::Append(key, suffix) => {
+                return match open.get(key.clone()) {
+                    Ok(value) => {
+                        let value = value.unwrap_or_default() + &suffix;
+                        let len = value.len() as u64;
+                        open.set(key, value);
+                        Response::AppendOk(len)
+                    }
+                    Err(err) => error_response(err),
+                };
+            }
+            Request::GetRange(key, offset, len) => {
+                let range = open.get(key).and_then(|value| {
+                    value
+                        .map(|value| engines::value_range(&value, offset, len))
+                        .transpose()
+                });
+                return match range {
+                    Ok(range) => Response::GetRangeOk(range),
+                    Err(err) => error_response(err),
+                };
+            }
+            Request::Rename(old_key, new_key) => {
+                let result = open.get(old_key.clone()).and_then(|value| {
+                    let value = value.ok_or(KvsError::KeyNotFound)?;
+                    if old_key != new_key {
+                        open.remove(old_key)?;
+                        open.set(new_key, value);
+                    }
+                    Ok(())
+                });
+                return match result {
+                    Ok(()) => Response::RenameOk(()),
+                    Err(err) => error_response(err),
+                };
+            }
+            Request::Contains(key) => {
+                return match open.get(key) {
+                    Ok(value) => Response::ContainsOk(value.is_some()),
+                    Err(err) => error_response(err),
+                }
+            }
+            Request::Begin => {
+                return Response::Err(
+                    ErrorCode::Other,
+                    "a transaction is already in progress".to_owned(),
+                )
+            }
+            Request::Commit => {
+                let open = txn.take().unwrap();
+                let changes: Vec<(String, bool)> =
+                    open.writes().map(|(key, set)| (key.clone(), set)).collect();
+                return match open.commit() {
+                    Ok(()) => {
+                        for (key, set) in changes {
+                            let op = if set { ChangeOp::Set } else { ChangeOp::Remove };
+                            services.watchers.publish(&key, op);
+                        }
+                        Response::CommitOk(())
+                    }
+                    Err(err) => error_response(err),
+                };
+            }
+            Request::Rollback => {
+                txn.take().unwrap().rollback();
+                return Response::RollbackOk(());
+            }
+            _ => {}
+        }
+    }
+
+    let engine = &services.engine;
     match request {
         Request::Get(key) => match engine.get(key.clone()) {
             Ok(value) => Response::GetOk(value.clone()),
-            Err(err) => Response::Err(err.to_string()),
+            Err(err) => error_response(err),
+        },
+        Request::MultiGet(keys) => match engine.get_many(keys) {
+            Ok(values) => Response::MultiGetOk(values),
+            Err(err) => error_response(err),
+        },
+        Request::GetWithMeta(key) => match engine.get_with_meta(key) {
+            Ok(found) => Response::GetWithMetaOk(found),
+            Err(err) => error_response(err),
+        },
+        Request::Stat(key) => match engine.metadata(key) {
+            Ok(found) => Response::StatOk(found),
+            Err(err) => error_response(err),
+        },
+        Request::Set(key, value) => {
+            let result = match (&services.raft, &services.batcher) {
+                (Some(raft), _) => raft.propose(Command::Set(key.clone(), value)),
+                (None, Some(batcher)) => batcher.set(key.clone(), value),
+                (None, None) => engine.set(key.clone(), value),
+            };
+            match result {
+                Ok(()) => {
+                    services.watchers.publish(&key, ChangeOp::Set);
+                    Response::SetOk(())
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        Request::Remove(key) => {
+            let result = match &services.raft {
+                Some(raft) => raft.propose(Command::Remove(key.clone())),
+                None => engine.remove(key.clone()),
+            };
+            match result {
+                Ok(()) => {
+                    services.watchers.publish(&key, ChangeOp::Remove);
+                    Response::RemoveOk(())
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        // Proposals apply once committed, so a sum computed beforehand could be stale by then.
+        Request::Incr(_, _) if services.raft.is_some() => Response::Err(
+            ErrorCode::Other,
+            "incr is not supported by replicated servers".to_owned(),
+        ),
+        Request::Incr(key, delta) => match engine.incr(key.clone(), delta) {
+            Ok(value) => {
+                services.watchers.publish(&key, ChangeOp::Set);
+                Response::IncrOk(value)
+            }
+            Err(err) => error_response(err),
+        },
+        // As with incr, the value appended to could be stale by the time the proposal applies.
+        Request::Append(_, _) if services.raft.is_some() => Response::Err(
+            ErrorCode::Other,
+            "append is not supported by replicated servers".to_owned(),
+        ),
+        Request::Append(key, suffix) => match engine.append(key.clone(), suffix) {
+            Ok(len) => {
+                services.watchers.publish(&key, ChangeOp::Set);
+                Response::AppendOk(len)
+            }
+            Err(err) => error_response(err),
+        },
+        Request::GetRange(key, offset, len) => match engine.get_range(key, offset, len) {
+            Ok(range) => Response::GetRangeOk(range),
+            Err(err) => error_response(err),
         },
-        Request::Set(key, value) => match engine.set(key, value) {
-            Ok(()) => Response::SetOk(()),
-            Err(err) => Response::Err(err.to_string()),
+        Request::FindByValue(value) => match engine.find_by_value(&value) {
+            Ok(keys) => Response::FindByValueOk(keys),
+            Err(err) => error_response(err),
         },
-        Request::Remove(key) => match engine.remove(key) {
-            Ok(()) => Response::RemoveOk(()),
-            Err(err) => Response::Err(err.to_string()),
+        Request::WaitGet { key, timeout_ms } => {
+            let (tx, rx) = channel::bounded(1);
+            let wake = Box::new(move || {
+                let _ = tx.send(());
+            });
+            park_wait_get(services, key.clone(), timeout_ms, wake);
+            let _ = rx.recv();
+            wait_get_response(services, key)
+        }
+        // As with incr, whether the lock was free could be stale by the time the proposal
+        // applies.
+        Request::AcquireLock(_, _, _) | Request::ReleaseLock(_, _) if services.raft.is_some() => {
+            Response::Err(
+                ErrorCode::Other,
+                "locks are not supported by replicated servers".to_owned(),
+            )
+        }
+        Request::AcquireLock(key, owner, ttl) => {
+            match engine.acquire_lock(key.clone(), owner, ttl) {
+                Ok(taken) => {
+                    if taken {
+                        services.watchers.publish(&key, ChangeOp::Set);
+                    }
+                    Response::AcquireLockOk(taken)
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        Request::ReleaseLock(key, owner) => match engine.release_lock(key.clone(), owner) {
+            Ok(released) => {
+                if released {
+                    services.watchers.publish(&key, ChangeOp::Remove);
+                }
+                Response::ReleaseLockOk(released)
+            }
+            Err(err) => error_response(err),
         },
+        // As with incr, the value moved could be stale by the time the proposal applies.
+        Request::Rename(_, _) if services.raft.is_some() => Response::Err(
+            ErrorCode::Other,
+            "rename is not supported by replicated servers".to_owned(),
+        ),
+        Request::Rename(old_key, new_key) => {
+            match engine.rename(old_key.clone(), new_key.clone()) {
+                Ok(()) => {
+                    if old_key != new_key {
+                        services.watchers.publish(&old_key, ChangeOp::Remove);
+                        services.watchers.publish(&new_key, ChangeOp::Set);
+                    }
+                    Response::RenameOk(())
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        Request::Contains(key) => match engine.contains(key) {
+            Ok(found) => Response::ContainsOk(found),
+            Err(err) => error_response(err),
+        },
+        Request::Len => match engine.len() {
+            Ok(len) => Response::LenOk(len),
+            Err(err) => error_response(err),
+        },
+        Request::SlotKeys(0, _) => Response::Err(
+            ErrorCode::Other,
+            "the slot count must be positive".to_owned(),
+        ),
+        Request::SlotKeys(slot_count, slots) => match engine.keys() {
+            Ok(mut keys) => {
+                let slots: HashSet<u32> = slots.into_iter().collect();
+                keys.retain(|key| slots.contains(&ring::slot_for_key(key, slot_count)));
+                Response::SlotKeysOk(keys)
+            }
+            Err(err) => error_response(err),
+        },
+        Request::Keys { cursor, limit } => keys_response(engine, cursor, limit),
+        Request::AcquireLease(key, ttl) => {
+            Response::AcquireLeaseOk(services.leases.acquire(key, ttl))
+        }
+        Request::RenewLease(key, id, ttl) => {
+            Response::RenewLeaseOk(services.leases.renew(&key, id, ttl))
+        }
+        Request::ReleaseLease(key, id) => {
+            Response::ReleaseLeaseOk(services.leases.release(&key, id))
+        }
+        Request::Begin if services.raft.is_some() => Response::Err(
+            ErrorCode::Other,
+            "transactions are not supported by replicated servers".to_owned(),
+        ),
+        Request::Begin => match engine.transaction() {
+            Ok(begun) => {
+                *txn = Some(begun);
+                Response::BeginOk(())
+            }
+            Err(err) => error_response(err),
+        },
+        Request::Commit | Request::Rollback => {
+            Response::Err(ErrorCode::Other, "no transaction is in progress".to_owned())
+        }
+        // `serve` answers these itself, unless they came wrapped where they can't be.
+        request @ (Request::Stats
+        | Request::InBucket(_, _)
+        | Request::Tagged(_, _)
+        | Request::Traced(_, _)
+        | Request::Pinned(_)
+        | Request::Subscribe(_)) => misplaced(&request),
+        Request::Compact => match engine.compact() {
+            Ok(()) => Response::CompactOk(()),
+            Err(err) => error_response(err),
+        },
+        Request::Ping => Response::PingOk(Pong {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            engine: engine.name().to_owned(),
+            uptime: services.started.elapsed(),
+            pings: services.pings.fetch_add(1, Ordering::SeqCst) + 1,
+        }),
+        Request::Gossip {
+            slot_count,
+            members,
+        } => match &services.cluster {
+            Some(cluster) => match cluster.gossip(slot_count, members) {
+                Ok(members) => Response::GossipOk(members),
+                Err(err) => error_response(err),
+            },
+            None => not_clustered(),
+        },
+        Request::ClusterMap => match &services.cluster {
+            Some(cluster) => Response::ClusterMapOk(cluster.map()),
+            None => not_clustered(),
+        },
+        Request::Authenticate(_) => Response::Err(
+            ErrorCode::Other,
+            "authenticate requests must be sent on their own".to_owned(),
+        ),
     }
 }
 
-fn respond(stream: TcpStream, response: &mut Response) -> Result<()> {
-    let mut writer = Serializer::new(BufWriter::new(&stream));
-    response.serialize(&mut writer)?;
-    writer.get_mut().flush()?;
-    Ok(())
+// The response to a request that reached `serve_request` wrapped where it can't be, such
+// as stats sent pipelined inside a pin.
+fn misplaced(request: &Request) -> Response {
+    Response::Err(
+        ErrorCode::Other,
+        format!("{} requests can't be wrapped like that", request.name()),
+    )
+}
+
+fn not_clustered() -> Response {
+    Response::Err(
+        ErrorCode::Other,
+        "the server isn't a member of a cluster".to_owned(),
+    )
+}
+
+// Serve a request made in `bucket` as a request of the engine's own, on the bucket's keys.
+fn bucket_response<E: KvsEngine>(
+    services: &Services<E>,
+    txn: &mut Option<Txn>,
+    bucket: &str,
+    request: Request,
+) -> Response {
+    let prefix = match engines::bucket_prefix(bucket) {
+        Ok(prefix) => prefix,
+        Err(err) => return error_response(err),
+    };
+    let key = |key: String| format!("{}{}", prefix, key);
+    // The keys of the bucket, without its prefix.
+    let keys = || -> Result<Vec<String>> {
+        Ok(services
+            .engine
+            .keys()?
+            .into_iter()
+            .filter_map(|key| key.strip_prefix(&prefix).map(str::to_owned))
+            .collect())
+    };
+    let request = match request {
+        Request::Get(k) => Request::Get(key(k)),
+        Request::MultiGet(keys) => Request::MultiGet(keys.into_iter().map(key).collect()),
+        Request::GetWithMeta(k) => Request::GetWithMeta(key(k)),
+        Request::Stat(k) => Request::Stat(key(k)),
+        Request::Set(k, value) => Request::Set(key(k), value),
+        Request::Remove(k) => Request::Remove(key(k)),
+        Request::Incr(k, delta) => Request::Incr(key(k), delta),
+        Request::Rename(old, new) => Request::Rename(key(old), key(new)),
+        Request::Append(k, suffix) => Request::Append(key(k), suffix),
+        Request::GetRange(k, offset, len) => Request::GetRange(key(k), offset, len),
+        Request::WaitGet { key: k, timeout_ms } => Request::WaitGet {
+            key: key(k),
+            timeout_ms,
+        },
+        Request::AcquireLock(k, owner, ttl) => Request::AcquireLock(key(k), owner, ttl),
+        Request::ReleaseLock(k, owner) => Request::ReleaseLock(key(k), owner),
+        Request::Contains(k) => Request::Contains(key(k)),
+        Request::Len => {
+            return match keys() {
+                Ok(keys) => Response::LenOk(keys.len() as u64),
+                Err(err) => error_response(err),
+            }
+        }
+        // Slots are those of the keys without the prefix, as the client hashed them.
+        Request::SlotKeys(slot_count, slots) if slot_count > 0 => {
+            return match keys() {
+                Ok(mut keys) => {
+                    let slots: HashSet<u32> = slots.into_iter().collect();
+                    keys.retain(|key| slots.contains(&ring::slot_for_key(key, slot_count)));
+                    Response::SlotKeysOk(keys)
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        request @ Request::SlotKeys(_, _) => request,
+        Request::Keys { cursor, limit } => {
+            return match services.engine.bucket(bucket) {
+                Ok(bucket) => keys_response(&bucket, cursor, limit),
+                Err(err) => error_response(err),
+            }
+        }
+        Request::FindByValue(value) => {
+            return match services
+                .engine
+                .bucket(bucket)
+                .and_then(|bucket| bucket.find_by_value(&value))
+            {
+                Ok(keys) => Response::FindByValueOk(keys),
+                Err(err) => error_response(err),
+            }
+        }
+        Request::InBucket(_, _) => {
+            return Response::Err(ErrorCode::Other, "buckets can't be nested".to_owned())
+        }
+        request => {
+            return Response::Err(
+                ErrorCode::Other,
+                format!("{} requests can't be made in a bucket", request.name()),
+            )
+        }
+    };
+    serve_request(services, txn, request)
+}
+
+// The most keys a page holds, however many are asked for, so that no request makes the
+// server gather too many at once.
+const MAX_KEYS_PAGE: u32 = 10_000;
+
+// Answer a `keys` request of `engine`. One key more than the page holds is read, to tell
+// whether another page follows.
+fn keys_response<E: KvsEngine>(engine: &E, cursor: Option<String>, limit: u32) -> Response {
+    if limit == 0 {
+        return Response::Err(ErrorCode::Other, "the limit must be positive".to_owned());
+    }
+    let limit = limit.min(MAX_KEYS_PAGE) as usize;
+    match engine.keys_after(cursor, limit + 1) {
+        Ok(mut keys) => {
+            let cursor = if keys.len() > limit {
+                keys.truncate(limit);
+                keys.last().cloned()
+            } else {
+                None
+            };
+            Response::KeysOk { keys, cursor }
+        }
+        Err(err) => error_response(err),
+    }
+}
+
+fn error_response(err: KvsError) -> Response {
+    match err {
+        KvsError::NotLeader(leader) => Response::NotLeader(leader),
+        KvsError::Moved(owner) => Response::Moved(owner),
+        err => {
+            let (code, msg) = ErrorCode::split(&err);
+            Response::Err(code, msg)
+        }
+    }
 }
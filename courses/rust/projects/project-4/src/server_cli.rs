@@ -0,0 +1,1353 @@
+//! The command line of `kvs-server`, which `run` serves.
+//!
+//! It lives in the library so that a crate with an engine of its own can build the server
+//! with that engine in it, registering it with `register_engine` before calling `run`.
+//! `run` leaves reporting to its caller, as an `Outcome` or an `InvalidConfig` error, which
+//! such a crate's `main` can report as kvs-server's does.
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use clap::CommandFactory;
+use clap::FromArgMatches;
+use clap::Parser;
+use clap::ValueEnum;
+
+use crate::acl::Acl;
+use crate::cluster::ClusterConfig;
+use crate::data_dir;
+use crate::log_file::LogFile;
+use crate::log_file::Rotation;
+use crate::open_registered;
+use crate::raft::Peer;
+use crate::raft::RaftConfig;
+use crate::registered_engines;
+use crate::ring;
+use crate::thread_pool::ThreadPoolKind;
+use crate::BoxedEngine;
+use crate::Durability;
+use crate::KvStore;
+use crate::KvsServer;
+use crate::Protocol;
+use crate::Request;
+#[cfg(feature = "rocksdb")]
+use crate::RocksKvsEngine;
+use crate::SizeLimits;
+use crate::SledKvsEngine;
+use crate::TombstoneHorizon;
+use crate::Traced;
+use crate::WriteStall;
+use crate::BUILTIN_ENGINES;
+#[cfg(unix)]
+use daemonize::Daemonize;
+use serde::Deserialize;
+use slog::error;
+use slog::info;
+use slog::o;
+use slog::warn;
+use slog::Drain;
+use slog::Fuse;
+use slog::Level;
+use slog::LevelFilter;
+use slog::Logger;
+use slog_async::Async;
+use slog_json::Json;
+use slog_term::CompactFormat;
+use slog_term::PlainDecorator;
+use slog_term::TermDecorator;
+use std::env::current_dir;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::result::Result;
+use std::time::Duration;
+
+/// How log messages are written.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    /// A line of text per message, for people.
+    Term,
+    /// A JSON object per line, for log shippers.
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            Self::Term => write!(f, "term"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+// FIXME: define this in another module shared between client and server
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+const ADDR_NAME: &str = "IP-PORT";
+
+const DEFAULT_GROUP_COMMIT_BYTES: u64 = 1024 * 1024;
+const DEFAULT_THREADS: u32 = 32;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Read settings from this TOML file. Its keys are the names of the other options,
+    /// such as `engine = "sled"`; options given on the command line take precedence.
+    #[arg(long, name = "CONFIG-PATH")]
+    config: Option<PathBuf>,
+
+    #[arg(long, name=ADDR_NAME, default_value=DEFAULT_ADDR)]
+    addr: SocketAddr,
+
+    /// Serve on the Unix domain socket at this path rather than on --addr.
+    #[cfg(unix)]
+    #[arg(long, name = "SOCKET-PATH")]
+    socket: Option<PathBuf>,
+
+    /// Engine to keep the data in: kvs, sled, rocksdb if built with the rocksdb feature, or
+    /// one registered with `register_engine` by the binary the server is built into.
+    #[arg(long, name = "ENGINE-NAME", default_value = "kvs")]
+    engine: String,
+
+    /// Protocol to speak with clients: kvs, for kvs-client, or resp, for Redis clients.
+    #[arg(long, name = "PROTOCOL", default_value_t = Protocol::Kvs)]
+    protocol: Protocol,
+
+    /// Serve Prometheus-format metrics over HTTP on this address.
+    #[arg(long, name = "METRICS-IP-PORT")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Serve the store over HTTP on this address, for curl and clients that don't speak
+    /// the kvs protocol.
+    #[arg(long, name = "HTTP-IP-PORT")]
+    http_addr: Option<SocketAddr>,
+
+    /// Serve the gRPC service of proto/kvs.proto on this address.
+    #[cfg(feature = "grpc")]
+    #[arg(long, name = "GRPC-IP-PORT")]
+    grpc_addr: Option<SocketAddr>,
+
+    /// Batch sets arriving within this many microseconds into a single engine write.
+    #[arg(long, name = "MICROSECONDS")]
+    batch_window_us: Option<u64>,
+
+    /// Sync the kvs engine's log for the writes of each window of this many microseconds
+    /// together, rather than flushing every write on its own.
+    #[arg(long, name = "GROUP-MICROSECONDS")]
+    group_commit_us: Option<u64>,
+
+    /// Sync a group commit early once this many bytes are waiting.
+    #[arg(long, name = "BYTES", default_value_t = DEFAULT_GROUP_COMMIT_BYTES)]
+    group_commit_bytes: u64,
+
+    /// Sync the kvs engine's log in the background every this many milliseconds, bounding
+    /// what a power loss can take of the writes that don't wait for a sync.
+    #[arg(long, name = "SYNC-MILLISECONDS")]
+    sync_interval_ms: Option<u64>,
+
+    /// Give the sled engine's page cache this many bytes.
+    #[arg(long, name = "CACHE-BYTES")]
+    sled_cache_bytes: Option<u64>,
+
+    /// Have sled flush in the background every this many milliseconds; 0 turns it off.
+    #[arg(long, name = "MILLISECONDS")]
+    sled_flush_every_ms: Option<u64>,
+
+    /// When the sled engine's writes reach the disk: "every-write" flushes each one before
+    /// replying, "background" leaves it to sled's background flushes.
+    #[arg(long, name = "DURABILITY", default_value_t = Durability::EveryWrite)]
+    sled_durability: Durability,
+
+    /// Kind of thread pool serving connections: naive, shared-queue or rayon.
+    #[arg(long, name = "THREAD-POOL", default_value_t = ThreadPoolKind::Naive)]
+    thread_pool: ThreadPoolKind,
+
+    /// Number of threads in the pool. Each connection holds a thread while it's open, so
+    /// this bounds the clients served at once, except by the naive pool, which ignores it.
+    #[arg(long, name = "THREADS", default_value_t = DEFAULT_THREADS)]
+    threads: u32,
+
+    /// Let at most this many connections wait for a thread of the shared-queue pool, and
+    /// turn further ones away as busy rather than queueing them.
+    #[arg(long, name = "QUEUE-LIMIT")]
+    queue_limit: Option<usize>,
+
+    /// Keep at most this many client connections open at once, and turn further ones away
+    /// as busy.
+    #[arg(long, name = "MAX-CONNECTIONS")]
+    max_connections: Option<usize>,
+
+    /// Close client connections that send nothing for this many seconds.
+    #[arg(long, name = "IDLE-SECONDS")]
+    idle_timeout: Option<u64>,
+
+    /// Serve each client IP address at most this many requests a second, and answer the
+    /// rest as rate limited.
+    #[arg(long, name = "REQUESTS-PER-SECOND")]
+    max_rps: Option<u32>,
+
+    /// Let a client IP address make this many requests at once before --max-rps holds it
+    /// back. Defaults to --max-rps.
+    #[arg(long, name = "BURST-REQUESTS")]
+    burst: Option<u32>,
+
+    /// Serve only the requests the ACL in this file allows each client's token; see
+    /// `kvs::acl`.
+    #[arg(long, name = "ACL-PATH")]
+    acl_file: Option<PathBuf>,
+
+    /// Refuse keys of more than this many bytes.
+    #[arg(long, name = "MAX-KEY-BYTES")]
+    max_key_bytes: Option<usize>,
+
+    /// Refuse values of more than this many bytes. Together with --max-key-bytes, this also
+    /// bounds what the server reads of a request before refusing it.
+    #[arg(long, name = "MAX-VALUE-BYTES")]
+    max_value_bytes: Option<usize>,
+
+    /// Compact the kvs engine's logs once more than this many bytes of them are stale.
+    #[arg(long, name = "COMPACTION-BYTES")]
+    compaction_threshold: Option<u64>,
+
+    /// Compact the kvs engine's logs once the oldest remove since the last compaction is
+    /// this many seconds old, however few bytes are stale.
+    #[arg(long, name = "TOMBSTONE-SECONDS")]
+    tombstone_max_age_secs: Option<u64>,
+
+    /// Compact the kvs engine's logs once this many removes have been made since the last
+    /// compaction, however few bytes are stale.
+    #[arg(long, name = "TOMBSTONES")]
+    tombstone_max_count: Option<u64>,
+
+    /// Answer writes made while the kvs engine compacts as busy, asking clients to retry
+    /// after this many milliseconds, rather than have them wait.
+    #[arg(long, name = "RETRY-MILLISECONDS")]
+    compaction_backpressure_ms: Option<u64>,
+
+    /// Stall writes to the kvs engine while more than this many bytes of its logs are
+    /// stale, as when compaction keeps failing: refuse them, or delay them with
+    /// --write-stall-delay-ms.
+    #[arg(long, name = "STALL-BYTES")]
+    write_stall_bytes: Option<u64>,
+
+    /// Delay each stalled write by this many milliseconds, rather than refuse it.
+    #[arg(long, name = "STALL-MILLISECONDS")]
+    write_stall_delay_ms: Option<u64>,
+
+    /// Have the kvs engine read sealed logs through memory maps.
+    #[arg(long)]
+    mmap_reads: bool,
+
+    /// Have the kvs engine keep an index of the keys set to each value, for find-by-value
+    /// requests.
+    #[arg(long)]
+    value_index: bool,
+
+    /// Refuse to start on a kvs log ending in a partly written record, rather than cut it off.
+    #[arg(long)]
+    strict_recovery: bool,
+
+    /// Log messages of this level and above: critical, error, warn, info, debug or trace.
+    #[arg(long, name = "LEVEL", default_value = "debug", value_parser = parse_level)]
+    log_level: Level,
+
+    /// Append log messages to this file rather than write them to the terminal.
+    #[arg(long, name = "LOG-PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Write log messages as term, lines of text, or json, an object per line.
+    #[arg(long, value_enum, name = "LOG-FORMAT", default_value_t = LogFormat::Term)]
+    log_format: LogFormat,
+
+    /// Rotate the log file once it would grow past this many bytes.
+    #[arg(long, name = "LOG-BYTES")]
+    log_rotate_bytes: Option<u64>,
+
+    /// Rotate the log file once it has been written for this many hours.
+    #[arg(long, name = "LOG-HOURS")]
+    log_rotate_hours: Option<u64>,
+
+    /// Keep this many rotated log files, as LOG-PATH.1 and on, the oldest last.
+    #[arg(long, name = "LOG-FILES", default_value_t = Rotation::default().keep)]
+    log_keep: usize,
+
+    /// Detach from the terminal and serve in the background, logging to --log-file, or to
+    /// kvs-server.log in the data directory.
+    #[cfg(unix)]
+    #[arg(long)]
+    daemonize: bool,
+
+    /// Write the server's process id to this file once it's started. The file is left
+    /// behind when the server is killed.
+    #[arg(long, name = "PID-PATH")]
+    pid_file: Option<PathBuf>,
+
+    /// Log a warning for every request taking at least this many milliseconds to serve.
+    #[arg(long, name = "SLOW-MILLISECONDS")]
+    slow_request_ms: Option<u64>,
+
+    /// Log a warning for every engine operation taking at least this many milliseconds,
+    /// saying whether the engine compacted meanwhile.
+    #[arg(long, name = "SLOW-OP-MILLISECONDS")]
+    slow_op_ms: Option<u64>,
+
+    /// Keep the server's files, including the one pinning its engine, in this directory
+    /// rather than the current one. It's created if it doesn't exist.
+    #[arg(long, name = "DATA-DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Replicate with Raft as the member with this id. Requires --raft-addr.
+    #[arg(long, name = "RAFT-ID")]
+    raft_id: Option<u64>,
+
+    /// Listen for the other members of the Raft group on this address.
+    #[arg(long, name = "RAFT-IP-PORT")]
+    raft_addr: Option<SocketAddr>,
+
+    /// Another member of the Raft group. Repeat for each member.
+    #[arg(long, name = "ID,RAFT-IP-PORT,CLIENT-IP-PORT")]
+    raft_peer: Vec<Peer>,
+
+    /// Ship every change to the replicas that connect on this address.
+    #[arg(long, name = "REPLICATION-IP-PORT")]
+    replication_addr: Option<SocketAddr>,
+
+    /// Follow the primary with this replication address, as a replica serving reads only.
+    #[arg(long, name = "PRIMARY-IP-PORT")]
+    replica_of: Option<SocketAddr>,
+
+    /// The token to follow the primary with, if it has an ACL. Its grant there must cover
+    /// every key. Left out of config files and --check-config, as a secret.
+    #[arg(
+        long,
+        name = "REPLICATION-TOKEN",
+        env = "KVS_REPLICATION_TOKEN",
+        hide_env_values = true
+    )]
+    replication_token: Option<String>,
+
+    /// Join a cluster as the member serving on --addr, sharing out keys with the others and
+    /// redirecting clients to the member owning each key.
+    #[arg(long)]
+    cluster: bool,
+
+    /// Another member of the cluster to gossip with until the others are known. Repeat for
+    /// more; any one already in the cluster will do.
+    #[arg(long, name = "PEER-IP-PORT")]
+    cluster_peer: Vec<SocketAddr>,
+
+    /// Number of slots the cluster hashes keys into, the same for every member.
+    #[arg(long, name = "CLUSTER-SLOTS", default_value_t = ring::DEFAULT_SLOT_COUNT)]
+    cluster_slots: u32,
+
+    /// The token members of the cluster gossip with, if they have ACLs, which must grant it
+    /// the admin role. Left out of config files and --check-config, as a secret.
+    #[arg(
+        long,
+        name = "CLUSTER-TOKEN",
+        env = "KVS_CLUSTER_TOKEN",
+        hide_env_values = true
+    )]
+    cluster_token: Option<String>,
+
+    /// Validate the configuration, print it and exit without starting the server.
+    #[arg(long)]
+    check_config: bool,
+}
+
+/// Settings read from the file given with `--config`, named like the options they set.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigFile {
+    addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    socket: Option<PathBuf>,
+    engine: Option<String>,
+    protocol: Option<Protocol>,
+    metrics_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    #[cfg(feature = "grpc")]
+    grpc_addr: Option<SocketAddr>,
+    batch_window_us: Option<u64>,
+    group_commit_us: Option<u64>,
+    group_commit_bytes: Option<u64>,
+    sync_interval_ms: Option<u64>,
+    sled_cache_bytes: Option<u64>,
+    sled_flush_every_ms: Option<u64>,
+    sled_durability: Option<Durability>,
+    thread_pool: Option<ThreadPoolKind>,
+    threads: Option<u32>,
+    queue_limit: Option<usize>,
+    max_connections: Option<usize>,
+    idle_timeout: Option<u64>,
+    max_rps: Option<u32>,
+    burst: Option<u32>,
+    acl_file: Option<PathBuf>,
+    max_key_bytes: Option<usize>,
+    max_value_bytes: Option<usize>,
+    compaction_threshold: Option<u64>,
+    tombstone_max_age_secs: Option<u64>,
+    tombstone_max_count: Option<u64>,
+    compaction_backpressure_ms: Option<u64>,
+    write_stall_bytes: Option<u64>,
+    write_stall_delay_ms: Option<u64>,
+    mmap_reads: Option<bool>,
+    value_index: Option<bool>,
+    strict_recovery: Option<bool>,
+    log_level: Option<String>,
+    log_file: Option<PathBuf>,
+    log_format: Option<LogFormat>,
+    log_rotate_bytes: Option<u64>,
+    log_rotate_hours: Option<u64>,
+    log_keep: Option<usize>,
+    #[cfg(unix)]
+    daemonize: Option<bool>,
+    pid_file: Option<PathBuf>,
+    slow_request_ms: Option<u64>,
+    slow_op_ms: Option<u64>,
+    data_dir: Option<PathBuf>,
+    raft_id: Option<u64>,
+    raft_addr: Option<SocketAddr>,
+    raft_peer: Option<Vec<String>>,
+    replication_addr: Option<SocketAddr>,
+    replica_of: Option<SocketAddr>,
+    cluster: Option<bool>,
+    cluster_peer: Option<Vec<SocketAddr>>,
+    cluster_slots: Option<u32>,
+}
+
+fn parse_level(level: &str) -> Result<Level, String> {
+    level
+        .parse()
+        .map_err(|()| format!("invalid log level {:?}", level))
+}
+
+// Set an option with a default from the config file, unless it was given on the command line.
+fn fill<T>(option: &mut T, given: bool, from_file: Option<T>) {
+    if let (false, Some(value)) = (given, from_file) {
+        *option = value;
+    }
+}
+
+/// Fill in the options not given on the command line from the `--config` file, if any.
+fn apply_config_file(cli: &mut Cli, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let path = match &cli.config {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("unable to read config file {}: {}", path.display(), err))?;
+    let file: ConfigFile = toml::from_str(&contents)
+        .map_err(|err| format!("invalid config file {}: {}", path.display(), err))?;
+
+    // Options with defaults always have a value, so ask clap whether it came from the user.
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    fill(&mut cli.addr, given(ADDR_NAME), file.addr);
+    fill(&mut cli.engine, given("ENGINE-NAME"), file.engine);
+    fill(&mut cli.protocol, given("PROTOCOL"), file.protocol);
+    fill(
+        &mut cli.group_commit_bytes,
+        given("BYTES"),
+        file.group_commit_bytes,
+    );
+    fill(
+        &mut cli.sled_durability,
+        given("DURABILITY"),
+        file.sled_durability,
+    );
+    fill(&mut cli.thread_pool, given("THREAD-POOL"), file.thread_pool);
+    fill(&mut cli.threads, given("THREADS"), file.threads);
+    fill(&mut cli.log_format, given("LOG-FORMAT"), file.log_format);
+    fill(&mut cli.log_keep, given("LOG-FILES"), file.log_keep);
+    fill(
+        &mut cli.cluster_slots,
+        given("CLUSTER-SLOTS"),
+        file.cluster_slots,
+    );
+    if !given("LEVEL") {
+        if let Some(level) = file.log_level {
+            cli.log_level = parse_level(&level)?;
+        }
+    }
+    if cli.raft_peer.is_empty() {
+        for peer in file.raft_peer.unwrap_or_default() {
+            cli.raft_peer.push(peer.parse()?);
+        }
+    }
+    if cli.cluster_peer.is_empty() {
+        cli.cluster_peer = file.cluster_peer.unwrap_or_default();
+    }
+
+    #[cfg(unix)]
+    {
+        cli.socket = cli.socket.take().or(file.socket);
+        cli.daemonize |= file.daemonize.unwrap_or(false);
+    }
+    cli.log_file = cli.log_file.take().or(file.log_file);
+    cli.log_rotate_bytes = cli.log_rotate_bytes.or(file.log_rotate_bytes);
+    cli.log_rotate_hours = cli.log_rotate_hours.or(file.log_rotate_hours);
+    cli.pid_file = cli.pid_file.take().or(file.pid_file);
+    cli.metrics_addr = cli.metrics_addr.or(file.metrics_addr);
+    cli.http_addr = cli.http_addr.or(file.http_addr);
+    #[cfg(feature = "grpc")]
+    {
+        cli.grpc_addr = cli.grpc_addr.or(file.grpc_addr);
+    }
+    cli.batch_window_us = cli.batch_window_us.or(file.batch_window_us);
+    cli.group_commit_us = cli.group_commit_us.or(file.group_commit_us);
+    cli.sync_interval_ms = cli.sync_interval_ms.or(file.sync_interval_ms);
+    cli.sled_cache_bytes = cli.sled_cache_bytes.or(file.sled_cache_bytes);
+    cli.sled_flush_every_ms = cli.sled_flush_every_ms.or(file.sled_flush_every_ms);
+    cli.compaction_threshold = cli.compaction_threshold.or(file.compaction_threshold);
+    cli.tombstone_max_age_secs = cli.tombstone_max_age_secs.or(file.tombstone_max_age_secs);
+    cli.tombstone_max_count = cli.tombstone_max_count.or(file.tombstone_max_count);
+    cli.compaction_backpressure_ms = cli
+        .compaction_backpressure_ms
+        .or(file.compaction_backpressure_ms);
+    cli.write_stall_bytes = cli.write_stall_bytes.or(file.write_stall_bytes);
+    cli.write_stall_delay_ms = cli.write_stall_delay_ms.or(file.write_stall_delay_ms);
+    cli.mmap_reads |= file.mmap_reads.unwrap_or(false);
+    cli.value_index |= file.value_index.unwrap_or(false);
+    cli.strict_recovery |= file.strict_recovery.unwrap_or(false);
+    cli.queue_limit = cli.queue_limit.or(file.queue_limit);
+    cli.max_connections = cli.max_connections.or(file.max_connections);
+    cli.idle_timeout = cli.idle_timeout.or(file.idle_timeout);
+    cli.max_rps = cli.max_rps.or(file.max_rps);
+    cli.burst = cli.burst.or(file.burst);
+    cli.acl_file = cli.acl_file.take().or(file.acl_file);
+    cli.max_key_bytes = cli.max_key_bytes.or(file.max_key_bytes);
+    cli.max_value_bytes = cli.max_value_bytes.or(file.max_value_bytes);
+    cli.slow_request_ms = cli.slow_request_ms.or(file.slow_request_ms);
+    cli.slow_op_ms = cli.slow_op_ms.or(file.slow_op_ms);
+    cli.data_dir = cli.data_dir.take().or(file.data_dir);
+    cli.raft_id = cli.raft_id.or(file.raft_id);
+    cli.raft_addr = cli.raft_addr.or(file.raft_addr);
+    cli.replication_addr = cli.replication_addr.or(file.replication_addr);
+    cli.replica_of = cli.replica_of.or(file.replica_of);
+    cli.cluster |= file.cluster.unwrap_or(false);
+    Ok(())
+}
+
+/// What `run` leaves its caller to report.
+#[derive(Debug)]
+pub enum Outcome {
+    /// The server was served until it stopped.
+    Served,
+    /// `--check-config` was given: the configuration the server would run with, as the
+    /// lines of a config file giving it, and its problems.
+    ConfigChecked {
+        config: Vec<String>,
+        problems: Vec<String>,
+    },
+}
+
+/// The problems with a configuration, found once a server's been told to run with it.
+#[derive(Debug)]
+pub struct InvalidConfig {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration: {}", self.problems.join("; "))
+    }
+}
+
+impl Error for InvalidConfig {}
+
+/// Run `kvs-server`: read the command line and any config file, open the engine they name
+/// and serve it until stopped. Problems found before detaching fail with `InvalidConfig`;
+/// those found later are logged, and exit the process.
+pub fn run() -> Result<Outcome, Box<dyn Error>> {
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    apply_config_file(&mut cli, &matches)?;
+    let data_dir = match &cli.data_dir {
+        Some(data_dir) => current_dir()?.join(data_dir),
+        None => current_dir()?,
+    };
+
+    if cli.check_config {
+        return Ok(Outcome::ConfigChecked {
+            config: config_lines(&cli, &data_dir),
+            problems: validate(&cli, &data_dir),
+        });
+    }
+
+    // A detached server has no terminal to log to.
+    #[cfg(unix)]
+    if cli.daemonize {
+        cli.log_file = cli.log_file.or_else(|| Some(data_dir.join(LOG_FILE)));
+        detach(&cli, &data_dir)?;
+    }
+    if let Some(path) = &cli.pid_file {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .map_err(|err| format!("unable to write pid file {}: {}", path.display(), err))?;
+    }
+    let log = logger(&cli)?;
+    #[cfg(feature = "tracing")]
+    tracing::subscriber::set_global_default(crate::SpanLog::new(log.clone()))?;
+
+    info!(log, "starting up"; "version" => env!("CARGO_PKG_VERSION"));
+    info!(
+        log,
+        "using configuration";
+        "engine" => &cli.engine, "ip-port" => cli.addr.to_string(),
+        "data-dir" => data_dir.to_str()
+    );
+
+    if let Err(err) = std::fs::create_dir_all(&data_dir) {
+        error!(
+            log,
+            "unable to create data directory {}: {}",
+            data_dir.display(),
+            err
+        );
+        log.fuse();
+        std::process::exit(1);
+    }
+    let problems = validate(&cli, &data_dir);
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!(log, "{}", problem);
+        }
+        error!(log, "invalid configuration; quitting!");
+        log.fuse();
+        std::process::exit(1);
+    }
+
+    data_dir::set_engine(&data_dir, &cli.engine)?;
+
+    let engine = match cli.engine.as_str() {
+        "kvs" => {
+            info!(log, "kvs store"; "directory" => data_dir.to_str());
+            let opened = if cli.strict_recovery {
+                KvStore::open_strict(&data_dir)
+            } else {
+                KvStore::open(&data_dir)
+            };
+            let engine = match opened {
+                Ok(engine) => engine,
+                Err(err) => {
+                    error!(log, "unable to open the kvs store: {}", err);
+                    log.fuse();
+                    std::process::exit(1);
+                }
+            };
+            if let Some(tail) = engine.torn_tail() {
+                warn!(
+                    log,
+                    "cut a partly written record off the end of log {}", tail.log_number;
+                    "offset" => tail.offset, "bytes" => tail.dropped_bytes
+                );
+            }
+            let mut engine = engine.with_size_limits(size_limits(&cli));
+            if let Some(bytes) = cli.compaction_threshold {
+                engine = engine.with_compaction_threshold(bytes);
+            }
+            engine = engine.with_tombstone_horizon(TombstoneHorizon {
+                max_age: cli.tombstone_max_age_secs.map(Duration::from_secs),
+                max_count: cli.tombstone_max_count,
+            });
+            if cli.mmap_reads {
+                engine = engine.with_mmap_reads()?;
+            }
+            if cli.value_index {
+                engine.create_value_index()?;
+            }
+            if let Some(micros) = cli.group_commit_us {
+                engine = engine
+                    .with_group_commit(Duration::from_micros(micros), cli.group_commit_bytes)?;
+            }
+            if let Some(bytes) = cli.write_stall_bytes {
+                let stall = match cli.write_stall_delay_ms {
+                    Some(millis) => WriteStall::Delay(Duration::from_millis(millis)),
+                    None => WriteStall::Refuse,
+                };
+                engine = engine.with_write_stall(bytes, stall);
+            }
+            if let Some(millis) = cli.sync_interval_ms {
+                engine = engine.with_background_sync(Duration::from_millis(millis))?;
+            }
+            BoxedEngine::new(engine)
+        }
+        "sled" => {
+            info!(log, "sled engine"; "directory" => data_dir.to_str());
+            let mut config = sled::Config::new().path(&data_dir);
+            if let Some(bytes) = cli.sled_cache_bytes {
+                config = config.cache_capacity(bytes);
+            }
+            if let Some(millis) = cli.sled_flush_every_ms {
+                config = config.flush_every_ms(Some(millis).filter(|&ms| ms > 0));
+            }
+            let engine = SledKvsEngine::with_config(config, cli.sled_durability)?
+                .with_size_limits(size_limits(&cli));
+            BoxedEngine::new(engine)
+        }
+        #[cfg(feature = "rocksdb")]
+        "rocksdb" => {
+            info!(log, "rocksdb engine"; "directory" => data_dir.to_str());
+            let engine = RocksKvsEngine::open(&data_dir)?.with_size_limits(size_limits(&cli));
+            BoxedEngine::new(engine)
+        }
+        name => {
+            info!(log, "registered engine {}", name; "directory" => data_dir.to_str());
+            match open_registered(name, &data_dir) {
+                Ok(engine) => engine,
+                Err(err) => {
+                    error!(log, "unable to open the {} engine: {}", name, err);
+                    log.fuse();
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+    // Built with tracing, every operation is traced, so the engine is wrapped even when
+    // slow ones aren't logged.
+    let slow_op = cli
+        .slow_op_ms
+        .map(Duration::from_millis)
+        .or_else(|| cfg!(feature = "tracing").then_some(Duration::MAX));
+    let engine = match slow_op {
+        Some(threshold) => BoxedEngine::new(Traced::new(engine, log.clone(), threshold)),
+        None => engine,
+    };
+    serve(engine, log, &cli, &data_dir)?;
+    Ok(Outcome::Served)
+}
+
+// Where a detached server logs without --log-file, in its data directory.
+#[cfg(unix)]
+const LOG_FILE: &str = "kvs-server.log";
+
+/// Detach from the terminal, once the configuration is known to be good, so that its
+/// problems are reported there. Anything written to stderr from then on, such as a panic,
+/// goes to the log file.
+#[cfg(unix)]
+fn detach(cli: &Cli, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let problems = validate(cli, data_dir);
+    if !problems.is_empty() {
+        return Err(InvalidConfig { problems }.into());
+    }
+    std::fs::create_dir_all(data_dir)?;
+    let log_path = cli
+        .log_file
+        .as_deref()
+        .expect("a detached server logs to a file");
+    let stderr = open_log(log_path)?;
+    Daemonize::new()
+        .working_directory(current_dir()?)
+        .stderr(stderr)
+        .start()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn open_log(path: &Path) -> Result<File, Box<dyn Error>> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("unable to open log file {}: {}", path.display(), err))?)
+}
+
+fn logger(cli: &Cli) -> Result<Logger, Box<dyn Error>> {
+    let drain = match (&cli.log_file, cli.log_format) {
+        (Some(path), format) => {
+            let rotation = Rotation {
+                max_bytes: cli.log_rotate_bytes,
+                max_age: cli
+                    .log_rotate_hours
+                    .map(|hours| Duration::from_secs(hours * 60 * 60)),
+                keep: cli.log_keep,
+            };
+            let file = LogFile::open(path, rotation)
+                .map_err(|err| format!("unable to open log file {}: {}", path.display(), err))?;
+            match format {
+                LogFormat::Term => async_drain(
+                    CompactFormat::new(PlainDecorator::new(file)).build(),
+                    cli.log_level,
+                ),
+                LogFormat::Json => async_drain(json_drain(file), cli.log_level),
+            }
+        }
+        (None, LogFormat::Term) => async_drain(
+            CompactFormat::new(TermDecorator::new().stderr().build()).build(),
+            cli.log_level,
+        ),
+        (None, LogFormat::Json) => async_drain(json_drain(io::stderr()), cli.log_level),
+    };
+    Ok(Logger::root(drain, o!()))
+}
+
+// Log messages of `level` and above through `drain`, off the threads logging them.
+fn async_drain<D>(drain: D, level: Level) -> Fuse<Async>
+where
+    D: Drain<Ok = (), Err = io::Error> + Send + 'static,
+{
+    let drain = LevelFilter::new(drain.fuse(), level).fuse();
+    Async::new(drain).build().fuse()
+}
+
+fn json_drain<W: Write + Send + 'static>(writer: W) -> impl Drain<Ok = (), Err = io::Error> {
+    Json::new(writer).add_default_keys().build()
+}
+
+fn size_limits(cli: &Cli) -> SizeLimits {
+    SizeLimits {
+        max_key_bytes: cli.max_key_bytes,
+        max_value_bytes: cli.max_value_bytes,
+    }
+}
+
+// The configuration the server would run with, as the lines of a config file giving it.
+fn config_lines(cli: &Cli, data_dir: &Path) -> Vec<String> {
+    let mut lines = Vec::new();
+    let or_none = |value: Option<String>| value.unwrap_or_else(|| "none".to_owned());
+    lines.push(format!("addr = {}", cli.addr));
+    #[cfg(unix)]
+    lines.push(format!(
+        "socket = {}",
+        or_none(cli.socket.as_ref().map(|path| path.display().to_string()))
+    ));
+    lines.push(format!("engine = {}", cli.engine));
+    lines.push(format!("protocol = {}", cli.protocol));
+    lines.push(format!(
+        "metrics-addr = {}",
+        or_none(cli.metrics_addr.map(|a| a.to_string()))
+    ));
+    lines.push(format!(
+        "http-addr = {}",
+        or_none(cli.http_addr.map(|a| a.to_string()))
+    ));
+    #[cfg(feature = "grpc")]
+    lines.push(format!(
+        "grpc-addr = {}",
+        or_none(cli.grpc_addr.map(|a| a.to_string()))
+    ));
+    lines.push(format!(
+        "batch-window-us = {}",
+        or_none(cli.batch_window_us.map(|us| us.to_string()))
+    ));
+    lines.push(format!(
+        "group-commit-us = {}",
+        or_none(cli.group_commit_us.map(|us| us.to_string()))
+    ));
+    lines.push(format!("group-commit-bytes = {}", cli.group_commit_bytes));
+    lines.push(format!(
+        "sync-interval-ms = {}",
+        or_none(cli.sync_interval_ms.map(|ms| ms.to_string()))
+    ));
+    lines.push(format!(
+        "sled-cache-bytes = {}",
+        or_none(cli.sled_cache_bytes.map(|bytes| bytes.to_string()))
+    ));
+    lines.push(format!(
+        "sled-flush-every-ms = {}",
+        or_none(cli.sled_flush_every_ms.map(|ms| ms.to_string()))
+    ));
+    lines.push(format!("sled-durability = {}", cli.sled_durability));
+    lines.push(format!("thread-pool = {}", cli.thread_pool));
+    lines.push(format!("threads = {}", cli.threads));
+    lines.push(format!(
+        "queue-limit = {}",
+        or_none(cli.queue_limit.map(|limit| limit.to_string()))
+    ));
+    lines.push(format!(
+        "max-connections = {}",
+        or_none(cli.max_connections.map(|limit| limit.to_string()))
+    ));
+    lines.push(format!(
+        "idle-timeout = {}",
+        or_none(cli.idle_timeout.map(|secs| secs.to_string()))
+    ));
+    lines.push(format!(
+        "max-rps = {}",
+        or_none(cli.max_rps.map(|rps| rps.to_string()))
+    ));
+    lines.push(format!(
+        "burst = {}",
+        or_none(cli.burst.map(|requests| requests.to_string()))
+    ));
+    lines.push(format!(
+        "acl-file = {}",
+        or_none(cli.acl_file.as_ref().map(|path| path.display().to_string()))
+    ));
+    lines.push(format!(
+        "max-key-bytes = {}",
+        or_none(cli.max_key_bytes.map(|bytes| bytes.to_string()))
+    ));
+    lines.push(format!(
+        "max-value-bytes = {}",
+        or_none(cli.max_value_bytes.map(|bytes| bytes.to_string()))
+    ));
+    lines.push(format!(
+        "compaction-threshold = {}",
+        or_none(cli.compaction_threshold.map(|bytes| bytes.to_string()))
+    ));
+    lines.push(format!(
+        "tombstone-max-age-secs = {}",
+        or_none(cli.tombstone_max_age_secs.map(|secs| secs.to_string()))
+    ));
+    lines.push(format!(
+        "tombstone-max-count = {}",
+        or_none(cli.tombstone_max_count.map(|count| count.to_string()))
+    ));
+    lines.push(format!(
+        "compaction-backpressure-ms = {}",
+        or_none(cli.compaction_backpressure_ms.map(|ms| ms.to_string()))
+    ));
+    lines.push(format!(
+        "write-stall-bytes = {}",
+        or_none(cli.write_stall_bytes.map(|bytes| bytes.to_string()))
+    ));
+    lines.push(format!(
+        "write-stall-delay-ms = {}",
+        or_none(cli.write_stall_delay_ms.map(|ms| ms.to_string()))
+    ));
+    lines.push(format!("mmap-reads = {}", cli.mmap_reads));
+    lines.push(format!("value-index = {}", cli.value_index));
+    lines.push(format!("strict-recovery = {}", cli.strict_recovery));
+    lines.push(format!(
+        "log-level = {}",
+        cli.log_level.as_str().to_lowercase()
+    ));
+    lines.push(format!(
+        "log-file = {}",
+        or_none(cli.log_file.as_ref().map(|path| path.display().to_string()))
+    ));
+    lines.push(format!("log-format = {}", cli.log_format));
+    lines.push(format!(
+        "log-rotate-bytes = {}",
+        or_none(cli.log_rotate_bytes.map(|bytes| bytes.to_string()))
+    ));
+    lines.push(format!(
+        "log-rotate-hours = {}",
+        or_none(cli.log_rotate_hours.map(|hours| hours.to_string()))
+    ));
+    lines.push(format!("log-keep = {}", cli.log_keep));
+    #[cfg(unix)]
+    lines.push(format!("daemonize = {}", cli.daemonize));
+    lines.push(format!(
+        "pid-file = {}",
+        or_none(cli.pid_file.as_ref().map(|path| path.display().to_string()))
+    ));
+    lines.push(format!(
+        "slow-request-ms = {}",
+        or_none(cli.slow_request_ms.map(|ms| ms.to_string()))
+    ));
+    lines.push(format!(
+        "slow-op-ms = {}",
+        or_none(cli.slow_op_ms.map(|ms| ms.to_string()))
+    ));
+    lines.push(format!(
+        "raft-id = {}",
+        or_none(cli.raft_id.map(|id| id.to_string()))
+    ));
+    lines.push(format!(
+        "raft-addr = {}",
+        or_none(cli.raft_addr.map(|a| a.to_string()))
+    ));
+    for peer in &cli.raft_peer {
+        lines.push(format!(
+            "raft-peer = {},{},{}",
+            peer.id, peer.raft_addr, peer.client_addr
+        ));
+    }
+    lines.push(format!(
+        "replication-addr = {}",
+        or_none(cli.replication_addr.map(|a| a.to_string()))
+    ));
+    lines.push(format!(
+        "replica-of = {}",
+        or_none(cli.replica_of.map(|a| a.to_string()))
+    ));
+    lines.push(format!("cluster = {}", cli.cluster));
+    for peer in &cli.cluster_peer {
+        lines.push(format!("cluster-peer = {}", peer));
+    }
+    lines.push(format!("cluster-slots = {}", cli.cluster_slots));
+    lines.push(format!("data-dir = {}", data_dir.display()));
+    lines
+}
+
+/// Return every problem with the configuration that would stop the server from running correctly.
+fn validate(cli: &Cli, data_dir: &Path) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if cli.metrics_addr == Some(cli.addr) {
+        problems.push(format!(
+            "metrics address must differ from the server address ({})",
+            cli.addr
+        ));
+    }
+    if let Some(http_addr) = cli.http_addr {
+        if http_addr == cli.addr || Some(http_addr) == cli.metrics_addr {
+            problems.push(format!(
+                "http address must differ from the server and metrics addresses ({})",
+                http_addr
+            ));
+        }
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = cli.grpc_addr {
+        if grpc_addr == cli.addr
+            || Some(grpc_addr) == cli.metrics_addr
+            || Some(grpc_addr) == cli.http_addr
+        {
+            problems.push(format!(
+                "grpc address must differ from the server, metrics and http addresses ({})",
+                grpc_addr
+            ));
+        }
+    }
+    let mut engines: Vec<String> = BUILTIN_ENGINES
+        .iter()
+        .map(|&name| name.to_owned())
+        .collect();
+    engines.extend(registered_engines());
+    if !engines.contains(&cli.engine) {
+        problems.push(format!(
+            "unknown engine {}; the engines are {}",
+            cli.engine,
+            engines.join(", ")
+        ));
+    }
+    if cli.batch_window_us == Some(0) {
+        problems.push("batch window must be greater than 0 microseconds".to_owned());
+    }
+    if cli.group_commit_us.is_some() && cli.engine != "kvs" {
+        problems.push("group commit is only supported by the kvs engine".to_owned());
+    }
+    if cli.group_commit_us == Some(0) {
+        problems.push("group commit window must be greater than 0 microseconds".to_owned());
+    }
+    if cli.sync_interval_ms.is_some() && cli.engine != "kvs" {
+        problems.push("background sync is only supported by the kvs engine".to_owned());
+    }
+    if cli.sync_interval_ms == Some(0) {
+        problems.push("a sync interval must be at least 1 millisecond".to_owned());
+    }
+    if cli.compaction_threshold.is_some() && cli.engine != "kvs" {
+        problems.push("a compaction threshold is only supported by the kvs engine".to_owned());
+    }
+    if cli.write_stall_bytes.is_some() && cli.engine != "kvs" {
+        problems.push("write stalls are only supported by the kvs engine".to_owned());
+    }
+    if cli.write_stall_delay_ms.is_some() && cli.write_stall_bytes.is_none() {
+        problems.push("a write stall delay needs a write stall limit".to_owned());
+    }
+    if let (Some(stall), Some(threshold)) = (cli.write_stall_bytes, cli.compaction_threshold) {
+        if stall <= threshold {
+            problems.push(format!(
+                "the write stall limit must be over the compaction threshold ({} bytes)",
+                threshold
+            ));
+        }
+    }
+    let horizon = cli.tombstone_max_age_secs.is_some() || cli.tombstone_max_count.is_some();
+    if horizon && cli.engine != "kvs" {
+        problems.push("a tombstone horizon is only supported by the kvs engine".to_owned());
+    }
+    if cli.tombstone_max_age_secs == Some(0) {
+        problems.push("a tombstone age must be at least 1 second".to_owned());
+    }
+    if cli.tombstone_max_count == Some(0) {
+        problems.push("a tombstone count must be at least 1 remove".to_owned());
+    }
+    if cli.compaction_backpressure_ms.is_some() && cli.engine != "kvs" {
+        problems.push("compaction backpressure is only supported by the kvs engine".to_owned());
+    }
+    if cli.compaction_backpressure_ms == Some(0) {
+        problems.push("a compaction retry delay must be at least 1 millisecond".to_owned());
+    }
+    if cli.mmap_reads && cli.engine != "kvs" {
+        problems.push("mmap reads are only supported by the kvs engine".to_owned());
+    }
+    if cli.value_index && cli.engine != "kvs" {
+        problems.push("value indexes are only supported by the kvs engine".to_owned());
+    }
+    if cli.strict_recovery && cli.engine != "kvs" {
+        problems.push("strict recovery is only supported by the kvs engine".to_owned());
+    }
+    let rotates = cli.log_rotate_bytes.is_some() || cli.log_rotate_hours.is_some();
+    #[cfg(unix)]
+    let logs_to_file = cli.log_file.is_some() || cli.daemonize;
+    #[cfg(not(unix))]
+    let logs_to_file = cli.log_file.is_some();
+    if rotates && !logs_to_file {
+        problems.push("log rotation needs a log file".to_owned());
+    }
+    if cli.log_rotate_bytes == Some(0) || cli.log_rotate_hours == Some(0) {
+        problems.push("log rotation limits must be at least 1".to_owned());
+    }
+    if cli.threads == 0 {
+        problems.push("a thread pool needs at least 1 thread".to_owned());
+    }
+    if cli.queue_limit.is_some() && cli.thread_pool != ThreadPoolKind::SharedQueue {
+        problems.push("a queue limit is only supported by the shared-queue thread pool".to_owned());
+    }
+    if cli.queue_limit == Some(0) {
+        problems.push("a queue limit must be at least 1".to_owned());
+    }
+    #[cfg(unix)]
+    if cli.socket.is_some() && cli.raft_id.is_some() {
+        problems.push(
+            "a replicated server must serve on --addr, which other members send clients to"
+                .to_owned(),
+        );
+    }
+    if cli.max_connections == Some(0) {
+        problems.push("a connection limit must be at least 1".to_owned());
+    }
+    if cli.idle_timeout == Some(0) {
+        problems.push("an idle timeout must be at least 1 second".to_owned());
+    }
+    if cli.max_rps == Some(0) {
+        problems.push("a rate limit must be at least 1 request a second".to_owned());
+    }
+    if cli.burst.is_some() && cli.max_rps.is_none() {
+        problems.push("a burst needs a rate limit".to_owned());
+    }
+    if cli.burst == Some(0) {
+        problems.push("a burst must be at least 1 request".to_owned());
+    }
+    if let Some(path) = &cli.acl_file {
+        match Acl::load(path) {
+            // Other members gossip with the same token, which this member's ACL must allow.
+            Ok(acl) if cli.cluster => {
+                let gossip = Request::Gossip {
+                    slot_count: cli.cluster_slots,
+                    members: Vec::new(),
+                };
+                if acl.check(cli.cluster_token.as_deref(), &gossip).is_err() {
+                    let problem = match cli.cluster_token {
+                        Some(_) => "the ACL must grant the cluster token the admin role",
+                        None => {
+                            "a cluster member with an ACL needs a --cluster-token it grants \
+                                 the admin role, unless its anonymous grant is admin"
+                        }
+                    };
+                    problems.push(problem.to_owned());
+                }
+            }
+            Ok(_) => {}
+            Err(err) => problems.push(err.to_string()),
+        }
+    }
+    if cli.max_key_bytes == Some(0) {
+        problems.push("a key size limit must be at least 1 byte".to_owned());
+    }
+    let sled_tuned = cli.sled_cache_bytes.is_some()
+        || cli.sled_flush_every_ms.is_some()
+        || cli.sled_durability != Durability::EveryWrite;
+    if sled_tuned && cli.engine != "sled" {
+        problems.push("sled options are only supported by the sled engine".to_owned());
+    }
+    if cli.sled_durability == Durability::Background && cli.sled_flush_every_ms == Some(0) {
+        problems.push("background durability needs sled's background flushes".to_owned());
+    }
+
+    match (cli.raft_id, cli.raft_addr) {
+        (Some(id), Some(raft_addr)) => {
+            if raft_addr == cli.addr
+                || Some(raft_addr) == cli.metrics_addr
+                || Some(raft_addr) == cli.http_addr
+            {
+                problems.push(format!(
+                    "raft address must differ from the server, metrics and http addresses ({})",
+                    raft_addr
+                ));
+            }
+            let mut ids = vec![id];
+            for peer in &cli.raft_peer {
+                if ids.contains(&peer.id) {
+                    problems.push(format!("raft id {} is used more than once", peer.id));
+                }
+                ids.push(peer.id);
+            }
+            if cli.batch_window_us.is_some() {
+                problems.push("a batch window can't be combined with raft".to_owned());
+            }
+        }
+        (None, None) if cli.raft_peer.is_empty() => {}
+        _ => problems.push("raft needs both --raft-id and --raft-addr".to_owned()),
+    }
+
+    if let Some(replication_addr) = cli.replication_addr {
+        if replication_addr == cli.addr
+            || Some(replication_addr) == cli.metrics_addr
+            || Some(replication_addr) == cli.http_addr
+            || Some(replication_addr) == cli.raft_addr
+        {
+            problems.push(format!(
+                "replication address must differ from the server, metrics, http and raft addresses ({})",
+                replication_addr
+            ));
+        }
+        // Members apply the leader's writes without publishing them, so they'd go unshipped.
+        if cli.raft_id.is_some() {
+            problems.push("a member of a raft group can't ship its changes to replicas".to_owned());
+        }
+    }
+    if let Some(primary) = cli.replica_of {
+        if cli.raft_id.is_some() {
+            problems.push("a replica can't be a member of a raft group".to_owned());
+        }
+        if Some(primary) == cli.replication_addr {
+            problems.push("a replica can't follow itself".to_owned());
+        }
+    } else if cli.replication_token.is_some() {
+        problems.push("a replication token needs --replica-of".to_owned());
+    }
+    if cli.cluster {
+        if cli.addr.ip().is_unspecified() {
+            problems.push(format!(
+                "a cluster member must serve on an address the others can reach, not {}",
+                cli.addr
+            ));
+        }
+        #[cfg(unix)]
+        if cli.socket.is_some() {
+            problems.push(
+                "a cluster member must serve on --addr, which other members send clients to"
+                    .to_owned(),
+            );
+        }
+        if cli.cluster_peer.contains(&cli.addr) {
+            problems.push("a cluster member can't be its own peer".to_owned());
+        }
+        if cli.cluster_slots == 0 {
+            problems.push("a cluster needs at least 1 slot".to_owned());
+        }
+        // Clients are sent to the member owning a key, which must take its writes itself.
+        if cli.raft_id.is_some() {
+            problems.push("a member of a raft group can't be a member of a cluster".to_owned());
+        }
+        if cli.replica_of.is_some() {
+            problems.push("a replica can't be a member of a cluster".to_owned());
+        }
+    } else if !cli.cluster_peer.is_empty() {
+        problems.push("cluster peers need --cluster".to_owned());
+    } else if cli.cluster_token.is_some() {
+        problems.push("a cluster token needs --cluster".to_owned());
+    }
+
+    match data_dir::engine(data_dir) {
+        Ok(Some(last_engine)) if last_engine != cli.engine => problems.push(format!(
+            "{} was chosen, but last engine was {}; `kvs migrate` moves the data to {}",
+            cli.engine, last_engine, cli.engine
+        )),
+        Ok(_) => {}
+        Err(err) => problems.push(format!("unable to read the last engine: {}", err)),
+    }
+    match data_dir::moved_to(data_dir) {
+        Ok(Some(moved)) => problems.push(format!(
+            "data directory {} was moved to {}",
+            data_dir.display(),
+            moved.display()
+        )),
+        Ok(None) => {}
+        Err(err) => problems.push(format!(
+            "unable to check for a moved data directory: {}",
+            err
+        )),
+    }
+
+    // A data directory that doesn't exist yet is created in the nearest one that does.
+    let writable_dir = data_dir
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(data_dir);
+    let probe = writable_dir.join(".kvs-check-config");
+    if let Err(err) = std::fs::write(&probe, b"").and_then(|()| std::fs::remove_file(&probe)) {
+        problems.push(format!(
+            "data directory {} is not writable: {}",
+            data_dir.display(),
+            err
+        ));
+    }
+
+    problems
+}
+
+fn serve(
+    engine: BoxedEngine,
+    log: Logger,
+    cli: &Cli,
+    data_dir: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let mut server = KvsServer::new(engine, log)
+        .with_thread_pool(cli.thread_pool, cli.threads)
+        .with_protocol(cli.protocol)
+        .with_size_limits(size_limits(cli));
+    if let Some(limit) = cli.queue_limit {
+        server = server.with_queue_limit(limit);
+    }
+    if let Some(limit) = cli.max_connections {
+        server = server.with_max_connections(limit);
+    }
+    if let Some(secs) = cli.idle_timeout {
+        server = server.with_idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(max_rps) = cli.max_rps {
+        server = server.with_rate_limit(max_rps, cli.burst.unwrap_or(max_rps));
+    }
+    if let Some(path) = &cli.acl_file {
+        server = server.with_acl(Acl::load(path)?);
+    }
+    if let Some(metrics_addr) = cli.metrics_addr {
+        server = server.with_metrics_addr(metrics_addr);
+    }
+    if let Some(http_addr) = cli.http_addr {
+        server = server.with_http_addr(http_addr);
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = cli.grpc_addr {
+        server = server.with_grpc_addr(grpc_addr);
+    }
+    if let Some(millis) = cli.slow_request_ms {
+        server = server.with_slow_request_threshold(Duration::from_millis(millis));
+    }
+    if let Some(millis) = cli.compaction_backpressure_ms {
+        server = server.with_compaction_backpressure(Duration::from_millis(millis));
+    }
+    if let Some(micros) = cli.batch_window_us {
+        server = server.with_batch_window(Duration::from_micros(micros));
+    }
+    if let (Some(id), Some(raft_addr)) = (cli.raft_id, cli.raft_addr) {
+        server = server.with_raft(RaftConfig::new(
+            id,
+            raft_addr,
+            cli.raft_peer.clone(),
+            data_dir.join("raft"),
+        ));
+    }
+    if let Some(replication_addr) = cli.replication_addr {
+        server = server.with_replication_addr(replication_addr);
+    }
+    if let Some(primary) = cli.replica_of {
+        server = server.with_primary(primary);
+    }
+    if let Some(token) = &cli.replication_token {
+        server = server.with_replication_token(token.clone());
+    }
+    if cli.cluster {
+        let mut config = ClusterConfig::new(cli.addr, cli.cluster_peer.clone());
+        config.slot_count = cli.cluster_slots;
+        config.token = cli.cluster_token.clone();
+        server = server.with_cluster(config);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &cli.socket {
+        server.serve_unix(path)?;
+        return Ok(());
+    }
+    server.serve(&cli.addr)?;
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! A client that shards keys over several servers by consistent hashing; see
+//! `ShardedKvsClient`.
+
+use crate::client::KvsClient;
+use crate::client::KvsClientBuilder;
+use crate::ring;
+use crate::ring::HashRing;
+use crate::KvsError;
+use crate::Result;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// A client of several servers, each holding a share of the keys, which routes each request
+/// to the server owning its key. Keys are placed as `kvs rebalance` places them: hashed into
+/// slots, and slots onto a `HashRing` of the servers, so adding a server moves only its
+/// share of the keys, and `kvs rebalance` moves them. The servers needn't know they're
+/// sharded.
+///
+/// Requests about several keys are split among the servers owning them, and requests about
+/// every key are made of every server, with their answers put together. Each server is
+/// connected to on first use, and the connection kept.
+pub struct ShardedKvsClient {
+    servers: Vec<SocketAddr>,
+    ring: HashRing,
+    // The options to connect with, if not the defaults.
+    options: Option<KvsClientBuilder>,
+    clients: HashMap<SocketAddr, KvsClient>,
+}
+
+impl ShardedKvsClient {
+    /// Shard over `servers`, with the default number of slots.
+    pub fn new(servers: &[SocketAddr]) -> Self {
+        let mut unique = Vec::new();
+        for server in servers {
+            if !unique.contains(server) {
+                unique.push(*server);
+            }
+        }
+        Self {
+            ring: HashRing::new(&unique, ring::DEFAULT_SLOT_COUNT),
+            servers: unique,
+            options: None,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Shard over the members of the cluster the server at `addr` is a member of, as it
+    /// places keys on them; see `kvs::cluster`. Requests about the keys of members that join
+    /// later are redirected to them.
+    pub fn from_cluster(addr: &SocketAddr) -> Result<Self> {
+        let map = KvsClient::connect(addr)?.cluster_map()?;
+        Ok(Self::new(&map.members).with_slot_count(map.slot_count))
+    }
+
+    /// Hash keys into `slot_count` slots, as `kvs rebalance --slots` does, rather than the
+    /// default number.
+    pub fn with_slot_count(mut self, slot_count: u32) -> Self {
+        self.ring = HashRing::new(&self.servers, slot_count);
+        self
+    }
+
+    /// Connect to each server with the options of `options`, but over TCP to the server's
+    /// own address, whatever `options` connects to.
+    pub fn with_options(mut self, options: KvsClientBuilder) -> Self {
+        self.options = Some(options);
+        self.clients.clear();
+        self
+    }
+
+    /// The server owning `key`, or None if there are no servers.
+    pub fn server_for_key(&self, key: &str) -> Option<SocketAddr> {
+        self.ring.node_for_key(key)
+    }
+
+    /// The client of the server owning `key`, to make any request about it.
+    pub fn client_for_key(&mut self, key: &str) -> Result<&mut KvsClient> {
+        let server = self.server_for_key(key).ok_or_else(no_servers)?;
+        self.client(server)
+    }
+
+    // The client of `server`, connecting to it if it hasn't been yet.
+    fn client(&mut self, server: SocketAddr) -> Result<&mut KvsClient> {
+        if !self.clients.contains_key(&server) {
+            let options = match &self.options {
+                Some(options) => options.for_addr(server),
+                None => KvsClientBuilder::new(server),
+            };
+            let client = options.connect()?;
+            self.clients.insert(server, client);
+        }
+        Ok(self.clients.get_mut(&server).unwrap())
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.client_for_key(&key)?.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.client_for_key(&key)?.set(key, value)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.client_for_key(&key)?.remove(key)
+    }
+
+    pub fn contains(&mut self, key: String) -> Result<bool> {
+        self.client_for_key(&key)?.contains(key)
+    }
+
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.client_for_key(&key)?.incr(key, delta)
+    }
+
+    pub fn append(&mut self, key: String, suffix: String) -> Result<u64> {
+        self.client_for_key(&key)?.append(key, suffix)
+    }
+
+    /// Move a key's value to another key owned by the same server. Fails for keys owned by
+    /// different servers, since no server could move the value at once.
+    pub fn rename(&mut self, old_key: String, new_key: String) -> Result<()> {
+        if self.server_for_key(&old_key) != self.server_for_key(&new_key) {
+            return Err(KvsError::StringError(format!(
+                "{:?} and {:?} are owned by different servers",
+                old_key, new_key
+            )));
+        }
+        self.client_for_key(&old_key)?.rename(old_key, new_key)
+    }
+
+    /// Get the values of several keys, in the order of `keys`, in one round trip to each
+    /// server owning any of them.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut by_server: BTreeMap<SocketAddr, Vec<(usize, String)>> = BTreeMap::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            let server = self.server_for_key(&key).ok_or_else(no_servers)?;
+            by_server.entry(server).or_default().push((i, key));
+        }
+        let mut values = vec![None; by_server.values().map(Vec::len).sum()];
+        for (server, keys) in by_server {
+            let (positions, keys): (Vec<usize>, Vec<String>) = keys.into_iter().unzip();
+            let found = self.client(server)?.get_many(keys)?;
+            for (i, value) in positions.into_iter().zip(found) {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Return a page of up to `limit` keys of every server, in order, and the cursor to
+    /// pass for the next page, or None if this is the last; see `KvsClient::keys`.
+    pub fn keys(
+        &mut self,
+        cursor: Option<String>,
+        limit: u32,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let mut keys = Vec::new();
+        // The last key of the servers' pages that have more after them. Keys past the first
+        // of these may come after keys of its server not yet read, so they wait for the
+        // next page.
+        let mut cut: Option<String> = None;
+        for server in self.servers.clone() {
+            let (page, next) = self.client(server)?.keys(cursor.clone(), limit)?;
+            if let (Some(last), Some(_)) = (page.last(), next) {
+                if cut.as_ref().is_none_or(|cut| last < cut) {
+                    cut = Some(last.clone());
+                }
+            }
+            keys.extend(page);
+        }
+        keys.sort();
+        if let Some(cut) = &cut {
+            keys.retain(|key| key <= cut);
+        }
+        let more = cut.is_some() || keys.len() > limit as usize;
+        keys.truncate(limit as usize);
+        let cursor = if more { keys.last().cloned() } else { None };
+        Ok((keys, cursor))
+    }
+
+    /// The number of keys every server holds.
+    pub fn len(&mut self) -> Result<u64> {
+        let mut len = 0;
+        for server in self.servers.clone() {
+            len += self.client(server)?.len()?;
+        }
+        Ok(len)
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The keys set to `value` on every server, in order.
+    pub fn find_by_value(&mut self, value: String) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for server in self.servers.clone() {
+            keys.extend(self.client(server)?.find_by_value(value.clone())?);
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Have every server compact its engine now.
+    pub fn compact(&mut self) -> Result<()> {
+        for server in self.servers.clone() {
+            self.client(server)?.compact()?;
+        }
+        Ok(())
+    }
+}
+
+fn no_servers() -> KvsError {
+    KvsError::StringError("there are no servers to shard over".to_owned())
+}
@@ -0,0 +1,194 @@
+use crate::sync::Mutex;
+use slog::info;
+use slog::Logger;
+use slog::Record;
+use slog::Serializer;
+use slog::KV;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use thread_local::ThreadLocal;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::span;
+use tracing::Event;
+use tracing::Metadata;
+use tracing::Subscriber;
+use tracing_core::span::Current;
+
+// The field carrying a request's trace id, which every span within it is logged with.
+const TRACE_ID: &str = "trace_id";
+
+struct OpenSpan {
+    metadata: &'static Metadata<'static>,
+    fields: Vec<(&'static str, String)>,
+    trace_id: Option<String>,
+    start: Instant,
+    // Handles to the span still open; it's closed, and logged, once the last is dropped.
+    handles: usize,
+}
+
+impl Visit for OpenSpan {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, format!("{:?}", value));
+    }
+}
+
+impl OpenSpan {
+    fn set(&mut self, field: &Field, value: String) {
+        if field.name() == TRACE_ID {
+            self.trace_id = Some(value);
+            return;
+        }
+        match self
+            .fields
+            .iter_mut()
+            .find(|(name, _)| *name == field.name())
+        {
+            Some((_, old)) => *old = value,
+            None => self.fields.push((field.name(), value)),
+        }
+    }
+}
+
+impl KV for OpenSpan {
+    fn serialize(&self, _record: &Record, serializer: &mut dyn Serializer) -> slog::Result {
+        for (name, value) in &self.fields {
+            serializer.emit_str(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A `tracing` subscriber that logs every span once it closes, at info level, with its
+/// fields, how long it was open, and the trace id of the request it was part of, so the
+/// spans of one slow request can be picked out of the log by its trace id. `kvs-server`
+/// built with the `tracing` feature logs its spans with one.
+///
+/// Events are ignored; the crate logs with `slog` rather than `tracing` otherwise.
+pub struct SpanLog {
+    log: Logger,
+    spans: Mutex<HashMap<u64, OpenSpan>>,
+    last_id: AtomicU64,
+    // The spans each thread is in, innermost last.
+    entered: ThreadLocal<RefCell<Vec<u64>>>,
+}
+
+impl SpanLog {
+    pub fn new(log: Logger) -> Self {
+        Self {
+            log,
+            spans: Mutex::new(HashMap::new()),
+            last_id: AtomicU64::new(0),
+            entered: ThreadLocal::new(),
+        }
+    }
+
+    fn entered(&self) -> &RefCell<Vec<u64>> {
+        self.entered.get_or_default()
+    }
+}
+
+impl Subscriber for SpanLog {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span()
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let parent = match attrs.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attrs.is_contextual() => self.entered().borrow().last().copied(),
+            None => None,
+        };
+        let mut spans = self.spans.lock();
+        let mut span = OpenSpan {
+            metadata: attrs.metadata(),
+            fields: Vec::new(),
+            trace_id: None,
+            start: Instant::now(),
+            handles: 1,
+        };
+        attrs.record(&mut span);
+        if span.trace_id.is_none() {
+            span.trace_id = parent
+                .and_then(|parent| spans.get(&parent))
+                .and_then(|parent| parent.trace_id.clone());
+        }
+        spans.insert(id, span);
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, id: &span::Id, values: &span::Record<'_>) {
+        if let Some(span) = self.spans.lock().get_mut(&id.into_u64()) {
+            values.record(span);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, id: &span::Id) {
+        self.entered().borrow_mut().push(id.into_u64());
+    }
+
+    fn exit(&self, id: &span::Id) {
+        let mut entered = self.entered().borrow_mut();
+        if let Some(i) = entered
+            .iter()
+            .rposition(|&entered| entered == id.into_u64())
+        {
+            entered.remove(i);
+        }
+    }
+
+    fn clone_span(&self, id: &span::Id) -> span::Id {
+        if let Some(span) = self.spans.lock().get_mut(&id.into_u64()) {
+            span.handles += 1;
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: span::Id) -> bool {
+        let span = {
+            let mut spans = self.spans.lock();
+            match spans.get_mut(&id.into_u64()) {
+                Some(span) if span.handles > 1 => {
+                    span.handles -= 1;
+                    return false;
+                }
+                Some(_) => spans.remove(&id.into_u64()),
+                None => return false,
+            }
+        };
+        if let Some(span) = span {
+            info!(
+                self.log,
+                "span closed";
+                &span,
+                "span" => span.metadata.name(),
+                "trace_id" => span.trace_id.as_deref(),
+                "micros" => span.start.elapsed().as_micros() as u64
+            );
+        }
+        true
+    }
+
+    fn current_span(&self) -> Current {
+        let Some(id) = self.entered().borrow().last().copied() else {
+            return Current::none();
+        };
+        match self.spans.lock().get(&id) {
+            Some(span) => Current::new(span::Id::from_u64(id), span.metadata),
+            None => Current::none(),
+        }
+    }
+}
@@ -1,4 +1,11 @@
+use crate::error::KvsError;
 use crate::error::Result;
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 pub trait ThreadPool {
     fn new(threads: u32) -> Result<Self>
@@ -8,6 +15,22 @@ pub trait ThreadPool {
     fn spawn<F>(&self, task: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawn `task` unless the pool's queue is full, in which case hand it back. Pools
+    /// without a bound on their queue always spawn it.
+    fn try_spawn<F>(&self, task: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(task);
+        Ok(())
+    }
+
+    /// Number of tasks spawned that haven't started yet.
+    fn queued_tasks(&self) -> usize;
+
+    /// Number of tasks running.
+    fn active_workers(&self) -> usize;
 }
 
 mod naive;
@@ -18,3 +41,78 @@ pub use shared_queue::SharedQueueThreadPool;
 
 mod rayon;
 pub use self::rayon::RayonThreadPool;
+
+/// Names a `ThreadPool` implementation, for choosing one at run time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThreadPoolKind {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+impl fmt::Display for ThreadPoolKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Naive => write!(f, "naive"),
+            Self::SharedQueue => write!(f, "shared-queue"),
+            Self::Rayon => write!(f, "rayon"),
+        }
+    }
+}
+
+impl FromStr for ThreadPoolKind {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "naive" => Ok(Self::Naive),
+            "shared-queue" => Ok(Self::SharedQueue),
+            "rayon" => Ok(Self::Rayon),
+            _ => Err(KvsError::StringError(format!(
+                "invalid thread pool {:?}; expected naive, shared-queue or rayon",
+                s
+            ))),
+        }
+    }
+}
+
+// Counts of a pool's tasks waiting to start and running.
+#[derive(Default)]
+struct TaskCounts {
+    queued: AtomicUsize,
+    active: AtomicUsize,
+}
+
+impl TaskCounts {
+    // Count `task` as queued until it starts, then as active until it returns or panics.
+    fn track<F>(self: &Arc<Self>, task: F) -> impl FnOnce() + Send + 'static
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let counts = self.clone();
+        move || {
+            counts.queued.fetch_sub(1, Ordering::SeqCst);
+            counts.active.fetch_add(1, Ordering::SeqCst);
+            let _active = Active(counts);
+            task();
+        }
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+struct Active(Arc<TaskCounts>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
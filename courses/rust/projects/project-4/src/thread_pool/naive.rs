@@ -1,18 +1,32 @@
+use super::TaskCounts;
 use super::ThreadPool;
 use crate::error::Result;
+use std::sync::Arc;
 use std::thread;
 
-pub struct NaiveThreadPool;
+pub struct NaiveThreadPool {
+    counts: Arc<TaskCounts>,
+}
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: u32) -> Result<Self> {
-        Ok(Self)
+        Ok(Self {
+            counts: Arc::default(),
+        })
     }
 
     fn spawn<F>(&self, task: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(task);
+        thread::spawn(self.counts.track(task));
+    }
+
+    fn queued_tasks(&self) -> usize {
+        self.counts.queued()
+    }
+
+    fn active_workers(&self) -> usize {
+        self.counts.active()
     }
 }
@@ -1,9 +1,14 @@
+use super::TaskCounts;
 use super::ThreadPool;
 use crate::error::KvsError;
 use crate::error::Result;
 use rayon::ThreadPoolBuilder;
+use std::sync::Arc;
 
-pub struct RayonThreadPool(rayon::ThreadPool);
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+    counts: Arc<TaskCounts>,
+}
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
@@ -11,13 +16,24 @@ impl ThreadPool for RayonThreadPool {
             .num_threads(threads as usize)
             .build()
             .map_err(|e| KvsError::StringError(e.to_string()))?;
-        Ok(Self(pool))
+        Ok(Self {
+            pool,
+            counts: Arc::default(),
+        })
     }
 
     fn spawn<F>(&self, task: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.0.spawn(task);
+        self.pool.spawn(self.counts.track(task));
+    }
+
+    fn queued_tasks(&self) -> usize {
+        self.counts.queued()
+    }
+
+    fn active_workers(&self) -> usize {
+        self.counts.active()
     }
 }
@@ -1,50 +1,158 @@
+use super::TaskCounts;
 use super::ThreadPool;
 use crate::error::Result;
 use crossbeam::channel;
 use crossbeam::channel::Receiver;
 use crossbeam::channel::Sender;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+
+type Task = Box<dyn FnOnce() + Send + 'static>;
+
+// How long the supervisor waits before trying again to replace a worker it failed to spawn.
+const RESPAWN_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Runs tasks on a fixed set of workers taking them from a shared queue.
+///
+/// A task that panics is caught, and the worker that ran it retires, since the panic may
+/// have left its thread-local state broken. A supervisor thread replaces it with a fresh
+/// worker.
 pub struct SharedQueueThreadPool {
-    tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    tx: Sender<Task>,
+    counts: Arc<TaskCounts>,
+    panics: Arc<AtomicU64>,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self> {
-        let (tx, rx) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
+impl SharedQueueThreadPool {
+    /// Create a pool whose queue holds at most `limit` tasks waiting for a thread. Beyond
+    /// that, `spawn` blocks until a task starts, and `try_spawn` hands the task back. A
+    /// limit of 0 makes `try_spawn` refuse every task.
+    pub fn with_queue_limit(threads: u32, limit: usize) -> Result<Self> {
+        Self::start(threads, channel::bounded(limit))
+    }
+
+    /// Number of tasks that have panicked.
+    pub fn panicked_tasks(&self) -> u64 {
+        self.panics.load(Ordering::SeqCst)
+    }
+
+    fn start(threads: u32, (tx, rx): (Sender<Task>, Receiver<Task>)) -> Result<Self> {
+        let panics = Arc::new(AtomicU64::new(0));
+        let (events_tx, events_rx) = channel::unbounded();
         for _ in 0..threads {
-            let rx = TaskReceiver(rx.clone());
-            thread::Builder::new().spawn(move || run_tasks(rx))?;
+            spawn_worker(rx.clone(), events_tx.clone(), panics.clone())?;
         }
-        Ok(Self { tx })
+        let supervisor = Supervisor {
+            tasks: rx,
+            events_tx,
+            events_rx,
+            panics: panics.clone(),
+            workers: threads,
+        };
+        thread::Builder::new().spawn(move || supervisor.run())?;
+        Ok(Self {
+            tx,
+            counts: Arc::default(),
+            panics,
+        })
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        Self::start(threads, channel::unbounded())
     }
 
     fn spawn<F: FnOnce() + Send + 'static>(&self, task: F) {
-        self.tx.send(Box::new(task)).unwrap();
+        self.tx.send(Box::new(self.counts.track(task))).unwrap();
     }
+
+    // Only the full check and the send race, so with a single spawner the send never blocks.
+    fn try_spawn<F: FnOnce() + Send + 'static>(&self, task: F) -> std::result::Result<(), F> {
+        if self.tx.is_full() {
+            return Err(task);
+        }
+        self.spawn(task);
+        Ok(())
+    }
+
+    fn queued_tasks(&self) -> usize {
+        self.counts.queued()
+    }
+
+    fn active_workers(&self) -> usize {
+        self.counts.active()
+    }
+}
+
+// Why a worker stopped.
+enum Exit {
+    // A task panicked; the worker needs replacing.
+    Panicked,
+    // The pool was dropped and the queue is empty.
+    Finished,
 }
 
-#[derive(Clone)]
-struct TaskReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+// Keeps the pool at its size, replacing workers retired by a panic.
+struct Supervisor {
+    tasks: Receiver<Task>,
+    events_tx: Sender<Exit>,
+    events_rx: Receiver<Exit>,
+    panics: Arc<AtomicU64>,
+    // Workers running or about to run.
+    workers: u32,
+}
 
-impl Drop for TaskReceiver {
-    fn drop(&mut self) {
-        if thread::panicking() {
-            println!("task receiver drop, thread panicking");
-            let rx = self.clone();
-            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(rx)) {
-                println!("Failed to spawn a thread: {}", e);
+impl Supervisor {
+    fn run(mut self) {
+        while self.workers > 0 {
+            // The supervisor holds a sender itself, so this only fails if it's been dropped.
+            let Ok(exit) = self.events_rx.recv() else {
+                return;
+            };
+            self.workers -= 1;
+            if let Exit::Panicked = exit {
+                self.replace();
             }
         }
     }
-}
 
-fn run_tasks(rx: TaskReceiver) {
-    loop {
-        match rx.0.recv() {
-            Ok(task) => {
-                task();
+    fn replace(&mut self) {
+        loop {
+            let spawned = spawn_worker(
+                self.tasks.clone(),
+                self.events_tx.clone(),
+                self.panics.clone(),
+            );
+            if spawned.is_ok() {
+                self.workers += 1;
+                return;
             }
-            Err(err) => println!("Thread exits {}", err),
+            thread::sleep(RESPAWN_BACKOFF);
+        }
+    }
+}
+
+fn spawn_worker(tasks: Receiver<Task>, events: Sender<Exit>, panics: Arc<AtomicU64>) -> Result<()> {
+    thread::Builder::new().spawn(move || {
+        let exit = run_tasks(&tasks, &panics);
+        let _ = events.send(exit);
+    })?;
+    Ok(())
+}
+
+fn run_tasks(tasks: &Receiver<Task>, panics: &AtomicU64) -> Exit {
+    // Fails once the pool is dropped and the queue drained.
+    while let Ok(task) = tasks.recv() {
+        if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+            panics.fetch_add(1, Ordering::SeqCst);
+            return Exit::Panicked;
         }
     }
+    Exit::Finished
 }
@@ -0,0 +1,211 @@
+//! Spans of the work of serving requests, for a distributed trace.
+//!
+//! Built with the `tracing` feature, the client, the server and the engines make `tracing`
+//! spans: the client one for each call, the server one for each request, carrying the
+//! client's trace id, with the time it queued for a thread, and the engines ones for their
+//! operations, compactions, write stalls and syncs, within the request's. A subscriber such
+//! as `tracing-opentelemetry` can then put a slow call together with where its time went.
+//! Built without it, spans are nothing, and cost nothing.
+
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// The id of a distributed trace, which a client sends with its requests so the spans the
+/// server makes serving them can be found with the client's. Shown, and parsed, as 32 hex
+/// digits, as W3C trace context and OpenTelemetry write them.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceId(pub u128);
+
+impl TraceId {
+    /// A new trace id, unlikely to be any other's.
+    pub fn random() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        // Each `RandomState` is keyed apart from the last, so the two halves differ too.
+        let half = || {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(now);
+            hasher.finish() as u128
+        };
+        Self(half() << 64 | half())
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+impl FromStr for TraceId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match u128::from_str_radix(s, 16) {
+            Ok(id) if s.len() == 32 => Ok(Self(id)),
+            _ => Err(format!("{:?} is not a trace id of 32 hex digits", s)),
+        }
+    }
+}
+
+/// A `tracing::Span`, or nothing without the `tracing` feature. Spans are made with the
+/// constructors below, since `tracing` takes span names that are known at compile time.
+#[derive(Clone)]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// The guard of an entered `Span`, which exits it once dropped.
+pub(crate) struct Entered<'a> {
+    #[cfg(feature = "tracing")]
+    _entered: tracing::span::Entered<'a>,
+    _span: PhantomData<&'a Span>,
+}
+
+impl Span {
+    /// The span of the server serving a request, the `seq`th of its connection.
+    pub(crate) fn request(name: &'static str, seq: u64, trace_id: Option<TraceId>) -> Self {
+        #[cfg(feature = "tracing")]
+        return Self {
+            span: tracing::info_span!(
+                "kvs.request",
+                request = name,
+                seq,
+                trace_id = trace_id.map(tracing::field::display),
+                queued_micros = tracing::field::Empty,
+                failed = tracing::field::Empty,
+            ),
+        };
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = (name, seq, trace_id);
+            Self {}
+        }
+    }
+
+    /// The span of a client's call, from sending the request to reading its response.
+    pub(crate) fn call(name: &'static str, trace_id: Option<TraceId>) -> Self {
+        #[cfg(feature = "tracing")]
+        return Self {
+            span: tracing::info_span!(
+                "kvs.call",
+                request = name,
+                trace_id = trace_id.map(tracing::field::display),
+                failed = tracing::field::Empty,
+            ),
+        };
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = (name, trace_id);
+            Self {}
+        }
+    }
+
+    /// The span of an engine operation on `keys` keys, the first of which is `key`.
+    pub(crate) fn engine_op(op: &'static str, key: Option<&str>, keys: usize) -> Self {
+        #[cfg(feature = "tracing")]
+        return Self {
+            span: tracing::info_span!(
+                "kvs.engine",
+                op,
+                key,
+                keys,
+                compacted = tracing::field::Empty,
+                failed = tracing::field::Empty,
+            ),
+        };
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = (op, key, keys);
+            Self {}
+        }
+    }
+
+    /// The span of a `KvStore` compacting its logs.
+    pub(crate) fn compaction() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!("kvs.compaction", failed = tracing::field::Empty),
+        }
+    }
+
+    /// The span of a write held back while compaction falls behind; see `WriteStall`.
+    pub(crate) fn write_stall(dead_bytes: u64, limit: u64) -> Self {
+        #[cfg(feature = "tracing")]
+        return Self {
+            span: tracing::info_span!("kvs.write_stall", dead_bytes, limit),
+        };
+        #[cfg(not(feature = "tracing"))]
+        {
+            let _ = (dead_bytes, limit);
+            Self {}
+        }
+    }
+
+    /// The span of a write waiting for group commit to sync it.
+    pub(crate) fn group_commit() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!("kvs.group_commit"),
+        }
+    }
+
+    /// The span entered on this thread.
+    pub(crate) fn current() -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::current(),
+        }
+    }
+
+    /// Enter the span until the guard returned is dropped.
+    pub(crate) fn enter(&self) -> Entered<'_> {
+        Entered {
+            #[cfg(feature = "tracing")]
+            _entered: self.span.enter(),
+            _span: PhantomData,
+        }
+    }
+
+    /// Run `f` in the span.
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _entered = self.enter();
+        f()
+    }
+
+    /// Record how long a request waited for a thread to serve it.
+    pub(crate) fn record_queued(&self, queued: Duration) {
+        #[cfg(feature = "tracing")]
+        self.span.record("queued_micros", queued.as_micros() as u64);
+        #[cfg(not(feature = "tracing"))]
+        let _ = queued;
+    }
+
+    /// Record whether the operation failed.
+    pub(crate) fn record_failed(&self, failed: bool) {
+        #[cfg(feature = "tracing")]
+        self.span.record("failed", failed);
+        #[cfg(not(feature = "tracing"))]
+        let _ = failed;
+    }
+
+    /// Record whether the engine compacted during the operation.
+    pub(crate) fn record_compacted(&self, compacted: bool) {
+        #[cfg(feature = "tracing")]
+        self.span.record("compacted", compacted);
+        #[cfg(not(feature = "tracing"))]
+        let _ = compacted;
+    }
+}
@@ -0,0 +1,151 @@
+//! The streams clients and servers talk over: TCP, or a Unix domain socket, for clients on
+//! the same host that want lower latency and access controlled by the socket's permissions.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A connection between a client and a server.
+pub(crate) trait Transport: Read + Write + Send {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Shut the connection down both ways, so a read blocked on it on another thread, by
+    /// way of a clone, returns.
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Who is at the other end, for logs.
+    fn peer(&self) -> String;
+
+    /// The address of the other end, if it has one.
+    fn peer_ip(&self) -> Option<IpAddr>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn peer(&self) -> String {
+        match self.peer_addr() {
+            Ok(peer) => peer.to_string(),
+            Err(_) => "unknown".to_owned(),
+        }
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|peer| peer.ip())
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    // Clients' ends of a socket are rarely bound to a path, so they can't be told apart.
+    fn peer(&self) -> String {
+        "unix".to_owned()
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+/// Accepts the connections of clients.
+pub(crate) trait Listener: Send {
+    fn accept(&self) -> io::Result<Box<dyn Transport>>;
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpListener::accept(self)?.0))
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(UnixListener::accept(self)?.0))
+    }
+}
+
+/// Bind a socket at `path`. A socket left there by a server that's gone is replaced; one a
+/// server is still listening on is not.
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(err)
+            if err.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).is_err() =>
+        {
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+/// Where a server listens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    pub(crate) fn connect(&self, timeout: Option<Duration>) -> io::Result<Box<dyn Transport>> {
+        match self {
+            Self::Tcp(addr) => Ok(Box::new(match timeout {
+                Some(timeout) => TcpStream::connect_timeout(addr, timeout)?,
+                None => TcpStream::connect(addr)?,
+            })),
+            // Connecting on the same host doesn't wait on the network.
+            #[cfg(unix)]
+            Self::Unix(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Notifications of changes to keys, for connections that subscribe to them, for
+//! requests parked until a key is set, and for a primary's replicas.
+
+use crate::replication::ChangeLog;
+use crate::sync::Condvar;
+use crate::sync::Mutex;
+use crossbeam::channel;
+use crossbeam::channel::Receiver;
+use crossbeam::channel::Sender;
+use serde::Deserialize;
+use serde::Serialize;
+use std::io;
+use std::mem;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How a key changed.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    Set,
+    Remove,
+}
+
+/// A change to a key, as a subscription sees it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    pub key: String,
+    pub op: ChangeOp,
+}
+
+// Changes a subscriber can fall behind by before it's dropped.
+const BUFFER: usize = 1024;
+
+/// What a parked request does once it's woken: answer the request, on a thread of its
+/// own choosing, since it's called with other requests waiting to be woken.
+pub(crate) type Wake = Box<dyn FnOnce() + Send>;
+
+// A request parked until `key` is set, or until `deadline` passes.
+struct Parked {
+    id: u64,
+    key: String,
+    deadline: Instant,
+    wake: Wake,
+}
+
+#[derive(Default)]
+struct ParkedRequests {
+    parked: Vec<Parked>,
+    last_id: u64,
+}
+
+// The longest the timer waits before checking whether the server is gone.
+const TIMER_POLL: Duration = Duration::from_secs(1);
+
+/// The subscribers to changes of a server's keys, and the requests waiting for keys to be
+/// set.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    subscribers: Mutex<Vec<(String, Sender<KeyChange>)>>,
+    parked: Mutex<ParkedRequests>,
+    // Notified when a request is parked, so the timer waits for its deadline if it's the
+    // soonest.
+    parking: Condvar,
+    // Where the changes are recorded for replicas, if the server is a primary.
+    changes: Option<Arc<ChangeLog>>,
+}
+
+impl Watchers {
+    /// Watchers that also record every change in `changes`, to ship to replicas.
+    pub(crate) fn with_change_log(changes: Arc<ChangeLog>) -> Self {
+        Self {
+            changes: Some(changes),
+            ..Self::default()
+        }
+    }
+
+    /// Subscribe to changes of the keys starting with `prefix`. The receiver is disconnected
+    /// if its subscriber falls too far behind, since it has then missed changes.
+    pub(crate) fn subscribe(&self, prefix: String) -> Receiver<KeyChange> {
+        let (tx, rx) = channel::bounded(BUFFER);
+        self.subscribers.lock().push((prefix, tx));
+        rx
+    }
+
+    /// Tell the subscribers to `key` that it changed, dropping those that are gone or
+    /// behind, and wake the requests waiting for it if it was set.
+    pub(crate) fn publish(&self, key: &str, op: ChangeOp) {
+        if let Some(changes) = &self.changes {
+            changes.append(key);
+        }
+        let mut subscribers = self.subscribers.lock();
+        subscribers.retain(|(prefix, tx)| {
+            if !key.starts_with(prefix.as_str()) {
+                return true;
+            }
+            let change = KeyChange {
+                key: key.to_owned(),
+                op,
+            };
+            tx.try_send(change).is_ok()
+        });
+        drop(subscribers);
+        if op == ChangeOp::Set {
+            self.wake_where(|parked| parked.key == key);
+        }
+    }
+
+    /// Park a request until `key` is set, or `timeout` passes, either of which calls
+    /// `wake`. Return the id to unpark it with. The timer that wakes requests whose time is
+    /// up runs only once `spawn_timer` is called.
+    pub(crate) fn park(&self, key: String, timeout: Duration, wake: Wake) -> u64 {
+        let mut requests = self.parked.lock();
+        requests.last_id += 1;
+        let id = requests.last_id;
+        requests.parked.push(Parked {
+            id,
+            key,
+            deadline: Instant::now() + timeout,
+            wake,
+        });
+        self.parking.notify_one();
+        id
+    }
+
+    /// Unpark the request `id`, and return what it would have done once woken, or None if
+    /// it has been woken already.
+    pub(crate) fn unpark(&self, id: u64) -> Option<Wake> {
+        let mut requests = self.parked.lock();
+        let i = requests.parked.iter().position(|parked| parked.id == id)?;
+        Some(requests.parked.swap_remove(i).wake)
+    }
+
+    /// Wake the parked requests whose time is up, on a thread that runs until the last
+    /// handle to the watchers is dropped.
+    pub(crate) fn spawn_timer(self: &Arc<Self>) -> io::Result<()> {
+        let watchers = Arc::downgrade(self);
+        thread::Builder::new().spawn(move || {
+            while let Some(watchers) = watchers.upgrade() {
+                watchers.wait_for_deadline();
+                watchers.wake_where(|parked| parked.deadline <= Instant::now());
+            }
+        })?;
+        Ok(())
+    }
+
+    // Wait until the soonest deadline of a parked request, or for no longer than the timer
+    // polls.
+    fn wait_for_deadline(&self) {
+        let requests = self.parked.lock();
+        let now = Instant::now();
+        let soonest = requests.parked.iter().map(|parked| parked.deadline).min();
+        let wait = soonest.map_or(TIMER_POLL, |deadline| {
+            deadline.saturating_duration_since(now).min(TIMER_POLL)
+        });
+        if !wait.is_zero() {
+            let _ = self.parking.wait_timeout(requests, wait);
+        }
+    }
+
+    // Wake the parked requests for which `woken` holds, after unparking them all.
+    fn wake_where(&self, woken: impl Fn(&Parked) -> bool) {
+        let mut requests = self.parked.lock();
+        let (wake, park): (Vec<_>, Vec<_>) =
+            mem::take(&mut requests.parked).into_iter().partition(woken);
+        requests.parked = park;
+        drop(requests);
+        for parked in wake {
+            (parked.wake)();
+        }
+    }
+}
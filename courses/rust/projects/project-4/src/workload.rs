@@ -0,0 +1,243 @@
+//! Reproducible streams of operations for benchmarks and tests: which keys they touch, how
+//! large the values they write are, and how often they read rather than write.
+//!
+//! Everything is drawn from a seed with a generator of this module's own, so a workload
+//! gives the same operations on every run and every platform.
+
+use crate::KvsEngine;
+use crate::Result;
+use std::ops::RangeInclusive;
+
+/// How a workload picks the key of each operation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyDistribution {
+    /// Every key as likely as any other.
+    Uniform,
+    /// A few keys most of the time: `key0` is the most popular, then `key1`, and so on,
+    /// the `n`th drawn in proportion to 1 / (n + 1) ^ exponent. The exponent must be
+    /// between 0 and 1, exclusive; YCSB uses 0.99.
+    Zipfian(f64),
+    /// `key0`, `key1` and so on in turn, starting over after the last.
+    Sequential,
+}
+
+/// What a benchmark asks of an engine: which keys, how large the values are, and how
+/// often it reads rather than writes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Workload {
+    /// Keys are `key0` up to, but excluding, `key{key_space}`.
+    pub key_space: u64,
+    /// How the key of each operation is picked from the key space.
+    pub keys: KeyDistribution,
+    /// The lengths of the values written, in bytes. Each key has a length of its own within
+    /// the range, the same every time it's written.
+    pub value_size: RangeInclusive<usize>,
+    /// Fraction of operations that are gets; the rest are sets.
+    pub read_ratio: f64,
+    /// Seeds the choice of keys, so runs with the same seed do the same operations.
+    pub seed: u64,
+}
+
+impl Workload {
+    /// Only sets, of 16-byte values.
+    pub fn writes(key_space: u64) -> Self {
+        Self::mixed(key_space, 0.0)
+    }
+
+    /// Only gets. `populate` the engine first so they find something.
+    pub fn reads(key_space: u64) -> Self {
+        Self::mixed(key_space, 1.0)
+    }
+
+    /// Gets `read_ratio` of the time, and sets of 16-byte values otherwise, of keys drawn
+    /// uniformly.
+    pub fn mixed(key_space: u64, read_ratio: f64) -> Self {
+        Self {
+            key_space,
+            keys: KeyDistribution::Uniform,
+            value_size: 16..=16,
+            read_ratio,
+            seed: 0,
+        }
+    }
+
+    pub fn with_keys(mut self, keys: KeyDistribution) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Write values of `value_size` bytes each.
+    pub fn with_value_size(self, value_size: usize) -> Self {
+        self.with_value_sizes(value_size..=value_size)
+    }
+
+    /// Write values of lengths spread evenly over `value_size`.
+    pub fn with_value_sizes(mut self, value_size: RangeInclusive<usize>) -> Self {
+        self.value_size = value_size;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Return the `n`th key of the key space.
+    pub fn key(&self, n: u64) -> String {
+        format!("key{}", n)
+    }
+
+    /// Return the value written to the `n`th key: its number, padded to the key's length.
+    pub fn value(&self, n: u64) -> String {
+        let (min, max) = (*self.value_size.start(), *self.value_size.end());
+        let width = if max > min {
+            let span = (max - min) as u64 + 1;
+            min + (SplitMix64(n).next_u64() % span) as usize
+        } else {
+            min
+        };
+        format!("{:0>width$}", n, width = width)
+    }
+
+    /// Set every key of the key space.
+    pub fn populate<E: KvsEngine>(&self, engine: &E) -> Result<()> {
+        for n in 0..self.key_space {
+            engine.set(self.key(n), self.value(n))?;
+        }
+        Ok(())
+    }
+
+    /// Return an endless stream of operations. Take as many as the benchmark needs.
+    ///
+    /// Panics if the keys are zipfian with an exponent outside (0, 1).
+    pub fn ops(&self) -> Ops {
+        let keys = match self.keys {
+            KeyDistribution::Uniform => Keys::Uniform,
+            KeyDistribution::Zipfian(exponent) => {
+                Keys::Zipfian(Zipfian::new(self.key_space.max(1), exponent))
+            }
+            KeyDistribution::Sequential => Keys::Sequential(0),
+        };
+        Ops {
+            workload: self.clone(),
+            keys,
+            rng: SplitMix64(self.seed),
+        }
+    }
+}
+
+/// An operation of a workload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Get(String),
+    Set(String, String),
+}
+
+impl Op {
+    /// Run the operation against `engine`.
+    pub fn apply<E: KvsEngine>(self, engine: &E) -> Result<()> {
+        match self {
+            Self::Get(key) => engine.get(key).map(drop),
+            Self::Set(key, value) => engine.set(key, value),
+        }
+    }
+}
+
+/// The operations of a workload, returned by `Workload::ops`.
+pub struct Ops {
+    workload: Workload,
+    keys: Keys,
+    rng: SplitMix64,
+}
+
+impl Iterator for Ops {
+    type Item = Op;
+
+    fn next(&mut self) -> Option<Op> {
+        let key_space = self.workload.key_space.max(1);
+        let n = match &mut self.keys {
+            Keys::Uniform => self.rng.next_u64() % key_space,
+            Keys::Zipfian(zipfian) => zipfian.sample(self.rng.next_f64()),
+            Keys::Sequential(next) => {
+                let n = *next;
+                *next = (n + 1) % key_space;
+                n
+            }
+        };
+        let key = self.workload.key(n);
+        if self.rng.next_f64() < self.workload.read_ratio {
+            Some(Op::Get(key))
+        } else {
+            Some(Op::Set(key, self.workload.value(n)))
+        }
+    }
+}
+
+// The state of a `KeyDistribution` as keys are drawn from it.
+enum Keys {
+    Uniform,
+    Zipfian(Zipfian),
+    // The next key to return.
+    Sequential(u64),
+}
+
+// Zipfian draws over [0, n), as Gray et al. make them in "Quickly Generating Billion-Record
+// Synthetic Databases", which YCSB follows. Setting up sums over every key, once.
+struct Zipfian {
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zeta_n: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(n: u64, theta: f64) -> Self {
+        assert!(
+            theta > 0.0 && theta < 1.0,
+            "zipfian exponent {} is outside (0, 1)",
+            theta
+        );
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zeta_n = zeta(n);
+        let eta = (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zeta_n);
+        Self {
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zeta_n,
+            eta,
+        }
+    }
+
+    // Map `u`, uniform in [0, 1), to a key number.
+    fn sample(&self, u: f64) -> u64 {
+        let uz = u * self.zeta_n;
+        if uz < 1.0 || self.n == 1 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1;
+        }
+        let n = self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        (n as u64).min(self.n - 1)
+    }
+}
+
+// A small, fast generator that gives the same numbers on every platform.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1), from the top 53 bits.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
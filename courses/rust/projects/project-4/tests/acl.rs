@@ -0,0 +1,223 @@
+use kvs::acl::{Acl, Grant, Role};
+use kvs::{KvStore, KvsClient, KvsError, KvsServer};
+use slog::{o, Discard, Logger};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn spawn(temp_dir: &TempDir, acl: Acl) -> (kvs::ServerHandle, SocketAddr) {
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    KvsServer::new(engine, Logger::root(Discard, o!()))
+        .with_acl(acl)
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap()
+}
+
+fn denied<T>(result: kvs::Result<T>) -> bool {
+    matches!(result, Err(KvsError::PermissionDenied(_)))
+}
+
+// Each role allows the requests of the roles before it, and clients without a token get
+// the anonymous grant.
+#[test]
+fn acl_roles() {
+    let temp_dir = TempDir::new().unwrap();
+    let acl = Acl::new()
+        .with_anonymous(Grant::new(Role::ReadOnly))
+        .with_token("writer", Grant::new(Role::ReadWrite))
+        .with_token("admin", Grant::new(Role::Admin));
+    let (_server, addr) = spawn(&temp_dir, acl);
+
+    let mut scraper = KvsClient::connect(&addr).unwrap();
+    assert_eq!(scraper.get("key1".to_owned()).unwrap(), None);
+    assert!(denied(scraper.set("key1".to_owned(), "value1".to_owned())));
+    assert!(denied(
+        scraper.acquire_lease("key1".to_owned(), Duration::from_secs(1))
+    ));
+    assert!(denied(scraper.compact()));
+    scraper.stats().unwrap();
+
+    let mut writer = KvsClient::builder(addr).token("writer").connect().unwrap();
+    writer.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    writer.begin().unwrap();
+    writer.remove("key1".to_owned()).unwrap();
+    writer.commit().unwrap();
+    assert!(denied(writer.compact()));
+    assert_eq!(scraper.get("key1".to_owned()).unwrap(), None);
+
+    // A read-only client may look inside a transaction, but not commit it.
+    scraper.begin().unwrap();
+    assert!(denied(scraper.set("key1".to_owned(), "value1".to_owned())));
+    assert!(denied(scraper.commit()));
+    scraper.rollback().unwrap();
+
+    let mut admin = KvsClient::builder(addr).token("admin").connect().unwrap();
+    admin.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    admin.compact().unwrap();
+
+    assert!(denied(KvsClient::builder(addr).token("guess").connect()));
+}
+
+// A grant with key prefixes and buckets allows requests about only those keys, in only
+// those buckets, and none about every key.
+#[test]
+fn acl_key_prefixes_and_buckets() {
+    let temp_dir = TempDir::new().unwrap();
+    let grant = Grant {
+        role: Role::ReadWrite,
+        key_prefixes: vec!["app/".to_owned()],
+        buckets: Some(vec!["tenant1".to_owned()]),
+    };
+    let acl = Acl::new().with_token("tenant1", grant);
+    let (_server, addr) = spawn(&temp_dir, acl);
+
+    let mut client = KvsClient::builder(addr)
+        .token("tenant1")
+        .bucket("tenant1")
+        .connect()
+        .unwrap();
+    client
+        .set("app/key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.get("app/key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(denied(client.set("key1".to_owned(), "value1".to_owned())));
+    assert!(denied(
+        client.get_many(vec!["app/key1".to_owned(), "key1".to_owned()])
+    ));
+    assert!(denied(
+        client.rename("app/key1".to_owned(), "key1".to_owned())
+    ));
+    assert!(denied(client.len()));
+    assert!(denied(client.keys(None, 10)));
+
+    let mut other_bucket = KvsClient::builder(addr)
+        .token("tenant1")
+        .bucket("tenant2")
+        .connect()
+        .unwrap();
+    assert!(denied(other_bucket.get("app/key1".to_owned())));
+    let mut no_bucket = KvsClient::builder(addr).token("tenant1").connect().unwrap();
+    assert!(denied(no_bucket.get("app/key1".to_owned())));
+    no_bucket.ping().unwrap();
+
+    // Without an anonymous grant, a client needs a token.
+    let mut anonymous = KvsClient::connect(&addr).unwrap();
+    assert!(denied(anonymous.ping()));
+    assert!(denied(anonymous.stats()));
+}
+
+// Subscriptions must be to keys the grant allows.
+#[test]
+fn acl_subscriptions() {
+    let temp_dir = TempDir::new().unwrap();
+    let grant = Grant {
+        role: Role::ReadOnly,
+        key_prefixes: vec!["public/".to_owned()],
+        buckets: None,
+    };
+    let (_server, addr) = spawn(&temp_dir, Acl::new().with_anonymous(grant));
+
+    assert!(KvsClient::connect(&addr)
+        .unwrap()
+        .subscribe("public/news")
+        .is_ok());
+    assert!(denied(KvsClient::connect(&addr).unwrap().subscribe("")));
+}
+
+// An ACL file's grants are those it lists, and files that don't make sense are refused.
+#[test]
+fn acl_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("acl.toml");
+    std::fs::write(
+        &path,
+        r#"
+[anonymous]
+role = "read-only"
+
+[[token]]
+token = "app"
+role = "read-write"
+key-prefixes = ["app/"]
+"#,
+    )
+    .unwrap();
+    let acl = Acl::load(&path).unwrap();
+    let (_server, addr) = spawn(&temp_dir, acl);
+    let mut app = KvsClient::builder(addr).token("app").connect().unwrap();
+    app.set("app/key1".to_owned(), "value1".to_owned()).unwrap();
+    assert!(denied(app.set("key1".to_owned(), "value1".to_owned())));
+    let mut anonymous = KvsClient::connect(&addr).unwrap();
+    assert_eq!(
+        anonymous.get("app/key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(denied(anonymous.remove("app/key1".to_owned())));
+
+    for contents in [
+        "[[token]]\ntoken = \"a\"\nrole = \"owner\"\n",
+        "[[token]]\ntoken = \"a\"\nrole = \"admin\"\n[[token]]\ntoken = \"a\"\nrole = \"read-only\"\n",
+        "[anonymous]\nrole = \"admin\"\nprefixes = [\"a\"]\n",
+    ] {
+        std::fs::write(&path, contents).unwrap();
+        assert!(Acl::load(&path).is_err(), "{}", contents);
+    }
+    assert!(Acl::load(&temp_dir.path().join("missing.toml")).is_err());
+}
+
+// Requests over HTTP get the anonymous grant.
+#[test]
+fn acl_http() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let http_addr: SocketAddr = "127.0.0.1:4079".parse().unwrap();
+    let (_server, _) = KvsServer::new(engine, Logger::root(Discard, o!()))
+        .with_acl(Acl::new().with_anonymous(Grant::new(Role::ReadOnly)))
+        .with_http_addr(http_addr)
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+
+    let mut stream = None;
+    for _ in 0..50 {
+        if let Ok(connected) = TcpStream::connect(http_addr) {
+            stream = Some(BufReader::new(connected));
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let mut stream = stream.expect("http gateway didn't come up");
+    assert_eq!(status(&mut stream, "GET /keys/key1"), 404);
+    assert_eq!(status(&mut stream, "PUT /keys/key1"), 403);
+}
+
+// Send a request with an empty body on a kept-alive connection, and return the status of
+// the response.
+fn status(stream: &mut BufReader<TcpStream>, request: &str) -> u16 {
+    write!(
+        stream.get_mut(),
+        "{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+        request
+    )
+    .unwrap();
+    let mut line = String::new();
+    stream.read_line(&mut line).unwrap();
+    let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut len = 0;
+    loop {
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+            len = value.trim().parse().unwrap();
+        }
+    }
+    stream.read_exact(&mut vec![0; len]).unwrap();
+    status
+}
@@ -0,0 +1,32 @@
+#![cfg(feature = "async")]
+
+use kvs::async_client::KvsClient;
+use kvs::{KvStore, KvsError, KvsServer};
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn async_client() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+
+    for mut client in [
+        KvsClient::connect(&addr).await.unwrap(),
+        KvsClient::connect_with_checksums(&addr).await.unwrap(),
+    ] {
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).await.unwrap(),
+            Some("value1".to_owned())
+        );
+        client.remove("key1".to_owned()).await.unwrap();
+        assert_eq!(client.get("key1".to_owned()).await.unwrap(), None);
+        assert!(matches!(
+            client.remove("key1".to_owned()).await,
+            Err(KvsError::KeyNotFound)
+        ));
+    }
+}
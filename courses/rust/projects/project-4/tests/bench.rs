@@ -0,0 +1,103 @@
+#![cfg(feature = "bench")]
+
+use kvs::bench::{self, Report, RunLength};
+use kvs::workload::{KeyDistribution, Op, Workload};
+use kvs::{KvStore, KvsEngine};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// The same seed should give the same operations, and a different one different ones.
+#[test]
+fn ops_depend_only_on_seed() {
+    let workload = Workload::mixed(1000, 0.5).with_seed(7);
+    let first: Vec<Op> = workload.ops().take(100).collect();
+    let second: Vec<Op> = workload.ops().take(100).collect();
+    assert_eq!(first, second);
+
+    let other: Vec<Op> = workload.clone().with_seed(8).ops().take(100).collect();
+    assert_ne!(first, other);
+}
+
+#[test]
+fn read_ratio_extremes() {
+    let writes = Workload::writes(10).with_value_size(4);
+    for op in writes.ops().take(100) {
+        match op {
+            Op::Set(key, value) => {
+                assert!(key.starts_with("key"));
+                assert_eq!(value.len(), 4);
+            }
+            Op::Get(_) => panic!("writes workload produced a get"),
+        }
+    }
+    assert!(Workload::reads(10)
+        .ops()
+        .take(100)
+        .all(|op| matches!(op, Op::Get(_))));
+}
+
+// Zipfian keys should favour the first, sequential ones should come in turn, and values
+// should keep to their sizes, the same for a key every time.
+#[test]
+fn key_and_value_distributions() {
+    let key_of = |op: Op| match op {
+        Op::Get(key) | Op::Set(key, _) => key,
+    };
+    let zipfian = Workload::reads(1000).with_keys(KeyDistribution::Zipfian(0.99));
+    let keys: Vec<String> = zipfian.ops().take(10_000).map(key_of).collect();
+    let hottest = keys.iter().filter(|key| *key == "key0").count();
+    let coldest = keys.iter().filter(|key| *key == "key999").count();
+    assert!(hottest > 1000, "key0 drawn {} times", hottest);
+    assert!(hottest > 10 * coldest);
+    assert!(keys
+        .iter()
+        .all(|key| key[3..].parse::<u64>().unwrap() < 1000));
+    let again: Vec<String> = zipfian.ops().take(10_000).map(key_of).collect();
+    assert_eq!(keys, again);
+
+    let sequential = Workload::writes(3).with_keys(KeyDistribution::Sequential);
+    let keys: Vec<String> = sequential.ops().take(5).map(key_of).collect();
+    assert_eq!(keys, ["key0", "key1", "key2", "key0", "key1"]);
+
+    let sized = Workload::writes(100).with_value_sizes(8..=64);
+    let mut lengths = Vec::new();
+    for op in sized.ops().take(1000) {
+        if let Op::Set(key, value) = op {
+            let n = key[3..].parse().unwrap();
+            assert_eq!(value, sized.value(n));
+            lengths.push(value.len());
+        }
+    }
+    assert!(lengths.iter().all(|len| (8..=64).contains(len)));
+    assert!(lengths.iter().min() < lengths.iter().max());
+}
+
+#[test]
+fn run_times_every_op() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let workload = Workload::mixed(50, 0.5);
+    workload.populate(&store).unwrap();
+    assert_eq!(store.len().unwrap(), 50);
+
+    let report = bench::run(&store, &workload, 4, RunLength::Ops(25)).unwrap();
+    assert_eq!(report.ops(), 100);
+    assert!(report.percentile(0.5) <= report.percentile(0.99));
+    assert!(report.percentile(0.99) <= report.percentile(1.0));
+    assert_eq!(store.len().unwrap(), 50);
+}
+
+#[test]
+fn report_percentiles() {
+    let latencies = (1..=100).rev().map(Duration::from_millis).collect();
+    let report = Report::new(latencies, Duration::from_secs(1));
+    assert_eq!(report.percentile(0.5), Duration::from_millis(50));
+    assert_eq!(report.percentile(0.99), Duration::from_millis(99));
+    assert_eq!(report.percentile(1.0), Duration::from_millis(100));
+    assert_eq!(report.percentile(0.0), Duration::from_millis(1));
+    assert_eq!(report.throughput(), 100.0);
+
+    let empty = Report::new(Vec::new(), Duration::from_secs(1));
+    assert_eq!(empty.percentile(0.99), Duration::ZERO);
+    assert_eq!(empty.mean(), Duration::ZERO);
+}
@@ -1,12 +1,53 @@
 use assert_cmd::prelude::*;
+use kvs::ring::{self, HashRing};
+use kvs::{KvsClient, KvsError};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command, Output};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
 
+// A server spawned by a test, killed once the test is done with it even if an assertion
+// fails first, so it doesn't hold its port into the next run.
+struct Server(Option<Child>);
+
+impl Server {
+    fn id(&self) -> u32 {
+        self.0.as_ref().unwrap().id()
+    }
+
+    // Kill the server now, and return what it wrote to the pipes it was given.
+    fn stop(mut self) -> Output {
+        let mut child = self.0.take().unwrap();
+        child.kill().expect("server exited before killed");
+        child.wait_with_output().unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.0.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+trait SpawnServer {
+    fn spawn_server(&mut self) -> Server;
+}
+
+impl SpawnServer for Command {
+    fn spawn_server(&mut self) -> Server {
+        Server(Some(self.spawn().unwrap()))
+    }
+}
+
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -16,6 +57,7 @@ fn client_cli_no_args() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -48,6 +90,7 @@ fn client_cli_invalid_get() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -87,6 +130,7 @@ fn client_cli_invalid_set() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -119,6 +163,7 @@ fn client_cli_invalid_rm() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -131,6 +176,7 @@ fn client_cli_invalid_subcommand() {
 
 // `kvs-client -V` should print the version
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
@@ -142,6 +188,7 @@ fn client_cli_version() {
 
 // `kvs-server -V` should print the version
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
@@ -152,6 +199,7 @@ fn server_cli_version() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
@@ -164,6 +212,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -172,6 +221,7 @@ fn cli_log_configuration() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
@@ -184,6 +234,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +254,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -212,6 +264,7 @@ fn cli_wrong_engine() {
     }
 }
 
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -224,6 +277,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -305,6 +359,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -326,6 +381,26 @@ fn cli_access_server(engine: &str, addr: &str) {
     handle.join().unwrap();
 }
 
+// A second server can't open a kvs data directory a running one holds.
+#[test]
+fn cli_locked_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4017"])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4018"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!("locked by process {}", child.id())));
+}
+
 #[test]
 fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
@@ -334,4 +409,1644 @@ fn cli_access_server_kvs_engine() {
 #[test]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
-}
\ No newline at end of file
+}
+#[test]
+fn cli_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let metrics_addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--addr", addr, "--metrics-addr", metrics_addr])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("kvs_requests_total{type=\"set\"} 1"));
+
+    let mut stream = TcpStream::connect(metrics_addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut page = String::new();
+    stream.read_to_string(&mut page).unwrap();
+    assert!(page.starts_with("HTTP/1.1 200 OK"));
+    assert!(page.contains("kvs_requests_total{type=\"stats\"} 1"));
+    assert!(page.contains("kvs_engine_size_bytes"));
+    assert!(page.contains("kvs_unsynced_bytes"));
+    assert!(page.contains("kvs_writes_stalled 0"));
+    assert!(page.contains("kvs_engine_segments 1"));
+    assert!(page.contains("kvs_engine_dead_bytes 0"));
+    assert!(!page.contains("kvs_engine_cache_hits_total"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compact", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("kvs_compactions_total 1"));
+}
+
+#[test]
+fn server_cli_check_config() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine = sled"));
+    // Checking the configuration doesn't pin the engine.
+    assert!(!temp_dir.path().join("kvs.engine").exists());
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            "127.0.0.1:4008",
+            "--metrics-addr",
+            "127.0.0.1:4008",
+        ])
+        .arg("--check-config")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("metrics address"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4008", "--http-addr", "127.0.0.1:4008"])
+        .arg("--check-config")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("http address"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--sled-durability", "background"])
+        .args(["--sled-flush-every-ms", "100", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("sled-durability = background"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--sled-cache-bytes",
+            "1024",
+            "--check-config",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("only supported by the sled engine"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--max-rps", "100", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("max-rps = 100"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--burst", "10", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("a burst needs a rate limit"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--tombstone-max-count", "100", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            "tombstone-max-age-secs = none\ntombstone-max-count = 100",
+        ));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--tombstone-max-age-secs", "60"])
+        .arg("--check-config")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "a tombstone horizon is only supported by the kvs engine",
+        ));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--sync-interval-ms", "50", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("sync-interval-ms = 50"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--sync-interval-ms", "50"])
+        .arg("--check-config")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "background sync is only supported by the kvs engine",
+        ));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--write-stall-bytes",
+            "8388608",
+            "--write-stall-delay-ms",
+            "10",
+        ])
+        .arg("--check-config")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            "write-stall-bytes = 8388608\nwrite-stall-delay-ms = 10",
+        ));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--write-stall-bytes",
+            "1000",
+            "--compaction-threshold",
+            "4096",
+        ])
+        .arg("--check-config")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("must be over the compaction threshold"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--replica-of", "127.0.0.1:4100", "--raft-id", "1"])
+        .args(["--raft-addr", "127.0.0.1:4101", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("replica-of = 127.0.0.1:4100"))
+        .stderr(contains("a replica can't be a member of a raft group"));
+
+    // The token is a secret, so it's left out of what's printed.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--check-config"])
+        .env("KVS_REPLICATION_TOKEN", "secret-token")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("secret-token").not())
+        .stderr(contains("a replication token needs --replica-of"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "0.0.0.0:4000", "--cluster"])
+        .args(["--cluster-peer", "127.0.0.1:4100", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("cluster-peer = 127.0.0.1:4100"))
+        .stderr(contains("an address the others can reach"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--cluster-peer", "127.0.0.1:4100", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("cluster peers need --cluster"));
+
+    // Engines other than the built-in ones are only known to binaries that register them.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "rocksdb", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("engine = rocksdb"))
+        .stderr(contains(
+            "unknown engine rocksdb; the engines are kvs, sled",
+        ));
+
+    fs::write(temp_dir.path().join("kvs.engine"), "sled").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("last engine was sled"));
+}
+
+#[test]
+fn cli_repl() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    // Every command goes over the same connection.
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["repl", "--addr", addr])
+        .current_dir(&temp_dir)
+        .write_stdin("set key1 some value\nget key1\nrm key1\nget key1\nrm key1\n")
+        .assert()
+        .success()
+        .stdout(contains("some value\n"))
+        .stdout(contains("Key not found"))
+        .stderr(contains("error: Key not found"));
+}
+
+#[test]
+fn cli_ping() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4023";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--addr", addr, "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine = sled\n").and(contains("pings = 1\n")));
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--json", "--addr", addr])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let pong: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(pong["engine"], "sled");
+    assert_eq!(pong["pings"], 2);
+    assert!(pong["uptime_ms"].is_u64());
+}
+
+// A detached server should write its pid and log to files, and keep serving after the
+// command that started it returns.
+#[cfg(unix)]
+#[test]
+fn cli_daemonize() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4024";
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--daemonize", "--pid-file", "kvs.pid", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+
+    let pid = fs::read_to_string(temp_dir.path().join("kvs.pid")).unwrap();
+    let _daemon = Daemon(pid.trim().to_owned());
+    let mut client = KvsClient::connect(&addr.parse().unwrap()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let log = fs::read_to_string(temp_dir.path().join("kvs-server.log")).unwrap();
+    assert!(log.contains("starting up"));
+}
+
+// A detached server, by its pid, killed once the test is done with it like a `Server`.
+#[cfg(unix)]
+struct Daemon(String);
+
+#[cfg(unix)]
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = Command::new("kill").arg(&self.0).status();
+    }
+}
+
+// A JSON log should hold an object per line.
+#[test]
+fn cli_json_log_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4025"])
+        .args(["--log-file", "server.log", "--log-format", "json"])
+        .args(["--log-rotate-bytes", "1000000"])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+    drop(child);
+
+    let log = fs::read_to_string(temp_dir.path().join("server.log")).unwrap();
+    let first: serde_json::Value = serde_json::from_str(log.lines().next().unwrap()).unwrap();
+    assert_eq!(first["msg"], "starting up");
+    assert_eq!(first["level"], "INFO");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--log-rotate-hours", "24", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("log rotation needs a log file"));
+}
+
+// Values should go through stdin and stdout byte for byte, newlines and all.
+#[test]
+fn cli_stdin_and_raw() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4021";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "line one\n  line two\n\n";
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "--stdin", "--addr", addr])
+        .current_dir(&temp_dir)
+        .write_stdin(value)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--raw", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(value);
+
+    // A missing key is an error, so nothing on stdout can be mistaken for a value.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--raw", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--stdin", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "--stdin", "--addr", addr])
+        .current_dir(&temp_dir)
+        .write_stdin(vec![0xff, 0xfe])
+        .assert()
+        .failure()
+        .stderr(contains("values must be UTF-8"));
+}
+
+// Without --addr, the client should connect to $KVS_ADDR, or else to the addr of its
+// config file.
+#[test]
+fn cli_client_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4022";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+    let client = || {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.current_dir(&temp_dir)
+            .env("HOME", temp_dir.path())
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("KVS_ADDR");
+        cmd
+    };
+
+    client()
+        .args(["set", "key1", "value1"])
+        .env("KVS_ADDR", addr)
+        .assert()
+        .success();
+
+    let config_dir = temp_dir.path().join(".config").join("kvs");
+    fs::create_dir_all(&config_dir).unwrap();
+    fs::write(
+        config_dir.join("client.toml"),
+        format!("addr = \"{}\"\ntimeout-ms = 5000\n", addr),
+    )
+    .unwrap();
+    client()
+        .args(["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // --addr wins over the environment, which wins over the file.
+    client()
+        .args(["get", "key1", "--addr", addr])
+        .env("KVS_ADDR", "127.0.0.1:1")
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client()
+        .args(["get", "key1", "--timeout-ms", "1000"])
+        .env("KVS_ADDR", "127.0.0.1:1")
+        .assert()
+        .failure();
+
+    fs::write(config_dir.join("client.toml"), "adr = \"127.0.0.1:4022\"\n").unwrap();
+    client()
+        .args(["get", "key1"])
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `adr`"));
+}
+
+#[test]
+fn cli_exists_and_count() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["count", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("0\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["exists", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("true\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["exists", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("false\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["count", "--addr", addr, "--checksums"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    // A bucket has keys of its own.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["exists", "key1", "--addr", addr, "--bucket", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("false\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", addr, "--bucket", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["count", "--addr", addr, "--bucket", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+}
+
+// `kvs-client stat` should print a value's size, version and write time, but not the value.
+#[test]
+fn cli_stat() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4026";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stat", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("size = 6\nversion = 1\nmodified-ms = ")
+                .and(contains("stamp-ns = "))
+                .and(contains("value1").not()),
+        );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stat", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+}
+
+#[test]
+fn cli_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--socket", "kvs.sock"])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--socket", "kvs.sock"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--socket", "kvs.sock"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+#[test]
+fn cli_rebalance() {
+    let old_addr: SocketAddr = "127.0.0.1:4050".parse().unwrap();
+    let new_addr: SocketAddr = "127.0.0.1:4051".parse().unwrap();
+    let mut children = Vec::new();
+    let mut temp_dirs = Vec::new();
+    for addr in [old_addr, new_addr] {
+        let temp_dir = TempDir::new().unwrap();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr.to_string()])
+            .current_dir(&temp_dir)
+            .spawn_server();
+        children.push(child);
+        temp_dirs.push(temp_dir);
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    let mut old_client = KvsClient::connect(&old_addr).unwrap();
+    for i in 0..100 {
+        old_client
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rebalance", "--from", "127.0.0.1:4050"])
+        .args(["--to", "127.0.0.1:4050,127.0.0.1:4051", "--dry-run"])
+        .assert()
+        .success()
+        .stdout(contains("127.0.0.1:4050 -> 127.0.0.1:4051"));
+    assert_eq!(old_client.len().unwrap(), 100);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rebalance", "--from", "127.0.0.1:4050"])
+        .args(["--to", "127.0.0.1:4050,127.0.0.1:4051"])
+        .assert()
+        .success()
+        .stderr(contains("done"));
+
+    let ring = HashRing::new(&[old_addr, new_addr], ring::DEFAULT_SLOT_COUNT);
+    let mut new_client = KvsClient::connect(&new_addr).unwrap();
+    for i in 0..100 {
+        let key = format!("key{}", i);
+        let (owner, other) = if ring.node_for_key(&key) == Some(old_addr) {
+            (&mut old_client, &mut new_client)
+        } else {
+            (&mut new_client, &mut old_client)
+        };
+        assert_eq!(owner.get(key.clone()).unwrap(), Some(format!("value{}", i)));
+        assert!(!other.contains(key).unwrap());
+    }
+    assert!(new_client.len().unwrap() > 0);
+    assert_eq!(old_client.len().unwrap() + new_client.len().unwrap(), 100);
+}
+
+#[test]
+fn cli_incr_and_decr() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "counter", "10", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("11\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["decr", "counter", "-4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("15\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["decr", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("14\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "text", "value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "text", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Value is not an integer"));
+}
+
+#[test]
+fn cli_rename() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4028";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mv", "key1", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rename", "key1", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+}
+
+#[test]
+fn cli_append_get_range() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4029";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["append", "key1", "hello", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("5\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["append", "key1", " world", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("11\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-range", "key1", "6", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("world\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-range", "key2", "0", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait-get", "key1", "--wait-ms", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("hello world\n");
+
+    // The answer comes once the wait is over, which the client waits for whatever its own
+    // timeout, however often it's tried.
+    for timeout in [&[][..], &["--timeout-ms", "50"], &["--timeout-ms", "5000"]].repeat(4) {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["wait-get", "key2", "--wait-ms", "100", "--addr", addr])
+            .args(timeout)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr("error: timed out waiting for the key\n");
+    }
+
+    for (args, stdout) in [
+        (&["lock", "job", "worker1"][..], "true\n"),
+        (&["lock", "job", "worker2", "--ttl-ms", "1000"], "false\n"),
+        (&["unlock", "job", "worker1"], "true\n"),
+        (&["unlock", "job", "worker1"], "false\n"),
+        // A lock held so briefly has expired by the time another owner asks for it.
+        (&["lock", "lease", "worker1", "--ttl-ms", "1"], "true\n"),
+        (&["lock", "lease", "worker2"], "true\n"),
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(stdout);
+    }
+}
+
+#[cfg(feature = "bench")]
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--ops", "100", "--key-space", "10", "--threads", "2"])
+        .arg("--dir")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("ops         200").and(contains("p99")));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--engine", "sled", "--ops", "10", "--key-space", "10"])
+        .assert()
+        .success()
+        .stdout(contains("ops         10"));
+}
+
+#[test]
+#[cfg(feature = "bench")]
+fn cli_bench_server() {
+    let addr = "127.0.0.1:4027";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        drop(child);
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", addr, "--populate", "--key-space", "10"])
+        .args(["--connections", "2", "--ops", "50", "--read-ratio", "1"])
+        .assert()
+        .success()
+        .stdout(contains("ops         100").and(contains("p95")));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", addr, "--duration", "1", "--key-space", "10"])
+        .assert()
+        .success()
+        .stdout(contains("throughput"));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", addr, "--engine", "sled"])
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_move_data() {
+    let from = TempDir::new().unwrap();
+    let to = TempDir::new().unwrap();
+    let to = to.path().join("moved");
+    let store = kvs::KvStore::open(from.path()).unwrap();
+    kvs::KvsEngine::set(&store, "key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["move-data", "--from"])
+        .arg(from.path())
+        .arg("--to")
+        .arg(&to)
+        .assert()
+        .success()
+        .stderr(contains("done; copied"));
+    assert!(to.join("0.kvs.log").exists());
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--check-config")
+        .current_dir(&from)
+        .assert()
+        .failure()
+        .stderr(contains("was moved to"));
+}
+
+#[test]
+fn server_cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs-server.toml");
+    fs::write(
+        &config,
+        r#"
+addr = "127.0.0.1:4012"
+engine = "sled"
+protocol = "resp"
+thread-pool = "shared-queue"
+threads = 4
+log-level = "info"
+sled-durability = "background"
+raft-id = 1
+raft-addr = "127.0.0.1:4100"
+raft-peer = ["2,127.0.0.1:4101,127.0.0.1:4201"]
+"#,
+    )
+    .unwrap();
+
+    // Options on the command line win over the file.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .args([
+            "--addr",
+            "127.0.0.1:4013",
+            "--threads",
+            "8",
+            "--check-config",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("addr = 127.0.0.1:4013")
+                .and(contains("engine = sled"))
+                .and(contains("protocol = resp"))
+                .and(contains("thread-pool = shared-queue"))
+                .and(contains("threads = 8"))
+                .and(contains("log-level = info"))
+                .and(contains("sled-durability = background"))
+                .and(contains("raft-peer = 2,127.0.0.1:4101,127.0.0.1:4201")),
+        );
+
+    fs::write(&config, "engine = \"kvs\"\nthreds = 4\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .arg("--check-config")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `threds`"));
+}
+
+#[test]
+fn cli_config_file_serves() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    let config = temp_dir.path().join("kvs-server.toml");
+    fs::write(
+        &config,
+        format!(
+            "addr = \"127.0.0.1:4012\"\nthread-pool = \"rayon\"\nthreads = 2\n\
+             compaction-threshold = 4096\nmmap-reads = true\nstrict-recovery = true\n\
+             value-index = true\n\
+             max-value-bytes = 16\n\
+             data-dir = {:?}\n",
+            data_dir
+        ),
+    )
+    .unwrap();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(&"127.0.0.1:4012".parse().unwrap()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        client.set("key2".to_owned(), "v".repeat(17)),
+        Err(KvsError::TooLarge(_))
+    ));
+    client.set("key3".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.find_by_value("value1".to_owned()).unwrap(),
+        ["key1", "key3"]
+    );
+    drop(client);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["find-by-value", "value1", "--addr", "127.0.0.1:4012"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\nkey3\n");
+    drop(child);
+
+    assert!(data_dir.join("kvs.engine").exists());
+    assert!(data_dir.join("kvs.values").exists());
+    assert!(!temp_dir.path().join("kvs.engine").exists());
+}
+
+// Servers with their own data directories can run side by side from the same directory.
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut children = Vec::new();
+    for (engine, addr) in [("kvs", "127.0.0.1:4014"), ("sled", "127.0.0.1:4015")] {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--addr", addr])
+            .args(["--data-dir", &format!("instances/{}", engine)])
+            .current_dir(&temp_dir)
+            .spawn_server();
+        children.push(child);
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    for (engine, addr) in [("kvs", "127.0.0.1:4014"), ("sled", "127.0.0.1:4015")] {
+        let mut client = KvsClient::connect(&addr.parse().unwrap()).unwrap();
+        client.set("engine".to_owned(), engine.to_owned()).unwrap();
+    }
+    for (engine, addr) in [("kvs", "127.0.0.1:4014"), ("sled", "127.0.0.1:4015")] {
+        let mut client = KvsClient::connect(&addr.parse().unwrap()).unwrap();
+        assert_eq!(
+            client.get("engine".to_owned()).unwrap(),
+            Some(engine.to_owned())
+        );
+    }
+    drop(children);
+
+    let instances = temp_dir.path().join("instances");
+    assert_eq!(
+        fs::read_to_string(instances.join("kvs").join("kvs.engine")).unwrap(),
+        "kvs"
+    );
+    assert_eq!(
+        fs::read_to_string(instances.join("sled").join("kvs.engine")).unwrap(),
+        "sled"
+    );
+    assert!(!temp_dir.path().join("kvs.engine").exists());
+
+    // Checking the configuration doesn't create the directory.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--data-dir", "not-yet", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("not-yet"));
+    assert!(!temp_dir.path().join("not-yet").exists());
+}
+
+#[test]
+fn cli_dump_and_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = kvs::KvStore::open(temp_dir.path().join("old")).unwrap();
+    for i in 0..10 {
+        kvs::KvsEngine::set(&store, format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", "--data-dir", "old"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("dumped 10 pairs"));
+
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", "--data-dir", "new", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .write_stdin(output.stdout.clone())
+        .assert()
+        .success()
+        .stderr(contains("restored 10 pairs"));
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("new").join("kvs.engine")).unwrap(),
+        "sled"
+    );
+
+    // The directory is pinned to sled now.
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", "--data-dir", "new", "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .write_stdin(output.stdout)
+        .assert()
+        .failure()
+        .stderr(contains("holds sled data"));
+
+    let engine = kvs::SledKvsEngine::new(sled::open(temp_dir.path().join("new")).unwrap());
+    assert_eq!(
+        kvs::KvsEngine::get(&engine, "key3".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+}
+
+#[test]
+fn cli_stats_offline() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..10 {
+        kvs::KvsEngine::set(&store, format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    kvs::KvsEngine::remove(&store, "key0".to_owned()).unwrap();
+
+    // The store is read while it's still open for writing.
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--data-dir", "."])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys = 9\nlogs = 1\n").and(contains("last-compaction = never")));
+
+    kvs::KvsEngine::compact(&store).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("dead-bytes = 0\n").and(contains("s ago")));
+
+    // A mistyped directory is reported, rather than read as an empty store.
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--data-dir", "misspelled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("StoreNotFound"));
+    assert!(!temp_dir.path().join("misspelled").exists());
+}
+
+#[test]
+fn cli_fsck() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..10 {
+        kvs::KvsEngine::set(&store, format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    kvs::KvsEngine::compact(&store).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("log 1: ").and(contains("ok; 10 keys")));
+
+    let log = temp_dir.path().join("1.kvs.log");
+    let len = fs::metadata(&log).unwrap().len();
+    File::options()
+        .write(true)
+        .open(&log)
+        .unwrap()
+        .set_len(len - 3)
+        .unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("log 1: ").and(contains("damaged: Corruption: Truncated")))
+        .stderr(contains("--repair"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck", "--repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("repaired; rewrote 9 keys"));
+    assert_eq!(
+        kvs::KvsEngine::len(&kvs::KvStore::open(temp_dir.path()).unwrap()).unwrap(),
+        9
+    );
+}
+
+#[test]
+fn cli_apply() {
+    let temp_dir = TempDir::new().unwrap();
+    let ops = "set key1 value one\n# fixtures\n\nset key2 value2\nrm key1\nset key3 value3\n";
+    fs::write(temp_dir.path().join("ops.txt"), ops).unwrap();
+    fs::write(
+        temp_dir.path().join("bad.txt"),
+        "set key4 value4\nget key4\n",
+    )
+    .unwrap();
+    let more = "set key1 value one\nrm key9\nset key5 value5\n";
+    fs::write(temp_dir.path().join("more.txt"), more).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["apply", "--file", "ops.txt", "--data-dir", "data"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("applied 3 sets and 1 removes\n");
+    // Nothing is applied from a file with a bad line.
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["apply", "--file", "bad.txt", "--data-dir", "data"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("line 2"));
+    let store = kvs::KvStore::open(temp_dir.path().join("data")).unwrap();
+    assert_eq!(kvs::KvsEngine::keys(&store).unwrap(), ["key2", "key3"]);
+    drop(store);
+
+    let addr = "127.0.0.1:4019";
+    let _server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--data-dir", "server"])
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["apply", "--file", "ops.txt", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("applied 3 sets and 1 removes\n");
+    // A failing write stops the file, naming its line.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["apply", "--file", "more.txt", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("line 2: Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value one\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["exists", "key5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("false\n");
+}
+
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..10 {
+        kvs::KvsEngine::set(&store, format!("key{}", i), format!("value{}", i)).unwrap();
+    }
+    drop(store);
+    kvs::data_dir::set_engine(temp_dir.path(), "kvs").unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("migrated 10 pairs from kvs to sled"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--check-config"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs migrate"));
+
+    let engine = kvs::SledKvsEngine::new(sled::open(temp_dir.path()).unwrap());
+    assert_eq!(
+        kvs::KvsEngine::get(&engine, "key4".to_owned()).unwrap(),
+        Some("value4".to_owned())
+    );
+    kvs::KvsEngine::remove(&engine, "key4".to_owned()).unwrap();
+    drop(engine);
+
+    // Migrating back mustn't bring back what the old kvs files still hold.
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("migrated 9 pairs"));
+    let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(kvs::KvsEngine::len(&store).unwrap(), 9);
+    assert_eq!(
+        kvs::KvsEngine::get(&store, "key4".to_owned()).unwrap(),
+        None
+    );
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("kvs.engine")).unwrap(),
+        "kvs"
+    );
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already in kvs"));
+}
+
+// Each connection's log entries name it and its peer, and requests are numbered and timed.
+#[test]
+fn cli_slow_request_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4016";
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "--log-level",
+            "warn",
+            "--slow-request-ms",
+            "0",
+        ])
+        .current_dir(&temp_dir)
+        .stderr(std::process::Stdio::piped())
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(&addr.parse().unwrap()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    drop(client);
+    thread::sleep(Duration::from_millis(500));
+
+    let output = child.stop();
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("slow request"), "{}", log);
+    assert!(log.contains("conn: 1"), "{}", log);
+    assert!(log.contains("peer: 127.0.0.1:"), "{}", log);
+    assert!(
+        log.contains("seq: 1") && log.contains("request: set"),
+        "{}",
+        log
+    );
+    assert!(
+        log.contains("seq: 2") && log.contains("request: get"),
+        "{}",
+        log
+    );
+}
+
+#[test]
+fn cli_slow_op_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4020";
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--log-level", "warn", "--slow-op-ms", "0"])
+        .current_dir(&temp_dir)
+        .stderr(std::process::Stdio::piped())
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(&addr.parse().unwrap()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.compact().unwrap();
+    drop(client);
+    thread::sleep(Duration::from_millis(500));
+
+    let output = child.stop();
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("slow engine operation"), "{}", log);
+    assert!(
+        log.contains("op: set") && log.contains("key: key1") && log.contains("compacted: false"),
+        "{}",
+        log
+    );
+    assert!(
+        log.contains("op: compact") && log.contains("compacted: true"),
+        "{}",
+        log
+    );
+}
+
+// A replica started with --replica-of serves the primary's keys, but not writes, and
+// `kvs-client get --prefer-replica` reads from it, or from the primary if it's down.
+#[test]
+fn cli_replica() {
+    let temp_dir = TempDir::new().unwrap();
+    let primary_dir = temp_dir.path().join("primary");
+    let replica_dir = temp_dir.path().join("replica");
+    let (addr, replication_addr, replica_addr) =
+        ("127.0.0.1:4063", "127.0.0.1:4064", "127.0.0.1:4065");
+    let _primary = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--replication-addr", replication_addr])
+        .arg("--data-dir")
+        .arg(&primary_dir)
+        .spawn_server();
+    let replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", replica_addr, "--replica-of", replication_addr])
+        .arg("--data-dir")
+        .arg(&replica_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+
+    let get_from_replica = [
+        "get",
+        "key1",
+        "--prefer-replica",
+        "--replica",
+        replica_addr,
+        "--addr",
+        addr,
+    ];
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(get_from_replica)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", replica_addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", replica_addr])
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", replica_addr])
+        .assert()
+        .success()
+        .stdout(contains("kvs_replication_connected 1").and(contains("kvs_replication_lag 0")));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--prefer-replica", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("--replica"));
+
+    drop(replica);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(get_from_replica)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+#[test]
+fn cli_acl() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4078";
+    let acl_path = temp_dir.path().join("acl.toml");
+    fs::write(
+        &acl_path,
+        "[anonymous]\nrole = \"read-only\"\n\n[[token]]\ntoken = \"app\"\nrole = \"read-write\"\n\n\
+         [[token]]\ntoken = \"gossip\"\nrole = \"admin\"\n",
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let _server = server
+        .args(["--addr", addr, "--acl-file"])
+        .arg(&acl_path)
+        .current_dir(&temp_dir)
+        .spawn_server();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .env_remove("KVS_TOKEN")
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--token", "app", "--addr", addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .env("KVS_TOKEN", "guess")
+        .assert()
+        .failure()
+        .stderr(contains("unknown token"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .env_remove("KVS_TOKEN")
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--check-config", "--cluster", "--addr", addr, "--acl-file"])
+        .arg(&acl_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("needs a --cluster-token it grants the admin role"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--check-config", "--cluster", "--addr", addr, "--acl-file"])
+        .arg(&acl_path)
+        .env("KVS_CLUSTER_TOKEN", "app")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("must grant the cluster token the admin role"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--check-config", "--cluster", "--addr", addr, "--acl-file"])
+        .arg(&acl_path)
+        .env("KVS_CLUSTER_TOKEN", "gossip")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("gossip").not());
+}
@@ -0,0 +1,1379 @@
+use kvs::thread_pool::ThreadPoolKind;
+use kvs::{
+    Capabilities, ChangeOp, KvStore, KvsClient, KvsClientBuilder, KvsEngine, KvsError, KvsServer,
+    Request, Response, RetryPolicy, ServerStats, ShardedKvsClient, SizeLimits, StatsFrame, TraceId,
+    WireFormat, PROTOCOL_VERSION,
+};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// A server that accepts connections but never answers should make requests time out.
+#[test]
+fn read_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut streams = Vec::new();
+        for stream in listener.incoming() {
+            streams.push(stream.unwrap());
+        }
+    });
+
+    let mut client = KvsClient::builder(addr)
+        .read_timeout(Duration::from_millis(200))
+        .retry_policy(RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+        })
+        .connect()
+        .unwrap();
+
+    let start = Instant::now();
+    assert!(client.get("key1".to_owned()).is_err());
+    let elapsed = start.elapsed();
+    // One attempt plus two retries, each waiting out the read timeout.
+    assert!(elapsed >= Duration::from_millis(600), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+}
+
+// A lease is exclusive until it is released or expires, and each grant gets a larger id.
+#[test]
+fn leases() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+
+    let mut first = KvsClient::connect(&addr).unwrap();
+    let mut second = KvsClient::connect(&addr).unwrap();
+    let ttl = Duration::from_millis(300);
+
+    let id1 = first
+        .acquire_lease("leader".to_owned(), ttl)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        second.acquire_lease("leader".to_owned(), ttl).unwrap(),
+        None
+    );
+    assert!(first.renew_lease("leader".to_owned(), id1, ttl).unwrap());
+    assert!(first.release_lease("leader".to_owned(), id1).unwrap());
+
+    let id2 = second
+        .acquire_lease("leader".to_owned(), ttl)
+        .unwrap()
+        .unwrap();
+    assert!(id2 > id1);
+    assert!(!first.renew_lease("leader".to_owned(), id1, ttl).unwrap());
+
+    thread::sleep(ttl * 2);
+    assert!(!second.renew_lease("leader".to_owned(), id2, ttl).unwrap());
+    let id3 = first
+        .acquire_lease("leader".to_owned(), ttl)
+        .unwrap()
+        .unwrap();
+    assert!(id3 > id2);
+}
+
+// Writes inside a transaction are applied on commit, and a conflicting commit fails.
+#[test]
+fn transactions() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+
+    let mut first = KvsClient::connect(&addr).unwrap();
+    let mut second = KvsClient::connect(&addr).unwrap();
+    first.set("counter".to_owned(), "1".to_owned()).unwrap();
+
+    first.begin().unwrap();
+    first.set("counter".to_owned(), "2".to_owned()).unwrap();
+    assert_eq!(
+        first.get("counter".to_owned()).unwrap(),
+        Some("2".to_owned())
+    );
+    assert_eq!(
+        second.get("counter".to_owned()).unwrap(),
+        Some("1".to_owned())
+    );
+    first.commit().unwrap();
+    assert_eq!(
+        second.get("counter".to_owned()).unwrap(),
+        Some("2".to_owned())
+    );
+
+    first.begin().unwrap();
+    first.get("counter".to_owned()).unwrap();
+    second.set("counter".to_owned(), "5".to_owned()).unwrap();
+    first.set("counter".to_owned(), "3".to_owned()).unwrap();
+    assert!(first.commit().is_err());
+    assert_eq!(
+        first.get("counter".to_owned()).unwrap(),
+        Some("5".to_owned())
+    );
+
+    first.begin().unwrap();
+    first
+        .rename("counter".to_owned(), "renamed".to_owned())
+        .unwrap();
+    assert_eq!(second.get("renamed".to_owned()).unwrap(), None);
+    first.commit().unwrap();
+    assert_eq!(
+        second.get("renamed".to_owned()).unwrap(),
+        Some("5".to_owned())
+    );
+    assert_eq!(second.get("counter".to_owned()).unwrap(), None);
+    second
+        .rename("renamed".to_owned(), "counter".to_owned())
+        .unwrap();
+
+    first.begin().unwrap();
+    first.remove("counter".to_owned()).unwrap();
+    first.rollback().unwrap();
+    assert_eq!(
+        second.get("counter".to_owned()).unwrap(),
+        Some("5".to_owned())
+    );
+}
+
+// A spawned server accepts connections right away and stops accepting them once shut down.
+#[test]
+fn spawn_local_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (server, addr) = KvsServer::spawn_local(engine).unwrap();
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    server.shutdown().unwrap();
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+// Stats arrive as a stream of frames, after which the connection serves requests as usual.
+#[test]
+fn stats_frames() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let mut frames = Vec::new();
+    client.stats_frames(|frame| frames.push(frame)).unwrap();
+    assert!(frames.contains(&StatsFrame::Requests("set".to_owned(), 1)));
+    assert!(frames.contains(&StatsFrame::ActiveConnections(1)));
+
+    let mut stats = ServerStats::default();
+    for frame in frames {
+        stats.apply(frame);
+    }
+    assert_eq!(stats.requests.get("set"), Some(&1));
+    assert_eq!(stats.latency_count, 1);
+    assert_eq!(stats.latency_buckets.len(), 12);
+
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.stats().unwrap().requests.get("stats"), Some(&1));
+}
+
+// Relay checksummed frames from `from` to `to`, damaging as many as `corrupt` says.
+fn relay_frames(mut from: TcpStream, mut to: TcpStream, corrupt: Arc<AtomicU32>) {
+    loop {
+        let mut header = [0; 8];
+        if from.read_exact(&mut header).is_err() {
+            break;
+        }
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let mut payload = vec![0; len as usize];
+        from.read_exact(&mut payload).unwrap();
+        let damage = corrupt
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if damage {
+            payload[0] ^= 0xff;
+        }
+        if to.write_all(&header).is_err() || to.write_all(&payload).is_err() {
+            break;
+        }
+    }
+    let _ = to.shutdown(Shutdown::Both);
+}
+
+// Proxy connections to `server`, damaging as many request and response frames as asked.
+fn corrupting_proxy(
+    server: SocketAddr,
+    corrupt_requests: Arc<AtomicU32>,
+    corrupt_responses: Arc<AtomicU32>,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let mut client = client.unwrap();
+            let mut upstream = TcpStream::connect(server).unwrap();
+            // The hellos aren't framed, so pass them on untouched.
+            let mut hello = [0; 14];
+            client.read_exact(&mut hello).unwrap();
+            upstream.write_all(&hello).unwrap();
+            upstream.read_exact(&mut hello).unwrap();
+            client.write_all(&hello).unwrap();
+
+            let (requests, responses) = (corrupt_requests.clone(), corrupt_responses.clone());
+            let (client_reader, upstream_reader) =
+                (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            thread::spawn(move || relay_frames(client_reader, upstream, requests));
+            thread::spawn(move || relay_frames(upstream_reader, client, responses));
+        }
+    });
+    addr
+}
+
+// Damaged frames are sent again when that's safe, and reported as corruption otherwise.
+#[test]
+fn checksums() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, server_addr) = KvsServer::spawn_local(engine).unwrap();
+    let corrupt_requests = Arc::new(AtomicU32::new(0));
+    let corrupt_responses = Arc::new(AtomicU32::new(0));
+    let addr = corrupting_proxy(
+        server_addr,
+        corrupt_requests.clone(),
+        corrupt_responses.clone(),
+    );
+    let mut client = KvsClient::builder(addr).checksums(true).connect().unwrap();
+
+    // A damaged request never ran, so even a write is sent again.
+    corrupt_requests.store(2, Ordering::SeqCst);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(corrupt_requests.load(Ordering::SeqCst), 0);
+
+    // A read whose response was damaged is sent again.
+    corrupt_responses.store(1, Ordering::SeqCst);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // A write whose response was damaged may have run, so it isn't.
+    corrupt_responses.store(1, Ordering::SeqCst);
+    assert!(matches!(
+        client.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::TransportCorruption)
+    ));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    // Resending gives up eventually.
+    corrupt_requests.store(10, Ordering::SeqCst);
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::TransportCorruption)
+    ));
+}
+
+#[test]
+fn compact() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    for i in 0..10 {
+        client
+            .set("key1".to_owned(), format!("value{}", i))
+            .unwrap();
+    }
+    let before = client.stats().unwrap();
+    assert_eq!(before.compactions, 0);
+    assert!(before.compaction.dead_bytes > 0);
+
+    client.compact().unwrap();
+    let after = client.stats().unwrap();
+    assert_eq!(after.compactions, 1);
+    assert!(after.engine_size < before.engine_size);
+    assert!(!after.compaction.running);
+    assert!(after.compaction.bytes_processed > 0);
+    assert_eq!(after.compaction.dead_bytes, 0);
+    assert_eq!(after.engine.dead_bytes, Some(0));
+    assert_eq!(after.engine.disk_bytes, after.engine_size);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value9".to_owned())
+    );
+}
+
+// Pings should say what the server runs, and count up.
+#[test]
+fn ping() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    let first = client.ping().unwrap();
+    assert_eq!(first.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(first.engine, "kvs");
+    assert_eq!(first.pings, 1);
+    thread::sleep(Duration::from_millis(10));
+    let second = KvsClient::connect(&addr).unwrap().ping().unwrap();
+    assert_eq!(second.pings, 2);
+    assert!(second.uptime > first.uptime);
+}
+
+// A value read with its meta should show a new version once it's written again, in a
+// bucket as well as outside one, but not inside a transaction.
+#[test]
+fn get_with_meta() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::new(engine, slog::Logger::root(slog::Discard, slog::o!()))
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+    assert!(client
+        .server_capabilities()
+        .unwrap()
+        .contains(Capabilities::VERSIONS));
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let (value, first) = client.get_with_meta("key1".to_owned()).unwrap().unwrap();
+    assert_eq!(value, "value1");
+    assert!(first.written.is_some());
+    client.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    let (value, second) = client.get_with_meta("key1".to_owned()).unwrap().unwrap();
+    assert_eq!(value, "value2");
+    assert!(second.version > first.version);
+    assert_eq!(client.get_with_meta("missing".to_owned()).unwrap(), None);
+    assert_eq!(client.metadata("key1".to_owned()).unwrap(), Some(second));
+    assert_eq!(client.metadata("missing".to_owned()).unwrap(), None);
+
+    let mut bucket = KvsClient::builder(addr).bucket("b").connect().unwrap();
+    bucket.set("key1".to_owned(), "value3".to_owned()).unwrap();
+    let (value, meta) = bucket.get_with_meta("key1".to_owned()).unwrap().unwrap();
+    assert_eq!(value, "value3");
+    assert!(meta.version > second.version);
+
+    client.begin().unwrap();
+    assert!(client.get_with_meta("key1".to_owned()).is_err());
+    assert!(client.metadata("key1".to_owned()).is_err());
+    client.rollback().unwrap();
+}
+
+// A server with compaction backpressure should refuse writes while the engine compacts,
+// keeping the connection, and take them once it's done.
+#[test]
+fn compaction_backpressure() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..2000 {
+        engine.set(format!("key{}", i), "v".repeat(1024)).unwrap();
+    }
+    let (_server, addr) = KvsServer::new(
+        engine.clone(),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .with_compaction_backpressure(Duration::from_millis(1))
+    .spawn(&([127, 0, 0, 1], 0).into())
+    .unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+    assert!(client
+        .server_capabilities()
+        .unwrap()
+        .contains(Capabilities::BACKPRESSURE));
+
+    let done = Arc::new(AtomicBool::new(false));
+    let compactor = {
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                engine.compact().unwrap();
+                thread::sleep(Duration::from_millis(5));
+            }
+        })
+    };
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut throttled = 0;
+    let mut i = 0;
+    while throttled == 0 && Instant::now() < deadline {
+        loop {
+            match client.set(format!("new{}", i), "value".to_owned()) {
+                Ok(()) => break,
+                Err(KvsError::Throttled(retry_after)) => {
+                    assert_eq!(retry_after, Duration::from_millis(1));
+                    throttled += 1;
+                    thread::sleep(retry_after);
+                }
+                Err(err) => panic!("unexpected error {}", err),
+            }
+        }
+        i += 1;
+    }
+    done.store(true, Ordering::SeqCst);
+    compactor.join().unwrap();
+
+    assert!(throttled > 0, "no write was refused during compaction");
+    for i in 0..i {
+        assert_eq!(
+            client.get(format!("new{}", i)).unwrap(),
+            Some("value".to_owned())
+        );
+    }
+}
+
+// A client over the rate limit should have its requests refused, keeping the connection,
+// until its bucket refills; one that retries should get through.
+#[test]
+fn rate_limit() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::new(engine, slog::Logger::root(slog::Discard, slog::o!()))
+        .with_rate_limit(1, 2)
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::RateLimited)
+    ));
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    let mut retrying = KvsClient::builder(addr)
+        .retry_policy(RetryPolicy::with_max_retries(10))
+        .connect()
+        .unwrap();
+    assert_eq!(
+        retrying.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// A server that answers the handshake with `version` and `capabilities`, then hangs up.
+fn fake_server(version: u16, capabilities: Capabilities) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut hello = [0; 14];
+            if stream.read_exact(&mut hello).is_err() {
+                continue;
+            }
+            hello[4..6].copy_from_slice(&version.to_be_bytes());
+            hello[6..].copy_from_slice(&capabilities.bits().to_be_bytes());
+            let _ = stream.write_all(&hello);
+        }
+    });
+    addr
+}
+
+#[test]
+fn handshake() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+    let capabilities = client.server_capabilities().unwrap();
+    assert!(capabilities.contains(Capabilities::INCR | Capabilities::COMPACT));
+    assert!(capabilities.contains(Capabilities::CHECKSUMS));
+    assert_eq!(client.incr("counter".to_owned(), 1).unwrap(), 1);
+
+    let addr = fake_server(PROTOCOL_VERSION + 1, Capabilities::empty());
+    let mut client = KvsClient::connect(&addr).unwrap();
+    match client.get("key1".to_owned()) {
+        Err(KvsError::ProtocolMismatch { client, server }) => {
+            assert_eq!(client, PROTOCOL_VERSION);
+            assert_eq!(server, PROTOCOL_VERSION + 1);
+        }
+        result => panic!("expected a protocol mismatch, got {:?}", result),
+    }
+
+    // A request the server doesn't offer is refused without being sent.
+    let addr = fake_server(PROTOCOL_VERSION, Capabilities::INCR);
+    let mut client = KvsClient::connect(&addr).unwrap();
+    assert!(matches!(
+        client.compact(),
+        Err(KvsError::Unsupported(capability)) if capability == Capabilities::COMPACT
+    ));
+}
+
+// Each wire format should carry requests and responses alike, and JSON should be simple
+// enough to speak by hand.
+#[test]
+fn wire_formats() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    for format in [
+        WireFormat::MessagePack,
+        WireFormat::Json,
+        WireFormat::Bincode,
+    ] {
+        let mut client = KvsClient::builder(addr)
+            .wire_format(format)
+            .connect()
+            .unwrap();
+        // Trace ids take the whole of their 128 bits in every format.
+        client.set_trace_id(Some(TraceId(u128::MAX)));
+        let key = format.to_string();
+        client.set(key.clone(), "value1".to_owned()).unwrap();
+        assert_eq!(
+            client
+                .get_many(vec![key.clone(), "missing".to_owned()])
+                .unwrap(),
+            [Some("value1".to_owned()), None]
+        );
+        assert_eq!(client.incr(format!("{}-counter", key), 2).unwrap(), 2);
+        assert!(client.stats().unwrap().requests["set"] >= 1);
+        client.remove(key.clone()).unwrap();
+        assert_eq!(client.get(key).unwrap(), None);
+    }
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut hello = vec![0xc1, b'K', b'V', b'S'];
+    hello.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    hello.extend_from_slice(&Capabilities::JSON.bits().to_be_bytes());
+    let request = br#"{"Get":"key1"}"#;
+    hello.extend_from_slice(&(request.len() as u32).to_be_bytes());
+    hello.extend_from_slice(&crc32fast::hash(request).to_be_bytes());
+    hello.extend_from_slice(request);
+    stream.write_all(&hello).unwrap();
+    let mut header = [0; 14 + 8];
+    stream.read_exact(&mut header).unwrap();
+    let len = u32::from_be_bytes(header[14..18].try_into().unwrap());
+    let mut response = vec![0; len as usize];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(response, br#"{"GetOk":"value1"}"#);
+
+    // The request is never sent in a format the server doesn't speak.
+    let addr = fake_server(PROTOCOL_VERSION, Capabilities::INCR);
+    let mut client = KvsClient::builder(addr)
+        .wire_format(WireFormat::Json)
+        .connect()
+        .unwrap();
+    assert!(matches!(
+        client.len(),
+        Err(KvsError::Unsupported(capability)) if capability == Capabilities::JSON
+    ));
+}
+
+// Paging through keys should list each once, with a cursor until the last page.
+#[test]
+fn keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+    for i in 0..25 {
+        client
+            .set(format!("key{:02}", i), "value".to_owned())
+            .unwrap();
+    }
+
+    let mut listed = Vec::new();
+    let mut cursor = None;
+    let mut pages = 0;
+    loop {
+        let (keys, next) = client.keys(cursor, 10).unwrap();
+        listed.extend(keys);
+        pages += 1;
+        cursor = next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(pages, 3);
+    assert_eq!(
+        listed,
+        (0..25).map(|i| format!("key{:02}", i)).collect::<Vec<_>>()
+    );
+    // A page that ends with the last key has no cursor.
+    let (keys, cursor) = client.keys(Some("key14".to_owned()), 10).unwrap();
+    assert_eq!((keys.len(), cursor), (10, None));
+    assert!(client.keys(None, 0).is_err());
+
+    let mut bucket_client = KvsClient::builder(addr).bucket("bucket").connect().unwrap();
+    bucket_client
+        .set("key1".to_owned(), "value".to_owned())
+        .unwrap();
+    assert_eq!(
+        bucket_client.keys(None, 10).unwrap(),
+        (vec!["key1".to_owned()], None)
+    );
+}
+
+// Errors keep their kind across the wire.
+#[test]
+fn typed_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    client.set("text".to_owned(), "value".to_owned()).unwrap();
+    assert!(matches!(
+        client.incr("text".to_owned(), 1),
+        Err(KvsError::NotAnInteger)
+    ));
+    client.set("max".to_owned(), i64::MAX.to_string()).unwrap();
+    assert!(matches!(
+        client.incr("max".to_owned(), 1),
+        Err(KvsError::IntegerOverflow)
+    ));
+    match client.commit() {
+        Err(KvsError::StringError(msg)) => assert_eq!(msg, "no transaction is in progress"),
+        result => panic!("expected a plain error, got {:?}", result),
+    }
+}
+
+// Locks are taken and released over the wire, one owner at a time, and not in transactions.
+#[test]
+fn locks() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+    assert!(client
+        .server_capabilities()
+        .unwrap()
+        .contains(Capabilities::LOCKS));
+
+    let ttl = Duration::from_secs(60);
+    assert!(client
+        .acquire_lock("job".to_owned(), "worker1".to_owned(), ttl)
+        .unwrap());
+    let mut other = KvsClient::connect(&addr).unwrap();
+    assert!(!other
+        .acquire_lock("job".to_owned(), "worker2".to_owned(), ttl)
+        .unwrap());
+    assert!(client
+        .release_lock("job".to_owned(), "worker1".to_owned())
+        .unwrap());
+    assert!(other
+        .acquire_lock("job".to_owned(), "worker2".to_owned(), ttl)
+        .unwrap());
+
+    client.set("text".to_owned(), "value".to_owned()).unwrap();
+    assert!(matches!(
+        client.acquire_lock("text".to_owned(), "worker1".to_owned(), ttl),
+        Err(KvsError::NotALock)
+    ));
+
+    client.begin().unwrap();
+    assert!(client
+        .acquire_lock("job2".to_owned(), "worker1".to_owned(), ttl)
+        .is_err());
+    client.rollback().unwrap();
+}
+
+// A server whose threads are busy and whose queue is full turns further connections away.
+#[test]
+fn server_busy() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::new(engine, slog::Logger::root(slog::Discard, slog::o!()))
+        .with_thread_pool(ThreadPoolKind::SharedQueue, 1)
+        .with_queue_limit(1)
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+
+    // The first connection holds the only thread, the second waits in the queue.
+    let mut first = KvsClient::connect(&addr).unwrap();
+    first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let _second = KvsClient::connect(&addr).unwrap();
+
+    let mut third = KvsClient::connect(&addr).unwrap();
+    assert!(matches!(
+        third.get("key1".to_owned()),
+        Err(KvsError::ServerBusy)
+    ));
+    assert_eq!(first.stats().unwrap().rejected_connections, 1);
+}
+
+// A server at its connection limit turns further connections away until one closes, and
+// closes connections that go idle.
+#[test]
+fn connection_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::new(
+        engine.clone(),
+        slog::Logger::root(slog::Discard, slog::o!()),
+    )
+    .with_max_connections(2)
+    .spawn(&([127, 0, 0, 1], 0).into())
+    .unwrap();
+    let mut first = KvsClient::connect(&addr).unwrap();
+    first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut second = KvsClient::connect(&addr).unwrap();
+    second.get("key1".to_owned()).unwrap();
+    let mut third = KvsClient::connect(&addr).unwrap();
+    assert!(matches!(
+        third.get("key1".to_owned()),
+        Err(KvsError::ServerBusy)
+    ));
+    assert_eq!(second.stats().unwrap().rejected_connections, 1);
+
+    // The slot is given back once the server sees the client hang up.
+    drop(first);
+    let mut third = KvsClient::builder(addr)
+        .retry_policy(RetryPolicy::with_max_retries(10))
+        .connect()
+        .unwrap();
+    assert_eq!(
+        third.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    let (_server, addr) = KvsServer::new(engine, slog::Logger::root(slog::Discard, slog::o!()))
+        .with_idle_timeout(Duration::from_millis(100))
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+    let mut plain = KvsClient::connect(&addr).unwrap();
+    let mut retrying = KvsClient::builder(addr)
+        .retry_policy(RetryPolicy::with_max_retries(1))
+        .connect()
+        .unwrap();
+    plain.get("key1".to_owned()).unwrap();
+    retrying.get("key1".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert!(plain.get("key1".to_owned()).is_err());
+    assert_eq!(
+        retrying.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// A server can be reached over a Unix domain socket, which it removes once stopped, and a
+// socket left behind by a server that's gone doesn't stop another from starting.
+#[test]
+fn unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    drop(UnixListener::bind(&path).unwrap());
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let server = KvsServer::new(engine, slog::Logger::root(slog::Discard, slog::o!()))
+        .spawn_unix(&path)
+        .unwrap();
+
+    // With a socket, the address goes unused.
+    let mut client = KvsClient::builder(([127, 0, 0, 1], 1).into())
+        .socket(&path)
+        .connect()
+        .unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    drop(client);
+    server.shutdown().unwrap();
+    assert!(!path.exists());
+}
+
+// Keys and values over the server's limits are refused, and the connection stays usable.
+#[test]
+fn too_large() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::new(engine, slog::Logger::root(slog::Discard, slog::o!()))
+        .with_size_limits(SizeLimits {
+            max_key_bytes: Some(8),
+            max_value_bytes: Some(16),
+        })
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+
+    match client.set("key1".to_owned(), "v".repeat(17)) {
+        Err(KvsError::TooLarge(msg)) => {
+            assert_eq!(msg, "value of 17 bytes is over the limit of 16 bytes")
+        }
+        result => panic!("expected a too large error, got {:?}", result),
+    }
+    assert!(matches!(
+        client.get("k".repeat(9)),
+        Err(KvsError::TooLarge(_))
+    ));
+    client.set("key1".to_owned(), "v".repeat(16)).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("v".repeat(16)));
+
+    // The limits hold in buckets too.
+    let mut bucketed = KvsClient::builder(addr).bucket("b").connect().unwrap();
+    assert!(matches!(
+        bucketed.set("key1".to_owned(), "v".repeat(17)),
+        Err(KvsError::TooLarge(_))
+    ));
+}
+
+// Sets written in a batch fail with the engine's error, rebuilt for each connection in the
+// batch, rather than with its message alone.
+#[test]
+fn batched_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open_read_only(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::new(engine, slog::Logger::root(slog::Discard, slog::o!()))
+        .with_batch_window(Duration::from_millis(50))
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+
+    let handles: Vec<_> = (0..2)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(&addr).unwrap();
+                client.set(format!("key{}", i), "value".to_owned())
+            })
+        })
+        .collect();
+    for handle in handles {
+        match handle.join().unwrap() {
+            Err(KvsError::ReadOnly) => {}
+            result => panic!("expected a read-only error, got {:?}", result),
+        }
+    }
+}
+
+// Clients in different buckets see only their own keys.
+#[test]
+fn buckets() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    let mut plain = KvsClient::connect(&addr).unwrap();
+    let mut users = KvsClient::builder(addr).bucket("users").connect().unwrap();
+    let mut orders = KvsClient::builder(addr).bucket("orders").connect().unwrap();
+
+    users.set("key1".to_owned(), "user1".to_owned()).unwrap();
+    orders.set("key1".to_owned(), "order1".to_owned()).unwrap();
+    assert_eq!(
+        users.get("key1".to_owned()).unwrap(),
+        Some("user1".to_owned())
+    );
+    assert_eq!(
+        orders.get("key1".to_owned()).unwrap(),
+        Some("order1".to_owned())
+    );
+    assert_eq!(plain.get("key1".to_owned()).unwrap(), None);
+
+    users.set("key2".to_owned(), "user2".to_owned()).unwrap();
+    assert_eq!(users.len().unwrap(), 2);
+    assert_eq!(orders.len().unwrap(), 1);
+    let mut keys = users.slot_keys(1, vec![0]).unwrap();
+    keys.sort();
+    assert_eq!(keys, ["key1", "key2"]);
+
+    orders.remove("key1".to_owned()).unwrap();
+    assert!(users.contains("key1".to_owned()).unwrap());
+    assert_eq!(users.incr("count".to_owned(), 2).unwrap(), 2);
+    assert_eq!(users.append("log".to_owned(), "ab".to_owned()).unwrap(), 2);
+    assert_eq!(users.append("log".to_owned(), "cd".to_owned()).unwrap(), 4);
+    assert_eq!(
+        users.get_range("log".to_owned(), 1, 2).unwrap(),
+        Some("bc".to_owned())
+    );
+    assert_eq!(orders.get_range("log".to_owned(), 0, 1).unwrap(), None);
+    users.rename("key2".to_owned(), "key3".to_owned()).unwrap();
+    assert_eq!(
+        users.get("key3".to_owned()).unwrap(),
+        Some("user2".to_owned())
+    );
+    assert!(!users.contains("key2".to_owned()).unwrap());
+    assert!(matches!(
+        orders.rename("key3".to_owned(), "key4".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    let mut unnamed = KvsClient::builder(addr).bucket("").connect().unwrap();
+    assert!(unnamed.get("key1".to_owned()).is_err());
+}
+
+#[test]
+fn get_many() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+    let mut users = KvsClient::builder(addr).bucket("users").connect().unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    users.set("key2".to_owned(), "user2".to_owned()).unwrap();
+    let keys = || vec!["key2".to_owned(), "key1".to_owned()];
+    assert_eq!(
+        client.get_many(keys()).unwrap(),
+        [None, Some("value1".to_owned())]
+    );
+    assert_eq!(
+        users.get_many(keys()).unwrap(),
+        [Some("user2".to_owned()), None]
+    );
+
+    client.begin().unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        client.get_many(keys()).unwrap(),
+        [Some("value2".to_owned()), Some("value1".to_owned())]
+    );
+    client.rollback().unwrap();
+}
+
+// Pipelined requests are answered by id, in whatever order the server finishes them.
+#[test]
+fn pipelining() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    for checksums in [false, true] {
+        let mut client = KvsClient::builder(addr)
+            .checksums(checksums)
+            .connect()
+            .unwrap();
+        assert!(client
+            .server_capabilities()
+            .unwrap()
+            .contains(Capabilities::PIPELINING));
+
+        let mut keys = HashMap::new();
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            let id = client
+                .send(Request::Set(key.clone(), format!("value{}", i)))
+                .unwrap();
+            keys.insert(id, key);
+        }
+        // Plain calls would take the pipelined responses for their own.
+        assert!(client.len().is_err());
+        for _ in 0..100 {
+            let (id, response) = client.recv().unwrap();
+            assert!(keys.contains_key(&id));
+            assert!(matches!(response, Response::SetOk(())));
+        }
+        assert!(client.recv().is_err());
+
+        let mut gets = HashMap::new();
+        for key in keys.into_values() {
+            gets.insert(client.send(Request::Get(key.clone())).unwrap(), key);
+        }
+        while !gets.is_empty() {
+            let (id, response) = client.recv().unwrap();
+            let key = gets.remove(&id).unwrap();
+            match response {
+                Response::GetOk(Some(value)) => assert_eq!(value, key.replace("key", "value")),
+                other => panic!("unexpected response {:?}", other),
+            }
+        }
+
+        client.send(Request::Begin).unwrap();
+        let (_, response) = client.recv().unwrap();
+        assert!(response.into_result().is_err());
+        assert_eq!(client.len().unwrap(), 100);
+    }
+}
+
+// Stats and subscriptions are served where they're sent, pinned or not, and pipelined ones
+// are refused however they're wrapped, without taking the connection down.
+#[test]
+fn pinned_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::new(engine, slog::Logger::root(slog::Discard, slog::o!()))
+        .with_thread_pool(ThreadPoolKind::Rayon, 2)
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+    let mut pinned = KvsClient::builder(addr).pinned(true).connect().unwrap();
+    pinned.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert!(pinned.stats().unwrap().requests["set"] >= 1);
+
+    for request in [
+        Request::Stats,
+        Request::Subscribe("key".to_owned()),
+        Request::Pinned(Box::new(Request::Stats)),
+    ] {
+        pinned.send(request).unwrap();
+        let (_, response) = pinned.recv().unwrap();
+        assert!(response.into_result().is_err());
+    }
+    assert_eq!(
+        pinned.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // Pinned by a client that doesn't know better.
+    let mut stream = json_connection(addr);
+    send_json(&mut stream, &Request::Pinned(Box::new(Request::Stats)));
+    loop {
+        match recv_json(&mut stream) {
+            Response::StatsFrame(_) => {}
+            Response::StatsOk(()) => break,
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+    send_json(&mut stream, &Request::Get("key1".to_owned()));
+    assert!(matches!(
+        recv_json(&mut stream),
+        Response::GetOk(Some(value)) if value == "value1"
+    ));
+}
+
+// Stats under any number of trace ids are served as stats, and pipelined ones are refused,
+// without taking the connection down.
+#[test]
+fn traced_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::new(engine, slog::Logger::root(slog::Discard, slog::o!()))
+        .with_thread_pool(ThreadPoolKind::Rayon, 2)
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+    let traced = |request: Request| Request::Traced(TraceId(1), Box::new(request));
+
+    let mut stream = json_connection(addr);
+    for request in [
+        traced(traced(Request::Stats)),
+        traced(Request::Pinned(Box::new(traced(Request::Stats)))),
+    ] {
+        send_json(&mut stream, &request);
+        loop {
+            match recv_json(&mut stream) {
+                Response::StatsFrame(_) => {}
+                Response::StatsOk(()) => break,
+                response => panic!("unexpected response {:?}", response),
+            }
+        }
+    }
+
+    let mut client = KvsClient::connect(&addr).unwrap();
+    for request in [
+        traced(Request::Stats),
+        traced(traced(Request::Len)),
+        traced(Request::Tagged(2, Box::new(Request::Len))),
+    ] {
+        client.send(request).unwrap();
+        let (_, response) = client.recv().unwrap();
+        assert!(response.into_result().is_err());
+    }
+    assert_eq!(client.len().unwrap(), 0);
+}
+
+// Connect to `addr` with a hello asking for JSON frames, and read the server's.
+fn json_connection(addr: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut hello = vec![0xc1, b'K', b'V', b'S'];
+    hello.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    hello.extend_from_slice(&Capabilities::JSON.bits().to_be_bytes());
+    stream.write_all(&hello).unwrap();
+    stream.read_exact(&mut [0; 14]).unwrap();
+    stream
+}
+
+fn send_json(stream: &mut TcpStream, request: &Request) {
+    let payload = serde_json::to_vec(request).unwrap();
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    stream.write_all(&frame).unwrap();
+}
+
+fn recv_json(stream: &mut TcpStream) -> Response {
+    let mut header = [0; 8];
+    stream.read_exact(&mut header).unwrap();
+    let len = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).unwrap();
+    serde_json::from_slice(&payload).unwrap()
+}
+
+// A subscription sees the changes to keys with its prefix, in the order they were made.
+#[test]
+fn subscribe() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    let mut subscription = KvsClient::builder(addr)
+        .read_timeout(Duration::from_secs(5))
+        .connect()
+        .unwrap()
+        .subscribe("user")
+        .unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+
+    client.set("other".to_owned(), "1".to_owned()).unwrap();
+    client.set("user1".to_owned(), "1".to_owned()).unwrap();
+    client.incr("user2".to_owned(), 1).unwrap();
+    client.remove("user1".to_owned()).unwrap();
+    client.begin().unwrap();
+    client.set("user3".to_owned(), "3".to_owned()).unwrap();
+    client.commit().unwrap();
+    for (key, op) in [
+        ("user1", ChangeOp::Set),
+        ("user2", ChangeOp::Set),
+        ("user1", ChangeOp::Remove),
+        ("user3", ChangeOp::Set),
+    ] {
+        let change = subscription.recv().unwrap();
+        assert_eq!((change.key.as_str(), change.op), (key, op));
+    }
+
+    let bucketed = KvsClient::builder(addr).bucket("users").connect().unwrap();
+    assert!(bucketed.subscribe("").is_err());
+}
+
+// A cached client serves repeated gets itself, dropping the least recently read values to
+// make room, and the values others and it change.
+#[test]
+fn cache() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    let mut other = KvsClient::connect(&addr).unwrap();
+    for key in ["key1", "key2", "key3"] {
+        other.set(key.to_owned(), "1".to_owned()).unwrap();
+    }
+    let mut client = KvsClient::connect(&addr).unwrap().with_cache(2).unwrap();
+
+    for key in ["key1", "key2", "key1", "key3", "key1", "key2"] {
+        assert_eq!(client.get(key.to_owned()).unwrap(), Some("1".to_owned()));
+    }
+    assert_eq!(client.get("key4".to_owned()).unwrap(), None);
+    assert_eq!(client.get("key4".to_owned()).unwrap(), None);
+    // key1 stayed cached while key2 and key3 pushed each other out.
+    assert_eq!(other.stats().unwrap().requests["get"], 5);
+
+    // Another client's writes reach the cache shortly.
+    other.set("key4".to_owned(), "2".to_owned()).unwrap();
+    let start = Instant::now();
+    while client.get("key4".to_owned()).unwrap().is_none() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // The client's own writes reach it at once.
+    client.set("key1".to_owned(), "3".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), Some("3".to_owned()));
+    client.rename("key1".to_owned(), "key4".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(client.get("key4".to_owned()).unwrap(), Some("3".to_owned()));
+
+    let bucketed = KvsClient::builder(addr).bucket("users").connect().unwrap();
+    assert!(bucketed.with_cache(2).is_err());
+}
+
+// A wait_get answers once the key is set, or with None once the wait times out, and parked
+// pipelined waits leave the server's threads free to serve other requests.
+#[test]
+fn wait_get() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+    assert!(client
+        .server_capabilities()
+        .unwrap()
+        .contains(Capabilities::WAIT_GET));
+
+    client.set("ready".to_owned(), "1".to_owned()).unwrap();
+    assert_eq!(
+        client
+            .wait_get("ready".to_owned(), Duration::from_secs(5))
+            .unwrap(),
+        Some("1".to_owned())
+    );
+    let start = Instant::now();
+    assert_eq!(
+        client
+            .wait_get("missing".to_owned(), Duration::from_millis(100))
+            .unwrap(),
+        None
+    );
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    let waiter = thread::spawn(move || {
+        let mut client = KvsClient::connect(&addr).unwrap();
+        client.wait_get("job".to_owned(), Duration::from_secs(5))
+    });
+    thread::sleep(Duration::from_millis(200));
+    client.set("job".to_owned(), "work".to_owned()).unwrap();
+    assert_eq!(waiter.join().unwrap().unwrap(), Some("work".to_owned()));
+
+    // More waits than the server has threads, and a get pipelined behind them.
+    let mut pipelined = KvsClient::connect(&addr).unwrap();
+    let mut waits = HashMap::new();
+    for i in 0..32 {
+        let key = format!("job{}", i);
+        let request = Request::WaitGet {
+            key: key.clone(),
+            timeout_ms: 5000,
+        };
+        waits.insert(pipelined.send(request).unwrap(), key);
+    }
+    let get = pipelined.send(Request::Get("ready".to_owned())).unwrap();
+    let (id, response) = pipelined.recv().unwrap();
+    assert_eq!(id, get);
+    assert!(matches!(response, Response::GetOk(Some(_))));
+    for key in waits.values() {
+        client
+            .set(key.clone(), key.replace("job", "result"))
+            .unwrap();
+    }
+    while !waits.is_empty() {
+        let (id, response) = pipelined.recv().unwrap();
+        let key = waits.remove(&id).unwrap();
+        match response {
+            Response::WaitGetOk(Some(value)) => assert_eq!(value, key.replace("job", "result")),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    let mut bucketed = KvsClient::builder(addr).bucket("jobs").connect().unwrap();
+    bucketed.set("job".to_owned(), "queued".to_owned()).unwrap();
+    assert_eq!(
+        bucketed
+            .wait_get("job".to_owned(), Duration::from_secs(5))
+            .unwrap(),
+        Some("queued".to_owned())
+    );
+
+    client.begin().unwrap();
+    assert!(client
+        .wait_get("job".to_owned(), Duration::from_millis(10))
+        .is_err());
+    client.rollback().unwrap();
+}
+
+// A sharded client puts each key on the server the ring gives it, and puts together the
+// answers of every server.
+#[test]
+fn sharded() {
+    let temp_dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let engines: Vec<KvStore> = temp_dirs
+        .iter()
+        .map(|dir| KvStore::open(dir.path()).unwrap())
+        .collect();
+    let mut servers = Vec::new();
+    let mut addrs = Vec::new();
+    for engine in &engines {
+        let (server, addr) = KvsServer::spawn_local(engine.clone()).unwrap();
+        servers.push(server);
+        addrs.push(addr);
+    }
+    let mut client = ShardedKvsClient::new(&addrs);
+    let keys: Vec<String> = (0..60).map(|i| format!("key{:02}", i)).collect();
+    for key in &keys {
+        client.set(key.clone(), format!("value-{}", key)).unwrap();
+    }
+    for key in &keys {
+        let owner = addrs
+            .iter()
+            .position(|addr| Some(*addr) == client.server_for_key(key))
+            .unwrap();
+        for (i, engine) in engines.iter().enumerate() {
+            assert_eq!(engine.get(key.clone()).unwrap().is_some(), i == owner);
+        }
+    }
+    assert!(engines
+        .iter()
+        .all(|engine| !engine.keys().unwrap().is_empty()));
+    assert_eq!(client.len().unwrap(), 60);
+    assert_eq!(
+        client.get("key07".to_owned()).unwrap(),
+        Some("value-key07".to_owned())
+    );
+
+    let wanted = vec![
+        "key42".to_owned(),
+        "missing".to_owned(),
+        "key03".to_owned(),
+        "key17".to_owned(),
+    ];
+    assert_eq!(
+        client.get_many(wanted).unwrap(),
+        vec![
+            Some("value-key42".to_owned()),
+            None,
+            Some("value-key03".to_owned()),
+            Some("value-key17".to_owned()),
+        ]
+    );
+
+    let mut listed = Vec::new();
+    let mut cursor = None;
+    loop {
+        let (page, next) = client.keys(cursor, 7).unwrap();
+        assert!(page.len() <= 7);
+        listed.extend(page);
+        cursor = next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(listed, keys);
+
+    client.set("key05".to_owned(), "same".to_owned()).unwrap();
+    client.set("key55".to_owned(), "same".to_owned()).unwrap();
+    assert_eq!(
+        client.find_by_value("same".to_owned()).unwrap(),
+        vec!["key05".to_owned(), "key55".to_owned()]
+    );
+
+    let (near, far) = {
+        let owner = client.server_for_key("key00");
+        let far = keys
+            .iter()
+            .find(|key| client.server_for_key(key) != owner)
+            .unwrap();
+        let near = keys[1..]
+            .iter()
+            .find(|key| client.server_for_key(key) == owner)
+            .unwrap();
+        (near.clone(), far.clone())
+    };
+    assert!(client.rename("key00".to_owned(), far.clone()).is_err());
+    client.remove(near.clone()).unwrap();
+    client.rename("key00".to_owned(), near.clone()).unwrap();
+    assert_eq!(client.get(near).unwrap(), Some("value-key00".to_owned()));
+    assert!(!client.contains("key00".to_owned()).unwrap());
+
+    // Options apply to every server.
+    let mut bucketed =
+        ShardedKvsClient::new(&addrs).with_options(KvsClientBuilder::new(addrs[0]).bucket("users"));
+    assert!(bucketed.is_empty().unwrap());
+    bucketed.set(far.clone(), "user".to_owned()).unwrap();
+    assert_eq!(bucketed.len().unwrap(), 1);
+    assert_eq!(
+        client.get(far.clone()).unwrap(),
+        Some(format!("value-{}", far))
+    );
+
+    assert!(ShardedKvsClient::new(&[]).get("key".to_owned()).is_err());
+}
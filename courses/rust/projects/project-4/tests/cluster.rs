@@ -0,0 +1,214 @@
+use kvs::acl::{Acl, Grant, Role};
+use kvs::cluster::ClusterConfig;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, KvsServer, ServerHandle, ShardedKvsClient};
+use slog::{o, Discard, Logger};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn local(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+// Start a member of a cluster serving `engine` on `addr`, which knows of `peers` to start.
+fn member(engine: &KvStore, addr: SocketAddr, peers: &[SocketAddr]) -> ServerHandle {
+    KvsServer::new(engine.clone(), Logger::root(Discard, o!()))
+        .with_cluster(ClusterConfig::new(addr, peers.to_vec()))
+        .spawn(&addr)
+        .unwrap()
+        .0
+}
+
+// Wait until the member at `addr` knows of exactly `members`, all up.
+fn wait_for_members(addr: SocketAddr, members: &[SocketAddr]) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let stats = KvsClient::connect(&addr).unwrap().stats().unwrap();
+        let cluster = stats.cluster.clone().unwrap();
+        let known: Vec<SocketAddr> = cluster.members.iter().map(|member| member.addr).collect();
+        if known == members && cluster.members.iter().all(|member| member.up) {
+            return;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "{} never knew of {:?}: {:?}",
+            addr,
+            members,
+            cluster
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Members that know of a single peer come to know of each other, and agree on the owner of
+// each key, which any member sends clients to.
+#[test]
+fn members_gossip_and_redirect() {
+    let addrs = [local(4070), local(4071), local(4072)];
+    let temp_dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let engines: Vec<KvStore> = temp_dirs
+        .iter()
+        .map(|dir| KvStore::open(dir.path()).unwrap())
+        .collect();
+    let _first = member(&engines[0], addrs[0], &[]);
+    let _second = member(&engines[1], addrs[1], &[addrs[0]]);
+    let _third = member(&engines[2], addrs[2], &[addrs[1]]);
+    for addr in addrs {
+        wait_for_members(addr, &addrs);
+    }
+
+    let map = KvsClient::connect(&addrs[2])
+        .unwrap()
+        .cluster_map()
+        .unwrap();
+    assert_eq!(map.members, addrs);
+    let ring = map.ring();
+
+    // Every key lands on its owner, whichever member the client connected to.
+    let mut client = KvsClient::connect(&addrs[0]).unwrap();
+    for i in 0..30 {
+        let key = format!("key{:02}", i);
+        client.set(key.clone(), format!("value{}", i)).unwrap();
+        let owner = addrs
+            .iter()
+            .position(|addr| Some(*addr) == ring.node_for_key(&key))
+            .unwrap();
+        for (j, engine) in engines.iter().enumerate() {
+            assert_eq!(engine.get(key.clone()).unwrap().is_some(), j == owner);
+        }
+    }
+    assert!(engines.iter().all(|engine| engine.len().unwrap() > 0));
+    let mut other = KvsClient::connect(&addrs[2]).unwrap();
+    for i in 0..30 {
+        assert_eq!(
+            other.get(format!("key{:02}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+
+    // Keys owned by several members can't be served together.
+    let (near, far) = {
+        let owner = ring.node_for_key("key00");
+        let keys: Vec<String> = (1..30).map(|i| format!("key{:02}", i)).collect();
+        let near = keys.iter().find(|key| ring.node_for_key(key) == owner);
+        let far = keys.iter().find(|key| ring.node_for_key(key) != owner);
+        (near.unwrap().clone(), far.unwrap().clone())
+    };
+    assert!(client
+        .get_many(vec!["key00".to_owned(), far.clone()])
+        .is_err());
+    assert_eq!(
+        client
+            .get_many(vec!["key00".to_owned(), near.clone()])
+            .unwrap()
+            .len(),
+        2
+    );
+
+    // A transaction can't follow a key to another member.
+    let foreign = (0..30)
+        .map(|i| format!("key{:02}", i))
+        .find(|key| ring.node_for_key(key) != Some(addrs[0]))
+        .unwrap();
+    let mut txn_client = KvsClient::connect(&addrs[0]).unwrap();
+    txn_client.begin().unwrap();
+    assert!(matches!(
+        txn_client.get(foreign.clone()),
+        Err(KvsError::Moved(owner)) if Some(owner) == ring.node_for_key(&foreign)
+    ));
+    txn_client.rollback().unwrap();
+
+    // A sharded client learns the map from any member.
+    let mut sharded = ShardedKvsClient::from_cluster(&addrs[1]).unwrap();
+    assert_eq!(sharded.len().unwrap(), 30);
+    assert_eq!(sharded.get(far.clone()).unwrap(), client.get(far).unwrap());
+
+    // A pinned request is served where it's sent.
+    let mut pinned = KvsClient::builder(addrs[0]).pinned(true).connect().unwrap();
+    pinned.set(foreign.clone(), "pinned".to_owned()).unwrap();
+    assert_eq!(
+        engines[0].get(foreign.clone()).unwrap(),
+        Some("pinned".to_owned())
+    );
+    assert_ne!(client.get(foreign).unwrap(), Some("pinned".to_owned()));
+
+    let metrics = client.stats().unwrap().to_prometheus();
+    assert!(metrics.contains("kvs_cluster_members 3"));
+    assert!(metrics.contains("kvs_cluster_members_up 3"));
+}
+
+// A member that shuts down hands its slots to the others, and one that comes back takes
+// them again.
+#[test]
+fn member_leaves_and_rejoins() {
+    let addrs = [local(4073), local(4074)];
+    let temp_dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    let engines: Vec<KvStore> = temp_dirs
+        .iter()
+        .map(|dir| KvStore::open(dir.path()).unwrap())
+        .collect();
+    let _staying = member(&engines[0], addrs[0], &[]);
+    let leaving = member(&engines[1], addrs[1], &[addrs[0]]);
+    wait_for_members(addrs[0], &addrs);
+    wait_for_members(addrs[1], &addrs);
+
+    leaving.shutdown().unwrap();
+    wait_for_members(addrs[0], &addrs[..1]);
+    let mut client = KvsClient::connect(&addrs[0]).unwrap();
+    for i in 0..20 {
+        client
+            .set(format!("key{:02}", i), "value".to_owned())
+            .unwrap();
+    }
+    assert_eq!(engines[0].len().unwrap(), 20);
+    assert_eq!(engines[1].len().unwrap(), 0);
+
+    let _back = member(&engines[1], addrs[1], &[addrs[0]]);
+    wait_for_members(addrs[0], &addrs);
+    let map = client.cluster_map().unwrap();
+    assert_eq!(map.members, addrs);
+    let moved = (0..20)
+        .map(|i| format!("key{:02}", i))
+        .find(|key| map.ring().node_for_key(key) == Some(addrs[1]))
+        .unwrap();
+    // Keys aren't moved with their slots.
+    assert_eq!(client.get(moved).unwrap(), None);
+}
+
+// Members with ACLs gossip with the token they share, and one whose token isn't admin under
+// them isn't let in.
+#[test]
+fn members_gossip_under_acl() {
+    let addrs = [local(4087), local(4088)];
+    let outsider_addr = local(4089);
+    let acl = Acl::new()
+        .with_anonymous(Grant::new(Role::ReadOnly))
+        .with_token("gossip", Grant::new(Role::Admin))
+        .with_token("app", Grant::new(Role::ReadWrite));
+    let temp_dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let engines: Vec<KvStore> = temp_dirs
+        .iter()
+        .map(|dir| KvStore::open(dir.path()).unwrap())
+        .collect();
+    let start = |engine: &KvStore, addr: SocketAddr, peers: &[SocketAddr], token: &str| {
+        let mut config = ClusterConfig::new(addr, peers.to_vec());
+        config.token = Some(token.to_owned());
+        KvsServer::new(engine.clone(), Logger::root(Discard, o!()))
+            .with_cluster(config)
+            .with_acl(acl.clone())
+            .spawn(&addr)
+            .unwrap()
+            .0
+    };
+    let _first = start(&engines[0], addrs[0], &[], "gossip");
+    let _second = start(&engines[1], addrs[1], &[addrs[0]], "gossip");
+    let _outsider = start(&engines[2], outsider_addr, &[addrs[0]], "app");
+    wait_for_members(addrs[0], &addrs);
+    wait_for_members(addrs[1], &addrs);
+
+    // The outsider has had time to gossip, and been turned away.
+    thread::sleep(Duration::from_millis(500));
+    wait_for_members(addrs[0], &addrs);
+    wait_for_members(outsider_addr, &[outsider_addr]);
+}
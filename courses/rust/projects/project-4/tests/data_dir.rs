@@ -0,0 +1,93 @@
+use kvs::data_dir;
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use std::fs;
+use tempfile::TempDir;
+
+fn populated_store(dir: &std::path::Path) -> Result<()> {
+    let store = KvStore::open(dir)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    fs::create_dir(dir.join("raft"))?;
+    fs::write(dir.join("raft").join("term"), "3")?;
+    Ok(())
+}
+
+#[test]
+fn move_store() -> Result<()> {
+    let from = TempDir::new().expect("unable to create temporary working directory");
+    let to = TempDir::new().expect("unable to create temporary working directory");
+    let to = to.path().join("moved");
+    populated_store(from.path())?;
+
+    let summary = data_dir::move_data(from.path(), &to, |_, _| Ok(()))?;
+    assert_eq!(summary.resumed_files, 0);
+    assert!(summary.copied_files >= 2);
+    assert_eq!(data_dir::moved_to(from.path())?, Some(to.clone()));
+    assert_eq!(data_dir::moved_to(&to)?, None);
+    assert_eq!(fs::read_to_string(to.join("raft").join("term"))?, "3");
+
+    let store = KvStore::open(&to)?;
+    assert_eq!(store.len()?, 100);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+
+    // A moved directory can't be moved again.
+    let again = TempDir::new().expect("unable to create temporary working directory");
+    assert!(data_dir::move_data(from.path(), again.path(), |_, _| Ok(())).is_err());
+    Ok(())
+}
+
+// A move stopped partway should carry on with the files it hadn't copied.
+#[test]
+fn resume_interrupted_move() -> Result<()> {
+    let from = TempDir::new().expect("unable to create temporary working directory");
+    let to = TempDir::new().expect("unable to create temporary working directory");
+    populated_store(from.path())?;
+
+    let interrupted = data_dir::move_data(from.path(), to.path(), |_, _| {
+        Err(KvsError::StringError("interrupted".to_owned()))
+    });
+    assert!(interrupted.is_err());
+    assert_eq!(data_dir::moved_to(from.path())?, None);
+
+    let summary = data_dir::move_data(from.path(), to.path(), |_, _| Ok(()))?;
+    assert_eq!(summary.resumed_files, 1);
+    assert!(summary.copied_files >= 1);
+    assert_eq!(KvStore::open(to.path())?.len()?, 100);
+    Ok(())
+}
+
+#[test]
+fn refuse_unsafe_destinations() -> Result<()> {
+    let from = TempDir::new().expect("unable to create temporary working directory");
+    let to = TempDir::new().expect("unable to create temporary working directory");
+    populated_store(from.path())?;
+
+    fs::write(to.path().join("unrelated"), "data")?;
+    assert!(data_dir::move_data(from.path(), to.path(), |_, _| Ok(())).is_err());
+    assert!(data_dir::move_data(from.path(), &from.path().join("inside"), |_, _| Ok(())).is_err());
+    assert_eq!(data_dir::moved_to(from.path())?, None);
+    Ok(())
+}
+
+// A copy damaged after it was verified is caught when the move resumes.
+#[test]
+fn detect_damaged_copy() -> Result<()> {
+    let from = TempDir::new().expect("unable to create temporary working directory");
+    let to = TempDir::new().expect("unable to create temporary working directory");
+    populated_store(from.path())?;
+
+    let mut copied = None;
+    let _ = data_dir::move_data(from.path(), to.path(), |file, _| {
+        copied = Some(file.to_owned());
+        Err(KvsError::StringError("interrupted".to_owned()))
+    });
+    let copied = to.path().join(copied.unwrap());
+    let mut bytes = fs::read(&copied)?;
+    bytes[0] ^= 0xff;
+    fs::write(&copied, bytes)?;
+
+    let err = data_dir::move_data(from.path(), to.path(), |_, _| Ok(())).unwrap_err();
+    assert!(err.to_string().contains("damaged"), "{}", err);
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use bincode::Options;
+use kvs::{Codec, KvStore, KvsEngine, KvsError, Request};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use tempfile::TempDir;
+
+// `Request::Tagged` wrapped around itself `depth` times, around a `Len`, in bincode.
+fn nested_bincode(depth: usize) -> Vec<u8> {
+    let options = bincode::DefaultOptions::new();
+    let inner = options.serialize(&Request::Len).unwrap();
+    let outer = options
+        .serialize(&Request::Tagged(1, Box::new(Request::Len)))
+        .unwrap();
+    let prefix = &outer[..outer.len() - inner.len()];
+    let mut bytes = prefix.repeat(depth);
+    bytes.extend_from_slice(&inner);
+    bytes
+}
+
+#[test]
+fn deeply_nested_requests() {
+    // Deep enough to overflow the stack if decoding recursed all the way down.
+    let payload = nested_bincode(1_000_000);
+    let options = bincode::DefaultOptions::new();
+    assert!(options.deserialize::<Request>(&payload).is_err());
+
+    let tagged = rmp_serde::to_vec(&Request::Tagged(1, Box::new(Request::Len))).unwrap();
+    let len = rmp_serde::to_vec(&Request::Len).unwrap();
+    let prefix = &tagged[..tagged.len() - len.len()];
+    let mut msgpack = prefix.repeat(1_000_000);
+    msgpack.extend_from_slice(&len);
+    assert!(rmp_serde::from_slice::<Request>(&msgpack).is_err());
+
+    // The nesting a client does make still decodes.
+    let request = Request::InBucket(
+        "bucket".to_owned(),
+        Box::new(Request::Tagged(1, Box::new(Request::Len))),
+    );
+    let bytes = options.serialize(&request).unwrap();
+    assert!(options.deserialize::<Request>(&bytes).is_ok());
+}
+
+#[test]
+fn deeply_nested_batches() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())
+        .unwrap()
+        .with_codec(Codec::Bincode)
+        .unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    // A batch holding a batch holding a batch, and so on, as one record: the variant of
+    // `Batch` and a length of 1, over and over.
+    let mut record = Vec::new();
+    for _ in 0..1_000_000 {
+        record.extend_from_slice(&[2, 1]);
+    }
+    record.push(0);
+    let log = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.to_string_lossy().ends_with(".kvs.log"))
+        .unwrap();
+    OpenOptions::new()
+        .append(true)
+        .open(&log)
+        .unwrap()
+        .write_all(&record)
+        .unwrap();
+
+    match KvStore::verify(temp_dir.path()) {
+        Err(KvsError::Corruption { .. }) => {}
+        result => panic!("expected corruption, got {:?}", result.map(drop)),
+    }
+}
+
+// The entry points of the fuzz targets, on a few hand-made inputs and a few hundred
+// arbitrary ones.
+#[cfg(feature = "fuzz")]
+mod entry_points {
+    use super::*;
+    use kvs::{Capabilities, PROTOCOL_VERSION};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    const HELLO_MAGIC: [u8; 4] = [0xc1, b'K', b'V', b'S'];
+
+    // The hello of a client asking for `capabilities`.
+    fn hello(capabilities: Capabilities) -> Vec<u8> {
+        let mut hello = HELLO_MAGIC.to_vec();
+        hello.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        hello.extend_from_slice(&capabilities.bits().to_be_bytes());
+        hello
+    }
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn fuzz_entry_points_decode() {
+        let mut bytes = rmp_serde::to_vec(&Request::Get("key1".to_owned())).unwrap();
+        bytes.extend(rmp_serde::to_vec(&Request::Len).unwrap());
+        let requests = kvs::fuzz::decode_requests(&bytes, None).unwrap();
+        assert_eq!(requests.len(), 2);
+
+        let mut bytes = hello(Capabilities::JSON);
+        bytes.extend(frame(br#"{"Set":["key1","value1"]}"#));
+        bytes.extend(frame(br#""Len""#));
+        let requests = kvs::fuzz::decode_requests(&bytes, None).unwrap();
+        assert_eq!(requests.len(), 2);
+
+        let mut bytes = hello(Capabilities::BINCODE);
+        bytes.extend(frame(&nested_bincode(100_000)));
+        assert!(kvs::fuzz::decode_requests(&bytes, None).is_err());
+
+        let commands =
+            kvs::fuzz::decode_resp_commands(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\nPING\r\n").unwrap();
+        assert_eq!(commands.len(), 2);
+        assert!(kvs::fuzz::decode_resp_commands(b"*1\r\n$9\r\nGET\r\n").is_err());
+
+        let requests =
+            kvs::fuzz::decode_http_requests(b"GET /keys/k%31 HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(requests, [("GET".to_owned(), "/keys/k1".to_owned())]);
+
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        drop(store);
+        let log = temp_dir.path().join("0.kvs.log");
+        assert_eq!(kvs::fuzz::load_index(&log).unwrap(), 2);
+    }
+
+    proptest! {
+        // A few hundred inputs on every test run; the fuzz targets in `fuzz/` go much further.
+        #[test]
+        fn arbitrary_requests_decode_or_fail(bytes in vec(any::<u8>(), 0..256), hello_caps in any::<u64>()) {
+            let _ = kvs::fuzz::decode_requests(&bytes, Some(1024));
+            let mut framed = hello(Capabilities::from_bits(hello_caps));
+            framed.extend(frame(&bytes));
+            let _ = kvs::fuzz::decode_requests(&framed, None);
+            let _ = kvs::fuzz::decode_resp_commands(&bytes);
+            let _ = kvs::fuzz::decode_http_requests(&bytes);
+        }
+
+        #[test]
+        fn arbitrary_logs_index_or_fail(bytes in vec(any::<u8>(), 0..256)) {
+            let temp_dir = TempDir::new().unwrap();
+            let log = temp_dir.path().join("0.kvs.log");
+            fs::write(&log, &bytes).unwrap();
+            match kvs::fuzz::load_index(&log) {
+                Ok(_)
+                | Err(KvsError::Corruption { .. })
+                | Err(KvsError::UnsupportedLogFormat { .. })
+                | Err(KvsError::UnexpectedCommand) => {}
+                Err(err) => panic!("unclassified error: {}", err),
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+#![cfg(feature = "grpc")]
+
+use kvs::grpc::proto::kvs_client::KvsClient as GrpcClient;
+use kvs::grpc::proto::{GetRequest, Pair, RemoveRequest, ScanRequest, SetRequest};
+use kvs::{KvStore, KvsClient, KvsServer};
+use slog::{o, Discard, Logger};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tonic::transport::Channel;
+use tonic::Code;
+
+// Connect to the gRPC listener at `addr`, which starts once the server does.
+async fn connect(addr: SocketAddr) -> GrpcClient<Channel> {
+    let url = format!("http://{}", addr);
+    for _ in 0..50 {
+        if let Ok(client) = GrpcClient::connect(url.clone()).await {
+            return client;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("the grpc listener never started");
+}
+
+async fn get(client: &mut GrpcClient<Channel>, key: &str) -> Option<String> {
+    let request = GetRequest {
+        key: key.to_owned(),
+    };
+    client.get(request).await.unwrap().into_inner().value
+}
+
+async fn set(client: &mut GrpcClient<Channel>, key: &str, value: &str) {
+    let request = SetRequest {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    client.set(request).await.unwrap();
+}
+
+async fn scan(client: &mut GrpcClient<Channel>, prefix: &str) -> Vec<(String, String)> {
+    let request = ScanRequest {
+        prefix: prefix.to_owned(),
+    };
+    let mut stream = client.scan(request).await.unwrap().into_inner();
+    let mut pairs = Vec::new();
+    while let Some(Pair { key, value }) = stream.message().await.unwrap() {
+        pairs.push((key, value));
+    }
+    pairs
+}
+
+// Calls over gRPC reach the same engine as the kvs protocol, and are counted alike.
+#[tokio::test]
+async fn grpc_service() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let addr: SocketAddr = "127.0.0.1:4076".parse().unwrap();
+    let grpc_addr: SocketAddr = "127.0.0.1:4077".parse().unwrap();
+    let (_server, addr) = KvsServer::new(engine, Logger::root(Discard, o!()))
+        .with_grpc_addr(grpc_addr)
+        .spawn(&addr)
+        .unwrap();
+    let mut client = connect(grpc_addr).await;
+
+    assert_eq!(get(&mut client, "key1").await, None);
+    set(&mut client, "key1", "value1").await;
+    assert_eq!(get(&mut client, "key1").await, Some("value1".to_owned()));
+    let mut kvs_client = KvsClient::connect(&addr).unwrap();
+    assert_eq!(
+        kvs_client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    client
+        .remove(RemoveRequest {
+            key: "key1".to_owned(),
+        })
+        .await
+        .unwrap();
+    let missing = client
+        .remove(RemoveRequest {
+            key: "key1".to_owned(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(missing.code(), Code::NotFound);
+
+    // More keys than a scan reads at a time, around keys that don't start with the prefix.
+    kvs_client.set("a".to_owned(), "before".to_owned()).unwrap();
+    kvs_client.set("c".to_owned(), "after".to_owned()).unwrap();
+    kvs_client.set("b".to_owned(), "itself".to_owned()).unwrap();
+    let mut expected = vec![("b".to_owned(), "itself".to_owned())];
+    for i in 0..250 {
+        let key = format!("b{:03}", i);
+        set(&mut client, &key, &i.to_string()).await;
+        expected.push((key, i.to_string()));
+    }
+    assert_eq!(scan(&mut client, "b").await, expected);
+    assert_eq!(scan(&mut client, "b1").await.len(), 100);
+    assert_eq!(scan(&mut client, "").await.len(), 253);
+    assert!(scan(&mut client, "d").await.is_empty());
+
+    // The sets of both protocols.
+    let stats = kvs_client.stats().unwrap();
+    assert_eq!(stats.requests["set"], 254);
+}
@@ -0,0 +1,194 @@
+use kvs::{KvStore, KvsClient, KvsError, KvsServer, RetryPolicy, SizeLimits};
+use slog::{o, Discard, Logger};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn spawn_http(temp_dir: &TempDir, http_addr: SocketAddr) -> kvs::ServerHandle {
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (handle, _) = KvsServer::new(engine, Logger::root(Discard, o!()))
+        .with_http_addr(http_addr)
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+    handle
+}
+
+// Send a request on a kept-alive connection. Return the status and body of the response.
+fn request(
+    stream: &mut BufReader<TcpStream>,
+    method: &str,
+    target: &str,
+    body: &str,
+) -> (u16, String) {
+    write!(
+        stream.get_mut(),
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut line = String::new();
+    stream.read_line(&mut line).unwrap();
+    let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut len = 0;
+    loop {
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+            len = value.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).unwrap();
+    (status, String::from_utf8(body).unwrap())
+}
+
+fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+    // The gateway starts listening on the server's thread, possibly after `spawn` returns.
+    for _ in 0..50 {
+        if let Ok(stream) = TcpStream::connect(addr) {
+            return BufReader::new(stream);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("http gateway didn't come up on {}", addr);
+}
+
+#[test]
+fn http_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4040".parse().unwrap();
+    let _server = spawn_http(&temp_dir, addr);
+    let mut stream = connect(addr);
+
+    assert_eq!(request(&mut stream, "GET", "/keys/key1", "").0, 404);
+    assert_eq!(
+        request(&mut stream, "PUT", "/keys/key1", "value1"),
+        (204, String::new())
+    );
+    assert_eq!(
+        request(&mut stream, "PUT", "/keys/user%2F2", "two words"),
+        (204, String::new())
+    );
+    assert_eq!(request(&mut stream, "PUT", "/keys/user%2F10", "ten").0, 204);
+    assert_eq!(
+        request(&mut stream, "GET", "/keys/key1", ""),
+        (200, "value1".to_owned())
+    );
+    assert_eq!(
+        request(&mut stream, "GET", "/keys/user%2F2", ""),
+        (200, "two words".to_owned())
+    );
+
+    assert_eq!(
+        request(&mut stream, "GET", "/keys", ""),
+        (200, r#"["key1","user/10","user/2"]"#.to_owned())
+    );
+    assert_eq!(
+        request(&mut stream, "GET", "/keys?prefix=user%2F", ""),
+        (200, r#"["user/10","user/2"]"#.to_owned())
+    );
+
+    assert_eq!(
+        request(&mut stream, "DELETE", "/keys/key1", ""),
+        (204, String::new())
+    );
+    assert_eq!(request(&mut stream, "DELETE", "/keys/key1", "").0, 404);
+    assert_eq!(request(&mut stream, "GET", "/keys/key1", "").0, 404);
+
+    assert_eq!(request(&mut stream, "POST", "/keys/key1", "").0, 405);
+    assert_eq!(request(&mut stream, "DELETE", "/keys", "").0, 405);
+    assert_eq!(request(&mut stream, "GET", "/values", "").0, 404);
+}
+
+// A malformed request gets a 400 and the connection is closed.
+#[test]
+fn http_bad_request() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4041".parse().unwrap();
+    let _server = spawn_http(&temp_dir, addr);
+    let mut stream = connect(addr);
+
+    stream
+        .get_mut()
+        .write_all(b"PUT /keys/key1 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(response.contains("Connection: close\r\n"));
+    assert!(response.ends_with("chunked bodies aren't supported; send a Content-Length\n"));
+}
+
+// HTTP connections count against the server's connection limit along with the others, and
+// a body longer than any value the server takes is refused before it's read.
+#[test]
+fn http_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4090".parse().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, kvs_addr) = KvsServer::new(engine, Logger::root(Discard, o!()))
+        .with_http_addr(addr)
+        .with_max_connections(1)
+        .with_size_limits(SizeLimits {
+            max_key_bytes: None,
+            max_value_bytes: Some(16),
+        })
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+
+    let mut stream = connect(addr);
+    stream
+        .get_mut()
+        .write_all(b"PUT /keys/key1 HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    assert!(response.ends_with("value of 1000000 bytes is over the limit of 16 bytes\n"));
+
+    // The slot is given back once the server sees the client hang up, which may be just
+    // after the client sees the response.
+    let mut held = None;
+    for _ in 0..50 {
+        let mut stream = connect(addr);
+        let put = write!(
+            stream.get_mut(),
+            "PUT /keys/key1 HTTP/1.1\r\nContent-Length: 6\r\n\r\nvalue1"
+        );
+        let mut line = String::new();
+        if put.and_then(|_| stream.read_line(&mut line)).is_ok() && line.starts_with("HTTP/1.1 204")
+        {
+            held = Some(stream);
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let held = held.expect("the slot was never given back");
+
+    let mut response = String::new();
+    connect(addr).read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    let mut client = KvsClient::connect(&kvs_addr).unwrap();
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::ServerBusy)
+    ));
+
+    drop(held);
+    let mut client = KvsClient::builder(kvs_addr)
+        .retry_policy(RetryPolicy::with_max_retries(10))
+        .connect()
+        .unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
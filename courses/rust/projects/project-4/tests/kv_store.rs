@@ -1,6 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    BoxedEngine, Codec, CompactionState, CorruptionKind, Durability, EngineMetrics, EntryMeta,
+    KvStore, KvsEngine, KvsError, Result, SizeLimits, SledKvsEngine, TombstoneHorizon, WriteStall,
+};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -132,15 +139,16 @@ fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
@@ -148,7 +156,12 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data. The directory stays locked while any
+    // clone is alive, and a thread still holds its clone for a moment after the barrier, so
+    // wait for them all to finish before reopening.
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
@@ -208,4 +221,1979 @@ fn concurrent_get() -> Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Reads going on while compaction moves records and removes logs should always find the
+// keys, whether reading files or maps.
+#[test]
+fn get_during_compaction() -> Result<()> {
+    for mmap_reads in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?.with_compaction_threshold(4 * 1024);
+        if mmap_reads {
+            store = store.with_mmap_reads()?;
+        }
+        for i in 0..50 {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let mut handles = Vec::new();
+        for thread_id in 0..4 {
+            let store = store.clone();
+            let done = done.clone();
+            handles.push(thread::spawn(move || {
+                let mut i = thread_id;
+                while !done.load(Ordering::SeqCst) {
+                    let value = store.get(format!("key{}", i % 50)).unwrap();
+                    assert!(value.unwrap().starts_with("value"));
+                    i += 1;
+                }
+            }));
+        }
+        for i in 0..2000 {
+            store.set(format!("key{}", i % 50), format!("value{}", i))?;
+        }
+        done.store(true, Ordering::SeqCst);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(store.compactions() > 0);
+    }
+    Ok(())
+}
+
+// A thread's readers of the logs compaction removes are dropped, and its reads go to the
+// compacted log.
+#[test]
+fn get_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    store.compact()?;
+    assert!(!temp_dir.path().join("0.kvs.log").exists());
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("changed".to_owned()));
+    for i in 1..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn set_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value0".to_owned())?;
+    store.set_batch(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ])?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A log cut short mid-record should be reported as corruption, not a decode error.
+#[test]
+fn verify_truncated_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    KvStore::verify(temp_dir.path())?;
+
+    let log = temp_dir.path().join("0.kvs.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    match KvStore::verify(temp_dir.path()) {
+        Err(KvsError::Corruption {
+            log_number, kind, ..
+        }) => {
+            assert_eq!(log_number, 0);
+            assert_eq!(kind, CorruptionKind::Truncated);
+        }
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    assert!(KvStore::open_strict(temp_dir.path()).is_err());
+
+    // The torn record is cut off, and the log appended to after the one before it.
+    let store = KvStore::open(temp_dir.path())?;
+    let tail = store.torn_tail().expect("no torn tail reported");
+    assert_eq!(
+        (tail.log_number, tail.offset + tail.dropped_bytes),
+        (0, len - 3)
+    );
+    assert_eq!(fs::metadata(&log)?.len(), tail.offset);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    KvStore::verify(temp_dir.path())?;
+    let store = KvStore::open_strict(temp_dir.path())?;
+    assert_eq!(store.torn_tail(), None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Logs should be refused if they aren't kvs logs or are in a newer format, and logs from
+// before headers read as they are.
+#[test]
+fn log_headers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log = temp_dir.path().join("0.kvs.log");
+    let bytes = fs::read(&log)?;
+
+    // A header that says format 5, as a newer build would write.
+    let mut newer = bytes.clone();
+    newer[4..6].copy_from_slice(&5u16.to_be_bytes());
+    fs::write(&log, &newer)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedLogFormat {
+            log_number: 0,
+            version: 5,
+            ..
+        }) => {}
+        other => panic!("expected an unsupported format, got {:?}", other.err()),
+    }
+
+    // A format 1 header, which has no codec, before the records.
+    let mut older = bytes[..24].to_vec();
+    older[4..6].copy_from_slice(&1u16.to_be_bytes());
+    let checksum = crc32fast::hash(&older[..20]);
+    older[20..24].copy_from_slice(&checksum.to_be_bytes());
+    older.extend_from_slice(&bytes[26..]);
+    fs::write(&log, &older)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    // Writes go to a new log rather than after the older format's records.
+    assert_eq!(fs::read(&log)?, older);
+    assert!(temp_dir.path().join("1.kvs.log").exists());
+
+    fs::write(&log, b"not a kvs log")?;
+    match KvStore::verify(temp_dir.path()) {
+        Err(KvsError::Corruption {
+            log_number: 0,
+            offset: 0,
+            kind: CorruptionKind::Header,
+        }) => {}
+        other => panic!("expected a bad header, got {:?}", other),
+    }
+
+    // The records alone, as logs were written before they had headers.
+    fs::write(&log, &bytes[26..])?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert!(!log.exists());
+    KvStore::verify(temp_dir.path())?;
+
+    Ok(())
+}
+
+// Each codec should read back sets, removes and batches, be kept by a store opened again
+// without choosing one, and be what the logs are rewritten in when a store chooses it.
+#[test]
+fn codecs() -> Result<()> {
+    for codec in [Codec::MessagePack, Codec::Bincode, Codec::JsonLines] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?.with_codec(codec)?;
+        // A new store just starts its log over in the codec.
+        assert_eq!(store.compactions(), 0);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set_batch(vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ])?;
+        let mut txn = store.transaction()?;
+        txn.set("key4".to_owned(), "value4".to_owned());
+        txn.remove("key3".to_owned())?;
+        txn.commit()?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?.with_mmap_reads()?;
+        store.set("key5".to_owned(), "value5".to_owned())?;
+        store.compact()?;
+        store.set("key1".to_owned(), "value6".to_owned())?;
+        drop(store);
+        KvStore::verify(temp_dir.path())?;
+
+        let store = KvStore::open(temp_dir.path())?.with_codec(codec)?;
+        assert_eq!(store.compactions(), 0);
+        assert_eq!(store.get("key1".to_owned())?, Some("value6".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?.with_codec(Codec::JsonLines)?;
+    assert_eq!(store.compactions(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    let compacted = fs::read(temp_dir.path().join("1.kvs.log"))?;
+    assert!(String::from_utf8_lossy(&compacted)
+        .contains("{\"SetStamped\":[\"key1\",\"value1\",{\"version\":1,\"written\":"));
+
+    Ok(())
+}
+
+// Fsck should report damaged logs and stray filters, and repair should rewrite what can
+// still be read so the store opens again.
+#[test]
+fn fsck() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..=3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let report = KvStore::fsck(temp_dir.path(), false)?;
+    assert_eq!(report.logs.len(), 2);
+    assert!(!report.is_damaged());
+    assert_eq!(report.keys, 4);
+
+    // Cut the last record, key3's, off the sealed log.
+    let sealed = temp_dir.path().join("1.kvs.log");
+    let len = fs::metadata(&sealed)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&sealed)?
+        .set_len(len - 3)?;
+    fs::remove_file(temp_dir.path().join("1.kvs.bloom"))?;
+    let stray = temp_dir.path().join("7.kvs.bloom");
+    fs::write(&stray, b"")?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = KvStore::fsck(temp_dir.path(), false)?;
+    match &report.logs[0].damage {
+        Some(KvsError::Corruption { log_number: 1, .. }) => {}
+        other => panic!("expected log 1 to be damaged, got {:?}", other),
+    }
+    assert!(report.logs[1].damage.is_none());
+    assert_eq!(report.missing_filters, [1]);
+    assert_eq!(report.orphaned_files, [stray.as_path()]);
+    assert!(!report.repaired);
+
+    let report = KvStore::fsck(temp_dir.path(), true)?;
+    assert!(report.repaired);
+    assert_eq!(report.keys, 3);
+    assert!(!sealed.exists() && !stray.exists());
+    let report = KvStore::fsck(temp_dir.path(), false)?;
+    assert!(!report.is_damaged());
+    assert!(report.missing_filters.is_empty() && report.orphaned_files.is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Options should refuse to create a store, or to open one that exists, without leaving
+// anything behind.
+#[test]
+fn open_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+    let existing = kvs::OpenOptions {
+        create_if_missing: false,
+        ..kvs::OpenOptions::default()
+    };
+    match KvStore::open_with(&path, existing) {
+        Err(KvsError::StoreNotFound(missing)) => assert_eq!(missing, path),
+        other => panic!("expected no store, got {:?}", other.err()),
+    }
+    assert!(!path.exists());
+
+    let new = kvs::OpenOptions {
+        error_if_exists: true,
+        ..kvs::OpenOptions::default()
+    };
+    let store = KvStore::open_with(&path, new)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(matches!(
+        KvStore::open_with(&path, new),
+        Err(KvsError::StoreExists(_))
+    ));
+
+    let store = KvStore::open_with(&path, existing)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let read_only = kvs::OpenOptions {
+        read_only: true,
+        ..existing
+    };
+    let reader = KvStore::open_with(&path, read_only)?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        reader.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    // An empty directory holds no store.
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), read_only),
+        Err(KvsError::StoreNotFound(_))
+    ));
+    Ok(())
+}
+
+// The index should come out the same on any number of threads and shards, with the writes
+// of later logs winning over those of earlier ones.
+#[test]
+fn index_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    // Removes and overwrites in the log after the compacted one.
+    for i in (0..1000).step_by(3) {
+        store.remove(format!("key{}", i))?;
+    }
+    for i in (1..1000).step_by(3) {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    let expected = store.range(..)?;
+    assert_eq!(expected.len(), 666);
+    drop(store);
+
+    for (index_threads, index_shards) in [(0, 0), (1, 1), (3, 5), (16, 64)] {
+        let options = kvs::OpenOptions {
+            index_threads,
+            index_shards,
+            ..kvs::OpenOptions::default()
+        };
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.range(..)?, expected);
+        assert_eq!(store.len()?, 666);
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("new1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// Logs read side by side are still applied oldest first, however many are read at once.
+#[test]
+fn index_logs_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Each log is written by a store of its own and moved in after the last. Log `n` sets
+    // the keys from `n * 10` up, and removes the first of them again.
+    for n in 0..8 {
+        let log_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(log_dir.path())?;
+        for i in n * 10..100 {
+            store.set(format!("key{}", i), format!("value{}", n))?;
+        }
+        store.remove(format!("key{}", n * 10))?;
+        drop(store);
+        fs::rename(
+            log_dir.path().join("0.kvs.log"),
+            temp_dir.path().join(format!("{}.kvs.log", n)),
+        )?;
+    }
+
+    for index_threads in [1, 2, 3, 8] {
+        let options = kvs::OpenOptions {
+            index_threads,
+            index_shards: 3,
+            ..kvs::OpenOptions::default()
+        };
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for i in 0..100 {
+            let n = (i / 10).min(7);
+            let expected = (i != n * 10).then(|| format!("value{}", n));
+            assert_eq!(store.get(format!("key{}", i))?, expected, "key{}", i);
+        }
+        assert_eq!(store.len()?, 92);
+    }
+    Ok(())
+}
+
+// Walks of the index come out in key order however many shards its keys are split across,
+// and however many pages each shard is read in.
+#[test]
+fn index_shards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = kvs::OpenOptions {
+        index_shards: 4,
+        ..kvs::OpenOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let mut expected: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+    for key in &expected {
+        store.set(key.clone(), format!("value of {}", key))?;
+    }
+    expected.sort();
+    assert_eq!(store.keys()?, expected);
+    assert_eq!(store.len()?, 3000);
+    assert_eq!(store.first_key(), Some("key0".to_owned()));
+    assert_eq!(store.last_key(), Some("key999".to_owned()));
+
+    let mut after = None;
+    let mut paged = Vec::new();
+    loop {
+        let page = store.keys_after(after.clone(), 700)?;
+        if page.is_empty() {
+            break;
+        }
+        after = page.last().cloned();
+        paged.extend(page);
+    }
+    assert_eq!(paged, expected);
+
+    let range = store.range("key1".to_owned().."key2".to_owned())?;
+    let keys: Vec<String> = range.into_iter().map(|(key, _)| key).collect();
+    let in_range: Vec<String> = expected
+        .iter()
+        .filter(|key| key.as_str() >= "key1" && key.as_str() < "key2")
+        .cloned()
+        .collect();
+    assert_eq!(keys, in_range);
+    assert_eq!(
+        store.range("key2".to_owned().."key1".to_owned())?,
+        Vec::new()
+    );
+    assert_eq!(store.scan_prefix("key29")?.len(), 111);
+    Ok(())
+}
+
+// A read-only store reads alongside a writer, without writing or creating any file.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::open_read_only(temp_dir.path().join("missing")).is_err());
+    assert!(!temp_dir.path().join("missing").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let files = || fs::read_dir(temp_dir.path()).unwrap().count();
+    let files_before = files();
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        reader.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(reader.compact(), Err(KvsError::ReadOnly)));
+    assert_eq!(files(), files_before);
+
+    // The writer compacts the logs the reader holds away.
+    store.compact()?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    // Compaction sealed the values in log 1; this goes to log 2.
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // A record cut short, as one still being appended is, is left out.
+    let log = temp_dir.path().join("2.kvs.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.len()?, 2);
+    assert_eq!(reader.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Only one writable store can have a directory open at a time.
+#[test]
+fn lock_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::AlreadyLocked { pid }) => assert_eq!(pid, Some(std::process::id())),
+        other => panic!("expected the directory to be locked, got {:?}", other.err()),
+    }
+    // Readers don't need the lock.
+    KvStore::open_read_only(temp_dir.path())?;
+
+    // The lock is held until the last clone is dropped.
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// Reopening a directory fails while a clone of its store lives on, in any thread, and
+// works again once that thread is done with it.
+#[test]
+fn double_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (held, hold) = (Arc::new(Barrier::new(2)), Arc::new(Barrier::new(2)));
+    let handle = {
+        let (held, hold) = (held.clone(), hold.clone());
+        thread::spawn(move || {
+            held.wait();
+            hold.wait();
+            drop(store);
+        })
+    };
+    held.wait();
+    let err = KvStore::open(temp_dir.path()).err();
+    assert!(
+        matches!(err, Some(KvsError::AlreadyLocked { .. })),
+        "expected the directory to be locked, got {:?}",
+        err
+    );
+    hold.wait();
+    handle.join().unwrap();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// I/O errors must say which file the store was working on, and doing what. A directory
+// stands in for each file, so they fail even for root.
+#[test]
+fn io_error_context() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lock_file = temp_dir.path().join("kvs.lock");
+    fs::create_dir(&lock_file)?;
+    let err = KvStore::open(temp_dir.path())
+        .err()
+        .expect("opened the store");
+    let context = err.context().expect("the error has no context");
+    assert_eq!(context.operation, "locking");
+    assert_eq!(context.file.as_deref(), Some(lock_file.as_path()));
+    assert!(matches!(err.root(), KvsError::IO(_)));
+    assert!(err.source().is_some());
+    assert!(err.backtrace().is_some());
+    let msg = err.to_string();
+    assert!(
+        msg.starts_with(&format!("locking {}: IO: ", lock_file.display())),
+        "{}",
+        msg
+    );
+    fs::remove_dir(&lock_file)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let compacted_file = temp_dir.path().join("kvs.compacted");
+    fs::create_dir(&compacted_file)?;
+    let err = store.compact().expect_err("compacted");
+    let context = err.context().expect("the error has no context");
+    assert_eq!(context.operation, "writing");
+    assert_eq!(context.file.as_deref(), Some(compacted_file.as_path()));
+    // Errors that aren't I/O are left as they were, to be matched on.
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// Reads through maps of sealed logs must see what plain reads do, across compactions,
+// reopens and batch records.
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_batch(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+    ])?;
+    drop(store);
+
+    // The log written before reopening is sealed.
+    let store = KvStore::open(temp_dir.path())?.with_mmap_reads()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    store.compact()?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    assert_eq!(
+        store.range("key1".to_owned()..)?,
+        vec![
+            ("key1".to_owned(), "new".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+    assert_eq!(store.incr("key4".to_owned(), 2)?, 2);
+    store.compact()?;
+    assert_eq!(store.get("key4".to_owned())?, Some("2".to_owned()));
+
+    let reader = KvStore::open_read_only(temp_dir.path())?.with_mmap_reads()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("new".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 4);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Sealed logs have bloom filters of their keys, saved beside them and rebuilt if lost.
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    // Nothing is sealed until compaction.
+    assert!(!store.may_contain("key1"));
+    store.compact()?;
+    store.set("unsealed".to_owned(), "value".to_owned())?;
+
+    let bloom = temp_dir.path().join("1.kvs.bloom");
+    assert!(bloom.exists());
+    let check = |store: &KvStore| {
+        assert!((0..500).all(|key_id| store.may_contain(&format!("key{}", key_id))));
+        let false_positives = (0..1000)
+            .filter(|key_id| store.may_contain(&format!("missing{}", key_id)))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+    };
+    check(&store);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store);
+    drop(store);
+    fs::remove_file(&bloom)?;
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store);
+    assert!(bloom.exists());
+
+    // The filters of compacted logs go with them.
+    store.compact()?;
+    assert!(!bloom.exists());
+    assert!(store.may_contain("unsealed"));
+
+    Ok(())
+}
+
+// Concurrent read-modify-write sections under the same key lock must not lose updates.
+#[test]
+fn lock_key_serializes_updates() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..50 {
+                let _guard = store.lock_key("counter".to_owned());
+                let value: u64 = store
+                    .get("counter".to_owned())
+                    .unwrap()
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                store
+                    .set("counter".to_owned(), (value + 1).to_string())
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+
+    let guard = store.lock_key("counter".to_owned());
+    assert!(store.try_lock_key("counter".to_owned()).is_none());
+    assert!(store.try_lock_key("other".to_owned()).is_some());
+    drop(guard);
+    assert!(store.try_lock_key("counter".to_owned()).is_some());
+
+    Ok(())
+}
+
+// `contains` and `len` should track sets and removes, including across reopening.
+#[test]
+fn contains_and_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty()?);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.contains("key1".to_owned())?);
+    assert!(!store.contains("key2".to_owned())?);
+    assert_eq!(store.len()?, 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.contains("key1".to_owned())?);
+    assert!(!store.contains("key2".to_owned())?);
+    assert_eq!(store.len()?, 1);
+    Ok(())
+}
+
+// Stats should count the keys and logs, and the bytes compaction would reclaim.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!((stats.keys, stats.logs, stats.dead_bytes), (0, 1, 0));
+    assert_eq!(stats.log_bytes, store.size_on_disk()?);
+    assert_eq!(stats.last_compaction, None);
+
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 10);
+    assert_eq!(stats.log_bytes, store.size_on_disk()?);
+    assert!(stats.dead_bytes > stats.log_bytes * 8 / 10);
+    assert!(stats.index_bytes > 0);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!((stats.keys, stats.logs, stats.dead_bytes), (10, 2, 0));
+    assert!(stats.last_compaction.is_some());
+
+    // The time of the last compaction is kept across opens.
+    drop(store);
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert!(store.stats()?.last_compaction.is_some());
+    Ok(())
+}
+
+// Paging through keys should list each once, in order, and a bucket's page should hold only
+// its own keys.
+fn check_keys_after(engine: &impl KvsEngine) -> Result<()> {
+    for i in 0..5 {
+        engine.set(format!("key{}", i), "value".to_owned())?;
+    }
+    let bucket = engine.bucket("bucket")?;
+    bucket.set("key1".to_owned(), "value".to_owned())?;
+    bucket.set("key2".to_owned(), "value".to_owned())?;
+    engine
+        .bucket("other")?
+        .set("key0".to_owned(), "value".to_owned())?;
+
+    assert_eq!(
+        engine.keys_after(Some("key1".to_owned()), 2)?,
+        ["key2", "key3"]
+    );
+    assert_eq!(engine.keys_after(Some("key3".to_owned()), 10)?, ["key4"]);
+    assert!(engine.keys_after(Some("key4".to_owned()), 10)?.is_empty());
+    assert_eq!(bucket.keys_after(None, 1)?, ["key1"]);
+    assert_eq!(bucket.keys_after(Some("key1".to_owned()), 10)?, ["key2"]);
+    Ok(())
+}
+
+#[test]
+fn keys_after() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_keys_after(&KvStore::open(temp_dir.path())?)?;
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_keys_after(&SledKvsEngine::new(sled::open(sled_dir.path())?))
+}
+
+// Values should come back in the order of the keys asked for, wherever they lie in the logs.
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    let keys = || {
+        vec![
+            "key3".to_owned(),
+            "key4".to_owned(),
+            "key2".to_owned(),
+            "key1".to_owned(),
+        ]
+    };
+    let expected = vec![
+        Some("value4".to_owned()),
+        None,
+        Some("value2".to_owned()),
+        Some("value3".to_owned()),
+    ];
+    assert_eq!(store.get_many(keys())?, expected);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::new(sled::open(sled_dir.path())?);
+    for (key, value) in keys().into_iter().zip(&expected) {
+        if let Some(value) = value {
+            sled.set(key, value.clone())?;
+        }
+    }
+    assert_eq!(sled.get_many(keys())?, expected);
+    Ok(())
+}
+
+// A committed transaction's writes should be visible and survive reopening; a rolled
+// back one's should not.
+#[test]
+fn transaction_commit_and_rollback() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+
+    let mut txn = store.transaction()?;
+    txn.set("a".to_owned(), "10".to_owned());
+    txn.remove("b".to_owned())?;
+    txn.set("c".to_owned(), "3".to_owned());
+    assert_eq!(txn.get("a".to_owned())?, Some("10".to_owned()));
+    assert_eq!(txn.get("b".to_owned())?, None);
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    txn.commit()?;
+
+    let mut txn = store.transaction()?;
+    txn.set("a".to_owned(), "100".to_owned());
+    txn.rollback();
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("10".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.len()?, 2);
+    Ok(())
+}
+
+// Commit should fail, applying nothing, if another writer changed a key the transaction
+// read after it began.
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("balance".to_owned(), "10".to_owned())?;
+
+    let mut txn = store.transaction()?;
+    let balance: u64 = txn.get("balance".to_owned())?.unwrap().parse().unwrap();
+    txn.set("balance".to_owned(), (balance + 5).to_string());
+    txn.set("audit".to_owned(), "deposit".to_owned());
+    store.set("balance".to_owned(), "0".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(store.get("balance".to_owned())?, Some("0".to_owned()));
+    assert_eq!(store.get("audit".to_owned())?, None);
+
+    // Writes to keys the transaction didn't touch don't conflict.
+    let mut txn = store.transaction()?;
+    txn.set("balance".to_owned(), "5".to_owned());
+    store.set("other".to_owned(), "value".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.get("balance".to_owned())?, Some("5".to_owned()));
+    Ok(())
+}
+
+// Values written by transactions should survive compaction.
+#[test]
+fn transaction_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..1000 {
+        let mut txn = store.transaction()?;
+        for key_id in 0..100 {
+            txn.set(format!("key{}", key_id), format!("{}", iter));
+        }
+        txn.commit()?;
+        if store.compactions() > 0 {
+            break;
+        }
+    }
+    assert!(store.compactions() > 0, "No compaction detected");
+    let expected = store.get("key0".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// Should scan keys in order, by range and by prefix
+#[test]
+fn ordered_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.first_key(), None);
+    assert_eq!(store.last_key(), None);
+
+    for key in ["b/2", "a/1", "b/1", "c/1", "b/3"] {
+        store.set(key.to_owned(), format!("value {}", key))?;
+    }
+    store.remove("b/3".to_owned())?;
+
+    let pair = |key: &str| (key.to_owned(), format!("value {}", key));
+    assert_eq!(
+        store.range("a/1".to_owned().."b/2".to_owned())?,
+        vec![pair("a/1"), pair("b/1")]
+    );
+    assert_eq!(store.scan_prefix("b/")?, vec![pair("b/1"), pair("b/2")]);
+    assert_eq!(store.scan_prefix("d/")?, vec![]);
+    assert_eq!(store.first_key(), Some("a/1".to_owned()));
+    assert_eq!(store.last_key(), Some("c/1".to_owned()));
+    assert_eq!(store.keys()?, vec!["a/1", "b/1", "b/2", "c/1"]);
+
+    // Open from disk again and check the order is rebuilt
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.range(.."b/2".to_owned())?,
+        vec![pair("a/1"), pair("b/1")]
+    );
+
+    Ok(())
+}
+
+// Both engines should scan alike, and a bucket's scans should see only its own keys.
+fn check_scans(engine: &impl KvsEngine) -> Result<()> {
+    for key in ["b/2", "a/1", "b/1", "c/1"] {
+        engine.set(key.to_owned(), format!("value {}", key))?;
+    }
+    let bucket = engine.bucket("bucket")?;
+    bucket.set("b/1".to_owned(), "in bucket".to_owned())?;
+    bucket.set("b/4".to_owned(), "in bucket".to_owned())?;
+    engine
+        .bucket("other")?
+        .set("b/0".to_owned(), "in other".to_owned())?;
+
+    let pair = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+    assert_eq!(
+        engine.range("a/1".to_owned()..="b/1".to_owned())?,
+        vec![pair("a/1", "value a/1"), pair("b/1", "value b/1")]
+    );
+    assert_eq!(
+        engine.scan_prefix("b/")?,
+        vec![pair("b/1", "value b/1"), pair("b/2", "value b/2")]
+    );
+    assert_eq!(
+        bucket.range(.."b/4".to_owned())?,
+        vec![pair("b/1", "in bucket")]
+    );
+    assert_eq!(
+        bucket.range("b/2".to_owned()..)?,
+        vec![pair("b/4", "in bucket")]
+    );
+    assert_eq!(
+        bucket.scan_prefix("b/")?,
+        vec![pair("b/1", "in bucket"), pair("b/4", "in bucket")]
+    );
+    Ok(())
+}
+
+#[test]
+fn scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(&KvStore::open(temp_dir.path())?)?;
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(sled_dir.path())?;
+    check_scans(&SledKvsEngine::new(db.clone()))?;
+
+    // A key sled holds that isn't UTF-8 fails the scan that reaches it.
+    db.insert(b"b/\xff", "value")?;
+    let sled = SledKvsEngine::new(db);
+    assert!(matches!(sled.scan_prefix("b/"), Err(KvsError::Utf8(_))));
+    assert_eq!(sled.scan_prefix("c/")?.len(), 1);
+    Ok(())
+}
+
+// Each write should give a key a higher version, kept with its write time through
+// compaction and reopening.
+#[test]
+fn versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let before = SystemTime::now() - Duration::from_secs(1);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (value, first) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert!(first.written.unwrap() >= before);
+    assert_eq!(first.size, 6);
+    assert_eq!(store.metadata("key1".to_owned())?, Some(first));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set_batch(vec![("key2".to_owned(), "value3".to_owned())])?;
+    let mut txn = store.transaction()?;
+    txn.set("key3".to_owned(), "value4".to_owned());
+    txn.commit()?;
+    let meta =
+        |key: &str| -> Result<EntryMeta> { Ok(store.get_with_meta(key.to_owned())?.unwrap().1) };
+    let second = meta("key1")?;
+    assert!(second.version > first.version);
+    assert!(second.stamp > first.stamp);
+    assert!(meta("key2")?.version > second.version);
+    assert!(meta("key3")?.version > meta("key2")?.version);
+    assert!(meta("key3")?.stamp > meta("key2")?.stamp);
+    assert_eq!(store.get_with_meta("missing".to_owned())?, None);
+    assert_eq!(store.metadata("missing".to_owned())?, None);
+
+    store.compact()?;
+    assert_eq!(meta("key1")?, second);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let (value, reopened) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!((value.as_str(), reopened), ("value2", second));
+    store.set("key4".to_owned(), "value5".to_owned())?;
+    let (_, newest) = store.get_with_meta("key4".to_owned())?.unwrap();
+    let (_, key3) = store.get_with_meta("key3".to_owned())?.unwrap();
+    assert!(newest.version > key3.version);
+    assert!(newest.stamp > key3.stamp);
+    drop(store);
+
+    // A set of a format 3 log has a version and write time, but no stamp.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?.with_codec(Codec::JsonLines)?);
+    let log = temp_dir.path().join("0.kvs.log");
+    let mut format3 = fs::read(&log)?;
+    format3[4..6].copy_from_slice(&3u16.to_be_bytes());
+    let checksum = crc32fast::hash(&format3[..22]);
+    format3[22..26].copy_from_slice(&checksum.to_be_bytes());
+    format3.extend_from_slice(b"{\"Put\":[\"old\",\"value\",{\"version\":7,\"written\":1000}]}\n");
+    fs::write(&log, &format3)?;
+    let store = KvStore::open(temp_dir.path())?;
+    let old = store.metadata("old".to_owned())?.unwrap();
+    assert_eq!(old.version, 7);
+    assert_eq!(
+        old.written,
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1))
+    );
+    assert_eq!((old.stamp, old.size), (None, 5));
+    store.set("new".to_owned(), "value".to_owned())?;
+    assert_eq!(store.metadata("new".to_owned())?.unwrap().version, 8);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::new(sled::open(sled_dir.path())?);
+    assert!(sled.get_with_meta("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Earlier writes to a key should be read back until compaction drops them, in batches as
+// well as on their own, and across reopening.
+#[test]
+fn history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "other".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let mut txn = store.transaction()?;
+    txn.set("key1".to_owned(), "value3".to_owned());
+    txn.commit()?;
+
+    let history = store.history("key1")?;
+    let values: Vec<_> = history.iter().map(|rev| rev.value.as_deref()).collect();
+    assert_eq!(
+        values,
+        [Some("value1"), Some("value2"), None, Some("value3")]
+    );
+    assert!(history
+        .windows(2)
+        .all(|pair| pair[0].meta.version < pair[1].meta.version));
+    assert_eq!(history[3].meta, store.metadata("key1".to_owned())?.unwrap());
+    let version = |i: usize| history[i].meta.version;
+    assert_eq!(store.get_at("key1", version(0))?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get_at("key1", version(2) - 1)?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get_at("key1", version(2))?, None);
+    assert_eq!(store.get_at("key1", u64::MAX)?, Some("value3".to_owned()));
+    assert_eq!(store.get_at("key1", version(0) - 1)?, None);
+    assert!(store.history("missing")?.is_empty());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key1")?, history);
+    store.compact()?;
+    assert_eq!(store.history("key1")?, history[3..]);
+    assert_eq!(store.get_at("key1", version(1))?, None);
+    Ok(())
+}
+
+// Should add to counters atomically, treating missing keys as 0
+#[test]
+fn incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr("counter".to_owned(), -7)?, -2);
+    assert_eq!(store.get("counter".to_owned())?, Some("-2".to_owned()));
+
+    store.set("text".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        store.incr("text".to_owned(), 1),
+        Err(KvsError::NotAnInteger)
+    ));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.incr("max".to_owned(), 1),
+        Err(KvsError::IntegerOverflow)
+    ));
+
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..100 {
+                    store.incr("shared".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("shared".to_owned())?, Some("800".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("shared".to_owned(), 1)?, 801);
+
+    Ok(())
+}
+
+// Should move values between keys in one write, on every engine
+#[test]
+fn rename() -> Result<()> {
+    fn check(engine: impl KvsEngine) -> Result<()> {
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        engine.set("key2".to_owned(), "value2".to_owned())?;
+        engine.rename("key1".to_owned(), "key3".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, None);
+        assert_eq!(engine.get("key3".to_owned())?, Some("value1".to_owned()));
+
+        // An existing key is replaced.
+        engine.rename("key3".to_owned(), "key2".to_owned())?;
+        assert_eq!(engine.get("key2".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(engine.len()?, 1);
+
+        engine.rename("key2".to_owned(), "key2".to_owned())?;
+        assert_eq!(engine.get("key2".to_owned())?, Some("value1".to_owned()));
+        assert!(matches!(
+            engine.rename("key1".to_owned(), "key4".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert_eq!(engine.get("key4".to_owned())?, None);
+
+        let bucket = engine.bucket("users")?;
+        bucket.set("key1".to_owned(), "user1".to_owned())?;
+        bucket.rename("key1".to_owned(), "key2".to_owned())?;
+        assert_eq!(bucket.get("key2".to_owned())?, Some("user1".to_owned()));
+        assert_eq!(engine.get("key2".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    // The rename is one record, so it survives reopening whole.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::new(sled::open(temp_dir.path())?))?;
+    Ok(())
+}
+
+// Should hand a lock to one owner at a time, on every engine, until it's released or expires
+#[test]
+fn locks() -> Result<()> {
+    fn check(engine: impl KvsEngine) -> Result<()> {
+        let ttl = Duration::from_secs(60);
+        assert!(engine.acquire_lock("job".to_owned(), "worker1".to_owned(), ttl)?);
+        assert!(!engine.acquire_lock("job".to_owned(), "worker2".to_owned(), ttl)?);
+        // The owner takes it again to extend it.
+        assert!(engine.acquire_lock("job".to_owned(), "worker1".to_owned(), ttl)?);
+        assert!(!engine.release_lock("job".to_owned(), "worker2".to_owned())?);
+        assert!(engine.release_lock("job".to_owned(), "worker1".to_owned())?);
+        assert!(!engine.contains("job".to_owned())?);
+        assert!(!engine.release_lock("job".to_owned(), "worker1".to_owned())?);
+        assert!(engine.acquire_lock("job".to_owned(), "worker2".to_owned(), ttl)?);
+
+        // A lock whose owner never released it is free once it expires.
+        let short = Duration::from_millis(50);
+        assert!(engine.acquire_lock("task".to_owned(), "worker1".to_owned(), short)?);
+        assert!(!engine.acquire_lock("task".to_owned(), "worker2".to_owned(), ttl)?);
+        thread::sleep(Duration::from_millis(100));
+        assert!(!engine.release_lock("task".to_owned(), "worker1".to_owned())?);
+        assert!(engine.acquire_lock("task".to_owned(), "worker2".to_owned(), ttl)?);
+
+        engine.set("text".to_owned(), "value".to_owned())?;
+        assert!(matches!(
+            engine.acquire_lock("text".to_owned(), "worker1".to_owned(), ttl),
+            Err(KvsError::NotALock)
+        ));
+        assert!(matches!(
+            engine.release_lock("text".to_owned(), "worker1".to_owned()),
+            Err(KvsError::NotALock)
+        ));
+
+        let bucket = engine.bucket("jobs")?;
+        assert!(bucket.acquire_lock("job".to_owned(), "worker1".to_owned(), ttl)?);
+        assert!(bucket.release_lock("job".to_owned(), "worker1".to_owned())?);
+
+        // Of many owners racing for a free lock, one takes it.
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let engine = engine.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    engine
+                        .acquire_lock("race".to_owned(), format!("worker{}", i), ttl)
+                        .unwrap()
+                })
+            })
+            .collect();
+        let taken = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|&taken| taken)
+            .count();
+        assert_eq!(taken, 1);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    // Locks are values, so they're still held once the store is opened again.
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = Duration::from_secs(60);
+    assert!(!store.acquire_lock("job".to_owned(), "worker1".to_owned(), ttl)?);
+    assert!(store.acquire_lock("job".to_owned(), "worker2".to_owned(), ttl)?);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::new(sled::open(temp_dir.path())?))?;
+    Ok(())
+}
+
+// Should append to values and read parts of them, on every engine
+#[test]
+fn append_and_get_range() -> Result<()> {
+    fn check(engine: impl KvsEngine + Sync) -> Result<()> {
+        assert_eq!(engine.append("events".to_owned(), "a".to_owned())?, 1);
+        assert_eq!(engine.append("events".to_owned(), "bc".to_owned())?, 3);
+        assert_eq!(engine.get("events".to_owned())?, Some("abc".to_owned()));
+
+        assert_eq!(
+            engine.get_range("events".to_owned(), 1, 1)?,
+            Some("b".to_owned())
+        );
+        assert_eq!(
+            engine.get_range("events".to_owned(), 1, 10)?,
+            Some("bc".to_owned())
+        );
+        assert_eq!(
+            engine.get_range("events".to_owned(), 5, 1)?,
+            Some(String::new())
+        );
+        assert_eq!(engine.get_range("missing".to_owned(), 0, 1)?, None);
+        engine.set("text".to_owned(), "é".to_owned())?;
+        assert!(engine.get_range("text".to_owned(), 0, 1).is_err());
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        engine.append("shared".to_owned(), "x".to_owned()).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(engine.get("shared".to_owned())?.map(|v| v.len()), Some(200));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let store = KvStore::open(temp_dir.path())?.with_size_limits(SizeLimits {
+        max_key_bytes: None,
+        max_value_bytes: Some(3),
+    });
+    assert!(matches!(
+        store.append("events".to_owned(), "d".to_owned()),
+        Err(KvsError::TooLarge(_))
+    ));
+    assert_eq!(store.get("events".to_owned())?, Some("abc".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::new(sled::open(temp_dir.path())?))?;
+    Ok(())
+}
+
+// Should find the keys set to a value, on every engine, and keep a kvs value index across
+// writes, compactions and opens
+#[test]
+fn find_by_value() -> Result<()> {
+    fn check(engine: impl KvsEngine) -> Result<()> {
+        engine.set("key1".to_owned(), "red".to_owned())?;
+        engine.set_batch(vec![
+            ("key2".to_owned(), "blue".to_owned()),
+            ("key3".to_owned(), "red".to_owned()),
+        ])?;
+        engine.set("key4".to_owned(), "blue".to_owned())?;
+        engine.rename("key4".to_owned(), "key5".to_owned())?;
+        engine.set("key2".to_owned(), "red".to_owned())?;
+        engine.remove("key1".to_owned())?;
+
+        assert_eq!(engine.find_by_value("red")?, ["key2", "key3"]);
+        assert_eq!(engine.find_by_value("blue")?, ["key5"]);
+        assert!(engine.find_by_value("green")?.is_empty());
+        Ok(())
+    }
+
+    // Without an index, the pairs are scanned.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::new(sled::open(temp_dir.path())?))?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "red".to_owned())?;
+    assert!(!store.has_value_index());
+    store.create_value_index()?;
+    assert!(store.has_value_index());
+    assert!(temp_dir.path().join("kvs.values").exists());
+    store.remove("key0".to_owned())?;
+    check(store.clone())?;
+    let mut txn = store.transaction()?;
+    txn.set("key6".to_owned(), "red".to_owned());
+    txn.remove("key3".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.find_by_value("red")?, ["key2", "key6"]);
+    let bucket = store.bucket("paint")?;
+    bucket.set("key1".to_owned(), "red".to_owned())?;
+    assert_eq!(bucket.find_by_value("red")?, ["key1"]);
+    bucket.remove("key1".to_owned())?;
+    drop(bucket);
+
+    // Reopened, the index is kept, with the writes since the checkpoint read from the logs.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.has_value_index());
+    assert_eq!(store.find_by_value("red")?, ["key2", "key6"]);
+    store.compact()?;
+    store.set("key7".to_owned(), "blue".to_owned())?;
+    drop(store);
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.find_by_value("blue")?, ["key5", "key7"]);
+    drop(store);
+
+    // A damaged checkpoint is rebuilt from the logs.
+    fs::write(temp_dir.path().join("kvs.values"), b"not a checkpoint")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.find_by_value("red")?, ["key2", "key6"]);
+    assert_eq!(store.find_by_value("blue")?, ["key5", "key7"]);
+    drop(store);
+
+    // Without the checkpoint, the store doesn't keep the index.
+    fs::remove_file(temp_dir.path().join("kvs.values"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.has_value_index());
+    assert_eq!(store.find_by_value("red")?, ["key2", "key6"]);
+    Ok(())
+}
+
+// Should refuse writes of keys and values over the limits, writing none of a batch
+#[test]
+fn size_limits() -> Result<()> {
+    fn check<E: KvsEngine>(engine: E) -> Result<()> {
+        let too_large = |result: Result<_>| matches!(result, Err(KvsError::TooLarge(_)));
+        assert!(too_large(engine.set("k".repeat(9), "value".to_owned())));
+        assert!(too_large(engine.set("key1".to_owned(), "v".repeat(17))));
+        assert!(too_large(engine.set_batch(vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "v".repeat(17)),
+        ])));
+        assert!(too_large(engine.incr("k".repeat(9), 1).map(drop)));
+        assert_eq!(engine.len()?, 0);
+
+        engine.set("k".repeat(8), "v".repeat(16))?;
+        assert_eq!(engine.get("k".repeat(8))?, Some("v".repeat(16)));
+        Ok(())
+    }
+    let limits = SizeLimits {
+        max_key_bytes: Some(8),
+        max_value_bytes: Some(16),
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_size_limits(limits);
+    check(store.clone())?;
+    let mut txn = store.transaction()?;
+    txn.set("key2".to_owned(), "v".repeat(17));
+    assert!(matches!(txn.commit(), Err(KvsError::TooLarge(_))));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::new(sled::open(temp_dir.path())?).with_size_limits(limits))
+}
+
+// Should keep the keys of each bucket apart from those of others
+#[test]
+fn buckets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.bucket("users")?;
+    let orders = store.bucket("orders")?;
+
+    users.set("key1".to_owned(), "user1".to_owned())?;
+    orders.set_batch(vec![
+        ("key1".to_owned(), "order1".to_owned()),
+        ("key2".to_owned(), "order2".to_owned()),
+    ])?;
+    assert_eq!(users.get("key1".to_owned())?, Some("user1".to_owned()));
+    assert_eq!(orders.get("key1".to_owned())?, Some("order1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(users.keys()?, ["key1"]);
+    assert_eq!(orders.len()?, 2);
+
+    users.remove("key1".to_owned())?;
+    assert!(matches!(
+        users.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(orders.contains("key1".to_owned())?);
+    assert!(store.bucket("").is_err());
+    assert!(store.bucket("a\0b").is_err());
+
+    // Open from disk again and check persistent data
+    drop((store, users, orders));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.bucket("orders")?.get("key2".to_owned())?,
+        Some("order2".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should share syncs between concurrent writers, through compactions, and keep every write
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open(temp_dir.path())?.with_group_commit(Duration::from_millis(1), 4096)?;
+
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                // Enough overwrites to trigger a compaction partway through.
+                for iter in 0..200 {
+                    for key_id in 0..10 {
+                        let key = format!("key{}-{}", thread_id, key_id);
+                        store.set(key, format!("{:0>1000}", iter)).unwrap();
+                    }
+                }
+                store.remove(format!("key{}-0", thread_id)).unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(store.compactions() > 0);
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..8 {
+            assert_eq!(store.get(format!("key{}-0", thread_id))?, None);
+            for key_id in 1..10 {
+                let key = format!("key{}-{}", thread_id, key_id);
+                assert_eq!(store.get(key)?, Some(format!("{:0>1000}", 199)));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// Writes should count as unsynced until a background sync or a group commit syncs them
+#[test]
+fn background_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let unsynced = store.unsynced_bytes();
+    assert!(unsynced > 0);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.unsynced_bytes() > unsynced);
+    assert_eq!(store.stats()?.unsynced_bytes, store.unsynced_bytes());
+
+    let store = store.with_background_sync(Duration::from_millis(10))?;
+    let synced = |store: &KvStore| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while store.unsynced_bytes() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        store.unsynced_bytes() == 0
+    };
+    assert!(synced(&store));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(synced(&store));
+    // Compaction syncs the log it writes.
+    store.compact()?;
+    assert_eq!(store.unsynced_bytes(), 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    let store = store.with_group_commit(Duration::from_millis(1), 4096)?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.unsynced_bytes(), 0);
+    Ok(())
+}
+
+// Writes should stall once the dead bytes pass the limit, as when compaction keeps failing,
+// and go on once compaction catches up
+#[test]
+fn write_stall() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Compactions fail while the filters of the logs they write can't be written.
+    let blocked: Vec<_> = (1..200)
+        .map(|log_number| temp_dir.path().join(format!("{}.kvs.bloom", log_number)))
+        .collect();
+    for dir in &blocked {
+        fs::create_dir(dir)?;
+    }
+    let store = KvStore::open(temp_dir.path())?
+        .with_compaction_threshold(1000)
+        .with_write_stall(4000, WriteStall::Refuse);
+    let value = "x".repeat(100);
+    let mut refused = None;
+    for _ in 0..100 {
+        match store.set("key1".to_owned(), value.clone()) {
+            // The write that sets off a failed compaction gets its error.
+            Ok(()) | Err(KvsError::Context(_)) => {}
+            Err(KvsError::WriteStalled { dead_bytes, limit }) => {
+                refused = Some((dead_bytes, limit));
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    let (dead_bytes, limit) = refused.expect("writes were never stalled");
+    assert!(dead_bytes > limit);
+    assert_eq!(limit, 4000);
+    let state = store.compaction_state();
+    assert!(state.stalled);
+    assert_eq!(state.stalled_writes, 1);
+
+    // Delayed rather than refused, a write goes ahead after sleeping.
+    let delayed = store
+        .clone()
+        .with_write_stall(4000, WriteStall::Delay(Duration::from_millis(50)));
+    let start = Instant::now();
+    assert!(!matches!(
+        delayed.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::WriteStalled { .. })
+    ));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(store.compaction_state().stalled_writes, 2);
+
+    // Once compaction can go through, the next write compacts and goes ahead.
+    for dir in &blocked {
+        fs::remove_dir(dir)?;
+    }
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!store.compaction_state().stalled);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A sled engine that leaves flushing to the background should still have its writes
+// once it's reopened.
+#[test]
+fn sled_background_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || {
+        sled::Config::new()
+            .path(temp_dir.path())
+            .cache_capacity(1024 * 1024)
+            .flush_every_ms(Some(10))
+    };
+    let engine = SledKvsEngine::with_config(config(), Durability::Background)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    drop(engine);
+
+    let engine = SledKvsEngine::with_config(config(), Durability::EveryWrite)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    assert_eq!("background".parse::<Durability>()?, Durability::Background);
+    assert!("sometimes".parse::<Durability>().is_err());
+    Ok(())
+}
+
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_compaction_threshold(1024);
+    for iter in 0..10 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert!(store.compactions() > 0);
+    assert_eq!(store.get("key7".to_owned())?, Some("9".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_compaction_threshold(u64::MAX);
+    for iter in 0..100 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert_eq!(store.compactions(), 0);
+    Ok(())
+}
+
+// The compaction state should count the bytes overwritten and removed values hold, and
+// what the last compaction rewrote.
+#[test]
+fn compaction_state() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.compaction_state(), CompactionState::default());
+
+    for iter in 0..5 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let state = store.compaction_state();
+    assert!(!state.running);
+    assert!(state.dead_bytes > state.tombstone_bytes);
+    assert_eq!(state.tombstones, 1);
+    assert!(state.tombstone_bytes > 0);
+
+    store.compact()?;
+    let state = store.compaction_state();
+    assert!(!state.running);
+    assert!(state.bytes_processed > 0);
+    assert_eq!(state.bytes_remaining, 0);
+    assert_eq!(state.dead_bytes, 0);
+    assert_eq!((state.tombstones, state.tombstone_bytes), (0, 0));
+    Ok(())
+}
+
+// Each engine should report its gauges under the same names, leaving out those it has no
+// notion of.
+#[test]
+fn engine_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..5 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    let gauges = store.engine_metrics()?;
+    assert_eq!(gauges.disk_bytes, store.size_on_disk()?);
+    let logs = fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_string_lossy().ends_with(".kvs.log")
+        })
+        .count();
+    assert_eq!(gauges.segments, Some(logs as u64));
+    assert_eq!(gauges.dead_bytes, Some(store.compaction_state().dead_bytes));
+    assert!(gauges.dead_bytes > Some(0));
+    assert_eq!(gauges.cache_hits, None);
+    store.compact()?;
+    assert_eq!(store.engine_metrics()?.dead_bytes, Some(0));
+
+    // Buckets and boxed engines report the whole engine's.
+    let bucket = store.bucket("bucket1")?;
+    assert_eq!(bucket.engine_metrics()?, store.engine_metrics()?);
+    let boxed = BoxedEngine::new(store.clone());
+    assert_eq!(boxed.engine_metrics()?, store.engine_metrics()?);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let gauges = engine.engine_metrics()?;
+    assert!(gauges.disk_bytes > 0);
+    assert_eq!(
+        (gauges.segments, gauges.dead_bytes, gauges.cache_hits),
+        (None, None, None)
+    );
+    Ok(())
+}
+
+// Removes should bring on a compaction once there are enough of them, or the oldest is old
+// enough, however far the store is from its compaction threshold.
+#[test]
+fn tombstone_horizon() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?
+        .with_compaction_threshold(u64::MAX)
+        .with_tombstone_horizon(TombstoneHorizon {
+            max_age: None,
+            max_count: Some(3),
+        });
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.compactions(), 0);
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.compactions(), 1);
+    assert_eq!(store.compaction_state().tombstones, 0);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?
+        .with_compaction_threshold(u64::MAX)
+        .with_tombstone_horizon(TombstoneHorizon {
+            max_age: Some(Duration::from_millis(50)),
+            max_count: None,
+        });
+    store.set("key1".to_owned(), "value".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    assert_eq!(store.compactions(), 0);
+    thread::sleep(Duration::from_millis(100));
+    store.set("key3".to_owned(), "value".to_owned())?;
+    assert_eq!(store.compactions(), 1);
+    assert_eq!(store.history("key1")?, []);
+    Ok(())
+}
+
+// Compacting on demand should keep every live value and survive a reopen.
+#[test]
+fn compact_on_demand() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..5 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let size = store.size_on_disk()?;
+
+    store.compact()?;
+    assert_eq!(store.compactions(), 1);
+    assert!(store.size_on_disk()? < size);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 9);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key9".to_owned())?, Some("4".to_owned()));
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(sled_dir.path())?);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.compact()?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A dump of one engine should restore into either engine, and damage should be caught.
+#[test]
+fn export_import() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    for i in 0..2500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key7".to_owned())?;
+    let mut dump = Vec::new();
+    assert_eq!(store.export(&mut dump)?, 2499);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(sled_dir.path())?);
+    assert_eq!(engine.import(dump.as_slice())?, 2499);
+    assert_eq!(engine.len()?, 2499);
+    assert_eq!(engine.get("key7".to_owned())?, None);
+    assert_eq!(engine.get("key42".to_owned())?, Some("value42".to_owned()));
+
+    // Both engines dump the same pairs in the same order.
+    let mut sled_dump = Vec::new();
+    engine.export(&mut sled_dump)?;
+    assert_eq!(sled_dump, dump);
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path())?;
+    assert!(other.import(&dump[..dump.len() - 1]).is_err());
+    let mut damaged = dump.clone();
+    damaged[40] ^= 0xff;
+    assert!(other.import(damaged.as_slice()).is_err());
+    assert!(other.import(&b"not a dump at all"[..]).is_err());
+
+    let empty_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut empty_dump = Vec::new();
+    assert_eq!(KvStore::open(empty_dir.path())?.export(&mut empty_dump)?, 0);
+    assert_eq!(other.import(empty_dump.as_slice())?, 0);
+    Ok(())
+}
+
+// RocksDB should answer as the other engines do, scans and dumps included.
+#[cfg(feature = "rocksdb")]
+#[test]
+fn rocksdb_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(&kvs::RocksKvsEngine::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = kvs::RocksKvsEngine::open(temp_dir.path())?;
+    check_keys_after(&engine)?;
+    // The buckets' keys are the engine's too.
+    assert_eq!(engine.len()?, 8);
+    assert!(matches!(
+        engine.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.incr("counter".to_owned(), 2)?, 2);
+    assert_eq!(engine.incr("counter".to_owned(), -5)?, -3);
+    assert_eq!(
+        engine.get_many(vec!["counter".to_owned(), "missing".to_owned()])?,
+        vec![Some("-3".to_owned()), None]
+    );
+
+    let mut dump = Vec::new();
+    assert_eq!(engine.export(&mut dump)?, 9);
+    drop(engine);
+    let engine = kvs::RocksKvsEngine::open(temp_dir.path())?;
+    engine.compact()?;
+    assert_eq!(engine.get("counter".to_owned())?, Some("-3".to_owned()));
+    let store_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(KvStore::open(store_dir.path())?.import(dump.as_slice())?, 9);
+    Ok(())
+}
+
+// A registered engine should open by its name, which no other engine may take.
+#[test]
+fn registered_engines() -> Result<()> {
+    kvs::register_engine("registered-kvs", |dir| {
+        Ok(BoxedEngine::new(KvStore::open(dir)?))
+    })?;
+    assert!(kvs::register_engine("registered-kvs", |dir| {
+        Ok(BoxedEngine::new(KvStore::open(dir)?))
+    })
+    .is_err());
+    assert!(kvs::register_engine("sled", |dir| {
+        Ok(BoxedEngine::new(SledKvsEngine::new(sled::open(dir)?)))
+    })
+    .is_err());
+    assert!(kvs::registered_engines().contains(&"registered-kvs".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = kvs::open_registered("registered-kvs", temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(kvs::open_registered("unregistered", temp_dir.path()).is_err());
+    Ok(())
+}
+
+// Engines chosen at run time should behave as they do unboxed, down to what only one
+// of them supports.
+#[test]
+fn boxed_engines() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines = [
+        BoxedEngine::new(KvStore::open(kvs_dir.path())?),
+        BoxedEngine::new(SledKvsEngine::new(sled::open(sled_dir.path())?)),
+    ];
+    let mut dumps = Vec::new();
+    for engine in &engines {
+        check_scans(engine)?;
+        assert_eq!(engine.incr("counter".to_owned(), 3)?, 3);
+        let mut dump = Vec::new();
+        let pairs = engine.export(&mut dump)?;
+        assert_eq!(pairs, engine.len()?);
+        dumps.push(dump);
+    }
+    assert_eq!(dumps[0], dumps[1]);
+    assert_eq!(engines[0].name(), "kvs");
+    assert_eq!(engines[1].name(), "sled");
+
+    assert!(engines[0].transaction().is_ok());
+    assert!(engines[1].transaction().is_err());
+    assert_eq!(engines[0].metadata("counter".to_owned())?.unwrap().size, 1);
+    assert!(engines[1].metadata("counter".to_owned()).is_err());
+
+    let copy_dir = TempDir::new().expect("unable to create temporary working directory");
+    let copy = BoxedEngine::new(KvStore::open(copy_dir.path())?);
+    assert_eq!(copy.import(dumps[0].as_slice())?, engines[0].len()?);
+    assert_eq!(copy.get("counter".to_owned())?, Some("3".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use kvs::log_file::{LogFile, Rotation};
+use std::fs;
+use std::io::{Result, Write};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A file about to grow past its limit should be rotated between lines, keeping only as many
+// rotated files as asked.
+#[test]
+fn rotate_by_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("server.log");
+    let rotation = Rotation {
+        max_bytes: Some(20),
+        max_age: None,
+        keep: 2,
+    };
+    let mut log = LogFile::open(&path, rotation)?;
+    for i in 0..4 {
+        // A line written in pieces stays in one file.
+        write!(log, "line {} ", i)?;
+        writeln!(log, "of the log")?;
+    }
+    log.flush()?;
+
+    assert_eq!(fs::read_to_string(&path)?, "line 3 of the log\n");
+    assert_eq!(
+        fs::read_to_string(log.rotated_path(1))?,
+        "line 2 of the log\n"
+    );
+    assert_eq!(
+        fs::read_to_string(log.rotated_path(2))?,
+        "line 1 of the log\n"
+    );
+    assert!(!log.rotated_path(3).exists());
+    Ok(())
+}
+
+#[test]
+fn rotate_by_age() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("server.log");
+    let rotation = Rotation {
+        max_bytes: None,
+        max_age: Some(Duration::from_millis(50)),
+        keep: 1,
+    };
+    let mut log = LogFile::open(&path, rotation)?;
+    writeln!(log, "first")?;
+    writeln!(log, "second")?;
+    thread::sleep(Duration::from_millis(100));
+    writeln!(log, "third")?;
+    log.flush()?;
+
+    assert_eq!(fs::read_to_string(&path)?, "third\n");
+    assert_eq!(fs::read_to_string(log.rotated_path(1))?, "first\nsecond\n");
+    Ok(())
+}
+
+// Without limits the file is only ever appended to, including what it held before.
+#[test]
+fn append_without_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("server.log");
+    fs::write(&path, "before\n")?;
+    let mut log = LogFile::open(&path, Rotation::default())?;
+    for _ in 0..100 {
+        writeln!(log, "after")?;
+    }
+    log.flush()?;
+
+    assert_eq!(fs::read_to_string(&path)?.lines().count(), 101);
+    assert!(!log.rotated_path(1).exists());
+    Ok(())
+}
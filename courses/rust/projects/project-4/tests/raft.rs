@@ -0,0 +1,175 @@
+use kvs::raft::{Peer, RaftConfig};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, ServerHandle};
+use slog::{o, Discard, Logger};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+struct Member {
+    dir: TempDir,
+    engine: KvStore,
+    peers: Vec<Peer>,
+    id: u64,
+    client_addr: SocketAddr,
+    server: Option<ServerHandle>,
+}
+
+impl Member {
+    // Start the member's server, again if it was stopped.
+    fn start(&mut self) {
+        let me = &self.peers[self.id as usize];
+        let others = self.peers.iter().filter(|peer| peer.id != me.id).cloned();
+        let config = RaftConfig::new(
+            me.id,
+            me.raft_addr,
+            others.collect(),
+            self.dir.path().join("raft"),
+        );
+        let (server, client_addr) =
+            KvsServer::new(self.engine.clone(), Logger::root(Discard, o!()))
+                .with_raft(config)
+                .spawn(&me.client_addr)
+                .unwrap();
+        self.client_addr = client_addr;
+        self.server = Some(server);
+    }
+}
+
+// Start a group of three servers, with client ports from `base_port` and Raft ports after them.
+fn start_group(base_port: u16) -> Vec<Member> {
+    let addr = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+    let peers: Vec<Peer> = (0..3)
+        .map(|i| Peer {
+            id: i,
+            raft_addr: addr(base_port + 3 + i as u16),
+            client_addr: addr(base_port + i as u16),
+        })
+        .collect();
+    peers
+        .iter()
+        .map(|me| {
+            let dir = TempDir::new().unwrap();
+            let engine = KvStore::open(dir.path()).unwrap();
+            let mut member = Member {
+                dir,
+                engine,
+                peers: peers.clone(),
+                id: me.id,
+                client_addr: me.client_addr,
+                server: None,
+            };
+            member.start();
+            member
+        })
+        .collect()
+}
+
+// Wait until one of the running members reports itself as leader, and return its position.
+fn wait_for_leader(group: &[Member]) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        for (i, member) in group.iter().enumerate() {
+            if member.server.is_none() {
+                continue;
+            }
+            let stats = KvsClient::connect(&member.client_addr)
+                .and_then(|mut client| client.stats())
+                .unwrap();
+            let raft = stats.raft.unwrap();
+            if raft.leader == Some(raft.id) {
+                return i;
+            }
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("no leader was elected");
+}
+
+fn wait_for_value(engine: &KvStore, key: &str, value: Option<&str>) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while engine.get(key.to_owned()).unwrap().as_deref() != value {
+        assert!(
+            Instant::now() < deadline,
+            "{} never became {:?}",
+            key,
+            value
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Writes sent to any member should be redirected to the leader and applied on every member.
+#[test]
+fn replication() {
+    let group = start_group(4020);
+    let mut client = KvsClient::connect(&group[0].client_addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.remove("key2".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(client.remove("key2".to_owned()).is_err());
+
+    for member in &group {
+        wait_for_value(&member.engine, "key1", Some("value1"));
+        wait_for_value(&member.engine, "key2", None);
+    }
+}
+
+// When the leader stops, the others should elect a new one that has every committed write.
+#[test]
+fn leader_failover() {
+    let mut group = start_group(4030);
+    let leader = wait_for_leader(&group);
+    let mut client = KvsClient::connect(&group[leader].client_addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    group[leader].server.take().unwrap().shutdown().unwrap();
+    let survivor = (leader + 1) % group.len();
+    let mut client = KvsClient::connect(&group[survivor].client_addr).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    let new_leader = wait_for_leader(&group);
+    assert_ne!(new_leader, leader);
+    for (i, member) in group.iter().enumerate() {
+        if i != leader {
+            wait_for_value(&member.engine, "key2", Some("value2"));
+        }
+    }
+}
+
+// A member that restarts applies only the entries it hadn't applied before it stopped.
+#[test]
+fn restart_skips_applied_entries() {
+    let mut group = start_group(4080);
+    let leader = wait_for_leader(&group);
+    let mut client = KvsClient::connect(&group[leader].client_addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let follower = &mut group[(leader + 1) % 3];
+    wait_for_value(&follower.engine, "key1", Some("value1"));
+    follower.server.take().unwrap().shutdown().unwrap();
+    // Give the follower a moment to save how much it applied.
+    thread::sleep(Duration::from_millis(100));
+    // A change the log doesn't hold, which applying the log again would undo.
+    follower
+        .engine
+        .set("key1".to_owned(), "local".to_owned())
+        .unwrap();
+    follower.start();
+
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    let follower = &group[(leader + 1) % 3];
+    wait_for_value(&follower.engine, "key2", Some("value2"));
+    assert_eq!(
+        follower.engine.get("key1".to_owned()).unwrap(),
+        Some("local".to_owned())
+    );
+}
@@ -0,0 +1,177 @@
+use kvs::fault::FaultyWriter;
+use kvs::{CorruptionKind, KvStore, KvsEngine, KvsError, Result};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::sample::Index;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use tempfile::TempDir;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+    // Sets, or removes for `None`, committed together as a transaction.
+    Commit(Vec<(String, Option<String>)>),
+}
+
+// Few keys, so operations often overwrite and remove what earlier ones set.
+fn key() -> impl Strategy<Value = String> {
+    (0..8u8).prop_map(|n| format!("key{}", n))
+}
+
+fn value() -> impl Strategy<Value = String> {
+    "[a-z0-9]{0,24}"
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (key(), value()).prop_map(|(key, value)| Op::Set(key, value)),
+        2 => key().prop_map(Op::Remove),
+        1 => vec((key(), proptest::option::of(value())), 1..5).prop_map(Op::Commit),
+    ]
+}
+
+// Apply `op` to the store and to the model of what it should hold.
+fn apply(store: &KvStore, model: &mut HashMap<String, String>, op: Op) -> Result<()> {
+    match op {
+        Op::Set(key, value) => {
+            store.set(key.clone(), value.clone())?;
+            model.insert(key, value);
+        }
+        Op::Remove(key) => match store.remove(key.clone()) {
+            Err(KvsError::KeyNotFound) => assert!(!model.contains_key(&key)),
+            result => {
+                result?;
+                model.remove(&key).expect("removed a key the model lacks");
+            }
+        },
+        Op::Commit(writes) => {
+            let mut txn = store.transaction()?;
+            let mut after = model.clone();
+            for (key, value) in writes {
+                match value {
+                    Some(value) => {
+                        txn.set(key.clone(), value.clone());
+                        after.insert(key, value);
+                    }
+                    None if after.contains_key(&key) => {
+                        txn.remove(key.clone())?;
+                        after.remove(&key);
+                    }
+                    None => {}
+                }
+            }
+            txn.commit()?;
+            *model = after;
+        }
+    }
+    Ok(())
+}
+
+fn assert_matches(store: &KvStore, model: &HashMap<String, String>) -> Result<()> {
+    let mut keys: Vec<_> = model.keys().cloned().collect();
+    keys.sort();
+    assert_eq!(store.keys()?, keys);
+    for (key, value) in model {
+        assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+    }
+    Ok(())
+}
+
+// Copy the log in `from` to `to` as a process killed after writing `budget` bytes of it
+// would have left it.
+fn crash_copy(from: &Path, to: &Path, budget: u64) -> Result<()> {
+    let mut log = File::open(from.join("0.kvs.log"))?;
+    let mut writer = FaultyWriter::new(File::create(to.join("0.kvs.log"))?, budget);
+    if let Err(err) = io::copy(&mut log, &mut writer) {
+        assert_eq!(writer.budget(), 0, "copy failed before the fault: {}", err);
+    }
+    Ok(())
+}
+
+// Run `ops`, then reopen the store both cleanly and as if the process had died partway
+// through writing the log. A crash at the end of a record must recover every write up to
+// it; one in the middle of a record must be reported as a truncated log at that record,
+// and cut off there by a lenient open.
+fn check_recovery(ops: Vec<Op>, crash: Index) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Compaction would start new logs; keep everything in one.
+    let store = KvStore::open(temp_dir.path())?.with_compaction_threshold(u64::MAX);
+    let log = temp_dir.path().join("0.kvs.log");
+
+    // The length of the log after each operation, with what the store held then.
+    let mut model = HashMap::new();
+    // A crash before the new log's header is whole leaves it empty or truncated.
+    let mut checkpoints = vec![
+        (0, model.clone()),
+        (fs::metadata(&log)?.len(), model.clone()),
+    ];
+    for op in ops {
+        apply(&store, &mut model, op)?;
+        let len = fs::metadata(&log)?.len();
+        if len > checkpoints.last().unwrap().0 {
+            checkpoints.push((len, model.clone()));
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_matches(&store, &model)?;
+    drop(store);
+
+    let (len, _) = checkpoints.last().unwrap();
+    let budget = crash.index(*len as usize + 1) as u64;
+    let crashed_dir = TempDir::new().expect("unable to create temporary working directory");
+    crash_copy(temp_dir.path(), crashed_dir.path(), budget)?;
+
+    let (offset, expected) = checkpoints
+        .iter()
+        .rev()
+        .find(|(len, _)| *len <= budget)
+        .unwrap();
+    if *offset == budget {
+        KvStore::verify(crashed_dir.path())?;
+        let store = KvStore::open(crashed_dir.path())?;
+        assert_matches(&store, expected)?;
+    } else {
+        match KvStore::verify(crashed_dir.path()) {
+            Err(KvsError::Corruption {
+                log_number,
+                offset: at,
+                kind,
+            }) => {
+                assert_eq!(log_number, 0);
+                assert_eq!(at, *offset);
+                assert_eq!(kind, CorruptionKind::Truncated);
+            }
+            other => panic!("expected a truncated log, got {:?}", other),
+        }
+        assert!(KvStore::open_strict(crashed_dir.path()).is_err());
+        let store = KvStore::open(crashed_dir.path())?;
+        assert_eq!(store.torn_tail().map(|tail| tail.offset), Some(*offset));
+        assert_matches(&store, expected)?;
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn recovery_matches_model(ops in vec(op(), 1..40), crash in any::<Index>()) {
+        check_recovery(ops, crash).unwrap();
+    }
+}
+
+// A write torn by the fault lands partially, and nothing after it lands.
+#[test]
+fn faulty_writer_tears_writes() {
+    let mut writer = FaultyWriter::new(Vec::new(), 5);
+    io::Write::write_all(&mut writer, b"abc").unwrap();
+    assert!(io::Write::write_all(&mut writer, b"defg").is_err());
+    assert!(io::Write::write_all(&mut writer, b"h").is_err());
+    assert_eq!(writer.into_inner(), b"abcde");
+}
@@ -0,0 +1,245 @@
+use kvs::acl::{Acl, Grant, Role};
+use kvs::{ChangeOp, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, ServerStats};
+use slog::{o, Discard, Logger};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn local(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn wait_for_value(engine: &KvStore, key: &str, value: Option<&str>) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while engine.get(key.to_owned()).unwrap().as_deref() != value {
+        assert!(
+            Instant::now() < deadline,
+            "{} never became {:?}",
+            key,
+            value
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Wait until the stats of the server at `addr` pass `done`, and return them.
+fn wait_for_stats(addr: SocketAddr, done: impl Fn(&ServerStats) -> bool) -> ServerStats {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = KvsClient::connect(&addr).unwrap().stats().unwrap();
+        if done(&stats) {
+            return stats;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "stats never got there: {:?}",
+            stats
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// A replica is sent the primary's whole store, then every write made to it, however it was
+// made, and serves reads but refuses writes.
+#[test]
+fn replica_follows_primary() {
+    let replication_addr = local(4060);
+    let primary_dir = TempDir::new().unwrap();
+    let primary_engine = KvStore::open(primary_dir.path()).unwrap();
+    primary_engine
+        .set("before".to_owned(), "value0".to_owned())
+        .unwrap();
+    let (_primary, primary_addr) =
+        KvsServer::new(primary_engine.clone(), Logger::root(Discard, o!()))
+            .with_replication_addr(replication_addr)
+            .spawn(&local(0))
+            .unwrap();
+
+    // Keys the replica held that the primary doesn't are dropped as it catches up.
+    let replica_dir = TempDir::new().unwrap();
+    let replica_engine = KvStore::open(replica_dir.path()).unwrap();
+    replica_engine
+        .set("stale".to_owned(), "value".to_owned())
+        .unwrap();
+    let (_replica, replica_addr) =
+        KvsServer::new(replica_engine.clone(), Logger::root(Discard, o!()))
+            .with_primary(replication_addr)
+            .spawn(&local(0))
+            .unwrap();
+    wait_for_value(&replica_engine, "before", Some("value0"));
+    wait_for_value(&replica_engine, "stale", None);
+
+    let mut subscription = KvsClient::connect(&replica_addr)
+        .unwrap()
+        .subscribe("key")
+        .unwrap();
+    let mut client = KvsClient::connect(&primary_addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.incr("count".to_owned(), 5).unwrap();
+    client.append("key1".to_owned(), "!".to_owned()).unwrap();
+    client
+        .rename("before".to_owned(), "after".to_owned())
+        .unwrap();
+    wait_for_value(&replica_engine, "key1", Some("value1!"));
+    wait_for_value(&replica_engine, "count", Some("5"));
+    wait_for_value(&replica_engine, "before", None);
+    wait_for_value(&replica_engine, "after", Some("value0"));
+
+    // Watchers of the replica see the changes it applies.
+    let change = subscription.recv().unwrap();
+    assert_eq!(change.key, "key1");
+    assert_eq!(change.op, ChangeOp::Set);
+
+    let mut replica_client = KvsClient::connect(&replica_addr).unwrap();
+    assert_eq!(
+        replica_client.get("key1".to_owned()).unwrap(),
+        Some("value1!".to_owned())
+    );
+    assert!(matches!(
+        replica_client.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        replica_client.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert_eq!(replica_engine.get("key2".to_owned()).unwrap(), None);
+
+    client.remove("key1".to_owned()).unwrap();
+    wait_for_value(&replica_engine, "key1", None);
+
+    let stats = wait_for_stats(primary_addr, |stats| {
+        stats.replicas.as_ref().is_some_and(|replicas| {
+            replicas.len() == 1 && replicas[0].lag == 0 && replicas[0].position > 0
+        })
+    });
+    assert!(stats.following.is_none());
+    assert!(stats.to_prometheus().contains("kvs_replicas 1"));
+    let stats = wait_for_stats(replica_addr, |stats| {
+        stats
+            .following
+            .as_ref()
+            .is_some_and(|following| following.connected && following.lag == 0)
+    });
+    let following = stats.following.clone().unwrap();
+    assert_eq!(following.primary, replication_addr);
+    assert_eq!(following.resyncs, 1);
+    assert!(stats.replicas.is_none());
+    let metrics = stats.to_prometheus();
+    assert!(metrics.contains("kvs_replication_connected 1"));
+    assert!(metrics.contains("kvs_replication_lag 0"));
+}
+
+// A replica that loses its primary keeps serving reads, and once the primary is back, is
+// sent its whole store again, since the primary's changes are counted anew.
+#[test]
+fn replica_resyncs_after_primary_restarts() {
+    let replication_addr = local(4061);
+    let primary_addr = local(4062);
+    let primary_dir = TempDir::new().unwrap();
+    let primary_engine = KvStore::open(primary_dir.path()).unwrap();
+    let start_primary = || {
+        KvsServer::new(primary_engine.clone(), Logger::root(Discard, o!()))
+            .with_replication_addr(replication_addr)
+            .spawn(&primary_addr)
+            .unwrap()
+            .0
+    };
+    let primary = start_primary();
+
+    let replica_dir = TempDir::new().unwrap();
+    let replica_engine = KvStore::open(replica_dir.path()).unwrap();
+    let (_replica, replica_addr) =
+        KvsServer::new(replica_engine.clone(), Logger::root(Discard, o!()))
+            .with_primary(replication_addr)
+            .spawn(&local(0))
+            .unwrap();
+
+    let mut client = KvsClient::connect(&primary_addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    wait_for_value(&replica_engine, "key1", Some("value1"));
+
+    primary.shutdown().unwrap();
+    wait_for_stats(replica_addr, |stats| {
+        stats
+            .following
+            .as_ref()
+            .is_some_and(|following| !following.connected)
+    });
+    let mut replica_client = KvsClient::connect(&replica_addr).unwrap();
+    assert_eq!(
+        replica_client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // Written while no replica could be told.
+    primary_engine
+        .set("key2".to_owned(), "value2".to_owned())
+        .unwrap();
+    let _primary = start_primary();
+    wait_for_value(&replica_engine, "key2", Some("value2"));
+    let mut client = KvsClient::connect(&primary_addr).unwrap();
+    client.set("key3".to_owned(), "value3".to_owned()).unwrap();
+    wait_for_value(&replica_engine, "key3", Some("value3"));
+    wait_for_stats(replica_addr, |stats| {
+        stats
+            .following
+            .as_ref()
+            .is_some_and(|following| following.connected && following.resyncs == 2)
+    });
+}
+
+// A primary with an ACL ships its changes only to replicas whose token it grants every key.
+#[test]
+fn replica_needs_token_granting_every_key() {
+    let replication_addr = local(4086);
+    let mut tenant = Grant::new(Role::ReadOnly);
+    tenant.key_prefixes = vec!["tenant1/".to_owned()];
+    let acl = Acl::new()
+        .with_token("replica", Grant::new(Role::ReadOnly))
+        .with_token("writer", Grant::new(Role::ReadWrite))
+        .with_token("tenant1", tenant);
+    let primary_dir = TempDir::new().unwrap();
+    let primary_engine = KvStore::open(primary_dir.path()).unwrap();
+    primary_engine
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    let (_primary, primary_addr) =
+        KvsServer::new(primary_engine.clone(), Logger::root(Discard, o!()))
+            .with_replication_addr(replication_addr)
+            .with_acl(acl)
+            .spawn(&local(0))
+            .unwrap();
+
+    let start_replica = |token: Option<&str>| {
+        let dir = TempDir::new().unwrap();
+        let engine = KvStore::open(dir.path()).unwrap();
+        let mut server = KvsServer::new(engine.clone(), Logger::root(Discard, o!()))
+            .with_primary(replication_addr);
+        if let Some(token) = token {
+            server = server.with_replication_token(token.to_owned());
+        }
+        let (handle, addr) = server.spawn(&local(0)).unwrap();
+        (dir, engine, handle, addr)
+    };
+    let (_dir, anonymous, _anonymous_server, _) = start_replica(None);
+    let (_dir, unknown, _unknown_server, _) = start_replica(Some("nobody"));
+    let (_dir, prefixed, _prefixed_server, prefixed_addr) = start_replica(Some("tenant1"));
+    let (_dir, replica, _replica_server, _) = start_replica(Some("replica"));
+    wait_for_value(&replica, "key1", Some("value1"));
+
+    let mut client = KvsClient::builder(primary_addr)
+        .token("writer")
+        .connect()
+        .unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    wait_for_value(&replica, "key2", Some("value2"));
+    assert_eq!(client.stats().unwrap().replicas.unwrap().len(), 1);
+    for engine in [&anonymous, &unknown, &prefixed] {
+        assert_eq!(engine.get("key1".to_owned()).unwrap(), None);
+        assert_eq!(engine.get("key2".to_owned()).unwrap(), None);
+    }
+    let stats = KvsClient::connect(&prefixed_addr).unwrap().stats().unwrap();
+    assert!(!stats.following.unwrap().connected);
+}
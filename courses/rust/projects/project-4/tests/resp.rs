@@ -0,0 +1,140 @@
+use kvs::{KvStore, KvsServer, Protocol, SizeLimits};
+use slog::{o, Discard, Logger};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use tempfile::TempDir;
+
+fn spawn_resp(temp_dir: &TempDir) -> (kvs::ServerHandle, SocketAddr) {
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    KvsServer::new(engine, Logger::root(Discard, o!()))
+        .with_protocol(Protocol::Resp)
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap()
+}
+
+// Send a command as Redis clients do and return the raw reply.
+fn command(stream: &mut BufReader<TcpStream>, args: &[&str]) -> String {
+    let mut request = format!("*{}\r\n", args.len());
+    for arg in args {
+        request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    stream.get_mut().write_all(request.as_bytes()).unwrap();
+    read_reply(stream)
+}
+
+fn read_reply(stream: &mut BufReader<TcpStream>) -> String {
+    let mut reply = String::new();
+    stream.read_line(&mut reply).unwrap();
+    if let Some(len) = reply.strip_prefix('$') {
+        let len: i64 = len.trim_end().parse().unwrap();
+        if len >= 0 {
+            let mut bulk = vec![0; len as usize + 2];
+            stream.read_exact(&mut bulk).unwrap();
+            reply.push_str(&String::from_utf8(bulk).unwrap());
+        }
+    }
+    reply
+}
+
+#[test]
+fn resp_commands() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_resp(&temp_dir);
+    let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+
+    assert_eq!(command(&mut stream, &["PING"]), "+PONG\r\n");
+    assert_eq!(command(&mut stream, &["ping", "hi"]), "$2\r\nhi\r\n");
+    assert_eq!(command(&mut stream, &["GET", "key1"]), "$-1\r\n");
+    assert_eq!(command(&mut stream, &["SET", "key1", "value1"]), "+OK\r\n");
+    assert_eq!(command(&mut stream, &["SET", "key2", ""]), "+OK\r\n");
+    assert_eq!(command(&mut stream, &["GET", "key1"]), "$6\r\nvalue1\r\n");
+    assert_eq!(command(&mut stream, &["GET", "key2"]), "$0\r\n\r\n");
+    assert_eq!(
+        command(&mut stream, &["EXISTS", "key1", "key2", "key3", "key1"]),
+        ":3\r\n"
+    );
+    assert_eq!(command(&mut stream, &["DEL", "key1", "key3"]), ":1\r\n");
+    assert_eq!(command(&mut stream, &["EXISTS", "key1"]), ":0\r\n");
+    assert_eq!(command(&mut stream, &["RENAME", "key2", "key1"]), "+OK\r\n");
+    assert_eq!(command(&mut stream, &["EXISTS", "key1", "key2"]), ":1\r\n");
+    assert_eq!(
+        command(&mut stream, &["RENAME", "key2", "key1"]),
+        "-ERR no such key\r\n"
+    );
+    assert_eq!(command(&mut stream, &["APPEND", "key1", "ab"]), ":2\r\n");
+    assert_eq!(command(&mut stream, &["APPEND", "key1", "c"]), ":3\r\n");
+    assert_eq!(command(&mut stream, &["GET", "key1"]), "$3\r\nabc\r\n");
+
+    assert_eq!(
+        command(&mut stream, &["GET"]),
+        "-ERR wrong number of arguments for 'get' command\r\n"
+    );
+    assert_eq!(
+        command(&mut stream, &["SET", "key1", "value1", "NX"]),
+        "-ERR syntax error\r\n"
+    );
+    assert_eq!(
+        command(&mut stream, &["FLUSHALL"]),
+        "-ERR unknown command 'flushall'\r\n"
+    );
+
+    // Commands typed into a terminal come as lines of words.
+    stream.get_mut().write_all(b"set key3 value3\r\n").unwrap();
+    assert_eq!(read_reply(&mut stream), "+OK\r\n");
+    stream.get_mut().write_all(b"GET key3\n").unwrap();
+    assert_eq!(read_reply(&mut stream), "$6\r\nvalue3\r\n");
+}
+
+// Whatever follows a malformed command can't be parsed, so the server says why and hangs up.
+#[test]
+fn resp_protocol_error() {
+    let temp_dir = TempDir::new().unwrap();
+    let (_server, addr) = spawn_resp(&temp_dir);
+    let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+
+    stream.get_mut().write_all(b"*1\r\n+PING\r\n").unwrap();
+    assert_eq!(
+        read_reply(&mut stream),
+        "-ERR Protocol error: expected '$', got '+'\r\n"
+    );
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "");
+}
+
+// A bulk string longer than any key or value the server takes is refused before it's read,
+// while shorter ones over the limits are refused as other commands are.
+#[test]
+fn resp_bulk_over_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (_server, addr) = KvsServer::new(engine, Logger::root(Discard, o!()))
+        .with_protocol(Protocol::Resp)
+        .with_size_limits(SizeLimits {
+            max_key_bytes: Some(8),
+            max_value_bytes: Some(16),
+        })
+        .spawn(&([127, 0, 0, 1], 0).into())
+        .unwrap();
+    let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+
+    assert_eq!(
+        command(&mut stream, &["SET", "key1", &"v".repeat(17)]),
+        "-ERR value of 17 bytes is over the limit of 16 bytes\r\n"
+    );
+    assert_eq!(
+        command(&mut stream, &["SET", "key1", &"v".repeat(16)]),
+        "+OK\r\n"
+    );
+    stream
+        .get_mut()
+        .write_all(b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$67108864\r\n")
+        .unwrap();
+    assert_eq!(
+        read_reply(&mut stream),
+        "-ERR Protocol error: invalid bulk length\r\n"
+    );
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "");
+}
@@ -0,0 +1,65 @@
+use kvs::ring::{self, HashRing};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+fn nodes(count: u16) -> Vec<SocketAddr> {
+    (0..count)
+        .map(|i| SocketAddr::from(([127, 0, 0, 1], 4000 + i)))
+        .collect()
+}
+
+// Every slot should have an owner, and every server should own some slots.
+#[test]
+fn slots_are_spread_over_nodes() {
+    let nodes = nodes(4);
+    let ring = HashRing::new(&nodes, 1024);
+    let owners: Vec<SocketAddr> = (0..1024)
+        .map(|slot| ring.node_for_slot(slot).unwrap())
+        .collect();
+    for node in &nodes {
+        let owned = owners.iter().filter(|owner| *owner == node).count();
+        assert!(owned > 1024 / 4 / 2, "{} owns only {} slots", node, owned);
+    }
+
+    assert_eq!(HashRing::new(&[], 1024).node_for_key("key"), None);
+}
+
+// Adding a server should only move slots onto it, and only about its share of them.
+#[test]
+fn adding_node_moves_its_share() {
+    let old_nodes = nodes(4);
+    let new_nodes = nodes(5);
+    let moves = ring::plan_moves(
+        &HashRing::new(&old_nodes, 1024),
+        &HashRing::new(&new_nodes, 1024),
+    );
+    assert!(moves.iter().all(|slot_move| slot_move.to == new_nodes[4]));
+    assert!(moves.len() > 1024 / 5 / 2 && moves.len() < 1024 / 5 * 2);
+
+    let slots: HashSet<u32> = moves.iter().map(|slot_move| slot_move.slot).collect();
+    assert_eq!(slots.len(), moves.len());
+}
+
+// Removing a server should only move the slots it owned.
+#[test]
+fn removing_node_moves_its_slots() {
+    let old_nodes = nodes(5);
+    let new_nodes = nodes(4);
+    let old_ring = HashRing::new(&old_nodes, 1024);
+    let moves = ring::plan_moves(&old_ring, &HashRing::new(&new_nodes, 1024));
+    let owned = (0..1024)
+        .filter(|&slot| old_ring.node_for_slot(slot) == Some(old_nodes[4]))
+        .count();
+    assert_eq!(moves.len(), owned);
+    assert!(moves.iter().all(|slot_move| slot_move.from == old_nodes[4]));
+}
+
+// Slots come from a fixed hash, so keys keep their slots across builds.
+#[test]
+fn slot_for_key_is_stable() {
+    assert_eq!(
+        ring::slot_for_key("key", 1024),
+        ring::slot_for_key("key", 1024)
+    );
+    assert!((0..1000).all(|i| ring::slot_for_key(&format!("key{}", i), 16) < 16));
+}
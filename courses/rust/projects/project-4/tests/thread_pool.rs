@@ -68,3 +68,55 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn shared_queue_thread_pool_queue_limit() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue_limit(1, 1)?;
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let release_rx = Arc::new(std::sync::Mutex::new(release_rx));
+
+    // One task holds the only thread, one waits in the queue, and a third doesn't fit.
+    for queued in [false, true] {
+        let (started_tx, release_rx) = (started_tx.clone(), release_rx.clone());
+        let spawned = pool.try_spawn(move || {
+            started_tx.send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+        });
+        assert!(spawned.is_ok());
+        if !queued {
+            started_rx.recv().unwrap();
+        }
+    }
+    assert_eq!(pool.active_workers(), 1);
+    assert_eq!(pool.queued_tasks(), 1);
+    assert!(pool.try_spawn(|| {}).is_err());
+
+    release_tx.send(()).unwrap();
+    started_rx.recv().unwrap();
+    assert_eq!(pool.queued_tasks(), 0);
+    release_tx.send(()).unwrap();
+    spawn_counter(pool)
+}
+
+// Panicking tasks are counted, and the workers they retire are replaced.
+#[test]
+fn shared_queue_thread_pool_counts_panics() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(2)?;
+    for _ in 0..10 {
+        pool.spawn(|| {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        })
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while pool.panicked_tasks() < 10 {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "panics weren't counted"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(pool.active_workers(), 0);
+    spawn_counter(pool)
+}
@@ -0,0 +1,107 @@
+#![cfg(feature = "tracing")]
+
+use kvs::{KvStore, KvsClient, KvsServer, Request, SpanLog, TraceId, Traced};
+use serde_json::Value;
+use slog::{o, Discard, Drain, Logger};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Log lines, as the JSON drain writes them.
+#[derive(Clone, Default)]
+struct Lines(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Lines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Lines {
+    // The logged spans named `name` in the trace `trace_id`, waiting a while for the server
+    // to close them, since it may not have by the time the client has its response.
+    fn spans(&self, name: &str, trace_id: TraceId) -> Vec<Value> {
+        let trace_id = trace_id.to_string();
+        let start = Instant::now();
+        loop {
+            let bytes = self.0.lock().unwrap().clone();
+            let spans: Vec<Value> = String::from_utf8(bytes)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap())
+                .filter(|span| span["span"] == name && span["trace_id"] == trace_id.as_str())
+                .collect();
+            if !spans.is_empty() || start.elapsed() > Duration::from_secs(5) {
+                return spans;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+// The spans of a call can be found, by its trace id, from the client's down through the
+// server's to the engine's. The one global subscriber makes this the only test here.
+#[test]
+fn spans_carry_trace_ids() {
+    let lines = Lines::default();
+    let drain = Mutex::new(slog_json::Json::default(lines.clone())).fuse();
+    tracing::subscriber::set_global_default(SpanLog::new(Logger::root(drain, o!()))).unwrap();
+
+    let temp_dir = TempDir::new().unwrap();
+    let engine = Traced::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        Logger::root(Discard, o!()),
+        Duration::MAX,
+    );
+    let (_server, addr) = KvsServer::spawn_local(engine).unwrap();
+    let mut client = KvsClient::connect(&addr).unwrap();
+
+    let trace_id: TraceId = "4bf92f3577b34da6a3ce929d0e0e4736".parse().unwrap();
+    assert_eq!(trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert!("4bf92f35".parse::<TraceId>().is_err());
+    client.set_trace_id(Some(trace_id));
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.compact().unwrap();
+
+    let calls = lines.spans("kvs.call", trace_id);
+    assert_eq!(calls[0]["request"], "set");
+    assert_eq!(calls[0]["failed"], "false");
+    let requests = lines.spans("kvs.request", trace_id);
+    assert_eq!(requests[0]["request"], "set");
+    let ops = lines.spans("kvs.engine", trace_id);
+    assert_eq!(ops[0]["op"], "set");
+    assert_eq!(ops[0]["key"], "key1");
+    assert_eq!(lines.spans("kvs.compaction", trace_id).len(), 1);
+
+    // A pipelined request records how long it waited for a thread.
+    let trace_id = TraceId(1);
+    client.set_trace_id(Some(trace_id));
+    let id = client.send(Request::Get("key1".to_owned())).unwrap();
+    assert_eq!(client.recv().unwrap().0, id);
+    let requests = lines.spans("kvs.request", trace_id);
+    assert_eq!(requests[0]["request"], "get");
+    assert!(requests[0]["queued_micros"].is_string());
+
+    // Without a trace id, each call is made in a trace of its own.
+    client.set_trace_id(None);
+    client.get("key1".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    let bytes = lines.0.lock().unwrap().clone();
+    let gets: Vec<Value> = String::from_utf8(bytes)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .filter(|span| span["span"] == "kvs.call" && span["request"] == "get")
+        .collect();
+    assert_eq!(gets.len(), 2);
+    assert_ne!(gets[0]["trace_id"], gets[1]["trace_id"]);
+    let trace_id: TraceId = gets[0]["trace_id"].as_str().unwrap().parse().unwrap();
+    assert_eq!(lines.spans("kvs.request", trace_id).len(), 1);
+}